@@ -1,70 +1,204 @@
-use std::convert::TryFrom;
 use std::fs;
-use std::num::Wrapping;
+use std::process::ExitCode;
 
-use clap::clap_app;
-
-fn compute_checksum(data: &[u8]) -> u32 {
-    let mut sum = Wrapping(0_u32);
+use mzr::calibration;
+use mzr::checksum::{checksum_report, correct_rom_checksum};
+use mzr::container::RomContainer;
+use mzr::memory_map::MzrMemoryMap;
+use mzr::output::Output;
+use mzr::profile::EcuProfile;
+use mzr::transplant::transplant_calibration;
 
-    for chunk in data.chunks(4) {
-        sum = sum + Wrapping(u32::from_be_bytes(<&[u8; 4]>::try_from(chunk).unwrap().to_owned()));
-    }
-
-    sum.0
-}
-
-/// Returns true if the checksum was corrected
-fn correct_checksum(data: &mut [u8], target: u32) -> bool {
-    // Zero correction region
-    data[0] = 0;
-    data[1] = 0;
-    data[2] = 0;
-    data[3] = 0;
-
-    let sum = compute_checksum(&data);
-    let correction: u32 = (Wrapping(target) - Wrapping(sum)).0;
-    &mut data[0..4].copy_from_slice(&correction.to_be_bytes());
+use clap::clap_app;
+use serde_json::json;
 
-    compute_checksum(&data) == target
+pub fn main() -> ExitCode {
+    run();
+    Output::exit_code()
 }
 
-pub fn main() {
+fn run() {
     let matches = clap_app!(myapp =>
         (version: "1.0")
         (author: "Jacob Manning <jjacob.manning@gmail.com>")
         (about: "Verifies and corrects checksums for MZR-DISI ROMs")
         (@arg correct: --correct "Corrects checksum. This operation modifies the input file")
-        (@arg model: -m --model +takes_value "Vehicle model")
-        (@arg INPUT: +required "Input file")
+        (@arg transplant: --transplant +takes_value "Copies the calibration region of this ROM into the input and corrects checksums, e.g. to carry a tune onto a newer base image")
+        (@arg output: -o --output +takes_value requires("transplant") "Saves the transplanted ROM to this file instead of modifying the input")
+        (@arg model: -m --model +takes_value "Vehicle model selecting the ROM layout, e.g. ms6 or ms3-gen2")
+        (@arg json: --json "Prints machine-readable JSON output")
+        (@arg INPUT: +required "Input file (raw ROM or .mzrrom container)")
     )
     .get_matches();
 
+    let out = Output::new(matches.is_present("json"));
     let path = matches.value_of("INPUT").unwrap();
-    let mut data = fs::read(path).unwrap();
+    let contents = match fs::read(path) {
+        Ok(contents) => contents,
+        Err(err) => {
+            out.error(format!("Failed to read {}: {}", path, err));
+            return;
+        }
+    };
+
+    // Containers are checked and corrected in place
+    let mut container = None;
+    let mut data = if RomContainer::is_container(&contents) {
+        match RomContainer::read(&contents) {
+            Ok(c) => {
+                let data = c.data.clone();
+                container = Some(c);
+                data
+            }
+            Err(err) => {
+                out.error(format!("Invalid container: {}", err));
+                return;
+            }
+        }
+    } else {
+        contents
+    };
+
+    // Containers name the layout their image was built for; raw ROMs use the
+    // layout of the vehicle model
+    let map = match container {
+        Some(ref c) => match MzrMemoryMap::by_name(&c.header.memory_map) {
+            Some(map) => map,
+            None => {
+                out.error(format!("Unknown memory map {}", c.header.memory_map));
+                return;
+            }
+        },
+        None => match matches.value_of("model") {
+            Some(model) => match EcuProfile::for_model(model) {
+                Some(profile) => profile.memory_map,
+                None => {
+                    out.error(format!("Unknown vehicle model {}", model));
+                    return;
+                }
+            },
+            None => MzrMemoryMap::default(),
+        },
+    };
+    // The calibration of another ROM replaces the input's own
+    let mut transplanted = None;
+    if let Some(donor_path) = matches.value_of("transplant") {
+        let donor = match read_image(donor_path) {
+            Ok(donor) => donor,
+            Err(err) => {
+                out.error(err);
+                return;
+            }
+        };
+        match transplant_calibration(&mut data, &donor, &map) {
+            Ok(changed) => transplanted = Some(changed),
+            Err(err) => {
+                out.error(format!("Transplant failed: {}", err));
+                return;
+            }
+        }
+    }
+
+    let full_rom = !matches!(container, Some(ref c) if c.header.offset != map.flash().start);
+    let report = match checksum_report(&data, &map) {
+        Some(report) if full_rom => report,
+        _ => {
+            out.error(format!(
+                "Input file has invalid size (expected a {} KiB ROM file).",
+                map.flash_size() / 1024
+            ));
+            return;
+        }
+    };
 
-    let offset = 0x48000;
-    let end = 0x100000;
-    let target = 0x5AA55AA5;
-    if data.len() != end {
-        println!("Input file has invalid size (expected a 1MiB ROM file).");
-        return;
+    // Label the dump with the calibration stored in it
+    let calibration = calibration::identify(&data);
+    match calibration {
+        Some(calibration) => {
+            out.message(format!("Calibration: {} - {}", calibration.id, calibration))
+        }
+        None => out.message("Calibration: unknown"),
+    }
+    if let (Some(calibration), Some(model)) = (calibration, matches.value_of("model")) {
+        let other = EcuProfile::for_model(model).map(|profile| profile.name);
+        if other != EcuProfile::for_model(calibration.model).map(|profile| profile.name) {
+            out.message(format!(
+                "Warning: ROM holds a calibration for the {}, not a {}",
+                calibration, model
+            ));
+        }
     }
 
-    let checksum = compute_checksum(&data[offset..end]);
-    println!("Checksum: {:X}\tTarget: {:X}", checksum, target);
-    if checksum == target {
-        println!("Checksum is correct!");
+    let mut corrected = false;
+    if let Some(changed) = transplanted {
+        let output = matches.value_of("output").unwrap_or(path);
+        let contents = match container {
+            Some(ref mut container) => {
+                container.data = data.clone();
+                container.write()
+            }
+            None => data.clone(),
+        };
+        if let Err(err) = fs::write(output, contents) {
+            out.error(format!("Failed to write {}: {}", output, err));
+            return;
+        }
+        out.message(format!(
+            "Transplanted the calibration ({} bytes changed)! File saved as {}",
+            changed, output
+        ));
+    }
+    for region in &report.regions {
+        out.message(format!(
+            "Region {:X}-{:X}\tChecksum: {:X}\tTarget: {:X}",
+            region.start, region.end, region.checksum, region.target
+        ));
+    }
+    if report.valid {
+        out.message("Checksums are correct!");
     } else {
         if matches.is_present("correct") {
-            if correct_checksum(&mut data[offset..end], target) {
-                fs::write(path, data).unwrap();
-                println!("Corrected checksum! File saved as {}", path);
+            if correct_rom_checksum(&mut data, &map) {
+                let contents = match container {
+                    Some(ref mut container) => {
+                        container.data = data;
+                        container.write()
+                    }
+                    None => data,
+                };
+                if let Err(err) = fs::write(path, contents) {
+                    out.error(format!("Failed to write {}: {}", path, err));
+                    return;
+                }
+                corrected = true;
+                out.message(format!("Corrected checksums! File saved as {}", path));
             } else {
-                println!("Failed to correct checksum");
+                out.message("Failed to correct checksums");
             }
         } else {
-            println!("Checksum is incorrect! Correct it with --correct");
+            out.message("Checksums are incorrect! Correct them with --correct");
         }
     }
+
+    out.event(json!({
+        "path": path,
+        "memory_map": report.memory_map,
+        "calibration": calibration,
+        "regions": report.regions,
+        "valid": report.valid,
+        "corrected": corrected,
+        "transplanted": transplanted,
+    }));
+}
+
+/// Reads a raw ROM or the image of a container
+fn read_image(path: &str) -> Result<Vec<u8>, String> {
+    let contents = fs::read(path).map_err(|err| format!("Failed to read {}: {}", path, err))?;
+    if RomContainer::is_container(&contents) {
+        RomContainer::read(&contents)
+            .map(|container| container.data)
+            .map_err(|err| format!("Invalid container {}: {}", path, err))
+    } else {
+        Ok(contents)
+    }
 }
@@ -0,0 +1,306 @@
+//! ISO-TP (ISO 15765-2) frames. Frames borrow their data from the CAN
+//! payload they were decoded from or will be encoded into.
+
+use core::cmp;
+use core::fmt;
+use core::time::Duration;
+
+/// Largest packet whose length fits in the 12 bits of a first frame
+pub const MAX_SHORT_PACKET: usize = 4095;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    InvalidFcFlag,
+    InvalidFrameId,
+    /// The length of a frame doesn't match its contents
+    InvalidLength,
+    ReservedSeparationTime(u8),
+    /// The buffer can't hold the encoded frame
+    BufferTooSmall,
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::InvalidFcFlag => write!(f, "invalid flow control flag"),
+            FrameError::InvalidFrameId => write!(f, "invalid frame id"),
+            FrameError::InvalidLength => write!(f, "invalid frame length"),
+            FrameError::ReservedSeparationTime(st) => {
+                write!(f, "reserved separation time 0x{:02X}", st)
+            }
+            FrameError::BufferTooSmall => write!(f, "buffer too small for the frame"),
+        }
+    }
+}
+
+/// How strictly received frames are checked
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ParseMode {
+    /// Rejects malformed frames, e.g. for conformance testing
+    Strict,
+    /// Tolerates the quirks of real ECUs and noisy adapters, such as
+    /// lengths exceeding the frame or reserved flag bits
+    Lenient,
+}
+
+/// Addressing format of the CAN messages carrying frames
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Addressing {
+    /// The whole payload is the frame
+    Normal,
+    /// The first byte of the payload is an address, leaving one byte less
+    /// for the frame
+    Extended,
+}
+
+impl Addressing {
+    /// Returns the length of a full frame in a classic CAN message
+    pub fn classic_len(self) -> usize {
+        match self {
+            Addressing::Normal => 8,
+            Addressing::Extended => 7,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FCFlag {
+    Continue = 0,
+    Wait = 1,
+    Overflow = 2,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Frame<'a> {
+    Single {
+        data: &'a [u8],
+    },
+    First {
+        size: u32,
+        data: &'a [u8],
+    },
+    Consecutive {
+        index: u8,
+        data: &'a [u8],
+    },
+    Flow {
+        flag: FCFlag,
+        block_size: u8,
+        separation_time: Duration,
+    },
+}
+
+impl<'a> Frame<'a> {
+    /// Returns the protocol control information of the frame, and its length
+    fn pci(&self) -> ([u8; 6], usize) {
+        let mut pci = [0; 6];
+        let len = match *self {
+            // Single frames of more than 7 bytes only fit in CAN-FD frames,
+            // which carry the length in a second byte
+            Frame::Single { data } if data.len() > 7 => {
+                pci[1] = data.len() as u8;
+                2
+            }
+            Frame::Single { data } => {
+                pci[0] = data.len() as u8;
+                1
+            }
+            // Packets longer than 4095 bytes escape to a 32-bit length
+            Frame::First { size, .. } if size > MAX_SHORT_PACKET as u32 => {
+                pci[0] = 0x10;
+                pci[2..].copy_from_slice(&size.to_be_bytes());
+                6
+            }
+            Frame::First { size, .. } => {
+                pci[0] = (1 << 4) | ((size & 0xF00) >> 8) as u8;
+                pci[1] = (size & 0xFF) as u8;
+                2
+            }
+            Frame::Consecutive { index, .. } => {
+                pci[0] = (2 << 4) | index;
+                1
+            }
+            Frame::Flow {
+                flag,
+                block_size,
+                separation_time,
+            } => {
+                pci[0] = 0x30 | flag as u8;
+                pci[1] = block_size;
+                pci[2] = duration_to_st(separation_time);
+                3
+            }
+        };
+        (pci, len)
+    }
+
+    fn data(&self) -> &'a [u8] {
+        match *self {
+            Frame::Single { data }
+            | Frame::First { data, .. }
+            | Frame::Consecutive { data, .. } => data,
+            Frame::Flow { .. } => &[],
+        }
+    }
+
+    /// Returns the length of the encoded frame
+    pub fn encoded_len(&self) -> usize {
+        self.pci().1 + self.data().len()
+    }
+
+    /// Encodes the protocol control information and data of the frame into
+    /// `buffer`, returning the number of bytes written
+    pub fn encode(&self, buffer: &mut [u8]) -> Result<usize, FrameError> {
+        let (pci, pci_len) = self.pci();
+        let data = self.data();
+        let len = pci_len + data.len();
+        let buffer = buffer.get_mut(..len).ok_or(FrameError::BufferTooSmall)?;
+        buffer[..pci_len].copy_from_slice(&pci[..pci_len]);
+        buffer[pci_len..].copy_from_slice(data);
+        Ok(len)
+    }
+
+    /// Decodes a frame from the payload of a CAN message, after the address
+    /// byte with extended `addressing`. Padding is kept in the data of first
+    /// and consecutive frames.
+    pub fn decode(
+        payload: &'a [u8],
+        mode: ParseMode,
+        addressing: Addressing,
+    ) -> Result<Frame<'a>, FrameError> {
+        let strict = mode == ParseMode::Strict;
+        let classic_len = addressing.classic_len();
+        let pci = *payload.first().ok_or(FrameError::InvalidLength)?;
+        match pci >> 4 {
+            0 => {
+                // Single frame. A zero length escapes to the length byte of
+                // CAN-FD single frames.
+                let (length, data) = match pci & 0x0F {
+                    0 if payload.len() > classic_len => (payload[1] as usize, &payload[2..]),
+                    length => (length as usize, &payload[1..]),
+                };
+                if strict && (length == 0 || length > data.len()) {
+                    return Err(FrameError::InvalidLength);
+                }
+                // Escaped lengths must not fit in a classic single frame
+                if strict && pci & 0x0F == 0 && length <= 7 {
+                    return Err(FrameError::InvalidLength);
+                }
+                let data = &data[..cmp::min(length, data.len())];
+                Ok(Frame::Single { data })
+            }
+            1 => {
+                // First. A zero length escapes to a 32-bit length.
+                let size = ((pci as u32 & 0x0F) << 8) | *payload.get(1).unwrap_or(&0) as u32;
+                let escaped = size == 0;
+                let (size, data) = match payload.get(2..6) {
+                    Some(escaped) if size == 0 => (
+                        u32::from_be_bytes([escaped[0], escaped[1], escaped[2], escaped[3]]),
+                        &payload[6..],
+                    ),
+                    _ => (size, payload.get(2..).unwrap_or(&[])),
+                };
+                // First frames fill their CAN frame and carry packets that
+                // don't fit in a single frame
+                if strict && (payload.len() < classic_len || size as usize <= data.len()) {
+                    return Err(FrameError::InvalidLength);
+                }
+                if strict && escaped && size as usize <= MAX_SHORT_PACKET {
+                    return Err(FrameError::InvalidLength);
+                }
+                Ok(Frame::First { size, data })
+            }
+            2 => {
+                // Consecutive
+                let index = pci & 0x0F;
+                if strict && payload.len() < 2 {
+                    return Err(FrameError::InvalidLength);
+                }
+                let data = &payload[1..];
+                Ok(Frame::Consecutive { index, data })
+            }
+            3 => {
+                // Flow. Lenient parsing ignores the unused bits of the flag.
+                let flag_mask = if strict { 0x0F } else { 0x03 };
+                let flag = match pci & flag_mask {
+                    0 => FCFlag::Continue,
+                    1 => FCFlag::Wait,
+                    2 => FCFlag::Overflow,
+                    _ => return Err(FrameError::InvalidFcFlag),
+                };
+                if strict && payload.len() < 3 {
+                    return Err(FrameError::InvalidLength);
+                }
+                let block_size = *payload.get(1).unwrap_or(&0);
+                let separation_time = *payload.get(2).unwrap_or(&0);
+                if strict && !is_valid_st(separation_time) {
+                    return Err(FrameError::ReservedSeparationTime(separation_time));
+                }
+                Ok(Frame::Flow {
+                    flag,
+                    block_size,
+                    separation_time: st_to_duration(separation_time),
+                })
+            }
+            _ => Err(FrameError::InvalidFrameId),
+        }
+    }
+}
+
+/// Returns true if `st` is a defined separation time
+pub fn is_valid_st(st: u8) -> bool {
+    matches!(st, 0..=127 | 0xF1..=0xF9)
+}
+
+/// Converts separation time to [`Duration`]
+pub fn st_to_duration(st: u8) -> Duration {
+    match st {
+        0..=127 => Duration::from_millis(st as u64),
+        0xF1..=0xF9 => Duration::from_micros((st - 0xF0) as u64 * 100),
+        // Reserved values are treated as the maximum separation time
+        _ => Duration::from_millis(127),
+    }
+}
+
+/// Converts [`Duration`] to separation time
+pub fn duration_to_st(duration: Duration) -> u8 {
+    if duration < Duration::from_millis(1) {
+        if duration.subsec_micros() >= 100 {
+            return (duration.subsec_micros() / 100) as u8 + 0xF0;
+        }
+        return 0;
+    }
+    cmp::min(duration.as_millis(), 127) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_into_buffers() {
+        let mut buffer = [0u8; 8];
+        let frame = Frame::First {
+            size: 20,
+            data: &[1, 2, 3, 4, 5, 6],
+        };
+        assert_eq!(frame.encoded_len(), 8);
+        assert_eq!(frame.encode(&mut buffer), Ok(8));
+        assert_eq!(buffer, [0x10, 20, 1, 2, 3, 4, 5, 6]);
+        assert_eq!(
+            Frame::decode(&buffer, ParseMode::Strict, Addressing::Normal),
+            Ok(frame)
+        );
+
+        let flow = Frame::Flow {
+            flag: FCFlag::Wait,
+            block_size: 8,
+            separation_time: Duration::from_micros(300),
+        };
+        assert_eq!(flow.encode(&mut buffer), Ok(3));
+        assert_eq!(buffer[..3], [0x31, 8, 0xF3]);
+
+        let single = Frame::Single { data: &[0; 12] };
+        assert_eq!(single.encode(&mut buffer), Err(FrameError::BufferTooSmall));
+    }
+}
@@ -0,0 +1,48 @@
+//! Mazda's security access key algorithm
+
+/// Secret of the key generation algorithm used by MZR ECUs
+pub const MZR_KEY: &str = "MazdA";
+/// Initial value of the key generation algorithm for [`MZR_KEY`]
+pub const MZR_KEY_PARAMETER: u32 = 0xC541A9;
+
+/// Generates the security access key for the seed sent by an MZR ECU
+pub fn security_key(seed: &[u8]) -> [u8; 3] {
+    generate_key(MZR_KEY, MZR_KEY_PARAMETER, seed)
+}
+
+/// Generates a key from a seed for security access, with the secret `key`
+/// and initial value `parameter`
+pub fn generate_key(key: &str, parameter: u32, seed: &[u8]) -> [u8; 3] {
+    let mut parameter = parameter;
+    // This is Mazda's key generation algorithm reverse engineered from a
+    // Mazda 6 MPS ROM. Internally, the ECU uses a timer/counter for the seed
+    // generation
+
+    for c in seed.iter().chain(key.as_bytes()).cloned() {
+        let mut c = c;
+        for _ in (1..=8).rev() {
+            let s = (c & 1) ^ (parameter & 1) as u8;
+            let mut m: u32 = 0;
+            if s != 0 {
+                parameter |= 0x0100_0000;
+                m = 0x0010_9028;
+            }
+
+            c >>= 1;
+            parameter >>= 1;
+            let p3 = parameter & 0xFFEF_6FD7;
+            parameter ^= m;
+            parameter &= 0x0010_9028;
+
+            parameter |= p3;
+            parameter &= 0x00FF_FFFF;
+        }
+    }
+
+    let mut res = [0; 3];
+    res[0] = ((parameter >> 4) & 0xFF) as u8;
+    res[1] = (((parameter >> 20) & 0xFF) + ((parameter >> 8) & 0xF0)) as u8;
+    res[2] = (((parameter << 4) & 0xFF) + ((parameter >> 16) & 0x0F)) as u8;
+
+    res
+}
@@ -0,0 +1,10 @@
+//! Protocol logic without I/O or allocation: the seed/key algorithm, ISO-TP
+//! framing and UDS message construction. The crate is `no_std`, so the
+//! protocol can run on embedded gateways, while the `mzr` crate provides
+//! the transports.
+
+#![no_std]
+
+pub mod isotp;
+pub mod key;
+pub mod uds;
@@ -0,0 +1,130 @@
+//! UDS (ISO 14229) requests as sent to MZR ECUs, and parsing of responses
+
+use core::fmt;
+
+pub const UDS_REQ_SESSION: u8 = 0x10;
+pub const UDS_REQ_READDATABYIDENTIFIER: u8 = 0x22;
+pub const UDS_REQ_READMEMORYBYADDRESS: u8 = 0x23;
+pub const UDS_REQ_SECURITY: u8 = 0x27;
+pub const UDS_REQ_WRITEDATABYIDENTIFIER: u8 = 0x2E;
+pub const UDS_REQ_ROUTINECONTROL: u8 = 0x31;
+pub const UDS_REQ_REQUESTDOWNLOAD: u8 = 0x34;
+pub const UDS_REQ_TRANSFERDATA: u8 = 0x36;
+pub const UDS_REQ_TRANSFEREXIT: u8 = 0x37;
+pub const UDS_REQ_WRITEMEMORYBYADDRESS: u8 = 0x3D;
+pub const UDS_REQ_TESTERPRESENT: u8 = 0x3E;
+pub const UDS_REQ_ERASE: u8 = 0xB1;
+
+/// Service ID of negative responses
+pub const UDS_NEGATIVE_RESPONSE: u8 = 0x7F;
+
+/* Negative response codes */
+pub const NRC_SECURITY_ACCESS_DENIED: u8 = 0x33;
+pub const NRC_RESPONSE_PENDING: u8 = 0x78;
+pub const NRC_SERVICE_NOT_SUPPORTED_IN_SESSION: u8 = 0x7F;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ResponseError {
+    /// The ECU rejected the request with a negative response code
+    Negative(u8),
+    /// The ECU needs more time and will respond later
+    Pending,
+    /// The response doesn't answer the request
+    Invalid,
+}
+
+impl fmt::Display for ResponseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResponseError::Negative(nrc) => write!(f, "negative response 0x{:02X}", nrc),
+            ResponseError::Pending => write!(f, "response pending"),
+            ResponseError::Invalid => write!(f, "invalid response"),
+        }
+    }
+}
+
+/// Writes the request for `service` with `data` into `buffer`, returning
+/// its length, or `None` if it doesn't fit
+pub fn encode_request(service: u8, data: &[u8], buffer: &mut [u8]) -> Option<usize> {
+    let len = data.len() + 1;
+    let buffer = buffer.get_mut(..len)?;
+    buffer[0] = service;
+    buffer[1..].copy_from_slice(data);
+    Some(len)
+}
+
+/// Returns the data of a positive response to `service`
+pub fn parse_response(service: u8, response: &[u8]) -> Result<&[u8], ResponseError> {
+    match *response {
+        [UDS_NEGATIVE_RESPONSE, sid, NRC_RESPONSE_PENDING, ..] if sid == service => {
+            Err(ResponseError::Pending)
+        }
+        [UDS_NEGATIVE_RESPONSE, sid, nrc, ..] if sid == service => {
+            Err(ResponseError::Negative(nrc))
+        }
+        [sid, ..] if sid == service.wrapping_add(0x40) => Ok(&response[1..]),
+        _ => Err(ResponseError::Invalid),
+    }
+}
+
+/// Data of a RequestDownload for `length` bytes at `offset`
+pub fn request_download(offset: u32, length: u32) -> [u8; 8] {
+    let mut request = [0; 8];
+    request[..4].copy_from_slice(&offset.to_be_bytes());
+    request[4..].copy_from_slice(&length.to_be_bytes());
+    request
+}
+
+/// Data of a SecurityAccess sending `key`. The key is sent with the level
+/// following the seed request.
+pub fn send_key(level: u8, key: [u8; 3]) -> [u8; 4] {
+    [level + 1, key[0], key[1], key[2]]
+}
+
+/// Data of a WriteMemoryByAddress preceding the `len` bytes written at
+/// `address`
+pub fn write_memory_header(address: u32, len: u16) -> [u8; 6] {
+    let mut header = [0; 6];
+    header[..4].copy_from_slice(&address.to_be_bytes());
+    header[4..].copy_from_slice(&len.to_be_bytes());
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_requests_and_parses_responses() {
+        let mut buffer = [0u8; 16];
+        let data = request_download(0x8000, 0x100);
+        assert_eq!(data, [0, 0, 0x80, 0, 0, 0, 1, 0]);
+        let len = encode_request(UDS_REQ_REQUESTDOWNLOAD, &data, &mut buffer).unwrap();
+        assert_eq!(buffer[..len], [0x34, 0, 0, 0x80, 0, 0, 0, 1, 0]);
+        assert_eq!(
+            encode_request(UDS_REQ_TRANSFERDATA, &[0; 16], &mut buffer),
+            None
+        );
+
+        assert_eq!(send_key(1, [0xAA, 0xBB, 0xCC]), [2, 0xAA, 0xBB, 0xCC]);
+        assert_eq!(
+            write_memory_header(0xFFFF8000, 4),
+            [0xFF, 0xFF, 0x80, 0, 0, 4]
+        );
+
+        assert_eq!(parse_response(0x27, &[0x67, 1, 2]), Ok(&[1, 2][..]));
+        assert_eq!(
+            parse_response(0x27, &[0x7F, 0x27, 0x33]),
+            Err(ResponseError::Negative(0x33))
+        );
+        assert_eq!(
+            parse_response(0x31, &[0x7F, 0x31, 0x78]),
+            Err(ResponseError::Pending)
+        );
+        assert_eq!(
+            parse_response(0x27, &[0x62, 1]),
+            Err(ResponseError::Invalid)
+        );
+        assert_eq!(parse_response(0x27, &[]), Err(ResponseError::Invalid));
+    }
+}
@@ -1,75 +1,479 @@
 //! This example queries a VIN using a PassThru device
 
-use anyhow::Result;
-use obd::{PassThruIsoTp, Uds};
+use obd::Uds;
 use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 
-use mzr::{DownloadState, Downloader, MzrBus};
+use mzr::capture::{Capture, CaptureWriter};
+use mzr::config::Config;
+use mzr::image::{Image, ImageFormat};
+use mzr::iter::TransferIter;
+use mzr::kernel::{KernelTransfer, RamKernel, DEFAULT_KERNEL_ADDRESS};
+use mzr::metadata::RomMetadata;
+use mzr::output::Output;
+use mzr::partial::PartialFile;
+use mzr::passthru::PassThruChannel;
+use mzr::profile::EcuProfile;
+use mzr::progress::{Phase, Progress, ProgressObserver};
+use mzr::reconnect::Reconnecting;
+use mzr::session::KEEP_ALIVE_INTERVAL;
+use mzr::timeout::TimeoutProfile;
+use mzr::{passthru, MzrBus, MzrError, Transfer};
 
 use clap::clap_app;
 use indicatif::{ProgressBar, ProgressStyle};
+use serde_json::json;
+use tracing_subscriber::filter::{LevelFilter, Targets};
+use tracing_subscriber::fmt;
+use tracing_subscriber::prelude::*;
 
-pub fn main() {
+pub fn main() -> ExitCode {
+    run();
+    Output::exit_code()
+}
+
+fn run() {
     let matches = clap_app!(myapp =>
         (version: "1.0")
         (author: "Jacob Manning <jjacob.manning@gmail.com>")
         (about: "Downloads ROM from an MZR-DISI ECU")
-        (@arg passthru: -p --passthru +takes_value "PassThru device to use when connecting to the ECU")
-        (@arg model: -m --model +takes_value "Vehicle model")
+        (@arg device: -d --device alias("passthru") +takes_value "J2534 device to use when connecting to the ECU (name or index)")
+        (@arg library: --library +takes_value "J2534 library (DLL) to load instead of the installed drivers")
+        (@arg model: -m --model +takes_value "Vehicle model selecting the ECU profile, e.g. ms6 or ms3-gen2. Detected from the VIN by default")
+        (@arg json: --json "Prints machine-readable JSON output")
+        (@arg debug_adapter: --("debug-adapter") "Logs every J2534 call with its parameters and return code to stderr")
+        (@arg profile: -p --profile +takes_value "ECU profile file describing CAN IDs, sessions, security access and memory layout")
+        (@arg chunk_size: --("chunk-size") +takes_value "Bytes requested per read (adapts to the adapter by default)")
+        (@arg kernel: --kernel +takes_value "Reads through a RAM kernel uploaded from this file")
+        (@arg kernel_address: --("kernel-address") +takes_value requires("kernel") "RAM address to load the kernel to, in hex (default 0xFFFF6000)")
+        (@arg spot_check: --("spot-check") +takes_value conflicts_with("kernel") "Re-reads this percentage of blocks, chosen at random, and compares them before the dump is trusted")
+        (@arg restart: --restart "Discards any interrupted download instead of resuming it")
+        (@arg capture: --capture +takes_value "Records the bus traffic to this file (candump log, or PCAP if it ends in .pcap)")
+        (@arg format: -f --format +takes_value possible_values(&["bin", "ihex", "srec", "mzrrom"]) "Output format (defaults to the output file extension, or bin)")
         (@arg OUTPUT: "Output file (defaults to <vin>.bin)")
+        (@subcommand devices =>
+            (about: "Lists installed J2534 devices"))
     )
     .get_matches();
 
-    // Get a list of interfaces
-    let device = match j2534::drivers().unwrap().into_iter().next() {
-        Some(device) => device,
-        None => {
-            println!("No J2534 interfaces found");
+    let out = Output::new(matches.is_present("json"));
+    if matches.subcommand_matches("devices").is_some() {
+        print_drivers(out);
+        return;
+    }
+    // --debug-adapter shows the J2534 calls traced by mzr on stderr
+    if matches.is_present("debug_adapter") {
+        tracing_subscriber::registry()
+            .with(fmt::layer().with_writer(io::stderr))
+            .with(Targets::new().with_target("j2534", LevelFilter::TRACE))
+            .init();
+    }
+
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(err) => {
+            out.error(err);
+            return;
+        }
+    };
+
+    // The ECU is described by a profile file, or by the built-in profile of
+    // the vehicle model with the configured CAN IDs. Without either, the
+    // profile is detected from the VIN once connected.
+    let selected = match EcuProfile::select(
+        matches.value_of("profile"),
+        matches.value_of("model"),
+        &config,
+    ) {
+        Ok(profile) => profile,
+        Err(err) => {
+            out.error(err);
+            return;
+        }
+    };
+
+    let (request_id, response_id) = match selected {
+        Some(ref profile) => (profile.request_id, profile.response_id),
+        None => (config.request_id, config.response_id),
+    };
+
+    // Select an interface
+    let selector = matches.value_of("device").or(config.device.as_deref());
+    let library = matches
+        .value_of("library")
+        .map(Path::new)
+        .or(config.library.as_deref());
+    let device = match passthru::select_driver(library, selector) {
+        Ok(device) => device,
+        Err(err) => {
+            out.error(err);
             return;
         }
     };
 
-    println!("Opening interface '{}'", device.name);
-    let i = j2534::Interface::new(&device.path).unwrap();
+    out.message(format!("Opening interface '{}'", device.name));
+    let i = match j2534::Interface::new(&device.path) {
+        Ok(i) => i,
+        Err(err) => {
+            out.error(format!("Failed to load {}: {}", device.path, err));
+            return;
+        }
+    };
     // Open any connected device
-    let d = i.open_any().unwrap();
+    let d = match passthru::traced("PassThruOpen", format_args!(""), || i.open_any()) {
+        Ok(d) => d,
+        Err(err) => {
+            out.error(format!("Failed to open the adapter: {}", err));
+            return;
+        }
+    };
     // Get version information
-    let version_info = d.read_version().unwrap();
-    println!("{:#?}", version_info);
+    let version_info =
+        match passthru::traced("PassThruReadVersion", format_args!(""), || d.read_version()) {
+            Ok(version_info) => version_info,
+            Err(err) => {
+                out.error(format!("Failed to read the adapter version: {}", err));
+                return;
+            }
+        };
+    out.message(format!("{:#?}", version_info));
+    out.event(json!({
+        "event": "connected",
+        "interface": device.name,
+        "firmware_version": version_info.firmware_version,
+        "dll_version": version_info.dll_version,
+        "api_version": version_info.api_version,
+    }));
 
     // Query trouble codes
     /*for code in driver.query_trouble_codes(0x7e0).unwrap().iter() {
         println!("{}", code);
     }*/
 
-    // Create PassThru connection
-    let mut driver = PassThruIsoTp::new(&d, 500000, 10000).unwrap();
-    // isotp.set_filter(0x7e0, 0x7e8);
-    let vin = driver.query_vin(0x7e0).unwrap();
-    println!("VIN: {}", vin);
+    // Record the traffic for analyzing failures
+    let capture = match matches.value_of("capture").map(CaptureWriter::create) {
+        Some(Ok(writer)) => Some(writer),
+        Some(Err(err)) => {
+            out.error(format!("Failed to create capture file: {}", err));
+            return;
+        }
+        None => None,
+    };
+
+    // Create PassThru connection, reopening it if the adapter drops out
+    let driver = match Reconnecting::new(|| {
+        let mut channel = PassThruChannel::new(&d, 500000, TimeoutProfile::default().request)?;
+        channel.set_filter(request_id, response_id)?;
+        // TesterPresent from the adapter keeps the session alive even if
+        // this process stalls
+        if let Err(err) = channel.start_keep_alive(request_id, KEEP_ALIVE_INTERVAL) {
+            out.message(format!("Adapter keep-alive unavailable: {}", err));
+        }
+        Ok(channel)
+    }) {
+        Ok(driver) => driver,
+        Err(err) => {
+            out.error(format!("Failed to open channel: {}", err));
+            return;
+        }
+    };
+    let mut driver = Capture::new(driver, capture);
+    let vin = match driver.query_vin(request_id) {
+        Ok(vin) => vin,
+        Err(err) => {
+            out.error(format!("Failed to read the VIN: {}", err));
+            return;
+        }
+    };
+    out.message(format!("VIN: {}", vin));
+    out.event(json!({ "event": "vin", "vin": vin }));
+    let calibration_id = driver.calibration_id(request_id).ok();
+    let ecu_name = driver.ecu_name(request_id).ok();
+    let profile = match selected {
+        Some(profile) => profile,
+        None => {
+            let profile = EcuProfile::detect(Some(&vin), &config);
+            out.message(format!("Detected {} profile from VIN", profile.name));
+            out.event(json!({ "event": "profile", "profile": profile.name }));
+            profile
+        }
+    };
+    if let Some(ref calibration_id) = calibration_id {
+        out.message(format!("Calibration ID: {}", calibration_id));
+    }
+
+    // Get output path and format
+    let format = match matches.value_of("format") {
+        Some(format) => format.parse().unwrap(),
+        None => matches.value_of("OUTPUT").map_or(ImageFormat::Bin, |path| {
+            ImageFormat::from_path(Path::new(path))
+        }),
+    };
+    let output_path = matches
+        .value_of("OUTPUT")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| {
+            let file_name = format!("{}.{}", vin, format.extension());
+            match config.output_dir {
+                Some(ref dir) => dir.join(file_name),
+                None => PathBuf::from(file_name),
+            }
+        });
 
-    // Authenticate and download
-    let mut downloader = Downloader::new(&mut driver);
+    // Read through a RAM kernel instead of the bootloader
+    let kernel = match matches.value_of("kernel") {
+        Some(path) => {
+            let address = match matches.value_of("kernel_address") {
+                Some(address) => match u32::from_str_radix(address.trim_start_matches("0x"), 16) {
+                    Ok(address) => address,
+                    Err(_) => {
+                        out.error("Invalid kernel address");
+                        return;
+                    }
+                },
+                None => DEFAULT_KERNEL_ADDRESS,
+            };
+            match RamKernel::load(Path::new(path), address) {
+                Ok(kernel) => Some(kernel),
+                Err(err) => {
+                    out.error(format!("Failed to load kernel: {}", err));
+                    return;
+                }
+            }
+        }
+        None => None,
+    };
 
-    // Create progress bar
-    let pb = ProgressBar::new(downloader.total_size() as u64);
+    // Resume an interrupted download of the same vehicle
+    let resumed = if matches.is_present("restart") || kernel.is_some() {
+        None
+    } else {
+        match PartialFile::load(&output_path) {
+            Ok(Some(partial)) if partial.vin.as_deref() == Some(vin.as_str()) => Some(partial.data),
+            Ok(_) => None,
+            Err(err) => {
+                out.message(format!("Ignoring unreadable partial download: {}", err));
+                None
+            }
+        }
+    };
+    let resumed = resumed.unwrap_or_default();
+    let partial = match PartialFile::create(&output_path, &resumed, Some(&vin)) {
+        Ok(partial) => partial,
+        Err(err) => {
+            out.error(err);
+            return;
+        }
+    };
+    if !resumed.is_empty() {
+        out.message(format!("Resuming download at 0x{:X}", resumed.len()));
+        out.event(json!({ "event": "resume", "position": resumed.len() }));
+    }
+
+    // Authenticate and download
+    let memory_map = profile.memory_map.clone();
+    let mut builder = profile.downloader();
+    if let Some(size) = matches.value_of("chunk_size") {
+        let size = match size.strip_prefix("0x") {
+            Some(hex) => usize::from_str_radix(hex, 16),
+            None => size.parse(),
+        };
+        match size {
+            Ok(size) if size > 0 => builder = builder.chunk_size(size),
+            _ => {
+                out.error("Invalid chunk size");
+                return;
+            }
+        }
+    }
+    if let Some(percent) = matches.value_of("spot_check") {
+        match percent.trim_end_matches('%').parse::<u8>() {
+            Ok(percent) if percent <= 100 => builder = builder.spot_check(percent),
+            _ => {
+                out.error("Invalid spot check percentage");
+                return;
+            }
+        }
+    }
+    let flash = memory_map.flash();
+    let total = flash.len();
+    let pb = if out.is_json() {
+        ProgressBar::hidden()
+    } else {
+        ProgressBar::new(total as u64)
+    };
     pb.set_style(ProgressStyle::default_bar()
-        .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")
+        .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({msg})")
         .progress_chars("#>-"));
+    let mut progress = CliProgress { pb, out };
+
+    let result = match kernel {
+        Some(kernel) => {
+            let mut transfer = KernelTransfer::read(&mut driver, kernel, flash.start, total);
+            transfer.set_request_id(profile.request_id);
+            read_through_kernel(&mut transfer, &mut progress).map(|()| transfer.take_data())
+        }
+        None => {
+            let mut downloader = builder.build(&mut driver);
+            downloader.resume(resumed);
+            downloader.set_partial_file(partial);
+            downloader.run(&mut progress).map(|()| {
+                out.message(format!("Read {}", downloader.stats()));
+                out.event(downloader.stats().to_json());
+                downloader.take_data()
+            })
+        }
+    };
+    let data = match result {
+        Ok(data) => data,
+        Err(MzrError::VerifyFailed(address)) => {
+            progress.pb.abandon();
+            out.error(format!(
+                "Spot check failed: 0x{:X} read back differently. The adapter may be \
+                 corrupting data; run again with --restart",
+                address
+            ));
+            return;
+        }
+        Err(err) => {
+            progress.pb.abandon();
+            out.error(err);
+            return;
+        }
+    };
+    progress.pb.finish_with_message("downloaded");
+
+    // Describe the image so it can be identified later
+    let metadata = RomMetadata {
+        calibration_id,
+        ecu_name,
+        request_id: profile.request_id,
+        response_id: profile.response_id,
+        adapter: Some(device.name.clone()),
+        ..RomMetadata::new(&vin, &data)
+    };
 
-    downloader.start().unwrap();
-    while let DownloadState::InProgress(downloaded) = downloader.step().unwrap() {
-        pb.set_position(downloaded as u64);
+    // The ROM is read from the start of flash
+    let image = Image {
+        offset: flash.start,
+        data,
+    };
+    let contents = match format {
+        ImageFormat::Container => {
+            let mut container = image.to_container(&memory_map);
+            container.header.model = matches
+                .value_of("model")
+                .or(config.model.as_deref())
+                .map(str::to_owned);
+            container.header.metadata = Some(metadata.clone());
+            container.write()
+        }
+        _ => image.encode(format, &memory_map),
+    };
+    if let Err(err) = fs::write(&output_path, &contents) {
+        out.error(format!(
+            "Failed to write {}: {}",
+            output_path.display(),
+            err
+        ));
+        return;
     }
-    pb.finish_with_message("downloaded");
-    let data = downloader.take_data();
+    if let Err(err) = PartialFile::remove(&output_path) {
+        out.message(format!("Failed to remove partial download: {}", err));
+    }
+    out.message(format!("Downloaded to {}", output_path.display()));
 
-    // Get output path
-    let output_path = matches
-        .value_of("OUTPUT")
-        .map(|s| s.to_string())
-        .unwrap_or_else(|| vin + ".bin");
+    if let Err(err) = metadata.save(&output_path) {
+        out.message(format!("Failed to write metadata: {}", err));
+    }
+    out.message(format!("SHA-256: {}", metadata.sha256));
+    out.event(json!({
+        "event": "complete",
+        "path": output_path,
+        "metadata": RomMetadata::sidecar_path(&output_path),
+        "format": format.extension(),
+        "size": image.data.len(),
+        "sha256": metadata.sha256,
+    }));
+}
+
+/// Prints the installed J2534 drivers, numbered for `--device`
+fn print_drivers(out: Output) {
+    let drivers = match passthru::installed_drivers() {
+        Ok(drivers) => drivers,
+        Err(err) => {
+            out.error(err);
+            return;
+        }
+    };
+    for (index, driver) in drivers.iter().enumerate() {
+        out.message(format!(
+            "{}: {} ({})\n   {}",
+            index, driver.name, driver.vendor, driver.path
+        ));
+        out.event(json!({
+            "event": "device",
+            "index": index,
+            "name": driver.name,
+            "vendor": driver.vendor,
+            "path": driver.path,
+        }));
+    }
+}
 
-    fs::write(&output_path, &data).unwrap();
-    println!("Downloaded to {}", output_path);
+/// Uploads the kernel and reads through it, reporting progress like the
+/// downloader
+fn read_through_kernel<T: Transfer>(
+    transfer: &mut T,
+    progress: &mut CliProgress,
+) -> Result<(), MzrError> {
+    progress.on_phase_change(Phase::Uploading);
+    transfer.start()?;
+    progress.on_phase_change(Phase::Transferring);
+    for result in TransferIter::new(transfer) {
+        progress.on_progress(&result?);
+    }
+    Ok(())
+}
+
+/// Reports progress with a progress bar, or as JSON events
+struct CliProgress {
+    pb: ProgressBar,
+    out: Output,
+}
+
+impl ProgressObserver for CliProgress {
+    fn on_progress(&mut self, progress: &Progress) {
+        self.pb.set_position(progress.position as u64);
+        self.pb.set_message(&progress.to_string());
+        self.out.event(json!({
+            "event": "progress",
+            "position": progress.position,
+            "total": progress.total,
+            "rate": progress.rate,
+            "average_rate": progress.average_rate,
+            "eta": progress.eta.map(|eta| eta.as_secs_f64()),
+        }));
+    }
+
+    fn on_phase_change(&mut self, phase: Phase) {
+        match phase {
+            Phase::Authenticating => self.out.message("Authenticating..."),
+            Phase::Uploading => self.out.message("Uploading kernel..."),
+            Phase::Verifying => self.out.message("Spot-checking blocks..."),
+            _ => (),
+        }
+        self.out.event(json!({ "event": "phase", "phase": phase }));
+    }
+
+    fn on_retry(&mut self, attempt: usize, error: &MzrError) {
+        if !self.out.is_json() {
+            self.pb
+                .println(format!("Retrying (attempt {}): {}", attempt, error));
+        }
+        self.out
+            .event(json!({ "event": "retry", "attempt": attempt, "error": error.to_string() }));
+    }
 }
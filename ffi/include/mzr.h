@@ -0,0 +1,84 @@
+/*
+ * C interface to the mzr library, for GUIs embedding the tools.
+ *
+ * Functions returning int return MZR_OK on success or MZR_ERROR on failure,
+ * in which case mzr_last_error() describes the error. Strings are UTF-8.
+ */
+
+#ifndef MZR_H
+#define MZR_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define MZR_OK 0
+#define MZR_ERROR (-1)
+
+/* Connection to an ECU through a J2534 adapter */
+typedef struct MzrSession MzrSession;
+
+/* Called with the number of bytes transferred and the total */
+typedef void (*mzr_progress_cb)(void *user_data, size_t position, size_t total);
+
+/* Called from the logging thread with each value read */
+typedef void (*mzr_log_cb)(void *user_data, uint16_t pid, const uint8_t *data, size_t len);
+
+/*
+ * Returns the message of the last error on this thread, or NULL. The string
+ * is valid until the next call on this thread.
+ */
+const char *mzr_last_error(void);
+
+/*
+ * Connects to the ECU through the J2534 adapter `device`, a name or index,
+ * and the built-in profile of vehicle `model`. Either may be NULL to use the
+ * configured value, and the profile is detected from the VIN if no model is
+ * known. Returns NULL on failure.
+ */
+MzrSession *mzr_connect(const char *device, const char *model);
+
+/* Stops logging and closes the session and adapter. Accepts NULL. */
+void mzr_disconnect(MzrSession *session);
+
+/* Downloads the ROM to `path`, calling `progress` (may be NULL) as blocks are read */
+int mzr_download(MzrSession *session, const char *path, mzr_progress_cb progress,
+                 void *user_data);
+
+/*
+ * Flashes the image at `path` and verifies it, calling `progress` (may be
+ * NULL) as blocks are written. Refuses to start if the battery voltage is
+ * below the configured minimum, or if the file is a package locked to
+ * another vehicle. Files not signed by one of the configured `trusted_keys`
+ * are refused unless `allow_unsigned` is nonzero.
+ */
+int mzr_flash(MzrSession *session, const char *path, int allow_unsigned,
+              mzr_progress_cb progress, void *user_data);
+
+/*
+ * Checks the checksums of the ROM or .mzrrom container at `path`, correcting
+ * them in place if `correct` is nonzero. Raw ROMs use the layout of vehicle
+ * `model`, or MZR-DISI if NULL. Returns 1 if the checksums are valid, 0 if
+ * not or MZR_ERROR.
+ */
+int mzr_checksum(const char *path, const char *model, int correct);
+
+/*
+ * Starts reading the `count` PIDs in `pids` in a loop on another thread,
+ * calling `callback` with each value. Other operations fail until logging is
+ * stopped.
+ */
+int mzr_log_start(MzrSession *session, const uint16_t *pids, size_t count,
+                  mzr_log_cb callback, void *user_data);
+
+/* Stops logging. Fails with the error that stopped the logger early, if any. */
+int mzr_log_stop(MzrSession *session);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* MZR_H */
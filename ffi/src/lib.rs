@@ -0,0 +1,556 @@
+//! C interface for tuning GUIs, declared in `include/mzr.h`.
+//!
+//! Functions returning `int` return [`MZR_OK`] on success or [`MZR_ERROR`] on
+//! failure, in which case [`mzr_last_error`] describes the error. Panics are
+//! caught and reported as errors.
+
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::os::raw::{c_char, c_int, c_void};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+
+use mzr::checksum;
+use mzr::config::Config;
+use mzr::container::RomContainer;
+use mzr::image::{Image, ImageFormat};
+use mzr::memory_map::MzrMemoryMap;
+use mzr::passthru::{self, PassThruChannel};
+use mzr::profile::EcuProfile;
+use mzr::progress::{Progress, ProgressObserver};
+use mzr::session::KEEP_ALIVE_INTERVAL;
+use mzr::signing;
+use mzr::timeout::TimeoutProfile;
+use mzr::voltage::PassThruVoltage;
+use mzr::MzrBus;
+use obd::Uds;
+
+#[cfg(feature = "python")]
+mod python;
+
+pub const MZR_OK: c_int = 0;
+pub const MZR_ERROR: c_int = -1;
+
+/// Called with the number of bytes transferred and the total
+pub type MzrProgressCallback =
+    Option<extern "C" fn(user_data: *mut c_void, position: usize, total: usize)>;
+
+/// Called from the logging thread with each value read
+pub type MzrLogCallback =
+    Option<extern "C" fn(user_data: *mut c_void, pid: u16, data: *const u8, len: usize)>;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_error(message: String) {
+    let message = CString::new(message.replace('\0', " ")).unwrap();
+    LAST_ERROR.with(|error| *error.borrow_mut() = Some(message));
+}
+
+/// Runs `f`, turning errors and panics into [`MZR_ERROR`]
+fn call<F: FnOnce() -> Result<c_int, String>>(f: F) -> c_int {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(code)) => code,
+        Ok(Err(message)) => {
+            set_error(message);
+            MZR_ERROR
+        }
+        Err(_) => {
+            set_error("internal error".to_string());
+            MZR_ERROR
+        }
+    }
+}
+
+/// Reads an optional string argument
+unsafe fn str_arg<'a>(s: *const c_char, name: &str) -> Result<Option<&'a str>, String> {
+    if s.is_null() {
+        return Ok(None);
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map(Some)
+        .map_err(|_| format!("{} is not valid UTF-8", name))
+}
+
+unsafe fn session_arg<'a>(session: *mut MzrSession) -> Result<&'a mut MzrSession, String> {
+    session
+        .as_mut()
+        .ok_or_else(|| "session is NULL".to_string())
+}
+
+/// User data passed back to callbacks, possibly from another thread
+#[derive(Copy, Clone)]
+struct UserData(*mut c_void);
+
+unsafe impl Send for UserData {}
+
+struct CallbackProgress {
+    callback: MzrProgressCallback,
+    user_data: UserData,
+}
+
+impl ProgressObserver for CallbackProgress {
+    fn on_progress(&mut self, progress: &Progress) {
+        if let Some(callback) = self.callback {
+            callback(self.user_data.0, progress.position, progress.total);
+        }
+    }
+}
+
+/// J2534 library and the device opened with it. The device borrows the
+/// library, so both are boxed and freed in reverse order.
+struct Adapter {
+    interface: *mut j2534::Interface,
+    device: *mut j2534::Device<'static>,
+}
+
+impl Adapter {
+    fn open(path: &str) -> Result<Adapter, String> {
+        let interface = j2534::Interface::new(path)
+            .map_err(|err| format!("failed to load {}: {}", path, err))?;
+        let mut adapter = Adapter {
+            interface: Box::into_raw(Box::new(interface)),
+            device: ptr::null_mut(),
+        };
+        // The interface lives until the adapter is dropped
+        let interface = unsafe { &*adapter.interface };
+        let device = passthru::traced("PassThruOpen", format_args!(""), || interface.open_any())
+            .map_err(|err| format!("failed to open the adapter: {}", err))?;
+        adapter.device = Box::into_raw(Box::new(device));
+        Ok(adapter)
+    }
+
+    fn device(&self) -> &'static j2534::Device<'static> {
+        unsafe { &*self.device }
+    }
+}
+
+impl Drop for Adapter {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.device.is_null() {
+                drop(Box::from_raw(self.device));
+            }
+            drop(Box::from_raw(self.interface));
+        }
+    }
+}
+
+/// Thread reading PIDs until stopped
+struct Logger {
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<Result<(), String>>,
+}
+
+impl Logger {
+    fn stop(self) -> Result<(), String> {
+        self.stop.store(true, Ordering::Relaxed);
+        self.thread
+            .join()
+            .unwrap_or_else(|_| Err("internal error".to_string()))
+    }
+}
+
+/// Channel shared with the logging thread. The J2534 types aren't `Sync`
+/// since the API doesn't promise thread safety, but the mutex ensures only
+/// one thread calls into the library at a time.
+struct SharedChannel(Mutex<PassThruChannel<'static>>);
+
+unsafe impl Send for SharedChannel {}
+unsafe impl Sync for SharedChannel {}
+
+/// Connection to an ECU through a J2534 adapter. Fields are dropped in
+/// order, so the logger and channel are gone before the adapter is closed.
+pub struct MzrSession {
+    logger: Option<Logger>,
+    channel: Arc<SharedChannel>,
+    profile: EcuProfile,
+    min_voltage: f32,
+    trusted_keys: Vec<String>,
+    adapter: Adapter,
+}
+
+impl MzrSession {
+    fn connect(device: Option<&str>, model: Option<&str>) -> Result<MzrSession, String> {
+        let config = Config::load().map_err(|err| err.to_string())?;
+        let selected = EcuProfile::select(None, model, &config).map_err(|err| err.to_string())?;
+        let (request_id, response_id) = match selected {
+            Some(ref profile) => (profile.request_id, profile.response_id),
+            None => (config.request_id, config.response_id),
+        };
+
+        let selector = device.or(config.device.as_deref());
+        let driver = passthru::select_driver(config.library.as_deref(), selector)
+            .map_err(|err| err.to_string())?;
+        let adapter = Adapter::open(&driver.path)?;
+        let mut channel =
+            PassThruChannel::new(adapter.device(), 500000, TimeoutProfile::default().request)
+                .map_err(|err| err.to_string())?;
+        channel
+            .set_filter(request_id, response_id)
+            .map_err(|err| err.to_string())?;
+        // Not every adapter supports periodic messages. The library keeps
+        // the session alive itself during long operations.
+        let _ = channel.start_keep_alive(request_id, KEEP_ALIVE_INTERVAL);
+
+        let profile = match selected {
+            Some(profile) => profile,
+            None => EcuProfile::detect(channel.query_vin(request_id).ok().as_deref(), &config),
+        };
+        Ok(MzrSession {
+            logger: None,
+            channel: Arc::new(SharedChannel(Mutex::new(channel))),
+            profile,
+            min_voltage: config.min_voltage,
+            trusted_keys: config.trusted_keys,
+            adapter,
+        })
+    }
+
+    /// Locks the channel for an operation, which can't run while logging
+    fn channel(&self) -> Result<MutexGuard<'_, PassThruChannel<'static>>, String> {
+        if self.logger.is_some() {
+            return Err("logging is running".to_string());
+        }
+        self.channel
+            .0
+            .lock()
+            .map_err(|_| "channel is unusable after a failure".to_string())
+    }
+
+    fn download(&self, path: &Path, observer: &mut CallbackProgress) -> Result<(), String> {
+        let mut channel = self.channel()?;
+        let mut downloader = self.profile.downloader().build(&mut *channel);
+        downloader.run(observer).map_err(|err| err.to_string())?;
+        fs::write(path, downloader.take_data())
+            .map_err(|err| format!("failed to write {}: {}", path.display(), err))
+    }
+
+    fn flash(
+        &self,
+        path: &Path,
+        allow_unsigned: bool,
+        observer: &mut CallbackProgress,
+    ) -> Result<(), String> {
+        if !self.profile.flash_supported {
+            return Err(format!(
+                "flashing is not supported for the {} profile",
+                self.profile.name
+            ));
+        }
+        let data =
+            fs::read(path).map_err(|err| format!("failed to read {}: {}", path.display(), err))?;
+        let container = if RomContainer::is_container(&data) {
+            Some(RomContainer::read(&data).map_err(|err| err.to_string())?)
+        } else {
+            None
+        };
+        let mut channel = self.channel()?;
+        let vin = channel.query_vin(self.profile.request_id).ok();
+        signing::check_package(
+            container.as_ref(),
+            &self.trusted_keys,
+            vin.as_deref(),
+            allow_unsigned,
+        )
+        .map_err(|err| err.to_string())?;
+        let image = Image::parse(
+            &data,
+            ImageFormat::from_path(path),
+            &self.profile.memory_map,
+        )
+        .map_err(|err| err.to_string())?;
+        let mut programmer =
+            self.profile
+                .programmer()
+                .verify(true)
+                .build(&mut *channel, image.offset, image.data);
+        programmer.set_voltage_monitor(
+            PassThruVoltage::new(self.adapter.device()),
+            self.min_voltage,
+        );
+        programmer.run(observer).map_err(|err| err.to_string())
+    }
+
+    fn start_logging(
+        &mut self,
+        pids: Vec<u16>,
+        callback: MzrLogCallback,
+        user_data: UserData,
+    ) -> Result<(), String> {
+        drop(self.channel()?);
+        let channel = Arc::clone(&self.channel);
+        let request_id = self.profile.request_id;
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = Arc::clone(&stop);
+        let thread = thread::spawn(move || {
+            while !stopped.load(Ordering::Relaxed) {
+                let mut channel = channel
+                    .0
+                    .lock()
+                    .map_err(|_| "channel is unusable after a failure".to_string())?;
+                for &pid in &pids {
+                    let data = channel
+                        .read_identifier(request_id, pid)
+                        .map_err(|err| format!("failed to read PID {:04X}: {}", pid, err))?;
+                    if let Some(callback) = callback {
+                        callback(user_data.0, pid, data.as_ptr(), data.len());
+                    }
+                }
+            }
+            Ok(())
+        });
+        self.logger = Some(Logger { stop, thread });
+        Ok(())
+    }
+}
+
+/// Checks the checksums of the ROM or .mzrrom container at `path`,
+/// correcting them in place if `correct` is set. Returns whether the
+/// checksums are valid afterwards.
+fn check_rom(path: &Path, model: Option<&str>, correct: bool) -> Result<bool, String> {
+    let contents =
+        fs::read(path).map_err(|err| format!("failed to read {}: {}", path.display(), err))?;
+    let (map, mut container, mut data) = if RomContainer::is_container(&contents) {
+        let container = RomContainer::read(&contents).map_err(|err| err.to_string())?;
+        let map = MzrMemoryMap::by_name(&container.header.memory_map)
+            .ok_or_else(|| format!("unknown memory map {}", container.header.memory_map))?;
+        if container.header.offset != map.flash().start {
+            return Err("not a full ROM".to_string());
+        }
+        let data = container.data.clone();
+        (map, Some(container), data)
+    } else {
+        let map = match model {
+            Some(model) => {
+                EcuProfile::for_model(model)
+                    .ok_or_else(|| format!("unknown model {}", model))?
+                    .memory_map
+            }
+            None => MzrMemoryMap::default(),
+        };
+        (map, None, contents)
+    };
+
+    let report = checksum::checksum_report(&data, &map).ok_or("not a full ROM")?;
+    if report.valid || !correct {
+        return Ok(report.valid);
+    }
+    if !checksum::correct_rom_checksum(&mut data, &map) {
+        return Ok(false);
+    }
+    let contents = match container {
+        Some(ref mut container) => {
+            container.data = data;
+            container.write()
+        }
+        None => data,
+    };
+    fs::write(path, contents)
+        .map_err(|err| format!("failed to write {}: {}", path.display(), err))?;
+    Ok(true)
+}
+
+/// Returns the message of the last error on this thread, or NULL. The
+/// string is valid until the next call on this thread.
+#[no_mangle]
+pub extern "C" fn mzr_last_error() -> *const c_char {
+    LAST_ERROR.with(|error| match *error.borrow() {
+        Some(ref message) => message.as_ptr(),
+        None => ptr::null(),
+    })
+}
+
+/// Connects to the ECU through the J2534 adapter `device`, a name or index,
+/// and the built-in profile of vehicle `model`. Either may be NULL to use
+/// the configured value, and the profile is detected from the VIN if no
+/// model is known. Returns NULL on failure.
+///
+/// # Safety
+/// `device` and `model` must be NULL or NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn mzr_connect(
+    device: *const c_char,
+    model: *const c_char,
+) -> *mut MzrSession {
+    let mut session = ptr::null_mut();
+    call(|| {
+        let connected = MzrSession::connect(str_arg(device, "device")?, str_arg(model, "model")?)?;
+        session = Box::into_raw(Box::new(connected));
+        Ok(MZR_OK)
+    });
+    session
+}
+
+/// Stops logging and closes the session and adapter
+///
+/// # Safety
+/// `session` must be NULL or returned by [`mzr_connect`] and not yet
+/// disconnected.
+#[no_mangle]
+pub unsafe extern "C" fn mzr_disconnect(session: *mut MzrSession) {
+    if session.is_null() {
+        return;
+    }
+    let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+        let mut session = Box::from_raw(session);
+        if let Some(logger) = session.logger.take() {
+            let _ = logger.stop();
+        }
+    }));
+}
+
+/// Downloads the ROM to `path`, calling `progress` as blocks are read
+///
+/// # Safety
+/// `session` must be a connected session and `path` a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn mzr_download(
+    session: *mut MzrSession,
+    path: *const c_char,
+    progress: MzrProgressCallback,
+    user_data: *mut c_void,
+) -> c_int {
+    call(|| {
+        let session = session_arg(session)?;
+        let path = str_arg(path, "path")?.ok_or("path is NULL")?;
+        let mut observer = CallbackProgress {
+            callback: progress,
+            user_data: UserData(user_data),
+        };
+        session.download(Path::new(path), &mut observer)?;
+        Ok(MZR_OK)
+    })
+}
+
+/// Flashes the image at `path` and verifies it, calling `progress` as
+/// blocks are written. Refuses to start if the battery voltage is below
+/// the configured minimum, or if the file is a package locked to another
+/// vehicle. Files not signed by one of the configured `trusted_keys` are
+/// refused unless `allow_unsigned` is nonzero.
+///
+/// # Safety
+/// `session` must be a connected session and `path` a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn mzr_flash(
+    session: *mut MzrSession,
+    path: *const c_char,
+    allow_unsigned: c_int,
+    progress: MzrProgressCallback,
+    user_data: *mut c_void,
+) -> c_int {
+    call(|| {
+        let session = session_arg(session)?;
+        let path = str_arg(path, "path")?.ok_or("path is NULL")?;
+        let mut observer = CallbackProgress {
+            callback: progress,
+            user_data: UserData(user_data),
+        };
+        session.flash(Path::new(path), allow_unsigned != 0, &mut observer)?;
+        Ok(MZR_OK)
+    })
+}
+
+/// Checks the checksums of the ROM or .mzrrom container at `path`,
+/// correcting them in place if `correct` is nonzero. Raw ROMs use the
+/// layout of vehicle `model`, or MZR-DISI if NULL. Returns 1 if the
+/// checksums are valid, 0 if not or [`MZR_ERROR`].
+///
+/// # Safety
+/// `path` must be a NUL-terminated string and `model` NULL or one.
+#[no_mangle]
+pub unsafe extern "C" fn mzr_checksum(
+    path: *const c_char,
+    model: *const c_char,
+    correct: c_int,
+) -> c_int {
+    call(|| {
+        let path = str_arg(path, "path")?.ok_or("path is NULL")?;
+        let valid = check_rom(Path::new(path), str_arg(model, "model")?, correct != 0)?;
+        Ok(valid as c_int)
+    })
+}
+
+/// Starts reading the `count` PIDs in `pids` in a loop on another thread,
+/// calling `callback` with each value. Other operations fail until logging
+/// is stopped.
+///
+/// # Safety
+/// `session` must be a connected session and `pids` point to `count` PIDs.
+/// `callback` must be safe to call from another thread with `user_data`.
+#[no_mangle]
+pub unsafe extern "C" fn mzr_log_start(
+    session: *mut MzrSession,
+    pids: *const u16,
+    count: usize,
+    callback: MzrLogCallback,
+    user_data: *mut c_void,
+) -> c_int {
+    call(|| {
+        let session = session_arg(session)?;
+        if pids.is_null() || count == 0 {
+            return Err("no PIDs to log".to_string());
+        }
+        let pids = slice::from_raw_parts(pids, count).to_vec();
+        session.start_logging(pids, callback, UserData(user_data))?;
+        Ok(MZR_OK)
+    })
+}
+
+/// Stops logging. Fails with the error that stopped the logger early, if
+/// any.
+///
+/// # Safety
+/// `session` must be a connected session.
+#[no_mangle]
+pub unsafe extern "C" fn mzr_log_stop(session: *mut MzrSession) -> c_int {
+    call(|| {
+        let session = session_arg(session)?;
+        match session.logger.take() {
+            Some(logger) => logger.stop()?,
+            None => return Err("logging is not running".to_string()),
+        }
+        Ok(MZR_OK)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_and_corrects_checksums() {
+        let path = std::env::temp_dir().join(format!("mzr-ffi-{}.bin", std::process::id()));
+        let map = MzrMemoryMap::default();
+        let mut rom = vec![0u8; map.flash_size()];
+        rom[0x2000] = 0x5A;
+        fs::write(&path, &rom).unwrap();
+        let c_path = CString::new(path.to_str().unwrap()).unwrap();
+
+        let valid = checksum::checksum_valid(&rom, &map) as c_int;
+        unsafe {
+            assert_eq!(mzr_checksum(c_path.as_ptr(), ptr::null(), 0), valid);
+            assert_eq!(mzr_checksum(c_path.as_ptr(), ptr::null(), 1), 1);
+            assert_eq!(mzr_checksum(c_path.as_ptr(), ptr::null(), 0), 1);
+
+            let model = CString::new("no such car").unwrap();
+            assert_eq!(mzr_checksum(c_path.as_ptr(), model.as_ptr(), 0), MZR_ERROR);
+            let error = CStr::from_ptr(mzr_last_error()).to_str().unwrap();
+            assert_eq!(error, "unknown model no such car");
+
+            assert_eq!(mzr_checksum(ptr::null(), ptr::null(), 0), MZR_ERROR);
+            assert_eq!(mzr_log_stop(ptr::null_mut()), MZR_ERROR);
+            let error = CStr::from_ptr(mzr_last_error()).to_str().unwrap();
+            assert_eq!(error, "session is NULL");
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,205 @@
+//! Python bindings, built with the `python` feature. The module exposes the
+//! ROM checksum and identification functions, the seed/key algorithm and a
+//! session for reading PIDs.
+
+// The pyo3 macros convert errors that are already `PyErr`
+#![allow(clippy::useless_conversion)]
+
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict};
+
+use mzr::calibration::{self, Calibration};
+use mzr::checksum;
+use mzr::memory_map::MzrMemoryMap;
+use mzr::profile::EcuProfile;
+
+use mzr::MzrBus;
+
+use crate::MzrSession;
+
+impl MzrSession {
+    /// Reads the value of `pid`
+    fn read_identifier(&self, pid: u16) -> Result<Vec<u8>, String> {
+        self.channel()?
+            .read_identifier(self.profile.request_id, pid)
+            .map_err(|err| format!("failed to read PID {:04X}: {}", pid, err))
+    }
+}
+
+/// Returns the profile of vehicle `model`, or MZR-DISI if `None`
+fn profile(model: Option<&str>) -> PyResult<EcuProfile> {
+    match model {
+        Some(model) => EcuProfile::for_model(model)
+            .ok_or_else(|| PyValueError::new_err(format!("unknown model {}", model))),
+        None => Ok(EcuProfile::default()),
+    }
+}
+
+fn memory_map(model: Option<&str>) -> PyResult<MzrMemoryMap> {
+    profile(model).map(|profile| profile.memory_map)
+}
+
+fn calibration_dict<'py>(
+    py: Python<'py>,
+    calibration: &Calibration,
+) -> PyResult<Bound<'py, PyDict>> {
+    let dict = PyDict::new_bound(py);
+    dict.set_item("id", calibration.id)?;
+    dict.set_item("vehicle", calibration.vehicle)?;
+    dict.set_item("market", calibration.market)?;
+    dict.set_item("transmission", calibration.transmission)?;
+    dict.set_item("model", calibration.model)?;
+    Ok(dict)
+}
+
+/// Returns the checksum regions of a full ROM and whether they are valid,
+/// or None if `rom` isn't a full ROM for `model`
+#[pyfunction]
+#[pyo3(signature = (rom, model=None))]
+fn checksum_report<'py>(
+    py: Python<'py>,
+    rom: &[u8],
+    model: Option<&str>,
+) -> PyResult<Option<Bound<'py, PyDict>>> {
+    let report = match checksum::checksum_report(rom, &memory_map(model)?) {
+        Some(report) => report,
+        None => return Ok(None),
+    };
+    let regions = report
+        .regions
+        .iter()
+        .map(|region| {
+            let dict = PyDict::new_bound(py);
+            dict.set_item("start", region.start)?;
+            dict.set_item("end", region.end)?;
+            dict.set_item("checksum", region.checksum)?;
+            dict.set_item("target", region.target)?;
+            dict.set_item("valid", region.valid())?;
+            Ok(dict)
+        })
+        .collect::<PyResult<Vec<_>>>()?;
+    let dict = PyDict::new_bound(py);
+    dict.set_item("memory_map", report.memory_map)?;
+    dict.set_item("regions", regions)?;
+    dict.set_item("valid", report.valid)?;
+    Ok(Some(dict))
+}
+
+/// Returns true if the checksums of the full ROM `rom` are valid
+#[pyfunction]
+#[pyo3(signature = (rom, model=None))]
+fn checksum_valid(rom: &[u8], model: Option<&str>) -> PyResult<bool> {
+    Ok(checksum::checksum_valid(rom, &memory_map(model)?))
+}
+
+/// Returns a copy of the full ROM `rom` with its checksums corrected
+#[pyfunction]
+#[pyo3(signature = (rom, model=None))]
+fn correct_checksum<'py>(
+    py: Python<'py>,
+    rom: &[u8],
+    model: Option<&str>,
+) -> PyResult<Bound<'py, PyBytes>> {
+    let mut rom = rom.to_vec();
+    if !checksum::correct_rom_checksum(&mut rom, &memory_map(model)?) {
+        return Err(PyValueError::new_err("checksums can't be corrected"));
+    }
+    Ok(PyBytes::new_bound(py, &rom))
+}
+
+/// Identifies the vehicle a ROM was calibrated for from its calibration ID
+#[pyfunction]
+fn identify<'py>(py: Python<'py>, rom: &[u8]) -> PyResult<Option<Bound<'py, PyDict>>> {
+    calibration::identify(rom)
+        .map(|calibration| calibration_dict(py, calibration))
+        .transpose()
+}
+
+/// Looks up a calibration ID reported by an ECU
+#[pyfunction]
+fn lookup_calibration<'py>(
+    py: Python<'py>,
+    calibration_id: &str,
+) -> PyResult<Option<Bound<'py, PyDict>>> {
+    calibration::lookup(calibration_id)
+        .map(|calibration| calibration_dict(py, calibration))
+        .transpose()
+}
+
+/// Computes the security access key for `seed`
+#[pyfunction]
+#[pyo3(signature = (seed, model=None))]
+fn key<'py>(py: Python<'py>, seed: &[u8], model: Option<&str>) -> PyResult<Bound<'py, PyBytes>> {
+    let key = profile(model)?.key_algorithm.key(seed);
+    Ok(PyBytes::new_bound(py, &key))
+}
+
+/// Connection to an ECU through a J2534 adapter, for reading PIDs
+#[pyclass(unsendable)]
+struct Session {
+    session: Option<MzrSession>,
+}
+
+impl Session {
+    fn session(&self) -> PyResult<&MzrSession> {
+        self.session
+            .as_ref()
+            .ok_or_else(|| PyRuntimeError::new_err("session is closed"))
+    }
+}
+
+#[pymethods]
+impl Session {
+    /// Connects like `mzr_connect`, using the configured adapter and model
+    /// when not given
+    #[new]
+    #[pyo3(signature = (device=None, model=None))]
+    fn new(device: Option<&str>, model: Option<&str>) -> PyResult<Session> {
+        let session = MzrSession::connect(device, model).map_err(PyRuntimeError::new_err)?;
+        Ok(Session {
+            session: Some(session),
+        })
+    }
+
+    /// Name of the ECU profile in use
+    #[getter]
+    fn profile(&self) -> PyResult<String> {
+        Ok(self.session()?.profile.name.clone())
+    }
+
+    /// Reads the value of `pid`
+    fn read<'py>(&self, py: Python<'py>, pid: u16) -> PyResult<Bound<'py, PyBytes>> {
+        let data = self
+            .session()?
+            .read_identifier(pid)
+            .map_err(PyRuntimeError::new_err)?;
+        Ok(PyBytes::new_bound(py, &data))
+    }
+
+    /// Reads each of `pids`, returning a dict from PID to value
+    fn read_pids<'py>(&self, py: Python<'py>, pids: Vec<u16>) -> PyResult<Bound<'py, PyDict>> {
+        let dict = PyDict::new_bound(py);
+        for pid in pids {
+            dict.set_item(pid, self.read(py, pid)?)?;
+        }
+        Ok(dict)
+    }
+
+    /// Closes the session and adapter
+    fn close(&mut self) {
+        self.session = None;
+    }
+}
+
+#[pymodule]
+fn mzr_ffi(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(checksum_report, m)?)?;
+    m.add_function(wrap_pyfunction!(checksum_valid, m)?)?;
+    m.add_function(wrap_pyfunction!(correct_checksum, m)?)?;
+    m.add_function(wrap_pyfunction!(identify, m)?)?;
+    m.add_function(wrap_pyfunction!(lookup_calibration, m)?)?;
+    m.add_function(wrap_pyfunction!(key, m)?)?;
+    m.add_class::<Session>()?;
+    Ok(())
+}
@@ -1,73 +1,1098 @@
 //! This example queries a VIN using a PassThru device
 
-use anyhow::Result;
-use obd::{PassThruIsoTp, Uds};
 use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use mzr::{DownloadState, Downloader, MzrBus, Programmer, ProgrammerState};
+use obd::Uds;
+
+use mzr::backup::{self, Backup};
+use mzr::calibration;
+use mzr::cancel::CancelToken;
+use mzr::capture::{Capture, CaptureWriter};
+use mzr::config::Config;
+use mzr::container::RomContainer;
+use mzr::diff::{self, SectorUpdate};
+use mzr::event::Event;
+use mzr::hash;
+use mzr::history::{FlashRecord, FlashResult, History};
+use mzr::image::{Image, ImageFormat};
+use mzr::iter::TransferIter;
+use mzr::kernel::{KernelTransfer, RamKernel, DEFAULT_KERNEL_ADDRESS};
+use mzr::memory_map::Region;
+use mzr::metadata::RomMetadata;
+use mzr::output::Output;
+use mzr::passthru::PassThruChannel;
+use mzr::pause::PauseToken;
+use mzr::profile::EcuProfile;
+use mzr::progress::{Phase, Progress, ProgressObserver};
+use mzr::reconnect::Reconnecting;
+use mzr::selftest::{Outcome, SelfTest};
+use mzr::session::KEEP_ALIVE_INTERVAL;
+use mzr::signing::{self, PackageError};
+use mzr::timeout::{SetTimeout, TimeoutProfile};
+use mzr::voltage::PassThruVoltage;
+use mzr::{passthru, MzrBus, MzrError, Transfer};
 
 use clap::clap_app;
 use indicatif::{ProgressBar, ProgressStyle};
+use serde_json::json;
+use tracing_subscriber::filter::{LevelFilter, Targets};
+use tracing_subscriber::fmt;
+use tracing_subscriber::prelude::*;
+
+pub fn main() -> ExitCode {
+    run();
+    Output::exit_code()
+}
 
-pub fn main() {
+fn run() {
     let matches = clap_app!(myapp =>
         (version: "1.0")
         (author: "Jacob Manning <jjacob.manning@gmail.com>")
         (about: "Flashes ROM to an MZR-DISI ECU")
-        (@arg passthru: -p --passthru +takes_value "PassThru device to use when connecting to the ECU")
-        (@arg model: -m --model +takes_value "Vehicle model")
-        (@arg INPUT: +required "Input file")
+        (@arg device: -d --device alias("passthru") +takes_value "J2534 device to use when connecting to the ECU (name or index)")
+        (@arg library: --library +takes_value "J2534 library (DLL) to load instead of the installed drivers")
+        (@arg model: -m --model +takes_value "Vehicle model selecting the ECU profile, e.g. ms6 or ms3-gen2. Detected from the VIN by default")
+        (@arg json: --json "Prints machine-readable JSON output")
+        (@arg debug_adapter: --("debug-adapter") "Logs every J2534 call with its parameters and return code to stderr")
+        (@arg profile: -p --profile +takes_value "ECU profile file describing CAN IDs, sessions, security access and memory layout")
+        (@arg verify: --verify "Reads back the flashed image and compares it")
+        (@arg force: --force "Flashes even if the engine is running")
+        (@arg recover: --recover "Reflashes an ECU left unresponsive by a failed flash")
+        (@arg no_backup: --("no-backup") "Skips downloading a backup of the current ROM before flashing")
+        (@arg min_voltage: --("min-voltage") +takes_value "Minimum battery voltage required for flashing")
+        (@arg allow_unsigned: --("allow-unsigned") "Flashes files that aren't signed by a trusted key")
+        (@arg sha256: --sha256 +takes_value "Refuses to flash unless the input file has this SHA-256 hash")
+        (@arg kernel: --kernel +takes_value "RAM kernel to program through, needed for --delta")
+        (@arg kernel_address: --("kernel-address") +takes_value requires("kernel") "RAM address to load the kernel to, in hex (default 0xFFFF6000)")
+        (@arg delta: --delta requires("kernel") conflicts_with("recover") conflicts_with("verify") "Erases and programs only the flash sectors that differ from the current ROM")
+        (@arg reference: --reference +takes_value requires("delta") "Dump of the current ROM to compare against instead of the backup taken before flashing")
+        (@arg capture: --capture +takes_value "Records the bus traffic to this file (candump log, or PCAP if it ends in .pcap)")
+        (@arg INPUT: +required "Input file (raw .bin, Intel HEX, S-record or .mzrrom)")
+        (@setting SubcommandsNegateReqs)
+        (@subcommand devices =>
+            (about: "Lists installed J2534 devices"))
+        (@subcommand history =>
+            (about: "Lists past flashes")
+            (@arg VIN: "Only lists flashes of this vehicle"))
+        (@subcommand backups =>
+            (about: "Lists stored ROM dumps, or flashes one back to the ECU")
+            (@arg restore: --restore +takes_value "Flashes the backup with this number or SHA-256 prefix"))
+        (@subcommand selftest =>
+            (about: "Checks that the adapter works before risking a flash"))
     )
     .get_matches();
 
-    // Get a list of interfaces
-    let device = match j2534::drivers().unwrap().into_iter().next() {
-        Some(device) => device,
-        None => {
-            println!("No J2534 interfaces found");
+    let out = Output::new(matches.is_present("json"));
+    if matches.subcommand_matches("devices").is_some() {
+        print_drivers(out);
+        return;
+    }
+    // --debug-adapter shows the J2534 calls traced by mzr on stderr
+    if matches.is_present("debug_adapter") {
+        tracing_subscriber::registry()
+            .with(fmt::layer().with_writer(io::stderr))
+            .with(Targets::new().with_target("j2534", LevelFilter::TRACE))
+            .init();
+    }
+
+    if let Some(matches) = matches.subcommand_matches("history") {
+        print_history(out, matches.value_of("VIN"));
+        return;
+    }
+
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(err) => {
+            out.error(err);
+            return;
+        }
+    };
+
+    // The ECU is described by a profile file, or by the built-in profile of
+    // the vehicle model with the configured CAN IDs. Without either, the
+    // profile is detected from the VIN once connected.
+    let selected = match EcuProfile::select(
+        matches.value_of("profile"),
+        matches.value_of("model"),
+        &config,
+    ) {
+        Ok(profile) => profile,
+        Err(err) => {
+            out.error(err);
+            return;
+        }
+    };
+
+    // List stored dumps, or pick one to restore in place of INPUT
+    let mut restore = None;
+    if let Some(matches) = matches.subcommand_matches("backups") {
+        let dir = config
+            .output_dir
+            .clone()
+            .unwrap_or_else(|| PathBuf::from("."));
+        let backups = match backup::scan(&dir) {
+            Ok(backups) => backups,
+            Err(err) => {
+                out.error(format!("Failed to read {}: {}", dir.display(), err));
+                return;
+            }
+        };
+        match matches.value_of("restore") {
+            Some(selector) => match backup::select(&backups, selector) {
+                Some(backup) => restore = Some(backup.clone()),
+                None => {
+                    out.error(format!("No single backup matches '{}'", selector));
+                    return;
+                }
+            },
+            None => {
+                print_backups(out, &backups);
+                return;
+            }
+        }
+    }
+
+    let (request_id, response_id) = match selected {
+        Some(ref profile) => (profile.request_id, profile.response_id),
+        None => (config.request_id, config.response_id),
+    };
+
+    // Select an interface
+    let selector = matches.value_of("device").or(config.device.as_deref());
+    let library = matches
+        .value_of("library")
+        .map(Path::new)
+        .or(config.library.as_deref());
+    let device = match passthru::select_driver(library, selector) {
+        Ok(device) => device,
+        Err(err) => {
+            out.error(err);
             return;
         }
     };
 
-    println!("Opening interface '{}'", device.name);
-    let i = j2534::Interface::new(&device.path).unwrap();
+    if matches.subcommand_matches("selftest").is_some() {
+        let min_voltage = match matches.value_of("min_voltage").map(str::parse::<f32>) {
+            Some(Ok(voltage)) => voltage,
+            Some(Err(_)) => {
+                out.error("Invalid minimum voltage");
+                return;
+            }
+            None => config.min_voltage,
+        };
+        run_selftest(out, &device, min_voltage);
+        return;
+    }
+
+    // Delta flashes program through a RAM kernel, comparing against a
+    // reference dump or the backup taken before flashing
+    let kernel = match matches.value_of("kernel") {
+        Some(path) => {
+            let address = match matches.value_of("kernel_address") {
+                Some(address) => match u32::from_str_radix(address.trim_start_matches("0x"), 16) {
+                    Ok(address) => address,
+                    Err(_) => {
+                        out.error("Invalid kernel address");
+                        return;
+                    }
+                },
+                None => DEFAULT_KERNEL_ADDRESS,
+            };
+            match RamKernel::load(Path::new(path), address) {
+                Ok(kernel) => Some(kernel),
+                Err(err) => {
+                    out.error(format!("Failed to load kernel: {}", err));
+                    return;
+                }
+            }
+        }
+        None => None,
+    };
+    let delta = matches.is_present("delta");
+    let reference = match matches.value_of("reference") {
+        Some(path) => match fs::read(path) {
+            Ok(reference) => Some(reference),
+            Err(err) => {
+                out.error(format!("Failed to read {}: {}", path, err));
+                return;
+            }
+        },
+        None => None,
+    };
+    if delta && reference.is_none() && matches.is_present("no_backup") {
+        out.error("--delta compares against the backup; pass --reference with --no-backup");
+        return;
+    }
+
+    out.message(format!("Opening interface '{}'", device.name));
+    let i = match j2534::Interface::new(&device.path) {
+        Ok(i) => i,
+        Err(err) => {
+            out.error(format!("Failed to load {}: {}", device.path, err));
+            return;
+        }
+    };
     // Open any connected device
-    let d = i.open_any().unwrap();
+    let d = match passthru::traced("PassThruOpen", format_args!(""), || i.open_any()) {
+        Ok(d) => d,
+        Err(err) => {
+            out.error(format!("Failed to open the adapter: {}", err));
+            return;
+        }
+    };
     // Get version information
-    let version_info = d.read_version().unwrap();
-    println!("{:#?}", version_info);
+    let version_info =
+        match passthru::traced("PassThruReadVersion", format_args!(""), || d.read_version()) {
+            Ok(version_info) => version_info,
+            Err(err) => {
+                out.error(format!("Failed to read the adapter version: {}", err));
+                return;
+            }
+        };
+    out.message(format!("{:#?}", version_info));
+    out.event(json!({
+        "event": "connected",
+        "interface": device.name,
+        "firmware_version": version_info.firmware_version,
+        "dll_version": version_info.dll_version,
+        "api_version": version_info.api_version,
+    }));
 
     // Query trouble codes
     /*for code in driver.query_trouble_codes(0x7e0).unwrap().iter() {
         println!("{}", code);
     }*/
 
-    // Create PassThru connection
-    let mut driver = PassThruIsoTp::new(&d, 500000, 15000).unwrap();
-    // isotp.set_filter(0x7e0, 0x7e8);
-    //let vin = driver.query_vin(0x7e0).unwrap();
-    //println!("VIN: {}", vin);
+    // Record the traffic for analyzing failures
+    let capture = match matches.value_of("capture").map(CaptureWriter::create) {
+        Some(Ok(writer)) => Some(writer),
+        Some(Err(err)) => {
+            out.error(format!("Failed to create capture file: {}", err));
+            return;
+        }
+        None => None,
+    };
 
-    let input_path = matches.value_of("INPUT").unwrap();
+    // Create PassThru connection, reopening it if the adapter drops out
+    let driver = match Reconnecting::new(|| {
+        let mut channel = PassThruChannel::new(&d, 500000, TimeoutProfile::default().request)?;
+        channel.set_filter(request_id, response_id)?;
+        // TesterPresent from the adapter keeps the session alive even if
+        // this process stalls
+        if let Err(err) = channel.start_keep_alive(request_id, KEEP_ALIVE_INTERVAL) {
+            out.message(format!("Adapter keep-alive unavailable: {}", err));
+        }
+        Ok(channel)
+    }) {
+        Ok(driver) => driver,
+        Err(err) => {
+            out.error(format!("Failed to open channel: {}", err));
+            return;
+        }
+    };
+    let mut driver = Capture::new(driver, capture);
+    // Identify the vehicle for the history log. A bricked ECU can't answer.
+    let recover = matches.is_present("recover");
+    let (vin, calibration_id, ecu_name) = if recover {
+        (None, None, None)
+    } else {
+        (
+            driver.query_vin(request_id).ok(),
+            driver.calibration_id(request_id).ok(),
+            driver.ecu_name(request_id).ok(),
+        )
+    };
+    if let Some(ref vin) = vin {
+        out.message(format!("VIN: {}", vin));
+    }
+    let profile = match selected {
+        Some(profile) => profile,
+        None => {
+            if vin.is_none() {
+                out.message("VIN unavailable; pass --model unless this is an MZR-DISI ECU");
+            }
+            let profile = EcuProfile::detect(vin.as_deref(), &config);
+            out.message(format!("Using {} profile", profile.name));
+            out.event(json!({ "event": "profile", "profile": profile.name }));
+            profile
+        }
+    };
+    if !profile.flash_supported {
+        out.error(format!(
+            "Flashing is not supported for the {} profile; it can only be dumped and checksummed",
+            profile.name
+        ));
+        return;
+    }
+
+    let input_path = match restore {
+        Some(ref backup) => {
+            out.message(format!("Restoring {}", backup.path().display()));
+            backup.path().to_owned()
+        }
+        None => PathBuf::from(matches.value_of("INPUT").unwrap()),
+    };
+    if let (Some(backup), Some(vin)) = (&restore, &vin) {
+        if !backup.metadata.vin.is_empty() && backup.metadata.vin != *vin {
+            out.error(format!(
+                "Backup was read from {}, but this vehicle is {}",
+                backup.metadata.vin, vin
+            ));
+            return;
+        }
+    }
+
+    let data = match fs::read(&input_path) {
+        Ok(data) => data,
+        Err(err) => {
+            out.error(format!("Failed to read {}: {}", input_path.display(), err));
+            return;
+        }
+    };
+
+    // Check the image before touching the ECU
+    let sha256 = hash::sha256_hex(&data);
+    out.message(format!("SHA-256: {}", sha256));
+    let expected = match restore {
+        Some(ref backup) => Some(backup.metadata.sha256.as_str()),
+        None => matches.value_of("sha256"),
+    };
+    if let Some(expected) = expected {
+        if let Err(err) = hash::verify_sha256(&data, expected) {
+            out.error(err);
+            return;
+        }
+        out.message("SHA-256 matches");
+    }
+    out.event(
+        json!({ "event": "image", "path": input_path, "size": data.len(), "sha256": sha256 }),
+    );
+
+    // Packages must be intact, and signed by a trusted key once any are
+    // configured. Local backups are checked against their own hash instead.
+    let container = if RomContainer::is_container(&data) {
+        match RomContainer::read(&data) {
+            Ok(container) => Some(container),
+            Err(err) => {
+                out.error(format!(
+                    "Refusing to flash {}: {}",
+                    input_path.display(),
+                    err
+                ));
+                return;
+            }
+        }
+    } else {
+        None
+    };
+    let allow_unsigned = matches.is_present("allow_unsigned") || restore.is_some();
+    match signing::check_package(
+        container.as_ref(),
+        &config.trusted_keys,
+        vin.as_deref(),
+        allow_unsigned,
+    ) {
+        Ok(()) => (),
+        Err(err @ PackageError::VinLocked { .. }) => {
+            out.error(format!(
+                "Refusing to flash {}: {}",
+                input_path.display(),
+                err
+            ));
+            return;
+        }
+        Err(err) => {
+            out.error(format!(
+                "Refusing to flash {}: {}. Pass --allow-unsigned to flash it anyway.",
+                input_path.display(),
+                err
+            ));
+            return;
+        }
+    }
+    let signer = container.as_ref().and_then(RomContainer::signer);
+    if let Some(key) = signer.filter(|key| signing::is_trusted(key, &config.trusted_keys)) {
+        out.message(format!(
+            "Signed by trusted key {}",
+            signing::public_key_hex(&key)
+        ));
+    }
+    out.event(json!({
+        "event": "signature",
+        "signer": signer.map(|key| signing::public_key_hex(&key)),
+    }));
+
+    if let Some(ref container) = container {
+        let header = &container.header;
+        out.message(format!(
+            "Container for {} ({}), checksum {}",
+            header.model.as_deref().unwrap_or("unknown model"),
+            header.memory_map,
+            match header.checksum_valid {
+                Some(true) => "valid",
+                Some(false) => "INVALID",
+                None => "not checked",
+            }
+        ));
+        if let Some(ref notes) = header.notes {
+            out.message(format!("Notes: {}", notes));
+        }
+        if let Some(ref lock) = header.vin_lock {
+            out.message(format!("Package is locked to this vehicle ({})", lock));
+        }
+    }
+
+    // HEX and S-record files and containers carry their own addresses
+    let memory_map = profile.memory_map.clone();
+    let image = match Image::parse(&data, ImageFormat::from_path(&input_path), &memory_map) {
+        Ok(image) => image,
+        Err(err) => {
+            out.error(format!("Invalid image {}: {}", input_path.display(), err));
+            return;
+        }
+    };
+    let programmable = memory_map.programmable();
+    if image.offset != programmable.start || image.data.len() != programmable.len() {
+        out.message(format!(
+            "Warning: image covers 0x{:X}-0x{:X}. The rest of the flash will be left erased.",
+            image.offset,
+            image.offset as usize + image.data.len()
+        ));
+    }
+
+    // Another model's calibration usually leaves the car undrivable
+    let current = calibration_id.as_deref().and_then(calibration::lookup);
+    if let (Some(current), Some(new)) = (current, calibration::identify(&image.data)) {
+        if current.conflicts_with(new) {
+            out.message(format!(
+                "Warning: image holds a calibration for the {}, but the ECU holds one for the {}",
+                new, current
+            ));
+            out.event(json!({ "event": "calibration_mismatch", "ecu": current, "image": new }));
+        }
+    }
+
+    // Keep a copy of the current ROM so the previous calibration can be restored
+    // A bricked ECU can't be read, so there is nothing to back up
+    let mut current_rom = None;
+    if !recover && !matches.is_present("no_backup") {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let file_name = format!("backup-{}.bin", timestamp);
+        let backup_path = match config.output_dir {
+            Some(ref dir) => dir.join(file_name),
+            None => PathBuf::from(file_name),
+        };
+
+        out.message("Backing up current ROM...");
+        let mut downloader = profile.downloader().build(&mut driver);
+        let pb = if out.is_json() {
+            ProgressBar::hidden()
+        } else {
+            ProgressBar::new(downloader.total_size() as u64)
+        };
+        pb.set_style(ProgressStyle::default_bar()
+            .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({msg})")
+            .progress_chars("#>-"));
+        let mut progress = BackupProgress { pb, out };
+        if let Err(err) = downloader.run(&mut progress) {
+            progress.pb.abandon();
+            out.error(format!("Backup failed: {}", err));
+            out.message("Run with --no-backup to flash without a backup.");
+            return;
+        }
+        progress.pb.finish_and_clear();
+
+        let backup = downloader.take_data();
+        if let Err(err) = fs::write(&backup_path, &backup) {
+            out.error(format!("Failed to write backup: {}", err));
+            return;
+        }
+        let metadata = RomMetadata {
+            calibration_id: calibration_id.clone(),
+            ecu_name,
+            request_id: profile.request_id,
+            response_id: profile.response_id,
+            adapter: Some(device.name.clone()),
+            ..RomMetadata::new(vin.as_deref().unwrap_or_default(), &backup)
+        };
+        if let Err(err) = metadata.save(&backup_path) {
+            out.message(format!("Failed to write backup metadata: {}", err));
+        }
+        out.message(format!(
+            "Backed up current ROM to {} (SHA-256 {})",
+            backup_path.display(),
+            metadata.sha256
+        ));
+        out.event(json!({ "event": "backup", "path": backup_path, "sha256": metadata.sha256 }));
+        current_rom = Some(backup);
+    }
+
+    // Only the sectors whose contents change are reprogrammed
+    let updates = match reference.or(current_rom) {
+        Some(reference) if delta => {
+            match diff::changed_sectors(&memory_map, &reference, image.offset, &image.data) {
+                Some(updates) if updates.is_empty() => {
+                    out.message("The ECU already holds this image. Nothing to flash.");
+                    out.event(json!({ "event": "unchanged" }));
+                    return;
+                }
+                Some(updates) => {
+                    let bytes: usize = updates.iter().map(|update| update.data.len()).sum();
+                    out.message(format!(
+                        "Reprogramming {} of {} sectors ({} KiB)",
+                        updates.len(),
+                        memory_map.sectors.len(),
+                        bytes / 1024
+                    ));
+                    out.event(json!({
+                        "event": "delta",
+                        "sectors": updates.iter().map(|update| update.sector).collect::<Vec<_>>(),
+                        "bytes": bytes,
+                    }));
+                    Some(updates)
+                }
+                None => {
+                    out.error(format!(
+                        "Reference is not a full dump of the {} layout",
+                        memory_map.name
+                    ));
+                    return;
+                }
+            }
+        }
+        _ => None,
+    };
 
-    let data = fs::read(input_path).unwrap();
+    // Refuse to flash with a weak battery and pause if the voltage sags
+    let min_voltage = match matches.value_of("min_voltage").map(str::parse::<f32>) {
+        Some(Ok(voltage)) => voltage,
+        Some(Err(_)) => {
+            out.error("Invalid minimum voltage");
+            return;
+        }
+        None => config.min_voltage,
+    };
 
-    // Authenticate and download
-    let mut programmer = Programmer::new(&mut driver, 0x8000, data[0x8000..].to_owned());
+    // Abort cleanly on Ctrl-C, except while the flash is being erased
+    let token = CancelToken::new();
+    let erase = EraseState::default();
+    {
+        let token = token.clone();
+        let erasing = erase.erasing.clone();
+        ctrlc::set_handler(move || {
+            if erasing.load(Ordering::SeqCst) {
+                eprintln!("Flash is being erased and cannot be aborted. Please wait...");
+            } else if !token.is_cancelled() {
+                eprintln!("Aborting after the current block...");
+                token.cancel();
+            }
+        })
+        .unwrap();
+    }
 
     // Create progress bar
-    let pb = ProgressBar::new(programmer.total_size() as u64);
+    let total = match updates {
+        Some(ref updates) => updates.iter().map(|update| update.data.len()).sum(),
+        None => image.data.len(),
+    };
+    let mut progress = CliProgress {
+        pb: progress_bar(out, total),
+        out,
+    };
+    let safeguards = Safeguards {
+        device: &d,
+        min_voltage,
+        force: matches.is_present("force"),
+        token,
+        erase,
+        out,
+    };
+    let mut programmer = None;
+    let transferring = Arc::new(AtomicBool::new(true));
+    let (lines_tx, lines) = mpsc::channel();
+    let result = match (updates, kernel.clone()) {
+        (Some(updates), Some(kernel)) => {
+            let mut transfer = KernelTransfer::write_sectors(&mut driver, kernel, updates);
+            transfer.set_request_id(profile.request_id);
+            safeguards.apply(&mut transfer);
+            flash_sectors(&mut transfer, &mut progress)
+        }
+        _ => {
+            // Authenticate and download
+            let programmer = programmer.insert(
+                profile
+                    .programmer()
+                    .verify(matches.is_present("verify"))
+                    .build(&mut driver, image.offset, image.data.clone()),
+            );
+
+            programmer.allow_engine_running(safeguards.force);
+            programmer.set_recovery_mode(recover);
+            if recover {
+                out.message("Recovery mode. Turn the ignition off, then back on to connect...");
+            }
+            programmer.set_voltage_monitor(PassThruVoltage::new(&d), min_voltage);
+            programmer.set_event_sink(safeguards.report_events());
+            programmer.set_cancel_token(safeguards.token.clone());
+
+            // Pressing Enter pauses or resumes the transfer. Afterwards, lines
+            // are passed on to prompts.
+            let pause = PauseToken::new();
+            if !out.is_json() {
+                let pause = pause.clone();
+                let transferring = transferring.clone();
+                thread::spawn(move || {
+                    let mut line = String::new();
+                    while matches!(io::stdin().read_line(&mut line), Ok(n) if n > 0) {
+                        if !transferring.load(Ordering::SeqCst) {
+                            if lines_tx.send(line.clone()).is_err() {
+                                break;
+                            }
+                        } else if pause.toggle() {
+                            eprintln!("Pausing after the current block. Press Enter to resume.");
+                        } else {
+                            eprintln!("Resuming...");
+                        }
+                        line.clear();
+                    }
+                });
+            }
+            programmer.set_pause_token(pause);
+            programmer.run(&mut progress)
+        }
+    };
+    transferring.store(false, Ordering::SeqCst);
+    // A failed erase ends without EraseCompleted
+    safeguards.erase.erasing.store(false, Ordering::SeqCst);
+    let stats = programmer.map(|programmer| programmer.stats().clone());
+
+    // The bootloader can only erase the whole flash, so a region the ECU's
+    // checksum routine rejects is reprogrammed on its own through the kernel
+    let result = match result {
+        Err(MzrError::ChecksumRejected { start, end }) => {
+            progress.pb.abandon();
+            out.message(format!(
+                "The ECU reports a bad checksum for 0x{:X}-0x{:X}",
+                start, end
+            ));
+            out.event(json!({ "event": "checksum_rejected", "start": start, "end": end }));
+            match kernel {
+                Some(kernel) if !out.is_json() && confirm_reflash(&lines) => {
+                    let region = Region::new(start, end);
+                    match diff::region_sectors(&memory_map, region, image.offset, &image.data) {
+                        Some(updates) => reflash_sectors(
+                            &mut driver,
+                            kernel,
+                            profile.request_id,
+                            updates,
+                            &safeguards,
+                            &mut progress,
+                        ),
+                        None => result,
+                    }
+                }
+                Some(_) => result,
+                None => {
+                    out.message("Pass --kernel to reprogram only the failed region");
+                    result
+                }
+            }
+        }
+        result => result,
+    };
+
+    // Log the attempt, whatever the outcome
+    let record = FlashRecord {
+        vin,
+        calibration_id,
+        error: match result {
+            Ok(()) | Err(MzrError::Cancelled) => None,
+            Err(ref err) => Some(err.to_string()),
+        },
+        ..FlashRecord::new(
+            &input_path,
+            &sha256,
+            match result {
+                Ok(()) => FlashResult::Completed,
+                Err(MzrError::Cancelled) => FlashResult::Cancelled,
+                Err(_) => FlashResult::Failed,
+            },
+        )
+    };
+    if let Err(err) = History::append(&record) {
+        out.message(format!("Failed to update flash history: {}", err));
+    }
+
+    match result {
+        Ok(()) => progress.pb.finish_with_message("flashed"),
+        Err(MzrError::Cancelled) => {
+            progress.pb.abandon();
+            out.fail();
+            let erased = safeguards.erase.started.load(Ordering::SeqCst);
+            out.event(json!({ "event": "cancelled", "erased": erased }));
+            if erased {
+                out.message(
+                    "Flash aborted. The ECU has been erased and only partially programmed, so the \
+                     vehicle will not start.\nLeave the ignition on and run mzr-flash again with a \
+                     known-good ROM to recover.",
+                );
+            } else {
+                out.message("Flash aborted before erasing. The ECU was not modified.");
+            }
+            return;
+        }
+        Err(err) => {
+            progress.pb.abandon();
+            out.error(err);
+            return;
+        }
+    }
+
+    out.message("Uploaded ROM");
+    if let Some(stats) = stats {
+        out.message(format!("Wrote {}", stats));
+        out.event(stats.to_json());
+    }
+    out.event(json!({ "event": "complete", "size": total, "sha256": sha256 }));
+}
+
+/// Prints the installed J2534 drivers, numbered for `--device`
+fn print_drivers(out: Output) {
+    let drivers = match passthru::installed_drivers() {
+        Ok(drivers) => drivers,
+        Err(err) => {
+            out.error(err);
+            return;
+        }
+    };
+    for (index, driver) in drivers.iter().enumerate() {
+        out.message(format!(
+            "{}: {} ({})\n   {}",
+            index, driver.name, driver.vendor, driver.path
+        ));
+        out.event(json!({
+            "event": "device",
+            "index": index,
+            "name": driver.name,
+            "vendor": driver.vendor,
+            "path": driver.path,
+        }));
+    }
+}
+
+/// Uploads the kernel, erases the changed sectors and programs them,
+/// reporting progress like the programmer
+fn flash_sectors<T: Transfer>(
+    transfer: &mut T,
+    progress: &mut CliProgress,
+) -> Result<(), MzrError> {
+    // The kernel is uploaded and the sectors erased in one step
+    progress.on_phase_change(Phase::Erasing);
+    transfer.start()?;
+    progress.on_phase_change(Phase::Transferring);
+    for result in TransferIter::new(transfer) {
+        progress.on_progress(&result?);
+    }
+    progress.on_phase_change(Phase::Completed);
+    Ok(())
+}
+
+/// Asks whether to reprogram a region the ECU rejected, reading the answer
+/// passed on by the pause thread
+fn confirm_reflash(lines: &Receiver<String>) -> bool {
+    print!("Reprogram the sectors of this region through the kernel? [y/N] ");
+    let _ = io::stdout().flush();
+    match lines.recv() {
+        Ok(answer) => matches!(answer.trim(), "y" | "Y" | "yes"),
+        Err(_) => false,
+    }
+}
+
+/// Reprograms the sectors of a rejected region through the kernel
+fn reflash_sectors<M: Uds + SetTimeout>(
+    bus: &mut M,
+    kernel: RamKernel,
+    request_id: u32,
+    updates: Vec<SectorUpdate>,
+    safeguards: &Safeguards,
+    progress: &mut CliProgress,
+) -> Result<(), MzrError> {
+    let total = updates.iter().map(|update| update.data.len()).sum();
+    progress.pb = progress_bar(progress.out, total);
+    let mut transfer = KernelTransfer::write_sectors(bus, kernel, updates);
+    transfer.set_request_id(request_id);
+    safeguards.apply(&mut transfer);
+    flash_sectors(&mut transfer, progress)
+}
+
+/// Checks applied to every write, whether through the bootloader or the
+/// kernel
+struct Safeguards<'d> {
+    device: &'d j2534::Device<'d>,
+    min_voltage: f32,
+    force: bool,
+    token: CancelToken,
+    erase: EraseState,
+    out: Output,
+}
+
+impl<'d> Safeguards<'d> {
+    /// Refuses a kernel write with the engine running unless forced, and
+    /// monitors the battery voltage and Ctrl-C while it runs
+    fn apply<'a, M: Uds + SetTimeout>(&self, transfer: &mut KernelTransfer<'a, M>)
+    where
+        'd: 'a,
+    {
+        transfer.allow_engine_running(self.force);
+        transfer.set_voltage_monitor(PassThruVoltage::new(self.device), self.min_voltage);
+        transfer.set_event_sink(self.report_events());
+        transfer.set_cancel_token(self.token.clone());
+    }
+
+    /// Returns an event sink tracking the erase and reporting battery
+    /// voltage sags
+    fn report_events(&self) -> impl FnMut(Event) {
+        let out = self.out;
+        let erase = self.erase.clone();
+        move |event| match event {
+            Event::EraseStarted => {
+                erase.erasing.store(true, Ordering::SeqCst);
+                erase.started.store(true, Ordering::SeqCst);
+            }
+            Event::EraseCompleted => erase.erasing.store(false, Ordering::SeqCst),
+            event => report_voltage(out, event),
+        }
+    }
+}
+
+/// Erase state reported by the library, shared with the Ctrl-C handler
+#[derive(Clone, Default)]
+struct EraseState {
+    // Set while the ECU is erasing so Ctrl-C is ignored
+    erasing: Arc<AtomicBool>,
+    // Set once the ECU has begun erasing
+    started: Arc<AtomicBool>,
+}
+
+/// Reports battery voltage sags
+fn report_voltage(out: Output, event: Event) {
+    match event {
+        Event::LowVoltage { voltage } => {
+            out.message(format!(
+                "Battery voltage dropped to {:.1} V. Pausing until it recovers...",
+                voltage
+            ));
+            out.event(json!({ "event": "low_voltage", "voltage": voltage }));
+        }
+        Event::VoltageRecovered { voltage } => {
+            out.message(format!("Battery voltage recovered ({:.1} V)", voltage));
+            out.event(json!({ "event": "voltage_recovered", "voltage": voltage }));
+        }
+        _ => (),
+    }
+}
+
+/// Creates the progress bar of a transfer of `total` bytes, hidden in JSON
+/// mode
+fn progress_bar(out: Output, total: usize) -> ProgressBar {
+    let pb = if out.is_json() {
+        ProgressBar::hidden()
+    } else {
+        ProgressBar::new(total as u64)
+    };
     pb.set_style(ProgressStyle::default_bar()
-        .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")
+        .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({msg})")
         .progress_chars("#>-"));
+    pb
+}
 
-    println!("Erasing...");
-    programmer.start().unwrap();
-    println!("Beginning transfer...");
-    while let ProgrammerState::InProgress(uploaded) = programmer.step().unwrap() {
-        pb.set_position(uploaded as u64);
+/// Prints stored ROM dumps, numbered for `backups --restore`
+fn print_backups(out: Output, backups: &[Backup]) {
+    if backups.is_empty() {
+        out.message("No backups found");
     }
-    pb.finish_with_message("flashed");
+    for (i, backup) in backups.iter().enumerate() {
+        let metadata = &backup.metadata;
+        out.message(format!(
+            "{:>3}. {} {} calibration {} SHA-256 {}",
+            i + 1,
+            metadata.timestamp,
+            metadata.vin,
+            metadata.calibration_id.as_deref().unwrap_or("unknown"),
+            &metadata.sha256[..16],
+        ));
+        for path in backup.paths.iter() {
+            out.message(format!("       {}", path.display()));
+        }
+        out.event(json!({
+            "event": "backup",
+            "index": i + 1,
+            "metadata": metadata,
+            "paths": backup.paths,
+        }));
+    }
+}
+
+/// Runs the adapter self-test and reports each check
+fn run_selftest(out: Output, device: &j2534::Driver, min_voltage: f32) {
+    out.message(format!("Testing interface '{}'", device.name));
+    let interface = match j2534::Interface::new(&device.path) {
+        Ok(interface) => interface,
+        Err(err) => {
+            out.error(format!("Failed to load {}: {}", device.path, err));
+            return;
+        }
+    };
+    let selftest = SelfTest {
+        min_voltage,
+        ..SelfTest::default()
+    };
+    let report = selftest.run(&interface, |check| {
+        out.message(check);
+        out.event(json!({
+            "event": "selftest_check",
+            "name": check.name,
+            "outcome": format!("{:?}", check.outcome).to_lowercase(),
+            "detail": check.detail,
+        }));
+    });
+    let ms = |duration: Duration| duration.as_secs_f64() * 1000.0;
+    out.message(format!(
+        "{}: {} passed, {} failed, {} skipped",
+        if report.passed() { "PASSED" } else { "FAILED" },
+        report.count(Outcome::Passed),
+        report.count(Outcome::Failed),
+        report.count(Outcome::Skipped)
+    ));
+    out.event(json!({
+        "event": "selftest",
+        "interface": device.name,
+        "passed": report.passed(),
+        "latency": report.latency.map(|latency| json!({
+            "min_ms": ms(latency.min),
+            "mean_ms": ms(latency.mean),
+            "max_ms": ms(latency.max),
+            "samples": latency.samples,
+        })),
+    }));
+    if !report.passed() {
+        out.fail();
+    }
+}
 
-    println!("Uploaded ROM");
+/// Prints the flash history, optionally limited to one vehicle
+fn print_history(out: Output, vin: Option<&str>) {
+    let history = match History::load() {
+        Ok(history) => history,
+        Err(err) => {
+            out.error(err);
+            return;
+        }
+    };
+    let records: Vec<&FlashRecord> = match vin {
+        Some(vin) => history.for_vin(vin).collect(),
+        None => history.records().iter().collect(),
+    };
+    if records.is_empty() {
+        out.message("No flashes recorded");
+    }
+    for record in records {
+        out.message(format!(
+            "{} {} {} {} (SHA-256 {}, calibration {}){}",
+            record.timestamp,
+            record.vin.as_deref().unwrap_or("unknown VIN"),
+            format!("{:?}", record.result).to_lowercase(),
+            record.source.display(),
+            record.sha256,
+            record.calibration_id.as_deref().unwrap_or("unknown"),
+            record
+                .error
+                .as_ref()
+                .map(|err| format!(": {}", err))
+                .unwrap_or_default(),
+        ));
+        out.event(json!({ "event": "flash", "record": record }));
+    }
+}
+
+/// Reports the progress of the pre-flash backup
+struct BackupProgress {
+    pb: ProgressBar,
+    out: Output,
+}
+
+impl ProgressObserver for BackupProgress {
+    fn on_progress(&mut self, progress: &Progress) {
+        self.pb.set_position(progress.position as u64);
+        self.pb.set_message(&progress.to_string());
+        self.out.event(json!({
+            "event": "backup_progress",
+            "position": progress.position,
+            "total": progress.total,
+            "rate": progress.rate,
+            "average_rate": progress.average_rate,
+            "eta": progress.eta.map(|eta| eta.as_secs_f64()),
+        }));
+    }
+
+    fn on_retry(&mut self, attempt: usize, error: &MzrError) {
+        if !self.out.is_json() {
+            self.pb
+                .println(format!("Retrying (attempt {}): {}", attempt, error));
+        }
+    }
+}
+
+/// Reports progress with a progress bar, or as JSON events
+struct CliProgress {
+    pb: ProgressBar,
+    out: Output,
+}
+
+impl ProgressObserver for CliProgress {
+    fn on_progress(&mut self, progress: &Progress) {
+        self.pb.set_position(progress.position as u64);
+        self.pb.set_message(&progress.to_string());
+        self.out.event(json!({
+            "event": "progress",
+            "position": progress.position,
+            "total": progress.total,
+            "rate": progress.rate,
+            "average_rate": progress.average_rate,
+            "eta": progress.eta.map(|eta| eta.as_secs_f64()),
+        }));
+    }
+
+    fn on_phase_change(&mut self, phase: Phase) {
+        match phase {
+            Phase::Erasing => self.out.message("Erasing..."),
+            Phase::Transferring => self
+                .out
+                .message("Beginning transfer... (press Enter to pause)"),
+            Phase::Verifying => self.out.message("Verifying..."),
+            Phase::Checksumming => self.out.message("Checking checksums..."),
+            _ => (),
+        }
+        self.out.event(json!({ "event": "phase", "phase": phase }));
+    }
+
+    fn on_retry(&mut self, attempt: usize, error: &MzrError) {
+        if !self.out.is_json() {
+            self.pb
+                .println(format!("Retrying (attempt {}): {}", attempt, error));
+        }
+        self.out
+            .event(json!({ "event": "retry", "attempt": attempt, "error": error.to_string() }));
+    }
 }
@@ -1,50 +1,764 @@
 //! This example queries a VIN using a PassThru device
 
-use anyhow::Result;
-use obd::{PassThruIsoTp, Uds};
 use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use obd::{PassThruIsoTp, Uds};
 
-use mzr::{MzrBus};
+use mzr::actuator::{self, Actuator};
+use mzr::calibration::{self, Calibration};
+use mzr::cancel::CancelToken;
+use mzr::coding;
+use mzr::config::Config;
+use mzr::container::RomContainer;
+use mzr::datalink::can::CanBus;
+use mzr::dtc::Dtc;
+use mzr::kwp::{Kwp, ENGINE_ADDRESS};
+use mzr::metadata::RomMetadata;
+use mzr::module;
+use mzr::o2::{self, O2Sensor};
+use mzr::output::Output;
+use mzr::passthru::{self, PassThruChannel, PassThruKLine};
+use mzr::profile::EcuProfile;
+use mzr::vin::Vin;
+use mzr::MzrBus;
 
 use clap::clap_app;
-use indicatif::{ProgressBar, ProgressStyle};
+use serde_json::json;
+use tracing_subscriber::filter::{LevelFilter, Targets};
+use tracing_subscriber::fmt;
+use tracing_subscriber::prelude::*;
 
-pub fn main() {
+pub fn main() -> ExitCode {
+    run();
+    Output::exit_code()
+}
+
+fn run() {
     let matches = clap_app!(myapp =>
         (version: "1.0")
         (author: "Jacob Manning <jjacob.manning@gmail.com>")
         (about: "Queries information from an MZR-DISI ECU")
-        (@arg passthru: -p --passthru +takes_value "PassThru device to use when connecting to the ECU")
+        (@arg device: -d --device alias("passthru") +takes_value "J2534 device to use when connecting to the ECU (name or index)")
+        (@arg library: --library +takes_value "J2534 library (DLL) to load instead of the installed drivers")
         (@arg model: -m --model +takes_value "Vehicle model")
+        (@arg bitrate: --bitrate +takes_value default_value("500000") "CAN bitrate in bit/s")
+        (@arg protocol: -p --protocol +takes_value possible_values(&["can", "kwp"]) default_value("can") "Diagnostic protocol: CAN, or KWP2000 over K-line for early vehicles")
+        (@arg json: --json "Prints machine-readable JSON output")
+        (@arg debug_adapter: --("debug-adapter") "Logs every J2534 call with its parameters and return code to stderr")
+        (@subcommand devices =>
+            (about: "Lists installed J2534 devices"))
+        (@subcommand decode =>
+            (about: "Decodes a VIN, or the VIN a ROM dump was read from, without connecting")
+            (@arg INPUT: +required "VIN, ROM dump or .mzrrom container"))
+        (@subcommand modules =>
+            (about: "Identifies the known diagnostic modules on a CAN bus")
+            (@arg bus: -b --bus +takes_value possible_values(&["hs", "ms"]) default_value("ms") "Bus to query: high-speed (pins 6/14) or medium-speed (pins 3/11) CAN"))
+        (@subcommand scan =>
+            (about: "Lists the modules answering on the diagnostic IDs of a CAN bus")
+            (@arg bus: -b --bus +takes_value possible_values(&["hs", "ms"]) default_value("hs") "Bus to scan: high-speed (pins 6/14) or medium-speed (pins 3/11) CAN"))
+        (@subcommand o2 =>
+            (about: "Reads the live values and monitor test results of the oxygen sensors"))
+        (@subcommand actuate =>
+            (about: "Drives an output of the ECU through a routine for workshop diagnosis, e.g. the cooling fan. Lists the outputs of the ECU profile without NAME")
+            (@arg profile: --profile +takes_value "ECU profile file describing the outputs (default: the built-in profile of the model or VIN)")
+            (@arg duration: --duration +takes_value "Seconds to drive the output (default: the output's own, at most 60)")
+            (@arg yes: -y --yes "Drives the output without asking for confirmation")
+            (@arg NAME: "Output to drive"))
+        (@subcommand write_vin =>
+            (name: "write-vin")
+            (about: "Codes a replacement ECU to the vehicle by writing its VIN. The old VIN is backed up first")
+            (@arg profile: --profile +takes_value "ECU profile file providing the security access (default: the built-in profile of the model or VIN)")
+            (@arg session: --session +takes_value default_value("0x03") "Diagnostic session the ECU accepts the write in, in hex")
+            (@arg yes: -y --yes "Writes without asking for confirmation")
+            (@arg VIN: +required "VIN of the vehicle"))
     )
     .get_matches();
 
-    // Get a list of interfaces
-    let device = match j2534::drivers().unwrap().into_iter().next() {
-        Some(device) => device,
-        None => {
-            println!("No J2534 interfaces found");
+    let out = Output::new(matches.is_present("json"));
+    if matches.subcommand_matches("devices").is_some() {
+        print_drivers(out);
+        return;
+    }
+    // --debug-adapter shows the J2534 calls traced by mzr on stderr
+    if matches.is_present("debug_adapter") {
+        tracing_subscriber::registry()
+            .with(fmt::layer().with_writer(io::stderr))
+            .with(Targets::new().with_target("j2534", LevelFilter::TRACE))
+            .init();
+    }
+
+    if let Some(matches) = matches.subcommand_matches("decode") {
+        let input = matches.value_of("INPUT").unwrap();
+        let path = Path::new(input);
+        if !path.is_file() {
+            print_vehicle(out, input);
+        } else {
+            print_dump(out, path);
+        }
+        return;
+    }
+
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(err) => {
+            out.error(err);
+            return;
+        }
+    };
+
+    let mut profile = match EcuProfile::select(None, matches.value_of("model"), &config) {
+        Ok(profile) => profile,
+        Err(err) => {
+            out.error(err);
+            return;
+        }
+    };
+    let (request_id, response_id) = match profile {
+        Some(ref profile) => (profile.request_id, profile.response_id),
+        None => (config.request_id, config.response_id),
+    };
+    if let Some(write_matches) = matches.subcommand_matches("write_vin") {
+        if let Err(err) = Vin::parse(write_matches.value_of("VIN").unwrap()) {
+            out.error(err);
+            return;
+        }
+        profile = match EcuProfile::select(
+            write_matches.value_of("profile"),
+            matches.value_of("model"),
+            &config,
+        ) {
+            Ok(profile) => profile,
+            Err(err) => {
+                out.error(err);
+                return;
+            }
+        };
+        if matches.value_of("protocol") == Some("kwp") {
+            out.error("The VIN can only be written over CAN");
+            return;
+        }
+    }
+    if let Some(actuate_matches) = matches.subcommand_matches("actuate") {
+        profile = match EcuProfile::select(
+            actuate_matches.value_of("profile"),
+            matches.value_of("model"),
+            &config,
+        ) {
+            Ok(profile) => profile,
+            Err(err) => {
+                out.error(err);
+                return;
+            }
+        };
+        if !actuate_matches.is_present("NAME") {
+            let profile = profile.unwrap_or_else(|| EcuProfile::detect(None, &config));
+            print_actuators(out, &profile);
+            return;
+        }
+        if matches.value_of("protocol") == Some("kwp") {
+            out.error("Outputs can only be driven over CAN");
+            return;
+        }
+    }
+
+    // Select an interface
+    let selector = matches.value_of("device").or(config.device.as_deref());
+    let library = matches
+        .value_of("library")
+        .map(Path::new)
+        .or(config.library.as_deref());
+    let device = match passthru::select_driver(library, selector) {
+        Ok(device) => device,
+        Err(err) => {
+            out.error(err);
             return;
         }
     };
 
-    println!("Opening interface '{}'", device.name);
-    let i = j2534::Interface::new(&device.path).unwrap();
+    out.message(format!("Opening interface '{}'", device.name));
+    let i = match j2534::Interface::new(&device.path) {
+        Ok(i) => i,
+        Err(err) => {
+            out.error(format!("Failed to load {}: {}", device.path, err));
+            return;
+        }
+    };
     // Open any connected device
-    let d = i.open_any().unwrap();
+    let d = match passthru::traced("PassThruOpen", format_args!(""), || i.open_any()) {
+        Ok(d) => d,
+        Err(err) => {
+            out.error(format!("Failed to open the adapter: {}", err));
+            return;
+        }
+    };
     // Get version information
-    let version_info = d.read_version().unwrap();
-    println!("{:#?}", version_info);
+    let version_info =
+        match passthru::traced("PassThruReadVersion", format_args!(""), || d.read_version()) {
+            Ok(version_info) => version_info,
+            Err(err) => {
+                out.error(format!("Failed to read the adapter version: {}", err));
+                return;
+            }
+        };
+    out.message(format!("{:#?}", version_info));
+    out.event(json!({
+        "event": "connected",
+        "interface": device.name,
+        "firmware_version": version_info.firmware_version,
+        "dll_version": version_info.dll_version,
+        "api_version": version_info.api_version,
+    }));
+
+    if let Some((command @ ("modules" | "scan"), matches)) = matches.subcommand() {
+        let bus = match matches.value_of("bus") {
+            Some("hs") => CanBus::HighSpeed,
+            _ => CanBus::MediumSpeed,
+        };
+        let mut channel = match PassThruChannel::open(&d, bus, Duration::from_millis(500)) {
+            Ok(channel) => channel,
+            Err(err) => {
+                out.error(format!("Failed to open {} channel: {}", bus, err));
+                return;
+            }
+        };
+        if command == "scan" {
+            scan(out, &mut channel, bus);
+        } else {
+            query_modules(out, &mut channel, bus);
+        }
+        return;
+    }
+
+    if matches.value_of("protocol") == Some("kwp") {
+        let line = match PassThruKLine::new(&d, Duration::from_millis(1000)) {
+            Ok(line) => line,
+            Err(err) => {
+                out.error(format!("Failed to open K-line channel: {}", err));
+                return;
+            }
+        };
+        let mut kwp = Kwp::new(line, ENGINE_ADDRESS);
+        if matches.subcommand_matches("o2").is_some() {
+            print_o2(out, &mut kwp, request_id);
+        } else {
+            query(out, &mut kwp, request_id);
+        }
+    } else {
+        let bitrate = match can_bitrate(out, matches.value_of("bitrate").unwrap()) {
+            Some(bitrate) => bitrate,
+            None => return,
+        };
+        // Create PassThru connection
+        let mut driver = match PassThruIsoTp::new(&d, bitrate, 15000) {
+            Ok(driver) => driver,
+            Err(err) => {
+                out.error(format!("Failed to open channel: {}", err));
+                return;
+            }
+        };
+        let write_matches = matches.subcommand_matches("write_vin");
+        let actuate_matches = matches.subcommand_matches("actuate");
+        if write_matches.is_some() || actuate_matches.is_some() {
+            let (request_id, response_id) = match profile {
+                Some(ref profile) => (profile.request_id, profile.response_id),
+                None => (request_id, response_id),
+            };
+            if let Err(err) = driver.set_filter(request_id, response_id) {
+                out.error(format!("Failed to set filter: {}", err));
+                return;
+            }
+            let profile = profile.unwrap_or_else(|| {
+                let vin = driver.query_vin(request_id).ok();
+                EcuProfile::detect(vin.as_deref(), &config)
+            });
+            match (write_matches, actuate_matches) {
+                (Some(write_matches), _) => write_vin(
+                    out,
+                    &mut driver,
+                    &profile,
+                    write_matches,
+                    config.output_dir.as_deref(),
+                ),
+                (None, Some(actuate_matches)) => {
+                    actuate(out, &mut driver, &profile, actuate_matches)
+                }
+                (None, None) => {}
+            }
+            return;
+        }
+        if let Err(err) = driver.set_filter(request_id, response_id) {
+            out.error(format!("Failed to set filter: {}", err));
+            return;
+        }
+        if matches.subcommand_matches("o2").is_some() {
+            print_o2(out, &mut driver, request_id);
+        } else {
+            query(out, &mut driver, request_id);
+        }
+    }
+}
+
+/// Prints the installed J2534 drivers, numbered for `--device`
+fn print_drivers(out: Output) {
+    let drivers = match passthru::installed_drivers() {
+        Ok(drivers) => drivers,
+        Err(err) => {
+            out.error(err);
+            return;
+        }
+    };
+    for (index, driver) in drivers.iter().enumerate() {
+        out.message(format!(
+            "{}: {} ({})\n   {}",
+            index, driver.name, driver.vendor, driver.path
+        ));
+        out.event(json!({
+            "event": "device",
+            "index": index,
+            "name": driver.name,
+            "vendor": driver.vendor,
+            "path": driver.path,
+        }));
+    }
+}
+
+/// Prints the live values and monitor results of each oxygen sensor
+fn print_o2<U: Uds>(out: Output, driver: &mut U, request_id: u32) {
+    let sensors = match o2::sensors(driver, request_id) {
+        Ok(sensors) => sensors,
+        // Early ECUs answer only the monitor service
+        Err(err) => {
+            out.message(format!(
+                "Failed to read the oxygen sensors present ({}), assuming B1S1 and B1S2",
+                err
+            ));
+            O2Sensor::from_mask(0b0011)
+        }
+    };
+    for sensor in sensors {
+        let reading = match o2::read_sensor(driver, request_id, sensor) {
+            Ok(reading) => reading,
+            Err(err) => {
+                out.error(format!("Failed to read {}: {}", sensor, err));
+                continue;
+            }
+        };
+        let mut values = Vec::new();
+        if let Some(voltage) = reading.voltage {
+            values.push(format!("{:.3} V", voltage));
+        }
+        if let Some(trim) = reading.fuel_trim {
+            values.push(format!("trim {:+.1} %", trim));
+        }
+        if let Some(lambda) = reading.lambda {
+            values.push(format!("lambda {:.3}", lambda));
+        }
+        if let Some(current) = reading.current {
+            values.push(format!("{:+.2} mA", current));
+        }
+        out.message(format!("{}: {}", sensor, values.join(", ")));
+
+        // Monitor results are optional, e.g. CAN ECUs report them in
+        // service 0x06 instead
+        let tests = o2::read_tests(driver, request_id, sensor).unwrap_or_default();
+        for test in &tests {
+            let limits = match test.limits {
+                Some((min, max)) => format!(
+                    " (limits {:.3}–{:.3}) {}",
+                    min,
+                    max,
+                    if test.passed() { "pass" } else { "FAIL" }
+                ),
+                None => String::new(),
+            };
+            out.message(format!(
+                "  {}: {:.3} {}{}",
+                test.name, test.value, test.unit, limits
+            ));
+        }
+        out.event(json!({
+            "event": "o2_sensor",
+            "reading": reading,
+            "tests": tests,
+        }));
+    }
+}
+
+/// Lists the outputs described in `profile`
+fn print_actuators(out: Output, profile: &EcuProfile) {
+    if profile.actuators.is_empty() {
+        out.message(format!(
+            "The {} profile describes no outputs; add them to a profile file",
+            profile.name
+        ));
+    }
+    for actuator in &profile.actuators {
+        out.message(format!(
+            "{}\troutine 0x{:04X}\t{}",
+            actuator.name, actuator.routine, actuator.description
+        ));
+    }
+    out.event(json!({
+        "event": "actuators",
+        "profile": profile.name,
+        "actuators": profile.actuators,
+    }));
+}
+
+/// Drives the output named on the command line after confirmation. Ctrl-C
+/// stops it early.
+fn actuate<U: Uds>(out: Output, driver: &mut U, profile: &EcuProfile, matches: &clap::ArgMatches) {
+    let name = matches.value_of("NAME").unwrap();
+    let actuator = match profile.actuator(name) {
+        Some(actuator) => actuator,
+        None => {
+            out.error(format!(
+                "The {} profile has no output '{}'",
+                profile.name, name
+            ));
+            return;
+        }
+    };
+    let duration = match matches.value_of("duration") {
+        Some(value) => match value.parse::<u64>() {
+            Ok(secs) => Duration::from_secs(secs),
+            Err(_) => {
+                out.error(format!("Invalid duration '{}'", value));
+                return;
+            }
+        },
+        None => Duration::from_secs(actuator.duration),
+    }
+    .min(actuator::MAX_DURATION);
+    if !matches.is_present("yes") && !confirm_actuation(out, actuator, duration) {
+        return;
+    }
+
+    let cancel = CancelToken::new();
+    {
+        let cancel = cancel.clone();
+        ctrlc::set_handler(move || cancel.cancel()).unwrap();
+    }
+    out.message(format!(
+        "Driving {} for {} s, Ctrl-C stops it",
+        actuator.name,
+        duration.as_secs()
+    ));
+    let result = actuator::actuate(driver, profile, actuator, duration, &cancel, |elapsed| {
+        out.event(json!({
+            "event": "actuating",
+            "actuator": actuator.name,
+            "elapsed": elapsed.as_secs_f64(),
+        }))
+    });
+    match result {
+        Ok(()) => {
+            out.message(format!("Stopped {}", actuator.name));
+            out.event(json!({ "event": "actuated", "actuator": actuator.name }));
+        }
+        Err(err) => out.error(format!("Driving {} failed: {}", actuator.name, err)),
+    }
+}
+
+/// Asks on stdin before driving `actuator`. Outputs are only driven in JSON
+/// mode with `--yes`.
+fn confirm_actuation(out: Output, actuator: &Actuator, duration: Duration) -> bool {
+    if out.is_json() {
+        out.error("Driving outputs requires --yes in JSON mode");
+        return false;
+    }
+    if !actuator.description.is_empty() {
+        println!("{}", actuator.description);
+    }
+    if let Some(ref warning) = actuator.warning {
+        println!("WARNING: {}", warning);
+    }
+    print!(
+        "Drive {} for {} s? Make sure the vehicle is parked and nobody is near moving parts. [y/N] ",
+        actuator.name,
+        duration.as_secs()
+    );
+    let _ = io::stdout().flush();
+    let mut answer = String::new();
+    if io::stdin().lock().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim(), "y" | "Y" | "yes")
+}
+
+/// Codes the ECU to the VIN given on the command line. The old VIN is saved
+/// to `vin-backup-<time>.json` in `backup_dir` before anything is written.
+fn write_vin<U: Uds>(
+    out: Output,
+    driver: &mut U,
+    profile: &EcuProfile,
+    matches: &clap::ArgMatches,
+    backup_dir: Option<&Path>,
+) {
+    let vin = Vin::parse(matches.value_of("VIN").unwrap()).unwrap();
+    if !vin.is_mazda() {
+        out.error(format!("{} is not a Mazda VIN", vin));
+        return;
+    }
+    let session = match matches
+        .value_of("session")
+        .and_then(|value| u8::from_str_radix(value.trim_start_matches("0x"), 16).ok())
+    {
+        Some(session) => session,
+        None => {
+            out.error("Invalid session");
+            return;
+        }
+    };
+    let request_id = profile.request_id;
+    let old = match coding::read_vin(driver, request_id) {
+        Ok(old) => old,
+        Err(err) => {
+            out.error(format!("Failed to read the current VIN: {}", err));
+            return;
+        }
+    };
+    if old == vin.as_str() {
+        out.message(format!("The ECU is already coded to {}", vin));
+        return;
+    }
+    if !matches.is_present("yes") && !confirm_vin_write(out, &old, &vin) {
+        return;
+    }
 
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let file_name = format!("vin-backup-{}.json", timestamp);
+    let backup_path = match backup_dir {
+        Some(dir) => dir.join(file_name),
+        None => PathBuf::from(file_name),
+    };
+    let backup = json!({
+        "vin": old,
+        "calibration_id": driver.calibration_id(request_id).ok(),
+        "timestamp": timestamp,
+    });
+    if let Err(err) = fs::write(&backup_path, serde_json::to_string_pretty(&backup).unwrap()) {
+        out.error(format!("Failed to write backup: {}", err));
+        return;
+    }
+    out.message(format!(
+        "Backed up the old VIN to {}",
+        backup_path.display()
+    ));
 
-    // Create PassThru connection
-    let mut driver = PassThruIsoTp::new(&d, 500000, 15000).unwrap();
-    // isotp.set_filter(0x7e0, 0x7e8);
-    let vin = driver.query_vin(0x7e0).unwrap();
-    println!("VIN: {}", vin);
+    match coding::write_vin(driver, profile, session, &vin) {
+        Ok(()) => {
+            out.message(format!("The ECU is now coded to {}", vin));
+            out.event(json!({
+                "event": "vin_written",
+                "vin": vin.as_str(),
+                "previous": old,
+                "backup": backup_path,
+            }));
+        }
+        Err(err) => out.error(format!("Writing the VIN failed: {}", err)),
+    }
+}
+
+/// Asks on stdin before replacing the VIN `old` with `vin`. Writes in JSON
+/// mode must be confirmed with `--yes`.
+fn confirm_vin_write(out: Output, old: &str, vin: &Vin) -> bool {
+    if out.is_json() {
+        out.error("Writing the VIN requires --yes in JSON mode");
+        return false;
+    }
+    let old = if old.is_empty() { "no VIN" } else { old };
+    print!(
+        "Replace {} with {}? Only code an ECU to the vehicle it is installed in. [y/N] ",
+        old, vin
+    );
+    let _ = io::stdout().flush();
+    let mut answer = String::new();
+    if io::stdin().lock().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim(), "y" | "Y" | "yes")
+}
+
+/// Queries the VIN, calibration and trouble codes of the ECU
+fn query<U: Uds>(out: Output, driver: &mut U, request_id: u32) {
+    let vin = match driver.query_vin(request_id) {
+        Ok(vin) => vin,
+        Err(err) => {
+            out.error(format!("Failed to read the VIN: {}", err));
+            return;
+        }
+    };
+    print_vehicle(out, &vin);
+    if let Ok(calibration_id) = driver.calibration_id(request_id) {
+        print_calibration(out, &calibration_id, calibration::lookup(&calibration_id));
+    }
 
     // Query trouble codes
-    for code in driver.query_trouble_codes(0x7e0).unwrap().iter() {
-        println!("{}", code);
+    let codes: Vec<Dtc> = match driver.query_trouble_codes(request_id) {
+        Ok(codes) => codes.iter().map(Dtc::from).collect(),
+        Err(err) => {
+            out.error(format!("Failed to read trouble codes: {}", err));
+            return;
+        }
+    };
+    for code in codes.iter() {
+        out.message(code);
+    }
+    out.event(json!({
+        "event": "info",
+        "vin": vin,
+        "dtcs": codes,
+    }));
+}
+
+/// Reads the part number of each known module on `bus`
+fn query_modules(out: Output, channel: &mut PassThruChannel, bus: CanBus) {
+    out.message(format!("Querying modules on {}", bus));
+    for module in module::on_bus(bus) {
+        if let Err(err) = channel.set_filter(module.request_id, module.response_id()) {
+            out.error(format!("Failed to set filter: {}", err));
+            return;
+        }
+        match module.part_number(channel) {
+            Ok(part_number) => {
+                out.message(format!(
+                    "0x{:03X} {}: {}",
+                    module.request_id, module.name, part_number
+                ));
+                out.event(
+                    json!({ "event": "module", "module": module, "part_number": part_number }),
+                );
+            }
+            Err(err) => {
+                out.message(format!(
+                    "0x{:03X} {}: no response ({})",
+                    module.request_id, module.name, err
+                ));
+                out.event(json!({ "event": "module", "module": module, "error": err.to_string() }));
+            }
+        }
+    }
+}
+
+/// Probes the diagnostic IDs of `bus` and lists the modules that answer
+fn scan(out: Output, channel: &mut PassThruChannel, bus: CanBus) {
+    out.message(format!("Scanning {}", bus));
+    let mut found = 0;
+    for request_id in module::scan_ids(bus) {
+        if let Err(err) = channel.set_filter(request_id, request_id + 8) {
+            out.error(format!("Failed to set filter: {}", err));
+            return;
+        }
+        if let Some(module) = module::probe(channel, request_id) {
+            found += 1;
+            out.message(format!(
+                "0x{:03X} {}: {}",
+                module.request_id,
+                module.name.unwrap_or("Unknown module"),
+                module
+                    .identification
+                    .as_deref()
+                    .unwrap_or("no identification")
+            ));
+            out.event(json!({ "event": "module", "module": module }));
+        }
+    }
+    out.message(format!("{} modules found", found));
+}
+
+/// Prints the vehicle and calibration a dump belongs to, from the metadata
+/// recorded with it and the calibration ID stored in the ROM
+fn print_dump(out: Output, path: &Path) {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(err) => {
+            out.error(format!("Failed to read {}: {}", path.display(), err));
+            return;
+        }
+    };
+    let (metadata, image) = if RomContainer::is_container(&data) {
+        match RomContainer::read(&data) {
+            Ok(container) => (container.header.metadata, container.data),
+            Err(err) => {
+                out.error(format!("Invalid container {}: {}", path.display(), err));
+                return;
+            }
+        }
+    } else {
+        (RomMetadata::load(path).ok(), data)
+    };
+
+    match metadata {
+        Some(ref metadata) => print_vehicle(out, &metadata.vin),
+        None => out.message(format!("No VIN is recorded for {}", path.display())),
+    }
+    match calibration::identify(&image) {
+        Some(calibration) => print_calibration(out, calibration.id, Some(calibration)),
+        None => match metadata.and_then(|metadata| metadata.calibration_id) {
+            Some(id) => print_calibration(out, &id, calibration::lookup(&id)),
+            None => out.message("Calibration: unknown"),
+        },
+    }
+}
+
+/// Prints a calibration ID along with the vehicle it was released for
+fn print_calibration(out: Output, id: &str, calibration: Option<&Calibration>) {
+    match calibration {
+        Some(calibration) => out.message(format!("Calibration: {} - {}", id, calibration)),
+        None => out.message(format!("Calibration: {} (unknown)", id)),
+    }
+    out.event(json!({ "event": "calibration", "id": id, "calibration": calibration }));
+}
+
+/// Prints a VIN along with the vehicle it decodes to
+fn print_vehicle(out: Output, vin: &str) {
+    out.message(format!("VIN: {}", vin));
+    let vin = match Vin::parse(vin) {
+        Ok(vin) => vin,
+        Err(err) => {
+            out.message(format!("Cannot decode VIN: {}", err));
+            out.event(json!({ "event": "vehicle", "vin": vin, "error": err.to_string() }));
+            return;
+        }
+    };
+    let info = vin.decode();
+    let unknown = "unknown";
+    out.message(format!(
+        "Vehicle: {} {}",
+        info.model_year
+            .map_or_else(|| unknown.to_string(), |year| year.to_string()),
+        info.model.unwrap_or(unknown)
+    ));
+    out.message(format!("Engine: {}", info.engine.unwrap_or(unknown)));
+    out.message(format!(
+        "Manufacturer: {}",
+        info.manufacturer.unwrap_or(unknown)
+    ));
+    out.message(format!("Plant: {}", info.plant.unwrap_or(unknown)));
+    out.event(json!({ "event": "vehicle", "vin": vin.as_str(), "decoded": info }));
+}
+
+/// Returns the CAN bitrate selected by `--bitrate` for a J2534 adapter.
+/// J2534 has no listen-only mode, and probing with an active controller at
+/// the wrong bitrate disturbs the bus, so `auto` is refused.
+fn can_bitrate(out: Output, value: &str) -> Option<u32> {
+    if value == "auto" {
+        out.error("J2534 adapters can't detect the bitrate without disturbing the bus. Pass it with --bitrate");
+        return None;
+    }
+    match value.parse() {
+        Ok(bitrate) => Some(bitrate),
+        Err(_) => {
+            out.error(format!("Invalid bitrate '{}'", value));
+            None
+        }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,156 @@
+//! Terminal dashboard of live values, for users without a laptop GUI
+
+use std::io;
+use std::time::{Duration, Instant};
+
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Borders, Gauge as GaugeBar, Paragraph, Sparkline};
+use ratatui::Frame;
+
+use mzr::daemon::read_pid;
+use mzr::dashboard::{Dashboard, Gauge};
+use mzr::datalink::can::Can;
+use mzr::isotp::IsotpCan;
+use mzr::record::{LogChannel, Record};
+
+/// Response timeout of each PID request
+const REQUEST_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Resolution of the sparklines
+const SPARKLINE_MAX: u64 = 1000;
+
+/// Reads `channels` from the ECU at `request_id` and shows them on
+/// `dashboard` until q or Esc is pressed
+pub fn run<C: Can>(
+    can: &C,
+    request_id: u32,
+    response_id: u32,
+    channels: &[LogChannel],
+    mut dashboard: Dashboard,
+) -> io::Result<()> {
+    let isotp = IsotpCan::new(can, request_id, response_id, REQUEST_TIMEOUT);
+    isotp.filter_bus().map_err(io::Error::other)?;
+
+    let mut terminal = ratatui::try_init()?;
+    let mut error = None;
+    let mut rate = 0.0;
+    let result = loop {
+        let start = Instant::now();
+        let mut record = Record::new();
+        for channel in channels {
+            match read_pid(&isotp, channel.pid) {
+                Ok(data) => {
+                    if let Some(value) = channel.decode(&data) {
+                        record.values.insert(channel.name.clone(), value);
+                    }
+                }
+                Err(err) => error = Some(format!("PID 0x{:04X}: {}", channel.pid, err)),
+            }
+        }
+        dashboard.update(&record);
+        rate = 0.8 * rate + 0.2 / start.elapsed().as_secs_f64().max(0.001);
+
+        let status = format!(
+            " q quit  r reset min/max  {:.1} records/s  {}",
+            rate,
+            error.as_deref().unwrap_or("")
+        );
+        if let Err(err) = terminal.draw(|frame| draw(frame, &dashboard, &status)) {
+            break Err(err);
+        }
+        match handle_keys(&mut dashboard) {
+            Ok(true) => break Ok(()),
+            Ok(false) => {}
+            Err(err) => break Err(err),
+        }
+    };
+    ratatui::restore();
+    result
+}
+
+/// Handles pending key presses. Returns whether to quit.
+fn handle_keys(dashboard: &mut Dashboard) -> io::Result<bool> {
+    while event::poll(Duration::ZERO)? {
+        if let Event::Key(key) = event::read()? {
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => return Ok(true),
+                KeyCode::Char('r') => dashboard.reset(),
+                _ => {}
+            }
+        }
+    }
+    Ok(false)
+}
+
+fn draw(frame: &mut Frame, dashboard: &Dashboard, status: &str) {
+    let mut constraints = vec![Constraint::Length(4); dashboard.gauges.len()];
+    constraints.push(Constraint::Min(1));
+    let rows = Layout::vertical(constraints).split(frame.area());
+    for (gauge, &area) in dashboard.gauges.iter().zip(rows.iter()) {
+        draw_gauge(frame, gauge, area);
+    }
+    frame.render_widget(Paragraph::new(status), rows[rows.len() - 1]);
+}
+
+/// Formats an optional value for display
+fn value(value: Option<f64>) -> String {
+    value.map_or_else(|| "-".to_string(), |value| format!("{:.2}", value))
+}
+
+fn draw_gauge(frame: &mut Frame, gauge: &Gauge, area: Rect) {
+    let mut title = format!(
+        " {}  min {}  max {} ",
+        gauge.name,
+        value(gauge.min),
+        value(gauge.max)
+    );
+    let mut style = Style::default();
+    if gauge.knock && gauge.knock_events > 0 {
+        title.push_str(&format!("knock events {} ", gauge.knock_events));
+    }
+    if gauge.knocking() {
+        style = style.fg(Color::Red).add_modifier(Modifier::BOLD);
+    }
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .border_style(style)
+        .title(Line::styled(title, style));
+    let inner = block.inner(area);
+    frame.render_widget(block, area);
+
+    let [bar, history] =
+        Layout::horizontal([Constraint::Percentage(40), Constraint::Percentage(60)]).areas(inner);
+    let label = format!("{} {}", value(gauge.latest), gauge.unit);
+    frame.render_widget(
+        GaugeBar::default()
+            .gauge_style(if gauge.knocking() {
+                style
+            } else {
+                style.fg(Color::Cyan)
+            })
+            .ratio(gauge.ratio())
+            .label(label.trim_end().to_string()),
+        bar,
+    );
+
+    let data: Vec<u64> = gauge
+        .history
+        .iter()
+        .map(|&value| (gauge.scale(value) * SPARKLINE_MAX as f64) as u64)
+        .collect();
+    // Show the latest values when the history is wider than the area
+    let skip = data.len().saturating_sub(history.width as usize);
+    frame.render_widget(
+        Sparkline::default()
+            .data(&data[skip..])
+            .max(SPARKLINE_MAX)
+            .style(style),
+        history,
+    );
+}
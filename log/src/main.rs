@@ -1,51 +1,808 @@
 //! This example queries a VIN using a PassThru device
 
-use anyhow::Result;
+mod dashboard;
+
 use obd::{PassThruIsoTp, Uds};
-use std::fs;
 
-use mzr::{MzrBus};
+use mzr::binlog::{self, BinaryLogWriter};
+use mzr::cancel::CancelToken;
+use mzr::config::Config;
+use mzr::daemon::{Daemon, DaemonEvent};
+use mzr::dashboard::Dashboard;
+use mzr::datalink::can::Can;
+use mzr::gps::{GpsConfig, GpsMerger, GpsReceiver};
+use mzr::influx::InfluxWriter;
+use mzr::kwp::{Kwp, ENGINE_ADDRESS};
+use mzr::lap::{self, MarkerInjector, Markers};
+use mzr::metadata::{self, Annotation, LogMetadata};
+use mzr::mlg;
+use mzr::mqtt::MqttPublisher;
+use mzr::output::Output;
+use mzr::passthru::{self, PassThruCan, PassThruKLine};
+use mzr::profile::EcuProfile;
+use mzr::record::{self, LogChannel, Record, RecordSink, RecordWriter};
 
-use clap::clap_app;
-use indicatif::{ProgressBar, ProgressStyle};
+use clap::{clap_app, ArgMatches};
+use serde_json::json;
+use tracing_subscriber::filter::{LevelFilter, Targets};
+use tracing_subscriber::fmt;
+use tracing_subscriber::prelude::*;
 
+use std::ffi::OsStr;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufWriter};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex};
+use std::thread;
 use std::time::{Duration, Instant};
 
-pub fn main() {
+pub fn main() -> ExitCode {
+    run();
+    Output::exit_code()
+}
+
+fn run() {
     let matches = clap_app!(myapp =>
         (version: "1.0")
         (author: "Jacob Manning <jjacob.manning@gmail.com>")
         (about: "Queries information from an MZR-DISI ECU")
-        (@arg passthru: -p --passthru +takes_value "PassThru device to use when connecting to the ECU")
+        (@arg device: -d --device alias("passthru") +takes_value "J2534 device to use when connecting to the ECU (name or index)")
+        (@arg library: --library +takes_value "J2534 library (DLL) to load instead of the installed drivers")
         (@arg model: -m --model +takes_value "Vehicle model")
+        (@arg bitrate: --bitrate +takes_value default_value("500000") "CAN bitrate in bit/s, or auto to detect it on a SocketCAN interface (daemon --can)")
+        (@arg protocol: -p --protocol +takes_value possible_values(&["can", "kwp"]) default_value("can") "Diagnostic protocol: CAN, or KWP2000 over K-line for early vehicles")
+        (@arg json: --json "Prints machine-readable JSON output")
+        (@arg dashboard: --dashboard "Shows the logged channels live as gauges in the terminal")
+        (@arg debug_adapter: --("debug-adapter") "Logs every J2534 call with its parameters and return code to stderr")
+        (@subcommand devices =>
+            (about: "Lists installed J2534 devices"))
+        (@subcommand daemon =>
+            (about: "Logs the configured PIDs on every ignition cycle until stopped, for adapters left in the car")
+            (@arg can: --can +takes_value "SocketCAN interface to use instead of a J2534 device, e.g. can0 (Linux only)")
+            (@arg output_dir: -o --("output-dir") +takes_value "Directory for log files (default: output_dir from the config, or the current directory)")
+            (@arg max_size: --("max-size") +takes_value default_value("10") "Size in MB after which a new log file is started")
+            (@arg max_age: --("max-age") +takes_value default_value("60") "Age in minutes after which a new log file is started")
+            (@arg idle: --idle +takes_value default_value("5") "Seconds without answers from the ECU after which the ignition is assumed off")
+            (@arg dtc_interval: --("dtc-interval") +takes_value "Seconds between polls of the trouble codes, to log codes setting during the session as events")
+            (@arg gps: --gps +takes_value "Serial port of an NMEA GPS receiver whose position is added to the records")
+            (@arg records: --records +takes_value "File the decoded records, markers and session metadata are written to, for the laps and convert subcommands: a binary log if it ends in .mzrlog, otherwise JSON lines appended to it")
+            (@arg marker_gpio: --("marker-gpio") +takes_value "Value file of a GPIO input whose rising edge sets a marker, e.g. /sys/class/gpio/gpio17/value"))
+        (@subcommand laps =>
+            (about: "Splits a records file at its markers and reports the min/max/avg of channels per lap")
+            (@arg FILE: +required "Records file written with daemon --records")
+            (@arg channels: --channels +takes_value "Comma-separated channels to report (default: channels from the config, or all)"))
+        (@subcommand convert =>
+            (about: "Converts a records file to CSV, MegaLogViewer (.mlg) or a binary log (.mzrlog)")
+            (@arg INPUT: +required "Records file written with daemon --records")
+            (@arg OUTPUT: +required "Output file, whose extension selects the format"))
+        (@subcommand annotate =>
+            (about: "Adds a note to a log file, kept next to it in LOG.notes")
+            (@arg LOG: +required "Log file")
+            (@arg TEXT: +required "Text of the note"))
+        (@subcommand info =>
+            (about: "Prints the session metadata and notes of a log file")
+            (@arg LOG: +required "Log file"))
     )
     .get_matches();
 
-    // Get a list of interfaces
-    let device = match j2534::drivers().unwrap().into_iter().next() {
-        Some(device) => device,
-        None => {
-            println!("No J2534 interfaces found");
+    let out = Output::new(matches.is_present("json"));
+    if matches.subcommand_matches("devices").is_some() {
+        print_drivers(out);
+        return;
+    }
+    // --debug-adapter shows the J2534 calls traced by mzr on stderr
+    if matches.is_present("debug_adapter") {
+        tracing_subscriber::registry()
+            .with(fmt::layer().with_writer(io::stderr))
+            .with(Targets::new().with_target("j2534", LevelFilter::TRACE))
+            .init();
+    }
+
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(err) => {
+            out.error(err);
             return;
         }
     };
+    let (request_id, response_id) =
+        match EcuProfile::select(None, matches.value_of("model"), &config) {
+            Ok(Some(profile)) => (profile.request_id, profile.response_id),
+            Ok(None) => (config.request_id, config.response_id),
+            Err(err) => {
+                out.error(err);
+                return;
+            }
+        };
 
-    println!("Opening interface '{}'", device.name);
-    let i = j2534::Interface::new(&device.path).unwrap();
+    if let Some(laps_matches) = matches.subcommand_matches("laps") {
+        print_laps(out, laps_matches, &config);
+        return;
+    }
+    if let Some(convert_matches) = matches.subcommand_matches("convert") {
+        convert(out, convert_matches);
+        return;
+    }
+    if let Some(annotate_matches) = matches.subcommand_matches("annotate") {
+        annotate(out, annotate_matches);
+        return;
+    }
+    if let Some(info_matches) = matches.subcommand_matches("info") {
+        print_info(out, info_matches);
+        return;
+    }
+
+    let pids = if config.log_pids.is_empty() {
+        vec![0, 1, 2]
+    } else {
+        config.log_pids.clone()
+    };
+
+    let daemon = match matches.subcommand_matches("daemon") {
+        Some(daemon_matches) => match daemon_settings(
+            out,
+            daemon_matches,
+            &config,
+            (request_id, response_id),
+            pids.clone(),
+        ) {
+            Some(settings) => Some((settings, daemon_matches.value_of("can"))),
+            None => return,
+        },
+        None => None,
+    };
+    let daemon = match daemon {
+        Some(((mut daemon, sinks, current), Some(interface))) => {
+            daemon.metadata.adapter = Some(format!("SocketCAN {}", interface));
+            let bitrate = matches.value_of("bitrate").unwrap();
+            run_socketcan_daemon(out, &daemon, sinks, &current, interface, bitrate);
+            return;
+        }
+        daemon => daemon,
+    };
+
+    // Select an interface
+    let selector = matches.value_of("device").or(config.device.as_deref());
+    let library = matches
+        .value_of("library")
+        .map(Path::new)
+        .or(config.library.as_deref());
+    let device = match passthru::select_driver(library, selector) {
+        Ok(device) => device,
+        Err(err) => {
+            out.error(err);
+            return;
+        }
+    };
+
+    out.message(format!("Opening interface '{}'", device.name));
+    let i = match j2534::Interface::new(&device.path) {
+        Ok(i) => i,
+        Err(err) => {
+            out.error(format!("Failed to load {}: {}", device.path, err));
+            return;
+        }
+    };
     // Open any connected device
-    let d = i.open_any().unwrap();
+    let d = match passthru::traced("PassThruOpen", format_args!(""), || i.open_any()) {
+        Ok(d) => d,
+        Err(err) => {
+            out.error(format!("Failed to open the adapter: {}", err));
+            return;
+        }
+    };
     // Get version information
-    let version_info = d.read_version().unwrap();
-    println!("{:#?}", version_info);
+    let version_info =
+        match passthru::traced("PassThruReadVersion", format_args!(""), || d.read_version()) {
+            Ok(version_info) => version_info,
+            Err(err) => {
+                out.error(format!("Failed to read the adapter version: {}", err));
+                return;
+            }
+        };
+    out.message(format!("{:#?}", version_info));
+    out.event(json!({
+        "event": "connected",
+        "interface": device.name,
+        "firmware_version": version_info.firmware_version,
+        "dll_version": version_info.dll_version,
+        "api_version": version_info.api_version,
+    }));
+
+    if let Some(((mut daemon, sinks, current), _)) = daemon {
+        daemon.metadata.adapter = Some(device.name.clone());
+        daemon.metadata.adapter_firmware = Some(version_info.firmware_version.clone());
+        let bitrate = match can_bitrate(out, matches.value_of("bitrate").unwrap()) {
+            Some(bitrate) => bitrate,
+            None => return,
+        };
+        match PassThruCan::new(&d, bitrate) {
+            Ok(can) => run_daemon(out, &daemon, sinks, &current, &can, &device.name),
+            Err(err) => out.error(format!("Failed to open CAN channel: {}", err)),
+        }
+    } else if matches.is_present("dashboard") {
+        let bitrate = match can_bitrate(out, matches.value_of("bitrate").unwrap()) {
+            Some(bitrate) => bitrate,
+            None => return,
+        };
+        let can = match PassThruCan::new(&d, bitrate) {
+            Ok(can) => can,
+            Err(err) => {
+                out.error(format!("Failed to open CAN channel: {}", err));
+                return;
+            }
+        };
+        let channels = log_channels(&config, &pids);
+        let dashboard = Dashboard::new(&config.dashboard.clone().unwrap_or_default(), &channels);
+        if let Err(err) = dashboard::run(&can, request_id, response_id, &channels, dashboard) {
+            out.error(format!("Dashboard failed: {}", err));
+        }
+    } else if matches.value_of("protocol") == Some("kwp") {
+        let line = match PassThruKLine::new(&d, Duration::from_millis(1000)) {
+            Ok(line) => line,
+            Err(err) => {
+                out.error(format!("Failed to open K-line channel: {}", err));
+                return;
+            }
+        };
+        log(out, &mut Kwp::new(line, ENGINE_ADDRESS), request_id, &pids);
+    } else {
+        let bitrate = match can_bitrate(out, matches.value_of("bitrate").unwrap()) {
+            Some(bitrate) => bitrate,
+            None => return,
+        };
+        // Create PassThru connection
+        let mut driver = match PassThruIsoTp::new(&d, bitrate, 15000) {
+            Ok(driver) => driver,
+            Err(err) => {
+                out.error(format!("Failed to open channel: {}", err));
+                return;
+            }
+        };
+        if let Err(err) = driver.set_filter(request_id, response_id) {
+            out.error(format!("Failed to set filter: {}", err));
+            return;
+        }
+        log(out, &mut driver, request_id, &pids);
+    }
+}
+
+/// Prints the installed J2534 drivers, numbered for `--device`
+fn print_drivers(out: Output) {
+    let drivers = match passthru::installed_drivers() {
+        Ok(drivers) => drivers,
+        Err(err) => {
+            out.error(err);
+            return;
+        }
+    };
+    for (index, driver) in drivers.iter().enumerate() {
+        out.message(format!(
+            "{}: {} ({})\n   {}",
+            index, driver.name, driver.vendor, driver.path
+        ));
+        out.event(json!({
+            "event": "device",
+            "index": index,
+            "name": driver.name,
+            "vendor": driver.vendor,
+            "path": driver.path,
+        }));
+    }
+}
+
+/// Returns the channels of the config, or raw channels of `pids` if none
+/// are configured
+fn log_channels(config: &Config, pids: &[u16]) -> Vec<LogChannel> {
+    let channels = config.channels();
+    if channels.is_empty() {
+        record::channels_for(pids, &[])
+    } else {
+        channels
+    }
+}
+
+/// Records file of the daemon, in one of the two formats
+type RecordFile = (
+    Option<RecordWriter<BufWriter<File>>>,
+    Option<BinaryLogWriter<BufWriter<File>>>,
+);
+
+/// Outputs of the daemon's records besides the CSV files
+type Outputs = ((Option<MqttPublisher>, Option<InfluxWriter>), RecordFile);
+
+/// Opens the records file at `path`, as a binary log of `channels` and the
+/// GPS fields if it has the binary log extension
+fn open_records(path: &str, channels: &[LogChannel], gps: bool) -> io::Result<RecordFile> {
+    if Path::new(path).extension() == Some(OsStr::new(binlog::EXTENSION)) {
+        let fields = binlog::fields_for(channels, gps);
+        let writer = BinaryLogWriter::new(BufWriter::new(File::create(path)?), fields);
+        return Ok((None, Some(writer)));
+    }
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    Ok((Some(RecordWriter::new(BufWriter::new(file))), None))
+}
+
+/// Outputs behind the markers, with the position added to the records if a
+/// GPS is configured
+enum Sinks {
+    Direct(MarkerInjector<Outputs>),
+    Gps(GpsMerger<MarkerInjector<Outputs>>),
+}
+
+impl RecordSink for Sinks {
+    fn send(&mut self, record: &Record) {
+        match self {
+            Sinks::Direct(outputs) => outputs.send(record),
+            Sinks::Gps(merger) => merger.send(record),
+        }
+    }
+
+    fn begin_session(&mut self, metadata: &LogMetadata) {
+        match self {
+            Sinks::Direct(outputs) => outputs.begin_session(metadata),
+            Sinks::Gps(merger) => merger.begin_session(metadata),
+        }
+    }
+
+    fn flush(&mut self) {
+        match self {
+            Sinks::Direct(outputs) => outputs.flush(),
+            Sinks::Gps(merger) => merger.flush(),
+        }
+    }
+}
+
+/// CSV file the daemon is writing to, if any
+type CurrentFile = Arc<Mutex<Option<PathBuf>>>;
+
+/// Returns the daemon configured by the arguments of the daemon subcommand,
+/// the outputs of its records with the configured marker sources, and the
+/// CSV file that notes typed on the terminal are added to
+fn daemon_settings(
+    out: Output,
+    matches: &ArgMatches,
+    config: &Config,
+    (request_id, response_id): (u32, u32),
+    pids: Vec<u16>,
+) -> Option<(Daemon, Sinks, CurrentFile)> {
+    let number = |name| -> Option<u64> {
+        let value = matches.value_of(name).unwrap();
+        let number = value.parse().ok();
+        if number.is_none() {
+            out.error(format!("Invalid {} '{}'", name, value));
+        }
+        number
+    };
+    let max_size = number("max_size")?;
+    let max_age = number("max_age")?;
+    let idle = number("idle")?;
+
+    let output_dir = matches
+        .value_of("output_dir")
+        .map(PathBuf::from)
+        .or_else(|| config.output_dir.clone())
+        .unwrap_or_else(|| PathBuf::from("."));
+    let mut daemon = Daemon::new(log_channels(config, &pids), output_dir);
+    daemon.request_id = request_id;
+    daemon.response_id = response_id;
+    daemon.max_file_size = max_size * 1024 * 1024;
+    daemon.max_file_age = Duration::from_secs(max_age * 60);
+    daemon.idle_timeout = Duration::from_secs(idle);
+    if matches.is_present("dtc_interval") {
+        daemon.dtc_interval = Some(Duration::from_secs(number("dtc_interval")?));
+    }
+
+    let publisher = match config.mqtt {
+        Some(ref mqtt) => match MqttPublisher::connect(mqtt) {
+            Ok(publisher) => {
+                out.message(format!(
+                    "Publishing to MQTT broker {}:{}",
+                    mqtt.host, mqtt.port
+                ));
+                Some(publisher)
+            }
+            Err(err) => {
+                out.error(format!("Invalid MQTT settings: {}", err));
+                return None;
+            }
+        },
+        None => None,
+    };
+    let writer = config.influx.as_ref().map(|influx| {
+        out.message(format!("Writing to InfluxDB at {}", influx.url));
+        InfluxWriter::new(influx)
+    });
+    let gps = match matches.value_of("gps") {
+        Some(device) => Some(GpsConfig {
+            device: device.to_string(),
+            ..config.gps.clone().unwrap_or_default()
+        }),
+        None => config.gps.clone(),
+    };
+    let records = match matches.value_of("records") {
+        Some(path) => match open_records(path, &daemon.channels, gps.is_some()) {
+            Ok(records) => records,
+            Err(err) => {
+                out.error(format!("Failed to open {}: {}", path, err));
+                return None;
+            }
+        },
+        None => (None, None),
+    };
 
+    // Enter on the terminal sets a marker, labelled with the text typed.
+    // Text starting with # is a note on the current log files instead.
+    let markers = Markers::new();
+    let current = CurrentFile::default();
+    {
+        let markers = markers.clone();
+        let current = current.clone();
+        let records_path = matches.value_of("records").map(PathBuf::from);
+        thread::spawn(move || {
+            for line in io::stdin().lock().lines().map_while(Result::ok) {
+                let label = line.trim();
+                if let Some(text) = label.strip_prefix('#') {
+                    let annotation = Annotation::new(text.trim());
+                    let csv = current.lock().unwrap().clone();
+                    for path in records_path.iter().chain(csv.iter()) {
+                        if let Err(err) = annotation.append(path) {
+                            eprintln!("Failed to add note to {}: {}", path.display(), err);
+                        }
+                    }
+                } else {
+                    markers.mark(if label.is_empty() { "marker" } else { label });
+                }
+            }
+        });
+    }
+    if let Some(path) = matches.value_of("marker_gpio") {
+        markers.watch_gpio(PathBuf::from(path));
+    }
+    let lap = config.lap.clone().unwrap_or_default();
+    let sinks = MarkerInjector::new(markers, &lap, ((publisher, writer), records));
+
+    let sinks = match gps {
+        Some(gps) => match GpsReceiver::open(&gps) {
+            Ok(receiver) => {
+                out.message(format!("Reading GPS from {}", gps.device));
+                Sinks::Gps(GpsMerger::new(receiver, sinks))
+            }
+            Err(err) => {
+                out.error(format!("Failed to open GPS {}: {}", gps.device, err));
+                return None;
+            }
+        },
+        None => Sinks::Direct(sinks),
+    };
+    Some((daemon, sinks, current))
+}
+
+/// Runs `daemon` on `can` until Ctrl-C or SIGTERM, keeping track of the
+/// `current` CSV file
+fn run_daemon<C: Can>(
+    out: Output,
+    daemon: &Daemon,
+    sinks: Sinks,
+    current: &CurrentFile,
+    can: &C,
+    interface: &str,
+) {
+    let token = CancelToken::new();
+    {
+        let token = token.clone();
+        ctrlc::set_handler(move || token.cancel()).unwrap();
+    }
+
+    out.message(format!("Waiting for traffic on {}", interface));
+    let result = daemon.run(
+        can,
+        &token,
+        |event| {
+            match event {
+                DaemonEvent::FileOpened { ref path } => {
+                    *current.lock().unwrap() = Some(path.clone());
+                }
+                // CSV files have no column for events, so trouble codes
+                // are added as notes
+                DaemonEvent::TroubleCode { .. } => {
+                    if let Some(ref path) = *current.lock().unwrap() {
+                        if let Err(err) = Annotation::new(&event.to_string()).append(path) {
+                            out.error(format!("Failed to add note to {}: {}", path.display(), err));
+                        }
+                    }
+                }
+                _ => {}
+            }
+            out.message(&event);
+            out.event(serde_json::to_value(&event).unwrap());
+        },
+        sinks,
+    );
+    if let Err(err) = result {
+        out.error(err);
+    }
+}
 
-    // Create PassThru connection
-    let mut driver = PassThruIsoTp::new(&d, 500000, 15000).unwrap();
-    // isotp.set_filter(0x7e0, 0x7e8);
+#[cfg(target_os = "linux")]
+fn run_socketcan_daemon(
+    out: Output,
+    daemon: &Daemon,
+    sinks: Sinks,
+    current: &CurrentFile,
+    interface: &str,
+    bitrate: &str,
+) {
+    use mzr::datalink::socketcan::{self, SocketCan};
+
+    // The interface is used as configured unless asked to detect the bitrate
+    if bitrate == "auto" {
+        out.message("Detecting CAN bitrate");
+        match socketcan::detect_bitrate(interface, Duration::from_secs(1)) {
+            Ok(Some(bitrate)) => {
+                out.message(format!("Detected {} bit/s", bitrate));
+                out.event(json!({ "event": "bitrate", "bitrate": bitrate }));
+            }
+            Ok(None) => {
+                out.error("No CAN traffic detected. Is the ignition on?");
+                return;
+            }
+            Err(err) => {
+                out.error(format!("Failed to detect CAN bitrate: {}", err));
+                return;
+            }
+        }
+    }
+    match SocketCan::open(interface) {
+        Ok(can) => run_daemon(out, daemon, sinks, current, &can, interface),
+        Err(err) => out.error(format!("Failed to open {}: {}", interface, err)),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn run_socketcan_daemon(
+    out: Output,
+    _daemon: &Daemon,
+    _sinks: Sinks,
+    _current: &CurrentFile,
+    _interface: &str,
+    _bitrate: &str,
+) {
+    out.error("SocketCAN is only available on Linux");
+}
+
+/// Prints the statistics of each lap in the records file given to the laps
+/// subcommand
+fn print_laps(out: Output, matches: &ArgMatches, config: &Config) {
+    let path = matches.value_of("FILE").unwrap();
+    let records = match binlog::load(Path::new(path)) {
+        Ok(log) => log.records,
+        Err(err) => {
+            out.error(format!("Failed to read {}: {}", path, err));
+            return;
+        }
+    };
+    let channels: Vec<String> = match matches.value_of("channels") {
+        Some(channels) => channels
+            .split(',')
+            .map(|name| name.trim().to_string())
+            .collect(),
+        None => config.lap.clone().unwrap_or_default().channels,
+    };
+    for lap in lap::laps(&records, &channels) {
+        let duration = lap.duration().as_millis();
+        out.message(format!(
+            "Lap {} ({}): {}:{:02}.{:03}",
+            lap.number,
+            lap.marker.as_deref().unwrap_or("start"),
+            duration / 60_000,
+            duration / 1000 % 60,
+            duration % 1000
+        ));
+        for (name, stats) in &lap.channels {
+            out.message(format!(
+                "  {}: min {:.2}, max {:.2}, avg {:.2}",
+                name, stats.min, stats.max, stats.avg
+            ));
+        }
+        let mut event = serde_json::to_value(&lap).unwrap();
+        event["event"] = json!("lap");
+        out.event(event);
+    }
+}
+
+/// Converts the records file given to the convert subcommand to the format
+/// of the output file's extension
+fn convert(out: Output, matches: &ArgMatches) {
+    let input = matches.value_of("INPUT").unwrap();
+    let output = Path::new(matches.value_of("OUTPUT").unwrap());
+    let log = match binlog::load(Path::new(input)) {
+        Ok(log) => log,
+        Err(err) => {
+            out.error(format!("Failed to read {}: {}", input, err));
+            return;
+        }
+    };
+    let annotations = match Annotation::load(Path::new(input)) {
+        Ok(annotations) => annotations,
+        Err(err) => {
+            out.error(format!("Failed to read the notes of {}: {}", input, err));
+            return;
+        }
+    };
+    let binlog::LoadedLog {
+        fields,
+        records,
+        metadata: session,
+    } = log;
+    // CSV and MLG files have no room for notes alongside, so they are
+    // included with the metadata
+    let description = metadata::describe(session.as_ref(), &annotations);
+    let extension = output.extension().and_then(OsStr::to_str).unwrap_or("");
+    if !["csv", "mlg", binlog::EXTENSION].contains(&extension) {
+        out.error("Unknown output format, expected .csv, .mlg or .mzrlog");
+        return;
+    }
+    let result = File::create(output).and_then(|file| {
+        let file = BufWriter::new(file);
+        match extension {
+            "csv" => binlog::write_csv(&fields, &records, &description, file),
+            "mlg" => mlg::write_mlg(&fields, &records, &description, file),
+            binlog::EXTENSION => {
+                let mut writer = BinaryLogWriter::new(file, fields);
+                if let Some(ref session) = session {
+                    writer.set_metadata(session);
+                }
+                for record in &records {
+                    writer.write(record)?;
+                }
+                writer.finish()?;
+                annotations
+                    .iter()
+                    .try_for_each(|annotation| annotation.append(output))
+            }
+            _ => unreachable!(),
+        }
+    });
+    match result {
+        Ok(()) => {
+            out.message(format!(
+                "Wrote {} records to {}",
+                records.len(),
+                output.display()
+            ));
+            out.event(json!({
+                "event": "converted",
+                "path": output,
+                "records": records.len(),
+            }));
+        }
+        Err(err) => out.error(format!("Failed to write {}: {}", output.display(), err)),
+    }
+}
+
+/// Adds the note given to the annotate subcommand to its log file
+fn annotate(out: Output, matches: &ArgMatches) {
+    let log = Path::new(matches.value_of("LOG").unwrap());
+    if !log.exists() {
+        out.error(format!("{} does not exist", log.display()));
+        return;
+    }
+    let annotation = Annotation::new(matches.value_of("TEXT").unwrap());
+    match annotation.append(log) {
+        Ok(()) => {
+            out.message(format!(
+                "Added note to {}",
+                Annotation::notes_path(log).display()
+            ));
+            out.event(json!({
+                "event": "annotated",
+                "path": log,
+                "time": annotation.time,
+                "text": annotation.text,
+            }));
+        }
+        Err(err) => out.error(format!("Failed to add note to {}: {}", log.display(), err)),
+    }
+}
+
+/// Prints the metadata and notes of the log file given to the info
+/// subcommand. CSV logs of the daemon keep their metadata in comment lines.
+fn print_info(out: Output, matches: &ArgMatches) {
+    let path = Path::new(matches.value_of("LOG").unwrap());
+    let annotations = match Annotation::load(path) {
+        Ok(annotations) => annotations,
+        Err(err) => {
+            out.error(format!(
+                "Failed to read the notes of {}: {}",
+                path.display(),
+                err
+            ));
+            return;
+        }
+    };
+    let (description, records, span) = if path.extension() == Some(OsStr::new("csv")) {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(err) => {
+                out.error(format!("Failed to read {}: {}", path.display(), err));
+                return;
+            }
+        };
+        let comment: String = text
+            .lines()
+            .take_while(|line| line.starts_with('#'))
+            .map(|line| format!("{}\n", line))
+            .collect();
+        let rows = text.lines().filter(|line| !line.starts_with('#')).count();
+        let description = comment + &metadata::describe(None, &annotations);
+        (description, rows.saturating_sub(1), None)
+    } else {
+        match binlog::load(path) {
+            Ok(log) => {
+                let span = match (log.records.first(), log.records.last()) {
+                    (Some(first), Some(last)) => Some((first.time, last.time)),
+                    _ => None,
+                };
+                let description = metadata::describe(log.metadata.as_ref(), &annotations);
+                (description, log.records.len(), span)
+            }
+            Err(err) => {
+                out.error(format!("Failed to read {}: {}", path.display(), err));
+                return;
+            }
+        }
+    };
+    for line in description.lines() {
+        out.message(line.trim_start_matches("# "));
+    }
+    out.message(format!("records: {}", records));
+    if let Some((start, end)) = span {
+        out.message(format!("duration: {:.1} s", (end - start) as f64 / 1000.0));
+    }
+    out.event(json!({
+        "event": "log_info",
+        "path": path,
+        "description": description,
+        "records": records,
+        "start": span.map(|span| span.0),
+        "end": span.map(|span| span.1),
+    }));
+}
+
+/// Measures the rate at which `pids` can be read
+fn log<U: Uds>(out: Output, driver: &mut U, request_id: u32, pids: &[u16]) {
+    let request: Vec<u8> = pids
+        .iter()
+        .flat_map(|pid| pid.to_be_bytes().to_vec())
+        .collect();
 
     let start_time = Instant::now();
-    for i in 0..100 {
-        let response = driver.query_uds(0x7e0, 0x22, &[0, 0, 0, 1, 0, 2]).unwrap(); //, 0, 3, 0, 4, 0, 5, 0, 6, 0, 7, 0, 8, 0, 9, 0, 10, 0, 11, 0, 12, 0, 13, 0, 14, 0, 15, 0, 16, 0, 17, 0, 18, 0, 19, 0, 20]).unwrap();
+    for _ in 0..100 {
+        if let Err(err) = driver.query_uds(request_id, 0x22, &request) {
+            out.error(format!("Failed to read PIDs: {}", err));
+            return;
+        }
+    }
+    let rate = pids.len() as f64 * 100.0 / start_time.elapsed().as_secs_f64();
+    out.message(format!("PID/s: {}", rate));
+    out.event(json!({ "event": "rate", "pids_per_second": rate }));
+}
+
+/// Returns the CAN bitrate selected by `--bitrate` for a J2534 adapter.
+/// J2534 has no listen-only mode, and probing with an active controller at
+/// the wrong bitrate disturbs the bus, so `auto` is refused.
+fn can_bitrate(out: Output, value: &str) -> Option<u32> {
+    if value == "auto" {
+        out.error("J2534 adapters can't detect the bitrate without disturbing the bus. Pass it with --bitrate");
+        return None;
+    }
+    match value.parse() {
+        Ok(bitrate) => Some(bitrate),
+        Err(_) => {
+            out.error(format!("Invalid bitrate '{}'", value));
+            None
+        }
     }
-    println!("PID/s: {}", 3.0 * 100.0 / start_time.elapsed().as_secs_f64());
-}
\ No newline at end of file
+}
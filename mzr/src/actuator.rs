@@ -0,0 +1,222 @@
+//! Output tests through RoutineControl, for workshop diagnosis: running the
+//! cooling fan, cycling the purge valve or priming the fuel pump. Which
+//! routines an ECU exposes differs between calibrations, so actuators are
+//! described in the ECU profile. Routines can be found with
+//! `mzr-probe capabilities`.
+//!
+//! ```toml
+//! [[actuators]]
+//! name = "fan"
+//! description = "Cooling fan at full speed"
+//! routine = 0x0203
+//! session = 0x03
+//! duration = 10
+//! warning = "Keep hands clear of the fan"
+//! ```
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use obd::Uds;
+use serde::{Deserialize, Serialize};
+
+use crate::cancel::CancelToken;
+use crate::profile::EcuProfile;
+use crate::{MzrBus, MzrError};
+
+const UDS_REQ_ROUTINECONTROL: u8 = 0x31;
+const ROUTINE_START: u8 = 0x01;
+const ROUTINE_STOP: u8 = 0x02;
+/// Negative response of routines that stop by themselves
+const NRC_SUBFUNCTION_NOT_SUPPORTED: u8 = 0x12;
+
+/// Longest time an output is driven, whatever is requested
+pub const MAX_DURATION: Duration = Duration::from_secs(60);
+
+/// Interval at which the session is kept alive while an output is driven
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Output the ECU can drive through a routine
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Actuator {
+    /// Name used on the command line, e.g. `fan`
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    /// Routine identifier
+    pub routine: u16,
+    /// Option record sent with the start request
+    #[serde(default)]
+    pub parameters: Vec<u8>,
+    /// Diagnostic session the routine runs in, extended by default
+    #[serde(default = "default_session")]
+    pub session: u8,
+    /// Whether security access is needed first
+    #[serde(default)]
+    pub security: bool,
+    /// Seconds the output is driven by default
+    #[serde(default = "default_duration")]
+    pub duration: u64,
+    /// Whether the engine must be off, which is the default
+    #[serde(default = "default_engine_off")]
+    pub engine_off: bool,
+    /// Shown before asking for confirmation
+    #[serde(default)]
+    pub warning: Option<String>,
+}
+
+fn default_session() -> u8 {
+    0x03
+}
+
+fn default_duration() -> u64 {
+    5
+}
+
+fn default_engine_off() -> bool {
+    true
+}
+
+/// Drives `actuator` for `duration`, capped to [`MAX_DURATION`], or until
+/// `cancel` is cancelled. `progress` is called with the time elapsed about
+/// every second. The routine is stopped and the session left even if
+/// driving the output fails.
+pub fn actuate<B, F>(
+    bus: &mut B,
+    profile: &EcuProfile,
+    actuator: &Actuator,
+    duration: Duration,
+    cancel: &CancelToken,
+    mut progress: F,
+) -> Result<(), MzrError>
+where
+    B: Uds,
+    F: FnMut(Duration),
+{
+    let request_id = profile.request_id;
+    if actuator.engine_off {
+        let rpm = bus.engine_rpm(request_id)?;
+        if rpm > 0.0 {
+            return Err(MzrError::EngineRunning(rpm));
+        }
+    }
+    bus.set_diagnostic_session(request_id, actuator.session)?;
+    let result = drive(
+        bus,
+        profile,
+        actuator,
+        duration.min(MAX_DURATION),
+        cancel,
+        &mut progress,
+    );
+    let exited = bus.exit_session(request_id);
+    result.and(exited)
+}
+
+fn drive<B: Uds, F: FnMut(Duration)>(
+    bus: &mut B,
+    profile: &EcuProfile,
+    actuator: &Actuator,
+    duration: Duration,
+    cancel: &CancelToken,
+    progress: &mut F,
+) -> Result<(), MzrError> {
+    let request_id = profile.request_id;
+    if actuator.security {
+        bus.unlock_with(request_id, profile.security_level, &profile.key_algorithm)?;
+    }
+    let [high, low] = actuator.routine.to_be_bytes();
+    let start = [&[ROUTINE_START, high, low][..], &actuator.parameters].concat();
+    bus.query_uds(request_id, UDS_REQ_ROUTINECONTROL, &start)?;
+
+    let started = Instant::now();
+    let mut result = Ok(());
+    while !cancel.is_cancelled() && started.elapsed() < duration {
+        thread::sleep(KEEP_ALIVE_INTERVAL.min(duration - started.elapsed()));
+        progress(started.elapsed());
+        if let Err(err) = bus.tester_present(request_id) {
+            result = Err(err);
+            break;
+        }
+    }
+
+    let stopped = match bus.query_uds(
+        request_id,
+        UDS_REQ_ROUTINECONTROL,
+        &[ROUTINE_STOP, high, low],
+    ) {
+        Ok(_) => Ok(()),
+        Err(obd::Error::NegativeResponse(Some(NRC_SUBFUNCTION_NOT_SUPPORTED))) => Ok(()),
+        Err(err) => Err(err.into()),
+    };
+    result.and(stopped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockEcu;
+
+    /// ECU with a fan routine 0x0203 in the extended session
+    fn ecu() -> MockEcu<()> {
+        MockEcu::new((), |_, request| {
+            Some(match request {
+                [0x10, session] => vec![0x50, *session],
+                [0x3E, 0x00] => vec![0x7E, 0x00],
+                [0x31, sub @ (0x01 | 0x02), 0x02, 0x03, ..] => vec![0x71, *sub, 0x02, 0x03],
+                _ => return None,
+            })
+        })
+    }
+
+    #[test]
+    fn drives_and_stops_routines() {
+        let profile: EcuProfile = toml::from_str(
+            "[[actuators]]\n\
+             name = \"fan\"\n\
+             routine = 0x0203\n\
+             parameters = [0xFF]\n",
+        )
+        .unwrap();
+        let fan = &profile.actuators[0];
+        assert_eq!((fan.session, fan.duration, fan.engine_off), (0x03, 5, true));
+
+        let mut ecu = ecu();
+        let mut ticks = 0;
+        actuate(
+            &mut ecu,
+            &profile,
+            fan,
+            Duration::from_millis(10),
+            &CancelToken::new(),
+            |_| ticks += 1,
+        )
+        .unwrap();
+        assert_eq!(ticks, 1);
+        assert_eq!(
+            ecu.requests,
+            [
+                vec![0x01, 0x0C],
+                vec![0x10, 0x03],
+                vec![0x31, 0x01, 0x02, 0x03, 0xFF],
+                vec![0x3E, 0x00],
+                vec![0x31, 0x02, 0x02, 0x03],
+                vec![0x10, 0x81],
+            ]
+        );
+
+        // Refused with the engine running, before anything is started
+        ecu.rpm = 800;
+        ecu.requests.clear();
+        let result = actuate(
+            &mut ecu,
+            &profile,
+            fan,
+            Duration::from_secs(1),
+            &CancelToken::new(),
+            |_| {},
+        );
+        assert!(matches!(result, Err(MzrError::EngineRunning(rpm)) if rpm == 800.0));
+        assert_eq!(ecu.requests.len(), 1);
+    }
+}
@@ -0,0 +1,122 @@
+//! Stored ROM dumps, found by scanning for the metadata sidecars written
+//! next to downloads and backups
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::metadata::RomMetadata;
+
+/// A stored ROM image. Identical images saved more than once are grouped
+/// together.
+#[derive(Debug, Clone)]
+pub struct Backup {
+    /// Metadata of the most recent copy
+    pub metadata: RomMetadata,
+    /// Paths of all copies, newest first
+    pub paths: Vec<PathBuf>,
+}
+
+impl Backup {
+    /// Returns the path of the most recent copy
+    pub fn path(&self) -> &Path {
+        &self.paths[0]
+    }
+}
+
+/// Finds the ROMs in `dir` that have a metadata sidecar, newest first.
+/// Copies with the same SHA-256 hash are merged into one entry.
+pub fn scan<P: AsRef<Path>>(dir: P) -> io::Result<Vec<Backup>> {
+    let mut found = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension() != Some("json".as_ref()) {
+            continue;
+        }
+        let rom = path.with_extension("bin");
+        if !rom.is_file() {
+            continue;
+        }
+        // Other JSON files are not sidecars
+        if let Ok(metadata) = RomMetadata::load(&rom) {
+            found.push((rom, metadata));
+        }
+    }
+    found.sort_by(|(a_path, a), (b_path, b)| {
+        b.timestamp
+            .cmp(&a.timestamp)
+            .then_with(|| a_path.cmp(b_path))
+    });
+
+    let mut backups: Vec<Backup> = Vec::new();
+    for (path, metadata) in found {
+        match backups
+            .iter_mut()
+            .find(|backup| backup.metadata.sha256 == metadata.sha256)
+        {
+            Some(backup) => backup.paths.push(path),
+            None => backups.push(Backup {
+                metadata,
+                paths: vec![path],
+            }),
+        }
+    }
+    Ok(backups)
+}
+
+/// Selects a backup by its 1-based index in `backups` or by a prefix of
+/// its SHA-256 hash
+pub fn select<'a>(backups: &'a [Backup], selector: &str) -> Option<&'a Backup> {
+    if let Ok(index) = selector.parse::<usize>() {
+        if let Some(backup) = index.checked_sub(1).and_then(|i| backups.get(i)) {
+            return Some(backup);
+        }
+    }
+    let prefix = selector.to_ascii_lowercase();
+    let mut matches = backups
+        .iter()
+        .filter(|backup| backup.metadata.sha256.starts_with(&prefix));
+    match (matches.next(), matches.next()) {
+        (Some(backup), None) => Some(backup),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scan_merges_duplicates() {
+        let dir = std::env::temp_dir().join(format!("mzr-backups-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let save = |name: &str, data: &[u8], timestamp: u64| {
+            let path = dir.join(name);
+            fs::write(&path, data).unwrap();
+            RomMetadata {
+                timestamp,
+                ..RomMetadata::new("JM1BK34M071234567", data)
+            }
+            .save(&path)
+            .unwrap();
+        };
+        save("a.bin", b"first", 1);
+        save("b.bin", b"second", 2);
+        save("c.bin", b"first", 3);
+        fs::write(dir.join("unrelated.json"), "{}").unwrap();
+
+        let backups = scan(&dir).unwrap();
+        assert_eq!(backups.len(), 2);
+        assert_eq!(backups[0].paths, vec![dir.join("c.bin"), dir.join("a.bin")]);
+        assert_eq!(backups[1].path(), dir.join("b.bin"));
+
+        assert_eq!(select(&backups, "2").unwrap().path(), dir.join("b.bin"));
+        let hash = &backups[1].metadata.sha256;
+        assert_eq!(
+            select(&backups, &hash[..8]).unwrap().path(),
+            dir.join("b.bin")
+        );
+        assert!(select(&backups, "zz").is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
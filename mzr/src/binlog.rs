@@ -0,0 +1,606 @@
+//! Compact binary log of records, for sample rates at which CSV gets
+//! unwieldy. Values are stored as floats in the order of a channel
+//! dictionary in the header, and an index of record times written on close
+//! lets readers seek to a time range without reading the whole file.
+//!
+//! Layout, little-endian:
+//!
+//! ```text
+//! header   "MZRLOG", version u16, start time u64 (Unix ms), field count u16,
+//!          per field: kind u8 (0 f32, 1 f64), name and unit (u8 length + UTF-8),
+//!          session metadata as JSON (u32 length + UTF-8, empty if unknown)
+//! record   time u32 (ms since start), a value per field (NaN if missing),
+//!          marker count u8, per marker: label (u8 length + UTF-8),
+//!          event count u8, per event: text (u8 length + UTF-8)
+//! index    per entry: time u64, offset u64
+//! footer   index offset u64, "MZRINDEX"
+//! ```
+//!
+//! Logs cut short, e.g. by a power loss, have no index and are read up to
+//! the last complete record. Version 1 logs have no metadata, and versions
+//! 1 and 2 no events.
+
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::metadata::LogMetadata;
+use crate::record::{self, LogChannel, Record, RecordSink};
+
+/// Extension of binary logs
+pub const EXTENSION: &str = "mzrlog";
+
+const MAGIC: &[u8; 6] = b"MZRLOG";
+const VERSION: u16 = 3;
+const INDEX_MAGIC: &[u8; 8] = b"MZRINDEX";
+/// Records between index entries
+const INDEX_INTERVAL: u64 = 256;
+
+#[derive(Error, Debug)]
+pub enum BinaryLogError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error("not a binary log")]
+    InvalidMagic,
+
+    #[error("unsupported log version {0}")]
+    UnsupportedVersion(u16),
+
+    #[error("invalid field kind {0}")]
+    InvalidKind(u8),
+}
+
+/// Storage of a field's values
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FieldKind {
+    F32,
+    /// For values needing more than 7 significant digits, e.g. coordinates
+    F64,
+}
+
+impl FieldKind {
+    fn size(self) -> usize {
+        match self {
+            FieldKind::F32 => 4,
+            FieldKind::F64 => 8,
+        }
+    }
+}
+
+/// Entry of the channel dictionary
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LogField {
+    pub name: String,
+    pub unit: String,
+    pub kind: FieldKind,
+}
+
+impl LogField {
+    pub fn new(name: &str, unit: &str, kind: FieldKind) -> LogField {
+        LogField {
+            name: name.to_string(),
+            unit: unit.to_string(),
+            kind,
+        }
+    }
+}
+
+/// Returns the fields of `channels`, followed by the position fields added
+/// by a GPS if `gps` is set
+pub fn fields_for(channels: &[LogChannel], gps: bool) -> Vec<LogField> {
+    let mut fields: Vec<LogField> = channels
+        .iter()
+        .map(|channel| LogField::new(&channel.name, &channel.unit, FieldKind::F32))
+        .collect();
+    if gps {
+        fields.extend(vec![
+            LogField::new("latitude", "deg", FieldKind::F64),
+            LogField::new("longitude", "deg", FieldKind::F64),
+            LogField::new("gps_speed", "km/h", FieldKind::F32),
+            LogField::new("course", "deg", FieldKind::F32),
+            LogField::new("altitude", "m", FieldKind::F64),
+        ]);
+    }
+    fields
+}
+
+/// Returns a field for each value name in `records`, e.g. of a JSON lines
+/// log without a dictionary
+pub fn fields_of(records: &[Record]) -> Vec<LogField> {
+    let names: BTreeSet<&String> = records
+        .iter()
+        .flat_map(|record| record.values.keys())
+        .collect();
+    names
+        .into_iter()
+        .map(|name| LogField::new(name, "", FieldKind::F64))
+        .collect()
+}
+
+fn write_str<W: Write>(writer: &mut W, s: &str) -> io::Result<()> {
+    // Truncated on a character boundary to fit the length byte
+    let mut end = s.len().min(u8::MAX as usize);
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    writer.write_all(&[end as u8])?;
+    writer.write_all(&s.as_bytes()[..end])
+}
+
+/// Writes records as a binary log. The header is written with the first
+/// record, so it includes the metadata of the session, and the index when
+/// the writer is finished or dropped. Values of channels missing from the
+/// dictionary are not stored.
+pub struct BinaryLogWriter<W: Write> {
+    writer: W,
+    fields: Vec<LogField>,
+    metadata: Option<LogMetadata>,
+    /// Time of the first record, once the header is written
+    start: Option<u64>,
+    /// Bytes written so far
+    offset: u64,
+    records: u64,
+    index: Vec<(u64, u64)>,
+    finished: bool,
+}
+
+impl<W: Write> BinaryLogWriter<W> {
+    pub fn new(writer: W, fields: Vec<LogField>) -> BinaryLogWriter<W> {
+        BinaryLogWriter {
+            writer,
+            fields,
+            metadata: None,
+            start: None,
+            offset: 0,
+            records: 0,
+            index: Vec::new(),
+            finished: false,
+        }
+    }
+
+    /// Sets the metadata of the log. Ignored once the header is written.
+    pub fn set_metadata(&mut self, metadata: &LogMetadata) {
+        if self.start.is_none() {
+            self.metadata = Some(metadata.clone());
+        }
+    }
+
+    /// Writes the header of a log starting at Unix time `start` in
+    /// milliseconds
+    fn write_header(&mut self, start: u64) -> io::Result<()> {
+        let mut header = Vec::new();
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&VERSION.to_le_bytes());
+        header.extend_from_slice(&start.to_le_bytes());
+        header.extend_from_slice(&(self.fields.len() as u16).to_le_bytes());
+        for field in &self.fields {
+            header.push(field.kind as u8);
+            write_str(&mut header, &field.name)?;
+            write_str(&mut header, &field.unit)?;
+        }
+        let metadata = match self.metadata {
+            Some(ref metadata) => serde_json::to_vec(metadata)?,
+            None => Vec::new(),
+        };
+        header.extend_from_slice(&(metadata.len() as u32).to_le_bytes());
+        header.extend_from_slice(&metadata);
+        self.writer.write_all(&header)?;
+        self.start = Some(start);
+        self.offset = header.len() as u64;
+        Ok(())
+    }
+
+    pub fn write(&mut self, record: &Record) -> io::Result<()> {
+        let start = match self.start {
+            Some(start) => start,
+            None => {
+                self.write_header(record.time)?;
+                record.time
+            }
+        };
+        if self.records.is_multiple_of(INDEX_INTERVAL) {
+            self.index.push((record.time, self.offset));
+        }
+        // Records from before the start are stored at the start
+        let time = record.time.saturating_sub(start).min(u32::MAX as u64) as u32;
+        let mut data = time.to_le_bytes().to_vec();
+        for field in &self.fields {
+            let value = record.values.get(&field.name).copied().unwrap_or(f64::NAN);
+            match field.kind {
+                FieldKind::F32 => data.extend_from_slice(&(value as f32).to_le_bytes()),
+                FieldKind::F64 => data.extend_from_slice(&value.to_le_bytes()),
+            }
+        }
+        write_labels(&mut data, &record.markers)?;
+        write_labels(&mut data, &record.events)?;
+        self.writer.write_all(&data)?;
+        self.offset += data.len() as u64;
+        self.records += 1;
+        Ok(())
+    }
+
+    /// Writes the index and flushes the log
+    pub fn finish(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        if self.start.is_none() {
+            self.write_header(0)?;
+        }
+        let mut index = Vec::new();
+        for (time, offset) in &self.index {
+            index.extend_from_slice(&time.to_le_bytes());
+            index.extend_from_slice(&offset.to_le_bytes());
+        }
+        index.extend_from_slice(&self.offset.to_le_bytes());
+        index.extend_from_slice(INDEX_MAGIC);
+        self.writer.write_all(&index)?;
+        self.writer.flush()
+    }
+}
+
+impl<W: Write> RecordSink for BinaryLogWriter<W> {
+    fn send(&mut self, record: &Record) {
+        if self.finished {
+            return;
+        }
+        if let Err(err) = self.write(record) {
+            tracing::warn!(%err, "failed to write record");
+        }
+    }
+
+    fn begin_session(&mut self, metadata: &LogMetadata) {
+        self.set_metadata(metadata);
+    }
+
+    fn flush(&mut self) {
+        if let Err(err) = self.writer.flush() {
+            tracing::warn!(%err, "failed to write records");
+        }
+    }
+}
+
+impl<W: Write> Drop for BinaryLogWriter<W> {
+    fn drop(&mut self) {
+        if let Err(err) = self.finish() {
+            tracing::warn!(%err, "failed to write log index");
+        }
+    }
+}
+
+fn read_array<R: Read, const N: usize>(reader: &mut R) -> io::Result<[u8; N]> {
+    let mut buf = [0; N];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Writes up to 255 `labels` with their count
+fn write_labels(buf: &mut Vec<u8>, labels: &[String]) -> io::Result<()> {
+    let labels = &labels[..labels.len().min(u8::MAX as usize)];
+    buf.push(labels.len() as u8);
+    for label in labels {
+        write_str(buf, label)?;
+    }
+    Ok(())
+}
+
+fn read_labels<R: Read>(reader: &mut R) -> io::Result<Vec<String>> {
+    let [count] = read_array(reader)?;
+    (0..count).map(|_| read_string(reader)).collect()
+}
+
+fn read_string<R: Read>(reader: &mut R) -> io::Result<String> {
+    let [len] = read_array(reader)?;
+    let mut buf = vec![0; len as usize];
+    reader.read_exact(&mut buf)?;
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+/// Reader of a binary log
+pub struct BinaryLog<R: Read + Seek> {
+    reader: R,
+    version: u16,
+    pub fields: Vec<LogField>,
+    /// Unix time in milliseconds of the start of the log
+    pub start: u64,
+    pub metadata: Option<LogMetadata>,
+    /// Time and offset of every [`INDEX_INTERVAL`]th record, empty if the
+    /// log has no index
+    index: Vec<(u64, u64)>,
+    data_start: u64,
+    data_end: u64,
+}
+
+impl<R: Read + Seek> BinaryLog<R> {
+    /// Reads the header and index of a log
+    pub fn open(mut reader: R) -> Result<BinaryLog<R>, BinaryLogError> {
+        let magic: [u8; 6] = read_array(&mut reader).map_err(|_| BinaryLogError::InvalidMagic)?;
+        if &magic != MAGIC {
+            return Err(BinaryLogError::InvalidMagic);
+        }
+        let version = u16::from_le_bytes(read_array(&mut reader)?);
+        if version == 0 || version > VERSION {
+            return Err(BinaryLogError::UnsupportedVersion(version));
+        }
+        let start = u64::from_le_bytes(read_array(&mut reader)?);
+        let count = u16::from_le_bytes(read_array(&mut reader)?);
+        let mut fields = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let kind = match read_array(&mut reader)? {
+                [0] => FieldKind::F32,
+                [1] => FieldKind::F64,
+                [kind] => return Err(BinaryLogError::InvalidKind(kind)),
+            };
+            let name = read_string(&mut reader)?;
+            let unit = read_string(&mut reader)?;
+            fields.push(LogField { name, unit, kind });
+        }
+        let mut metadata = None;
+        if version >= 2 {
+            let len = u32::from_le_bytes(read_array(&mut reader)?);
+            let mut json = vec![0; len as usize];
+            reader.read_exact(&mut json)?;
+            if !json.is_empty() {
+                metadata = Some(
+                    serde_json::from_slice(&json)
+                        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
+                );
+            }
+        }
+        let data_start = reader.stream_position()?;
+
+        let len = reader.seek(SeekFrom::End(0))?;
+        let mut index = Vec::new();
+        let mut data_end = len;
+        if len >= data_start + 16 {
+            reader.seek(SeekFrom::End(-16))?;
+            let index_start = u64::from_le_bytes(read_array(&mut reader)?);
+            let magic: [u8; 8] = read_array(&mut reader)?;
+            if &magic == INDEX_MAGIC && index_start >= data_start && index_start <= len - 16 {
+                reader.seek(SeekFrom::Start(index_start))?;
+                for _ in 0..(len - 16 - index_start) / 16 {
+                    let time = u64::from_le_bytes(read_array(&mut reader)?);
+                    let offset = u64::from_le_bytes(read_array(&mut reader)?);
+                    index.push((time, offset));
+                }
+                data_end = index_start;
+            }
+        }
+        Ok(BinaryLog {
+            reader,
+            version,
+            fields,
+            start,
+            metadata,
+            index,
+            data_start,
+            data_end,
+        })
+    }
+
+    /// Reads the record at the current position, or `None` at the end of
+    /// the data or a truncated record
+    fn read_record(&mut self) -> io::Result<Option<Record>> {
+        if self.reader.stream_position()? >= self.data_end {
+            return Ok(None);
+        }
+        match self.read_fields() {
+            Ok(record) => Ok(Some(record)),
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn read_fields(&mut self) -> io::Result<Record> {
+        let mut record = Record::new();
+        record.time = self.start + u32::from_le_bytes(read_array(&mut self.reader)?) as u64;
+        for field in &self.fields {
+            let value = match field.kind {
+                FieldKind::F32 => f32::from_le_bytes(read_array(&mut self.reader)?) as f64,
+                FieldKind::F64 => f64::from_le_bytes(read_array(&mut self.reader)?),
+            };
+            if !value.is_nan() {
+                record.values.insert(field.name.clone(), value);
+            }
+        }
+        record.markers = read_labels(&mut self.reader)?;
+        if self.version >= 3 {
+            record.events = read_labels(&mut self.reader)?;
+        }
+        Ok(record)
+    }
+
+    /// Reads every record
+    pub fn records(&mut self) -> io::Result<Vec<Record>> {
+        self.range(0, u64::MAX)
+    }
+
+    /// Reads the records from Unix time `from` up to `to` in milliseconds,
+    /// starting at the closest indexed record
+    pub fn range(&mut self, from: u64, to: u64) -> io::Result<Vec<Record>> {
+        let entry = self.index.partition_point(|&(time, _)| time <= from);
+        let offset = match entry {
+            0 => self.data_start,
+            entry => self.index[entry - 1].1,
+        };
+        self.reader.seek(SeekFrom::Start(offset))?;
+        let mut records = Vec::new();
+        while let Some(record) = self.read_record()? {
+            if record.time > to {
+                break;
+            }
+            if record.time >= from {
+                records.push(record);
+            }
+        }
+        Ok(records)
+    }
+
+    /// Bytes per record without markers or events
+    pub fn record_size(&self) -> usize {
+        let counts = if self.version >= 3 { 2 } else { 1 };
+        4 + self
+            .fields
+            .iter()
+            .map(|field| field.kind.size())
+            .sum::<usize>()
+            + counts
+    }
+}
+
+/// Contents of a log file
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoadedLog {
+    pub fields: Vec<LogField>,
+    pub records: Vec<Record>,
+    pub metadata: Option<LogMetadata>,
+}
+
+/// Reads a binary log, or a JSON lines log written by
+/// [`RecordWriter`](crate::record::RecordWriter)
+pub fn load(path: &Path) -> Result<LoadedLog, BinaryLogError> {
+    let mut file = BufReader::new(File::open(path)?);
+    let mut magic = [0; 6];
+    let binary = file.read_exact(&mut magic).is_ok() && &magic == MAGIC;
+    file.seek(SeekFrom::Start(0))?;
+    if binary {
+        let mut log = BinaryLog::open(file)?;
+        let records = log.records()?;
+        Ok(LoadedLog {
+            fields: log.fields,
+            records,
+            metadata: log.metadata,
+        })
+    } else {
+        let (metadata, records) = record::read_log(file)?;
+        Ok(LoadedLog {
+            fields: fields_of(&records),
+            records,
+            metadata,
+        })
+    }
+}
+
+/// Writes `records` as CSV with a column per field, headed with the name
+/// and unit, and columns of markers and events. `comment`, e.g. from
+/// [`describe`](crate::metadata::describe), goes before the header.
+pub fn write_csv<W: Write>(
+    fields: &[LogField],
+    records: &[Record],
+    comment: &str,
+    mut writer: W,
+) -> io::Result<()> {
+    writer.write_all(comment.as_bytes())?;
+    let mut header = String::from("time");
+    for field in fields {
+        if field.unit.is_empty() {
+            header.push_str(&format!(",{}", field.name));
+        } else {
+            header.push_str(&format!(",{} ({})", field.name, field.unit));
+        }
+    }
+    writeln!(writer, "{},markers,events", header)?;
+    for record in records {
+        let mut row = record.time.to_string();
+        for field in fields {
+            row.push(',');
+            if let Some(value) = record.values.get(&field.name) {
+                row.push_str(&value.to_string());
+            }
+        }
+        writeln!(
+            writer,
+            "{},{},{}",
+            row,
+            record.markers.join(";"),
+            record.events.join(";")
+        )?;
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn records(count: u64) -> Vec<Record> {
+        (0..count)
+            .map(|i| {
+                let mut record = Record::new();
+                record.time = 1_600_000_000_000 + i * 10;
+                record.values.insert("rpm".to_string(), 800.0 + i as f64);
+                if i % 2 == 0 {
+                    record.values.insert("latitude".to_string(), 48.117_312_5);
+                }
+                if i == 3 {
+                    record.markers.push("lap".to_string());
+                    record.events.push("DTC P0301 pending".to_string());
+                }
+                record
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trips_and_seeks() {
+        let rpm = LogChannel {
+            name: "rpm".to_string(),
+            unit: "rpm".to_string(),
+            ..LogChannel::raw(0x0C)
+        };
+        let fields = fields_for(&[rpm], true);
+        let records = records(1000);
+        let metadata = LogMetadata {
+            vin: Some("JM1BK143141123456".to_string()),
+            pids: vec![0x0C],
+            ..LogMetadata::default()
+        };
+        let mut file = Vec::new();
+        {
+            let mut writer = BinaryLogWriter::new(&mut file, fields.clone());
+            writer.begin_session(&metadata);
+            for record in &records {
+                writer.send(record);
+            }
+        }
+
+        let mut log = BinaryLog::open(Cursor::new(&file)).unwrap();
+        assert_eq!(log.fields, fields);
+        assert_eq!(log.start, records[0].time);
+        assert_eq!(log.metadata, Some(metadata));
+        assert_eq!(log.index.len(), 4);
+        // rpm f32, 5 GPS fields
+        assert_eq!(log.record_size(), 4 + 4 + 8 + 8 + 4 + 4 + 8 + 2);
+        assert_eq!(log.records().unwrap(), records);
+        let range = log.range(records[600].time, records[610].time).unwrap();
+        assert_eq!(range, &records[600..=610]);
+
+        // Cut short in the middle of a record
+        let truncated = &file[..log.data_end as usize - 5];
+        let mut log = BinaryLog::open(Cursor::new(truncated)).unwrap();
+        assert!(log.index.is_empty());
+        assert_eq!(log.records().unwrap(), &records[..999]);
+        assert!(matches!(
+            BinaryLog::open(Cursor::new(b"MZRLOG\x04\x00")),
+            Err(BinaryLogError::UnsupportedVersion(4))
+        ));
+
+        let mut csv = Vec::new();
+        write_csv(&fields[..2], &records[2..4], "# vin: JM1\n", &mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "# vin: JM1\n\
+             time,rpm (rpm),latitude (deg),markers,events\n\
+             1600000000020,802,48.1173125,,\n\
+             1600000000030,803,,lap,DTC P0301 pending\n"
+        );
+    }
+}
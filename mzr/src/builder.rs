@@ -0,0 +1,334 @@
+//! Builders for [`Downloader`] and [`Programmer`]
+
+use std::time::Duration;
+
+use obd::Uds;
+
+use crate::cancel::CancelToken;
+use crate::chunk::ChunkSize;
+use crate::event::Events;
+use crate::memory_map::MzrMemoryMap;
+use crate::pause::PauseToken;
+use crate::profile::KeyAlgorithm;
+use crate::session::{Session, KEEP_ALIVE_INTERVAL};
+use crate::stats::TransferStats;
+use crate::timeout::{SetTimeout, TimeoutProfile};
+use crate::{
+    Downloader, Programmer, BLOCK_SIZE, DEFAULT_REQUEST_ID, ERASE_ROUTINE_DEFAULT, READ_RETRIES,
+    SECURITY_LEVEL_DEFAULT, SESSION_DOWNLOAD, SESSION_PROGRAMMING,
+};
+
+/// How failed reads and transfers are retried by [`Downloader::run`] and
+/// [`Programmer::run`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Number of retries before the error is returned
+    pub attempts: usize,
+    /// Delay before each retry
+    pub delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            attempts: READ_RETRIES,
+            delay: Duration::from_secs(0),
+        }
+    }
+}
+
+/// Session settings shared by both builders
+#[derive(Debug, Clone)]
+struct SessionOptions {
+    request_id: u32,
+    session_id: u8,
+    security_level: u8,
+    key_algorithm: KeyAlgorithm,
+    keep_alive_interval: Duration,
+}
+
+impl SessionOptions {
+    fn new(session_id: u8) -> SessionOptions {
+        SessionOptions {
+            request_id: DEFAULT_REQUEST_ID,
+            session_id,
+            security_level: SECURITY_LEVEL_DEFAULT,
+            key_algorithm: KeyAlgorithm::Mazda,
+            keep_alive_interval: KEEP_ALIVE_INTERVAL,
+        }
+    }
+
+    fn session<'a, M: 'a + Uds>(&self, bus: &'a mut M) -> Session<'a, M> {
+        let mut session = Session::new(bus);
+        session.set_request_id(self.request_id);
+        session.set_security_level(self.security_level);
+        session.set_key_algorithm(self.key_algorithm.clone());
+        session.set_keep_alive_interval(self.keep_alive_interval);
+        session
+    }
+}
+
+/// Configures a [`Downloader`]. Options that aren't set keep the defaults
+/// used by [`Downloader::new`].
+#[derive(Debug, Clone)]
+pub struct DownloaderBuilder {
+    session: SessionOptions,
+    memory_map: MzrMemoryMap,
+    chunk_size: Option<usize>,
+    retry: RetryPolicy,
+    timeouts: TimeoutProfile,
+    spot_check: u8,
+}
+
+impl Default for DownloaderBuilder {
+    fn default() -> DownloaderBuilder {
+        DownloaderBuilder {
+            session: SessionOptions::new(SESSION_DOWNLOAD),
+            memory_map: MzrMemoryMap::default(),
+            chunk_size: None,
+            retry: RetryPolicy::default(),
+            timeouts: TimeoutProfile::default(),
+            spot_check: 0,
+        }
+    }
+}
+
+impl DownloaderBuilder {
+    pub fn new() -> DownloaderBuilder {
+        DownloaderBuilder::default()
+    }
+
+    /// Sets the arbitration ID requests are sent to
+    pub fn request_id(mut self, request_id: u32) -> DownloaderBuilder {
+        self.session.request_id = request_id;
+        self
+    }
+
+    /// Sets the diagnostic session entered to read memory
+    pub fn session(mut self, session_id: u8) -> DownloaderBuilder {
+        self.session.session_id = session_id;
+        self
+    }
+
+    /// Sets the security access level
+    pub fn security_level(mut self, level: u8) -> DownloaderBuilder {
+        self.session.security_level = level;
+        self
+    }
+
+    /// Sets the algorithm used to answer security access seeds
+    pub fn key_algorithm(mut self, algorithm: KeyAlgorithm) -> DownloaderBuilder {
+        self.session.key_algorithm = algorithm;
+        self
+    }
+
+    /// Sets the interval between TesterPresent requests while idle
+    pub fn keep_alive_interval(mut self, interval: Duration) -> DownloaderBuilder {
+        self.session.keep_alive_interval = interval;
+        self
+    }
+
+    /// Sets the layout of the ECU. The whole flash is read.
+    pub fn memory_map(mut self, map: MzrMemoryMap) -> DownloaderBuilder {
+        self.memory_map = map;
+        self
+    }
+
+    /// Reads a fixed number of bytes per request instead of adapting the
+    /// size to the adapter
+    pub fn chunk_size(mut self, size: usize) -> DownloaderBuilder {
+        self.chunk_size = Some(size);
+        self
+    }
+
+    /// Sets how failed reads are retried
+    pub fn retry_policy(mut self, retry: RetryPolicy) -> DownloaderBuilder {
+        self.retry = retry;
+        self
+    }
+
+    /// Sets the timeouts used for each class of request
+    pub fn timeouts(mut self, timeouts: TimeoutProfile) -> DownloaderBuilder {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Re-reads `percent` of the blocks, chosen at random, after the
+    /// download in [`Downloader::run`] and compares them, catching adapters
+    /// that silently corrupt data. Values above 100 check every block.
+    pub fn spot_check(mut self, percent: u8) -> DownloaderBuilder {
+        self.spot_check = percent.min(100);
+        self
+    }
+
+    /// Creates the downloader
+    pub fn build<'a, M: 'a + Uds + SetTimeout>(self, bus: &'a mut M) -> Downloader<'a, M> {
+        let flash = self.memory_map.flash();
+        Downloader {
+            offset: flash.start,
+            remaining: flash.len(),
+            block: 0,
+            data: Vec::with_capacity(flash.len()),
+            memory_map: self.memory_map,
+            session_id: self.session.session_id,
+            session: self.session.session(bus),
+            events: Events::new(),
+            cancel: CancelToken::new(),
+            timeouts: self.timeouts,
+            chunk: match self.chunk_size {
+                Some(size) => ChunkSize::fixed(size),
+                None => ChunkSize::adaptive(),
+            },
+            retry: self.retry,
+            spot_check: self.spot_check,
+            stats: TransferStats::default(),
+            partial: None,
+        }
+    }
+}
+
+/// Configures a [`Programmer`]. Options that aren't set keep the defaults
+/// used by [`Programmer::new`].
+#[derive(Debug, Clone)]
+pub struct ProgrammerBuilder {
+    session: SessionOptions,
+    memory_map: MzrMemoryMap,
+    erase_routine: Vec<u8>,
+    block_size: usize,
+    timeouts: TimeoutProfile,
+    verify: bool,
+    checksum_routine: Option<u16>,
+    retry: RetryPolicy,
+}
+
+impl Default for ProgrammerBuilder {
+    fn default() -> ProgrammerBuilder {
+        ProgrammerBuilder {
+            session: SessionOptions::new(SESSION_PROGRAMMING),
+            memory_map: MzrMemoryMap::default(),
+            erase_routine: ERASE_ROUTINE_DEFAULT.to_vec(),
+            block_size: BLOCK_SIZE,
+            timeouts: TimeoutProfile::default(),
+            verify: false,
+            checksum_routine: None,
+            retry: RetryPolicy::default(),
+        }
+    }
+}
+
+impl ProgrammerBuilder {
+    pub fn new() -> ProgrammerBuilder {
+        ProgrammerBuilder::default()
+    }
+
+    /// Sets the arbitration ID requests are sent to
+    pub fn request_id(mut self, request_id: u32) -> ProgrammerBuilder {
+        self.session.request_id = request_id;
+        self
+    }
+
+    /// Sets the diagnostic session entered to program flash
+    pub fn session(mut self, session_id: u8) -> ProgrammerBuilder {
+        self.session.session_id = session_id;
+        self
+    }
+
+    /// Sets the security access level
+    pub fn security_level(mut self, level: u8) -> ProgrammerBuilder {
+        self.session.security_level = level;
+        self
+    }
+
+    /// Sets the algorithm used to answer security access seeds
+    pub fn key_algorithm(mut self, algorithm: KeyAlgorithm) -> ProgrammerBuilder {
+        self.session.key_algorithm = algorithm;
+        self
+    }
+
+    /// Sets the interval between TesterPresent requests while idle
+    pub fn keep_alive_interval(mut self, interval: Duration) -> ProgrammerBuilder {
+        self.session.keep_alive_interval = interval;
+        self
+    }
+
+    /// Sets the layout of the ECU
+    pub fn memory_map(mut self, map: MzrMemoryMap) -> ProgrammerBuilder {
+        self.memory_map = map;
+        self
+    }
+
+    /// Sets the parameters passed to the bootloader's erase routine
+    pub fn erase_routine(mut self, parameters: Vec<u8>) -> ProgrammerBuilder {
+        self.erase_routine = parameters;
+        self
+    }
+
+    /// Sets the number of bytes sent per TransferData request. Sizes larger
+    /// than the ECU accepts are clamped.
+    pub fn block_size(mut self, size: usize) -> ProgrammerBuilder {
+        self.block_size = size.clamp(1, BLOCK_SIZE);
+        self
+    }
+
+    /// Sets the timeouts used for each class of request
+    pub fn timeouts(mut self, timeouts: TimeoutProfile) -> ProgrammerBuilder {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Reads back and compares the programmed image after the transfer in
+    /// [`Programmer::run`]
+    pub fn verify(mut self, verify: bool) -> ProgrammerBuilder {
+        self.verify = verify;
+        self
+    }
+
+    /// Sets the RoutineControl identifier of the ECU's checksum routine, run
+    /// after the transfer in [`Programmer::run`]. `None` skips the check.
+    pub fn checksum_routine(mut self, routine: Option<u16>) -> ProgrammerBuilder {
+        self.checksum_routine = routine;
+        self
+    }
+
+    /// Sets how failed TransferData requests are retried by
+    /// [`Programmer::run`]. The ECU is re-synchronized with the last
+    /// acknowledged block before each retry.
+    pub fn retry_policy(mut self, retry: RetryPolicy) -> ProgrammerBuilder {
+        self.retry = retry;
+        self
+    }
+
+    /// Creates a programmer writing `data` to flash at `offset`
+    pub fn build<'a, M: 'a + Uds + SetTimeout>(
+        self,
+        bus: &'a mut M,
+        offset: u32,
+        data: Vec<u8>,
+    ) -> Programmer<'a, M> {
+        Programmer {
+            offset,
+            position: 0,
+            block: 0,
+            block_size: self.block_size,
+            data,
+            memory_map: self.memory_map,
+            erase_routine: self.erase_routine,
+            session_id: self.session.session_id,
+            session: self.session.session(bus),
+            erased: false,
+            events: Events::new(),
+            cancel: CancelToken::new(),
+            pause: PauseToken::new(),
+            voltage: None,
+            allow_engine_running: false,
+            allow_bootloader_write: false,
+            recovery: false,
+            timeouts: self.timeouts,
+            verify: self.verify,
+            checksum_routine: self.checksum_routine,
+            transfer_exited: false,
+            retry: self.retry,
+            stats: TransferStats::default(),
+        }
+    }
+}
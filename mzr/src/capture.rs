@@ -0,0 +1,285 @@
+//! Records the bus traffic of a session to a candump log or PCAP file, so a
+//! failed flash can be analyzed with full traces
+//!
+//! [`Capture`] wraps either a raw CAN interface, recording every frame, or
+//! an ISO-TP transport. Adapters doing ISO-TP themselves only hand over
+//! whole packets, which are recorded as the frames the sender puts on the
+//! bus. Flow control frames are then missing from the capture.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use obd::IsoTp;
+
+use crate::datalink::can::{BusStatus, Can, Filter, Message};
+use crate::isotp;
+use crate::timeout::SetTimeout;
+
+/// Interface name written to candump logs
+const CANDUMP_INTERFACE: &str = "can0";
+
+/// Link type of SocketCAN frames in PCAP files
+const LINKTYPE_CAN_SOCKETCAN: u32 = 227;
+/// Flag marking 29-bit identifiers in SocketCAN frames
+const CAN_EFF_FLAG: u32 = 0x8000_0000;
+/// Flags of CAN-FD frames in SocketCAN frames
+const CANFD_BRS: u8 = 0x01;
+const CANFD_FDF: u8 = 0x04;
+
+/// File format of a capture
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CaptureFormat {
+    /// Text log of `candump -l`, readable by the can-utils
+    Candump,
+    /// PCAP with SocketCAN frames, readable by Wireshark
+    Pcap,
+}
+
+impl CaptureFormat {
+    /// Returns the format of a file named `path`. Files ending in `.pcap`
+    /// are PCAP, others candump logs.
+    pub fn from_path(path: &Path) -> CaptureFormat {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("pcap") => CaptureFormat::Pcap,
+            _ => CaptureFormat::Candump,
+        }
+    }
+}
+
+/// Writes timestamped CAN frames to a capture file
+pub struct CaptureWriter<W: Write> {
+    out: W,
+    format: CaptureFormat,
+}
+
+impl CaptureWriter<BufWriter<File>> {
+    /// Creates a capture file with the format chosen by its name
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<CaptureWriter<BufWriter<File>>> {
+        let format = CaptureFormat::from_path(path.as_ref());
+        CaptureWriter::new(BufWriter::new(File::create(path)?), format)
+    }
+}
+
+impl<W: Write> CaptureWriter<W> {
+    /// Starts a capture in `format` on `out`
+    pub fn new(mut out: W, format: CaptureFormat) -> io::Result<CaptureWriter<W>> {
+        if format == CaptureFormat::Pcap {
+            out.write_all(&0xA1B2_C3D4u32.to_le_bytes())?;
+            out.write_all(&2u16.to_le_bytes())?;
+            out.write_all(&4u16.to_le_bytes())?;
+            // Time zone offset and timestamp accuracy
+            out.write_all(&[0; 8])?;
+            out.write_all(&65535u32.to_le_bytes())?;
+            out.write_all(&LINKTYPE_CAN_SOCKETCAN.to_le_bytes())?;
+        }
+        Ok(CaptureWriter { out, format })
+    }
+
+    /// Records `msg` as sent or received at `time`
+    pub fn write_frame(&mut self, msg: &Message, time: SystemTime) -> io::Result<()> {
+        let time = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        match self.format {
+            CaptureFormat::Candump => self.write_candump(msg, time),
+            CaptureFormat::Pcap => self.write_pcap(msg, time),
+        }
+    }
+
+    fn write_candump(&mut self, msg: &Message, time: Duration) -> io::Result<()> {
+        let id = if msg.extended {
+            format!("{:08X}", msg.id)
+        } else {
+            format!("{:03X}", msg.id)
+        };
+        let separator = if msg.fd {
+            format!("##{:X}", if msg.brs { CANFD_BRS } else { 0 })
+        } else {
+            "#".to_string()
+        };
+        let data: String = msg.payload().iter().map(|b| format!("{:02X}", b)).collect();
+        writeln!(
+            self.out,
+            "({}.{:06}) {} {}{}{}",
+            time.as_secs(),
+            time.subsec_micros(),
+            CANDUMP_INTERFACE,
+            id,
+            separator,
+            data
+        )
+    }
+
+    fn write_pcap(&mut self, msg: &Message, time: Duration) -> io::Result<()> {
+        // SocketCAN frame: big-endian ID and flags, length, FD flags, two
+        // reserved bytes and the data, padded to 8 bytes for classic frames
+        let data_len = if msg.fd { msg.len as usize } else { 8 };
+        let id = if msg.extended {
+            msg.id | CAN_EFF_FLAG
+        } else {
+            msg.id
+        };
+        let flags = match (msg.fd, msg.brs) {
+            (false, _) => 0,
+            (true, false) => CANFD_FDF,
+            (true, true) => CANFD_FDF | CANFD_BRS,
+        };
+        let mut frame = Vec::with_capacity(8 + data_len);
+        frame.extend_from_slice(&id.to_be_bytes());
+        frame.extend_from_slice(&[msg.len, flags, 0, 0]);
+        frame.extend_from_slice(&msg.data[..data_len]);
+
+        self.out.write_all(&(time.as_secs() as u32).to_le_bytes())?;
+        self.out.write_all(&time.subsec_micros().to_le_bytes())?;
+        self.out.write_all(&(frame.len() as u32).to_le_bytes())?;
+        self.out.write_all(&(frame.len() as u32).to_le_bytes())?;
+        self.out.write_all(&frame)
+    }
+
+    /// Writes buffered frames to the file
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    /// Returns the writer the capture is written to
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+/// Transport or CAN interface recording its traffic. Capturing is best
+/// effort: failing to write the capture never fails the session, and
+/// capturing stops at the first write error.
+pub struct Capture<T, W: Write = BufWriter<File>> {
+    inner: T,
+    writer: Mutex<Option<CaptureWriter<W>>>,
+}
+
+impl<T, W: Write> Capture<T, W> {
+    /// Records the traffic of `inner` with `writer`, or passes it through
+    /// unrecorded if `writer` is `None`
+    pub fn new(inner: T, writer: Option<CaptureWriter<W>>) -> Capture<T, W> {
+        Capture {
+            inner,
+            writer: Mutex::new(writer),
+        }
+    }
+
+    /// Returns the wrapped transport
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn record(&self, msgs: &[Message]) {
+        let mut writer = self.writer.lock().unwrap();
+        if let Some(out) = writer.as_mut() {
+            let time = SystemTime::now();
+            let result = msgs
+                .iter()
+                .try_for_each(|msg| out.write_frame(msg, time))
+                .and_then(|_| out.flush());
+            if result.is_err() {
+                *writer = None;
+            }
+        }
+    }
+
+    /// Records an ISO-TP packet sent with `id`
+    fn record_packet(&self, id: u32, data: &[u8]) {
+        let msgs: Vec<Message> = isotp::segment(data)
+            .iter()
+            .map(|payload| Message::new(id, payload))
+            .collect();
+        self.record(&msgs);
+    }
+}
+
+impl<T: IsoTp, W: Write> IsoTp for Capture<T, W> {
+    fn send_isotp(&mut self, id: u32, data: &[u8]) -> Result<(), obd::Error> {
+        self.inner.send_isotp(id, data)?;
+        self.record_packet(id, data);
+        Ok(())
+    }
+
+    fn read_isotp(&mut self, id: u32) -> Result<Vec<u8>, obd::Error> {
+        let data = self.inner.read_isotp(id)?;
+        self.record_packet(id, &data);
+        Ok(data)
+    }
+}
+
+impl<T: SetTimeout, W: Write> SetTimeout for Capture<T, W> {
+    fn set_timeout(&mut self, timeout: Duration) {
+        self.inner.set_timeout(timeout)
+    }
+}
+
+impl<C: Can, W: Write> Can for Capture<C, W> {
+    fn send_msg(&self, msg: &Message) -> io::Result<()> {
+        self.inner.send_msg(msg)?;
+        self.record(&[*msg]);
+        Ok(())
+    }
+
+    fn read(&self, timeout: Duration) -> io::Result<Message> {
+        let msg = self.inner.read(timeout)?;
+        self.record(&[msg]);
+        Ok(msg)
+    }
+
+    fn supports_fd(&self) -> bool {
+        self.inner.supports_fd()
+    }
+
+    fn set_filters(&self, filters: &[Filter]) -> io::Result<()> {
+        self.inner.set_filters(filters)
+    }
+
+    fn bus_status(&self) -> io::Result<Option<BusStatus>> {
+        self.inner.bus_status()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_candump_and_pcap() {
+        let time = UNIX_EPOCH + Duration::from_micros(1_600_000_000_123_456);
+        let mut candump = CaptureWriter::new(Vec::new(), CaptureFormat::Candump).unwrap();
+        candump
+            .write_frame(&Message::new(0x7E0, &[0x02, 0x10, 0x85]), time)
+            .unwrap();
+        candump
+            .write_frame(&Message::new(0x18DA10F1, &[0x01, 0x3E]), time)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(candump.out).unwrap(),
+            "(1600000000.123456) can0 7E0#021085\n\
+             (1600000000.123456) can0 18DA10F1#013E\n"
+        );
+
+        let mut pcap = CaptureWriter::new(Vec::new(), CaptureFormat::Pcap).unwrap();
+        pcap.write_frame(&Message::new(0x7E8, &[0x02, 0x50, 0x85]), time)
+            .unwrap();
+        assert_eq!(pcap.out.len(), 24 + 16 + 16);
+        assert_eq!(&pcap.out[20..24], &LINKTYPE_CAN_SOCKETCAN.to_le_bytes());
+        assert_eq!(
+            &pcap.out[40..],
+            &[0, 0, 7, 0xE8, 3, 0, 0, 0, 0x02, 0x50, 0x85, 0, 0, 0, 0, 0]
+        );
+
+        // Packets of transports are recorded as their frames
+        let capture = Capture::new(
+            (),
+            Some(CaptureWriter::new(Vec::new(), CaptureFormat::Candump).unwrap()),
+        );
+        capture.record_packet(0x7E0, &(0..10).collect::<Vec<u8>>());
+        let log = capture.writer.into_inner().unwrap().unwrap().out;
+        let log = String::from_utf8(log).unwrap();
+        let frames: Vec<&str> = log.lines().map(|line| &line[25..]).collect();
+        assert_eq!(frames, ["7E0#100A000102030405", "7E0#2106070809000000"]);
+    }
+}
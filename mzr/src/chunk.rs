@@ -0,0 +1,99 @@
+//! Adaptive read chunk sizing. Large reads are fastest, but some adapters
+//! can't keep up with a full block and time out.
+
+use std::cmp;
+
+use crate::BLOCK_SIZE;
+
+/// Smallest chunk the size is reduced to after failures
+pub const MIN_CHUNK_SIZE: usize = 0x80;
+
+/// Number of consecutive successful reads before the chunk size grows
+const GROW_AFTER: usize = 16;
+
+/// Tracks the chunk size used for reads. The size starts at the maximum,
+/// halves after every failed read and creeps back up after a run of
+/// successful reads, staying below the smallest size that failed.
+pub(crate) struct ChunkSize {
+    size: usize,
+    // Smallest size that failed
+    ceiling: usize,
+    fixed: bool,
+    successes: usize,
+}
+
+impl ChunkSize {
+    pub fn adaptive() -> ChunkSize {
+        ChunkSize {
+            size: BLOCK_SIZE,
+            ceiling: BLOCK_SIZE + 1,
+            fixed: false,
+            successes: 0,
+        }
+    }
+
+    pub fn fixed(size: usize) -> ChunkSize {
+        ChunkSize {
+            size: size.clamp(1, BLOCK_SIZE),
+            ceiling: BLOCK_SIZE + 1,
+            fixed: true,
+            successes: 0,
+        }
+    }
+
+    pub fn get(&self) -> usize {
+        self.size
+    }
+
+    pub fn succeeded(&mut self) {
+        if self.fixed {
+            return;
+        }
+        self.successes += 1;
+        if self.successes >= GROW_AFTER {
+            self.successes = 0;
+            self.size = cmp::min(self.size + self.size / 4, self.ceiling - 1);
+        }
+    }
+
+    pub fn failed(&mut self) {
+        if self.fixed {
+            return;
+        }
+        self.successes = 0;
+        self.ceiling = cmp::min(self.ceiling, self.size);
+        self.size = cmp::max(self.size / 2, MIN_CHUNK_SIZE);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adapts_to_failures() {
+        let mut chunk = ChunkSize::adaptive();
+        assert_eq!(chunk.get(), BLOCK_SIZE);
+
+        chunk.failed();
+        assert_eq!(chunk.get(), BLOCK_SIZE / 2);
+
+        for _ in 0..GROW_AFTER * 10 {
+            chunk.succeeded();
+        }
+        assert_eq!(chunk.get(), BLOCK_SIZE - 1);
+
+        for _ in 0..10 {
+            chunk.failed();
+        }
+        assert_eq!(chunk.get(), MIN_CHUNK_SIZE);
+    }
+
+    #[test]
+    fn fixed_size() {
+        let mut chunk = ChunkSize::fixed(0x400);
+        chunk.failed();
+        assert_eq!(chunk.get(), 0x400);
+        assert_eq!(ChunkSize::fixed(0x10000).get(), BLOCK_SIZE);
+    }
+}
@@ -0,0 +1,137 @@
+//! Coding a replacement ECU to the vehicle. A used or new ECU carries the
+//! VIN of another car, or none at all, and has to be given the VIN of the
+//! vehicle it is installed in. The VIN is written with
+//! WriteDataByIdentifier to the standard VIN identifier after security
+//! access, then read back.
+
+use mzr_core::uds::UDS_REQ_WRITEDATABYIDENTIFIER;
+use obd::Uds;
+use thiserror::Error;
+
+use crate::profile::EcuProfile;
+use crate::vin::Vin;
+use crate::{MzrBus, MzrError};
+
+/// Data identifier of the VIN
+pub const VIN_DID: u16 = 0xF190;
+
+/// Extended diagnostic session, which VIN writes are accepted in
+pub const SESSION_EXTENDED: u8 = 0x03;
+
+#[derive(Error, Debug)]
+pub enum CodingError {
+    #[error("the ECU reports VIN '{0}' after writing")]
+    Mismatch(String),
+    #[error(transparent)]
+    Mzr(#[from] MzrError),
+}
+
+/// Reads the VIN stored in the ECU. Padding is removed, so an ECU that
+/// hasn't been coded yet returns an empty string.
+pub fn read_vin<B: Uds>(bus: &mut B, request_id: u32) -> Result<String, MzrError> {
+    let data = bus.read_identifier(request_id, VIN_DID)?;
+    Ok(data
+        .iter()
+        .filter(|b| b.is_ascii_alphanumeric())
+        .map(|&b| b as char)
+        .collect())
+}
+
+/// Writes `vin` to the ECU in `session` and reads it back. Refuses to write
+/// while the engine is running. The ECU is returned to the default session
+/// even if the write fails.
+pub fn write_vin<B: Uds>(
+    bus: &mut B,
+    profile: &EcuProfile,
+    session: u8,
+    vin: &Vin,
+) -> Result<(), CodingError> {
+    let request_id = profile.request_id;
+    let rpm = bus.engine_rpm(request_id)?;
+    if rpm > 0.0 {
+        return Err(MzrError::EngineRunning(rpm).into());
+    }
+    bus.set_diagnostic_session(request_id, session)
+        .map_err(MzrError::from)?;
+    let result = write_unlocked(bus, profile, vin);
+    let exited = bus.exit_session(request_id);
+    result?;
+    exited?;
+    Ok(())
+}
+
+fn write_unlocked<B: Uds>(bus: &mut B, profile: &EcuProfile, vin: &Vin) -> Result<(), CodingError> {
+    let request_id = profile.request_id;
+    bus.unlock_with(request_id, profile.security_level, &profile.key_algorithm)?;
+    let request = [&VIN_DID.to_be_bytes()[..], vin.as_str().as_bytes()].concat();
+    bus.query_uds(request_id, UDS_REQ_WRITEDATABYIDENTIFIER, &request)
+        .map_err(MzrError::from)?;
+    let written = read_vin(bus, request_id)?;
+    if written != vin.as_str() {
+        return Err(CodingError::Mismatch(written));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockEcu;
+
+    /// ECU storing a VIN, writable after security access in the extended
+    /// session
+    struct Coding {
+        vin: Vec<u8>,
+        session: u8,
+        unlocked: bool,
+    }
+
+    fn answer(ecu: &mut Coding, request: &[u8]) -> Option<Vec<u8>> {
+        Some(match request {
+            [0x10, session] => {
+                ecu.session = *session;
+                ecu.unlocked = false;
+                vec![0x50, *session]
+            }
+            [0x27, 0x01] => vec![0x67, 0x01, 0x12, 0x34, 0x56],
+            [0x27, 0x02, ..] => {
+                ecu.unlocked = true;
+                vec![0x67, 0x02]
+            }
+            [0x22, 0xF1, 0x90] => [&[0x62, 0xF1, 0x90][..], &ecu.vin].concat(),
+            [0x2E, 0xF1, 0x90, vin @ ..] if ecu.session == 0x03 && ecu.unlocked => {
+                ecu.vin = vin.to_vec();
+                vec![0x6E, 0xF1, 0x90]
+            }
+            [0x2E, ..] => vec![0x7F, 0x2E, 0x33],
+            _ => return None,
+        })
+    }
+
+    #[test]
+    fn writes_and_reads_back_vin() {
+        let coding = Coding {
+            vin: vec![0xFF; 17],
+            session: 0x81,
+            unlocked: false,
+        };
+        let mut ecu = MockEcu::new(coding, answer);
+        assert_eq!(read_vin(&mut ecu, 0x7E0).unwrap(), "");
+
+        let profile = EcuProfile::default();
+        let vin = Vin::parse("JM1BL1ML3A1234567").unwrap();
+        write_vin(&mut ecu, &profile, SESSION_EXTENDED, &vin).unwrap();
+        assert_eq!(read_vin(&mut ecu, 0x7E0).unwrap(), vin.as_str());
+        assert_eq!(ecu.state.session, 0x81);
+
+        // Rejected in a session without write access, and left again
+        let other = Vin::parse("JM1BK32F781234567").unwrap();
+        let result = write_vin(&mut ecu, &profile, 0x87, &other);
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            MzrError::Obd(obd::Error::NegativeResponse(Some(0x33))).to_string()
+        );
+        assert_eq!(read_vin(&mut ecu, 0x7E0).unwrap(), vin.as_str());
+        assert_eq!(ecu.state.session, 0x81);
+    }
+}
@@ -0,0 +1,164 @@
+//! User configuration shared by the CLIs. The configuration is stored as
+//! TOML in the platform configuration directory, e.g.
+//! `~/.config/mzr/config.toml` on Linux.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::dashboard::DashboardConfig;
+use crate::gps::GpsConfig;
+use crate::influx::InfluxConfig;
+use crate::lap::LapConfig;
+use crate::mqtt::MqttConfig;
+use crate::record::{self, LogChannel};
+use crate::voltage;
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("failed to access config file: {0}")]
+    Io(#[from] io::Error),
+    #[error("invalid config file: {0}")]
+    Parse(#[from] toml::de::Error),
+    #[error("failed to serialize config: {0}")]
+    Serialize(#[from] toml::ser::Error),
+    #[error("no configuration directory available on this platform")]
+    NoConfigDir,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// J2534 device name or index
+    pub device: Option<String>,
+    /// Path of a J2534 library to use instead of the installed drivers
+    pub library: Option<PathBuf>,
+    /// Vehicle model
+    pub model: Option<String>,
+    /// Arbitration ID used for requests to the ECU
+    pub request_id: u32,
+    /// Arbitration ID the ECU responds with
+    pub response_id: u32,
+    /// Directory downloads are saved to when no output file is given
+    pub output_dir: Option<PathBuf>,
+    /// PIDs queried by the logger
+    pub log_pids: Vec<u16>,
+    /// Minimum battery voltage required for flashing
+    pub min_voltage: f32,
+    /// Public keys (hex) of package signers trusted by the flash tool. When
+    /// set, unsigned files are refused unless `--allow-unsigned` is given.
+    pub trusted_keys: Vec<String>,
+    /// Channels of the live dashboard
+    pub dashboard: Option<DashboardConfig>,
+    /// GPS receiver whose position is merged into log records
+    pub gps: Option<GpsConfig>,
+    /// InfluxDB server the logger writes records to
+    pub influx: Option<InfluxConfig>,
+    /// Finish line and channels of the lap analysis
+    pub lap: Option<LapConfig>,
+    /// Broker the logger publishes records to
+    pub mqtt: Option<MqttConfig>,
+    /// Scaling of logged PIDs. PIDs without a channel are logged raw.
+    pub log_channels: Vec<LogChannel>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            device: None,
+            library: None,
+            model: None,
+            request_id: 0x7e0,
+            response_id: 0x7e8,
+            output_dir: None,
+            log_pids: Vec::new(),
+            min_voltage: voltage::DEFAULT_MIN_VOLTAGE,
+            trusted_keys: Vec::new(),
+            dashboard: None,
+            gps: None,
+            influx: None,
+            lap: None,
+            mqtt: None,
+            log_channels: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Returns the default config file path
+    pub fn path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("mzr").join("config.toml"))
+    }
+
+    /// Loads the config from the default path. Returns the default
+    /// configuration if the file does not exist.
+    pub fn load() -> Result<Config, ConfigError> {
+        match Config::path() {
+            Some(path) => Config::load_from(path),
+            None => Ok(Config::default()),
+        }
+    }
+
+    /// Loads the config from `path`. Returns the default configuration if
+    /// the file does not exist.
+    pub fn load_from<P: AsRef<Path>>(path: P) -> Result<Config, ConfigError> {
+        match fs::read_to_string(path) {
+            Ok(contents) => Ok(toml::from_str(&contents)?),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Saves the config to the default path
+    pub fn save(&self) -> Result<(), ConfigError> {
+        let path = Config::path().ok_or(ConfigError::NoConfigDir)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        self.save_to(path)
+    }
+
+    /// Returns the channels of `log_pids`, or of the channels configured in
+    /// `log_channels` if no PIDs are set
+    pub fn channels(&self) -> Vec<LogChannel> {
+        if self.log_pids.is_empty() {
+            return self.log_channels.clone();
+        }
+        record::channels_for(&self.log_pids, &self.log_channels)
+    }
+
+    /// Saves the config to `path`
+    pub fn save_to<P: AsRef<Path>>(&self, path: P) -> Result<(), ConfigError> {
+        fs::write(path, toml::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partial_config() {
+        let config: Config =
+            toml::from_str("device = \"Tactrix Openport 2.0\"\nlog_pids = [1, 2]").unwrap();
+        assert_eq!(config.device.as_deref(), Some("Tactrix Openport 2.0"));
+        assert_eq!(config.request_id, 0x7e0);
+        assert_eq!(config.log_pids, vec![1, 2]);
+    }
+
+    #[test]
+    fn round_trip() {
+        let config = Config {
+            model: Some("mps6".to_string()),
+            output_dir: Some(PathBuf::from("roms")),
+            ..Config::default()
+        };
+        let parsed: Config = toml::from_str(&toml::to_string_pretty(&config).unwrap()).unwrap();
+        assert_eq!(parsed.model, config.model);
+        assert_eq!(parsed.output_dir, config.output_dir);
+    }
+}
@@ -0,0 +1,323 @@
+//! `.mzrrom` container, bundling a ROM image with the information needed to
+//! use it safely later.
+//!
+//! Layout: the magic `MZRROM\0`, a format version byte, the length of the
+//! header as a little-endian `u32`, the signature block, the header as JSON,
+//! then the image. The signature block holds the signer's ed25519 public key
+//! and the signature of every other byte of the file, or zeros if the
+//! container is unsigned. Version 1 containers have no signature block.
+
+use std::convert::TryInto;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use ed25519_dalek::{
+    Signature, Signer, SigningKey, Verifier, VerifyingKey, PUBLIC_KEY_LENGTH, SIGNATURE_LENGTH,
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::checksum;
+use crate::hash::sha256_hex;
+use crate::memory_map::MzrMemoryMap;
+use crate::metadata::RomMetadata;
+
+/// File extension of containers
+pub const EXTENSION: &str = "mzrrom";
+
+const MAGIC: &[u8; 7] = b"MZRROM\0";
+const VERSION: u8 = 2;
+// Magic, version and header length
+const PREAMBLE_SIZE: usize = MAGIC.len() + 1 + 4;
+const SIGNATURE_BLOCK_SIZE: usize = PUBLIC_KEY_LENGTH + SIGNATURE_LENGTH;
+
+#[derive(Error, Debug)]
+pub enum ContainerError {
+    #[error("failed to access container: {0}")]
+    Io(#[from] io::Error),
+    #[error("not an mzrrom container")]
+    BadMagic,
+    #[error("unsupported container version {0}")]
+    UnsupportedVersion(u8),
+    #[error("container is truncated")]
+    Truncated,
+    #[error("invalid container header: {0}")]
+    Header(#[from] serde_json::Error),
+    #[error("container image does not match its SHA-256 hash")]
+    HashMismatch,
+    #[error("container signature is invalid")]
+    BadSignature,
+}
+
+/// Description of the image stored in a container
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ContainerHeader {
+    /// Identifies the memory map the image was built for
+    pub memory_map: String,
+    /// Vehicle model
+    pub model: Option<String>,
+    /// Address of the first byte of the image
+    pub offset: u32,
+    /// Whether the ROM checksum was correct when the container was written.
+    /// `None` if the image isn't a full ROM.
+    pub checksum_valid: Option<bool>,
+    /// Free-form notes
+    pub notes: Option<String>,
+    /// VIN of the only vehicle the image may be flashed to
+    pub vin_lock: Option<String>,
+    /// SHA-256 hash of the image
+    pub sha256: String,
+    /// Where the image came from, if it was downloaded
+    pub metadata: Option<RomMetadata>,
+}
+
+impl ContainerHeader {
+    /// Returns true if the image may be flashed to the vehicle with `vin`.
+    /// A locked image is refused if the VIN is unknown.
+    pub fn allows_vin(&self, vin: Option<&str>) -> bool {
+        match self.vin_lock {
+            Some(ref lock) => matches!(vin, Some(vin) if lock.eq_ignore_ascii_case(vin)),
+            None => true,
+        }
+    }
+}
+
+/// Signature of a container
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PackageSignature {
+    /// Public key of the signer
+    pub key: VerifyingKey,
+    pub signature: Signature,
+}
+
+/// ROM image with its header
+#[derive(Debug, Clone, PartialEq)]
+pub struct RomContainer {
+    pub header: ContainerHeader,
+    pub data: Vec<u8>,
+    signature: Option<PackageSignature>,
+}
+
+impl RomContainer {
+    /// Wraps a full ROM image read from address 0
+    pub fn new(data: Vec<u8>) -> RomContainer {
+        RomContainer {
+            header: ContainerHeader {
+                memory_map: MzrMemoryMap::default().name,
+                model: None,
+                offset: 0,
+                checksum_valid: None,
+                notes: None,
+                vin_lock: None,
+                sha256: String::new(),
+                metadata: None,
+            },
+            data,
+            signature: None,
+        }
+    }
+
+    /// Returns the signature of a container that was read or signed. Read
+    /// containers only carry signatures that were verified.
+    pub fn signature(&self) -> Option<&PackageSignature> {
+        self.signature.as_ref()
+    }
+
+    /// Returns the public key of the signer
+    pub fn signer(&self) -> Option<VerifyingKey> {
+        self.signature.map(|signature| signature.key)
+    }
+
+    /// Signs the container with `key`. Changing the container afterwards
+    /// drops the signature when it is written.
+    pub fn sign(&mut self, key: &SigningKey) {
+        let (preamble, header) = self.encode_header();
+        let signature = key.sign(&signed_bytes(&preamble, &header, &self.data));
+        self.signature = Some(PackageSignature {
+            key: key.verifying_key(),
+            signature,
+        });
+    }
+
+    /// Returns true if `data` starts with the container magic
+    pub fn is_container(data: &[u8]) -> bool {
+        data.starts_with(MAGIC)
+    }
+
+    /// Parses a container, checking the image against its hash and the
+    /// signature, if any
+    pub fn read(data: &[u8]) -> Result<RomContainer, ContainerError> {
+        if !RomContainer::is_container(data) {
+            return Err(ContainerError::BadMagic);
+        }
+        if data.len() < PREAMBLE_SIZE {
+            return Err(ContainerError::Truncated);
+        }
+        let version = data[MAGIC.len()];
+        let signature_size = match version {
+            1 => 0,
+            VERSION => SIGNATURE_BLOCK_SIZE,
+            _ => return Err(ContainerError::UnsupportedVersion(version)),
+        };
+        let length = u32::from_le_bytes(data[MAGIC.len() + 1..PREAMBLE_SIZE].try_into().unwrap());
+        let header_start = PREAMBLE_SIZE + signature_size;
+        let header_end = header_start
+            .checked_add(length as usize)
+            .filter(|&end| end <= data.len())
+            .ok_or(ContainerError::Truncated)?;
+        let header: ContainerHeader = serde_json::from_slice(&data[header_start..header_end])?;
+        let image = &data[header_end..];
+        if sha256_hex(image) != header.sha256 {
+            return Err(ContainerError::HashMismatch);
+        }
+
+        let block = &data[PREAMBLE_SIZE..header_start];
+        let signature = if block.iter().all(|&b| b == 0) {
+            None
+        } else {
+            let (key, signature) = block.split_at(PUBLIC_KEY_LENGTH);
+            let key = VerifyingKey::from_bytes(key.try_into().unwrap())
+                .map_err(|_| ContainerError::BadSignature)?;
+            let signature = Signature::from_bytes(signature.try_into().unwrap());
+            let signed = signed_bytes(
+                &data[..PREAMBLE_SIZE],
+                &data[header_start..header_end],
+                image,
+            );
+            key.verify(&signed, &signature)
+                .map_err(|_| ContainerError::BadSignature)?;
+            Some(PackageSignature { key, signature })
+        };
+
+        Ok(RomContainer {
+            header,
+            data: image.to_vec(),
+            signature,
+        })
+    }
+
+    /// Serializes the container. The hash and checksum status are updated
+    /// from the image, and a signature that no longer matches is dropped.
+    pub fn write(&mut self) -> Vec<u8> {
+        let (preamble, header) = self.encode_header();
+        let signed = signed_bytes(&preamble, &header, &self.data);
+        if let Some(signature) = self.signature {
+            if signature.key.verify(&signed, &signature.signature).is_err() {
+                self.signature = None;
+            }
+        }
+
+        let mut out = Vec::with_capacity(signed.len() + SIGNATURE_BLOCK_SIZE);
+        out.extend_from_slice(&preamble);
+        match self.signature {
+            Some(signature) => {
+                out.extend_from_slice(signature.key.as_bytes());
+                out.extend_from_slice(&signature.signature.to_bytes());
+            }
+            None => out.extend_from_slice(&[0; SIGNATURE_BLOCK_SIZE]),
+        }
+        out.extend_from_slice(&signed[PREAMBLE_SIZE..]);
+        out
+    }
+
+    /// Updates the hash and checksum status, and returns the preamble and
+    /// encoded header
+    fn encode_header(&mut self) -> (Vec<u8>, Vec<u8>) {
+        self.header.sha256 = sha256_hex(&self.data);
+        self.header.checksum_valid = match MzrMemoryMap::by_name(&self.header.memory_map) {
+            Some(ref map) if self.header.offset == map.flash().start => {
+                checksum::rom_checksums(&self.data, map)
+                    .map(|_| checksum::checksum_valid(&self.data, map))
+            }
+            _ => None,
+        };
+        let header = serde_json::to_vec(&self.header).unwrap();
+        let mut preamble = Vec::with_capacity(PREAMBLE_SIZE);
+        preamble.extend_from_slice(MAGIC);
+        preamble.push(VERSION);
+        preamble.extend_from_slice(&(header.len() as u32).to_le_bytes());
+        (preamble, header)
+    }
+
+    /// Loads the container at `path`
+    pub fn load(path: &Path) -> Result<RomContainer, ContainerError> {
+        RomContainer::read(&fs::read(path)?)
+    }
+
+    /// Writes the container to `path`
+    pub fn save(&mut self, path: &Path) -> Result<(), ContainerError> {
+        fs::write(path, self.write())?;
+        Ok(())
+    }
+}
+
+/// Returns the bytes covered by the signature: the file without its
+/// signature block
+fn signed_bytes(preamble: &[u8], header: &[u8], image: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(preamble.len() + header.len() + image.len());
+    bytes.extend_from_slice(preamble);
+    bytes.extend_from_slice(header);
+    bytes.extend_from_slice(image);
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let map = MzrMemoryMap::default();
+        let mut rom = vec![0; map.flash_size()];
+        checksum::correct_rom_checksum(&mut rom, &map);
+        let mut container = RomContainer::new(rom);
+        container.header.notes = Some("stock".to_string());
+        let mut bytes = container.write();
+        assert_eq!(container.header.checksum_valid, Some(true));
+        assert_eq!(RomContainer::read(&bytes).unwrap(), container);
+
+        *bytes.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            RomContainer::read(&bytes),
+            Err(ContainerError::HashMismatch)
+        ));
+        assert!(matches!(
+            RomContainer::read(&bytes[..10]),
+            Err(ContainerError::Truncated)
+        ));
+    }
+
+    #[test]
+    fn signatures() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let mut container = RomContainer::new(vec![1, 2, 3]);
+        container.sign(&key);
+        let bytes = container.write();
+        let read = RomContainer::read(&bytes).unwrap();
+        assert_eq!(read.signer(), Some(key.verifying_key()));
+
+        // Tampering with the header invalidates the signature
+        let mut tampered = bytes.clone();
+        let at = bytes.windows(3).position(|w| w == b"-1m").unwrap();
+        tampered[at + 1] = b'2';
+        assert!(matches!(
+            RomContainer::read(&tampered),
+            Err(ContainerError::BadSignature)
+        ));
+
+        // The VIN lock is covered by the signature
+        container.header.vin_lock = Some("JM1BK00000000001".to_string());
+        container.sign(&key);
+        let read = RomContainer::read(&container.write()).unwrap();
+        assert!(read.signer().is_some());
+        assert!(read.header.allows_vin(Some("JM1BK00000000001")));
+        assert!(!read.header.allows_vin(Some("JM1BK00000000002")));
+        assert!(!read.header.allows_vin(None));
+
+        // Editing a signed container drops the signature
+        container.header.notes = Some("edited".to_string());
+        let bytes = container.write();
+        assert_eq!(RomContainer::read(&bytes).unwrap().signature(), None);
+    }
+}
@@ -0,0 +1,586 @@
+//! Unattended logging for an adapter left in the car, e.g. a Raspberry Pi
+//! with a CAN hat in the glovebox. The daemon waits for bus traffic, which
+//! starts when the ignition is switched on, logs a set of PIDs to CSV files
+//! rotated by size and age, and closes the file once the ECU stops
+//! answering after the ignition is switched off. Each file starts with the
+//! metadata of the session, e.g. the VIN and calibration ID read from the
+//! ECU when the ignition is switched on. Trouble codes can be polled at a
+//! low rate alongside, so a code setting is logged with the data around it.
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use thiserror::Error;
+
+use mzr_core::uds::{self, UDS_REQ_READDATABYIDENTIFIER};
+
+use crate::cancel::CancelToken;
+use crate::datalink::can::{has_traffic, Can};
+use crate::dtc::Dtc;
+use crate::isotp::{Isotp, IsotpCan, IsotpError};
+use crate::metadata::LogMetadata;
+use crate::record::{LogChannel, Record, RecordSink};
+
+/// Time without answers from the ECU after which the ignition is assumed off
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Size after which a new log file is started
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 10 * 1024 * 1024;
+/// Age after which a new log file is started
+pub const DEFAULT_MAX_FILE_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// How long the bus is listened to between checks for cancellation
+const ACTIVITY_WINDOW: Duration = Duration::from_secs(1);
+/// Response timeout of each PID request
+const REQUEST_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// OBD services reporting stored and pending trouble codes
+const OBD_REQ_TROUBLECODES: u8 = 0x03;
+const OBD_REQ_PENDINGTROUBLECODES: u8 = 0x07;
+
+#[derive(Error, Debug)]
+pub enum DaemonError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error(transparent)]
+    Isotp(#[from] IsotpError),
+}
+
+/// Serialized tagged with its snake_case name, like [`Event`](crate::event::Event)
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum DaemonEvent {
+    /// Traffic appeared on the bus and logging started
+    IgnitionOn,
+    /// The ECU stopped answering and the log file was closed after `rows`
+    /// rows
+    IgnitionOff { rows: u64 },
+    /// A new log file was started
+    FileOpened { path: PathBuf },
+    /// Reading `pid` failed. Its column is left empty for this row.
+    ReadFailed { pid: u16, error: String },
+    /// `code` set since logging started, as pending if it is not yet
+    /// confirmed
+    TroubleCode { code: Dtc, pending: bool },
+    /// Polling trouble codes failed. They are not polled again until the
+    /// next ignition cycle.
+    TroubleCodesFailed { error: String },
+}
+
+impl fmt::Display for DaemonEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DaemonEvent::IgnitionOn => write!(f, "Ignition on, logging"),
+            DaemonEvent::IgnitionOff { rows } => {
+                write!(f, "Ignition off, logged {} rows", rows)
+            }
+            DaemonEvent::FileOpened { path } => write!(f, "Logging to {}", path.display()),
+            DaemonEvent::ReadFailed { pid, error } => {
+                write!(f, "Failed to read PID 0x{:04X}: {}", pid, error)
+            }
+            DaemonEvent::TroubleCode { code, pending } => {
+                write!(f, "Trouble code {} set", code)?;
+                if *pending {
+                    write!(f, " (pending)")?;
+                }
+                Ok(())
+            }
+            DaemonEvent::TroubleCodesFailed { error } => {
+                write!(f, "Failed to read trouble codes: {}", error)
+            }
+        }
+    }
+}
+
+/// Returns the header of CSV logs of `pids`, e.g. `time,0x000C,0x000D`
+pub fn csv_header(pids: &[u16]) -> String {
+    pids.iter().fold(String::from("time"), |header, pid| {
+        format!("{},0x{:04X}", header, pid)
+    })
+}
+
+struct LogFile {
+    writer: BufWriter<File>,
+    opened: Instant,
+    size: u64,
+}
+
+/// CSV log split into files of limited size and age. Each file starts with
+/// the header and is named after the time it was started, e.g.
+/// `log-1600000000.csv`.
+pub struct RotatingLog {
+    dir: PathBuf,
+    header: String,
+    max_size: u64,
+    max_age: Duration,
+    current: Option<LogFile>,
+}
+
+impl RotatingLog {
+    pub fn new(
+        dir: impl Into<PathBuf>,
+        header: String,
+        max_size: u64,
+        max_age: Duration,
+    ) -> RotatingLog {
+        RotatingLog {
+            dir: dir.into(),
+            header,
+            max_size,
+            max_age,
+            current: None,
+        }
+    }
+
+    /// Appends `line`, first starting a new file if there is none or the
+    /// current one is full or too old. Returns the path of the new file,
+    /// if one was started.
+    pub fn write_line(&mut self, line: &str) -> io::Result<Option<PathBuf>> {
+        let expired = match &self.current {
+            Some(file) => file.size >= self.max_size || file.opened.elapsed() >= self.max_age,
+            None => true,
+        };
+        let mut opened = None;
+        if expired {
+            self.close()?;
+            opened = Some(self.open()?);
+        }
+        let file = self.current.as_mut().unwrap();
+        writeln!(file.writer, "{}", line)?;
+        file.size += line.len() as u64 + 1;
+        Ok(opened)
+    }
+
+    /// Sets the header of files started from now on
+    pub fn set_header(&mut self, header: String) {
+        self.header = header;
+    }
+
+    /// Flushes and closes the current file. The next line starts a new one.
+    pub fn close(&mut self) -> io::Result<()> {
+        if let Some(mut file) = self.current.take() {
+            file.writer.flush()?;
+        }
+        Ok(())
+    }
+
+    fn open(&mut self) -> io::Result<PathBuf> {
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs());
+        let mut path = self.dir.join(format!("log-{}.csv", secs));
+        let mut index = 1;
+        while path.exists() {
+            path = self.dir.join(format!("log-{}-{}.csv", secs, index));
+            index += 1;
+        }
+        let mut writer = BufWriter::new(
+            OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)?,
+        );
+        writeln!(writer, "{}", self.header)?;
+        self.current = Some(LogFile {
+            writer,
+            opened: Instant::now(),
+            size: self.header.len() as u64 + 1,
+        });
+        Ok(path)
+    }
+}
+
+/// Logs `channels` on every ignition cycle until cancelled
+pub struct Daemon {
+    pub channels: Vec<LogChannel>,
+    pub request_id: u32,
+    pub response_id: u32,
+    /// Time without answers after which the ignition is assumed off
+    pub idle_timeout: Duration,
+    pub max_file_size: u64,
+    pub max_file_age: Duration,
+    pub output_dir: PathBuf,
+    /// Metadata known before logging, e.g. the adapter. The VIN,
+    /// calibration ID, PIDs and start time are filled in each session.
+    pub metadata: LogMetadata,
+    /// Interval at which trouble codes are polled between rows, if at all
+    pub dtc_interval: Option<Duration>,
+}
+
+impl Daemon {
+    pub fn new(channels: Vec<LogChannel>, output_dir: impl AsRef<Path>) -> Daemon {
+        Daemon {
+            channels,
+            request_id: 0x7E0,
+            response_id: 0x7E8,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            max_file_age: DEFAULT_MAX_FILE_AGE,
+            output_dir: output_dir.as_ref().to_path_buf(),
+            metadata: LogMetadata::default(),
+            dtc_interval: None,
+        }
+    }
+
+    /// Waits for ignition-on, logs until ignition-off and repeats until
+    /// `cancel` is cancelled. Rows are also decoded and passed to `records`,
+    /// which are told of each session first.
+    /// The current file is closed before returning.
+    pub fn run<C: Can, F: FnMut(DaemonEvent), R: RecordSink>(
+        &self,
+        can: &C,
+        cancel: &CancelToken,
+        mut on_event: F,
+        mut records: R,
+    ) -> Result<(), DaemonError> {
+        let pids: Vec<u16> = self.channels.iter().map(|channel| channel.pid).collect();
+        let mut log = RotatingLog::new(
+            &self.output_dir,
+            csv_header(&pids),
+            self.max_file_size,
+            self.max_file_age,
+        );
+        while !cancel.is_cancelled() {
+            // Listen to the whole bus for any sign of life
+            can.set_filters(&[])?;
+            if !has_traffic(can, ACTIVITY_WINDOW)? {
+                continue;
+            }
+            on_event(DaemonEvent::IgnitionOn);
+            let result = self.log_cycle(can, cancel, &mut log, &mut on_event, &mut records);
+            log.close()?;
+            records.flush();
+            on_event(DaemonEvent::IgnitionOff { rows: result? });
+        }
+        Ok(())
+    }
+
+    /// Logs rows until the ECU stops answering. Returns the number of rows.
+    fn log_cycle<C: Can, F: FnMut(DaemonEvent), R: RecordSink>(
+        &self,
+        can: &C,
+        cancel: &CancelToken,
+        log: &mut RotatingLog,
+        on_event: &mut F,
+        records: &mut R,
+    ) -> Result<u64, DaemonError> {
+        let isotp = IsotpCan::new(can, self.request_id, self.response_id, REQUEST_TIMEOUT);
+        isotp.filter_bus()?;
+        let metadata = self.session_metadata(&isotp);
+        let pids: Vec<u16> = self.channels.iter().map(|channel| channel.pid).collect();
+        log.set_header(format!("{}{}", metadata.comment(), csv_header(&pids)));
+        records.begin_session(&metadata);
+        let mut trouble_codes = self.dtc_interval.map(TroubleCodes::new);
+        let mut last_answer = Instant::now();
+        let mut rows = 0;
+        while !cancel.is_cancelled() && last_answer.elapsed() < self.idle_timeout {
+            let mut record = Record::new();
+            let mut row = record.time.to_string();
+            let mut answered = false;
+            for channel in &self.channels {
+                let pid = channel.pid;
+                row.push(',');
+                match read_pid(&isotp, pid) {
+                    Ok(value) => {
+                        answered = true;
+                        for byte in &value {
+                            row.push_str(&format!("{:02X}", byte));
+                        }
+                        if let Some(value) = channel.decode(&value) {
+                            record.values.insert(channel.name.clone(), value);
+                        }
+                    }
+                    Err(error) => on_event(DaemonEvent::ReadFailed { pid, error }),
+                }
+            }
+            if !answered {
+                continue;
+            }
+            last_answer = Instant::now();
+            if let Some(codes) = &mut trouble_codes {
+                if !codes.poll(&isotp, &mut record, on_event) {
+                    trouble_codes = None;
+                }
+            }
+            if let Some(path) = log.write_line(&row)? {
+                on_event(DaemonEvent::FileOpened { path });
+            }
+            records.send(&record);
+            rows += 1;
+        }
+        Ok(rows)
+    }
+
+    /// Completes the metadata of a session starting now. The VIN and
+    /// calibration ID are left out if the ECU does not report them.
+    fn session_metadata<I: Isotp>(&self, isotp: &I) -> LogMetadata {
+        LogMetadata {
+            vin: read_vehicle_info(isotp, crate::OBD_PID_VIN),
+            calibration_id: read_vehicle_info(isotp, crate::OBD_PID_CALIBRATION_ID),
+            pids: self.channels.iter().map(|channel| channel.pid).collect(),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_secs()),
+            ..self.metadata.clone()
+        }
+    }
+}
+
+/// Trouble codes seen in an ignition cycle, polled at an interval
+struct TroubleCodes {
+    interval: Duration,
+    last_poll: Option<Instant>,
+    /// Codes seen so far, and whether they were pending
+    seen: BTreeSet<(Dtc, bool)>,
+}
+
+impl TroubleCodes {
+    fn new(interval: Duration) -> TroubleCodes {
+        TroubleCodes {
+            interval,
+            last_poll: None,
+            seen: BTreeSet::new(),
+        }
+    }
+
+    /// Polls the codes if due, adding codes set since the first poll to the
+    /// events of `record`. Returns false if polling failed.
+    fn poll<I: Isotp, F: FnMut(DaemonEvent)>(
+        &mut self,
+        isotp: &I,
+        record: &mut Record,
+        on_event: &mut F,
+    ) -> bool {
+        let first = match self.last_poll {
+            Some(time) if time.elapsed() < self.interval => return true,
+            Some(_) => false,
+            None => true,
+        };
+        self.last_poll = Some(Instant::now());
+        for &(service, pending) in &[
+            (OBD_REQ_TROUBLECODES, false),
+            (OBD_REQ_PENDINGTROUBLECODES, true),
+        ] {
+            let codes = match read_trouble_codes(isotp, service) {
+                Ok(codes) => codes,
+                Err(error) => {
+                    on_event(DaemonEvent::TroubleCodesFailed { error });
+                    return false;
+                }
+            };
+            for code in codes {
+                // Codes set before logging started are not events
+                if self.seen.insert((code, pending)) && !first {
+                    let state = if pending { "pending" } else { "stored" };
+                    record.events.push(format!("DTC {} {}", code, state));
+                    on_event(DaemonEvent::TroubleCode { code, pending });
+                }
+            }
+        }
+        true
+    }
+}
+
+/// Reads the trouble codes reported by OBD `service`
+fn read_trouble_codes<I: Isotp>(isotp: &I, service: u8) -> Result<Vec<Dtc>, String> {
+    let response = isotp
+        .request_isotp(&[service])
+        .map_err(|err| err.to_string())?;
+    let data = uds::parse_response(service, &response).map_err(|err| err.to_string())?;
+    match data {
+        [_count, codes @ ..] => Ok(codes
+            .chunks_exact(2)
+            .map(|code| Dtc(u16::from_be_bytes([code[0], code[1]])))
+            // Unused slots are zero
+            .filter(|code| code.0 != 0)
+            .collect()),
+        _ => Err(uds::ResponseError::Invalid.to_string()),
+    }
+}
+
+/// Reads the OBD vehicle information string `pid`, e.g. the VIN
+fn read_vehicle_info<I: Isotp>(isotp: &I, pid: u8) -> Option<String> {
+    let response = isotp
+        .request_isotp(&[crate::OBD_REQ_VEHICLEINFO, pid])
+        .ok()?;
+    match response.split_first() {
+        Some((&service, info)) if service == crate::OBD_REQ_VEHICLEINFO + 0x40 => {
+            crate::info_string(pid, info)
+                .ok()
+                .filter(|text| !text.is_empty())
+        }
+        _ => None,
+    }
+}
+
+/// Reads `pid` with ReadDataByIdentifier and returns its value
+pub fn read_pid<I: Isotp>(isotp: &I, pid: u16) -> Result<Vec<u8>, String> {
+    let did = pid.to_be_bytes();
+    let response = isotp
+        .request_isotp(&[UDS_REQ_READDATABYIDENTIFIER, did[0], did[1]])
+        .map_err(|err| err.to_string())?;
+    let data = uds::parse_response(UDS_REQ_READDATABYIDENTIFIER, &response)
+        .map_err(|err| err.to_string())?;
+    match data {
+        [hi, lo, value @ ..] if [*hi, *lo] == did => Ok(value.to_vec()),
+        _ => Err(uds::ResponseError::Invalid.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datalink::can::Message;
+    use std::cell::{Cell, RefCell};
+    use std::collections::VecDeque;
+    use std::fs;
+
+    /// ECU answering `answers` PID requests, preceded by some bus traffic,
+    /// before the ignition is switched off. P0301 sets after the first poll
+    /// of stored trouble codes.
+    struct Car {
+        traffic: Cell<u32>,
+        answers: Cell<u32>,
+        dtc_polls: Cell<u32>,
+        received: RefCell<VecDeque<Message>>,
+    }
+
+    impl Can for Car {
+        fn send_msg(&self, msg: &Message) -> io::Result<()> {
+            if let [0x03, 0x22, hi, lo, ..] = *msg.payload() {
+                if self.answers.get() > 0 {
+                    self.answers.set(self.answers.get() - 1);
+                    self.received
+                        .borrow_mut()
+                        .push_back(Message::new(0x7E8, &[0x05, 0x62, hi, lo, 0x12, 0x34]));
+                }
+            }
+            match *msg.payload() {
+                [0x01, 0x03, ..] => {
+                    let polls = self.dtc_polls.get();
+                    self.dtc_polls.set(polls + 1);
+                    let response = if polls == 0 {
+                        Message::new(0x7E8, &[0x02, 0x43, 0x00])
+                    } else {
+                        Message::new(0x7E8, &[0x04, 0x43, 0x01, 0x03, 0x01])
+                    };
+                    self.received.borrow_mut().push_back(response);
+                }
+                [0x01, 0x07, ..] => self
+                    .received
+                    .borrow_mut()
+                    .push_back(Message::new(0x7E8, &[0x02, 0x47, 0x00])),
+                _ => {}
+            }
+            Ok(())
+        }
+
+        fn read(&self, _timeout: Duration) -> io::Result<Message> {
+            if let Some(msg) = self.received.borrow_mut().pop_front() {
+                return Ok(msg);
+            }
+            if self.traffic.get() == 0 {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            self.traffic.set(self.traffic.get() - 1);
+            Ok(Message::new(0x201, &[0, 0, 0x12, 0x34]))
+        }
+    }
+
+    /// Records and sessions passed to the sink
+    #[derive(Default)]
+    struct Records {
+        records: Vec<Record>,
+        sessions: Vec<LogMetadata>,
+    }
+
+    impl RecordSink for &mut Records {
+        fn send(&mut self, record: &Record) {
+            self.records.push(record.clone());
+        }
+
+        fn begin_session(&mut self, metadata: &LogMetadata) {
+            self.sessions.push(metadata.clone());
+        }
+    }
+
+    #[test]
+    fn logs_one_ignition_cycle_into_rotated_files() {
+        let dir = std::env::temp_dir().join(format!("mzr-daemon-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let car = Car {
+            traffic: Cell::new(2),
+            answers: Cell::new(6),
+            dtc_polls: Cell::new(0),
+            received: RefCell::new(VecDeque::new()),
+        };
+        let rpm = LogChannel {
+            name: "rpm".to_string(),
+            factor: 0.25,
+            ..LogChannel::raw(0x000C)
+        };
+        let mut daemon = Daemon::new(vec![rpm, LogChannel::raw(0x000D)], &dir);
+        daemon.idle_timeout = Duration::from_millis(50);
+        daemon.dtc_interval = Some(Duration::ZERO);
+        daemon.metadata.adapter = Some("Car".to_string());
+        // The header of 80 bytes and two rows of 24 bytes fill a file
+        daemon.max_file_size = 120;
+
+        let cancel = CancelToken::new();
+        let mut events = Vec::new();
+        let mut records = Records::default();
+        daemon
+            .run(
+                &car,
+                &cancel,
+                |event| {
+                    if let DaemonEvent::IgnitionOff { .. } = event {
+                        cancel.cancel();
+                    }
+                    events.push(event);
+                },
+                &mut records,
+            )
+            .unwrap();
+
+        assert_eq!(events[0], DaemonEvent::IgnitionOn);
+        assert_eq!(events.last(), Some(&DaemonEvent::IgnitionOff { rows: 3 }));
+        assert_eq!(records.records.len(), 3);
+        assert_eq!(records.records[0].values["rpm"], 1165.0);
+        assert_eq!(records.records[0].values["0x000D"], 4660.0);
+        assert!(records.records[0].events.is_empty());
+        assert_eq!(records.records[1].events, ["DTC P0301 stored"]);
+        assert!(records.records[2].events.is_empty());
+        assert!(events.contains(&DaemonEvent::TroubleCode {
+            code: Dtc(0x0301),
+            pending: false
+        }));
+        let metadata = &records.sessions[0];
+        assert_eq!(records.sessions.len(), 1);
+        assert_eq!(metadata.adapter.as_deref(), Some("Car"));
+        assert_eq!(metadata.vin, None);
+        assert_eq!(metadata.pids, [0x000C, 0x000D]);
+        let files: Vec<PathBuf> = events
+            .iter()
+            .filter_map(|event| match event {
+                DaemonEvent::FileOpened { path } => Some(path.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(files.len(), 2);
+        let first = fs::read_to_string(&files[0]).unwrap();
+        let lines: Vec<&str> = first.lines().collect();
+        assert_eq!(lines.len(), 6);
+        assert_eq!(lines[0], "# adapter: Car");
+        assert_eq!(lines[1], "# pids: 0x000C 0x000D");
+        assert_eq!(lines[3], "time,0x000C,0x000D");
+        assert!(lines[4].ends_with(",1234,1234"));
+        assert_eq!(fs::read_to_string(&files[1]).unwrap().lines().count(), 5);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,204 @@
+//! State of the live dashboard: the latest value, min/max and recent
+//! history of each shown channel, and knock events. Rendering is left to
+//! the frontend, e.g. the terminal dashboard of mzr-log.
+//!
+//! ```toml
+//! [dashboard]
+//! channels = ["rpm", "boost", "afr", "knock_retard"]
+//! knock_channels = ["knock_retard"]
+//! ranges = { rpm = [0, 7000], boost = [-15, 25] }
+//! ```
+
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::record::{LogChannel, Record};
+
+/// Samples kept for each channel's history
+pub const HISTORY_LENGTH: usize = 120;
+
+/// How long a knock event stays highlighted
+pub const KNOCK_HIGHLIGHT: Duration = Duration::from_secs(3);
+
+/// Channels shown and how
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DashboardConfig {
+    /// Names of the channels shown. Empty shows every logged channel.
+    pub channels: Vec<String>,
+    /// Channels reporting knock, e.g. knock retard or a knock count. A
+    /// value beyond `knock_threshold` either way is a knock event.
+    pub knock_channels: Vec<String>,
+    pub knock_threshold: f64,
+    /// Scale of each channel's gauge. Channels without one are scaled to
+    /// the min/max seen so far.
+    pub ranges: BTreeMap<String, [f64; 2]>,
+}
+
+/// Values of one channel seen so far
+#[derive(Debug, Clone, PartialEq)]
+pub struct Gauge {
+    pub name: String,
+    pub unit: String,
+    pub range: Option<[f64; 2]>,
+    pub knock: bool,
+    pub latest: Option<f64>,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    /// Recent values, oldest first
+    pub history: VecDeque<f64>,
+    /// Knock events seen
+    pub knock_events: u64,
+    /// When the latest knock event was seen
+    pub last_knock: Option<Instant>,
+}
+
+impl Gauge {
+    /// Position of `value` within the configured range, or the min/max
+    /// seen so far, from 0 to 1
+    pub fn scale(&self, value: f64) -> f64 {
+        let (min, max) = match self.range {
+            Some([min, max]) => (min, max),
+            None => (self.min.unwrap_or(0.0), self.max.unwrap_or(0.0)),
+        };
+        if max > min {
+            ((value - min) / (max - min)).clamp(0.0, 1.0)
+        } else {
+            0.0
+        }
+    }
+
+    /// Position of the latest value, from 0 to 1
+    pub fn ratio(&self) -> f64 {
+        self.latest.map_or(0.0, |value| self.scale(value))
+    }
+
+    /// Whether a knock event was seen within [`KNOCK_HIGHLIGHT`]
+    pub fn knocking(&self) -> bool {
+        self.last_knock
+            .is_some_and(|time| time.elapsed() < KNOCK_HIGHLIGHT)
+    }
+}
+
+/// Gauges of the shown channels, updated from log records
+pub struct Dashboard {
+    pub gauges: Vec<Gauge>,
+    knock_threshold: f64,
+}
+
+impl Dashboard {
+    /// Creates gauges for the `channels` selected in `config`
+    pub fn new(config: &DashboardConfig, channels: &[LogChannel]) -> Dashboard {
+        let gauges = channels
+            .iter()
+            .filter(|channel| config.channels.is_empty() || config.channels.contains(&channel.name))
+            .map(|channel| Gauge {
+                name: channel.name.clone(),
+                unit: channel.unit.clone(),
+                range: config.ranges.get(&channel.name).copied(),
+                knock: config.knock_channels.contains(&channel.name),
+                latest: None,
+                min: None,
+                max: None,
+                history: VecDeque::with_capacity(HISTORY_LENGTH),
+                knock_events: 0,
+                last_knock: None,
+            })
+            .collect();
+        Dashboard {
+            gauges,
+            knock_threshold: config.knock_threshold,
+        }
+    }
+
+    /// Adds the values of `record`. A knock event is counted when a knock
+    /// channel goes beyond the threshold.
+    pub fn update(&mut self, record: &Record) {
+        let threshold = self.knock_threshold;
+        let knocked = |value: Option<f64>| value.is_some_and(|value| value.abs() > threshold);
+        for gauge in &mut self.gauges {
+            let value = match record.values.get(&gauge.name) {
+                Some(&value) => value,
+                None => continue,
+            };
+            if gauge.knock && knocked(Some(value)) && !knocked(gauge.latest) {
+                gauge.knock_events += 1;
+                gauge.last_knock = Some(Instant::now());
+            }
+            gauge.latest = Some(value);
+            gauge.min = Some(gauge.min.map_or(value, |min| min.min(value)));
+            gauge.max = Some(gauge.max.map_or(value, |max| max.max(value)));
+            if gauge.history.len() == HISTORY_LENGTH {
+                gauge.history.pop_front();
+            }
+            gauge.history.push_back(value);
+        }
+    }
+
+    /// Forgets the min/max and knock events seen so far
+    pub fn reset(&mut self) {
+        for gauge in &mut self.gauges {
+            gauge.min = gauge.latest;
+            gauge.max = gauge.latest;
+            gauge.knock_events = 0;
+            gauge.last_knock = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracks_min_max_and_knock() {
+        let config: DashboardConfig = toml::from_str(
+            "channels = [\"rpm\", \"knock\"]\nknock_channels = [\"knock\"]\nranges = { rpm = [0, 8000] }",
+        )
+        .unwrap();
+        let channels = [
+            LogChannel::raw(1),
+            LogChannel {
+                name: "rpm".to_string(),
+                ..LogChannel::raw(2)
+            },
+            LogChannel {
+                name: "knock".to_string(),
+                ..LogChannel::raw(3)
+            },
+        ];
+        let mut dashboard = Dashboard::new(&config, &channels);
+        assert_eq!(dashboard.gauges.len(), 2);
+        for (rpm, knock) in [
+            (2000.0, 0.0),
+            (4000.0, -1.5),
+            (3000.0, -2.0),
+            (3000.0, 0.0),
+            (3500.0, -1.0),
+        ] {
+            let mut record = Record::new();
+            record.values.insert("rpm".to_string(), rpm);
+            record.values.insert("knock".to_string(), knock);
+            dashboard.update(&record);
+        }
+        let rpm = &dashboard.gauges[0];
+        assert_eq!(
+            (rpm.min, rpm.max, rpm.latest),
+            (Some(2000.0), Some(4000.0), Some(3500.0))
+        );
+        assert_eq!(rpm.ratio(), 3500.0 / 8000.0);
+        assert_eq!(rpm.history.len(), 5);
+        let knock = &dashboard.gauges[1];
+        // Continued knock is one event
+        assert_eq!(knock.knock_events, 2);
+        assert!(knock.knocking());
+        assert_eq!(knock.ratio(), 0.5);
+        assert!(!rpm.knocking());
+
+        dashboard.reset();
+        assert_eq!(dashboard.gauges[0].min, Some(3500.0));
+        assert!(!dashboard.gauges[1].knocking());
+    }
+}
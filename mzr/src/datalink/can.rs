@@ -0,0 +1,358 @@
+use std::fmt;
+use std::io;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use thiserror::Error;
+
+/// Diagnostic CAN bus of Mazda vehicles. The engine and chassis modules are
+/// on the high-speed bus (pins 6/14), the body and instrument modules on the
+/// medium-speed bus (pins 3/11).
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+pub enum CanBus {
+    HighSpeed,
+    MediumSpeed,
+}
+
+impl CanBus {
+    /// Returns the bitrate of the bus in bits per second
+    pub fn bitrate(self) -> u32 {
+        match self {
+            CanBus::HighSpeed => 500_000,
+            CanBus::MediumSpeed => 125_000,
+        }
+    }
+
+    /// Returns the bus running at `bitrate`, if any
+    pub fn from_bitrate(bitrate: u32) -> Option<CanBus> {
+        [CanBus::HighSpeed, CanBus::MediumSpeed]
+            .iter()
+            .copied()
+            .find(|bus| bus.bitrate() == bitrate)
+    }
+}
+
+impl fmt::Display for CanBus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            CanBus::HighSpeed => "HS-CAN",
+            CanBus::MediumSpeed => "MS-CAN",
+        })
+    }
+}
+
+/// Largest 11-bit identifier
+pub const MAX_STANDARD_ID: u32 = 0x7FF;
+
+/// Largest 29-bit identifier
+pub const MAX_EXTENDED_ID: u32 = 0x1FFF_FFFF;
+
+/// Payload lengths a CAN-FD frame can have. Shorter payloads are padded to
+/// the next one.
+pub const FD_FRAME_LENGTHS: [usize; 16] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 12, 16, 20, 24, 32, 48, 64];
+
+/// Returns the length of the smallest CAN-FD frame holding `len` bytes, or
+/// `None` if `len` exceeds 64 bytes
+pub fn fd_frame_len(len: usize) -> Option<usize> {
+    FD_FRAME_LENGTHS
+        .iter()
+        .copied()
+        .find(|&frame_len| frame_len >= len)
+}
+
+/// Classic or CAN-FD message
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub id: u32,
+    pub data: [u8; 64],
+    pub len: u8,
+    /// Whether `id` is a 29-bit identifier
+    pub extended: bool,
+    /// Whether this is a CAN-FD frame
+    pub fd: bool,
+    /// Whether the data phase of a CAN-FD frame is sent at the higher
+    /// bitrate (bit rate switch)
+    pub brs: bool,
+}
+
+impl Message {
+    /// Creates a classic message. `data` must not be longer than 8 bytes.
+    /// IDs that don't fit in 11 bits are sent as 29-bit identifiers.
+    pub fn new(id: u32, data: &[u8]) -> Message {
+        assert!(data.len() <= 8);
+        let mut message_data = [0_u8; 64];
+        message_data[..data.len()].copy_from_slice(data);
+        Message {
+            id,
+            data: message_data,
+            len: data.len() as u8,
+            extended: id > MAX_STANDARD_ID,
+            fd: false,
+            brs: false,
+        }
+    }
+
+    /// Creates a CAN-FD message with bit rate switching. `data` must be a
+    /// valid CAN-FD payload length, see [`FD_FRAME_LENGTHS`].
+    pub fn new_fd(id: u32, data: &[u8]) -> Message {
+        assert!(FD_FRAME_LENGTHS.contains(&data.len()));
+        let mut message_data = [0_u8; 64];
+        message_data[..data.len()].copy_from_slice(data);
+        Message {
+            id,
+            data: message_data,
+            len: data.len() as u8,
+            extended: id > MAX_STANDARD_ID,
+            fd: true,
+            brs: true,
+        }
+    }
+
+    /// Creates a message with a 29-bit identifier, even if `id` fits in 11
+    /// bits
+    pub fn new_extended(id: u32, data: &[u8]) -> Message {
+        Message {
+            extended: true,
+            ..Message::new(id, data)
+        }
+    }
+
+    /// Returns true if the identifier fits the message's ID format and the
+    /// length is valid for the frame type
+    pub fn is_valid(&self) -> bool {
+        let valid_len = if self.fd {
+            FD_FRAME_LENGTHS.contains(&(self.len as usize))
+        } else {
+            self.len <= 8
+        };
+        valid_len
+            && self.id
+                <= if self.extended {
+                    MAX_EXTENDED_ID
+                } else {
+                    MAX_STANDARD_ID
+                }
+    }
+
+    /// Returns the valid message payload
+    pub fn payload(&self) -> &[u8] {
+        &self.data[..self.len as usize]
+    }
+}
+
+/// Fault confinement state of a CAN controller
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+pub enum BusState {
+    ErrorActive,
+    /// The controller sent or received too many bad frames and no longer
+    /// signals errors on the bus
+    ErrorPassive,
+    /// The controller sent too many bad frames and disconnected from the
+    /// bus until it is restarted
+    BusOff,
+}
+
+/// Error state reported by a CAN controller
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+pub struct BusStatus {
+    pub state: BusState,
+    /// Transmit error counter, if reported
+    pub tx_errors: Option<u8>,
+    /// Receive error counter, if reported
+    pub rx_errors: Option<u8>,
+}
+
+/// Returned by reads and sends of interfaces whose controller went bus-off
+#[derive(Error, Debug)]
+#[error("CAN controller is bus-off")]
+pub struct BusOff;
+
+/// Returns an error saying the controller is bus-off
+pub fn bus_off_error() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, BusOff)
+}
+
+/// Returns true if `err` says the controller is bus-off
+pub fn is_bus_off(err: &io::Error) -> bool {
+    err.get_ref().is_some_and(|inner| inner.is::<BusOff>())
+}
+
+/// Acceptance filter. A frame passes if its ID format matches and
+/// `frame.id & mask == id & mask`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Filter {
+    pub id: u32,
+    pub mask: u32,
+    /// Whether the filter matches 29-bit identifiers
+    pub extended: bool,
+}
+
+impl Filter {
+    /// Creates a filter passing only frames with `id`
+    pub fn exact(id: u32, extended: bool) -> Filter {
+        Filter {
+            id,
+            mask: if extended {
+                MAX_EXTENDED_ID
+            } else {
+                MAX_STANDARD_ID
+            },
+            extended,
+        }
+    }
+
+    /// Returns true if `msg` passes the filter
+    pub fn matches(&self, msg: &Message) -> bool {
+        msg.extended == self.extended && msg.id & self.mask == self.id & self.mask
+    }
+}
+
+/// Raw CAN interface
+pub trait Can {
+    /// Sends a CAN message
+    fn send_msg(&self, msg: &Message) -> io::Result<()>;
+
+    /// Reads the next CAN message. Returns an error of kind
+    /// [`io::ErrorKind::TimedOut`] if no message arrives within `timeout`.
+    fn read(&self, timeout: Duration) -> io::Result<Message>;
+
+    /// Returns true if the interface can send CAN-FD frames
+    fn supports_fd(&self) -> bool {
+        false
+    }
+
+    /// Receives only frames passing any of `filters`, replacing the previous
+    /// filters. An empty slice receives every frame. Interfaces without
+    /// hardware filtering ignore filters, so callers must still check the
+    /// IDs of received frames.
+    fn set_filters(&self, filters: &[Filter]) -> io::Result<()> {
+        let _ = filters;
+        Ok(())
+    }
+
+    /// Returns the error state of the controller, or `None` if the
+    /// interface doesn't report it. Reads and sends fail with
+    /// [`bus_off_error`] while the controller is bus-off.
+    fn bus_status(&self) -> io::Result<Option<BusStatus>> {
+        Ok(None)
+    }
+}
+
+/// Lets several users take turns on one interface, e.g. successive ISO-TP
+/// connections
+impl<C: Can + ?Sized> Can for &C {
+    fn send_msg(&self, msg: &Message) -> io::Result<()> {
+        (**self).send_msg(msg)
+    }
+
+    fn read(&self, timeout: Duration) -> io::Result<Message> {
+        (**self).read(timeout)
+    }
+
+    fn supports_fd(&self) -> bool {
+        (**self).supports_fd()
+    }
+
+    fn set_filters(&self, filters: &[Filter]) -> io::Result<()> {
+        (**self).set_filters(filters)
+    }
+
+    fn bus_status(&self) -> io::Result<Option<BusStatus>> {
+        (**self).bus_status()
+    }
+}
+
+/// Bitrates tried by [`detect_bitrate`], fastest first
+pub const PROBE_BITRATES: [u32; 3] = [500_000, 250_000, 125_000];
+
+/// Frames that must be received at a bitrate for it to be accepted
+const PROBE_MIN_FRAMES: usize = 2;
+
+/// Listens on `can` for up to `window` and returns true if valid frames were
+/// received. Nothing is transmitted.
+pub fn has_traffic<C: Can>(can: &C, window: Duration) -> io::Result<bool> {
+    let start = Instant::now();
+    let mut frames = 0;
+    while let Some(remaining) = window.checked_sub(start.elapsed()) {
+        match can.read(remaining) {
+            Ok(msg) if msg.is_valid() => {
+                frames += 1;
+                if frames >= PROBE_MIN_FRAMES {
+                    return Ok(true);
+                }
+            }
+            Ok(_) => {}
+            Err(err) if err.kind() == io::ErrorKind::TimedOut => break,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(false)
+}
+
+/// Detects the bitrate of a bus by listening at each of [`PROBE_BITRATES`].
+/// `open` must open the interface at a bitrate in listen-only mode: an
+/// active controller at the wrong bitrate acknowledges frames and sends
+/// error frames, which can upset the modules on a running vehicle's bus.
+/// Returns `None` if there is no traffic at any bitrate, e.g. because the
+/// ignition is off.
+pub fn detect_bitrate<C, F>(mut open: F, window: Duration) -> io::Result<Option<u32>>
+where
+    C: Can,
+    F: FnMut(u32) -> io::Result<C>,
+{
+    for &bitrate in PROBE_BITRATES.iter() {
+        if has_traffic(&open(bitrate)?, window)? {
+            return Ok(Some(bitrate));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    /// Bus running at a fixed bitrate, heard only by interfaces opened at
+    /// the same bitrate
+    struct Bus {
+        bitrate: u32,
+        listening: u32,
+        frames: Cell<u32>,
+    }
+
+    impl Can for Bus {
+        fn send_msg(&self, _msg: &Message) -> io::Result<()> {
+            panic!("probing must not transmit");
+        }
+
+        fn read(&self, _timeout: Duration) -> io::Result<Message> {
+            if self.listening != self.bitrate || self.frames.get() == 0 {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            self.frames.set(self.frames.get() - 1);
+            Ok(Message::new(0x201, &[0, 0, 0x12, 0x34]))
+        }
+    }
+
+    #[test]
+    fn detects_bitrate() {
+        let open = |bitrate, frames| {
+            move |listening| {
+                Ok(Bus {
+                    bitrate,
+                    listening,
+                    frames: Cell::new(frames),
+                })
+            }
+        };
+        let window = Duration::from_millis(100);
+        assert_eq!(
+            detect_bitrate(open(125_000, 10), window).unwrap(),
+            Some(125_000)
+        );
+        assert_eq!(CanBus::from_bitrate(125_000), Some(CanBus::MediumSpeed));
+        // A single frame may be noise
+        assert_eq!(detect_bitrate(open(500_000, 1), window).unwrap(), None);
+    }
+}
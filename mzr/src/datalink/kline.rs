@@ -0,0 +1,13 @@
+/// K-line interface carrying KWP2000 frames. Frames are complete, including
+/// the header and checksum.
+pub trait KLine {
+    /// Generates the fast init wake-up pattern: the line is held low for
+    /// 25 ms, then released for 25 ms
+    fn wake_up(&mut self) -> Result<(), obd::Error>;
+
+    /// Sends a frame
+    fn send_frame(&mut self, frame: &[u8]) -> Result<(), obd::Error>;
+
+    /// Reads the next frame
+    fn read_frame(&mut self) -> Result<Vec<u8>, obd::Error>;
+}
@@ -0,0 +1,9 @@
+//! Data link backends used by the user-space ISO-TP and KWP2000 stacks
+
+pub mod can;
+pub mod kline;
+pub mod recovery;
+pub mod router;
+
+#[cfg(feature = "socketcan-datalink")]
+pub mod socketcan;
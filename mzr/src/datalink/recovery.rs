@@ -0,0 +1,190 @@
+//! CAN interface wrapper that re-initializes the interface when its
+//! controller goes bus-off
+
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::RwLock;
+use std::thread;
+use std::time::Duration;
+
+use super::can::{bus_off_error, is_bus_off, BusState, BusStatus, Can, Filter, Message};
+
+/// Number of attempts made to re-initialize the interface before giving up
+pub const RECOVERY_ATTEMPTS: usize = 5;
+/// Delay before the first attempt. It doubles after each failed attempt.
+pub const RECOVERY_DELAY: Duration = Duration::from_millis(100);
+
+/// CAN interface that is reopened when its controller goes bus-off, e.g.
+/// because of a marginal connection. Reads and sends failing with a bus-off
+/// error wait with exponential backoff, reopen the interface with the open
+/// function and retry once. Filters set on the interface are restored.
+///
+/// Recovery time isn't counted against read timeouts.
+pub struct BusOffRecovery<C, F> {
+    can: RwLock<C>,
+    open: F,
+    filters: RwLock<Vec<Filter>>,
+    recoveries: AtomicUsize,
+    /// Number of attempts made to reopen the interface
+    pub attempts: usize,
+    /// Delay before the first attempt
+    pub delay: Duration,
+}
+
+impl<C, F> BusOffRecovery<C, F>
+where
+    C: Can,
+    F: Fn() -> io::Result<C>,
+{
+    /// Opens the interface with `open`
+    pub fn new(open: F) -> io::Result<BusOffRecovery<C, F>> {
+        let can = open()?;
+        Ok(BusOffRecovery {
+            can: RwLock::new(can),
+            open,
+            filters: RwLock::new(Vec::new()),
+            recoveries: AtomicUsize::new(0),
+            attempts: RECOVERY_ATTEMPTS,
+            delay: RECOVERY_DELAY,
+        })
+    }
+
+    /// Returns the number of times the interface recovered from bus-off
+    pub fn recoveries(&self) -> usize {
+        self.recoveries.load(Ordering::Relaxed)
+    }
+
+    /// Reopens the interface with backoff until its controller is no longer
+    /// bus-off
+    fn recover(&self) -> io::Result<()> {
+        let mut can = self.can.write().unwrap();
+        // Another thread may have recovered while this one waited for the lock
+        if let Ok(Some(status)) = can.bus_status() {
+            if status.state != BusState::BusOff {
+                return Ok(());
+            }
+        }
+        let mut delay = self.delay;
+        let mut error = bus_off_error();
+        for _ in 0..self.attempts {
+            thread::sleep(delay);
+            delay *= 2;
+            match (self.open)().and_then(|new| {
+                new.set_filters(&self.filters.read().unwrap())?;
+                Ok(new)
+            }) {
+                Ok(new) => {
+                    *can = new;
+                    self.recoveries.fetch_add(1, Ordering::Relaxed);
+                    return Ok(());
+                }
+                Err(e) => error = e,
+            }
+        }
+        Err(error)
+    }
+}
+
+impl<C, F> Can for BusOffRecovery<C, F>
+where
+    C: Can,
+    F: Fn() -> io::Result<C>,
+{
+    fn send_msg(&self, msg: &Message) -> io::Result<()> {
+        let result = self.can.read().unwrap().send_msg(msg);
+        match result {
+            Err(e) if is_bus_off(&e) => {
+                self.recover()?;
+                self.can.read().unwrap().send_msg(msg)
+            }
+            result => result,
+        }
+    }
+
+    fn read(&self, timeout: Duration) -> io::Result<Message> {
+        let result = self.can.read().unwrap().read(timeout);
+        match result {
+            Err(e) if is_bus_off(&e) => {
+                self.recover()?;
+                self.can.read().unwrap().read(timeout)
+            }
+            result => result,
+        }
+    }
+
+    fn supports_fd(&self) -> bool {
+        self.can.read().unwrap().supports_fd()
+    }
+
+    fn set_filters(&self, filters: &[Filter]) -> io::Result<()> {
+        *self.filters.write().unwrap() = filters.to_vec();
+        self.can.read().unwrap().set_filters(filters)
+    }
+
+    fn bus_status(&self) -> io::Result<Option<BusStatus>> {
+        self.can.read().unwrap().bus_status()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    /// Interface whose controller goes bus-off after sending `frames` frames
+    struct Flaky {
+        frames: Cell<usize>,
+        filters: Cell<usize>,
+    }
+
+    impl Can for Flaky {
+        fn send_msg(&self, _msg: &Message) -> io::Result<()> {
+            match self.frames.get() {
+                0 => Err(bus_off_error()),
+                frames => {
+                    self.frames.set(frames - 1);
+                    Ok(())
+                }
+            }
+        }
+
+        fn read(&self, _timeout: Duration) -> io::Result<Message> {
+            Err(io::ErrorKind::TimedOut.into())
+        }
+
+        fn set_filters(&self, filters: &[Filter]) -> io::Result<()> {
+            self.filters.set(filters.len());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn recovers_from_bus_off() {
+        let opened = Cell::new(0);
+        let open = || {
+            opened.set(opened.get() + 1);
+            match opened.get() {
+                // The first reopen fails, e.g. while the bus is shorted
+                2 => Err(io::ErrorKind::NotConnected.into()),
+                _ => Ok(Flaky {
+                    frames: Cell::new(1),
+                    filters: Cell::new(0),
+                }),
+            }
+        };
+        let mut can = BusOffRecovery::new(open).unwrap();
+        can.delay = Duration::from_millis(1);
+        can.set_filters(&[Filter::exact(0x7E8, false)]).unwrap();
+
+        let msg = Message::new(0x7E0, &[0x3E, 0x00]);
+        can.send_msg(&msg).unwrap();
+        can.send_msg(&msg).unwrap();
+        assert_eq!(opened.get(), 3);
+        assert_eq!(can.recoveries(), 1);
+        assert_eq!(can.can.read().unwrap().filters.get(), 1);
+
+        // Gives up once every attempt failed
+        can.attempts = 0;
+        assert!(is_bus_off(&can.send_msg(&msg).unwrap_err()));
+    }
+}
@@ -0,0 +1,227 @@
+//! Background reader that routes received frames to connections by CAN ID,
+//! so several ISO-TP conversations (e.g. the ECU and the TCM) can share one
+//! adapter
+//!
+//! ```ignore
+//! let router = CanRouter::new(can);
+//! let ecu = IsotpCan::new(router.connect(0x7E8, false)?, 0x7E0, 0x7E8, timeout);
+//! let tcm = IsotpCan::new(router.connect(0x7E9, false)?, 0x7E1, 0x7E9, timeout);
+//! ```
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use super::can::{Can, Filter, Message};
+
+/// How often the reader checks whether the router was dropped
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Routes are keyed by CAN ID and whether it is a 29-bit identifier
+type Routes = HashMap<(u32, bool), Sender<Message>>;
+
+/// State shared by the router, its reader thread and its connections
+struct Shared<C> {
+    can: C,
+    routes: Mutex<Routes>,
+    stopped: AtomicBool,
+    /// Kind of the error that stopped the reader, if any
+    error: Mutex<Option<io::ErrorKind>>,
+}
+
+/// Owns a CAN interface and reads it on a background thread, forwarding
+/// each frame to the connection registered for its ID. Frames with no
+/// connection are dropped. The reader stops when the router is dropped or
+/// the interface fails.
+pub struct CanRouter<C: Can + Send + Sync + 'static> {
+    shared: Arc<Shared<C>>,
+    reader: Option<JoinHandle<()>>,
+}
+
+impl<C: Can + Send + Sync + 'static> CanRouter<C> {
+    /// Starts reading `can` on a background thread
+    pub fn new(can: C) -> CanRouter<C> {
+        let shared = Arc::new(Shared {
+            can,
+            routes: Mutex::new(HashMap::new()),
+            stopped: AtomicBool::new(false),
+            error: Mutex::new(None),
+        });
+        let reader = {
+            let shared = shared.clone();
+            thread::spawn(move || read_frames(&shared))
+        };
+        CanRouter {
+            shared,
+            reader: Some(reader),
+        }
+    }
+
+    /// Returns a connection receiving the frames sent with `id`. A previous
+    /// connection for the same ID stops receiving frames. The interface is
+    /// set to receive only the IDs of the connections.
+    pub fn connect(&self, id: u32, extended: bool) -> io::Result<RoutedCan<C>> {
+        let (tx, rx) = channel();
+        let mut routes = self.shared.routes.lock().unwrap();
+        routes.insert((id, extended), tx);
+        let filters: Vec<Filter> = routes
+            .keys()
+            .map(|&(id, extended)| Filter::exact(id, extended))
+            .collect();
+        self.shared.can.set_filters(&filters)?;
+        Ok(RoutedCan {
+            shared: self.shared.clone(),
+            rx,
+        })
+    }
+}
+
+impl<C: Can + Send + Sync + 'static> Drop for CanRouter<C> {
+    fn drop(&mut self) {
+        self.shared.stopped.store(true, Ordering::Relaxed);
+        if let Some(reader) = self.reader.take() {
+            let _ = reader.join();
+        }
+    }
+}
+
+fn read_frames<C: Can>(shared: &Shared<C>) {
+    while !shared.stopped.load(Ordering::Relaxed) {
+        let msg = match shared.can.read(POLL_INTERVAL) {
+            Ok(msg) => msg,
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+            Err(e) => {
+                *shared.error.lock().unwrap() = Some(e.kind());
+                break;
+            }
+        };
+        let mut routes = shared.routes.lock().unwrap();
+        let key = (msg.id, msg.extended);
+        if let Some(tx) = routes.get(&key) {
+            if tx.send(msg).is_err() {
+                // The connection was dropped
+                routes.remove(&key);
+            }
+        }
+    }
+    // Disconnects the connections so reads fail instead of timing out
+    shared.routes.lock().unwrap().clear();
+}
+
+/// Connection of a [`CanRouter`]. Reads return the frames routed to it;
+/// sends go straight to the interface. Filters are managed by the router,
+/// so [`Can::set_filters`] does nothing.
+pub struct RoutedCan<C> {
+    shared: Arc<Shared<C>>,
+    rx: Receiver<Message>,
+}
+
+impl<C: Can> Can for RoutedCan<C> {
+    fn send_msg(&self, msg: &Message) -> io::Result<()> {
+        self.shared.can.send_msg(msg)
+    }
+
+    fn read(&self, timeout: Duration) -> io::Result<Message> {
+        self.rx.recv_timeout(timeout).map_err(|e| match e {
+            RecvTimeoutError::Timeout => io::ErrorKind::TimedOut.into(),
+            RecvTimeoutError::Disconnected => io::Error::new(
+                self.shared
+                    .error
+                    .lock()
+                    .unwrap()
+                    .unwrap_or(io::ErrorKind::BrokenPipe),
+                "CAN reader stopped",
+            ),
+        })
+    }
+
+    fn supports_fd(&self) -> bool {
+        self.shared.can.supports_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::SyncSender;
+
+    /// Interface receiving the frames queued on `frames`
+    struct Queue {
+        frames: Mutex<Receiver<io::Result<Message>>>,
+        sent: Mutex<Vec<Message>>,
+        filters: Mutex<Vec<Filter>>,
+    }
+
+    impl Can for Queue {
+        fn send_msg(&self, msg: &Message) -> io::Result<()> {
+            self.sent.lock().unwrap().push(*msg);
+            Ok(())
+        }
+
+        fn set_filters(&self, filters: &[Filter]) -> io::Result<()> {
+            *self.filters.lock().unwrap() = filters.to_vec();
+            Ok(())
+        }
+
+        fn read(&self, timeout: Duration) -> io::Result<Message> {
+            match self.frames.lock().unwrap().recv_timeout(timeout) {
+                Ok(frame) => frame,
+                Err(_) => Err(io::ErrorKind::TimedOut.into()),
+            }
+        }
+    }
+
+    fn queue() -> (SyncSender<io::Result<Message>>, Queue) {
+        let (tx, rx) = std::sync::mpsc::sync_channel(16);
+        (
+            tx,
+            Queue {
+                frames: Mutex::new(rx),
+                sent: Mutex::new(Vec::new()),
+                filters: Mutex::new(Vec::new()),
+            },
+        )
+    }
+
+    #[test]
+    fn routes_frames_by_id() {
+        let (bus, can) = queue();
+        let router = CanRouter::new(can);
+        let ecu = router.connect(0x7E8, false).unwrap();
+        let tcm = router.connect(0x7E9, false).unwrap();
+        let filters = router.shared.can.filters.lock().unwrap().clone();
+        assert_eq!(filters.len(), 2);
+        assert!(filters.iter().any(|f| f.matches(&Message::new(0x7E9, &[]))));
+        assert!(!filters.iter().any(|f| f.matches(&Message::new(0x7EA, &[]))));
+        let timeout = Duration::from_secs(1);
+
+        bus.send(Ok(Message::new(0x7E9, &[2]))).unwrap();
+        bus.send(Ok(Message::new(0x123, &[0]))).unwrap();
+        bus.send(Ok(Message::new_extended(0x7E8, &[0]))).unwrap();
+        bus.send(Ok(Message::new(0x7E8, &[1]))).unwrap();
+        assert_eq!(ecu.read(timeout).unwrap().payload(), [1]);
+        assert_eq!(tcm.read(timeout).unwrap().payload(), [2]);
+        assert_eq!(
+            ecu.read(Duration::from_millis(20)).unwrap_err().kind(),
+            io::ErrorKind::TimedOut
+        );
+
+        tcm.send_msg(&Message::new(0x7E1, &[3])).unwrap();
+        assert_eq!(router.shared.can.sent.lock().unwrap()[0].id, 0x7E1);
+
+        // Interface failures reach every connection
+        bus.send(Err(io::ErrorKind::NotConnected.into())).unwrap();
+        assert_eq!(
+            ecu.read(timeout).unwrap_err().kind(),
+            io::ErrorKind::NotConnected
+        );
+        assert_eq!(
+            tcm.read(timeout).unwrap_err().kind(),
+            io::ErrorKind::NotConnected
+        );
+    }
+}
@@ -0,0 +1,185 @@
+use std::io;
+use std::process::Command;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use socketcan::{CANFilter, CANFrame, CANSocket, CANSocketOpenError, EFF_FLAG};
+
+use super::can::{self, bus_off_error, BusState, BusStatus, Can, Filter, Message, MAX_STANDARD_ID};
+
+/// Classes of Linux error frames, in the ID
+const CAN_ERR_CRTL: u32 = 0x004;
+const CAN_ERR_BUSOFF: u32 = 0x040;
+const CAN_ERR_RESTARTED: u32 = 0x100;
+const CAN_ERR_CNT: u32 = 0x200;
+
+/// Controller problems in byte 1 of CAN_ERR_CRTL frames
+const CAN_ERR_CRTL_PASSIVE: u8 = 0x30;
+const CAN_ERR_CRTL_ACTIVE: u8 = 0x40;
+
+/// Linux SocketCAN interface (including virtual `vcan` devices). The
+/// kernel restarts a bus-off controller if the interface was configured
+/// with `restart-ms`.
+pub struct SocketCan {
+    socket: CANSocket,
+    /// Error state from the last error frames
+    status: Mutex<BusStatus>,
+}
+
+impl SocketCan {
+    /// Opens the CAN interface named `ifname`, e.g. `vcan0`
+    pub fn open(ifname: &str) -> Result<SocketCan, CANSocketOpenError> {
+        let socket = CANSocket::open(ifname)?;
+        socket
+            .error_filter_accept_all()
+            .map_err(CANSocketOpenError::IOError)?;
+        Ok(SocketCan {
+            socket,
+            status: Mutex::new(BusStatus {
+                state: BusState::ErrorActive,
+                tx_errors: None,
+                rx_errors: None,
+            }),
+        })
+    }
+}
+
+/// Detects the bitrate of the bus on the interface `ifname`, listening for
+/// `window` at each bitrate with the controller in listen-only mode. The
+/// interface is reconfigured with `ip link`, which needs `CAP_NET_ADMIN`,
+/// and left up at the detected bitrate with listen-only mode off.
+pub fn detect_bitrate(ifname: &str, window: Duration) -> io::Result<Option<u32>> {
+    let detected = can::detect_bitrate(
+        |bitrate| {
+            configure(ifname, bitrate, true)?;
+            SocketCan::open(ifname).map_err(io::Error::other)
+        },
+        window,
+    )?;
+    if let Some(bitrate) = detected {
+        configure(ifname, bitrate, false)?;
+    }
+    Ok(detected)
+}
+
+/// Restarts `ifname` at `bitrate`, optionally in listen-only mode
+fn configure(ifname: &str, bitrate: u32, listen_only: bool) -> io::Result<()> {
+    let bitrate = bitrate.to_string();
+    let listen_only = if listen_only { "on" } else { "off" };
+    ip(&["link", "set", ifname, "down"])?;
+    ip(&[
+        "link",
+        "set",
+        ifname,
+        "type",
+        "can",
+        "bitrate",
+        &bitrate,
+        "listen-only",
+        listen_only,
+    ])?;
+    ip(&["link", "set", ifname, "up"])
+}
+
+fn ip(args: &[&str]) -> io::Result<()> {
+    let status = Command::new("ip").args(args).status()?;
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "ip {} failed ({})",
+            args.join(" "),
+            status
+        )));
+    }
+    Ok(())
+}
+
+/// Updates `status` with the error frame `frame`
+fn update_status(status: &mut BusStatus, frame: &CANFrame) {
+    let class = frame.err();
+    let data = frame.data();
+    if class & CAN_ERR_CRTL != 0 && data.len() > 1 {
+        if data[1] & CAN_ERR_CRTL_PASSIVE != 0 {
+            status.state = BusState::ErrorPassive;
+        } else if data[1] & CAN_ERR_CRTL_ACTIVE != 0 {
+            status.state = BusState::ErrorActive;
+        }
+    }
+    if class & CAN_ERR_RESTARTED != 0 {
+        status.state = BusState::ErrorActive;
+    }
+    if class & CAN_ERR_BUSOFF != 0 {
+        status.state = BusState::BusOff;
+    }
+    if class & CAN_ERR_CNT != 0 && data.len() == 8 {
+        status.tx_errors = Some(data[6]);
+        status.rx_errors = Some(data[7]);
+    }
+}
+
+impl Can for SocketCan {
+    fn send_msg(&self, msg: &Message) -> io::Result<()> {
+        // The socketcan crate sends IDs above 0x7FF as 29-bit identifiers
+        // and can't send smaller ones in the extended format
+        if msg.extended && msg.id <= MAX_STANDARD_ID {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "29-bit identifiers below 0x800 are not supported",
+            ));
+        }
+        let frame = CANFrame::new(msg.id, msg.payload(), false, false)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        self.socket.write_frame_insist(&frame)
+    }
+
+    fn read(&self, timeout: Duration) -> io::Result<Message> {
+        let start_time = Instant::now();
+        loop {
+            let remaining = timeout
+                .checked_sub(start_time.elapsed())
+                .filter(|remaining| *remaining > Duration::from_millis(0))
+                .ok_or(io::ErrorKind::TimedOut)?;
+            self.socket.set_read_timeout(remaining)?;
+            let frame = self.socket.read_frame().map_err(|e| match e.kind() {
+                io::ErrorKind::WouldBlock => io::Error::new(io::ErrorKind::TimedOut, e),
+                _ => e,
+            })?;
+            if frame.is_error() {
+                let mut status = self.status.lock().unwrap();
+                update_status(&mut status, &frame);
+                if status.state == BusState::BusOff {
+                    return Err(bus_off_error());
+                }
+                continue;
+            }
+            let message = Message::new(frame.id(), frame.data());
+            return Ok(Message {
+                extended: frame.is_extended(),
+                ..message
+            });
+        }
+    }
+
+    fn set_filters(&self, filters: &[Filter]) -> io::Result<()> {
+        if filters.is_empty() {
+            return self.socket.filter_accept_all();
+        }
+        let filters = filters
+            .iter()
+            .map(|filter| {
+                // The EFF flag in the mask makes the ID format part of the match
+                let id = if filter.extended {
+                    filter.id | EFF_FLAG
+                } else {
+                    filter.id
+                };
+                CANFilter::new(id, filter.mask | EFF_FLAG)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        self.socket.set_filter(&filters)
+    }
+
+    fn bus_status(&self) -> io::Result<Option<BusStatus>> {
+        Ok(Some(*self.status.lock().unwrap()))
+    }
+}
@@ -0,0 +1,94 @@
+//! Diagnostic trouble codes that can be stored and exchanged. Codes are
+//! serialized in their usual form, e.g. `P0301`.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+const SYSTEMS: [char; 4] = ['P', 'C', 'B', 'U'];
+
+#[derive(Error, Debug, PartialEq, Eq)]
+#[error("invalid trouble code '{0}'")]
+pub struct DtcError(String);
+
+/// Diagnostic trouble code, holding the two bytes the ECU reports
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Dtc(pub u16);
+
+impl fmt::Display for Dtc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}{:04X}",
+            SYSTEMS[(self.0 >> 14) as usize],
+            self.0 & 0x3FFF
+        )
+    }
+}
+
+impl FromStr for Dtc {
+    type Err = DtcError;
+
+    fn from_str(s: &str) -> Result<Dtc, DtcError> {
+        let invalid = || DtcError(s.to_string());
+        let mut chars = s.chars();
+        let system = chars.next().map(|c| c.to_ascii_uppercase());
+        let system = SYSTEMS
+            .iter()
+            .position(|&c| Some(c) == system)
+            .ok_or_else(invalid)?;
+        let digits = chars.as_str();
+        if digits.len() != 4 || !digits.starts_with(['0', '1', '2', '3']) {
+            return Err(invalid());
+        }
+        let code = u16::from_str_radix(digits, 16).map_err(|_| invalid())?;
+        Ok(Dtc((system as u16) << 14 | code))
+    }
+}
+
+impl TryFrom<String> for Dtc {
+    type Error = DtcError;
+
+    fn try_from(s: String) -> Result<Dtc, DtcError> {
+        s.parse()
+    }
+}
+
+impl From<Dtc> for String {
+    fn from(dtc: Dtc) -> String {
+        dtc.to_string()
+    }
+}
+
+impl From<&obd::DTC> for Dtc {
+    fn from(dtc: &obd::DTC) -> Dtc {
+        // The obd crate only exposes the text form, which is always valid
+        dtc.to_string().parse().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_serializes() {
+        let dtc: Dtc = "P0301".parse().unwrap();
+        assert_eq!(dtc, Dtc(0x0301));
+        assert_eq!("u3A0F".parse::<Dtc>().unwrap().to_string(), "U3A0F");
+        assert_eq!(Dtc(0x4123).to_string(), "C0123");
+        for invalid in ["", "X0301", "P4301", "P030", "P03011", "P03G1"] {
+            assert!(invalid.parse::<Dtc>().is_err(), "{}", invalid);
+        }
+
+        let json = serde_json::to_string(&[Dtc(0x0301), Dtc(0x8100)]).unwrap();
+        assert_eq!(json, r#"["P0301","B0100"]"#);
+        let codes: Vec<Dtc> = serde_json::from_str(&json).unwrap();
+        assert_eq!(codes, [Dtc(0x0301), Dtc(0x8100)]);
+        assert!(serde_json::from_str::<Dtc>(r#""P9999""#).is_err());
+    }
+}
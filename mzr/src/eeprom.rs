@@ -0,0 +1,182 @@
+//! Access to the configuration EEPROM, for cloning an ECU onto a
+//! replacement or repairing corrupted settings. The EEPROM is a memory space
+//! separate from the flash, mapped into the address space by the ECU as
+//! described by the [memory map](crate::memory_map::Eeprom). Writes go a
+//! page at a time, skip pages that are already identical and are read back.
+//! The caller enters the session and unlocks security access first.
+
+use obd::Uds;
+use thiserror::Error;
+
+use crate::memory_map::{Eeprom, Region};
+use crate::{MzrBus, MzrError};
+
+/// Largest read requested at once
+const MAX_TRANSFER: usize = 0x100;
+
+#[derive(Error, Debug)]
+pub enum EepromError {
+    #[error("image is {actual} bytes, but the EEPROM holds {expected}")]
+    SizeMismatch { expected: usize, actual: usize },
+    #[error(transparent)]
+    Mzr(#[from] MzrError),
+}
+
+/// Reads the whole EEPROM. `progress` is called with the number of bytes
+/// read so far.
+pub fn read<B, F>(
+    bus: &mut B,
+    request_id: u32,
+    eeprom: &Eeprom,
+    mut progress: F,
+) -> Result<Vec<u8>, MzrError>
+where
+    B: Uds,
+    F: FnMut(usize),
+{
+    let size = eeprom.size as usize;
+    let mut data = Vec::with_capacity(size);
+    while data.len() < size {
+        let length = (size - data.len()).min(MAX_TRANSFER);
+        let address = eeprom.base + data.len() as u32;
+        let chunk = bus.read_memory_address(request_id, address, length as u16)?;
+        if chunk.len() != length {
+            return Err(MzrError::InvalidResponse);
+        }
+        data.extend_from_slice(&chunk);
+        progress(data.len());
+    }
+    Ok(data)
+}
+
+/// Returns the pages whose contents differ between `current` and `image`
+pub fn changed_pages(eeprom: &Eeprom, current: &[u8], image: &[u8]) -> Vec<Region> {
+    eeprom
+        .pages()
+        .into_iter()
+        .filter(|page| {
+            let range = page.start as usize..page.end as usize;
+            current.get(range.clone()) != image.get(range)
+        })
+        .collect()
+}
+
+/// Writes `image` over the EEPROM currently holding `current`, one changed
+/// page at a time, and reads each page back. Refuses to write while the
+/// engine is running. `progress` is called with each page once it is
+/// verified. Returns the pages written.
+pub fn write<B, F>(
+    bus: &mut B,
+    request_id: u32,
+    eeprom: &Eeprom,
+    current: &[u8],
+    image: &[u8],
+    mut progress: F,
+) -> Result<Vec<Region>, EepromError>
+where
+    B: Uds,
+    F: FnMut(Region),
+{
+    let expected = eeprom.size as usize;
+    for actual in [current.len(), image.len()] {
+        if actual != expected {
+            return Err(EepromError::SizeMismatch { expected, actual });
+        }
+    }
+    let rpm = bus.engine_rpm(request_id)?;
+    if rpm > 0.0 {
+        return Err(MzrError::EngineRunning(rpm).into());
+    }
+
+    let pages = changed_pages(eeprom, current, image);
+    for page in &pages {
+        let data = &image[page.start as usize..page.end as usize];
+        let address = eeprom.base + page.start;
+        bus.write_memory(request_id, address, data)?;
+        let written = bus
+            .read_memory_address(request_id, address, data.len() as u16)
+            .map_err(MzrError::from)?;
+        if let Some(index) = (0..data.len()).find(|&i| written.get(i) != Some(&data[i])) {
+            return Err(MzrError::VerifyFailed(address + index as u32).into());
+        }
+        progress(*page);
+    }
+    Ok(pages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockEcu;
+
+    const EEPROM: Eeprom = Eeprom {
+        base: 0x0080_0000,
+        size: 0x200,
+        page_size: 0x80,
+    };
+
+    /// EEPROM behind the memory services, recording the addresses written
+    struct Memory {
+        data: Vec<u8>,
+        writes: Vec<u32>,
+    }
+
+    fn offset(address: &[u8]) -> usize {
+        (u32::from_be_bytes([address[0], address[1], address[2], address[3]]) - EEPROM.base)
+            as usize
+    }
+
+    fn answer(memory: &mut Memory, request: &[u8]) -> Option<Vec<u8>> {
+        Some(match request {
+            [0x23, address @ .., high, low] => {
+                let offset = offset(address);
+                let length = u16::from_be_bytes([*high, *low]) as usize;
+                [&[0x63][..], &memory.data[offset..offset + length]].concat()
+            }
+            [0x3D, rest @ ..] => {
+                let offset = offset(&rest[..4]);
+                let data = &rest[6..];
+                memory.data[offset..offset + data.len()].copy_from_slice(data);
+                memory.writes.push(EEPROM.base + offset as u32);
+                vec![0x7D]
+            }
+            _ => return None,
+        })
+    }
+
+    #[test]
+    fn writes_changed_pages() {
+        let memory = Memory {
+            data: (0..0x200).map(|i| i as u8).collect(),
+            writes: Vec::new(),
+        };
+        let mut ecu = MockEcu::new(memory, answer);
+        let mut read_so_far = Vec::new();
+        let current = read(&mut ecu, 0x7E0, &EEPROM, |n| read_so_far.push(n)).unwrap();
+        assert_eq!(current, ecu.state.data);
+        assert_eq!(read_so_far, [0x100, 0x200]);
+
+        let mut image = current.clone();
+        image[0x90] = 0xAA;
+        image[0x1FF] = 0x55;
+        let written = write(&mut ecu, 0x7E0, &EEPROM, &current, &image, |_| {}).unwrap();
+        assert_eq!(
+            written,
+            [Region::new(0x80, 0x100), Region::new(0x180, 0x200)]
+        );
+        assert_eq!(ecu.state.writes, [0x0080_0080, 0x0080_0180]);
+        assert_eq!(ecu.state.data, image);
+
+        // Refused with the engine running or an image of the wrong size
+        ecu.rpm = 750;
+        assert!(matches!(
+            write(&mut ecu, 0x7E0, &EEPROM, &image, &current, |_| {}),
+            Err(EepromError::Mzr(MzrError::EngineRunning(_)))
+        ));
+        assert!(matches!(
+            write(&mut ecu, 0x7E0, &EEPROM, &image, &image[..0x100], |_| {}),
+            Err(EepromError::SizeMismatch { actual: 0x100, .. })
+        ));
+        assert_eq!(ecu.state.writes.len(), 2);
+    }
+}
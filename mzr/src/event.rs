@@ -0,0 +1,119 @@
+//! Typed status events emitted by [`Downloader`](crate::Downloader) and
+//! [`Programmer`](crate::Programmer), so frontends can follow an operation
+//! without polling.
+
+use std::sync::mpsc::Sender;
+
+use serde::{Deserialize, Serialize};
+
+/// Serialized tagged with its snake_case name, e.g.
+/// `{"event":"low_voltage","voltage":11.5}`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// The ECU entered the requested diagnostic session
+    Connected { session: u8 },
+    /// Security access was granted
+    Authenticated,
+    /// A RAM kernel was uploaded and answered at `address`
+    KernelStarted { address: u32 },
+    /// Flash erase was requested
+    EraseStarted,
+    /// Flash erase completed
+    EraseCompleted,
+    /// A block was read from or written to the ECU
+    BlockTransferred {
+        /// Index of the transferred block, starting from 1
+        block: usize,
+        /// Total number of blocks
+        blocks: usize,
+        /// Bytes transferred so far
+        position: usize,
+        /// Total bytes to transfer
+        total: usize,
+    },
+    /// The session lapsed and was re-established. The transfer continues
+    /// from `offset`.
+    SessionRecovered { offset: usize },
+    /// The operation was paused between blocks
+    Paused,
+    /// A paused operation resumed
+    Resumed,
+    /// Battery voltage dropped below the minimum. Programming is paused
+    /// until it recovers.
+    LowVoltage { voltage: f32 },
+    /// Battery voltage recovered and programming continues
+    VoltageRecovered { voltage: f32 },
+    /// All data has been transferred
+    Completed,
+    /// The programmed image, or a sample of the downloaded blocks, was read
+    /// back and matches
+    Verified,
+    /// The ECU's checksum routine accepted the programmed flash
+    ChecksumsAccepted,
+}
+
+/// Receives events from an operation
+pub trait EventSink {
+    fn send(&mut self, event: Event);
+}
+
+impl<F: FnMut(Event)> EventSink for F {
+    fn send(&mut self, event: Event) {
+        self(event)
+    }
+}
+
+impl EventSink for Sender<Event> {
+    fn send(&mut self, event: Event) {
+        // The receiver hanging up must not abort the operation
+        let _ = Sender::send(self, event);
+    }
+}
+
+/// Optional event sink owned by an operation
+pub(crate) struct Events<'a> {
+    sink: Option<Box<dyn EventSink + 'a>>,
+}
+
+impl<'a> Events<'a> {
+    pub fn new() -> Events<'a> {
+        Events { sink: None }
+    }
+
+    pub fn set<S: EventSink + 'a>(&mut self, sink: S) {
+        self.sink = Some(Box::new(sink));
+    }
+
+    pub fn emit(&mut self, event: Event) {
+        if let Some(sink) = self.sink.as_mut() {
+            sink.send(event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serializes_tagged() {
+        let events = [
+            Event::Connected { session: 0x85 },
+            Event::EraseStarted,
+            Event::BlockTransferred {
+                block: 2,
+                blocks: 4,
+                position: 2048,
+                total: 4096,
+            },
+        ];
+        let json = serde_json::to_string(&events).unwrap();
+        assert_eq!(
+            json,
+            r#"[{"event":"connected","session":133},{"event":"erase_started"},{"event":"block_transferred","block":2,"blocks":4,"position":2048,"total":4096}]"#
+        );
+        let parsed: Vec<Event> = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, events);
+    }
+}
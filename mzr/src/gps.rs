@@ -0,0 +1,374 @@
+//! Position and speed from a GPS receiver speaking NMEA 0183, merged into
+//! log records so track logs can be overlaid on maps. Opening serial ports
+//! is behind the `gps` feature; any other reader of NMEA text works without
+//! it.
+//!
+//! Fixes are stamped with the local clock when they are received rather
+//! than with the UTC time they report, since loggers without a real-time
+//! clock or network often run with the wrong time. Records are then given
+//! the position interpolated between the fixes before and after them.
+//!
+//! ```toml
+//! [gps]
+//! device = "/dev/ttyACM0"
+//! baud_rate = 9600
+//! ```
+
+use std::collections::VecDeque;
+use std::io::{self, BufRead};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::metadata::LogMetadata;
+use crate::record::{Record, RecordSink};
+
+/// Speed in km/h of one knot
+const KMH_PER_KNOT: f64 = 1.852;
+
+/// Fixes kept for aligning records
+const FIX_HISTORY: usize = 64;
+
+/// Longest time records are held waiting for the next fix
+pub const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(2);
+
+/// Serial port of the receiver
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GpsConfig {
+    /// Port name, e.g. `/dev/ttyACM0` or `COM3`
+    pub device: String,
+    pub baud_rate: u32,
+}
+
+impl Default for GpsConfig {
+    fn default() -> GpsConfig {
+        GpsConfig {
+            device: "/dev/ttyACM0".to_string(),
+            baud_rate: 9600,
+        }
+    }
+}
+
+/// Position reported by the receiver
+#[derive(Debug, Copy, Clone, PartialEq, Serialize)]
+pub struct Fix {
+    /// Local Unix time in milliseconds at which the fix was received
+    pub time: u64,
+    /// Degrees, positive north
+    pub latitude: f64,
+    /// Degrees, positive east
+    pub longitude: f64,
+    /// Ground speed in km/h
+    pub speed: f64,
+    /// Degrees clockwise from true north
+    pub course: Option<f64>,
+    /// Meters above mean sea level
+    pub altitude: Option<f64>,
+}
+
+impl Fix {
+    /// Returns the fix linearly interpolated between `self` and `next` at
+    /// `time`
+    fn interpolate(&self, next: &Fix, time: u64) -> Fix {
+        if next.time <= self.time {
+            return *next;
+        }
+        let t = (time.saturating_sub(self.time)) as f64 / (next.time - self.time) as f64;
+        let lerp = |a: f64, b: f64| a + (b - a) * t;
+        Fix {
+            time,
+            latitude: lerp(self.latitude, next.latitude),
+            longitude: lerp(self.longitude, next.longitude),
+            speed: lerp(self.speed, next.speed),
+            // Courses wrap around, so the nearest is kept
+            course: if t < 0.5 { self.course } else { next.course },
+            altitude: match (self.altitude, next.altitude) {
+                (Some(a), Some(b)) => Some(lerp(a, b)),
+                (a, b) => b.or(a),
+            },
+        }
+    }
+
+    /// Adds the position to the values of `record`
+    pub fn merge_into(&self, record: &mut Record) {
+        let values = &mut record.values;
+        values.insert("latitude".to_string(), self.latitude);
+        values.insert("longitude".to_string(), self.longitude);
+        values.insert("gps_speed".to_string(), self.speed);
+        if let Some(course) = self.course {
+            values.insert("course".to_string(), course);
+        }
+        if let Some(altitude) = self.altitude {
+            values.insert("altitude".to_string(), altitude);
+        }
+    }
+}
+
+/// Returns the fields of an NMEA sentence after its address, e.g. `GPRMC`,
+/// if the checksum is valid
+fn fields(line: &str) -> Option<(&str, Vec<&str>)> {
+    let line = line.trim().strip_prefix('$')?;
+    let (body, checksum) = line.split_once('*')?;
+    let checksum = u8::from_str_radix(checksum, 16).ok()?;
+    if body.bytes().fold(0, |sum, b| sum ^ b) != checksum {
+        return None;
+    }
+    let mut fields = body.split(',');
+    let address = fields.next()?;
+    Some((address, fields.collect()))
+}
+
+/// Parses a coordinate in `dddmm.mmmm` format with its hemisphere
+fn coordinate(value: &str, hemisphere: &str) -> Option<f64> {
+    let value: f64 = value.parse().ok()?;
+    let degrees = (value / 100.0).trunc();
+    let degrees = degrees + (value - degrees * 100.0) / 60.0;
+    match hemisphere {
+        "N" | "E" => Some(degrees),
+        "S" | "W" => Some(-degrees),
+        _ => None,
+    }
+}
+
+/// Builds fixes from RMC sentences, with the altitude of the latest GGA
+/// sentence. Sentences from any talker (GPS, GLONASS, combined) are used.
+#[derive(Debug, Default)]
+pub struct NmeaParser {
+    altitude: Option<f64>,
+}
+
+impl NmeaParser {
+    pub fn new() -> NmeaParser {
+        NmeaParser::default()
+    }
+
+    /// Parses a sentence received at local Unix time `time` in
+    /// milliseconds. Returns a fix for each valid RMC sentence.
+    pub fn parse(&mut self, line: &str, time: u64) -> Option<Fix> {
+        let (address, fields) = fields(line)?;
+        match address.get(2..)? {
+            "GGA" => {
+                // Fix quality 0 is no fix
+                let valid = fields.get(5).is_some_and(|quality| *quality != "0");
+                self.altitude = fields
+                    .get(8)
+                    .filter(|_| valid)
+                    .and_then(|altitude| altitude.parse().ok());
+                None
+            }
+            "RMC" if fields.get(1) == Some(&"A") => Some(Fix {
+                time,
+                latitude: coordinate(fields.get(2)?, fields.get(3)?)?,
+                longitude: coordinate(fields.get(4)?, fields.get(5)?)?,
+                speed: fields.get(6)?.parse::<f64>().ok()? * KMH_PER_KNOT,
+                course: fields.get(7).and_then(|course| course.parse().ok()),
+                altitude: self.altitude,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the current Unix time in milliseconds
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_millis() as u64)
+}
+
+/// Reads NMEA sentences on a background thread and keeps the recent fixes.
+/// Clones share the same fixes.
+#[derive(Clone, Default)]
+pub struct GpsReceiver {
+    fixes: Arc<Mutex<VecDeque<Fix>>>,
+}
+
+impl GpsReceiver {
+    /// Starts reading `reader` until it ends or fails. Read timeouts, as
+    /// reported by serial ports, are retried.
+    pub fn start<R: BufRead + Send + 'static>(mut reader: R) -> GpsReceiver {
+        let receiver = GpsReceiver::default();
+        let fixes = receiver.clone();
+        thread::spawn(move || {
+            let mut parser = NmeaParser::new();
+            let mut line = Vec::new();
+            loop {
+                match reader.read_until(b'\n', &mut line) {
+                    Ok(0) => break,
+                    Ok(_) => {
+                        // Receivers emit noise while starting up
+                        let text = String::from_utf8_lossy(&line);
+                        if let Some(fix) = parser.parse(&text, now()) {
+                            fixes.push(fix);
+                        }
+                        line.clear();
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::TimedOut => {}
+                    Err(err) => {
+                        tracing::warn!(%err, "GPS receiver failed");
+                        break;
+                    }
+                }
+            }
+        });
+        receiver
+    }
+
+    /// Opens the receiver on the configured serial port
+    #[cfg(feature = "gps")]
+    pub fn open(config: &GpsConfig) -> io::Result<GpsReceiver> {
+        let port = serialport::new(&config.device, config.baud_rate)
+            .timeout(Duration::from_secs(1))
+            .open()?;
+        Ok(GpsReceiver::start(io::BufReader::new(port)))
+    }
+
+    /// Adds a fix, forgetting the oldest one if the history is full
+    pub fn push(&self, fix: Fix) {
+        let mut fixes = self.fixes.lock().unwrap();
+        if fixes.len() == FIX_HISTORY {
+            fixes.pop_front();
+        }
+        fixes.push_back(fix);
+    }
+
+    /// Returns the recent fixes, oldest first
+    pub fn fixes(&self) -> Vec<Fix> {
+        self.fixes.lock().unwrap().iter().copied().collect()
+    }
+}
+
+/// Position at local time `time` from `fixes`, oldest first
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Alignment {
+    /// Interpolated between the fixes around `time`
+    Found(Fix),
+    /// No fix after `time` has arrived yet
+    Pending,
+    /// No fix within `max_gap` of `time`
+    Lost,
+}
+
+fn align(fixes: &[Fix], time: u64, max_gap: u64) -> Alignment {
+    let before = fixes.iter().rev().find(|fix| fix.time <= time);
+    let after = fixes.iter().find(|fix| fix.time >= time);
+    match (before, after) {
+        (Some(before), Some(after)) if after.time - before.time <= max_gap => {
+            Alignment::Found(before.interpolate(after, time))
+        }
+        (Some(before), None) if time - before.time <= max_gap => Alignment::Pending,
+        (None, None) => Alignment::Pending,
+        _ => Alignment::Lost,
+    }
+}
+
+/// Adds the position at the time of each record and passes the records on
+/// to `sink`. Records are held until the following fix arrives, or for at
+/// most `max_delay` if the receiver lost its fix.
+pub struct GpsMerger<S> {
+    receiver: GpsReceiver,
+    sink: S,
+    pending: VecDeque<Record>,
+    pub max_delay: Duration,
+}
+
+impl<S: RecordSink> GpsMerger<S> {
+    pub fn new(receiver: GpsReceiver, sink: S) -> GpsMerger<S> {
+        GpsMerger {
+            receiver,
+            sink,
+            pending: VecDeque::new(),
+            max_delay: DEFAULT_MAX_DELAY,
+        }
+    }
+
+    /// Passes on the records that can be aligned, or all of them if
+    /// `force` is set
+    fn forward(&mut self, force: bool) {
+        let fixes = self.receiver.fixes();
+        let max_delay = self.max_delay.as_millis() as u64;
+        let now = now();
+        while let Some(record) = self.pending.front() {
+            let expired = now.saturating_sub(record.time) >= max_delay;
+            let fix = match align(&fixes, record.time, max_delay) {
+                Alignment::Found(fix) => Some(fix),
+                Alignment::Pending if !force && !expired => break,
+                // Positions aren't extrapolated
+                Alignment::Pending | Alignment::Lost => None,
+            };
+            let mut record = self.pending.pop_front().unwrap();
+            if let Some(fix) = fix {
+                fix.merge_into(&mut record);
+            }
+            self.sink.send(&record);
+        }
+    }
+}
+
+impl<S: RecordSink> RecordSink for GpsMerger<S> {
+    fn send(&mut self, record: &Record) {
+        self.pending.push_back(record.clone());
+        self.forward(false);
+    }
+
+    fn begin_session(&mut self, metadata: &LogMetadata) {
+        // Records of the previous session go first
+        self.forward(true);
+        self.sink.begin_session(metadata);
+    }
+
+    fn flush(&mut self) {
+        self.forward(true);
+        self.sink.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_aligns_fixes() {
+        let mut parser = NmeaParser::new();
+        let gga = "$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47";
+        assert_eq!(parser.parse(gga, 0), None);
+        let rmc = "$GPRMC,123519,A,4807.038,N,01131.000,E,022.4,084.4,230394,003.1,W*6A";
+        let first = parser.parse(rmc, 1000).unwrap();
+        assert!((first.latitude - 48.1173).abs() < 1e-4);
+        assert!((first.longitude - 11.516_667).abs() < 1e-4);
+        assert!((first.speed - 41.4848).abs() < 1e-4);
+        assert_eq!(first.course, Some(84.4));
+        assert_eq!(first.altitude, Some(545.4));
+        // Bad checksum and void fix
+        assert_eq!(parser.parse(&rmc.replace("*6A", "*00"), 0), None);
+        let void = "$GPRMC,123519,V,,,,,,,230394,,*33";
+        assert_eq!(fields(void).map(|(address, _)| address), Some("GPRMC"));
+        assert_eq!(parser.parse(void, 0), None);
+
+        let second = Fix {
+            time: 2000,
+            latitude: first.latitude + 0.001,
+            speed: first.speed + 10.0,
+            ..first
+        };
+        let fixes = [first, second];
+        match align(&fixes, 1250, 2000) {
+            Alignment::Found(fix) => {
+                assert!((fix.latitude - (first.latitude + 0.00025)).abs() < 1e-9);
+                assert!((fix.speed - (first.speed + 2.5)).abs() < 1e-9);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(align(&fixes, 2500, 2000), Alignment::Pending);
+        assert_eq!(align(&fixes, 5000, 2000), Alignment::Lost);
+        assert_eq!(align(&[], 5000, 2000), Alignment::Pending);
+
+        let mut record = Record::new();
+        second.merge_into(&mut record);
+        assert_eq!(record.values["gps_speed"], second.speed);
+        assert_eq!(record.values["altitude"], 545.4);
+    }
+}
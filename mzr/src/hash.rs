@@ -0,0 +1,42 @@
+//! SHA-256 hashing of ROM images, so an image can be checked against a
+//! known-good hash before it is flashed
+
+use sha2::{Digest, Sha256};
+
+use crate::MzrError;
+
+/// Returns the SHA-256 hash of `data` as lowercase hex
+pub fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Checks that the SHA-256 hash of `data` matches `expected`, given in hex.
+/// Case and surrounding whitespace are ignored.
+pub fn verify_sha256(data: &[u8], expected: &str) -> Result<(), MzrError> {
+    let actual = sha256_hex(data);
+    let expected = expected.trim().to_ascii_lowercase();
+    if actual == expected {
+        Ok(())
+    } else {
+        Err(MzrError::HashMismatch { expected, actual })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_and_verifies() {
+        let hash = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        assert_eq!(sha256_hex(b"abc"), hash);
+        assert!(verify_sha256(b"abc", &hash.to_ascii_uppercase()).is_ok());
+        assert!(matches!(
+            verify_sha256(b"abd", hash),
+            Err(MzrError::HashMismatch { .. })
+        ));
+    }
+}
@@ -0,0 +1,162 @@
+//! Local log of flash operations. Each flash is appended as a line of JSON
+//! to `history.jsonl` in the configuration directory, so past flashes of a
+//! vehicle can be looked up later.
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+/// Outcome of a flash
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FlashResult {
+    Completed,
+    Cancelled,
+    Failed,
+}
+
+/// A single flash operation
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FlashRecord {
+    /// Time of the flash, in seconds since the Unix epoch
+    pub timestamp: u64,
+    /// VIN of the flashed vehicle, if it could be read
+    pub vin: Option<String>,
+    /// Path of the flashed file
+    pub source: PathBuf,
+    /// SHA-256 hash of the flashed file
+    pub sha256: String,
+    /// Calibration ID reported by the ECU before flashing
+    pub calibration_id: Option<String>,
+    pub result: FlashResult,
+    /// Error message of a failed flash
+    pub error: Option<String>,
+}
+
+impl FlashRecord {
+    /// Creates a record of a flash finishing now
+    pub fn new(source: &Path, sha256: &str, result: FlashResult) -> FlashRecord {
+        FlashRecord {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            vin: None,
+            source: source.to_owned(),
+            sha256: sha256.to_string(),
+            calibration_id: None,
+            result,
+            error: None,
+        }
+    }
+}
+
+/// Flash history
+#[derive(Debug, Clone, Default)]
+pub struct History {
+    records: Vec<FlashRecord>,
+}
+
+impl History {
+    /// Returns the default history file path
+    pub fn path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("mzr").join("history.jsonl"))
+    }
+
+    /// Loads the history from the default path. Returns an empty history
+    /// if the file does not exist.
+    pub fn load() -> io::Result<History> {
+        match History::path() {
+            Some(path) => History::load_from(path),
+            None => Ok(History::default()),
+        }
+    }
+
+    /// Loads the history from `path`. Returns an empty history if the file
+    /// does not exist.
+    pub fn load_from<P: AsRef<Path>>(path: P) -> io::Result<History> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(History::default()),
+            Err(err) => return Err(err),
+        };
+        // A line cut short by a crash is skipped rather than hiding the
+        // rest of the history
+        let records = contents
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect();
+        Ok(History { records })
+    }
+
+    /// Appends `record` to the history at the default path
+    pub fn append(record: &FlashRecord) -> io::Result<()> {
+        let path = History::path().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                "no configuration directory available on this platform",
+            )
+        })?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        History::append_to(path, record)
+    }
+
+    /// Appends `record` to the history at `path`
+    pub fn append_to<P: AsRef<Path>>(path: P, record: &FlashRecord) -> io::Result<()> {
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        file.write_all(line.as_bytes())
+    }
+
+    /// Returns all records, oldest first
+    pub fn records(&self) -> &[FlashRecord] {
+        &self.records
+    }
+
+    /// Returns the records of flashes to the vehicle with `vin`, oldest first
+    pub fn for_vin<'a>(&'a self, vin: &'a str) -> impl Iterator<Item = &'a FlashRecord> + 'a {
+        self.records
+            .iter()
+            .filter(move |record| record.vin.as_deref() == Some(vin))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn append_and_filter() {
+        let path = std::env::temp_dir().join(format!("mzr-history-{}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut first = FlashRecord::new(Path::new("a.bin"), "00", FlashResult::Completed);
+        first.vin = Some("JM1BK34M071234567".to_string());
+        let second = FlashRecord {
+            error: Some("engine is running".to_string()),
+            ..FlashRecord::new(Path::new("b.bin"), "11", FlashResult::Failed)
+        };
+        History::append_to(&path, &first).unwrap();
+        History::append_to(&path, &second).unwrap();
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"{\"timestamp\":")
+            .unwrap();
+
+        let history = History::load_from(&path).unwrap();
+        assert_eq!(history.records(), &[first.clone(), second]);
+        assert_eq!(
+            history.for_vin("JM1BK34M071234567").collect::<Vec<_>>(),
+            vec![&first]
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,447 @@
+//! Loading flash images. Raw binaries are full ROM dumps; Intel HEX and
+//! Motorola S-record files carry their own load addresses.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use thiserror::Error;
+
+use crate::container::{self, ContainerError, RomContainer};
+use crate::memory_map::{MzrMemoryMap, Region};
+
+/// Value of erased flash, used to fill gaps between records
+const ERASED: u8 = 0xFF;
+
+#[derive(Error, Debug)]
+pub enum ImageError {
+    #[error("failed to read image: {0}")]
+    Io(#[from] io::Error),
+    #[error("line {line}: {reason}")]
+    Parse { line: usize, reason: &'static str },
+    #[error("record at 0x{0:X} is outside the flashable region")]
+    OutOfRange(u32),
+    #[error("image was built for memory map {0}")]
+    WrongMemoryMap(String),
+    #[error("image is too small to contain the flashable region")]
+    TooSmall,
+    #[error("image contains no data")]
+    Empty,
+    #[error("{0}")]
+    Container(#[from] ContainerError),
+}
+
+/// File format of a flash image
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ImageFormat {
+    /// Raw dump of the entire flash
+    Bin,
+    /// Intel HEX
+    Ihex,
+    /// Motorola S-record
+    Srec,
+    /// `.mzrrom` container
+    Container,
+}
+
+impl ImageFormat {
+    /// Guesses the format from the file extension
+    pub fn from_path(path: &Path) -> ImageFormat {
+        let ext = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(str::to_ascii_lowercase);
+        match ext.as_deref() {
+            Some("hex") | Some("ihex") | Some("ihx") => ImageFormat::Ihex,
+            Some("srec") | Some("s19") | Some("s28") | Some("s37") | Some("mot") => {
+                ImageFormat::Srec
+            }
+            Some(container::EXTENSION) => ImageFormat::Container,
+            _ => ImageFormat::Bin,
+        }
+    }
+
+    /// Returns the usual file extension for the format
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Bin => "bin",
+            ImageFormat::Ihex => "hex",
+            ImageFormat::Srec => "srec",
+            ImageFormat::Container => container::EXTENSION,
+        }
+    }
+}
+
+impl FromStr for ImageFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ImageFormat, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "bin" => Ok(ImageFormat::Bin),
+            "ihex" | "hex" => Ok(ImageFormat::Ihex),
+            "srec" => Ok(ImageFormat::Srec),
+            container::EXTENSION => Ok(ImageFormat::Container),
+            _ => Err(format!("unknown image format '{}'", s)),
+        }
+    }
+}
+
+/// Contiguous data to be written to flash at `offset`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    pub offset: u32,
+    pub data: Vec<u8>,
+}
+
+impl Image {
+    /// Loads the image at `path`, choosing the parser from the extension.
+    /// Only the programmable region of `map` may be covered.
+    pub fn load(path: &Path, map: &MzrMemoryMap) -> Result<Image, ImageError> {
+        Image::parse(&fs::read(path)?, ImageFormat::from_path(path), map)
+    }
+
+    /// Parses the contents of an image file. Containers are recognized
+    /// whatever `format` says.
+    pub fn parse(
+        data: &[u8],
+        format: ImageFormat,
+        map: &MzrMemoryMap,
+    ) -> Result<Image, ImageError> {
+        if RomContainer::is_container(data) {
+            return Image::from_container(RomContainer::read(data)?, map);
+        }
+        match format {
+            ImageFormat::Bin => Image::from_bin(data, map),
+            ImageFormat::Ihex => Image::from_ihex(&String::from_utf8_lossy(data), map),
+            ImageFormat::Srec => Image::from_srec(&String::from_utf8_lossy(data), map),
+            ImageFormat::Container => Err(ContainerError::BadMagic.into()),
+        }
+    }
+
+    /// Takes the flashable part of a container's image. The container must
+    /// have been built for `map`.
+    pub fn from_container(
+        container: RomContainer,
+        map: &MzrMemoryMap,
+    ) -> Result<Image, ImageError> {
+        if container.header.memory_map != map.name {
+            return Err(ImageError::WrongMemoryMap(container.header.memory_map));
+        }
+        let offset = container.header.offset;
+        if offset == map.flash().start {
+            return Image::from_bin(&container.data, map);
+        }
+        if !map
+            .programmable()
+            .contains_range(offset, container.data.len())
+        {
+            return Err(ImageError::OutOfRange(offset));
+        }
+        Ok(Image {
+            offset,
+            data: container.data,
+        })
+    }
+
+    /// Takes the programmable region of a full ROM dump
+    pub fn from_bin(data: &[u8], map: &MzrMemoryMap) -> Result<Image, ImageError> {
+        let region = map.programmable();
+        let data = data
+            .get((region.start - map.flash().start) as usize..)
+            .filter(|data| !data.is_empty())
+            .ok_or(ImageError::TooSmall)?;
+        if data.len() > region.len() {
+            return Err(ImageError::OutOfRange(region.end));
+        }
+        Ok(Image {
+            offset: region.start,
+            data: data.to_vec(),
+        })
+    }
+
+    /// Parses an Intel HEX file
+    pub fn from_ihex(text: &str, map: &MzrMemoryMap) -> Result<Image, ImageError> {
+        let mut records = Records::new(map.programmable());
+        let mut base = 0u32;
+        for (i, line) in lines(text) {
+            let bytes = parse_record(line.strip_prefix(':'), i)?;
+            let (&count, rest) = bytes.split_first().ok_or(parse_error(i, "truncated"))?;
+            if rest.len() != count as usize + 4 {
+                return Err(parse_error(i, "length mismatch"));
+            }
+            if bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) != 0 {
+                return Err(parse_error(i, "bad checksum"));
+            }
+            let address = u16::from_be_bytes([rest[0], rest[1]]) as u32;
+            let data = &rest[3..3 + count as usize];
+            match rest[2] {
+                0x00 => records.insert(base.wrapping_add(address), data, i)?,
+                0x01 => break,
+                0x02 if count == 2 => base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 4,
+                0x04 if count == 2 => base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 16,
+                // Start addresses don't matter when flashing
+                0x03 | 0x05 => (),
+                _ => return Err(parse_error(i, "unsupported record type")),
+            }
+        }
+        records.into_image()
+    }
+
+    /// Parses a Motorola S-record file
+    pub fn from_srec(text: &str, map: &MzrMemoryMap) -> Result<Image, ImageError> {
+        let mut records = Records::new(map.programmable());
+        for (i, line) in lines(text) {
+            let mut chars = line.chars();
+            if chars.next() != Some('S') {
+                return Err(parse_error(i, "missing 'S'"));
+            }
+            let kind = chars.next().ok_or(parse_error(i, "truncated"))?;
+            let bytes = parse_record(Some(chars.as_str()), i)?;
+            let (&count, rest) = bytes.split_first().ok_or(parse_error(i, "truncated"))?;
+            if rest.len() != count as usize || rest.is_empty() {
+                return Err(parse_error(i, "length mismatch"));
+            }
+            if bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) != 0xFF {
+                return Err(parse_error(i, "bad checksum"));
+            }
+            let address_len = match kind {
+                '1' => 2,
+                '2' => 3,
+                '3' => 4,
+                // Header, record counts and start addresses
+                '0' | '5' | '6' | '7' | '8' | '9' => continue,
+                _ => return Err(parse_error(i, "unsupported record type")),
+            };
+            if rest.len() < address_len + 1 {
+                return Err(parse_error(i, "truncated"));
+            }
+            let address = rest[..address_len]
+                .iter()
+                .fold(0u32, |a, &b| (a << 8) | b as u32);
+            records.insert(address, &rest[address_len..rest.len() - 1], i)?;
+        }
+        records.into_image()
+    }
+}
+
+/// Bytes per Intel HEX data record
+const IHEX_RECORD_SIZE: usize = 16;
+/// Bytes per S-record data record
+const SREC_RECORD_SIZE: usize = 32;
+
+impl Image {
+    /// Encodes the image of an ECU with layout `map` in `format`. Raw
+    /// binaries don't record the offset.
+    pub fn encode(&self, format: ImageFormat, map: &MzrMemoryMap) -> Vec<u8> {
+        match format {
+            ImageFormat::Bin => self.data.clone(),
+            ImageFormat::Ihex => self.to_ihex().into_bytes(),
+            ImageFormat::Srec => self.to_srec().into_bytes(),
+            ImageFormat::Container => self.to_container(map).write(),
+        }
+    }
+
+    /// Wraps the image in a container with a default header
+    pub fn to_container(&self, map: &MzrMemoryMap) -> RomContainer {
+        let mut container = RomContainer::new(self.data.clone());
+        container.header.memory_map = map.name.clone();
+        container.header.offset = self.offset;
+        container
+    }
+
+    /// Encodes the image as Intel HEX, using extended linear address records
+    pub fn to_ihex(&self) -> String {
+        let mut out = String::new();
+        let mut upper = None;
+        for (i, chunk) in self.data.chunks(IHEX_RECORD_SIZE).enumerate() {
+            let address = self.offset + (i * IHEX_RECORD_SIZE) as u32;
+            if upper != Some(address >> 16) {
+                upper = Some(address >> 16);
+                push_ihex(&mut out, 0, 0x04, &((address >> 16) as u16).to_be_bytes());
+            }
+            push_ihex(&mut out, address as u16, 0x00, chunk);
+        }
+        push_ihex(&mut out, 0, 0x01, &[]);
+        out
+    }
+
+    /// Encodes the image as Motorola S-records, with 24-bit addresses where
+    /// they fit
+    pub fn to_srec(&self) -> String {
+        let end = self.offset as u64 + self.data.len() as u64;
+        let (data_kind, end_kind, address_len) = if end <= 0x100_0000 {
+            ('2', '8', 3)
+        } else {
+            ('3', '7', 4)
+        };
+        let mut out = String::new();
+        push_srec(&mut out, '0', 2, 0, b"mzr");
+        for (i, chunk) in self.data.chunks(SREC_RECORD_SIZE).enumerate() {
+            let address = self.offset + (i * SREC_RECORD_SIZE) as u32;
+            push_srec(&mut out, data_kind, address_len, address, chunk);
+        }
+        push_srec(&mut out, end_kind, address_len, 0, &[]);
+        out
+    }
+}
+
+fn push_ihex(out: &mut String, address: u16, kind: u8, data: &[u8]) {
+    let mut bytes = vec![data.len() as u8];
+    bytes.extend_from_slice(&address.to_be_bytes());
+    bytes.push(kind);
+    bytes.extend_from_slice(data);
+    let checksum = bytes
+        .iter()
+        .fold(0u8, |sum, &b| sum.wrapping_add(b))
+        .wrapping_neg();
+    bytes.push(checksum);
+    out.push(':');
+    push_hex(out, &bytes);
+}
+
+fn push_srec(out: &mut String, kind: char, address_len: usize, address: u32, data: &[u8]) {
+    let mut bytes = vec![(address_len + data.len() + 1) as u8];
+    bytes.extend_from_slice(&address.to_be_bytes()[4 - address_len..]);
+    bytes.extend_from_slice(data);
+    let checksum = !bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+    bytes.push(checksum);
+    out.push('S');
+    out.push(kind);
+    push_hex(out, &bytes);
+}
+
+fn push_hex(out: &mut String, bytes: &[u8]) {
+    for byte in bytes {
+        out.push_str(&format!("{:02X}", byte));
+    }
+    out.push('\n');
+}
+
+/// Data records collected from a HEX or S-record file
+struct Records {
+    region: Region,
+    data: BTreeMap<u32, u8>,
+}
+
+impl Records {
+    /// Collects records that must lie within `region`
+    fn new(region: Region) -> Records {
+        Records {
+            region,
+            data: BTreeMap::new(),
+        }
+    }
+
+    fn insert(&mut self, address: u32, data: &[u8], line: usize) -> Result<(), ImageError> {
+        if !self.region.contains_range(address, data.len()) {
+            return Err(ImageError::OutOfRange(address));
+        }
+        for (i, &byte) in data.iter().enumerate() {
+            if self.data.insert(address + i as u32, byte).is_some() {
+                return Err(parse_error(line, "overlapping records"));
+            }
+        }
+        Ok(())
+    }
+
+    fn into_image(self) -> Result<Image, ImageError> {
+        let (&start, &end) = match (self.data.keys().next(), self.data.keys().next_back()) {
+            (Some(start), Some(end)) => (start, end),
+            _ => return Err(ImageError::Empty),
+        };
+        let mut data = vec![ERASED; (end - start + 1) as usize];
+        for (address, byte) in self.data {
+            data[(address - start) as usize] = byte;
+        }
+        Ok(Image {
+            offset: start,
+            data,
+        })
+    }
+}
+
+/// Returns the non-empty lines of `text`, numbered from 1
+fn lines(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    text.lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty())
+}
+
+/// Decodes the hex digits of a record
+fn parse_record(hex: Option<&str>, line: usize) -> Result<Vec<u8>, ImageError> {
+    let hex = hex.ok_or(parse_error(line, "missing start code"))?;
+    if !hex.len().is_multiple_of(2) {
+        return Err(parse_error(line, "odd number of digits"));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or(parse_error(line, "invalid hex digit"))
+        })
+        .collect()
+}
+
+fn parse_error(line: usize, reason: &'static str) -> ImageError {
+    ImageError::Parse { line, reason }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ihex() {
+        let map = MzrMemoryMap::default();
+        let text = ":020000040000FA\n\
+                    :048000000102030472\n\
+                    :02800600AABB13\n\
+                    :00000001FF\n";
+        let image = Image::from_ihex(text, &map).unwrap();
+        assert_eq!(image.offset, 0x8000);
+        assert_eq!(image.data, vec![1, 2, 3, 4, 0xFF, 0xFF, 0xAA, 0xBB]);
+
+        let bootloader = ":0400000001020304F2\n:00000001FF\n";
+        assert!(matches!(
+            Image::from_ihex(bootloader, &map),
+            Err(ImageError::OutOfRange(0))
+        ));
+        assert!(matches!(
+            Image::from_ihex(":048000000102030473\n", &map),
+            Err(ImageError::Parse { line: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn parses_srec() {
+        let map = MzrMemoryMap::default();
+        let text = "S00600004844521B\n\
+                    S208008000010203046D\n\
+                    S804000000FB\n";
+        let image = Image::from_srec(text, &map).unwrap();
+        assert_eq!(image.offset, 0x8000);
+        assert_eq!(image.data, vec![1, 2, 3, 4]);
+
+        assert!(matches!(
+            Image::from_srec("S2080FFFFE01020304E1\n", &map),
+            Err(ImageError::OutOfRange(0xFFFFE))
+        ));
+    }
+
+    #[test]
+    fn round_trip() {
+        let map = MzrMemoryMap::default();
+        let image = Image {
+            offset: 0xFF00,
+            data: (0..0x180).map(|i| i as u8).collect(),
+        };
+        assert_eq!(Image::from_ihex(&image.to_ihex(), &map).unwrap(), image);
+        assert_eq!(Image::from_srec(&image.to_srec(), &map).unwrap(), image);
+        assert!(image.to_ihex().ends_with(":00000001FF\n"));
+        assert!(image.to_srec().starts_with("S0060000"));
+    }
+}
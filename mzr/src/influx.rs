@@ -0,0 +1,251 @@
+//! Writing of log records to InfluxDB in batches of line protocol, so long
+//! sessions can be graphed in Grafana. The writer is behind the `influx`
+//! feature; the settings are always part of the config.
+//!
+//! ```toml
+//! [influx]
+//! url = "http://localhost:8086"
+//! org = "home"
+//! bucket = "car"
+//! token = "..."
+//! tags = { vehicle = "mps6" }
+//! ```
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::record::Record;
+
+#[cfg(feature = "influx")]
+pub use self::writer::InfluxWriter;
+
+/// Server and series records are written to
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InfluxConfig {
+    /// Base URL of the server
+    pub url: String,
+    /// Bucket, or database of InfluxDB 1.x
+    pub bucket: String,
+    /// Organization of InfluxDB 2. Without one, the 1.x endpoint is used.
+    pub org: Option<String>,
+    /// API token, or `user:password` for InfluxDB 1.x
+    pub token: Option<String>,
+    pub measurement: String,
+    /// Tags added to every point, e.g. the vehicle
+    pub tags: BTreeMap<String, String>,
+    /// Records sent per request
+    pub batch_size: usize,
+    /// Longest time in seconds a record is held before being sent
+    pub flush_interval: u64,
+}
+
+impl Default for InfluxConfig {
+    fn default() -> InfluxConfig {
+        InfluxConfig {
+            url: "http://localhost:8086".to_string(),
+            bucket: "mzr".to_string(),
+            org: None,
+            token: None,
+            measurement: "mzr".to_string(),
+            tags: BTreeMap::new(),
+            batch_size: 100,
+            flush_interval: 5,
+        }
+    }
+}
+
+/// Escapes `chars` and backslashes with a backslash
+fn escape(s: &str, chars: &[char]) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if c == '\\' || chars.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Percent-encodes a URL query value
+fn encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+impl InfluxConfig {
+    /// Returns the URL of the write endpoint, with millisecond timestamps
+    pub fn write_url(&self) -> String {
+        let base = self.url.trim_end_matches('/');
+        match self.org {
+            Some(ref org) => format!(
+                "{}/api/v2/write?org={}&bucket={}&precision=ms",
+                base,
+                encode(org),
+                encode(&self.bucket)
+            ),
+            None => format!("{}/write?db={}&precision=ms", base, encode(&self.bucket)),
+        }
+    }
+
+    /// Returns `record` as a line of line protocol with a field per
+    /// channel, or `None` if it has no finite values
+    pub fn line(&self, record: &Record) -> Option<String> {
+        let fields: Vec<String> = record
+            .values
+            .iter()
+            .filter(|(_, value)| value.is_finite())
+            .map(|(name, value)| format!("{}={}", escape(name, &[',', '=', ' ']), value))
+            .collect();
+        if fields.is_empty() {
+            return None;
+        }
+        let mut line = escape(&self.measurement, &[',', ' ']);
+        for (key, value) in &self.tags {
+            line.push_str(&format!(
+                ",{}={}",
+                escape(key, &[',', '=', ' ']),
+                escape(value, &[',', '=', ' '])
+            ));
+        }
+        Some(format!("{} {} {}", line, fields.join(","), record.time))
+    }
+}
+
+#[cfg(feature = "influx")]
+mod writer {
+    use std::mem;
+    use std::sync::mpsc::{sync_channel, SyncSender};
+    use std::thread::{self, JoinHandle};
+    use std::time::{Duration, Instant};
+
+    use super::InfluxConfig;
+    use crate::record::{Record, RecordSink};
+
+    /// Batches waiting to be sent. Batches are dropped while the server is
+    /// unreachable and the queue is full.
+    const QUEUED_BATCHES: usize = 16;
+
+    const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Batches records and sends them from a background thread, so a slow
+    /// or unreachable server never blocks the logger. Pending records are
+    /// sent when the writer is dropped.
+    pub struct InfluxWriter {
+        config: InfluxConfig,
+        lines: Vec<String>,
+        /// When the oldest pending line was added
+        oldest: Option<Instant>,
+        sender: Option<SyncSender<String>>,
+        thread: Option<JoinHandle<()>>,
+    }
+
+    impl InfluxWriter {
+        pub fn new(config: &InfluxConfig) -> InfluxWriter {
+            let (sender, batches) = sync_channel::<String>(QUEUED_BATCHES);
+            let url = config.write_url();
+            let token = config.token.clone();
+            let thread = thread::spawn(move || {
+                let agent = ureq::AgentBuilder::new().timeout(REQUEST_TIMEOUT).build();
+                for batch in batches {
+                    let mut request = agent
+                        .post(&url)
+                        .set("Content-Type", "text/plain; charset=utf-8");
+                    if let Some(ref token) = token {
+                        request = request.set("Authorization", &format!("Token {}", token));
+                    }
+                    if let Err(err) = request.send_string(&batch) {
+                        tracing::warn!(%err, "failed to write to InfluxDB");
+                    }
+                }
+            });
+            InfluxWriter {
+                config: config.clone(),
+                lines: Vec::new(),
+                oldest: None,
+                sender: Some(sender),
+                thread: Some(thread),
+            }
+        }
+    }
+
+    impl RecordSink for InfluxWriter {
+        fn send(&mut self, record: &Record) {
+            if let Some(line) = self.config.line(record) {
+                self.lines.push(line);
+                self.oldest.get_or_insert_with(Instant::now);
+            }
+            let interval = Duration::from_secs(self.config.flush_interval);
+            let due = self
+                .oldest
+                .is_some_and(|oldest| oldest.elapsed() >= interval);
+            if self.lines.len() >= self.config.batch_size || due {
+                self.flush();
+            }
+        }
+
+        fn flush(&mut self) {
+            self.oldest = None;
+            if self.lines.is_empty() {
+                return;
+            }
+            let batch = mem::take(&mut self.lines).join("\n");
+            if let Some(ref sender) = self.sender {
+                if sender.try_send(batch).is_err() {
+                    tracing::warn!("InfluxDB writes are backed up, dropping a batch");
+                }
+            }
+        }
+    }
+
+    impl Drop for InfluxWriter {
+        fn drop(&mut self) {
+            self.flush();
+            // Closing the queue stops the thread once it is drained
+            self.sender = None;
+            if let Some(thread) = self.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_line_protocol() {
+        let config: InfluxConfig = toml::from_str(
+            "url = \"http://influx:8086/\"\norg = \"my org\"\nbucket = \"car\"\n\
+             measurement = \"engine log\"\ntags = { vehicle = \"mps6\" }",
+        )
+        .unwrap();
+        assert_eq!(config.batch_size, 100);
+        assert_eq!(
+            config.write_url(),
+            "http://influx:8086/api/v2/write?org=my%20org&bucket=car&precision=ms"
+        );
+        let mut record = Record::new();
+        record.time = 1600000000123;
+        assert_eq!(config.line(&record), None);
+        record.values.insert("rpm".to_string(), 850.5);
+        record.values.insert("load, abs".to_string(), 20.0);
+        record.values.insert("bad".to_string(), f64::NAN);
+        assert_eq!(
+            config.line(&record).unwrap(),
+            "engine\\ log,vehicle=mps6 load\\,\\ abs=20,rpm=850.5 1600000000123"
+        );
+        assert_eq!(
+            InfluxConfig::default().write_url(),
+            "http://localhost:8086/write?db=mzr&precision=ms"
+        );
+    }
+}
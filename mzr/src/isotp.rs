@@ -0,0 +1,963 @@
+use std::cmp;
+use std::convert::TryFrom;
+use std::io;
+use std::result::Result;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use thiserror::Error;
+use tracing::{debug, trace};
+
+use mzr_core::isotp::{self as raw, FrameError, MAX_SHORT_PACKET};
+
+use crate::datalink::can::{fd_frame_len, Can, Filter, Message, MAX_STANDARD_ID};
+
+pub use mzr_core::isotp::{Addressing, FCFlag, ParseMode};
+
+#[derive(Error, Debug)]
+pub enum IsotpError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error("invalid flow control flag")]
+    InvalidFcFlag,
+
+    #[error("invalid frame id")]
+    InvalidFrameId,
+
+    /// No packet arrived within the timeout
+    #[error("timed out")]
+    TimedOut,
+
+    /// A frame could not be transmitted within N_As
+    #[error("timed out transmitting a frame (N_As)")]
+    TransmitTimeout,
+
+    /// The receiver sent no flow control frame within N_Bs
+    #[error("timed out waiting for flow control (N_Bs)")]
+    FlowControlTimeout,
+
+    /// The sender sent no consecutive frame within N_Cr
+    #[error("timed out waiting for a consecutive frame (N_Cr)")]
+    ConsecutiveFrameTimeout,
+
+    /// Occurs when a frame is received with an unexpected id, e.g. when waiting for a
+    /// flow control frame but another frame was received.
+    #[error("unexpected frame")]
+    UnexpectedFrame,
+
+    #[error("invalid consecutive frame index")]
+    InvalidIndex,
+
+    /// The length of a frame doesn't match its contents
+    #[error("invalid frame length")]
+    InvalidLength,
+
+    #[error("reserved separation time 0x{0:02X}")]
+    ReservedSeparationTime(u8),
+
+    /// The receiver can't accept a packet of this size
+    #[error("receiver buffer overflow")]
+    Overflow,
+
+    /// The receiver asked to wait more times than allowed
+    #[error("too many flow control wait frames")]
+    TooManyWaits,
+}
+
+impl From<FrameError> for IsotpError {
+    fn from(err: FrameError) -> IsotpError {
+        match err {
+            FrameError::InvalidFcFlag => IsotpError::InvalidFcFlag,
+            FrameError::InvalidFrameId => IsotpError::InvalidFrameId,
+            FrameError::InvalidLength | FrameError::BufferTooSmall => IsotpError::InvalidLength,
+            FrameError::ReservedSeparationTime(st) => IsotpError::ReservedSeparationTime(st),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Frame {
+    Single {
+        data: Vec<u8>,
+    },
+    First {
+        size: u32,
+        data: Vec<u8>,
+    },
+    Consecutive {
+        index: u8,
+        data: Vec<u8>,
+    },
+    Flow {
+        flag: FCFlag,
+        block_size: u8,
+        separation_time: Duration,
+    },
+}
+
+impl Frame {
+    /// Borrows the frame as a frame of `mzr_core`
+    fn as_core(&self) -> raw::Frame<'_> {
+        match *self {
+            Frame::Single { ref data } => raw::Frame::Single { data },
+            Frame::First { size, ref data } => raw::Frame::First { size, data },
+            Frame::Consecutive { index, ref data } => raw::Frame::Consecutive { index, data },
+            Frame::Flow {
+                flag,
+                block_size,
+                separation_time,
+            } => raw::Frame::Flow {
+                flag,
+                block_size,
+                separation_time,
+            },
+        }
+    }
+
+    /// Encodes the protocol control information and data of the frame
+    fn encode(&self) -> Vec<u8> {
+        let frame = self.as_core();
+        let mut payload = vec![0; frame.encoded_len()];
+        frame
+            .encode(&mut payload)
+            .expect("buffer holds the encoded frame");
+        payload
+    }
+
+    /// Decodes a frame from the payload of a CAN message, after the address
+    /// byte with extended `addressing`. Padding is kept in the data of first
+    /// and consecutive frames.
+    pub fn decode(
+        payload: &[u8],
+        mode: ParseMode,
+        addressing: Addressing,
+    ) -> Result<Frame, IsotpError> {
+        Ok(match raw::Frame::decode(payload, mode, addressing)? {
+            raw::Frame::Single { data } => Frame::Single {
+                data: data.to_vec(),
+            },
+            raw::Frame::First { size, data } => Frame::First {
+                size,
+                data: data.to_vec(),
+            },
+            raw::Frame::Consecutive { index, data } => Frame::Consecutive {
+                index,
+                data: data.to_vec(),
+            },
+            raw::Frame::Flow {
+                flag,
+                block_size,
+                separation_time,
+            } => Frame::Flow {
+                flag,
+                block_size,
+                separation_time,
+            },
+        })
+    }
+}
+
+impl TryFrom<Message> for Frame {
+    type Error = IsotpError;
+
+    /// Converts from CAN message, parsing leniently. Returns an error for
+    /// invalid frames.
+    fn try_from(msg: Message) -> Result<Self, Self::Error> {
+        Frame::decode(msg.payload(), ParseMode::Lenient, Addressing::Normal)
+    }
+}
+
+pub trait Isotp {
+    /// Receives an ISO-TP packet
+    fn read_isotp(&self) -> Result<Vec<u8>, IsotpError>;
+
+    /// Sends an ISO-TP packet
+    fn write_isotp(&self, data: &[u8]) -> Result<(), IsotpError>;
+
+    fn request_isotp(&self, request: &[u8]) -> Result<Vec<u8>, IsotpError> {
+        self.write_isotp(request)?;
+        self.read_isotp()
+    }
+}
+
+struct SendPacket<'a> {
+    buffer: &'a [u8],
+    index: u8,
+    /// Bytes available for the frame in each CAN message, after the address
+    /// extension
+    frame_len: usize,
+}
+
+/// Used for sending mutli-frame packets.
+/// It is NOT used for single-frame packets.
+impl<'a> SendPacket<'a> {
+    fn new(buffer: &[u8], frame_len: usize) -> SendPacket<'_> {
+        assert!(buffer.len() <= u32::MAX as usize);
+        SendPacket {
+            buffer,
+            index: 0,
+            frame_len,
+        }
+    }
+
+    fn first_frame(&mut self) -> Frame {
+        // The 32-bit length takes 4 more bytes
+        let pci_len = if self.buffer.len() > MAX_SHORT_PACKET {
+            6
+        } else {
+            2
+        };
+        let len = cmp::min(self.buffer.len(), self.frame_len - pci_len);
+        let frame = Frame::First {
+            size: self.buffer.len() as u32,
+            data: self.buffer[..len].to_vec(),
+        };
+        self.buffer = &self.buffer[len..];
+        self.index = 1;
+        frame
+    }
+
+    fn next_consec_frame(&mut self) -> Frame {
+        let len = cmp::min(self.buffer.len(), self.frame_len - 1);
+        let frame = Frame::Consecutive {
+            index: self.index,
+            data: self.buffer[..len].to_vec(),
+        };
+        self.buffer = &self.buffer[len..];
+        self.index += 1;
+        if self.index == 16 {
+            self.index = 0;
+        }
+        frame
+    }
+
+    fn eof(&self) -> bool {
+        self.buffer.is_empty()
+    }
+}
+
+/// Address bytes of extended addressing, where the first byte of every
+/// frame carries the target address
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct AddressExtension {
+    /// Address sent in every frame
+    pub target: u8,
+    /// Address expected in received frames. Other frames are ignored.
+    pub source: u8,
+}
+
+/// Returns the request and response IDs of the module at `target` with
+/// 29-bit normal fixed addressing, e.g. `normal_fixed_ids(0x10, 0xF1)` for
+/// the engine ECU
+pub fn normal_fixed_ids(target: u8, tester: u8) -> (u32, u32) {
+    const PHYSICAL: u32 = 0x18DA_0000;
+    (
+        PHYSICAL | (target as u32) << 8 | tester as u32,
+        PHYSICAL | (tester as u32) << 8 | target as u32,
+    )
+}
+
+/// Returns the classic CAN payloads a sender puts on the bus for `data`,
+/// padded with [`DEFAULT_PADDING`]. Flow control frames of the receiver are
+/// not included.
+pub fn segment(data: &[u8]) -> Vec<Vec<u8>> {
+    let frames = if data.len() <= 7 {
+        vec![Frame::Single {
+            data: data.to_vec(),
+        }]
+    } else {
+        let mut packet = SendPacket::new(data, 8);
+        let mut frames = vec![packet.first_frame()];
+        while !packet.eof() {
+            frames.push(packet.next_consec_frame());
+        }
+        frames
+    };
+    frames
+        .iter()
+        .map(|frame| {
+            let mut payload = frame.encode();
+            payload.resize(8, DEFAULT_PADDING);
+            payload
+        })
+        .collect()
+}
+
+/// Default limit on the size of received packets
+pub const DEFAULT_MAX_RECEIVE_SIZE: usize = 0x10_0000;
+
+/// Byte frames are padded with by default
+pub const DEFAULT_PADDING: u8 = 0x00;
+
+/// Default N_As, N_Bs and N_Cr timeouts of ISO 15765-2
+pub const DEFAULT_FRAME_TIMEOUT: Duration = Duration::from_millis(1000);
+
+/// Default number of flow control Wait frames accepted in a row
+pub const DEFAULT_MAX_WAIT_FRAMES: u8 = 10;
+
+/// ISO-TP stack implemented in user-space. Timing is likely nonconforming.
+pub struct IsotpCan<C: Can> {
+    can: C,
+    pub source_id: u32,
+    pub dest_id: u32,
+    /// Whether the IDs are 29-bit identifiers. Set by [`IsotpCan::new`] if
+    /// either ID doesn't fit in 11 bits.
+    pub extended_ids: bool,
+    /// Address bytes of extended addressing, or `None` for normal
+    /// addressing
+    pub address_extension: Option<AddressExtension>,
+    /// Time to wait for the first frame of a packet
+    pub timeout: Duration,
+    /// Time allowed for transmitting a frame (N_As)
+    pub transmit_timeout: Duration,
+    /// Time to wait for a flow control frame when sending (N_Bs)
+    pub flow_control_timeout: Duration,
+    /// Time to wait for each consecutive frame when receiving (N_Cr)
+    pub consecutive_frame_timeout: Duration,
+    /// Number of consecutive frames the sender may send before waiting for
+    /// the next flow control frame when receiving. 0 sends all frames
+    /// without waiting, which some ECUs and adapters mishandle.
+    pub block_size: u8,
+    /// Minimum time the sender waits between consecutive frames when
+    /// receiving
+    pub separation_time: Duration,
+    /// Number of consecutive flow control Wait frames accepted when sending
+    /// before giving up (N_WFTmax)
+    pub max_wait_frames: u8,
+    /// Largest packet accepted when receiving. Larger packets are refused
+    /// with an Overflow flow control frame.
+    pub max_receive_size: usize,
+    /// Byte short frames are padded to 8 bytes with, or `None` to send
+    /// frames no longer than their data. Some ECUs require padding, others
+    /// reject padded frames.
+    pub padding: Option<u8>,
+    /// Whether to send CAN-FD frames of up to 64 bytes. Ignored if the
+    /// interface doesn't support CAN-FD. CAN-FD frames are always accepted
+    /// when receiving.
+    pub fd: bool,
+    /// How strictly received frames are checked
+    pub parse_mode: ParseMode,
+}
+
+impl<C: Can> IsotpCan<C> {
+    pub fn new(can: C, source_id: u32, dest_id: u32, timeout: Duration) -> IsotpCan<C> {
+        IsotpCan {
+            can,
+            source_id,
+            dest_id,
+            extended_ids: source_id > MAX_STANDARD_ID || dest_id > MAX_STANDARD_ID,
+            address_extension: None,
+            timeout,
+            transmit_timeout: DEFAULT_FRAME_TIMEOUT,
+            flow_control_timeout: DEFAULT_FRAME_TIMEOUT,
+            consecutive_frame_timeout: DEFAULT_FRAME_TIMEOUT,
+            block_size: 0,
+            separation_time: Duration::from_millis(0),
+            max_wait_frames: DEFAULT_MAX_WAIT_FRAMES,
+            max_receive_size: DEFAULT_MAX_RECEIVE_SIZE,
+            padding: Some(DEFAULT_PADDING),
+            parse_mode: ParseMode::Lenient,
+            fd: false,
+        }
+    }
+
+    /// Makes the interface drop frames not sent with `dest_id`, which would
+    /// otherwise be filtered in software on a busy bus. The interface
+    /// shouldn't be shared with other connections.
+    pub fn filter_bus(&self) -> Result<(), IsotpError> {
+        self.can
+            .set_filters(&[Filter::exact(self.dest_id, self.extended_ids)])?;
+        Ok(())
+    }
+
+    /// Returns the number of bytes the address extension takes in each frame
+    fn address_len(&self) -> usize {
+        self.address_extension.map_or(0, |_| 1)
+    }
+
+    /// Returns true if CAN-FD frames are sent
+    fn sends_fd(&self) -> bool {
+        self.fd && self.can.supports_fd()
+    }
+
+    /// Returns the number of bytes available for a frame in each CAN
+    /// message
+    fn frame_len(&self) -> usize {
+        if self.sends_fd() {
+            64 - self.address_len()
+        } else {
+            8 - self.address_len()
+        }
+    }
+
+    fn send_flow_control(&self, flag: FCFlag) -> Result<(), IsotpError> {
+        self.send_frame(&Frame::Flow {
+            flag,
+            block_size: self.block_size,
+            separation_time: self.separation_time,
+        })
+    }
+
+    fn send_frame(&self, frame: &Frame) -> Result<(), IsotpError> {
+        let start_time = Instant::now();
+        let mut payload = Vec::with_capacity(64);
+        if let Some(extension) = self.address_extension {
+            payload.push(extension.target);
+        }
+        payload.extend_from_slice(&frame.encode());
+        let pad_byte = self.padding.unwrap_or(DEFAULT_PADDING);
+        if self.padding.is_some() {
+            payload.resize(payload.len().max(8), pad_byte);
+        }
+        let msg = if self.sends_fd() {
+            // CAN-FD frames longer than 8 bytes are always padded to the next
+            // valid length
+            payload.resize(fd_frame_len(payload.len()).unwrap_or(64), pad_byte);
+            Message::new_fd(self.source_id, &payload)
+        } else {
+            Message::new(self.source_id, &payload)
+        };
+        let msg = Message {
+            extended: self.extended_ids,
+            ..msg
+        };
+        trace!(id = self.source_id, ?frame, "sending frame");
+        match self.can.send_msg(&msg) {
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => Err(IsotpError::TransmitTimeout),
+            Err(e) => Err(e.into()),
+            // Adapters that queue frames report late transmissions as sent
+            Ok(()) if start_time.elapsed() > self.transmit_timeout => {
+                Err(IsotpError::TransmitTimeout)
+            }
+            Ok(()) => Ok(()),
+        }
+    }
+
+    /// Receives the next frame from `dest_id`, returning `timeout_error` if
+    /// none arrives within `timeout`
+    fn recv_frame(
+        &self,
+        timeout: Duration,
+        timeout_error: IsotpError,
+    ) -> Result<Frame, IsotpError> {
+        let start_time = Instant::now();
+        loop {
+            let remaining = match timeout.checked_sub(start_time.elapsed()) {
+                Some(remaining) => remaining,
+                None => return Err(timeout_error),
+            };
+            let msg = match self.can.read(remaining) {
+                Ok(msg) => msg,
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => return Err(timeout_error),
+                Err(e) => return Err(e.into()),
+            };
+            if msg.id != self.dest_id || msg.extended != self.extended_ids {
+                continue;
+            }
+            let payload = msg.payload();
+            let frame = match self.address_extension {
+                Some(extension) if payload.first() != Some(&extension.source) => continue,
+                Some(_) => Frame::decode(&payload[1..], self.parse_mode, Addressing::Extended),
+                None => Frame::decode(payload, self.parse_mode, Addressing::Normal),
+            };
+            match &frame {
+                Ok(frame) => trace!(id = self.dest_id, ?frame, "received frame"),
+                Err(err) => debug!(id = self.dest_id, %err, "invalid frame"),
+            }
+            return frame;
+        }
+    }
+
+    /// Returns (flag, block_size, separation_time)
+    fn recv_flow_control_frame(&self) -> Result<(FCFlag, u8, Duration), IsotpError> {
+        let frame = self.recv_frame(self.flow_control_timeout, IsotpError::FlowControlTimeout)?;
+        match frame {
+            Frame::Flow {
+                flag,
+                block_size,
+                separation_time,
+            } => Ok((flag, block_size, separation_time)),
+            _ => Err(IsotpError::UnexpectedFrame),
+        }
+    }
+
+    /// Waits until the receiver allows sending more frames. Returns
+    /// (block_size, separation_time).
+    fn wait_flow_control(&self) -> Result<(u8, Duration), IsotpError> {
+        let mut waits = 0;
+        loop {
+            match self.recv_flow_control_frame()? {
+                (FCFlag::Continue, block_size, separation_time) => {
+                    return Ok((block_size, separation_time))
+                }
+                (FCFlag::Wait, _, _) => {
+                    waits += 1;
+                    debug!(waits, "receiver asked to wait");
+                    if waits > self.max_wait_frames {
+                        return Err(IsotpError::TooManyWaits);
+                    }
+                }
+                (FCFlag::Overflow, _, _) => return Err(IsotpError::Overflow),
+            }
+        }
+    }
+}
+
+impl<C: Can> Isotp for IsotpCan<C> {
+    fn read_isotp(&self) -> Result<Vec<u8>, IsotpError> {
+        // Receive first or single frame
+        let frame = self.recv_frame(self.timeout, IsotpError::TimedOut)?;
+        match frame {
+            Frame::Single { data } => Ok(data),
+            Frame::First { size, data } => {
+                debug!(id = self.dest_id, size, "receiving segmented packet");
+                if size as usize > self.max_receive_size {
+                    self.send_flow_control(FCFlag::Overflow)?;
+                    return Err(IsotpError::Overflow);
+                }
+                let mut buffer = data[..cmp::min(size as usize, data.len())].to_vec();
+                let mut remaining = size as usize - buffer.len();
+                // Send the flow control frame
+                self.send_flow_control(FCFlag::Continue)?;
+
+                // Wait for all consecutive packets
+                let mut index = 1;
+                let mut block_remaining = self.block_size;
+                while remaining > 0 {
+                    let frame = self.recv_frame(
+                        self.consecutive_frame_timeout,
+                        IsotpError::ConsecutiveFrameTimeout,
+                    )?;
+                    let (msg_index, data) = match frame {
+                        Frame::Consecutive { index, data } => (index, data),
+                        _ => return Err(IsotpError::UnexpectedFrame),
+                    };
+                    if msg_index != index {
+                        // Invalid index
+                        return Err(IsotpError::InvalidIndex);
+                    }
+
+                    let len = cmp::min(remaining, data.len());
+                    buffer.extend_from_slice(&data[..len]);
+                    remaining -= len;
+
+                    index += 1;
+                    if index == 16 {
+                        index = 0;
+                    }
+
+                    if remaining > 0 && self.block_size > 0 {
+                        block_remaining -= 1;
+                        if block_remaining == 0 {
+                            // The sender waits for the next flow control frame
+                            self.send_flow_control(FCFlag::Continue)?;
+                            block_remaining = self.block_size;
+                        }
+                    }
+                }
+                Ok(buffer)
+            }
+            _ => Err(IsotpError::UnexpectedFrame),
+        }
+    }
+
+    fn write_isotp(&self, data: &[u8]) -> Result<(), IsotpError> {
+        // Single frames longer than 7 bytes take a second length byte
+        let single_len = match self.frame_len() {
+            len if len > 8 => len - 2,
+            len => len - 1,
+        };
+        if data.len() <= single_len {
+            // Send a single frame
+            self.send_frame(&Frame::Single {
+                data: data.to_vec(),
+            })?;
+        } else {
+            debug!(
+                id = self.source_id,
+                size = data.len(),
+                "sending segmented packet"
+            );
+            let mut packet = SendPacket::new(data, self.frame_len());
+            // Send a first frame
+            self.send_frame(&packet.first_frame())?;
+            // Get flow control and send consecutive frames
+
+            let (mut block_size, mut separation_time) = self.wait_flow_control()?;
+            while !packet.eof() {
+                // Loop until the buffer is empty
+                if separation_time != Duration::new(0, 0) {
+                    thread::sleep(separation_time);
+                }
+
+                self.send_frame(&packet.next_consec_frame())?;
+
+                if !packet.eof() && block_size > 0 {
+                    block_size -= 1;
+                    if block_size == 0 {
+                        // Get the next flow control packet
+                        let (f_block_size, f_separation_time) = self.wait_flow_control()?;
+                        block_size = f_block_size;
+                        separation_time = f_separation_time;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mzr_core::isotp::{duration_to_st, st_to_duration};
+    use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+
+    /// One end of an in-memory CAN bus
+    struct Pipe {
+        tx: Sender<Message>,
+        rx: Receiver<Message>,
+        fd: bool,
+    }
+
+    fn pipe() -> (Pipe, Pipe) {
+        let (a_tx, a_rx) = channel();
+        let (b_tx, b_rx) = channel();
+        (
+            Pipe {
+                tx: a_tx,
+                rx: b_rx,
+                fd: false,
+            },
+            Pipe {
+                tx: b_tx,
+                rx: a_rx,
+                fd: false,
+            },
+        )
+    }
+
+    impl Can for Pipe {
+        fn send_msg(&self, msg: &Message) -> io::Result<()> {
+            self.tx
+                .send(*msg)
+                .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+        }
+
+        fn read(&self, timeout: Duration) -> io::Result<Message> {
+            self.rx.recv_timeout(timeout).map_err(|e| match e {
+                RecvTimeoutError::Timeout => io::Error::from(io::ErrorKind::TimedOut),
+                RecvTimeoutError::Disconnected => io::Error::from(io::ErrorKind::BrokenPipe),
+            })
+        }
+
+        fn supports_fd(&self) -> bool {
+            self.fd
+        }
+    }
+
+    #[test]
+    fn isotp() {
+        let (tester, ecu) = pipe();
+        let timeout = Duration::from_secs(1);
+        let tester = IsotpCan::new(tester, 0x7e0, 0x7e8, timeout);
+        let ecu = IsotpCan::new(ecu, 0x7e8, 0x7e0, timeout);
+
+        let handle = thread::spawn(move || {
+            let request = ecu.read_isotp().unwrap();
+            ecu.write_isotp(&request.iter().rev().cloned().collect::<Vec<u8>>())
+                .unwrap();
+        });
+
+        let request: Vec<u8> = (0..200).collect();
+        let response = tester.request_isotp(&request).unwrap();
+        handle.join().unwrap();
+        assert_eq!(response, request.into_iter().rev().collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn flow_control_blocks() {
+        let (tester, ecu) = pipe();
+        let timeout = Duration::from_secs(1);
+        let mut tester = IsotpCan::new(tester, 0x7e0, 0x7e8, timeout);
+        tester.block_size = 4;
+        tester.separation_time = Duration::from_micros(500);
+        let ecu = IsotpCan::new(ecu, 0x7e8, 0x7e0, timeout);
+
+        // The sender waits for a flow control frame after every 4 frames,
+        // so the response only arrives if the tester sends them
+        let handle = thread::spawn(move || {
+            let request = ecu.read_isotp().unwrap();
+            ecu.write_isotp(&request.repeat(20)).unwrap();
+        });
+
+        let request: Vec<u8> = (0..10).collect();
+        let response = tester.request_isotp(&request).unwrap();
+        handle.join().unwrap();
+        assert_eq!(response, request.repeat(20));
+    }
+
+    #[test]
+    fn flow_control_wait_and_overflow() {
+        let (tester, ecu) = pipe();
+        let timeout = Duration::from_secs(1);
+        let mut tester = IsotpCan::new(tester, 0x7e0, 0x7e8, timeout);
+        tester.max_wait_frames = 2;
+        let wait = Frame::Flow {
+            flag: FCFlag::Wait,
+            block_size: 0,
+            separation_time: Duration::from_millis(0),
+        };
+        let send = |frame: &Frame| ecu.send_msg(&Message::new(0x7e8, &frame.encode())).unwrap();
+
+        // Waits up to the limit are accepted
+        send(&wait);
+        send(&wait);
+        send(&Frame::Flow {
+            flag: FCFlag::Continue,
+            block_size: 0,
+            separation_time: Duration::from_millis(0),
+        });
+        tester.write_isotp(&[0; 20]).unwrap();
+
+        send(&wait);
+        send(&wait);
+        send(&wait);
+        assert!(matches!(
+            tester.write_isotp(&[0; 20]),
+            Err(IsotpError::TooManyWaits)
+        ));
+
+        while ecu.read(Duration::from_millis(10)).is_ok() {}
+        send(&Frame::Flow {
+            flag: FCFlag::Overflow,
+            block_size: 0,
+            separation_time: Duration::from_millis(0),
+        });
+        assert!(matches!(
+            tester.write_isotp(&[0; 20]),
+            Err(IsotpError::Overflow)
+        ));
+    }
+
+    #[test]
+    fn frame_timeouts() {
+        let (tester, ecu) = pipe();
+        let mut tester = IsotpCan::new(tester, 0x7e0, 0x7e8, Duration::from_secs(1));
+        tester.flow_control_timeout = Duration::from_millis(20);
+        tester.consecutive_frame_timeout = Duration::from_millis(20);
+
+        // No flow control frame follows the first frame
+        assert!(matches!(
+            tester.write_isotp(&[0; 20]),
+            Err(IsotpError::FlowControlTimeout)
+        ));
+
+        // The response stops after its first frame
+        let first = Frame::First {
+            size: 20,
+            data: vec![0; 6],
+        };
+        ecu.send_msg(&Message::new(0x7e8, &first.encode())).unwrap();
+        assert!(matches!(
+            tester.read_isotp(),
+            Err(IsotpError::ConsecutiveFrameTimeout)
+        ));
+    }
+
+    #[test]
+    fn extended_ids() {
+        let (tester, ecu) = pipe();
+        let timeout = Duration::from_secs(1);
+        let (request_id, response_id) = normal_fixed_ids(0x10, 0xF1);
+        assert_eq!((request_id, response_id), (0x18DA_10F1, 0x18DA_F110));
+        let tester = IsotpCan::new(tester, request_id, response_id, timeout);
+        assert!(tester.extended_ids);
+        let ecu = IsotpCan::new(ecu, response_id, request_id, timeout);
+
+        let handle = thread::spawn(move || {
+            let request = ecu.read_isotp().unwrap();
+            // A standard frame with the same ID is not part of the response
+            let standard = Message {
+                extended: false,
+                ..Message::new(response_id, &[0x01, 0x7F])
+            };
+            ecu.can.send_msg(&standard).unwrap();
+            ecu.write_isotp(&request.repeat(3)).unwrap();
+        });
+
+        let request: Vec<u8> = (0..10).collect();
+        let response = tester.request_isotp(&request).unwrap();
+        handle.join().unwrap();
+        assert_eq!(response, request.repeat(3));
+    }
+
+    #[test]
+    fn extended_addressing() {
+        let (tester, ecu) = pipe();
+        let timeout = Duration::from_secs(1);
+        let mut tester = IsotpCan::new(tester, 0x6F1, 0x612, timeout);
+        tester.address_extension = Some(AddressExtension {
+            target: 0x12,
+            source: 0xF1,
+        });
+        let mut ecu = IsotpCan::new(ecu, 0x612, 0x6F1, timeout);
+        ecu.address_extension = Some(AddressExtension {
+            target: 0xF1,
+            source: 0x12,
+        });
+
+        let handle = thread::spawn(move || {
+            // Frames for other modules on the same ID are ignored
+            ecu.can
+                .send_msg(&Message::new(0x612, &[0x40, 0x02, 0x7F, 0x22]))
+                .unwrap();
+            let single = ecu.read_isotp().unwrap();
+            let multi = ecu.read_isotp().unwrap();
+            ecu.write_isotp(&[single, multi].concat()).unwrap();
+        });
+
+        tester.write_isotp(&[1, 2, 3, 4, 5, 6]).unwrap();
+        let request: Vec<u8> = (0..40).collect();
+        let response = tester.request_isotp(&request).unwrap();
+        handle.join().unwrap();
+        assert_eq!(response, [&[1, 2, 3, 4, 5, 6][..], &request].concat());
+    }
+
+    #[test]
+    fn can_fd_frames() {
+        let (mut tester, mut ecu) = pipe();
+        tester.fd = true;
+        ecu.fd = true;
+        let timeout = Duration::from_secs(1);
+        let mut tester = IsotpCan::new(tester, 0x7e0, 0x7e8, timeout);
+        tester.fd = true;
+        let mut ecu = IsotpCan::new(ecu, 0x7e8, 0x7e0, timeout);
+        ecu.fd = true;
+
+        let handle = thread::spawn(move || {
+            let request = ecu.read_isotp().unwrap();
+            ecu.write_isotp(&request.repeat(10)).unwrap();
+            ecu
+        });
+
+        // 40 bytes fit in a single frame
+        let request: Vec<u8> = (0..40).collect();
+        let response = tester.request_isotp(&request).unwrap();
+        let ecu = handle.join().unwrap();
+        assert_eq!(response, request.repeat(10));
+
+        // The first frame of a longer packet fills a whole CAN-FD frame
+        tester.flow_control_timeout = Duration::from_millis(20);
+        assert!(matches!(
+            tester.write_isotp(&[0; 400]),
+            Err(IsotpError::FlowControlTimeout)
+        ));
+        let frames: Vec<Message> = ecu.can.rx.try_iter().collect();
+        assert_eq!(frames.len(), 1);
+        assert!(frames[0].fd && frames[0].len == 64);
+    }
+
+    #[test]
+    fn large_packets() {
+        let (tester, ecu) = pipe();
+        let timeout = Duration::from_secs(1);
+        let tester = IsotpCan::new(tester, 0x7e0, 0x7e8, timeout);
+        let mut ecu = IsotpCan::new(ecu, 0x7e8, 0x7e0, timeout);
+        ecu.max_receive_size = 6000;
+
+        let handle = thread::spawn(move || {
+            let request = ecu.read_isotp().unwrap();
+            assert!(matches!(ecu.read_isotp(), Err(IsotpError::Overflow)));
+            request
+        });
+
+        let request: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        tester.write_isotp(&request).unwrap();
+        assert!(matches!(
+            tester.write_isotp(&[0; 7000]),
+            Err(IsotpError::Overflow)
+        ));
+        assert_eq!(handle.join().unwrap(), request);
+    }
+
+    #[test]
+    fn frame_padding() {
+        let (tester, ecu) = pipe();
+        let mut tester = IsotpCan::new(tester, 0x7e0, 0x7e8, Duration::from_secs(1));
+        tester.padding = Some(0xCC);
+        tester.write_isotp(&[0x3E, 0x00]).unwrap();
+        let msg = ecu.read(Duration::from_secs(1)).unwrap();
+        assert_eq!(
+            msg.payload(),
+            &[0x02, 0x3E, 0x00, 0xCC, 0xCC, 0xCC, 0xCC, 0xCC]
+        );
+
+        tester.padding = None;
+        tester.write_isotp(&[0x3E, 0x00]).unwrap();
+        let msg = ecu.read(Duration::from_secs(1)).unwrap();
+        assert_eq!(msg.payload(), &[0x02, 0x3E, 0x00]);
+
+        // Unpadded frames are accepted, and flow control is sent unpadded
+        ecu.send_msg(&Message::new(0x7e8, &[0x10, 0x09, 1, 2, 3, 4, 5, 6]))
+            .unwrap();
+        ecu.send_msg(&Message::new(0x7e8, &[0x21, 7, 8, 9]))
+            .unwrap();
+        assert_eq!(tester.read_isotp().unwrap(), (1..=9).collect::<Vec<u8>>());
+        let msg = ecu.read(Duration::from_secs(1)).unwrap();
+        assert_eq!(msg.payload(), &[0x30, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn parse_modes() {
+        let strict = |payload: &[u8]| Frame::decode(payload, ParseMode::Strict, Addressing::Normal);
+        let lenient =
+            |payload: &[u8]| Frame::decode(payload, ParseMode::Lenient, Addressing::Normal);
+
+        // Single frame claiming more data than it has
+        let truncated = [0x05, 0x3E, 0x00];
+        assert!(matches!(strict(&truncated), Err(IsotpError::InvalidLength)));
+        assert!(matches!(lenient(&truncated), Ok(Frame::Single { data }) if data == [0x3E, 0x00]));
+
+        // Short first frame
+        let first = [0x10, 0x09, 1, 2, 3];
+        assert!(matches!(strict(&first), Err(IsotpError::InvalidLength)));
+        assert!(lenient(&first).is_ok());
+
+        // First frame of a packet that fits in a single frame
+        let first = [0x10, 0x05, 1, 2, 3, 4, 5, 0];
+        assert!(matches!(strict(&first), Err(IsotpError::InvalidLength)));
+
+        // Reserved flag bits and separation time
+        let flow = [0x34, 0x00, 0x80];
+        assert!(matches!(strict(&flow), Err(IsotpError::InvalidFcFlag)));
+        let flow = [0x30, 0x00, 0x80];
+        assert!(matches!(
+            strict(&flow),
+            Err(IsotpError::ReservedSeparationTime(0x80))
+        ));
+        assert!(matches!(
+            lenient(&[0x34, 0x00, 0x80]),
+            Ok(Frame::Flow { separation_time, .. }) if separation_time == Duration::from_millis(127)
+        ));
+
+        assert!(matches!(strict(&[0x40]), Err(IsotpError::InvalidFrameId)));
+        assert!(strict(&[0x02, 0x3E, 0x00, 0, 0, 0, 0, 0]).is_ok());
+
+        // With extended addressing, a full first frame is one byte shorter
+        let first = [0x10, 0x09, 1, 2, 3, 4, 5];
+        assert!(strict(&first).is_err());
+        assert!(matches!(
+            Frame::decode(&first, ParseMode::Strict, Addressing::Extended),
+            Ok(Frame::First { size: 9, .. })
+        ));
+    }
+
+    #[test]
+    fn separation_time() {
+        for &st in &[0_u8, 1, 20, 127, 0xF1, 0xF9] {
+            assert_eq!(duration_to_st(st_to_duration(st)), st);
+        }
+    }
+}
@@ -0,0 +1,48 @@
+//! Iterator adapter over [`Transfer`] steps
+
+use crate::progress::{Progress, RateMeter};
+use crate::transfer::{Transfer, TransferState};
+use crate::MzrError;
+
+/// Iterator returned by [`Downloader::iter`](crate::Downloader::iter) and
+/// [`Programmer::iter`](crate::Programmer::iter). Yields the progress after
+/// each block and ends after the last block or the first error.
+pub struct TransferIter<'t, T: Transfer + ?Sized> {
+    transfer: &'t mut T,
+    meter: RateMeter,
+    done: bool,
+}
+
+impl<'t, T: Transfer + ?Sized> TransferIter<'t, T> {
+    /// Iterates over the remaining blocks of `transfer`. The rate of a
+    /// resumed transfer is measured from where it resumed.
+    pub fn new(transfer: &'t mut T) -> TransferIter<'t, T> {
+        let meter = RateMeter::resumed(transfer.total_size(), transfer.position());
+        TransferIter {
+            transfer,
+            meter,
+            done: false,
+        }
+    }
+}
+
+impl<'t, T: Transfer + ?Sized> Iterator for TransferIter<'t, T> {
+    type Item = Result<Progress, MzrError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        Some(match self.transfer.step() {
+            Ok(TransferState::InProgress(position)) => Ok(self.meter.update(position)),
+            Ok(TransferState::Completed) => {
+                self.done = true;
+                Ok(self.meter.update(self.transfer.total_size()))
+            }
+            Err(err) => {
+                self.done = true;
+                Err(err)
+            }
+        })
+    }
+}
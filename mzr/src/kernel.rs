@@ -0,0 +1,419 @@
+//! RAM kernel transfers. The stock bootloader reads and writes memory
+//! slowly, so a small kernel is uploaded to RAM with RequestDownload and
+//! TransferData and started. The kernel then answers a simple block protocol
+//! for full-speed reads and writes.
+//!
+//! Kernel protocol, carried over ISO-TP like UDS and answered with the
+//! service ID + 0x40 or a negative response:
+//!
+//! - `0x80`: ping, answered with the kernel version
+//! - `0x81 [address u32] [length u16]`: reads memory
+//! - `0x82 [address u32] [length u32]`: erases the flash sectors covering a
+//!   range
+//! - `0x83 [address u32] [data...]`: writes erased flash
+//! - `0x11 0x01`: resets the ECU, leaving the kernel
+//! - `0x3E 0x00`: tester present
+//!
+//! Because the kernel erases sector by sector, unlike the bootloader's erase
+//! routine, [`KernelTransfer::write_sectors`] can reprogram just the sectors
+//! a new image changes.
+
+use std::cmp;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use obd::Uds;
+
+use crate::cancel::CancelToken;
+use crate::diff::SectorUpdate;
+use crate::event::{Event, EventSink, Events};
+use crate::memory_map::Region;
+use crate::progress::Phase;
+use crate::session::{ExitAction, Session};
+use crate::timeout::{Operation, SetTimeout, TimeoutProfile};
+use crate::transfer::{Transfer, TransferState};
+use crate::voltage::{VoltageGate, VoltageMonitor};
+use crate::{MzrBus, MzrError, BLOCK_SIZE, SESSION_PROGRAMMING};
+
+/// Default RAM address kernels are loaded to and started from
+pub const DEFAULT_KERNEL_ADDRESS: u32 = 0xFFFF_6000;
+
+/// Largest block read or written by a single kernel request
+pub const KERNEL_BLOCK_SIZE: usize = 0xFF0;
+
+const UDS_REQ_STARTROUTINEBYADDRESS: u8 = 0x38;
+const KERNEL_REQ_PING: u8 = 0x80;
+const KERNEL_REQ_READ: u8 = 0x81;
+const KERNEL_REQ_ERASE: u8 = 0x82;
+const KERNEL_REQ_WRITE: u8 = 0x83;
+
+/// Kernel image and the RAM address it runs from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RamKernel {
+    address: u32,
+    image: Vec<u8>,
+}
+
+impl RamKernel {
+    /// Creates a kernel loaded to and started from `address`
+    pub fn new(address: u32, image: Vec<u8>) -> RamKernel {
+        RamKernel { address, image }
+    }
+
+    /// Loads a raw kernel image from `path`
+    pub fn load(path: &Path, address: u32) -> io::Result<RamKernel> {
+        Ok(RamKernel::new(address, fs::read(path)?))
+    }
+
+    /// Returns the RAM address of the kernel
+    pub fn address(&self) -> u32 {
+        self.address
+    }
+
+    /// Returns the kernel image
+    pub fn image(&self) -> &[u8] {
+        &self.image
+    }
+}
+
+/// Direction of a [`KernelTransfer`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Direction {
+    Read,
+    Write,
+}
+
+/// Reads or writes memory through a RAM kernel. Implements [`Transfer`], so
+/// it can replace a [`Downloader`](crate::Downloader) or
+/// [`Programmer`](crate::Programmer) wherever progress is reported
+/// generically. The ECU is reset when the transfer completes or is dropped.
+pub struct KernelTransfer<'a, M: 'a + Uds> {
+    kernel: RamKernel,
+    direction: Direction,
+    /// Address ranges transferred, in order
+    segments: Vec<Region>,
+    length: usize,
+    position: usize,
+    block: usize,
+    data: Vec<u8>,
+    session: Session<'a, M>,
+    started: bool,
+    events: Events<'a>,
+    cancel: CancelToken,
+    voltage: Option<VoltageGate<'a>>,
+    allow_engine_running: bool,
+    timeouts: TimeoutProfile,
+}
+
+impl<'a, M: 'a + Uds + SetTimeout> KernelTransfer<'a, M> {
+    fn new(
+        bus: &'a mut M,
+        kernel: RamKernel,
+        direction: Direction,
+        segments: Vec<Region>,
+        data: Vec<u8>,
+    ) -> KernelTransfer<'a, M> {
+        let length = segments.iter().map(Region::len).sum();
+        let mut session = Session::new(bus);
+        // The kernel only leaves RAM through a reset
+        session.set_exit_action(ExitAction::Reset);
+        KernelTransfer {
+            kernel,
+            direction,
+            segments,
+            length,
+            position: 0,
+            block: 0,
+            data,
+            session,
+            started: false,
+            events: Events::new(),
+            cancel: CancelToken::new(),
+            voltage: None,
+            allow_engine_running: false,
+            timeouts: TimeoutProfile::default(),
+        }
+    }
+
+    /// Creates a transfer reading `length` bytes from `offset`
+    pub fn read(
+        bus: &'a mut M,
+        kernel: RamKernel,
+        offset: u32,
+        length: usize,
+    ) -> KernelTransfer<'a, M> {
+        let data = Vec::with_capacity(length);
+        let segments = vec![Region::new(offset, offset + length as u32)];
+        KernelTransfer::new(bus, kernel, Direction::Read, segments, data)
+    }
+
+    /// Creates a transfer erasing the flash covered by `data` and writing
+    /// `data` at `offset`
+    pub fn write(
+        bus: &'a mut M,
+        kernel: RamKernel,
+        offset: u32,
+        data: Vec<u8>,
+    ) -> KernelTransfer<'a, M> {
+        let segments = vec![Region::new(offset, offset + data.len() as u32)];
+        KernelTransfer::new(bus, kernel, Direction::Write, segments, data)
+    }
+
+    /// Creates a transfer erasing and writing only the sectors in `updates`,
+    /// e.g. those [`changed_sectors`](crate::diff::changed_sectors) finds.
+    /// Adjacent sectors are erased together.
+    pub fn write_sectors(
+        bus: &'a mut M,
+        kernel: RamKernel,
+        updates: Vec<SectorUpdate>,
+    ) -> KernelTransfer<'a, M> {
+        let mut segments: Vec<Region> = Vec::new();
+        let mut data = Vec::new();
+        for update in updates {
+            match segments.last_mut() {
+                Some(last) if last.end == update.sector.start => last.end = update.sector.end,
+                _ => segments.push(update.sector),
+            }
+            data.extend_from_slice(&update.data);
+        }
+        KernelTransfer::new(bus, kernel, Direction::Write, segments, data)
+    }
+
+    /// Sets the arbitration ID requests are sent to
+    pub fn set_request_id(&mut self, request_id: u32) {
+        self.session.set_request_id(request_id);
+    }
+
+    /// Sets the sink receiving status events
+    pub fn set_event_sink<S: EventSink + 'a>(&mut self, sink: S) {
+        self.events.set(sink);
+    }
+
+    /// Sets a token that aborts the transfer between blocks. The ECU is
+    /// reset before failing with [`MzrError::Cancelled`].
+    pub fn set_cancel_token(&mut self, token: CancelToken) {
+        self.cancel = token;
+    }
+
+    /// Monitors battery voltage while writing, like
+    /// [`Programmer::set_voltage_monitor`](crate::Programmer::set_voltage_monitor).
+    /// Writes refuse to start below `minimum` volts and pause while the
+    /// voltage sags.
+    pub fn set_voltage_monitor<V: VoltageMonitor + 'a>(&mut self, monitor: V, minimum: f32) {
+        self.voltage = Some(VoltageGate::new(monitor, minimum));
+    }
+
+    /// Disables the check that refuses to write while the engine is running
+    pub fn allow_engine_running(&mut self, allow: bool) {
+        self.allow_engine_running = allow;
+    }
+
+    /// Sets the timeouts used for each class of request
+    pub fn set_timeouts(&mut self, timeouts: TimeoutProfile) {
+        self.timeouts = timeouts;
+    }
+
+    /// Returns the total transfer size
+    pub fn total_size(&self) -> usize {
+        self.length
+    }
+
+    /// Returns the number of bytes transferred so far
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    fn use_timeout(&mut self, operation: Operation) {
+        let timeout = self.timeouts.get(operation);
+        self.session.bus().set_timeout(timeout);
+    }
+
+    /// Returns the address of byte `position` of the transfer and the number
+    /// of bytes left in its segment
+    fn locate(&self, position: usize) -> (u32, usize) {
+        let mut skipped = 0;
+        for segment in &self.segments {
+            if position < skipped + segment.len() {
+                let within = position - skipped;
+                return (segment.start + within as u32, segment.len() - within);
+            }
+            skipped += segment.len();
+        }
+        (self.segments.last().map_or(0, |segment| segment.end), 0)
+    }
+
+    /// Returns the number of blocks of the transfer
+    fn blocks(&self) -> usize {
+        self.segments
+            .iter()
+            .map(|segment| segment.len().div_ceil(KERNEL_BLOCK_SIZE))
+            .sum()
+    }
+
+    fn query(&mut self, service: u8, data: &[u8]) -> Result<Vec<u8>, MzrError> {
+        let request_id = self.session.request_id();
+        Ok(self.session.bus().query_uds(request_id, service, data)?)
+    }
+
+    /// Uploads and starts the kernel, then erases the target regions of a
+    /// write. Must be called before [`step`](KernelTransfer::step).
+    pub fn start(&mut self) -> Result<(), MzrError> {
+        if !self.started {
+            if self.direction == Direction::Write {
+                self.preflight()?;
+            }
+            self.use_timeout(Operation::Connect);
+            self.session
+                .enter(SESSION_PROGRAMMING)
+                .map_err(|err| err.context(Phase::Authenticating, 0, 0))?;
+            self.events.emit(Event::Connected {
+                session: SESSION_PROGRAMMING,
+            });
+            self.use_timeout(Operation::Security);
+            self.session
+                .unlock()
+                .map_err(|err| err.context(Phase::Authenticating, 0, 0))?;
+            self.events.emit(Event::Authenticated);
+            self.upload()?;
+            self.started = true;
+        }
+
+        if self.direction == Direction::Write && self.position == 0 {
+            self.events.emit(Event::EraseStarted);
+            self.use_timeout(Operation::Erase);
+            for segment in self.segments.clone() {
+                let mut request = segment.start.to_be_bytes().to_vec();
+                request.extend_from_slice(&(segment.len() as u32).to_be_bytes());
+                self.query(KERNEL_REQ_ERASE, &request)
+                    .map_err(|err| err.context(Phase::Erasing, segment.start as usize, 0))?;
+            }
+            self.events.emit(Event::EraseCompleted);
+        }
+        Ok(())
+    }
+
+    /// Refuses to write with a low battery or the engine running, before
+    /// anything is erased
+    fn preflight(&mut self) -> Result<(), MzrError> {
+        if let Some(gate) = self.voltage.as_mut() {
+            gate.check()?;
+        }
+        if !self.allow_engine_running {
+            self.use_timeout(Operation::Request);
+            let request_id = self.session.request_id();
+            self.session.bus().ensure_engine_stopped(request_id)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the kernel to RAM, jumps to it and waits for it to answer
+    fn upload(&mut self) -> Result<(), MzrError> {
+        let address = self.kernel.address;
+        let request_id = self.session.request_id();
+        self.use_timeout(Operation::Request);
+        self.session
+            .bus()
+            .request_download(request_id, address, self.kernel.image.len() as u32)
+            .map_err(|err| err.context(Phase::Uploading, address as usize, 0))?;
+        self.use_timeout(Operation::Transfer);
+        for (index, chunk) in self.kernel.image.chunks(BLOCK_SIZE).enumerate() {
+            let offset = address as usize + index * BLOCK_SIZE;
+            self.session
+                .bus()
+                .transfer_data(request_id, chunk)
+                .map_err(|err| err.context(Phase::Uploading, offset, index + 1))?;
+        }
+        self.use_timeout(Operation::Request);
+        self.session
+            .bus()
+            .transfer_exit(request_id)
+            .map_err(|err| err.context(Phase::Uploading, address as usize, 0))?;
+        self.query(UDS_REQ_STARTROUTINEBYADDRESS, &address.to_be_bytes())
+            .map_err(|err| err.context(Phase::Uploading, address as usize, 0))?;
+        self.use_timeout(Operation::Connect);
+        self.query(KERNEL_REQ_PING, &[])
+            .map_err(|err| err.context(Phase::Uploading, address as usize, 0))?;
+        self.events.emit(Event::KernelStarted { address });
+        Ok(())
+    }
+
+    /// Reads or writes the next block
+    pub fn step(&mut self) -> Result<TransferState, MzrError> {
+        if !self.started {
+            return Err(MzrError::KernelNotStarted);
+        }
+        if self.position == self.length {
+            return Ok(TransferState::Completed);
+        }
+        if let Some(gate) = self.voltage.as_mut() {
+            let session = &mut self.session;
+            gate.wait(&mut self.events, &self.cancel, || session.keep_alive())?;
+        }
+        if self.cancel.is_cancelled() {
+            self.session.exit()?;
+            return Err(MzrError::Cancelled);
+        }
+
+        let (address, left) = self.locate(self.position);
+        let length = cmp::min(left, KERNEL_BLOCK_SIZE);
+        let mut request = address.to_be_bytes().to_vec();
+        self.use_timeout(Operation::Transfer);
+        let result = match self.direction {
+            Direction::Read => {
+                request.extend_from_slice(&(length as u16).to_be_bytes());
+                self.query(KERNEL_REQ_READ, &request)
+                    .and_then(|section| match section.len() {
+                        len if len == length => Ok(section),
+                        _ => Err(MzrError::InvalidResponse),
+                    })
+                    .map(|section| self.data.extend_from_slice(&section))
+            }
+            Direction::Write => {
+                request.extend_from_slice(&self.data[self.position..self.position + length]);
+                self.query(KERNEL_REQ_WRITE, &request).map(|_| ())
+            }
+        };
+        result.map_err(|err| err.context(Phase::Transferring, address as usize, self.block + 1))?;
+
+        self.position += length;
+        self.block += 1;
+        self.events.emit(Event::BlockTransferred {
+            block: self.block,
+            blocks: self.blocks(),
+            position: self.position,
+            total: self.length,
+        });
+
+        if self.position != self.length {
+            Ok(TransferState::InProgress(self.position))
+        } else {
+            self.session.exit()?;
+            self.events.emit(Event::Completed);
+            Ok(TransferState::Completed)
+        }
+    }
+
+    /// Returns the data read, or the data written
+    pub fn take_data(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+impl<'a, M: 'a + Uds + SetTimeout> Transfer for KernelTransfer<'a, M> {
+    fn start(&mut self) -> Result<(), MzrError> {
+        KernelTransfer::start(self)
+    }
+
+    fn step(&mut self) -> Result<TransferState, MzrError> {
+        KernelTransfer::step(self)
+    }
+
+    fn total_size(&self) -> usize {
+        KernelTransfer::total_size(self)
+    }
+
+    fn position(&self) -> usize {
+        KernelTransfer::position(self)
+    }
+}
@@ -0,0 +1,258 @@
+//! KWP2000 (ISO 14230) over K-line, spoken by early MZR vehicles and some
+//! modules without diagnostic CAN. [`Kwp`] implements [`IsoTp`], so the
+//! [`Uds`](obd::Uds) and [`MzrBus`](crate::MzrBus) queries work unchanged.
+//! K-line is point-to-point: requests go to the address the session was
+//! created for, whatever arbitration ID the query names.
+
+use std::time::{Duration, Instant};
+
+use obd::IsoTp;
+use thiserror::Error;
+
+use crate::datalink::kline::KLine;
+use crate::timeout::SetTimeout;
+
+/// Baud rate of KWP2000 on K-line
+pub const KLINE_BAUDRATE: u32 = 10400;
+/// Address of the tester
+pub const TESTER_ADDRESS: u8 = 0xF1;
+/// Address of the engine ECU
+pub const ENGINE_ADDRESS: u8 = 0x10;
+
+/// Idle time after which the ECU ends the session (P3max)
+const P3_MAX: Duration = Duration::from_secs(5);
+
+const KWP_REQ_STARTCOMMUNICATION: u8 = 0x81;
+const KWP_RES_STARTCOMMUNICATION: u8 = 0xC1;
+
+/// Format byte of a physically addressed frame with address bytes
+const FORMAT_PHYSICAL: u8 = 0x80;
+/// Largest length that fits in the format byte
+const MAX_SHORT_LENGTH: usize = 0x3F;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum KwpError {
+    #[error("frame is truncated")]
+    Truncated,
+    #[error("frame has no address bytes")]
+    NoAddress,
+    #[error("bad frame checksum")]
+    Checksum,
+}
+
+/// KWP2000 frame with address bytes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KwpFrame {
+    pub target: u8,
+    pub source: u8,
+    pub data: Vec<u8>,
+}
+
+impl KwpFrame {
+    /// Encodes the frame with its header and checksum. `data` must not be
+    /// longer than 255 bytes.
+    pub fn encode(&self) -> Vec<u8> {
+        assert!(self.data.len() <= 0xFF);
+        let mut frame = Vec::with_capacity(self.data.len() + 5);
+        if self.data.len() <= MAX_SHORT_LENGTH {
+            frame.push(FORMAT_PHYSICAL | self.data.len() as u8);
+            frame.extend_from_slice(&[self.target, self.source]);
+        } else {
+            frame.push(FORMAT_PHYSICAL);
+            frame.extend_from_slice(&[self.target, self.source, self.data.len() as u8]);
+        }
+        frame.extend_from_slice(&self.data);
+        frame.push(checksum(&frame));
+        frame
+    }
+
+    /// Decodes a frame, checking its checksum
+    pub fn decode(frame: &[u8]) -> Result<KwpFrame, KwpError> {
+        let (&format, rest) = frame.split_first().ok_or(KwpError::Truncated)?;
+        // Frames without address bytes are only used by single-node buses
+        if format & 0xC0 == 0 {
+            return Err(KwpError::NoAddress);
+        }
+        if rest.len() < 3 {
+            return Err(KwpError::Truncated);
+        }
+        let (length, header_len) = match (format & 0x3F) as usize {
+            0 => (rest[2] as usize, 4),
+            length => (length, 3),
+        };
+        if frame.len() != header_len + length + 1 {
+            return Err(KwpError::Truncated);
+        }
+        let (body, sum) = frame.split_at(frame.len() - 1);
+        if checksum(body) != sum[0] {
+            return Err(KwpError::Checksum);
+        }
+        Ok(KwpFrame {
+            target: frame[1],
+            source: frame[2],
+            data: body[header_len..].to_vec(),
+        })
+    }
+}
+
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+/// KWP2000 session with an ECU on K-line. The ECU is woken up on the first
+/// request, and again if the session lapsed while idle.
+pub struct Kwp<K: KLine> {
+    line: K,
+    target: u8,
+    last_request: Option<Instant>,
+}
+
+impl<K: KLine> Kwp<K> {
+    /// Creates a session with the ECU at `target`, e.g. [`ENGINE_ADDRESS`]
+    pub fn new(line: K, target: u8) -> Kwp<K> {
+        Kwp {
+            line,
+            target,
+            last_request: None,
+        }
+    }
+
+    /// Wakes the ECU up with a fast init and starts communication
+    pub fn start_communication(&mut self) -> Result<(), obd::Error> {
+        self.line.wake_up()?;
+        self.last_request = Some(Instant::now());
+        self.send(&[KWP_REQ_STARTCOMMUNICATION])?;
+        match self.receive()?.first() {
+            Some(&KWP_RES_STARTCOMMUNICATION) => Ok(()),
+            Some(&sid) => Err(obd::Error::InvalidResponseSid(sid)),
+            None => Err(obd::Error::EmptyResponse),
+        }
+    }
+
+    fn send(&mut self, data: &[u8]) -> Result<(), obd::Error> {
+        let frame = KwpFrame {
+            target: self.target,
+            source: TESTER_ADDRESS,
+            data: data.to_vec(),
+        };
+        self.line.send_frame(&frame.encode())
+    }
+
+    /// Reads the next frame the ECU sends the tester. Corrupted frames and
+    /// traffic between other nodes are skipped.
+    fn receive(&mut self) -> Result<Vec<u8>, obd::Error> {
+        loop {
+            match KwpFrame::decode(&self.line.read_frame()?) {
+                Ok(frame) if frame.source == self.target && frame.target == TESTER_ADDRESS => {
+                    return Ok(frame.data)
+                }
+                _ => continue,
+            }
+        }
+    }
+}
+
+impl<K: KLine> IsoTp for Kwp<K> {
+    fn send_isotp(&mut self, _id: u32, data: &[u8]) -> Result<(), obd::Error> {
+        let lapsed = self.last_request.is_none_or(|last| last.elapsed() > P3_MAX);
+        if lapsed {
+            self.start_communication()?;
+        }
+        self.last_request = Some(Instant::now());
+        self.send(data)
+    }
+
+    fn read_isotp(&mut self, _id: u32) -> Result<Vec<u8>, obd::Error> {
+        self.receive()
+    }
+}
+
+impl<K: KLine + SetTimeout> SetTimeout for Kwp<K> {
+    fn set_timeout(&mut self, timeout: Duration) {
+        self.line.set_timeout(timeout);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use obd::Uds;
+    use std::collections::VecDeque;
+
+    /// K-line with an ECU answering StartCommunication and the VIN query
+    #[derive(Default)]
+    struct Ecu {
+        awake: bool,
+        started: bool,
+        responses: VecDeque<Vec<u8>>,
+    }
+
+    impl KLine for Ecu {
+        fn wake_up(&mut self) -> Result<(), obd::Error> {
+            self.awake = true;
+            Ok(())
+        }
+
+        fn send_frame(&mut self, frame: &[u8]) -> Result<(), obd::Error> {
+            let request = KwpFrame::decode(frame).unwrap();
+            assert_eq!(request.target, ENGINE_ADDRESS);
+            let data = match request.data[..] {
+                [KWP_REQ_STARTCOMMUNICATION] if self.awake => {
+                    self.started = true;
+                    vec![KWP_RES_STARTCOMMUNICATION, 0xEF, 0x8F]
+                }
+                [0x09, 0x02] if self.started => {
+                    let mut data = vec![0x49, 0x02, 0x01];
+                    data.extend_from_slice(b"JM1BK34M071234567");
+                    data
+                }
+                _ => vec![0x7F, request.data[0], 0x11],
+            };
+            // Line noise and the reply
+            self.responses.push_back(vec![0x81, 0x33]);
+            self.responses.push_back(
+                KwpFrame {
+                    target: TESTER_ADDRESS,
+                    source: ENGINE_ADDRESS,
+                    data,
+                }
+                .encode(),
+            );
+            Ok(())
+        }
+
+        fn read_frame(&mut self) -> Result<Vec<u8>, obd::Error> {
+            self.responses.pop_front().ok_or(obd::Error::EmptyResponse)
+        }
+    }
+
+    #[test]
+    fn frames() {
+        let frame = KwpFrame {
+            target: 0x10,
+            source: 0xF1,
+            data: vec![0x81],
+        };
+        assert_eq!(frame.encode(), vec![0x81, 0x10, 0xF1, 0x81, 0x03]);
+        assert_eq!(KwpFrame::decode(&frame.encode()), Ok(frame));
+
+        let long = KwpFrame {
+            target: 0x10,
+            source: 0xF1,
+            data: vec![0x55; 100],
+        };
+        let mut bytes = long.encode();
+        assert_eq!(&bytes[..4], &[0x80, 0x10, 0xF1, 100]);
+        assert_eq!(KwpFrame::decode(&bytes), Ok(long));
+        *bytes.last_mut().unwrap() ^= 1;
+        assert_eq!(KwpFrame::decode(&bytes), Err(KwpError::Checksum));
+        assert_eq!(KwpFrame::decode(&bytes[..3]), Err(KwpError::Truncated));
+    }
+
+    #[test]
+    fn queries_over_kline() {
+        let mut kwp = Kwp::new(Ecu::default(), ENGINE_ADDRESS);
+        assert_eq!(kwp.query_vin(0x7e0).unwrap(), "JM1BK34M071234567");
+        assert!(kwp.line.started);
+    }
+}
@@ -0,0 +1,337 @@
+//! Markers in log records, e.g. from a hotkey, a button on a GPIO pin or
+//! crossing a start/finish line, and the statistics of selected channels
+//! between them, so laps of a track day can be compared.
+//!
+//! ```toml
+//! [lap]
+//! finish_line = { from = [48.1173, 11.5166], to = [48.1175, 11.5170] }
+//! channels = ["rpm", "boost", "gps_speed"]
+//! ```
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::metadata::LogMetadata;
+use crate::record::{Record, RecordSink};
+
+/// Label of markers added at the finish line
+pub const LAP_MARKER: &str = "lap";
+
+/// Interval at which GPIO inputs are sampled
+const GPIO_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Line between two points, as latitude and longitude in degrees
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FinishLine {
+    pub from: [f64; 2],
+    pub to: [f64; 2],
+}
+
+/// Which side of the line through `a` and `b` point `p` is on
+fn side(a: [f64; 2], b: [f64; 2], p: [f64; 2]) -> f64 {
+    (b[0] - a[0]) * (p[1] - a[1]) - (b[1] - a[1]) * (p[0] - a[0])
+}
+
+impl FinishLine {
+    /// Whether moving from `start` to `end` crosses the line, in either
+    /// direction. Coordinates are treated as planar, which holds over the
+    /// width of a track.
+    pub fn crossed(&self, start: [f64; 2], end: [f64; 2]) -> bool {
+        let (a, b) = (self.from, self.to);
+        side(a, b, start) * side(a, b, end) < 0.0 && side(start, end, a) * side(start, end, b) < 0.0
+    }
+}
+
+/// Marker detection and lap statistics
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LapConfig {
+    /// Line whose crossing starts a lap. Requires a GPS.
+    pub finish_line: Option<FinishLine>,
+    /// Shortest lap in seconds, so GPS noise near the line isn't counted
+    /// as more laps
+    pub min_lap_time: u64,
+    /// Channels reported per lap. Empty reports every channel.
+    pub channels: Vec<String>,
+}
+
+impl Default for LapConfig {
+    fn default() -> LapConfig {
+        LapConfig {
+            finish_line: None,
+            min_lap_time: 10,
+            channels: Vec::new(),
+        }
+    }
+}
+
+/// Returns the current Unix time in milliseconds
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_millis() as u64)
+}
+
+/// Markers waiting to be added to records. Clones share the same markers,
+/// so they can be set from other threads.
+#[derive(Clone, Default)]
+pub struct Markers {
+    pending: Arc<Mutex<Vec<(u64, String)>>>,
+}
+
+impl Markers {
+    pub fn new() -> Markers {
+        Markers::default()
+    }
+
+    /// Adds a marker at the current time
+    pub fn mark(&self, label: impl Into<String>) {
+        self.pending.lock().unwrap().push((now(), label.into()));
+    }
+
+    /// Removes and returns the labels of markers set at or before `time`
+    fn take(&self, time: u64) -> Vec<String> {
+        let mut pending = self.pending.lock().unwrap();
+        let (due, later) = pending.drain(..).partition(|(at, _)| *at <= time);
+        *pending = later;
+        due.into_iter().map(|(_, label)| label).collect()
+    }
+
+    /// Adds a `gpio` marker on each rising edge of the input whose value
+    /// file is `path`, e.g. `/sys/class/gpio/gpio17/value`, read on a
+    /// background thread
+    pub fn watch_gpio(&self, path: PathBuf) {
+        let markers = self.clone();
+        thread::spawn(move || {
+            let mut previous = false;
+            loop {
+                let high = match fs::read_to_string(&path) {
+                    Ok(value) => value.trim() == "1",
+                    Err(err) => {
+                        tracing::warn!(%err, path = %path.display(), "failed to read GPIO");
+                        break;
+                    }
+                };
+                if high && !previous {
+                    markers.mark("gpio");
+                }
+                previous = high;
+                thread::sleep(GPIO_POLL_INTERVAL);
+            }
+        });
+    }
+}
+
+/// Adds pending markers, and a [`LAP_MARKER`] when the position crosses the
+/// finish line, to records before passing them on to `sink`
+pub struct MarkerInjector<S> {
+    markers: Markers,
+    finish_line: Option<FinishLine>,
+    min_lap_time: u64,
+    position: Option<[f64; 2]>,
+    last_lap: Option<u64>,
+    sink: S,
+}
+
+impl<S: RecordSink> MarkerInjector<S> {
+    pub fn new(markers: Markers, config: &LapConfig, sink: S) -> MarkerInjector<S> {
+        MarkerInjector {
+            markers,
+            finish_line: config.finish_line,
+            min_lap_time: config.min_lap_time * 1000,
+            position: None,
+            last_lap: None,
+            sink,
+        }
+    }
+}
+
+impl<S: RecordSink> RecordSink for MarkerInjector<S> {
+    fn send(&mut self, record: &Record) {
+        let mut record = record.clone();
+        record.markers.extend(self.markers.take(record.time));
+        let position = match (
+            record.values.get("latitude"),
+            record.values.get("longitude"),
+        ) {
+            (Some(&latitude), Some(&longitude)) => Some([latitude, longitude]),
+            _ => None,
+        };
+        if let (Some(line), Some(start), Some(end)) = (self.finish_line, self.position, position) {
+            let due = self
+                .last_lap
+                .is_none_or(|last| record.time.saturating_sub(last) >= self.min_lap_time);
+            if due && line.crossed(start, end) {
+                record.markers.push(LAP_MARKER.to_string());
+                self.last_lap = Some(record.time);
+            }
+        }
+        // Keep the last known position across records without a fix
+        self.position = position.or(self.position);
+        self.sink.send(&record);
+    }
+
+    fn begin_session(&mut self, metadata: &LogMetadata) {
+        self.sink.begin_session(metadata);
+    }
+
+    fn flush(&mut self) {
+        self.sink.flush();
+    }
+}
+
+/// Minimum, maximum and average of a channel
+#[derive(Debug, Copy, Clone, PartialEq, Serialize)]
+pub struct ChannelStats {
+    pub min: f64,
+    pub max: f64,
+    pub avg: f64,
+    pub samples: u64,
+}
+
+impl ChannelStats {
+    fn new(value: f64) -> ChannelStats {
+        ChannelStats {
+            min: value,
+            max: value,
+            avg: value,
+            samples: 1,
+        }
+    }
+
+    fn add(&mut self, value: f64) {
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.samples += 1;
+        self.avg += (value - self.avg) / self.samples as f64;
+    }
+}
+
+/// Records between two markers
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Lap {
+    /// 0 for the records before the first marker, e.g. the out lap
+    pub number: usize,
+    /// Label of the marker that started the lap
+    pub marker: Option<String>,
+    /// Unix time in milliseconds of the first record
+    pub start: u64,
+    /// Unix time in milliseconds of the first record of the next lap, or of
+    /// the last record
+    pub end: u64,
+    pub channels: BTreeMap<String, ChannelStats>,
+}
+
+impl Lap {
+    pub fn duration(&self) -> Duration {
+        Duration::from_millis(self.end - self.start)
+    }
+}
+
+/// Splits `records` into laps at every marker and returns the statistics
+/// of `channels`, or of every channel if empty, in each lap
+pub fn laps(records: &[Record], channels: &[String]) -> Vec<Lap> {
+    let mut laps: Vec<Lap> = Vec::new();
+    for record in records {
+        let starts_lap = !record.markers.is_empty();
+        if starts_lap || laps.is_empty() {
+            if let Some(previous) = laps.last_mut() {
+                previous.end = record.time;
+            }
+            let number = if starts_lap {
+                laps.iter().filter(|lap| lap.marker.is_some()).count() + 1
+            } else {
+                0
+            };
+            laps.push(Lap {
+                number,
+                marker: record.markers.first().cloned(),
+                start: record.time,
+                end: record.time,
+                channels: BTreeMap::new(),
+            });
+        }
+        let lap = laps.last_mut().unwrap();
+        lap.end = record.time;
+        for (name, &value) in &record.values {
+            if !channels.is_empty() && !channels.contains(name) {
+                continue;
+            }
+            lap.channels
+                .entry(name.clone())
+                .and_modify(|stats| stats.add(value))
+                .or_insert_with(|| ChannelStats::new(value));
+        }
+    }
+    laps
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::{read_records, RecordWriter};
+
+    #[test]
+    fn splits_laps_at_markers() {
+        let config: LapConfig = toml::from_str(
+            "min_lap_time = 3\nfinish_line = { from = [0.0, 0.0], to = [0.0, 1.0] }",
+        )
+        .unwrap();
+        let markers = Markers::new();
+        let mut output = Vec::new();
+        let mut injector =
+            MarkerInjector::new(markers.clone(), &config, RecordWriter::new(&mut output));
+        // Crossing latitude 0 every 2 s
+        let positions = [-0.1, 0.1, 0.2, -0.2, -0.1, 0.1, 0.15, 0.1, -0.1];
+        for (i, &latitude) in positions.iter().enumerate() {
+            let mut record = Record::new();
+            record.time = i as u64 * 1000;
+            record.values.insert("latitude".to_string(), latitude);
+            record.values.insert("longitude".to_string(), 0.5);
+            record
+                .values
+                .insert("rpm".to_string(), 1000.0 * (i + 1) as f64);
+            if i == 6 {
+                markers.mark("button");
+                // Markers are added to the first record after them
+                record.time = u64::MAX;
+            }
+            injector.send(&record);
+        }
+        injector.flush();
+        drop(injector);
+
+        let records = read_records(&output[..]).unwrap();
+        assert_eq!(records.len(), positions.len());
+        assert_eq!(records[1].markers, ["lap"]);
+        // Crossing again before `min_lap_time`
+        assert!(records[3].markers.is_empty());
+        assert_eq!(records[5].markers, ["lap"]);
+        assert_eq!(records[6].markers, ["button"]);
+        assert_eq!(records[8].markers, ["lap"]);
+        assert!(!FinishLine {
+            from: [0.0, 0.0],
+            to: [0.0, 1.0]
+        }
+        .crossed([-1.0, 2.0], [1.0, 2.0]));
+
+        let laps = laps(&records[..6], &["rpm".to_string()]);
+        assert_eq!(laps.len(), 3);
+        assert_eq!(laps[0].number, 0);
+        assert_eq!(laps[1].number, 1);
+        assert_eq!(laps[1].marker.as_deref(), Some("lap"));
+        assert_eq!(laps[1].duration(), Duration::from_secs(4));
+        let rpm = laps[1].channels["rpm"];
+        assert_eq!(
+            (rpm.min, rpm.max, rpm.avg, rpm.samples),
+            (2000.0, 5000.0, 3500.0, 4)
+        );
+        assert_eq!(laps[2].channels.len(), 1);
+    }
+}
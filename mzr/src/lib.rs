@@ -1,12 +1,134 @@
 use obd::Uds;
+use rand_core::{OsRng, RngCore};
 use std::cmp;
+use std::thread;
+use std::time::{Duration, Instant};
 use thiserror::Error;
+use tracing::{debug, debug_span, info, info_span, trace, warn};
 
-static MZR_KEY: &'static str = "MazdA";
+pub use builder::{DownloaderBuilder, ProgrammerBuilder, RetryPolicy};
+use cancel::CancelToken;
+use chunk::ChunkSize;
+use event::{Event, EventSink, Events};
+use iter::TransferIter;
+use memory_map::{MzrMemoryMap, Region};
+use partial::PartialFile;
+use pause::PauseToken;
+use profile::KeyAlgorithm;
+use progress::{Phase, ProgressObserver, RateMeter};
+use session::Session;
+use stats::TransferStats;
+use timeout::{Operation, SetTimeout, TimeoutProfile};
+pub use transfer::{Transfer, TransferState};
+use voltage::{VoltageGate, VoltageMonitor};
 
+pub mod actuator;
+pub mod backup;
+pub mod binlog;
+pub mod builder;
+pub mod cancel;
+pub mod capture;
+pub mod chunk;
+pub mod coding;
+pub mod config;
+pub mod container;
+pub mod daemon;
+pub mod dashboard;
+pub mod datalink;
+pub mod dtc;
+pub mod eeprom;
+pub mod event;
+pub mod gps;
+pub mod hash;
+pub mod history;
+pub mod image;
+pub mod influx;
+pub mod isotp;
+pub mod iter;
+pub mod kernel;
+pub mod kwp;
+pub mod lap;
+pub mod metadata;
+pub mod mlg;
+#[cfg(test)]
+mod mock;
+pub mod module;
+pub mod mqtt;
+pub mod o2;
+pub mod output;
+pub mod partial;
+#[cfg(feature = "passthru")]
+pub mod passthru;
+pub mod pause;
+pub mod peek;
+pub mod probe;
+pub mod profile;
+pub mod progress;
+pub mod reconnect;
+pub mod record;
+pub mod repl;
+#[cfg(feature = "passthru")]
+pub mod selftest;
+pub mod session;
+pub mod shared;
+pub mod signing;
+pub mod stats;
+pub mod table;
+pub mod timeout;
+pub mod trace;
+pub mod transfer;
+pub mod vin;
+pub mod voltage;
 
-const UDS_REQ_REQUESTDOWNLOAD: u8 = 0x34;
-const UDS_REQ_TRANSFERDATA: u8 = 0x36;
+pub use mzr_core::key::security_key;
+use mzr_core::uds::{
+    self, NRC_SECURITY_ACCESS_DENIED, NRC_SERVICE_NOT_SUPPORTED_IN_SESSION, UDS_REQ_ERASE,
+    UDS_REQ_READDATABYIDENTIFIER, UDS_REQ_REQUESTDOWNLOAD, UDS_REQ_ROUTINECONTROL,
+    UDS_REQ_SECURITY, UDS_REQ_TESTERPRESENT, UDS_REQ_TRANSFERDATA, UDS_REQ_TRANSFEREXIT,
+    UDS_REQ_WRITEMEMORYBYADDRESS,
+};
+pub use mzr_rom::{calibration, checksum, diff, memory_map, transplant};
+
+/// Maximum payload of a single read or transfer request
+const BLOCK_SIZE: usize = 0xFFE;
+
+/// Default number of times a failed read is retried by [`Downloader::run`]
+const READ_RETRIES: usize = 3;
+
+/// Arbitration ID the ECU receives diagnostic requests on
+pub const DEFAULT_REQUEST_ID: u32 = 0x7e0;
+
+/// Default diagnostic session
+const SESSION_DEFAULT: u8 = 0x81;
+/// Diagnostic session used to read memory
+pub const SESSION_DOWNLOAD: u8 = 0x87;
+/// Diagnostic session used to read memory on naturally aspirated MZR ECUs
+pub const SESSION_DOWNLOAD_NA: u8 = 0x86;
+/// Diagnostic session used to program flash
+pub const SESSION_PROGRAMMING: u8 = 0x85;
+
+/// Security access level granting memory access
+pub const SECURITY_LEVEL_DEFAULT: u8 = 0x01;
+
+/// Parameters of the bootloader's erase routine on MZR-DISI ECUs
+pub const ERASE_ROUTINE_DEFAULT: [u8; 3] = [0x00, 0xB2, 0x00];
+
+/// Sub-function starting a routine with RoutineControl
+const ROUTINE_START: u8 = 0x01;
+/// Result reported by the checksum routine for a valid region
+const CHECKSUM_VALID: u8 = 0x00;
+
+/// How long recovery mode waits for the ECU to answer
+const RECOVERY_TIMEOUT: Duration = Duration::from_secs(60);
+/// Delay between connection attempts in recovery mode
+const RECOVERY_RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+const OBD_REQ_CURRENTDATA: u8 = 0x01;
+const OBD_REQ_VEHICLEINFO: u8 = 0x09;
+const OBD_PID_RPM: u8 = 0x0C;
+const OBD_PID_VIN: u8 = 0x02;
+const OBD_PID_CALIBRATION_ID: u8 = 0x04;
+const OBD_PID_ECU_NAME: u8 = 0x0A;
 
 #[derive(Error, Debug)]
 pub enum MzrError {
@@ -14,83 +136,397 @@ pub enum MzrError {
     EmptyPacket,
     #[error("flash memory must be erased before programming")]
     NotErased,
+    #[error("refusing to program the bootloader region (0x{offset:X}, {length} bytes)")]
+    BootloaderWrite { offset: u32, length: usize },
+    #[error("RAM kernel has not been started")]
+    KernelNotStarted,
+    #[error("operation cancelled")]
+    Cancelled,
+    #[error("battery voltage too low ({0:.1} V)")]
+    LowVoltage(f32),
+    #[error("engine is running ({0:.0} RPM)")]
+    EngineRunning(f32),
+    #[error("invalid response")]
+    InvalidResponse,
+    #[error("verification failed at 0x{0:X}")]
+    VerifyFailed(u32),
+    #[error("the ECU reports a bad checksum for 0x{start:X}-0x{end:X}")]
+    ChecksumRejected { start: u32, end: u32 },
+    #[error("SHA-256 mismatch (expected {expected}, got {actual})")]
+    HashMismatch { expected: String, actual: String },
     #[error("transmission error: {0}")]
     Obd(#[from] obd::Error),
+    #[error("failed to write partial download: {0}")]
+    Io(#[from] std::io::Error),
+    /// A request failed during an operation
+    #[error("{phase:?} failed at offset 0x{offset:X} (block {block}): {source}")]
+    Context {
+        phase: Phase,
+        offset: usize,
+        block: usize,
+        source: Box<MzrError>,
+    },
 }
 
-/// Trait for MZR-DISI specific operations.
-pub trait MzrBus {
-    fn authenticate(&mut self, session_id: u8) -> Result<(), MzrError>;
-    fn request_download(&mut self, offset: u32, length: u32) -> Result<(), MzrError>;
-    fn transfer_data(&mut self, data: &[u8]) -> Result<(), MzrError>;
+impl MzrError {
+    /// Wraps bus errors with the phase, offset and block index of the
+    /// failed request. Other errors are returned unchanged.
+    pub(crate) fn context(self, phase: Phase, offset: usize, block: usize) -> MzrError {
+        match self {
+            MzrError::Obd(_) | MzrError::InvalidResponse | MzrError::EmptyPacket => {
+                MzrError::Context {
+                    phase,
+                    offset,
+                    block,
+                    source: Box::new(self),
+                }
+            }
+            err => err,
+        }
+    }
+
+    /// Returns the underlying bus error, if any
+    pub fn bus_error(&self) -> Option<&obd::Error> {
+        match self {
+            MzrError::Obd(err) => Some(err),
+            MzrError::Context { source, .. } => source.bus_error(),
+            _ => None,
+        }
+    }
+
+    /// Returns the negative response code sent by the ECU, if any
+    pub fn negative_response_code(&self) -> Option<u8> {
+        match self.bus_error() {
+            Some(obd::Error::NegativeResponse(code)) => *code,
+            _ => None,
+        }
+    }
 }
 
+/// Trait for MZR-DISI specific operations. Requests are sent to
+/// `arbitration_id`, and responses are expected on `arbitration_id + 8`.
+pub trait MzrBus {
+    fn authenticate(&mut self, arbitration_id: u32, session_id: u8) -> Result<(), MzrError>;
+    /// Requests security access at `level` in the active diagnostic session
+    fn unlock(&mut self, arbitration_id: u32, level: u8) -> Result<(), MzrError>;
+    /// Requests security access, answering the seed with `algorithm`
+    fn unlock_with(
+        &mut self,
+        arbitration_id: u32,
+        level: u8,
+        algorithm: &KeyAlgorithm,
+    ) -> Result<(), MzrError>;
+    fn request_download(
+        &mut self,
+        arbitration_id: u32,
+        offset: u32,
+        length: u32,
+    ) -> Result<(), MzrError>;
+    fn transfer_data(&mut self, arbitration_id: u32, data: &[u8]) -> Result<(), MzrError>;
+    /// Ends a transfer started with [`request_download`](MzrBus::request_download)
+    fn transfer_exit(&mut self, arbitration_id: u32) -> Result<(), MzrError>;
+    /// Returns the ECU to the default diagnostic session
+    fn exit_session(&mut self, arbitration_id: u32) -> Result<(), MzrError>;
+    /// Keeps the active diagnostic session alive
+    fn tester_present(&mut self, arbitration_id: u32) -> Result<(), MzrError>;
+    /// Reads the engine speed in RPM
+    fn engine_rpm(&mut self, arbitration_id: u32) -> Result<f32, MzrError>;
+    /// Fails with [`MzrError::EngineRunning`] unless the engine is stopped
+    fn ensure_engine_stopped(&mut self, arbitration_id: u32) -> Result<(), MzrError>;
+    /// Reads the calibration ID of the installed software
+    fn calibration_id(&mut self, arbitration_id: u32) -> Result<String, MzrError>;
+    /// Reads the name of the ECU
+    fn ecu_name(&mut self, arbitration_id: u32) -> Result<String, MzrError>;
+    /// Reads the data identified by `did` with ReadDataByIdentifier
+    fn read_identifier(&mut self, arbitration_id: u32, did: u16) -> Result<Vec<u8>, MzrError>;
+    /// Writes `data` at `address` with WriteMemoryByAddress
+    fn write_memory(
+        &mut self,
+        arbitration_id: u32,
+        address: u32,
+        data: &[u8],
+    ) -> Result<(), MzrError>;
+}
 
 impl<T> MzrBus for T
 where
     T: Uds,
 {
-    fn authenticate(&mut self, session_id: u8) -> Result<(), MzrError> {
-        self.set_diagnostic_session(0x7e0, session_id)?;
-        let seed = self.request_security_seed(0x7e0)?;
-        let key = generate_key(MZR_KEY, 0xC541A9, &seed);
-        self.request_security_key(0x7e0, &key)?;
+    fn authenticate(&mut self, arbitration_id: u32, session_id: u8) -> Result<(), MzrError> {
+        self.set_diagnostic_session(arbitration_id, session_id)?;
+        self.unlock(arbitration_id, SECURITY_LEVEL_DEFAULT)
+    }
+
+    fn unlock(&mut self, arbitration_id: u32, level: u8) -> Result<(), MzrError> {
+        self.unlock_with(arbitration_id, level, &KeyAlgorithm::Mazda)
+    }
+
+    fn unlock_with(
+        &mut self,
+        arbitration_id: u32,
+        level: u8,
+        algorithm: &KeyAlgorithm,
+    ) -> Result<(), MzrError> {
+        let response = query(self, arbitration_id, UDS_REQ_SECURITY, &[level])?;
+        let seed = match response.split_first() {
+            Some((&access_type, seed)) if access_type == level => seed,
+            _ => return Err(MzrError::InvalidResponse),
+        };
+        let key = algorithm.key(seed);
+
+        query(
+            self,
+            arbitration_id,
+            UDS_REQ_SECURITY,
+            &uds::send_key(level, key),
+        )?;
+
+        Ok(())
+    }
 
+    fn request_download(
+        &mut self,
+        arbitration_id: u32,
+        offset: u32,
+        length: u32,
+    ) -> Result<(), MzrError> {
+        let request = uds::request_download(offset, length);
+        query(self, arbitration_id, UDS_REQ_REQUESTDOWNLOAD, &request)?;
         Ok(())
     }
 
-    fn request_download(&mut self, offset: u32, length: u32) -> Result<(), MzrError> {
-        let mut req = [0; 8];
-        req[0] = ((offset & 0xFF000000) >> 24) as u8;
-        req[1] = ((offset & 0xFF0000) >> 16) as u8;
-        req[2] = ((offset & 0xFF00) >> 8) as u8;
-        req[3] = (offset & 0xFF) as u8;
+    fn transfer_data(&mut self, arbitration_id: u32, data: &[u8]) -> Result<(), MzrError> {
+        query(self, arbitration_id, UDS_REQ_TRANSFERDATA, data)?;
+        Ok(())
+    }
 
-        req[4] = ((length & 0xFF000000) >> 24) as u8;
-        req[5] = ((length & 0xFF0000) >> 16) as u8;
-        req[6] = ((length & 0xFF00) >> 8) as u8;
-        req[7] = (length & 0xFF) as u8;
+    fn transfer_exit(&mut self, arbitration_id: u32) -> Result<(), MzrError> {
+        query(self, arbitration_id, UDS_REQ_TRANSFEREXIT, &[])?;
+        Ok(())
+    }
 
-        self.query_uds(0x7e0, UDS_REQ_REQUESTDOWNLOAD, &req)?;
+    fn exit_session(&mut self, arbitration_id: u32) -> Result<(), MzrError> {
+        self.set_diagnostic_session(arbitration_id, SESSION_DEFAULT)?;
         Ok(())
     }
 
-    fn transfer_data(&mut self, data: &[u8]) -> Result<(), MzrError> {
-        self.query_uds(0x7e0, UDS_REQ_TRANSFERDATA, data)?;
+    fn tester_present(&mut self, arbitration_id: u32) -> Result<(), MzrError> {
+        query(self, arbitration_id, UDS_REQ_TESTERPRESENT, &[0x00])?;
+        Ok(())
+    }
+
+    fn engine_rpm(&mut self, arbitration_id: u32) -> Result<f32, MzrError> {
+        let response = query(self, arbitration_id, OBD_REQ_CURRENTDATA, &[OBD_PID_RPM])?;
+        match response[..] {
+            [OBD_PID_RPM, a, b, ..] => Ok(((a as u32) << 8 | b as u32) as f32 / 4.0),
+            _ => Err(MzrError::InvalidResponse),
+        }
+    }
+
+    fn ensure_engine_stopped(&mut self, arbitration_id: u32) -> Result<(), MzrError> {
+        let rpm = self.engine_rpm(arbitration_id)?;
+        if rpm > 0.0 {
+            return Err(MzrError::EngineRunning(rpm));
+        }
+        Ok(())
+    }
+
+    fn calibration_id(&mut self, arbitration_id: u32) -> Result<String, MzrError> {
+        let response = query(
+            self,
+            arbitration_id,
+            OBD_REQ_VEHICLEINFO,
+            &[OBD_PID_CALIBRATION_ID],
+        )?;
+        info_string(OBD_PID_CALIBRATION_ID, &response)
+    }
+
+    fn ecu_name(&mut self, arbitration_id: u32) -> Result<String, MzrError> {
+        let response = query(
+            self,
+            arbitration_id,
+            OBD_REQ_VEHICLEINFO,
+            &[OBD_PID_ECU_NAME],
+        )?;
+        info_string(OBD_PID_ECU_NAME, &response)
+    }
+
+    fn read_identifier(&mut self, arbitration_id: u32, did: u16) -> Result<Vec<u8>, MzrError> {
+        let response = query(
+            self,
+            arbitration_id,
+            UDS_REQ_READDATABYIDENTIFIER,
+            &did.to_be_bytes(),
+        )?;
+        match response.get(..2) {
+            Some(id) if id == did.to_be_bytes() => Ok(response[2..].to_vec()),
+            _ => Err(MzrError::InvalidResponse),
+        }
+    }
+
+    fn write_memory(
+        &mut self,
+        arbitration_id: u32,
+        address: u32,
+        data: &[u8],
+    ) -> Result<(), MzrError> {
+        let header = uds::write_memory_header(address, data.len() as u16);
+        let req = [&header[..], data].concat();
+        query(self, arbitration_id, UDS_REQ_WRITEMEMORYBYADDRESS, &req)?;
         Ok(())
     }
 }
 
-pub enum DownloadState {
-    // Progress (length downloaded)
-    InProgress(usize),
-    Completed,
+/// Sends a UDS request, recording it and its outcome with `tracing`
+fn query<T: Uds>(
+    bus: &mut T,
+    arbitration_id: u32,
+    service: u8,
+    data: &[u8],
+) -> Result<Vec<u8>, obd::Error> {
+    let service_name = trace::service_name(service);
+    let _span = debug_span!("uds", arbitration_id, service = service_name).entered();
+    let start = Instant::now();
+    let result = bus.query_uds(arbitration_id, service, data);
+    let elapsed_ms = start.elapsed().as_secs_f64() * 1000.0;
+    match &result {
+        Ok(response) => trace!(
+            request_len = data.len(),
+            response_len = response.len(),
+            elapsed_ms,
+            "response"
+        ),
+        Err(err) => debug!(request_len = data.len(), elapsed_ms, %err, "request failed"),
+    }
+    result
+}
+
+/// Decodes a vehicle information response of the form
+/// `[pid, count, text...]`, with the text padded with nulls
+fn info_string(pid: u8, response: &[u8]) -> Result<String, MzrError> {
+    match response {
+        [p, _count, text @ ..] if *p == pid => Ok(String::from_utf8_lossy(text)
+            .trim_end_matches('\0')
+            .to_string()),
+        _ => Err(MzrError::InvalidResponse),
+    }
 }
 
+/// State after a [`Downloader`] step
+pub type DownloadState = TransferState;
+
 pub struct Downloader<'a, M: 'a + Uds> {
     offset: u32,
     remaining: usize,
+    block: usize,
     data: Vec<u8>,
-    bus: &'a mut M,
+    memory_map: MzrMemoryMap,
+    session_id: u8,
+    session: Session<'a, M>,
+    events: Events<'a>,
+    cancel: CancelToken,
+    timeouts: TimeoutProfile,
+    chunk: ChunkSize,
+    retry: RetryPolicy,
+    spot_check: u8,
+    stats: TransferStats,
+    partial: Option<PartialFile>,
 }
 
-impl<'a, M: 'a + Uds> Downloader<'a, M> {
+impl<'a, M: 'a + Uds + SetTimeout> Downloader<'a, M> {
     pub fn new(bus: &'a mut M) -> Downloader<'a, M> {
-        Downloader {
-            offset: 0,
-            remaining: 1024 * 1024,
-            data: Vec::with_capacity(1024 * 1024),
-            bus,
-        }
+        DownloaderBuilder::new().build(bus)
     }
 
-    /// Returns the total download size
+    /// Returns the total download size, which is the size of the flash
     pub fn total_size(&self) -> usize {
-        1024 * 1024
+        self.memory_map.flash_size()
+    }
+
+    /// Returns the layout of the ECU
+    pub fn memory_map(&self) -> &MzrMemoryMap {
+        &self.memory_map
+    }
+
+    /// Returns the number of bytes downloaded so far
+    pub fn position(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Sets the sink receiving status events. This can be a closure or an
+    /// mpsc [`Sender`](std::sync::mpsc::Sender).
+    pub fn set_event_sink<S: EventSink + 'a>(&mut self, sink: S) {
+        self.events.set(sink);
+    }
+
+    /// Sets a token that aborts the download between blocks. A cancelled
+    /// download returns the ECU to the default session and fails with
+    /// [`MzrError::Cancelled`].
+    pub fn set_cancel_token(&mut self, token: CancelToken) {
+        self.cancel = token;
+    }
+
+    /// Sets the timeouts used for each class of request
+    pub fn set_timeouts(&mut self, timeouts: TimeoutProfile) {
+        self.timeouts = timeouts;
+    }
+
+    /// Sets the number of bytes requested per read. By default the size
+    /// adapts to the adapter, shrinking after failed reads and growing again
+    /// after successful ones.
+    pub fn set_chunk_size(&mut self, size: Option<usize>) {
+        self.chunk = match size {
+            Some(size) => ChunkSize::fixed(size),
+            None => ChunkSize::adaptive(),
+        };
+    }
+
+    /// Returns the number of bytes requested by the next read
+    pub fn chunk_size(&self) -> usize {
+        self.chunk.get()
+    }
+
+    /// Returns statistics collected so far
+    pub fn stats(&self) -> &TransferStats {
+        &self.stats
+    }
+
+    /// Continues an interrupted download, skipping the `data` already read
+    pub fn resume(&mut self, data: Vec<u8>) {
+        let length = cmp::min(data.len(), self.total_size());
+        self.offset = self.memory_map.flash().start + length as u32;
+        self.remaining = self.total_size() - length;
+        self.block = length.div_ceil(BLOCK_SIZE);
+        self.data = data;
+        self.data.truncate(length);
+    }
+
+    /// Writes each received block to `file`, so the download can be resumed
+    /// if it is interrupted
+    pub fn set_partial_file(&mut self, file: PartialFile) {
+        self.partial = Some(file);
+    }
+
+    fn use_timeout(&mut self, operation: Operation) {
+        let timeout = self.timeouts.get(operation);
+        self.session.bus().set_timeout(timeout);
     }
 
     pub fn start(&mut self) -> Result<(), MzrError> {
-        self.bus.authenticate(0x87)
+        info!(session = self.session_id, "authenticating");
+        self.use_timeout(Operation::Connect);
+        self.session
+            .enter(self.session_id)
+            .map_err(|err| err.context(Phase::Authenticating, 0, 0))?;
+        self.events.emit(Event::Connected {
+            session: self.session_id,
+        });
+        self.use_timeout(Operation::Security);
+        self.session
+            .unlock()
+            .map_err(|err| err.context(Phase::Authenticating, 0, 0))?;
+        self.events.emit(Event::Authenticated);
+        Ok(())
     }
 
     /// Next download step
@@ -98,57 +534,232 @@ impl<'a, M: 'a + Uds> Downloader<'a, M> {
         if self.remaining == 0 {
             return Ok(DownloadState::Completed);
         }
-        let section = self.bus.read_memory_address(
-            0x7e0,
-            self.offset,
-            cmp::min(self.remaining, 0xFFE) as u16,
-        )?;
-        if section.is_empty() {
-            return Err(MzrError::EmptyPacket);
+        if self.cancel.is_cancelled() {
+            self.session.exit()?;
+            return Err(MzrError::Cancelled);
+        }
+        let length = cmp::min(self.remaining, self.chunk.get()) as u16;
+        debug!(offset = self.offset, length, "reading block");
+        self.use_timeout(Operation::Transfer);
+        let request_id = self.session.request_id();
+        let result = match self
+            .session
+            .bus()
+            .read_memory_address(request_id, self.offset, length)
+        {
+            Err(obd::Error::NegativeResponse(Some(code))) if is_session_lapsed(code) => {
+                // The session timed out. Re-authenticate and retry the same
+                // offset once; a second failure is returned to the caller.
+                warn!(
+                    offset = self.offset,
+                    nrc = code,
+                    "session lapsed, re-authenticating"
+                );
+                *self.stats.nrcs.entry(code).or_insert(0) += 1;
+                self.stats.session_recoveries += 1;
+                self.use_timeout(Operation::Connect);
+                self.session.enter(self.session_id)?;
+                self.use_timeout(Operation::Security);
+                self.session.unlock()?;
+                self.events.emit(Event::SessionRecovered {
+                    offset: self.offset as usize,
+                });
+                self.use_timeout(Operation::Transfer);
+                self.session
+                    .bus()
+                    .read_memory_address(request_id, self.offset, length)
+            }
+            result => result,
+        };
+        let section = match result {
+            Ok(section) if !section.is_empty() => section,
+            Ok(_) => {
+                self.chunk.failed();
+                return Err(MzrError::EmptyPacket.context(
+                    Phase::Transferring,
+                    self.offset as usize,
+                    self.block + 1,
+                ));
+            }
+            Err(err) => {
+                self.chunk.failed();
+                return Err(MzrError::from(err).context(
+                    Phase::Transferring,
+                    self.offset as usize,
+                    self.block + 1,
+                ));
+            }
+        };
+        self.chunk.succeeded();
+        self.stats.bytes += section.len();
+        if let Some(ref mut partial) = self.partial {
+            partial.append(&section)?;
         }
 
         // Add response to buffer
         self.data.extend_from_slice(&section);
         self.offset += section.len() as u32;
-        self.remaining -= section.len();
+        self.remaining -= cmp::min(section.len(), self.remaining);
+        self.block += 1;
+        self.events.emit(Event::BlockTransferred {
+            block: self.block,
+            // Estimated, as the chunk size may change
+            blocks: self.block + self.remaining.div_ceil(self.chunk.get()),
+            position: self.data.len(),
+            total: self.total_size(),
+        });
 
         if self.remaining > 0 {
             Ok(DownloadState::InProgress(self.data.len()))
         } else {
+            self.events.emit(Event::Completed);
             Ok(DownloadState::Completed)
         }
     }
 
-    pub fn take_data(self) -> Vec<u8> {
-        self.data
+    /// Re-reads the share of blocks set with
+    /// [`spot_check`](DownloaderBuilder::spot_check), chosen at random, and
+    /// compares them with the data downloaded. Fails with
+    /// [`MzrError::VerifyFailed`] at the first differing address. Returns
+    /// the number of blocks checked.
+    pub fn spot_check(&mut self) -> Result<usize, MzrError> {
+        let start = self.memory_map.flash().start;
+        let block_size = self.chunk.get();
+        let blocks = self.data.len().div_ceil(block_size);
+        let count = (blocks * self.spot_check as usize).div_ceil(100);
+        info!(count, blocks, "spot-checking blocks");
+        let request_id = self.session.request_id();
+        self.use_timeout(Operation::Transfer);
+        for index in sample(blocks, count) {
+            let position = index * block_size;
+            let length = cmp::min(self.data.len() - position, block_size);
+            let address = start + position as u32;
+            let mut attempt = 0;
+            let section = loop {
+                match self
+                    .session
+                    .bus()
+                    .read_memory_address(request_id, address, length as u16)
+                {
+                    Ok(section) => break section,
+                    Err(err) if attempt < self.retry.attempts => {
+                        attempt += 1;
+                        warn!(attempt, %err, "retrying spot check");
+                        thread::sleep(self.retry.delay);
+                        self.stats.retries += 1;
+                    }
+                    Err(err) => {
+                        return Err(MzrError::from(err).context(
+                            Phase::Verifying,
+                            address as usize,
+                            index + 1,
+                        ))
+                    }
+                }
+            };
+            let expected = &self.data[position..position + length];
+            if let Some(i) = (0..length).find(|&i| section.get(i) != Some(&expected[i])) {
+                warn!(address = address + i as u32, "spot check mismatch");
+                return Err(MzrError::VerifyFailed(address + i as u32));
+            }
+        }
+        self.events.emit(Event::Verified);
+        Ok(count)
+    }
+
+    /// Returns an iterator that downloads one block per item, yielding the
+    /// progress. [`start`](Downloader::start) must be called first.
+    pub fn iter(&mut self) -> TransferIter<'_, Self> {
+        TransferIter::new(self)
     }
-}
 
+    /// Authenticates and downloads the entire ROM, reporting progress to
+    /// `observer`. Failed reads are retried.
+    pub fn run<O: ProgressObserver>(&mut self, observer: &mut O) -> Result<(), MzrError> {
+        let result = self.run_phases(observer);
+        if let Err(ref err) = result {
+            self.stats.record_error(err);
+        }
+        result
+    }
 
+    fn run_phases<O: ProgressObserver>(&mut self, observer: &mut O) -> Result<(), MzrError> {
+        let _span =
+            info_span!("download", offset = self.offset, total = self.total_size()).entered();
+        self.stats.enter_phase(Phase::Authenticating);
+        observer.on_phase_change(Phase::Authenticating);
+        self.start()?;
+        self.stats.enter_phase(Phase::Transferring);
+        observer.on_phase_change(Phase::Transferring);
+        let mut meter = RateMeter::resumed(self.total_size(), self.data.len());
+        let mut attempt = 0;
+        loop {
+            match self.step() {
+                Ok(DownloadState::InProgress(position)) => {
+                    attempt = 0;
+                    observer.on_progress(&meter.update(position));
+                }
+                Ok(DownloadState::Completed) => break,
+                Err(MzrError::Cancelled) => return Err(MzrError::Cancelled),
+                Err(err) if attempt < self.retry.attempts => {
+                    attempt += 1;
+                    warn!(attempt, %err, "retrying block");
+                    thread::sleep(self.retry.delay);
+                    self.stats.retries += 1;
+                    self.stats.record_error(&err);
+                    observer.on_retry(attempt, &err);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        observer.on_progress(&meter.update(self.total_size()));
+        if self.spot_check > 0 {
+            self.stats.enter_phase(Phase::Verifying);
+            observer.on_phase_change(Phase::Verifying);
+            self.spot_check()?;
+        }
+        self.stats.enter_phase(Phase::Completed);
+        observer.on_phase_change(Phase::Completed);
+        Ok(())
+    }
 
-pub enum ProgrammerState {
-    // Progress (length uploaded)
-    InProgress(usize),
-    Completed,
+    pub fn take_data(self) -> Vec<u8> {
+        self.data
+    }
 }
 
+/// State after a [`Programmer`] step
+pub type ProgrammerState = TransferState;
+
 pub struct Programmer<'a, M: 'a + Uds> {
     offset: u32,
     position: usize,
+    block: usize,
+    block_size: usize,
     data: Vec<u8>,
-    bus: &'a mut M,
+    memory_map: MzrMemoryMap,
+    erase_routine: Vec<u8>,
+    session_id: u8,
+    session: Session<'a, M>,
     erased: bool,
+    events: Events<'a>,
+    cancel: CancelToken,
+    pause: PauseToken,
+    voltage: Option<VoltageGate<'a>>,
+    allow_engine_running: bool,
+    allow_bootloader_write: bool,
+    recovery: bool,
+    timeouts: TimeoutProfile,
+    verify: bool,
+    checksum_routine: Option<u16>,
+    transfer_exited: bool,
+    retry: RetryPolicy,
+    stats: TransferStats,
 }
 
-impl<'a, M: 'a + Uds> Programmer<'a, M> {
+impl<'a, M: 'a + Uds + SetTimeout> Programmer<'a, M> {
     pub fn new(bus: &'a mut M, offset: u32, data: Vec<u8>) -> Programmer<'a, M> {
-        Programmer {
-            offset,
-            position: 0,
-            data,
-            bus,
-            erased: false,
-        }
+        ProgrammerBuilder::new().build(bus, offset, data)
     }
 
     /// Returns the total data length
@@ -156,12 +767,177 @@ impl<'a, M: 'a + Uds> Programmer<'a, M> {
         self.data.len()
     }
 
+    /// Returns the number of bytes programmed so far
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Returns the layout of the ECU
+    pub fn memory_map(&self) -> &MzrMemoryMap {
+        &self.memory_map
+    }
+
+    /// Sets the sink receiving status events. This can be a closure or an
+    /// mpsc [`Sender`](std::sync::mpsc::Sender).
+    pub fn set_event_sink<S: EventSink + 'a>(&mut self, sink: S) {
+        self.events.set(sink);
+    }
+
+    /// Sets a token that aborts programming between blocks. A cancelled
+    /// transfer is ended with RequestTransferExit and the ECU is returned to
+    /// the default session before failing with [`MzrError::Cancelled`].
+    /// The flash is left partially programmed and must be reprogrammed.
+    pub fn set_cancel_token(&mut self, token: CancelToken) {
+        self.cancel = token;
+    }
+
+    /// Sets a token that pauses programming between blocks. While paused,
+    /// [`step`](Programmer::step) blocks and keeps the session alive with
+    /// TesterPresent until the token is resumed or the operation cancelled.
+    pub fn set_pause_token(&mut self, token: PauseToken) {
+        self.pause = token;
+    }
+
+    /// Monitors battery voltage while programming. Programming refuses to
+    /// start below `minimum` volts, and the transfer is paused if the voltage
+    /// sags below `minimum` until it recovers.
+    pub fn set_voltage_monitor<V: VoltageMonitor + 'a>(&mut self, monitor: V, minimum: f32) {
+        self.voltage = Some(VoltageGate::new(monitor, minimum));
+    }
+
+    /// Disables the check that refuses to program while the engine is
+    /// running
+    pub fn allow_engine_running(&mut self, allow: bool) {
+        self.allow_engine_running = allow;
+    }
+
+    /// Disables the guard that refuses to erase or program the bootloader
+    /// region of the memory map. Overwriting the bootloader with a bad image
+    /// leaves the ECU unrecoverable.
+    pub fn allow_bootloader_write(&mut self, allow: bool) {
+        self.allow_bootloader_write = allow;
+    }
+
+    /// Enables recovery mode for reflashing an ECU left in its bootloader by
+    /// a failed flash. The programming session is requested repeatedly until
+    /// the ECU answers (e.g. when the ignition is turned on), and checks that
+    /// need the application firmware are skipped.
+    pub fn set_recovery_mode(&mut self, recovery: bool) {
+        self.recovery = recovery;
+    }
+
+    /// Sets the timeouts used for each class of request
+    pub fn set_timeouts(&mut self, timeouts: TimeoutProfile) {
+        self.timeouts = timeouts;
+    }
+
+    /// Returns statistics collected so far
+    pub fn stats(&self) -> &TransferStats {
+        &self.stats
+    }
+
+    fn use_timeout(&mut self, operation: Operation) {
+        let timeout = self.timeouts.get(operation);
+        self.session.bus().set_timeout(timeout);
+    }
+
     // This function MUST be called before sending data
     pub fn start(&mut self) -> Result<(), MzrError> {
-        self.bus.authenticate(0x85)?;
+        self.authenticate()?;
+        self.erase()
+    }
+
+    fn authenticate(&mut self) -> Result<(), MzrError> {
+        if let Some(gate) = self.voltage.as_mut() {
+            gate.check()?;
+        }
+        info!(
+            session = self.session_id,
+            recovery = self.recovery,
+            "authenticating"
+        );
+        if self.recovery {
+            self.connect_recovery()?;
+        } else {
+            if !self.allow_engine_running {
+                self.use_timeout(Operation::Request);
+                let request_id = self.session.request_id();
+                self.session.bus().ensure_engine_stopped(request_id)?;
+            }
+            self.use_timeout(Operation::Connect);
+            self.session
+                .enter(self.session_id)
+                .map_err(|err| err.context(Phase::Authenticating, 0, 0))?;
+        }
+        self.events.emit(Event::Connected {
+            session: self.session_id,
+        });
+        self.use_timeout(Operation::Security);
+        self.session
+            .unlock()
+            .map_err(|err| err.context(Phase::Authenticating, 0, 0))?;
+        self.events.emit(Event::Authenticated);
+        Ok(())
+    }
+
+    /// Requests the programming session until the ECU answers
+    fn connect_recovery(&mut self) -> Result<(), MzrError> {
+        self.use_timeout(Operation::Connect);
+        let start = Instant::now();
+        loop {
+            match self.session.enter(self.session_id) {
+                Ok(()) => return Ok(()),
+                Err(err) => {
+                    if self.cancel.is_cancelled() {
+                        return Err(MzrError::Cancelled);
+                    }
+                    if start.elapsed() >= RECOVERY_TIMEOUT {
+                        return Err(err);
+                    }
+                }
+            }
+            thread::sleep(RECOVERY_RETRY_INTERVAL);
+        }
+    }
+
+    fn erase(&mut self) -> Result<(), MzrError> {
+        // A bad offset must never reach the bootloader
+        let bootloader = self.memory_map.bootloader;
+        if !self.allow_bootloader_write && bootloader.overlaps(self.offset, self.data.len()) {
+            return Err(MzrError::BootloaderWrite {
+                offset: self.offset,
+                length: self.data.len(),
+            });
+        }
+        // Last chance to abort without touching flash
+        if self.cancel.is_cancelled() {
+            self.session.exit()?;
+            return Err(MzrError::Cancelled);
+        }
         // Erase flash memory
-        self.bus.query_uds(0x7e0, 0xB1, &[0x00, 0xB2, 0x00])?;
-        self.bus.request_download(self.offset, self.data.len() as u32 - self.position as u32)?;
+        info!(offset = self.offset, length = self.data.len(), "erasing");
+        self.events.emit(Event::EraseStarted);
+        self.use_timeout(Operation::Erase);
+        let request_id = self.session.request_id();
+        let start = Instant::now();
+        query(
+            self.session.bus(),
+            request_id,
+            UDS_REQ_ERASE,
+            &self.erase_routine,
+        )
+        .map_err(|err| MzrError::from(err).context(Phase::Erasing, 0, 0))?;
+        info!(
+            elapsed_ms = start.elapsed().as_secs_f64() * 1000.0,
+            "erased"
+        );
+        self.events.emit(Event::EraseCompleted);
+        self.use_timeout(Operation::Request);
+        let length = self.data.len() as u32 - self.position as u32;
+        self.session
+            .bus()
+            .request_download(request_id, self.offset, length)
+            .map_err(|err| err.context(Phase::Erasing, self.offset as usize, 0))?;
         self.erased = true;
         Ok(())
     }
@@ -174,57 +950,285 @@ impl<'a, M: 'a + Uds> Programmer<'a, M> {
         if self.position == self.data.len() {
             return Ok(ProgrammerState::Completed);
         }
+        self.wait_while_paused()?;
+        self.wait_for_voltage()?;
+        if self.cancel.is_cancelled() {
+            self.exit_transfer()?;
+            self.session.exit()?;
+            self.erased = false;
+            return Err(MzrError::Cancelled);
+        }
 
-        let to_send = cmp::min(self.data.len() - self.position, 0xFFE);
-        self.bus.transfer_data(&self.data[self.position..(self.position + to_send)])?;
+        let to_send = cmp::min(self.data.len() - self.position, self.block_size);
+        debug!(
+            offset = self.offset as usize + self.position,
+            length = to_send,
+            "transferring block"
+        );
+        self.use_timeout(Operation::Transfer);
+        let request_id = self.session.request_id();
+        self.session
+            .bus()
+            .transfer_data(
+                request_id,
+                &self.data[self.position..(self.position + to_send)],
+            )
+            .map_err(|err| {
+                err.context(
+                    Phase::Transferring,
+                    self.offset as usize + self.position,
+                    self.block + 1,
+                )
+            })?;
         self.position += to_send;
+        self.stats.bytes += to_send;
+        self.block += 1;
+        self.events.emit(Event::BlockTransferred {
+            block: self.block,
+            blocks: self.data.len().div_ceil(self.block_size),
+            position: self.position,
+            total: self.data.len(),
+        });
 
         if self.position != self.data.len() {
             Ok(ProgrammerState::InProgress(self.position))
         } else {
+            self.events.emit(Event::Completed);
             Ok(ProgrammerState::Completed)
         }
     }
-}
 
-/// Generates a key from a seed for security access
-fn generate_key(key: &str, parameter: u32, seed: &[u8]) -> [u8; 3] {
-    let mut parameter = parameter;
-    // This is Mazda's key generation algorithm reverse engineered from a
-    // Mazda 6 MPS ROM. Internally, the ECU uses a timer/counter for the seed
-    // generation
-
-    let nseed = {
-        let mut nseed = seed.to_vec();
-        nseed.extend_from_slice(key.as_bytes());
-        nseed
-    };
-
-    for c in nseed.iter().cloned() {
-        let mut c = c;
-        for _ in (1..=8).rev() {
-            let s = (c & 1) ^ (parameter & 1) as u8;
-            let mut m: u32 = 0;
-            if s != 0 {
-                parameter |= 0x0100_0000;
-                m = 0x0010_9028;
+    /// Ends the transfer, then reads back the programmed region and compares
+    /// it with the image. Fails with [`MzrError::VerifyFailed`] at the first
+    /// differing address.
+    pub fn verify(&mut self) -> Result<(), MzrError> {
+        let request_id = self.session.request_id();
+        self.exit_transfer()?;
+
+        info!(offset = self.offset, length = self.data.len(), "verifying");
+        self.use_timeout(Operation::Transfer);
+        let mut position = 0;
+        while position < self.data.len() {
+            let length = cmp::min(self.data.len() - position, BLOCK_SIZE);
+            let address = self.offset + position as u32;
+            let section = self
+                .session
+                .bus()
+                .read_memory_address(request_id, address, length as u16)
+                .map_err(|err| {
+                    MzrError::from(err).context(
+                        Phase::Verifying,
+                        address as usize,
+                        position / BLOCK_SIZE + 1,
+                    )
+                })?;
+            let expected = &self.data[position..position + length];
+            if let Some(index) = (0..length).find(|&i| section.get(i) != Some(&expected[i])) {
+                return Err(MzrError::VerifyFailed(address + index as u32));
             }
+            position += length;
+        }
+        self.events.emit(Event::Verified);
+        Ok(())
+    }
+
+    /// Ends the transfer, then has the ECU check the checksum of each
+    /// region of the memory map the image overlaps with the checksum
+    /// routine of the profile. Fails with [`MzrError::ChecksumRejected`]
+    /// for the first region the ECU reports as bad. Does nothing if no
+    /// routine is configured.
+    pub fn check_checksums(&mut self) -> Result<(), MzrError> {
+        let routine = match self.checksum_routine {
+            Some(routine) => routine,
+            None => return Ok(()),
+        };
+        self.exit_transfer()?;
+
+        let request_id = self.session.request_id();
+        let [high, low] = routine.to_be_bytes();
+        let regions: Vec<_> = self
+            .memory_map
+            .checksums
+            .iter()
+            .filter(|region| {
+                Region::new(region.start, region.end).overlaps(self.offset, self.data.len())
+            })
+            .map(|region| (region.start, region.end))
+            .collect();
+        // Summing the flash takes about as long as erasing it
+        self.use_timeout(Operation::Erase);
+        for (start, end) in regions {
+            info!(start, end, "checking checksum");
+            let request = [
+                &[ROUTINE_START, high, low][..],
+                &start.to_be_bytes(),
+                &end.to_be_bytes(),
+            ]
+            .concat();
+            let response = query(
+                self.session.bus(),
+                request_id,
+                UDS_REQ_ROUTINECONTROL,
+                &request,
+            )
+            .map_err(|err| MzrError::from(err).context(Phase::Checksumming, start as usize, 0))?;
+            match response.as_slice() {
+                [ROUTINE_START, h, l, CHECKSUM_VALID, ..] if [*h, *l] == [high, low] => (),
+                [ROUTINE_START, h, l, _, ..] if [*h, *l] == [high, low] => {
+                    warn!(start, end, "checksum rejected");
+                    return Err(MzrError::ChecksumRejected { start, end });
+                }
+                _ => return Err(MzrError::InvalidResponse),
+            }
+        }
+        self.events.emit(Event::ChecksumsAccepted);
+        Ok(())
+    }
+
+    /// Re-synchronizes the ECU with the last acknowledged block after a
+    /// failed TransferData. The ECU may or may not have written the failed
+    /// block, so the download is ended and requested again from the first
+    /// byte that wasn't acknowledged.
+    fn resync(&mut self) -> Result<(), MzrError> {
+        let request_id = self.session.request_id();
+        let offset = self.offset + self.position as u32;
+        let length = (self.data.len() - self.position) as u32;
+        info!(offset, block = self.block, "resynchronizing transfer");
+        self.use_timeout(Operation::Request);
+        // The ECU may have dropped the download already
+        let _ = self.session.bus().transfer_exit(request_id);
+        self.session
+            .bus()
+            .request_download(request_id, offset, length)
+            .map_err(|err| err.context(Phase::Transferring, offset as usize, self.block + 1))
+    }
 
-            c >>= 1;
-            parameter >>= 1;
-            let p3 = parameter & 0xFFEF_6FD7;
-            parameter ^= m;
-            parameter &= 0x0010_9028;
+    /// Ends the download with RequestTransferExit, once
+    fn exit_transfer(&mut self) -> Result<(), MzrError> {
+        if !self.transfer_exited {
+            self.use_timeout(Operation::Request);
+            let request_id = self.session.request_id();
+            self.session.bus().transfer_exit(request_id)?;
+            self.transfer_exited = true;
+        }
+        Ok(())
+    }
 
-            parameter |= p3;
-            parameter &= 0x00FF_FFFF;
+    /// Blocks while paused, maintaining the diagnostic session
+    fn wait_while_paused(&mut self) -> Result<(), MzrError> {
+        if !self.pause.is_paused() {
+            return Ok(());
         }
+        info!(position = self.position, "paused");
+        self.events.emit(Event::Paused);
+        while self.pause.is_paused() && !self.cancel.is_cancelled() {
+            self.session.keep_alive()?;
+            thread::sleep(Duration::from_millis(50));
+        }
+        if !self.cancel.is_cancelled() {
+            self.events.emit(Event::Resumed);
+        }
+        Ok(())
     }
 
-    let mut res = [0; 3];
-    res[0] = ((parameter >> 4) & 0xFF) as u8;
-    res[1] = (((parameter >> 20) & 0xFF) + ((parameter >> 8) & 0xF0)) as u8;
-    res[2] = (((parameter << 4) & 0xFF) + ((parameter >> 16) & 0x0F)) as u8;
+    /// Checks the battery voltage and blocks while it is below the minimum,
+    /// maintaining the diagnostic session
+    fn wait_for_voltage(&mut self) -> Result<(), MzrError> {
+        let gate = match self.voltage.as_mut() {
+            Some(gate) => gate,
+            None => return Ok(()),
+        };
+        let session = &mut self.session;
+        gate.wait(&mut self.events, &self.cancel, || session.keep_alive())
+    }
+
+    /// Returns an iterator that programs one block per item, yielding the
+    /// progress. [`start`](Programmer::start) must be called first.
+    pub fn iter(&mut self) -> TransferIter<'_, Self> {
+        TransferIter::new(self)
+    }
+
+    /// Authenticates, erases and programs the entire image, reporting
+    /// progress to `observer`
+    pub fn run<O: ProgressObserver>(&mut self, observer: &mut O) -> Result<(), MzrError> {
+        let result = self.run_phases(observer);
+        if let Err(ref err) = result {
+            self.stats.record_error(err);
+        }
+        result
+    }
+
+    fn run_phases<O: ProgressObserver>(&mut self, observer: &mut O) -> Result<(), MzrError> {
+        let _span =
+            info_span!("program", offset = self.offset, total = self.total_size()).entered();
+        self.stats.enter_phase(Phase::Authenticating);
+        observer.on_phase_change(Phase::Authenticating);
+        self.authenticate()?;
+        self.stats.enter_phase(Phase::Erasing);
+        observer.on_phase_change(Phase::Erasing);
+        self.erase()?;
+        self.stats.enter_phase(Phase::Transferring);
+        observer.on_phase_change(Phase::Transferring);
+        let mut meter = RateMeter::new(self.total_size());
+        let mut attempt = 0;
+        loop {
+            match self.step() {
+                Ok(ProgrammerState::InProgress(position)) => {
+                    attempt = 0;
+                    observer.on_progress(&meter.update(position));
+                }
+                Ok(ProgrammerState::Completed) => break,
+                // Only failed transfers are retried
+                Err(
+                    err @ MzrError::Context {
+                        phase: Phase::Transferring,
+                        ..
+                    },
+                ) if attempt < self.retry.attempts => {
+                    attempt += 1;
+                    warn!(attempt, %err, "retrying block");
+                    thread::sleep(self.retry.delay);
+                    self.stats.retries += 1;
+                    self.stats.record_error(&err);
+                    observer.on_retry(attempt, &err);
+                    self.resync()?;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        observer.on_progress(&meter.update(self.total_size()));
+        if self.verify {
+            self.stats.enter_phase(Phase::Verifying);
+            observer.on_phase_change(Phase::Verifying);
+            self.verify()?;
+        }
+        if self.checksum_routine.is_some() {
+            self.stats.enter_phase(Phase::Checksumming);
+            observer.on_phase_change(Phase::Checksumming);
+            self.check_checksums()?;
+        }
+        self.stats.enter_phase(Phase::Completed);
+        observer.on_phase_change(Phase::Completed);
+        Ok(())
+    }
+}
+
+/// Picks `count` distinct indices below `total` at random, in ascending
+/// order
+fn sample(total: usize, count: usize) -> Vec<usize> {
+    let mut indices: Vec<usize> = (0..total).collect();
+    let count = cmp::min(count, total);
+    for i in 0..count {
+        let j = i + (OsRng.next_u64() % (total - i) as u64) as usize;
+        indices.swap(i, j);
+    }
+    indices.truncate(count);
+    indices.sort_unstable();
+    indices
+}
 
-    res
+/// Returns true if a negative response code indicates that the diagnostic
+/// session or security access has lapsed
+fn is_session_lapsed(code: u8) -> bool {
+    code == NRC_SECURITY_ACCESS_DENIED || code == NRC_SERVICE_NOT_SUPPORTED_IN_SESSION
 }
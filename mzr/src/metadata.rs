@@ -0,0 +1,227 @@
+//! Metadata saved with downloaded ROMs and logs, so backups and logs can
+//! still be identified and interpreted long after they were made
+
+use std::ffi::OsString;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::hash::sha256_hex;
+
+/// Description of a downloaded ROM image
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RomMetadata {
+    /// VIN of the vehicle the image was read from
+    pub vin: String,
+    /// Calibration ID reported by the ECU
+    pub calibration_id: Option<String>,
+    /// Name reported by the ECU
+    pub ecu_name: Option<String>,
+    /// Arbitration ID requests were sent to
+    pub request_id: u32,
+    /// Arbitration ID the ECU responded with
+    pub response_id: u32,
+    /// Name of the J2534 adapter used
+    pub adapter: Option<String>,
+    /// Time of the download, in seconds since the Unix epoch
+    pub timestamp: u64,
+    /// Size of the image in bytes
+    pub size: usize,
+    /// SHA-256 hash of the image, as lowercase hex
+    pub sha256: String,
+}
+
+impl RomMetadata {
+    /// Creates metadata for `data` read from the vehicle with `vin`. The
+    /// timestamp is set to the current time.
+    pub fn new(vin: &str, data: &[u8]) -> RomMetadata {
+        RomMetadata {
+            vin: vin.to_string(),
+            calibration_id: None,
+            ecu_name: None,
+            request_id: 0,
+            response_id: 0,
+            adapter: None,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            size: data.len(),
+            sha256: sha256_hex(data),
+        }
+    }
+
+    /// Returns the path of the sidecar file for the ROM at `rom`
+    pub fn sidecar_path(rom: &Path) -> PathBuf {
+        rom.with_extension("json")
+    }
+
+    /// Loads the sidecar of the ROM at `rom`
+    pub fn load(rom: &Path) -> io::Result<RomMetadata> {
+        let data = fs::read(RomMetadata::sidecar_path(rom))?;
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    /// Writes the sidecar of the ROM at `rom`
+    pub fn save(&self, rom: &Path) -> io::Result<()> {
+        let data = serde_json::to_vec_pretty(self)?;
+        fs::write(RomMetadata::sidecar_path(rom), data)
+    }
+}
+
+/// Description of a logging session, written at the top of each log file
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogMetadata {
+    /// VIN of the vehicle, if the ECU reported one
+    pub vin: Option<String>,
+    /// Calibration ID reported by the ECU
+    pub calibration_id: Option<String>,
+    /// Name of the adapter used
+    pub adapter: Option<String>,
+    /// Firmware version reported by the adapter
+    pub adapter_firmware: Option<String>,
+    /// PIDs logged
+    pub pids: Vec<u16>,
+    /// Start of the session, in seconds since the Unix epoch
+    pub timestamp: u64,
+}
+
+impl LogMetadata {
+    /// Returns the metadata as `# key: value` lines, e.g. for the top of
+    /// CSV logs
+    pub fn comment(&self) -> String {
+        let mut lines = Vec::new();
+        let fields = [
+            ("vin", &self.vin),
+            ("calibration_id", &self.calibration_id),
+            ("adapter", &self.adapter),
+            ("adapter_firmware", &self.adapter_firmware),
+        ];
+        for (key, value) in fields.iter() {
+            if let Some(value) = value {
+                lines.push(format!("# {}: {}\n", key, value));
+            }
+        }
+        let pids: Vec<String> = self
+            .pids
+            .iter()
+            .map(|pid| format!("0x{:04X}", pid))
+            .collect();
+        lines.push(format!("# pids: {}\n", pids.join(" ")));
+        lines.push(format!("# timestamp: {}\n", self.timestamp));
+        lines.concat()
+    }
+}
+
+/// Note about a log, e.g. "added 2 psi", kept in a file next to it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Annotation {
+    /// Unix time in milliseconds the note was made
+    pub time: u64,
+    pub text: String,
+}
+
+impl Annotation {
+    /// Creates an annotation stamped with the current time
+    pub fn new(text: &str) -> Annotation {
+        Annotation {
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_millis() as u64),
+            text: text.to_string(),
+        }
+    }
+
+    /// Returns the path of the annotations of the log at `log`, e.g.
+    /// `log-1600000000.csv.notes`
+    pub fn notes_path(log: &Path) -> PathBuf {
+        let mut path = OsString::from(log.as_os_str());
+        path.push(".notes");
+        PathBuf::from(path)
+    }
+
+    /// Appends the annotation to the notes of the log at `log`
+    pub fn append(&self, log: &Path) -> io::Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(Annotation::notes_path(log))?;
+        writeln!(file, "{}", serde_json::to_string(self)?)
+    }
+
+    /// Loads the annotations of the log at `log`, oldest first. Logs
+    /// without notes have none.
+    pub fn load(log: &Path) -> io::Result<Vec<Annotation>> {
+        let file = match fs::File::open(Annotation::notes_path(log)) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err),
+        };
+        let mut annotations = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            if !line.trim().is_empty() {
+                annotations.push(serde_json::from_str(&line)?);
+            }
+        }
+        annotations.sort_by_key(|annotation: &Annotation| annotation.time);
+        Ok(annotations)
+    }
+}
+
+/// Returns `metadata` and `annotations` as `#` comment lines, e.g. for the
+/// top of exported logs
+pub fn describe(metadata: Option<&LogMetadata>, annotations: &[Annotation]) -> String {
+    let mut text = metadata.map(LogMetadata::comment).unwrap_or_default();
+    for annotation in annotations {
+        text.push_str(&format!(
+            "# note {}: {}\n",
+            annotation.time, annotation.text
+        ));
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describes_logs_with_notes() {
+        let metadata = LogMetadata {
+            vin: Some("JM1BK143741234567".to_string()),
+            adapter: Some("Tactrix OpenPort 2.0".to_string()),
+            pids: vec![0x0C, 0x0D],
+            timestamp: 1_600_000_000,
+            ..LogMetadata::default()
+        };
+        let log = std::env::temp_dir().join(format!("mzr-notes-{}.csv", std::process::id()));
+        assert!(Annotation::load(&log).unwrap().is_empty());
+        let second = Annotation {
+            time: 2,
+            text: "93 octane".to_string(),
+        };
+        second.append(&log).unwrap();
+        Annotation {
+            time: 1,
+            text: "added 2psi".to_string(),
+        }
+        .append(&log)
+        .unwrap();
+        let annotations = Annotation::load(&log).unwrap();
+        fs::remove_file(Annotation::notes_path(&log)).unwrap();
+        assert_eq!(annotations[1], second);
+        assert_eq!(
+            describe(Some(&metadata), &annotations),
+            "# vin: JM1BK143741234567\n\
+             # adapter: Tactrix OpenPort 2.0\n\
+             # pids: 0x000C 0x000D\n\
+             # timestamp: 1600000000\n\
+             # note 1: added 2psi\n\
+             # note 2: 93 octane\n"
+        );
+    }
+}
@@ -0,0 +1,137 @@
+//! Export of records to the binary log format of MegaLogViewer (MLVLG
+//! version 1), so logs can be reviewed with the tools tuners already use.
+//! Values are written as 32-bit floats after a `Time` field in seconds,
+//! markers and events as marker blocks, and session metadata in the info
+//! text.
+
+use std::io::{self, Write};
+
+use crate::binlog::LogField;
+use crate::record::Record;
+
+const MAGIC: &[u8; 6] = b"MLVLG\0";
+const VERSION: u16 = 1;
+/// Size of a field definition
+const FIELD_SIZE: usize = 55;
+const NAME_SIZE: usize = 34;
+const UNIT_SIZE: usize = 10;
+const MARKER_SIZE: usize = 50;
+/// Field type of 32-bit floats
+const TYPE_F32: u8 = 7;
+const BLOCK_DATA: u8 = 0;
+const BLOCK_MARKER: u8 = 1;
+
+/// Appends `s` padded with zeros, or truncated, to `size` bytes
+fn push_padded(buf: &mut Vec<u8>, s: &str, size: usize) {
+    let bytes = &s.as_bytes()[..s.len().min(size - 1)];
+    buf.extend_from_slice(bytes);
+    buf.resize(buf.len() + size - bytes.len(), 0);
+}
+
+fn push_field(buf: &mut Vec<u8>, name: &str, unit: &str) {
+    buf.push(TYPE_F32);
+    push_padded(buf, name, NAME_SIZE);
+    push_padded(buf, unit, UNIT_SIZE);
+    // Display style float, scale 1, transform 0, 2 digits
+    buf.push(0);
+    buf.extend_from_slice(&1f32.to_be_bytes());
+    buf.extend_from_slice(&0f32.to_be_bytes());
+    buf.push(2);
+}
+
+/// Writes `records` with the values of `fields` as an MLG log, with `info`,
+/// e.g. from [`describe`](crate::metadata::describe), in the info text
+pub fn write_mlg<W: Write>(
+    fields: &[LogField],
+    records: &[Record],
+    info: &str,
+    mut writer: W,
+) -> io::Result<()> {
+    let start = records.first().map_or(0, |record| record.time);
+    let info = format!("Logged with mzr\n{}\0", info.replace('\0', ""));
+    let info_start = 22 + FIELD_SIZE * (fields.len() + 1);
+    let data_start = info_start + info.len();
+    let record_len = 4 * (fields.len() + 1);
+
+    let mut header = Vec::with_capacity(data_start);
+    header.extend_from_slice(MAGIC);
+    header.extend_from_slice(&VERSION.to_be_bytes());
+    header.extend_from_slice(&((start / 1000) as u32).to_be_bytes());
+    header.extend_from_slice(&(info_start as u16).to_be_bytes());
+    header.extend_from_slice(&(data_start as u32).to_be_bytes());
+    header.extend_from_slice(&(record_len as u16).to_be_bytes());
+    header.extend_from_slice(&(fields.len() as u16 + 1).to_be_bytes());
+    push_field(&mut header, "Time", "s");
+    for field in fields {
+        push_field(&mut header, &field.name, &field.unit);
+    }
+    header.extend_from_slice(info.as_bytes());
+    writer.write_all(&header)?;
+
+    let mut counter = 0u8;
+    for record in records {
+        let elapsed = record.time.saturating_sub(start);
+        // Timestamps are in 10 µs and wrap around
+        let timestamp = ((elapsed * 100) & 0xFFFF) as u16;
+        for marker in record.markers.iter().chain(&record.events) {
+            let mut block = vec![BLOCK_MARKER, counter];
+            block.extend_from_slice(&timestamp.to_be_bytes());
+            push_padded(&mut block, marker, MARKER_SIZE);
+            writer.write_all(&block)?;
+            counter = counter.wrapping_add(1);
+        }
+        let mut data = Vec::with_capacity(record_len);
+        data.extend_from_slice(&(elapsed as f32 / 1000.0).to_be_bytes());
+        for field in fields {
+            let value = record.values.get(&field.name).copied().unwrap_or(f64::NAN);
+            data.extend_from_slice(&(value as f32).to_be_bytes());
+        }
+        let crc = data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+        let mut block = vec![BLOCK_DATA, counter];
+        block.extend_from_slice(&timestamp.to_be_bytes());
+        block.extend_from_slice(&data);
+        block.push(crc);
+        writer.write_all(&block)?;
+        counter = counter.wrapping_add(1);
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::binlog::FieldKind;
+
+    #[test]
+    fn writes_header_and_blocks() {
+        let fields = [LogField::new("rpm", "rpm", FieldKind::F32)];
+        let mut first = Record::new();
+        first.time = 1_600_000_000_000;
+        first.values.insert("rpm".to_string(), 850.0);
+        let mut second = first.clone();
+        second.time += 20;
+        second.markers.push("lap".to_string());
+        let mut mlg = Vec::new();
+        write_mlg(&fields, &[first, second], "# vin: JM1\n", &mut mlg).unwrap();
+
+        assert_eq!(&mlg[..6], b"MLVLG\0");
+        let info_start = u16::from_be_bytes([mlg[12], mlg[13]]) as usize;
+        let data_start = u32::from_be_bytes([mlg[14], mlg[15], mlg[16], mlg[17]]) as usize;
+        assert_eq!(info_start, 22 + 2 * 55);
+        assert_eq!(&mlg[18..22], &[0, 8, 0, 2]);
+        assert_eq!(&mlg[23..27], b"Time");
+        assert_eq!(&mlg[22 + 55 + 1..22 + 55 + 4], b"rpm");
+        assert_eq!(
+            &mlg[info_start..data_start],
+            b"Logged with mzr\n# vin: JM1\n\0"
+        );
+        // Data block, marker block, data block
+        let blocks = &mlg[data_start..];
+        assert_eq!(blocks.len(), 13 + 54 + 13);
+        assert_eq!(&blocks[..4], &[0, 0, 0, 0]);
+        assert_eq!(&blocks[8..12], &850f32.to_be_bytes());
+        assert_eq!(&blocks[13..20], &[1, 1, 0x07, 0xD0, b'l', b'a', b'p']);
+        assert_eq!(&blocks[67..71], &[0, 2, 0x07, 0xD0]);
+        assert_eq!(&blocks[71..75], &0.02f32.to_be_bytes());
+    }
+}
@@ -0,0 +1,46 @@
+//! Scripted ECU shared by the unit tests
+
+use obd::IsoTp;
+
+/// Answers a request, or returns `None` to reject it with
+/// serviceNotSupported
+pub(crate) type Handler<S> = fn(&mut S, &[u8]) -> Option<Vec<u8>>;
+
+/// ECU answering each request with a handler that has access to per-test
+/// `state`. The engine speed is answered from `rpm`, and every request is
+/// recorded.
+pub(crate) struct MockEcu<S> {
+    pub state: S,
+    pub rpm: u16,
+    pub requests: Vec<Vec<u8>>,
+    handler: Handler<S>,
+    response: Vec<u8>,
+}
+
+impl<S> MockEcu<S> {
+    pub fn new(state: S, handler: Handler<S>) -> MockEcu<S> {
+        MockEcu {
+            state,
+            rpm: 0,
+            requests: Vec::new(),
+            handler,
+            response: Vec::new(),
+        }
+    }
+}
+
+impl<S> IsoTp for MockEcu<S> {
+    fn send_isotp(&mut self, _id: u32, data: &[u8]) -> Result<(), obd::Error> {
+        self.requests.push(data.to_vec());
+        let [a, b] = (self.rpm * 4).to_be_bytes();
+        self.response = match data {
+            [0x01, 0x0C] => vec![0x41, 0x0C, a, b],
+            _ => (self.handler)(&mut self.state, data).unwrap_or_else(|| vec![0x7F, data[0], 0x11]),
+        };
+        Ok(())
+    }
+
+    fn read_isotp(&mut self, _id: u32) -> Result<Vec<u8>, obd::Error> {
+        Ok(self.response.clone())
+    }
+}
@@ -0,0 +1,191 @@
+//! Diagnostic modules of Mazda vehicles besides the engine ECU. Modules
+//! answer on their request ID + 8 and identify themselves with the part
+//! number of their software. Modules missing from the table are found with
+//! [`probe`].
+
+use serde::Serialize;
+
+use crate::datalink::can::CanBus;
+use crate::{MzrBus, MzrError};
+
+/// Identifier of the software part number
+pub const DID_PART_NUMBER: u16 = 0xF188;
+
+/// Request IDs of the OBD emissions-related modules
+const OBD_REQUEST_IDS: std::ops::RangeInclusive<u32> = 0x7E0..=0x7E7;
+
+/// Module at a fixed diagnostic address
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Module {
+    pub name: &'static str,
+    pub bus: CanBus,
+    pub request_id: u32,
+}
+
+impl Module {
+    /// Returns the arbitration ID the module responds with
+    pub fn response_id(&self) -> u32 {
+        self.request_id + 8
+    }
+
+    /// Reads the software part number of the module
+    pub fn part_number<B: MzrBus>(&self, bus: &mut B) -> Result<String, MzrError> {
+        part_number(bus, self.request_id)
+    }
+}
+
+fn part_number<B: MzrBus>(bus: &mut B, request_id: u32) -> Result<String, MzrError> {
+    let data = bus.read_identifier(request_id, DID_PART_NUMBER)?;
+    Ok(String::from_utf8_lossy(&data)
+        .trim_end_matches(['\0', ' '])
+        .to_string())
+}
+
+const MODULES: &[Module] = &[
+    Module {
+        name: "Powertrain control module",
+        bus: CanBus::HighSpeed,
+        request_id: 0x7E0,
+    },
+    Module {
+        name: "Transmission control module",
+        bus: CanBus::HighSpeed,
+        request_id: 0x7E1,
+    },
+    Module {
+        name: "Power steering control module",
+        bus: CanBus::HighSpeed,
+        request_id: 0x730,
+    },
+    Module {
+        name: "Restraints control module",
+        bus: CanBus::HighSpeed,
+        request_id: 0x737,
+    },
+    Module {
+        name: "Anti-lock brake system",
+        bus: CanBus::HighSpeed,
+        request_id: 0x760,
+    },
+    Module {
+        name: "Instrument cluster",
+        bus: CanBus::MediumSpeed,
+        request_id: 0x720,
+    },
+    Module {
+        name: "Body control module",
+        bus: CanBus::MediumSpeed,
+        request_id: 0x726,
+    },
+    Module {
+        name: "Audio control module",
+        bus: CanBus::MediumSpeed,
+        request_id: 0x727,
+    },
+    Module {
+        name: "Climate control module",
+        bus: CanBus::MediumSpeed,
+        request_id: 0x733,
+    },
+    Module {
+        name: "Parking aid module",
+        bus: CanBus::MediumSpeed,
+        request_id: 0x736,
+    },
+];
+
+/// Returns the known modules
+pub fn all() -> &'static [Module] {
+    MODULES
+}
+
+/// Returns the known modules on `bus`
+pub fn on_bus(bus: CanBus) -> impl Iterator<Item = &'static Module> {
+    MODULES.iter().filter(move |module| module.bus == bus)
+}
+
+/// Returns the request IDs probed when scanning `bus`: the OBD range
+/// 0x7E0–0x7E7 on the high-speed bus and the known modules
+pub fn scan_ids(bus: CanBus) -> Vec<u32> {
+    let mut ids: Vec<u32> = match bus {
+        CanBus::HighSpeed => OBD_REQUEST_IDS.collect(),
+        CanBus::MediumSpeed => Vec::new(),
+    };
+    ids.extend(on_bus(bus).map(|module| module.request_id));
+    ids.sort_unstable();
+    ids.dedup();
+    ids
+}
+
+/// Module that answered a [`probe`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ScannedModule {
+    pub request_id: u32,
+    /// Name of the module, if known
+    pub name: Option<&'static str>,
+    /// Software part number, or the ECU name of OBD modules without one
+    pub identification: Option<String>,
+}
+
+/// Sends TesterPresent to `request_id` and identifies the module if it
+/// answers. Negative responses count as answers. Returns `None` if nothing
+/// answers.
+pub fn probe<B: MzrBus>(bus: &mut B, request_id: u32) -> Option<ScannedModule> {
+    if let Err(err) = bus.tester_present(request_id) {
+        if !matches!(err.bus_error(), Some(obd::Error::NegativeResponse(_))) {
+            return None;
+        }
+    }
+    let module = MODULES
+        .iter()
+        .find(|module| module.request_id == request_id);
+    let identification = part_number(bus, request_id)
+        .or_else(|_| bus.ecu_name(request_id))
+        .ok();
+    Some(ScannedModule {
+        request_id,
+        name: module.map(|module| module.name),
+        identification,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use obd::IsoTp;
+
+    /// Bus with a cluster answering ReadDataByIdentifier
+    struct Cluster {
+        response: Vec<u8>,
+    }
+
+    impl IsoTp for Cluster {
+        fn send_isotp(&mut self, id: u32, data: &[u8]) -> Result<(), obd::Error> {
+            self.response = match data {
+                [0x22, 0xF1, 0x88] if id == 0x720 => {
+                    [&[0x62, 0xF1, 0x88][..], b"BBM4-55-430 \0"].concat()
+                }
+                _ => vec![0x7F, data[0], 0x11],
+            };
+            Ok(())
+        }
+
+        fn read_isotp(&mut self, _id: u32) -> Result<Vec<u8>, obd::Error> {
+            Ok(self.response.clone())
+        }
+    }
+
+    #[test]
+    fn identifies_modules() {
+        let cluster = on_bus(CanBus::MediumSpeed).next().unwrap();
+        assert_eq!(cluster.response_id(), 0x728);
+        let mut bus = Cluster { response: vec![] };
+        assert_eq!(cluster.part_number(&mut bus).unwrap(), "BBM4-55-430");
+        assert!(all()[0].part_number(&mut bus).is_err());
+        assert!(all().iter().all(|module| all()
+            .iter()
+            .filter(|m| m.request_id == module.request_id)
+            .count()
+            == 1));
+    }
+}
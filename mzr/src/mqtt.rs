@@ -0,0 +1,160 @@
+//! Publishing of log records to an MQTT broker, for home and track
+//! telemetry stacks. The publisher is behind the `mqtt` feature; the
+//! settings are always part of the config.
+//!
+//! ```toml
+//! [mqtt]
+//! host = "192.168.1.10"
+//! topic = "car/mzr/{channel}"
+//! qos = 1
+//! channels = ["rpm", "boost"]
+//! ```
+
+use serde::{Deserialize, Serialize};
+
+use crate::record::Record;
+
+#[cfg(feature = "mqtt")]
+pub use self::publisher::{MqttError, MqttPublisher};
+
+/// Broker and topics records are published to
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Topic of each value. `{channel}` is replaced with the channel name.
+    pub topic: String,
+    /// Quality of service: 0 (at most once), 1 (at least once) or 2
+    /// (exactly once)
+    pub qos: u8,
+    /// Names of the channels to publish. Empty publishes every channel.
+    pub channels: Vec<String>,
+}
+
+impl Default for MqttConfig {
+    fn default() -> MqttConfig {
+        MqttConfig {
+            host: "localhost".to_string(),
+            port: 1883,
+            client_id: "mzr".to_string(),
+            username: None,
+            password: None,
+            topic: "mzr/{channel}".to_string(),
+            qos: 0,
+            channels: Vec::new(),
+        }
+    }
+}
+
+impl MqttConfig {
+    /// Returns the topic and payload of each selected value of `record`.
+    /// Payloads are the values as decimal text.
+    pub fn publications(&self, record: &Record) -> Vec<(String, String)> {
+        record
+            .values
+            .iter()
+            .filter(|(name, _)| self.channels.is_empty() || self.channels.contains(name))
+            .map(|(name, value)| (self.topic.replace("{channel}", name), value.to_string()))
+            .collect()
+    }
+}
+
+#[cfg(feature = "mqtt")]
+mod publisher {
+    use std::thread;
+    use std::time::Duration;
+
+    use rumqttc::{Client, MqttOptions};
+    use thiserror::Error;
+
+    use super::MqttConfig;
+    use crate::record::{Record, RecordSink};
+
+    /// Requests buffered while the broker is slow or unreachable. Values
+    /// published while the buffer is full are dropped.
+    const REQUEST_BUFFER: usize = 64;
+
+    /// Time to wait before reconnecting to the broker
+    const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+    #[derive(Error, Debug)]
+    pub enum MqttError {
+        #[error("invalid QoS {0}")]
+        InvalidQos(u8),
+    }
+
+    /// Publishes records to a broker. The connection is made and kept up
+    /// by a background thread, so publishing never blocks the logger.
+    pub struct MqttPublisher {
+        config: MqttConfig,
+        client: Client,
+        qos: rumqttc::QoS,
+    }
+
+    impl MqttPublisher {
+        pub fn connect(config: &MqttConfig) -> Result<MqttPublisher, MqttError> {
+            let qos = rumqttc::qos(config.qos).map_err(|_| MqttError::InvalidQos(config.qos))?;
+            let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+            options.set_keep_alive(Duration::from_secs(30));
+            if let Some(ref username) = config.username {
+                options.set_credentials(username, config.password.as_deref().unwrap_or(""));
+            }
+            let (client, mut connection) = Client::new(options, REQUEST_BUFFER);
+            thread::spawn(move || {
+                for notification in connection.iter() {
+                    if let Err(err) = notification {
+                        tracing::warn!(%err, "MQTT connection failed");
+                        thread::sleep(RECONNECT_DELAY);
+                    }
+                }
+            });
+            Ok(MqttPublisher {
+                config: config.clone(),
+                client,
+                qos,
+            })
+        }
+    }
+
+    impl RecordSink for MqttPublisher {
+        fn send(&mut self, record: &Record) {
+            for (topic, payload) in self.config.publications(record) {
+                // Dropped if the broker can't keep up
+                let _ = self.client.try_publish(topic, self.qos, false, payload);
+            }
+        }
+    }
+
+    impl Drop for MqttPublisher {
+        fn drop(&mut self) {
+            let _ = self.client.try_disconnect();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn publishes_selected_channels() {
+        let config: MqttConfig =
+            toml::from_str("host = \"broker\"\ntopic = \"car/{channel}\"\nchannels = [\"rpm\"]")
+                .unwrap();
+        assert_eq!(config.port, 1883);
+        let mut record = Record::new();
+        record.values.insert("rpm".to_string(), 850.5);
+        record.values.insert("coolant".to_string(), 90.0);
+        assert_eq!(
+            config.publications(&record),
+            [("car/rpm".to_string(), "850.5".to_string())]
+        );
+        let all = MqttConfig::default().publications(&record);
+        assert_eq!(all[0], ("mzr/coolant".to_string(), "90".to_string()));
+        assert_eq!(all.len(), 2);
+    }
+}
@@ -0,0 +1,260 @@
+//! Oxygen sensor data, for diagnosing rich or lean running. Live sensor
+//! values are read with ReadDataByIdentifier on the OBD data identifiers
+//! (0xF400 + PID), and the results of the sensor monitor with OBD service
+//! 0x05 where the ECU supports it.
+
+use std::fmt;
+
+use obd::Uds;
+use serde::Serialize;
+
+use crate::{MzrBus, MzrError};
+
+const OBD_REQ_O2_MONITORING: u8 = 0x05;
+/// Data identifier of the OBD PID for `pid`
+const OBD_DID_BASE: u16 = 0xF400;
+const OBD_PID_O2_SENSORS_PRESENT: u8 = 0x13;
+/// First PIDs of the narrowband voltage and the wideband equivalence ratio
+/// and current, one PID per sensor
+const OBD_PID_O2_VOLTAGE: u8 = 0x14;
+const OBD_PID_O2_WIDEBAND: u8 = 0x34;
+
+const NRC_SUBFUNCTION_NOT_SUPPORTED: u8 = 0x12;
+const NRC_REQUEST_OUT_OF_RANGE: u8 = 0x31;
+
+/// Oxygen sensor position, e.g. bank 1 sensor 1 upstream of the catalyst
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+pub struct O2Sensor {
+    pub bank: u8,
+    pub sensor: u8,
+}
+
+impl O2Sensor {
+    /// Returns the sensors flagged in the mask of OBD PID 0x13: bits 0–3
+    /// are sensors 1–4 of bank 1, bits 4–7 those of bank 2
+    pub fn from_mask(mask: u8) -> Vec<O2Sensor> {
+        (0..8)
+            .filter(|bit| mask & (1 << bit) != 0)
+            .map(|bit| O2Sensor {
+                bank: bit / 4 + 1,
+                sensor: bit % 4 + 1,
+            })
+            .collect()
+    }
+
+    /// Position in the layout of PID 0x13, from 0
+    fn index(self) -> u8 {
+        (self.bank - 1) * 4 + (self.sensor - 1)
+    }
+}
+
+impl fmt::Display for O2Sensor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "B{}S{}", self.bank, self.sensor)
+    }
+}
+
+/// Live values of a sensor. Narrowband sensors report a voltage and
+/// wideband sensors an equivalence ratio and pump current.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct O2Reading {
+    pub sensor: O2Sensor,
+    /// Sensor voltage in volts
+    pub voltage: Option<f64>,
+    /// Short-term fuel trim using this sensor in percent
+    pub fuel_trim: Option<f64>,
+    /// Commanded equivalence ratio (lambda)
+    pub lambda: Option<f64>,
+    /// Pump current in milliamps
+    pub current: Option<f64>,
+}
+
+/// Sensor monitor test identifiers of service 0x05 with their name, unit
+/// and scaling
+const TESTS: [(u8, &str, &str, f64); 10] = [
+    (0x01, "Rich to lean threshold voltage", "V", 0.005),
+    (0x02, "Lean to rich threshold voltage", "V", 0.005),
+    (0x03, "Low voltage for switch time", "V", 0.005),
+    (0x04, "High voltage for switch time", "V", 0.005),
+    (0x05, "Rich to lean switch time", "s", 0.004),
+    (0x06, "Lean to rich switch time", "s", 0.004),
+    (0x07, "Minimum voltage in test cycle", "V", 0.005),
+    (0x08, "Maximum voltage in test cycle", "V", 0.005),
+    (0x09, "Time between transitions", "s", 0.04),
+    (0x0A, "Sensor period", "s", 0.04),
+];
+
+/// Result of one test of the sensor monitor
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TestResult {
+    pub tid: u8,
+    pub name: &'static str,
+    pub unit: &'static str,
+    pub value: f64,
+    /// Limits of the test, `None` for constants such as thresholds
+    pub limits: Option<(f64, f64)>,
+}
+
+impl TestResult {
+    /// Decodes the result of test `tid` from its value and limits
+    pub fn decode(tid: u8, value: u8, min: u8, max: u8) -> Option<TestResult> {
+        let &(tid, name, unit, scale) = TESTS.iter().find(|test| test.0 == tid)?;
+        let limits = if min == 0 && max == 0 {
+            None
+        } else {
+            Some((min as f64 * scale, max as f64 * scale))
+        };
+        Some(TestResult {
+            tid,
+            name,
+            unit,
+            value: value as f64 * scale,
+            limits,
+        })
+    }
+
+    /// Whether the value is within the limits, if there are any
+    pub fn passed(&self) -> bool {
+        self.limits
+            .is_none_or(|(min, max)| self.value >= min && self.value <= max)
+    }
+}
+
+/// Returns `None` for negative responses to identifiers the ECU doesn't
+/// support
+fn optional<T>(result: Result<T, MzrError>) -> Result<Option<T>, MzrError> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(err) if err.negative_response_code().is_some() => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Returns the sensors the ECU reports as present
+pub fn sensors<B: MzrBus>(bus: &mut B, request_id: u32) -> Result<Vec<O2Sensor>, MzrError> {
+    let data = bus.read_identifier(request_id, OBD_DID_BASE + OBD_PID_O2_SENSORS_PRESENT as u16)?;
+    match data.first() {
+        Some(&mask) => Ok(O2Sensor::from_mask(mask)),
+        None => Err(MzrError::InvalidResponse),
+    }
+}
+
+/// Reads the live values of `sensor`. Values the ECU doesn't report are
+/// left out.
+pub fn read_sensor<B: MzrBus>(
+    bus: &mut B,
+    request_id: u32,
+    sensor: O2Sensor,
+) -> Result<O2Reading, MzrError> {
+    let mut reading = O2Reading {
+        sensor,
+        voltage: None,
+        fuel_trim: None,
+        lambda: None,
+        current: None,
+    };
+    let did = OBD_DID_BASE + (OBD_PID_O2_VOLTAGE + sensor.index()) as u16;
+    if let Some(data) = optional(bus.read_identifier(request_id, did))? {
+        if let [a, b, ..] = data[..] {
+            reading.voltage = Some(a as f64 * 0.005);
+            // 0xFF if the sensor isn't used for trim
+            if b != 0xFF {
+                reading.fuel_trim = Some((b as f64 - 128.0) * 100.0 / 128.0);
+            }
+        }
+    }
+    let did = OBD_DID_BASE + (OBD_PID_O2_WIDEBAND + sensor.index()) as u16;
+    if let Some(data) = optional(bus.read_identifier(request_id, did))? {
+        if let [a, b, c, d, ..] = data[..] {
+            reading.lambda = Some(u16::from_be_bytes([a, b]) as f64 * 2.0 / 65536.0);
+            reading.current = Some(u16::from_be_bytes([c, d]) as f64 / 256.0 - 128.0);
+        }
+    }
+    Ok(reading)
+}
+
+/// Reads the sensor monitor results of `sensor` with service 0x05. Tests
+/// the ECU doesn't support are left out. ECUs that report monitor results
+/// through service 0x06 instead reject the service.
+pub fn read_tests<B: Uds>(
+    bus: &mut B,
+    request_id: u32,
+    sensor: O2Sensor,
+) -> Result<Vec<TestResult>, MzrError> {
+    let mut results = Vec::new();
+    for &(tid, ..) in TESTS.iter() {
+        let response = match bus.query_uds(
+            request_id,
+            OBD_REQ_O2_MONITORING,
+            &[tid, sensor.index() + 1],
+        ) {
+            Ok(response) => response,
+            Err(obd::Error::NegativeResponse(Some(
+                NRC_SUBFUNCTION_NOT_SUPPORTED | NRC_REQUEST_OUT_OF_RANGE,
+            ))) => continue,
+            Err(err) => return Err(err.into()),
+        };
+        match response[..] {
+            [id, _sensor, value, min, max, ..] if id == tid => {
+                results.extend(TestResult::decode(tid, value, min, max))
+            }
+            _ => return Err(MzrError::InvalidResponse),
+        }
+    }
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockEcu;
+
+    /// ECU with a wideband sensor B1S1 and a narrowband sensor B1S2, whose
+    /// monitor reports the switch times
+    fn ecu() -> MockEcu<()> {
+        MockEcu::new((), |_, request| {
+            Some(match request {
+                [0x22, 0xF4, 0x13] => vec![0x62, 0xF4, 0x13, 0b0000_0011],
+                [0x22, 0xF4, 0x15] => vec![0x62, 0xF4, 0x15, 0x90, 0xFF],
+                [0x22, 0xF4, 0x34] => vec![0x62, 0xF4, 0x34, 0x80, 0x00, 0x80, 0x40],
+                [0x22, ..] => vec![0x7F, 0x22, NRC_REQUEST_OUT_OF_RANGE],
+                [0x05, tid @ (0x05 | 0x06), 0x02] => vec![0x45, *tid, 0x02, 0x19, 0x00, 0x32],
+                [0x05, ..] => vec![0x7F, 0x05, NRC_REQUEST_OUT_OF_RANGE],
+                _ => return None,
+            })
+        })
+    }
+
+    #[test]
+    fn reads_sensors_and_monitor_results() {
+        let mut ecu = ecu();
+        let found = sensors(&mut ecu, 0x7E0).unwrap();
+        assert_eq!(
+            found,
+            [
+                O2Sensor { bank: 1, sensor: 1 },
+                O2Sensor { bank: 1, sensor: 2 }
+            ]
+        );
+        assert_eq!(found[1].to_string(), "B1S2");
+        assert_eq!(O2Sensor::from_mask(0x10)[0].to_string(), "B2S1");
+
+        let wideband = read_sensor(&mut ecu, 0x7E0, found[0]).unwrap();
+        assert_eq!(
+            (wideband.voltage, wideband.lambda, wideband.current),
+            (None, Some(1.0), Some(0.25))
+        );
+        let narrowband = read_sensor(&mut ecu, 0x7E0, found[1]).unwrap();
+        assert_eq!(narrowband.voltage, Some(0.72));
+        assert_eq!((narrowband.fuel_trim, narrowband.lambda), (None, None));
+
+        let results = read_tests(&mut ecu, 0x7E0, found[1]).unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].name, "Rich to lean switch time");
+        assert_eq!(results[0].value, 0.1);
+        assert_eq!(results[0].limits, Some((0.0, 0.2)));
+        assert!(results[0].passed());
+        assert!(read_tests(&mut ecu, 0x7E0, found[0]).unwrap().is_empty());
+        assert!(!TestResult::decode(0x09, 10, 1, 5).unwrap().passed());
+    }
+}
@@ -0,0 +1,71 @@
+//! Human-readable or machine-readable (JSON) output for the CLIs. In JSON
+//! mode every event is printed as a single JSON object per line. Reporting
+//! an error makes the process exit with a failure status.
+
+use std::fmt::Display;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use serde_json::{json, Value};
+
+/// Set once the run has failed
+static FAILED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Copy, Clone)]
+pub struct Output {
+    json: bool,
+}
+
+impl Output {
+    pub fn new(json: bool) -> Output {
+        Output { json }
+    }
+
+    /// Returns true if output is emitted as JSON
+    pub fn is_json(&self) -> bool {
+        self.json
+    }
+
+    /// Prints a human-readable message. Suppressed in JSON mode.
+    pub fn message<D: Display>(&self, message: D) {
+        if !self.json {
+            println!("{}", message);
+        }
+    }
+
+    /// Prints a structured event. Suppressed in human-readable mode.
+    pub fn event(&self, event: Value) {
+        if self.json {
+            println!("{}", event);
+        }
+    }
+
+    /// Prints an error in either mode, to stderr in human-readable mode,
+    /// and fails the run
+    pub fn error<D: Display>(&self, error: D) {
+        self.fail();
+        if self.json {
+            println!(
+                "{}",
+                json!({ "event": "error", "message": error.to_string() })
+            );
+        } else {
+            eprintln!("{}", error);
+        }
+    }
+
+    /// Fails the run without an error, e.g. when it was cancelled
+    pub fn fail(&self) {
+        FAILED.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns the exit status of the process: failure if an error was
+    /// reported
+    pub fn exit_code() -> ExitCode {
+        if FAILED.load(Ordering::SeqCst) {
+            ExitCode::FAILURE
+        } else {
+            ExitCode::SUCCESS
+        }
+    }
+}
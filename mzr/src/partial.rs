@@ -0,0 +1,128 @@
+//! Crash-safe downloads. Received blocks are appended to `<output>.partial`
+//! and the last confirmed offset is recorded in `<output>.partial.json`, so
+//! an interrupted download can be resumed instead of starting over.
+
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+/// Contents of the sidecar file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PartialMeta {
+    /// Number of bytes in the partial file confirmed to be complete
+    offset: usize,
+    /// VIN of the vehicle the data was read from
+    vin: Option<String>,
+}
+
+/// Data recovered from an interrupted download
+#[derive(Debug, Clone)]
+pub struct PartialData {
+    /// Data read before the download was interrupted
+    pub data: Vec<u8>,
+    /// VIN of the vehicle the data was read from
+    pub vin: Option<String>,
+}
+
+/// Partial download file that received blocks are written to
+#[derive(Debug)]
+pub struct PartialFile {
+    file: File,
+    meta_path: PathBuf,
+    meta: PartialMeta,
+}
+
+impl PartialFile {
+    /// Creates the partial file for a download to `output`, starting with
+    /// the already confirmed `data`. Any previous partial file is replaced.
+    pub fn create(output: &Path, data: &[u8], vin: Option<&str>) -> io::Result<PartialFile> {
+        let (data_path, meta_path) = paths(output);
+        let mut file = File::create(&data_path)?;
+        file.write_all(data)?;
+        file.sync_data()?;
+        let mut partial = PartialFile {
+            file,
+            meta_path,
+            meta: PartialMeta {
+                offset: data.len(),
+                vin: vin.map(str::to_owned),
+            },
+        };
+        partial.write_meta()?;
+        Ok(partial)
+    }
+
+    /// Loads the confirmed data of an interrupted download to `output`.
+    /// Returns `None` if there is nothing to resume.
+    pub fn load(output: &Path) -> io::Result<Option<PartialData>> {
+        let (data_path, meta_path) = paths(output);
+        let meta = match fs::read(&meta_path) {
+            Ok(meta) => meta,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let meta: PartialMeta = serde_json::from_slice(&meta)?;
+        let mut data = Vec::with_capacity(meta.offset);
+        match File::open(&data_path) {
+            Ok(file) => file.take(meta.offset as u64).read_to_end(&mut data)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        // Blocks past the recorded offset may be incomplete and are dropped
+        if data.len() < meta.offset || data.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(PartialData {
+            data,
+            vin: meta.vin,
+        }))
+    }
+
+    /// Removes the partial file and sidecar for a download to `output`
+    pub fn remove(output: &Path) -> io::Result<()> {
+        let (data_path, meta_path) = paths(output);
+        for path in [data_path, meta_path].iter() {
+            match fs::remove_file(path) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the number of bytes confirmed so far
+    pub fn offset(&self) -> usize {
+        self.meta.offset
+    }
+
+    /// Appends a received block. The block is synced to disk before the
+    /// offset in the sidecar is advanced.
+    pub(crate) fn append(&mut self, data: &[u8]) -> io::Result<()> {
+        self.file.write_all(data)?;
+        self.file.sync_data()?;
+        self.meta.offset += data.len();
+        self.write_meta()
+    }
+
+    fn write_meta(&mut self) -> io::Result<()> {
+        // Written to a temporary file and renamed so the sidecar is never
+        // left truncated
+        let mut tmp = self.meta_path.clone().into_os_string();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        fs::write(&tmp, serde_json::to_vec(&self.meta)?)?;
+        fs::rename(&tmp, &self.meta_path)
+    }
+}
+
+/// Returns the paths of the partial file and sidecar for `output`
+fn paths(output: &Path) -> (PathBuf, PathBuf) {
+    let mut data = OsString::from(output.as_os_str());
+    data.push(".partial");
+    let mut meta = data.clone();
+    meta.push(".json");
+    (PathBuf::from(data), PathBuf::from(meta))
+}
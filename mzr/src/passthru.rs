@@ -0,0 +1,636 @@
+//! J2534 PassThru device discovery, ISO-TP channel and K-line channel
+
+use std::cell::RefCell;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use j2534::{
+    Channel, ConfigId, ConnectFlags, Driver, FilterId, FilterType, MessageId, PassThruMsg,
+    Protocol, RxStatus, TxFlags,
+};
+use obd::IsoTp;
+use thiserror::Error;
+use tracing::{debug, trace, Level};
+
+use crate::datalink::can::{Can, CanBus, Filter, Message, MAX_STANDARD_ID};
+use crate::datalink::kline::KLine;
+use crate::kwp::KLINE_BAUDRATE;
+use crate::timeout::SetTimeout;
+
+/// J1962 pins of the medium-speed CAN bus (CAN-H on 3, CAN-L on 11)
+const MS_CAN_PINS: u32 = 0x030B;
+
+/// Baud rate at which a 0x00 byte holds the K-line low for 25 ms (start bit
+/// and eight data bits)
+const WAKE_UP_BAUDRATE: u32 = 360;
+/// Length of the fast init wake-up pattern
+const WAKE_UP_TIME: Duration = Duration::from_millis(50);
+
+/// Range of periodic message intervals in milliseconds allowed by J2534
+const MIN_PERIODIC_INTERVAL: u32 = 5;
+const MAX_PERIODIC_INTERVAL: u32 = 65535;
+const UDS_REQ_TESTER_PRESENT: u8 = 0x3E;
+
+#[derive(Error, Debug)]
+pub enum DeviceError {
+    #[error("no J2534 interfaces found")]
+    NoDevices,
+    #[error("no J2534 interface matches '{0}'")]
+    NotFound(String),
+    #[error("J2534 library '{}' not found", .0.display())]
+    LibraryNotFound(PathBuf),
+    #[error("failed to enumerate J2534 interfaces: {0}")]
+    Io(#[from] io::Error),
+}
+
+/// Bytes of message data shown in traces
+const TRACE_BYTES: usize = 32;
+
+/// Makes the J2534 call `name`. With the `j2534` target enabled at debug
+/// level, the call is logged with `args`, its result and duration. Many
+/// failures in the field turn out to be adapter or driver quirks that only
+/// show in these calls.
+pub fn traced<T, F>(name: &str, args: fmt::Arguments, call: F) -> Result<T, j2534::Error>
+where
+    F: FnOnce() -> Result<T, j2534::Error>,
+{
+    if !tracing::enabled!(target: "j2534", Level::DEBUG) {
+        return call();
+    }
+    let start = Instant::now();
+    let result = call();
+    let elapsed = start.elapsed().as_secs_f64() * 1000.0;
+    match &result {
+        Ok(_) => debug!(target: "j2534", "{}({}) = ok ({:.1} ms)", name, args, elapsed),
+        Err(err) => debug!(
+            target: "j2534",
+            "{}({}) = {:?}: {} ({:.1} ms)",
+            name, args, err, err, elapsed
+        ),
+    }
+    result
+}
+
+/// Formats the data of a message for traces
+fn trace_data(data: &[u8]) -> String {
+    let mut hex: Vec<String> = data
+        .iter()
+        .take(TRACE_BYTES)
+        .map(|b| format!("{:02X}", b))
+        .collect();
+    if data.len() > TRACE_BYTES {
+        hex.push(format!(".. ({} bytes)", data.len()));
+    }
+    hex.join(" ")
+}
+
+/// Writes `message` to `channel`
+fn write_msg(channel: &Channel, message: PassThruMsg, timeout: u32) -> Result<(), j2534::Error> {
+    let tx_flags = message.tx_flags;
+    let data = if tracing::enabled!(target: "j2534", Level::DEBUG) {
+        trace_data(&message.data[..message.data_size as usize])
+    } else {
+        String::new()
+    };
+    traced(
+        "PassThruWriteMsgs",
+        format_args!("tx_flags=0x{:X}, [{}], timeout={}", tx_flags, data, timeout),
+        || channel.write(&mut [message], timeout),
+    )?;
+    Ok(())
+}
+
+/// Reads the next message from `channel`
+fn read_msg(channel: &Channel, timeout: u32) -> Result<PassThruMsg, j2534::Error> {
+    let message = traced(
+        "PassThruReadMsgs",
+        format_args!("timeout={}", timeout),
+        || channel.read_once(timeout),
+    )?;
+    if tracing::enabled!(target: "j2534", Level::TRACE) {
+        let rx_status = message.rx_status;
+        trace!(
+            target: "j2534",
+            "rx_status=0x{:X} [{}]",
+            rx_status,
+            trace_data(&message.data[..message.data_size as usize])
+        );
+    }
+    Ok(message)
+}
+
+/// Returns a list of all installed PassThru drivers
+pub fn list_drivers() -> Result<Vec<Driver>, DeviceError> {
+    Ok(j2534::drivers()?)
+}
+
+/// Selects an installed PassThru driver. `selector` may be an index into
+/// [`list_drivers`] or a (case-insensitive) driver name. If no selector is
+/// given, the first driver is returned.
+pub fn find_driver(selector: Option<&str>) -> Result<Driver, DeviceError> {
+    let drivers = list_drivers()?;
+    if drivers.is_empty() {
+        return Err(DeviceError::NoDevices);
+    }
+
+    let selector = match selector {
+        Some(selector) => selector,
+        None => return Ok(drivers.into_iter().next().unwrap()),
+    };
+
+    let index = match selector.parse::<usize>() {
+        Ok(index) if index < drivers.len() => Some(index),
+        _ => drivers
+            .iter()
+            .position(|d| d.name.eq_ignore_ascii_case(selector)),
+    };
+
+    match index {
+        Some(index) => Ok(drivers.into_iter().nth(index).unwrap()),
+        None => Err(DeviceError::NotFound(selector.to_string())),
+    }
+}
+
+/// Returns a driver for the PassThru library at `path`, bypassing the
+/// installed drivers. Useful for portable installs, Wine and broken registry
+/// entries.
+pub fn driver_from_library(path: &Path) -> Result<Driver, DeviceError> {
+    if !path.is_file() {
+        return Err(DeviceError::LibraryNotFound(path.to_path_buf()));
+    }
+    let name = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    Ok(Driver {
+        name,
+        vendor: String::new(),
+        path: path.to_string_lossy().into_owned(),
+    })
+}
+
+/// Selects the PassThru library at `library` if given, or else an installed
+/// driver with [`find_driver`]
+pub fn select_driver(
+    library: Option<&Path>,
+    selector: Option<&str>,
+) -> Result<Driver, DeviceError> {
+    match library {
+        Some(path) => driver_from_library(path),
+        None => find_driver(selector),
+    }
+}
+
+/// Returns the installed PassThru drivers, numbered by their index for
+/// [`select_driver`]. Fails with [`DeviceError::NoDevices`] if there are
+/// none.
+pub fn installed_drivers() -> Result<Vec<Driver>, DeviceError> {
+    let drivers = list_drivers()?;
+    if drivers.is_empty() {
+        return Err(DeviceError::NoDevices);
+    }
+    Ok(drivers)
+}
+
+struct PassThruFilter {
+    id: FilterId,
+    source_id: u32,
+    destination_id: u32,
+}
+
+/// Returns the transmit flags of ISO-TP messages to `id`. IDs that don't fit
+/// in 11 bits are 29-bit identifiers.
+fn isotp_flags(id: u32) -> TxFlags {
+    if id > MAX_STANDARD_ID {
+        TxFlags::ISO15765_FRAME_PAD | TxFlags::CAN_29BIT_ID
+    } else {
+        TxFlags::ISO15765_FRAME_PAD
+    }
+}
+
+/// PassThru ISO-TP channel with an adjustable timeout
+pub struct PassThruChannel<'ch> {
+    channel: Channel<'ch>,
+    // Timeout in milliseconds
+    timeout: u32,
+    filter: Option<PassThruFilter>,
+    keep_alive: Option<MessageId>,
+}
+
+impl<'ch> PassThruChannel<'ch> {
+    /// Opens an ISO-TP channel on `device`
+    pub fn new(
+        device: &'ch j2534::Device,
+        baudrate: u32,
+        timeout: Duration,
+    ) -> Result<PassThruChannel<'ch>, j2534::Error> {
+        let channel = traced(
+            "PassThruConnect",
+            format_args!("ISO15765, CAN_ID_BOTH, {}", baudrate),
+            || device.connect(Protocol::ISO15765, ConnectFlags::CAN_ID_BOTH, baudrate),
+        )?;
+        Ok(PassThruChannel {
+            channel,
+            timeout: timeout.as_millis() as u32,
+            filter: None,
+            keep_alive: None,
+        })
+    }
+
+    /// Opens an ISO-TP channel on `bus`, routing the medium-speed bus to
+    /// pins 3/11
+    pub fn open(
+        device: &'ch j2534::Device,
+        bus: CanBus,
+        timeout: Duration,
+    ) -> Result<PassThruChannel<'ch>, j2534::Error> {
+        let channel = PassThruChannel::new(device, bus.bitrate(), timeout)?;
+        if bus == CanBus::MediumSpeed {
+            traced(
+                "PassThruIoctl",
+                format_args!("SET_CONFIG, J1962_PINS=0x{:04X}", MS_CAN_PINS),
+                || {
+                    channel
+                        .channel
+                        .set_config(ConfigId::J1962_PINS, MS_CAN_PINS)
+                },
+            )?;
+        }
+        Ok(channel)
+    }
+
+    /// Establishes the flow control filter
+    pub fn set_filter(&mut self, source_id: u32, destination_id: u32) -> Result<(), j2534::Error> {
+        if let Some(ref filter) = self.filter {
+            if filter.source_id == source_id && filter.destination_id == destination_id {
+                return Ok(());
+            }
+            let filter_id = filter.id;
+            traced(
+                "PassThruStopMsgFilter",
+                format_args!("{:?}", filter_id),
+                || self.channel.stop_message_filter(filter_id),
+            )?;
+        }
+
+        let flags = isotp_flags(source_id);
+        let mask = PassThruMsg::new_isotp(0xFFFFFFFF, &[]).tx_flags(flags);
+        let pattern = PassThruMsg::new_isotp(destination_id, &[]).tx_flags(flags);
+        let fc_pattern = PassThruMsg::new_isotp(source_id, &[]).tx_flags(flags);
+        let id = traced(
+            "PassThruStartMsgFilter",
+            format_args!(
+                "FLOW_CONTROL, pattern=0x{:X}, flow_control=0x{:X}",
+                destination_id, source_id
+            ),
+            || {
+                self.channel.start_message_filter(
+                    j2534::FilterType::FlowControl,
+                    Some(&mask),
+                    Some(&pattern),
+                    Some(&fc_pattern),
+                )
+            },
+        )?;
+
+        self.filter = Some(PassThruFilter {
+            id,
+            source_id,
+            destination_id,
+        });
+        Ok(())
+    }
+
+    /// Has the adapter send TesterPresent to `request_id` every `interval`,
+    /// so the session is kept alive even when the host is slow to schedule
+    /// the tool. The response is suppressed, so nothing is received. The
+    /// flow control filter for `request_id` must be set first. Replaces a
+    /// keep-alive that is already running.
+    pub fn start_keep_alive(
+        &mut self,
+        request_id: u32,
+        interval: Duration,
+    ) -> Result<(), j2534::Error> {
+        self.stop_keep_alive()?;
+        let interval =
+            (interval.as_millis() as u32).clamp(MIN_PERIODIC_INTERVAL, MAX_PERIODIC_INTERVAL);
+        let message = PassThruMsg::new_isotp(request_id, &[UDS_REQ_TESTER_PRESENT, 0x80])
+            .tx_flags(isotp_flags(request_id));
+        let id = traced(
+            "PassThruStartPeriodicMsg",
+            format_args!("0x{:X} [3E 80], interval={}", request_id, interval),
+            || self.channel.start_periodic_message(&message, interval),
+        )?;
+        self.keep_alive = Some(id);
+        Ok(())
+    }
+
+    /// Stops the keep-alive started with
+    /// [`start_keep_alive`](PassThruChannel::start_keep_alive)
+    pub fn stop_keep_alive(&mut self) -> Result<(), j2534::Error> {
+        if let Some(id) = self.keep_alive.take() {
+            traced("PassThruStopPeriodicMsg", format_args!("{:?}", id), || {
+                self.channel.stop_periodic_message(id)
+            })?;
+        }
+        Ok(())
+    }
+}
+
+impl SetTimeout for PassThruChannel<'_> {
+    fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout.as_millis() as u32;
+    }
+}
+
+impl IsoTp for PassThruChannel<'_> {
+    fn send_isotp(&mut self, id: u32, data: &[u8]) -> Result<(), obd::Error> {
+        let destination_id = self.filter.as_ref().map_or(id + 8, |f| f.destination_id);
+        self.set_filter(id, destination_id)?;
+
+        let message = PassThruMsg::new_isotp(id, data).tx_flags(isotp_flags(id));
+        write_msg(&self.channel, message, self.timeout)?;
+        Ok(())
+    }
+
+    fn read_isotp(&mut self, id: u32) -> Result<Vec<u8>, obd::Error> {
+        let source_id = self.filter.as_ref().map_or(id - 8, |f| f.source_id);
+        self.set_filter(source_id, id)?;
+
+        loop {
+            let message = read_msg(&self.channel, self.timeout)?;
+            // Skip transmit confirmations and first frame indications
+            if message.transmitted() || message.first_frame() {
+                continue;
+            }
+            if let Some((msg_id, data)) = message.isotp_message() {
+                if msg_id == id {
+                    return Ok(data.to_vec());
+                }
+            }
+        }
+    }
+}
+
+/// Raw CAN channel on a PassThru device, receiving every frame until
+/// filters are set
+pub struct PassThruCan<'ch> {
+    channel: Channel<'ch>,
+    /// Pass filters installed on the channel
+    filters: RefCell<Vec<FilterId>>,
+}
+
+impl<'ch> PassThruCan<'ch> {
+    /// Opens a CAN channel at `bitrate` on `device`
+    pub fn new(device: &'ch j2534::Device, bitrate: u32) -> Result<PassThruCan<'ch>, j2534::Error> {
+        let channel = traced(
+            "PassThruConnect",
+            format_args!("CAN, CAN_ID_BOTH, {}", bitrate),
+            || device.connect(Protocol::CAN, ConnectFlags::CAN_ID_BOTH, bitrate),
+        )?;
+        let can = PassThruCan {
+            channel,
+            filters: RefCell::new(Vec::new()),
+        };
+        can.start_filters(&[])?;
+        Ok(can)
+    }
+
+    /// Echoes frames sent on the channel back to it as transmit
+    /// confirmations
+    pub fn set_loopback(&self, enabled: bool) -> Result<(), j2534::Error> {
+        traced(
+            "PassThruIoctl",
+            format_args!("SET_CONFIG, LOOPBACK={}", enabled as u32),
+            || self.channel.set_config(ConfigId::LOOPBACK, enabled as u32),
+        )
+    }
+
+    /// Sends `msg` and returns the time until the adapter echoes it back.
+    /// Loopback must be enabled.
+    pub fn echo(&self, msg: &Message, timeout: Duration) -> io::Result<Duration> {
+        let start = Instant::now();
+        self.send_msg(msg)?;
+        loop {
+            let remaining = timeout
+                .checked_sub(start.elapsed())
+                .ok_or_else(|| io::Error::from(io::ErrorKind::TimedOut))?;
+            let message =
+                read_msg(&self.channel, remaining.as_millis() as u32).map_err(io_error)?;
+            let echoed = message
+                .can_message()
+                .is_some_and(|(id, data)| id == msg.id && data == msg.payload());
+            if message.transmitted() && echoed {
+                return Ok(start.elapsed());
+            }
+        }
+    }
+
+    /// Replaces the pass filters of the channel. No filters passes all
+    /// 11-bit and 29-bit frames.
+    fn start_filters(&self, filters: &[Filter]) -> Result<(), j2534::Error> {
+        for id in self.filters.borrow_mut().drain(..) {
+            traced("PassThruStopMsgFilter", format_args!("{:?}", id), || {
+                self.channel.stop_message_filter(id)
+            })?;
+        }
+        let pass_all = [false, true].map(|extended| Filter {
+            id: 0,
+            mask: 0,
+            extended,
+        });
+        let filters = if filters.is_empty() {
+            &pass_all[..]
+        } else {
+            filters
+        };
+        for filter in filters {
+            let flags = if filter.extended {
+                TxFlags::CAN_29BIT_ID
+            } else {
+                TxFlags::NONE
+            };
+            let mask = PassThruMsg::new_can(filter.mask, &[]).tx_flags(flags);
+            let pattern = PassThruMsg::new_can(filter.id, &[]).tx_flags(flags);
+            let id = traced(
+                "PassThruStartMsgFilter",
+                format_args!(
+                    "PASS, mask=0x{:X}, pattern=0x{:X}, extended={}",
+                    filter.mask, filter.id, filter.extended
+                ),
+                || {
+                    self.channel.start_message_filter(
+                        FilterType::Pass,
+                        Some(&mask),
+                        Some(&pattern),
+                        None,
+                    )
+                },
+            )?;
+            self.filters.borrow_mut().push(id);
+        }
+        Ok(())
+    }
+}
+
+fn io_error(err: j2534::Error) -> io::Error {
+    match err {
+        j2534::Error::Timeout | j2534::Error::BufferEmpty => {
+            io::Error::new(io::ErrorKind::TimedOut, err)
+        }
+        err => io::Error::other(err),
+    }
+}
+
+impl Can for PassThruCan<'_> {
+    fn send_msg(&self, msg: &Message) -> io::Result<()> {
+        let mut message = PassThruMsg::new_can(msg.id, msg.payload());
+        if msg.extended {
+            message = message.tx_flags(TxFlags::CAN_29BIT_ID);
+        }
+        write_msg(&self.channel, message, 0).map_err(io_error)?;
+        Ok(())
+    }
+
+    fn read(&self, timeout: Duration) -> io::Result<Message> {
+        loop {
+            let message = read_msg(&self.channel, timeout.as_millis() as u32).map_err(io_error)?;
+            if message.transmitted() {
+                continue;
+            }
+            let extended = message.rx_status & RxStatus::CAN_29BIT_ID.bits() != 0;
+            match message.can_message() {
+                Some((id, data)) if data.len() <= 8 => {
+                    return Ok(Message {
+                        extended,
+                        ..Message::new(id, data)
+                    })
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    fn set_filters(&self, filters: &[Filter]) -> io::Result<()> {
+        self.start_filters(filters).map_err(io_error)
+    }
+}
+
+/// Creates an ISO 14230 message holding raw bytes
+fn kline_message(data: &[u8]) -> PassThruMsg {
+    let mut message = PassThruMsg::new(Protocol::ISO14230);
+    message.data[..data.len()].copy_from_slice(data);
+    message.data_size = data.len() as u32;
+    message
+}
+
+/// PassThru K-line channel carrying KWP2000 frames. Checksums are computed
+/// by [`Kwp`](crate::kwp::Kwp) rather than the adapter.
+pub struct PassThruKLine<'ch> {
+    channel: Channel<'ch>,
+    // Timeout in milliseconds
+    timeout: u32,
+}
+
+impl<'ch> PassThruKLine<'ch> {
+    /// Opens an ISO 14230 channel on `device`
+    pub fn new(
+        device: &'ch j2534::Device,
+        timeout: Duration,
+    ) -> Result<PassThruKLine<'ch>, j2534::Error> {
+        let channel = traced(
+            "PassThruConnect",
+            format_args!(
+                "ISO14230, ISO9141_NO_CHECKSUM | ISO9141_K_LINE_ONLY, {}",
+                KLINE_BAUDRATE
+            ),
+            || {
+                device.connect(
+                    Protocol::ISO14230,
+                    ConnectFlags::ISO9141_NO_CHECKSUM | ConnectFlags::ISO9141_K_LINE_ONLY,
+                    KLINE_BAUDRATE,
+                )
+            },
+        )?;
+        // Receive every frame
+        let pass = kline_message(&[0]);
+        traced("PassThruStartMsgFilter", format_args!("PASS, all"), || {
+            channel.start_message_filter(FilterType::Pass, Some(&pass), Some(&pass), None)
+        })?;
+        Ok(PassThruKLine {
+            channel,
+            timeout: timeout.as_millis() as u32,
+        })
+    }
+}
+
+impl PassThruKLine<'_> {
+    fn set_data_rate(&self, baudrate: u32) -> Result<(), j2534::Error> {
+        traced(
+            "PassThruIoctl",
+            format_args!("SET_CONFIG, DATA_RATE={}", baudrate),
+            || self.channel.set_config(ConfigId::DATA_RATE, baudrate),
+        )
+    }
+}
+
+impl SetTimeout for PassThruKLine<'_> {
+    fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout.as_millis() as u32;
+    }
+}
+
+impl KLine for PassThruKLine<'_> {
+    fn wake_up(&mut self) -> Result<(), obd::Error> {
+        // The j2534 crate doesn't expose the FAST_INIT ioctl, so the pattern
+        // is made by sending a zero byte at a low baud rate
+        self.set_data_rate(WAKE_UP_BAUDRATE)?;
+        write_msg(&self.channel, kline_message(&[0]), self.timeout)?;
+        thread::sleep(WAKE_UP_TIME);
+        self.set_data_rate(KLINE_BAUDRATE)?;
+        traced("PassThruIoctl", format_args!("CLEAR_RX_BUFFER"), || {
+            self.channel.clear_receive_buffer()
+        })?;
+        Ok(())
+    }
+
+    fn send_frame(&mut self, frame: &[u8]) -> Result<(), obd::Error> {
+        write_msg(&self.channel, kline_message(frame), self.timeout)?;
+        Ok(())
+    }
+
+    fn read_frame(&mut self) -> Result<Vec<u8>, obd::Error> {
+        loop {
+            let message = read_msg(&self.channel, self.timeout)?;
+            // Skip the echo of our own frames
+            if message.transmitted() {
+                continue;
+            }
+            let length = message.data_size as usize;
+            return Ok(message.data[..length].to_vec());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selects_library_by_path() {
+        let path = std::env::temp_dir().join("mzr-test-op20pt32.dll");
+        std::fs::write(&path, b"").unwrap();
+        let driver = select_driver(Some(&path), Some("ignored")).unwrap();
+        assert_eq!(driver.name, "mzr-test-op20pt32");
+        assert_eq!(driver.path, path.to_string_lossy());
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(
+            select_driver(Some(&path), None),
+            Err(DeviceError::LibraryNotFound(missing)) if missing == path
+        ));
+    }
+}
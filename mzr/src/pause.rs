@@ -0,0 +1,38 @@
+//! Pausing of long-running operations
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Shared flag used to pause an operation between blocks. Clones share the
+/// same flag.
+#[derive(Debug, Clone, Default)]
+pub struct PauseToken {
+    paused: Arc<AtomicBool>,
+}
+
+impl PauseToken {
+    pub fn new() -> PauseToken {
+        PauseToken::default()
+    }
+
+    /// Pauses the operation before the next block
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+    }
+
+    /// Resumes a paused operation
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+    }
+
+    /// Pauses a running operation or resumes a paused one. Returns true if
+    /// the operation is now paused.
+    pub fn toggle(&self) -> bool {
+        !self.paused.fetch_xor(true, Ordering::SeqCst)
+    }
+
+    /// Returns true if the operation should be paused
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,155 @@
+//! Typed reads and writes of live ECU memory, for experimenting on the
+//! bench. Values are big-endian, like the SuperH CPU of the ECU.
+
+use obd::Uds;
+use thiserror::Error;
+
+use crate::{MzrBus, MzrError};
+
+pub use mzr_rom::table::Width;
+
+/// How values are printed
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Format {
+    Hex,
+    Unsigned,
+    Signed,
+    /// IEEE 754 single precision. Only valid for [`Width::Long`].
+    Float,
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ValueError {
+    #[error("invalid value '{0}'")]
+    Invalid(String),
+    #[error("{0} does not fit in {1} bytes")]
+    OutOfRange(String, usize),
+    #[error("floats are 4 bytes wide")]
+    FloatWidth,
+}
+
+/// Formats the values of `width` in `data`. A trailing partial value is
+/// ignored.
+pub fn format_values(data: &[u8], width: Width, format: Format) -> Result<Vec<String>, ValueError> {
+    if format == Format::Float && width != Width::Long {
+        return Err(ValueError::FloatWidth);
+    }
+    let bytes = width.bytes();
+    Ok(data
+        .chunks_exact(bytes)
+        .map(|chunk| {
+            let value = chunk.iter().fold(0u32, |value, &b| value << 8 | b as u32);
+            match format {
+                Format::Hex => format!("0x{:0width$X}", value, width = bytes * 2),
+                Format::Unsigned => value.to_string(),
+                Format::Signed => {
+                    // Sign-extend from the value's width
+                    let shift = 32 - bytes as u32 * 8;
+                    (((value << shift) as i32) >> shift).to_string()
+                }
+                Format::Float => f32::from_bits(value).to_string(),
+            }
+        })
+        .collect())
+}
+
+/// Encodes a value typed by the user: hex with a `0x` prefix, a decimal
+/// that may be negative, or a float for 4-byte values
+pub fn parse_value(value: &str, width: Width) -> Result<Vec<u8>, ValueError> {
+    let bytes = width.bytes();
+    let invalid = || ValueError::Invalid(value.to_string());
+    let bits: u32 = if let Some(hex) = value.strip_prefix("0x") {
+        let parsed = u32::from_str_radix(hex, 16).map_err(|_| invalid())?;
+        if bytes < 4 && parsed >> (bytes * 8) != 0 {
+            return Err(ValueError::OutOfRange(value.to_string(), bytes));
+        }
+        parsed
+    } else if let Ok(parsed) = value.parse::<i64>() {
+        let min = -(1i64 << (bytes * 8 - 1));
+        let max = (1i64 << (bytes * 8)) - 1;
+        if parsed < min || parsed > max {
+            return Err(ValueError::OutOfRange(value.to_string(), bytes));
+        }
+        parsed as u32
+    } else {
+        let parsed = value.parse::<f32>().map_err(|_| invalid())?;
+        if width != Width::Long {
+            return Err(ValueError::FloatWidth);
+        }
+        parsed.to_bits()
+    };
+    Ok(bits.to_be_bytes()[4 - bytes..].to_vec())
+}
+
+/// Reads `count` values of `width` at `address`
+pub fn peek<B: Uds>(
+    bus: &mut B,
+    request_id: u32,
+    address: u32,
+    width: Width,
+    count: usize,
+) -> Result<Vec<u8>, MzrError> {
+    let length = width.bytes() * count;
+    let data = bus.read_memory_address(request_id, address, length as u16)?;
+    if data.len() != length {
+        return Err(MzrError::InvalidResponse);
+    }
+    Ok(data)
+}
+
+/// Writes `data` at `address` with WriteMemoryByAddress
+pub fn poke<B: MzrBus>(
+    bus: &mut B,
+    request_id: u32,
+    address: u32,
+    data: &[u8],
+) -> Result<(), MzrError> {
+    bus.write_memory(request_id, address, data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_and_parses_values() {
+        let data = [0xFF, 0xFE, 0x42, 0x28, 0x00, 0x00];
+        assert_eq!(
+            format_values(&data, Width::Word, Format::Hex).unwrap(),
+            vec!["0xFFFE", "0x4228", "0x0000"]
+        );
+        assert_eq!(
+            format_values(&data, Width::Word, Format::Signed).unwrap(),
+            vec!["-2", "16936", "0"]
+        );
+        assert_eq!(
+            format_values(&data[2..], Width::Long, Format::Float).unwrap(),
+            vec!["42"]
+        );
+        assert_eq!(
+            format_values(&data, Width::Byte, Format::Float),
+            Err(ValueError::FloatWidth)
+        );
+
+        assert_eq!(parse_value("0x12", Width::Word), Ok(vec![0x00, 0x12]));
+        assert_eq!(parse_value("-2", Width::Word), Ok(vec![0xFF, 0xFE]));
+        assert_eq!(parse_value("255", Width::Byte), Ok(vec![0xFF]));
+        assert_eq!(
+            parse_value("42.0", Width::Long),
+            Ok(vec![0x42, 0x28, 0x00, 0x00])
+        );
+        assert_eq!(
+            parse_value("256", Width::Byte),
+            Err(ValueError::OutOfRange("256".to_string(), 1))
+        );
+        assert_eq!(
+            parse_value("0x100", Width::Byte),
+            Err(ValueError::OutOfRange("0x100".to_string(), 1))
+        );
+        assert_eq!(parse_value("1.5", Width::Word), Err(ValueError::FloatWidth));
+        assert_eq!(
+            parse_value("abc", Width::Long),
+            Err(ValueError::Invalid("abc".to_string()))
+        );
+    }
+}
@@ -0,0 +1,389 @@
+//! Probing of what an ECU supports, for reverse engineering ECUs and model
+//! years the tools don't know yet. Probes never write memory or start
+//! routines.
+
+use std::ops::RangeInclusive;
+use std::thread;
+use std::time::Duration;
+
+use obd::Uds;
+use serde::Serialize;
+
+use crate::memory_map::Region;
+use crate::profile::KeyAlgorithm;
+use crate::{MzrBus, MzrError, UDS_REQ_SECURITY};
+
+const UDS_REQ_ROUTINECONTROL: u8 = 0x31;
+/// RoutineControl sub-function requesting the results of a routine, which
+/// tells whether it exists without starting it
+const ROUTINE_REQUEST_RESULTS: u8 = 0x03;
+
+/* Negative response codes */
+const NRC_SERVICE_NOT_SUPPORTED: u8 = 0x11;
+const NRC_SUBFUNCTION_NOT_SUPPORTED: u8 = 0x12;
+/// Negative response to identifiers the ECU doesn't support
+const NRC_REQUEST_OUT_OF_RANGE: u8 = 0x31;
+const NRC_EXCEEDED_ATTEMPTS: u8 = 0x36;
+const NRC_TIME_DELAY_NOT_EXPIRED: u8 = 0x37;
+const NRC_SERVICE_NOT_SUPPORTED_IN_SESSION: u8 = 0x7F;
+
+/// Security access levels probed by [`scan_security_levels`]: the odd seed
+/// request levels of ISO 14229
+pub const SECURITY_LEVELS: RangeInclusive<u8> = 0x01..=0x7D;
+
+/// Delay ECUs impose after a rejected key
+const SECURITY_DELAY: Duration = Duration::from_secs(10);
+
+/// Data identifier that answered ReadDataByIdentifier
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DidEntry {
+    pub did: u16,
+    /// Length of the payload of a positive response
+    pub length: Option<usize>,
+    /// Negative response code other than requestOutOfRange, e.g. 0x33 for
+    /// identifiers that need security access
+    pub nrc: Option<u8>,
+}
+
+/// Reads every data identifier in `dids` from the ECU at `request_id` and
+/// returns those that exist. `progress` is called with each identifier
+/// before it is read. Fails on bus errors, e.g. when the ECU stops
+/// responding.
+pub fn scan_dids<B, F>(
+    bus: &mut B,
+    request_id: u32,
+    dids: RangeInclusive<u16>,
+    mut progress: F,
+) -> Result<Vec<DidEntry>, MzrError>
+where
+    B: Uds,
+    F: FnMut(u16),
+{
+    let mut entries = Vec::new();
+    for did in dids {
+        progress(did);
+        let entry = match bus.read_identifier(request_id, did) {
+            Ok(data) => DidEntry {
+                did,
+                length: Some(data.len()),
+                nrc: None,
+            },
+            Err(err) => match err.bus_error() {
+                Some(obd::Error::NegativeResponse(Some(NRC_REQUEST_OUT_OF_RANGE))) => continue,
+                Some(obd::Error::NegativeResponse(nrc)) => DidEntry {
+                    did,
+                    length: None,
+                    nrc: *nrc,
+                },
+                _ => return Err(err),
+            },
+        };
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// Security access level that answered a seed request
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SecurityLevel {
+    /// Seed request level. The key is sent with `level + 1`.
+    pub level: u8,
+    /// Length of the seed, if one was sent
+    pub seed_length: Option<usize>,
+    /// Whether the key computed with the probed algorithm was accepted
+    pub granted: bool,
+    /// Negative response code of the seed request or the key
+    pub nrc: Option<u8>,
+}
+
+/// Requests a seed at every odd level in `levels` and answers it with the
+/// key computed by `algorithm`. Levels the ECU doesn't support are left out.
+/// The scan stops early if the ECU locks security access after too many
+/// rejected keys.
+pub fn scan_security_levels<B, F>(
+    bus: &mut B,
+    request_id: u32,
+    levels: RangeInclusive<u8>,
+    algorithm: &KeyAlgorithm,
+    mut progress: F,
+) -> Result<Vec<SecurityLevel>, MzrError>
+where
+    B: Uds,
+    F: FnMut(u8),
+{
+    let mut entries = Vec::new();
+    for level in levels.filter(|level| level % 2 == 1) {
+        progress(level);
+        let mut response = bus.query_uds(request_id, UDS_REQ_SECURITY, &[level]);
+        if let Err(obd::Error::NegativeResponse(Some(NRC_TIME_DELAY_NOT_EXPIRED))) = response {
+            thread::sleep(SECURITY_DELAY);
+            response = bus.query_uds(request_id, UDS_REQ_SECURITY, &[level]);
+        }
+        let seed = match response {
+            Ok(response) => match response.split_first() {
+                Some((&access_type, seed)) if access_type == level => seed.to_vec(),
+                _ => return Err(MzrError::InvalidResponse),
+            },
+            Err(obd::Error::NegativeResponse(Some(
+                NRC_SUBFUNCTION_NOT_SUPPORTED | NRC_REQUEST_OUT_OF_RANGE,
+            ))) => continue,
+            Err(obd::Error::NegativeResponse(nrc)) => {
+                entries.push(SecurityLevel {
+                    level,
+                    seed_length: None,
+                    granted: false,
+                    nrc,
+                });
+                continue;
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        // A zero seed means the level is already unlocked
+        let mut entry = SecurityLevel {
+            level,
+            seed_length: Some(seed.len()),
+            granted: seed.iter().all(|&b| b == 0),
+            nrc: None,
+        };
+        if !entry.granted {
+            let mut request = vec![level + 1];
+            request.extend_from_slice(&algorithm.key(&seed));
+            match bus.query_uds(request_id, UDS_REQ_SECURITY, &request) {
+                Ok(_) => entry.granted = true,
+                Err(obd::Error::NegativeResponse(nrc)) => entry.nrc = nrc,
+                Err(err) => return Err(err.into()),
+            }
+        }
+        let locked = entry.nrc == Some(NRC_EXCEEDED_ATTEMPTS);
+        entries.push(entry);
+        if locked {
+            break;
+        }
+    }
+    Ok(entries)
+}
+
+/// Routine identifier that exists on the ECU
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RoutineEntry {
+    pub id: u16,
+    /// Negative response code of the results request, e.g. 0x24 for
+    /// routines that haven't been started. `None` if results were returned.
+    pub nrc: Option<u8>,
+}
+
+/// Requests the results of every routine identifier in `ids` with
+/// RoutineControl and returns those that exist. Routines are never started.
+/// Fails if the ECU doesn't support RoutineControl in the active session.
+pub fn scan_routines<B, F>(
+    bus: &mut B,
+    request_id: u32,
+    ids: RangeInclusive<u16>,
+    mut progress: F,
+) -> Result<Vec<RoutineEntry>, MzrError>
+where
+    B: Uds,
+    F: FnMut(u16),
+{
+    let mut entries = Vec::new();
+    for id in ids {
+        progress(id);
+        let [high, low] = id.to_be_bytes();
+        let request = [ROUTINE_REQUEST_RESULTS, high, low];
+        let nrc = match bus.query_uds(request_id, UDS_REQ_ROUTINECONTROL, &request) {
+            Ok(_) => None,
+            Err(
+                err @ obd::Error::NegativeResponse(Some(
+                    NRC_SERVICE_NOT_SUPPORTED | NRC_SERVICE_NOT_SUPPORTED_IN_SESSION,
+                )),
+            ) => return Err(err.into()),
+            Err(obd::Error::NegativeResponse(Some(NRC_REQUEST_OUT_OF_RANGE))) => continue,
+            Err(obd::Error::NegativeResponse(nrc)) => nrc,
+            Err(err) => return Err(err.into()),
+        };
+        entries.push(RoutineEntry { id, nrc });
+    }
+    Ok(entries)
+}
+
+/// Returns true if the byte at `address` can be read with
+/// ReadMemoryByAddress
+fn readable<B: Uds>(bus: &mut B, request_id: u32, address: u32) -> Result<bool, MzrError> {
+    match bus.read_memory_address(request_id, address, 1) {
+        Ok(_) => Ok(true),
+        Err(obd::Error::NegativeResponse(_)) => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Maps the regions of `space` the ECU allows reading with
+/// ReadMemoryByAddress. The space is sampled every `step` bytes and each
+/// change between readable and unreadable samples is bisected down to the
+/// byte, so regions smaller than `step` may be missed. `progress` is called
+/// with each address read.
+pub fn map_readable<B, F>(
+    bus: &mut B,
+    request_id: u32,
+    space: Region,
+    step: u32,
+    mut progress: F,
+) -> Result<Vec<Region>, MzrError>
+where
+    B: Uds,
+    F: FnMut(u32),
+{
+    assert!(step > 0);
+    let mut probe = |address| {
+        progress(address);
+        readable(bus, request_id, address)
+    };
+    let mut regions = Vec::new();
+    let mut previous = space.start;
+    let mut region_start = if probe(space.start)? {
+        Some(space.start)
+    } else {
+        None
+    };
+    let samples = (space.start..space.end).step_by(step as usize).skip(1);
+    for address in samples {
+        let state = probe(address)?;
+        if state != region_start.is_some() {
+            // Find the first address in (previous, address] with the new state
+            let (mut low, mut high) = (previous, address);
+            while high - low > 1 {
+                let middle = low + (high - low) / 2;
+                if probe(middle)? == state {
+                    high = middle;
+                } else {
+                    low = middle;
+                }
+            }
+            match region_start.take() {
+                Some(start) => regions.push(Region::new(start, high)),
+                None => region_start = Some(high),
+            }
+        }
+        previous = address;
+    }
+    if let Some(start) = region_start {
+        regions.push(Region::new(start, space.end));
+    }
+    Ok(regions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockEcu;
+
+    const SEED: [u8; 3] = [0x12, 0x34, 0x56];
+
+    /// ECU supporting 0xF188, and 0xF190 after security access. Security
+    /// level 1 takes Mazda's key and level 3 needs another session. Routine
+    /// 0xFF00 has results and 0xFF01 hasn't been started. Memory can be read
+    /// at 0x1000–0x2344 and 0x8000–0x8FFF.
+    fn ecu() -> MockEcu<()> {
+        MockEcu::new((), |_, request| {
+            let key = KeyAlgorithm::Mazda.key(&SEED);
+            Some(match request {
+                [0x22, 0xF1, 0x88] => vec![0x62, 0xF1, 0x88, b'L', b'3', b'K', b'9'],
+                [0x22, 0xF1, 0x90] => vec![0x7F, 0x22, 0x33],
+                [0x22, ..] => vec![0x7F, 0x22, NRC_REQUEST_OUT_OF_RANGE],
+                [0x27, 0x01] => [&[0x67, 0x01][..], &SEED].concat(),
+                [0x27, 0x02, rest @ ..] if rest == key => vec![0x67, 0x02],
+                [0x27, 0x03] => vec![0x7F, 0x27, 0x22],
+                [0x27, ..] => vec![0x7F, 0x27, NRC_SUBFUNCTION_NOT_SUPPORTED],
+                [0x31, 0x03, 0xFF, 0x00] => vec![0x71, 0x03, 0xFF, 0x00, 0x10],
+                [0x31, 0x03, 0xFF, 0x01] => vec![0x7F, 0x31, 0x24],
+                [0x31, ..] => vec![0x7F, 0x31, NRC_REQUEST_OUT_OF_RANGE],
+                [0x23, a, b, c, d, ..] => match u32::from_be_bytes([*a, *b, *c, *d]) {
+                    0x1000..=0x2344 | 0x8000..=0x8FFF => vec![0x63, 0xFF],
+                    _ => vec![0x7F, 0x23, NRC_REQUEST_OUT_OF_RANGE],
+                },
+                _ => return None,
+            })
+        })
+    }
+
+    #[test]
+    fn scans_dids() {
+        let mut read = 0;
+        let entries = scan_dids(&mut ecu(), 0x7E0, 0xF180..=0xF19F, |_| read += 1).unwrap();
+        assert_eq!(read, 32);
+        assert_eq!(
+            entries,
+            vec![
+                DidEntry {
+                    did: 0xF188,
+                    length: Some(4),
+                    nrc: None,
+                },
+                DidEntry {
+                    did: 0xF190,
+                    length: None,
+                    nrc: Some(0x33),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn scans_security_and_routines() {
+        let levels = scan_security_levels(
+            &mut ecu(),
+            0x7E0,
+            SECURITY_LEVELS,
+            &KeyAlgorithm::Mazda,
+            |_| {},
+        )
+        .unwrap();
+        assert_eq!(
+            levels,
+            vec![
+                SecurityLevel {
+                    level: 1,
+                    seed_length: Some(3),
+                    granted: true,
+                    nrc: None,
+                },
+                SecurityLevel {
+                    level: 3,
+                    seed_length: None,
+                    granted: false,
+                    nrc: Some(0x22),
+                },
+            ]
+        );
+
+        let routines = scan_routines(&mut ecu(), 0x7E0, 0xFEFF..=0xFF02, |_| {}).unwrap();
+        assert_eq!(
+            routines,
+            vec![
+                RoutineEntry {
+                    id: 0xFF00,
+                    nrc: None
+                },
+                RoutineEntry {
+                    id: 0xFF01,
+                    nrc: Some(0x24),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn maps_readable_memory() {
+        let mut reads = 0;
+        let regions = map_readable(&mut ecu(), 0x7E0, Region::new(0, 0x10000), 0x800, |_| {
+            reads += 1
+        })
+        .unwrap();
+        assert_eq!(
+            regions,
+            vec![Region::new(0x1000, 0x2345), Region::new(0x8000, 0x9000)]
+        );
+        // 32 samples, and 11 reads to bisect each of the four boundaries
+        assert_eq!(reads, 32 + 4 * 11);
+    }
+}
@@ -0,0 +1,309 @@
+//! ECU profiles. A profile describes everything the download and flash
+//! engines need to know about an ECU: CAN IDs, diagnostic sessions, security
+//! access, the erase and checksum routines and the memory map, and the
+//! outputs it can drive for [actuator tests](crate::actuator). Built-in
+//! profiles cover the first and second-generation MZR-DISI ECUs and the
+//! naturally aspirated MZR ECUs, and are selected by vehicle model. Advanced
+//! users can describe other UDS ECUs in a TOML file:
+//!
+//! ```toml
+//! name = "my-ecu"
+//! request_id = 0x7e0
+//! response_id = 0x7e8
+//! download_session = 0x87
+//! programming_session = 0x85
+//! security_level = 1
+//! key_algorithm = "mazda"
+//! erase_routine = [0x00, 0xB2, 0x00]
+//! checksum_routine = 0xFF01
+//!
+//! [memory_map]
+//! name = "my-ecu-512k"
+//! bootloader = { start = 0, end = 0x4000 }
+//! code = { start = 0x4000, end = 0x40000 }
+//! calibration = { start = 0x40000, end = 0x80000 }
+//! ram = { start = 0xFFFF8000, end = 0xFFFFC000 }
+//! sectors = [0, 0x4000, 0x20000, 0x40000, 0x60000]
+//! checksums = [{ start = 0x40000, end = 0x80000, target = 0x5AA55AA5 }]
+//! eeprom = { base = 0x00800000, size = 0x800, page_size = 0x10 }
+//! ```
+//!
+//! ECUs using Mazda's algorithm with another secret take
+//! `key_algorithm = { mazda-custom = { secret = "...", parameter = 0x123456 } }`.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use mzr_core::key::{generate_key, MZR_KEY, MZR_KEY_PARAMETER};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::actuator::Actuator;
+use crate::config::Config;
+use crate::memory_map::MzrMemoryMap;
+use crate::vin::Vin;
+use crate::{
+    DownloaderBuilder, ProgrammerBuilder, DEFAULT_REQUEST_ID, ERASE_ROUTINE_DEFAULT,
+    SECURITY_LEVEL_DEFAULT, SESSION_DOWNLOAD, SESSION_DOWNLOAD_NA, SESSION_PROGRAMMING,
+};
+
+#[derive(Error, Debug)]
+pub enum ProfileError {
+    #[error("failed to read profile: {0}")]
+    Io(#[from] io::Error),
+    #[error("invalid profile: {0}")]
+    Parse(#[from] toml::de::Error),
+    #[error("unknown vehicle model {0}")]
+    UnknownModel(String),
+}
+
+/// Algorithm computing the security access key from the seed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum KeyAlgorithm {
+    /// Mazda's algorithm with the secret used by MZR ECUs
+    Mazda,
+    /// Mazda's algorithm with another secret and initial value
+    MazdaCustom { secret: String, parameter: u32 },
+}
+
+impl KeyAlgorithm {
+    /// Computes the key for `seed`
+    pub fn key(&self, seed: &[u8]) -> [u8; 3] {
+        match self {
+            KeyAlgorithm::Mazda => generate_key(MZR_KEY, MZR_KEY_PARAMETER, seed),
+            KeyAlgorithm::MazdaCustom { secret, parameter } => {
+                generate_key(secret, *parameter, seed)
+            }
+        }
+    }
+}
+
+/// Description of an ECU the download and flash engines can operate on
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EcuProfile {
+    /// Name of the profile
+    pub name: String,
+    /// Arbitration ID requests are sent to
+    pub request_id: u32,
+    /// Arbitration ID the ECU responds with
+    pub response_id: u32,
+    /// Diagnostic session used to read memory
+    pub download_session: u8,
+    /// Diagnostic session used to program flash
+    pub programming_session: u8,
+    /// Security access level requested in both sessions
+    pub security_level: u8,
+    /// Algorithm answering security access seeds
+    pub key_algorithm: KeyAlgorithm,
+    /// Parameters passed to the bootloader's erase routine
+    pub erase_routine: Vec<u8>,
+    /// RoutineControl identifier of the bootloader's checksum routine, run
+    /// over each checksummed region after programming
+    pub checksum_routine: Option<u16>,
+    /// Layout of the ECU's memory
+    pub memory_map: MzrMemoryMap,
+    /// Whether flashing is supported. Profiles that only support dumping
+    /// and checksumming are refused by the flash tool.
+    pub flash_supported: bool,
+    /// Outputs the ECU can drive, by name
+    pub actuators: Vec<Actuator>,
+}
+
+impl Default for EcuProfile {
+    fn default() -> EcuProfile {
+        EcuProfile::mzr_disi()
+    }
+}
+
+impl EcuProfile {
+    /// MZR-DISI ECU of the Mazdaspeed6, CX-7 and 2007–2009 Mazdaspeed3
+    pub fn mzr_disi() -> EcuProfile {
+        EcuProfile {
+            name: "mzr-disi".to_string(),
+            request_id: DEFAULT_REQUEST_ID,
+            response_id: DEFAULT_REQUEST_ID + 8,
+            download_session: SESSION_DOWNLOAD,
+            programming_session: SESSION_PROGRAMMING,
+            security_level: SECURITY_LEVEL_DEFAULT,
+            key_algorithm: KeyAlgorithm::Mazda,
+            erase_routine: ERASE_ROUTINE_DEFAULT.to_vec(),
+            checksum_routine: None,
+            memory_map: MzrMemoryMap::mzr_disi_1m(),
+            flash_supported: true,
+            actuators: Vec::new(),
+        }
+    }
+
+    /// MZR-DISI ECU of the 2010–2013 Mazdaspeed3. Its bootloader takes a
+    /// different erase routine and its flash is larger.
+    pub fn mzr_disi_gen2() -> EcuProfile {
+        EcuProfile {
+            name: "mzr-disi-gen2".to_string(),
+            erase_routine: vec![0x00, 0xB3, 0x00],
+            memory_map: MzrMemoryMap::mzr_disi_gen2(),
+            ..EcuProfile::mzr_disi()
+        }
+    }
+
+    /// ECU of the naturally aspirated 2.0 and 2.3 MZR engines in the Mazda3
+    /// and Mazda6. Only dumping and checksumming are supported.
+    pub fn mzr_na() -> EcuProfile {
+        EcuProfile {
+            name: "mzr-na".to_string(),
+            download_session: SESSION_DOWNLOAD_NA,
+            memory_map: MzrMemoryMap::mzr_na_512k(),
+            flash_supported: false,
+            ..EcuProfile::mzr_disi()
+        }
+    }
+
+    /// Returns the built-in profile for a vehicle model, e.g. `ms6` or
+    /// `ms3-gen2`. Profile names are accepted too.
+    pub fn for_model(model: &str) -> Option<EcuProfile> {
+        match model.to_ascii_lowercase().as_str() {
+            "ms3" | "mps3" | "mazdaspeed3" | "ms6" | "mps6" | "mazdaspeed6" | "cx7" | "cx-7"
+            | "mzr-disi" => Some(EcuProfile::mzr_disi()),
+            "ms3-gen2" | "mps3-gen2" | "mazdaspeed3-gen2" | "mzr-disi-gen2" => {
+                Some(EcuProfile::mzr_disi_gen2())
+            }
+            "mazda3" | "mazda6" | "mzr-na" => Some(EcuProfile::mzr_na()),
+            _ => None,
+        }
+    }
+
+    /// Returns the built-in profile for the vehicle with `vin`, or `None` if
+    /// the vehicle isn't recognized
+    pub fn for_vin(vin: &str) -> Option<EcuProfile> {
+        Vin::parse(vin)
+            .ok()?
+            .model()
+            .and_then(EcuProfile::for_model)
+    }
+
+    /// Selects the profile of a command: the profile file at `path` if
+    /// given, otherwise the built-in profile for `model` or the configured
+    /// model, using the configured CAN IDs. Returns `None` if no model is
+    /// known, in which case the profile is detected from the VIN with
+    /// [`detect`](EcuProfile::detect).
+    pub fn select(
+        path: Option<&str>,
+        model: Option<&str>,
+        config: &Config,
+    ) -> Result<Option<EcuProfile>, ProfileError> {
+        if let Some(path) = path {
+            return EcuProfile::load(path).map(Some);
+        }
+        match model.or(config.model.as_deref()) {
+            Some(model) => EcuProfile::for_model(model)
+                .map(|profile| Some(profile.with_ids(config)))
+                .ok_or_else(|| ProfileError::UnknownModel(model.to_string())),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the built-in profile for the vehicle with `vin`, or the
+    /// MZR-DISI profile if it isn't recognized, using the configured CAN IDs
+    pub fn detect(vin: Option<&str>, config: &Config) -> EcuProfile {
+        vin.and_then(EcuProfile::for_vin)
+            .unwrap_or_default()
+            .with_ids(config)
+    }
+
+    fn with_ids(self, config: &Config) -> EcuProfile {
+        EcuProfile {
+            request_id: config.request_id,
+            response_id: config.response_id,
+            ..self
+        }
+    }
+
+    /// Loads a profile from a TOML file. Missing fields take the values of
+    /// the MZR-DISI profile.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<EcuProfile, ProfileError> {
+        Ok(toml::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    /// Returns the actuator called `name`
+    pub fn actuator(&self, name: &str) -> Option<&Actuator> {
+        self.actuators
+            .iter()
+            .find(|actuator| actuator.name.eq_ignore_ascii_case(name))
+    }
+
+    /// Returns a downloader builder configured for this ECU
+    pub fn downloader(&self) -> DownloaderBuilder {
+        DownloaderBuilder::new()
+            .request_id(self.request_id)
+            .session(self.download_session)
+            .security_level(self.security_level)
+            .key_algorithm(self.key_algorithm.clone())
+            .memory_map(self.memory_map.clone())
+    }
+
+    /// Returns a programmer builder configured for this ECU
+    pub fn programmer(&self) -> ProgrammerBuilder {
+        ProgrammerBuilder::new()
+            .request_id(self.request_id)
+            .session(self.programming_session)
+            .security_level(self.security_level)
+            .key_algorithm(self.key_algorithm.clone())
+            .erase_routine(self.erase_routine.clone())
+            .checksum_routine(self.checksum_routine)
+            .memory_map(self.memory_map.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_profile() {
+        let profile: EcuProfile = toml::from_str(
+            "name = \"custom\"\n\
+             download_session = 0x86\n\
+             key_algorithm = { mazda-custom = { secret = \"Other\", parameter = 0x123456 } }\n",
+        )
+        .unwrap();
+        assert_eq!(profile.name, "custom");
+        assert_eq!(profile.download_session, 0x86);
+        assert_eq!(profile.programming_session, SESSION_PROGRAMMING);
+        assert_eq!(profile.memory_map, MzrMemoryMap::mzr_disi_1m());
+        assert_ne!(
+            profile.key_algorithm.key(&[1, 2, 3]),
+            KeyAlgorithm::Mazda.key(&[1, 2, 3])
+        );
+
+        let profile: EcuProfile = toml::from_str(
+            "[memory_map]\n\
+             name = \"small\"\n\
+             bootloader = { start = 0, end = 0x4000 }\n\
+             code = { start = 0x4000, end = 0x40000 }\n\
+             calibration = { start = 0x40000, end = 0x80000 }\n\
+             ram = { start = 0xFFFF8000, end = 0xFFFFC000 }\n\
+             sectors = [0, 0x4000, 0x20000, 0x40000, 0x60000]\n\
+             checksums = [{ start = 0x40000, end = 0x80000, target = 0x5AA55AA5 }]\n",
+        )
+        .unwrap();
+        assert_eq!(profile.memory_map.flash_size(), 0x80000);
+        assert_eq!(profile.memory_map.sector(0x50000).unwrap().start, 0x40000);
+
+        let gen2 = EcuProfile::for_model("MS3-Gen2").unwrap();
+        assert_eq!(gen2.memory_map.flash_size(), 0x180000);
+        assert_ne!(gen2.erase_routine, EcuProfile::default().erase_routine);
+        assert_eq!(EcuProfile::for_model("mps6"), Some(EcuProfile::mzr_disi()));
+        assert!(!EcuProfile::for_model("mazda3").unwrap().flash_supported);
+        assert!(EcuProfile::for_model("protege").is_none());
+        assert_eq!(
+            EcuProfile::detect(Some("JM1BL1ML3A1234567"), &Config::default()).name,
+            "mzr-disi-gen2"
+        );
+        assert_eq!(
+            EcuProfile::detect(None, &Config::default()).name,
+            "mzr-disi"
+        );
+    }
+}
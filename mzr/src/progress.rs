@@ -0,0 +1,186 @@
+//! Progress reporting for blocking operations
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::MzrError;
+
+/// Weight given to the latest block when smoothing the instantaneous rate
+const RATE_SMOOTHING: f64 = 0.3;
+
+/// Phase of a download or programming operation
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Phase {
+    /// Entering the diagnostic session and requesting security access
+    Authenticating,
+    /// Uploading and starting a RAM kernel
+    Uploading,
+    /// Erasing flash memory
+    Erasing,
+    /// Reading or writing data
+    Transferring,
+    /// Reading back programmed data
+    Verifying,
+    /// Running the ECU's checksum routine over programmed flash
+    Checksumming,
+    /// The operation finished successfully
+    Completed,
+}
+
+/// Progress of a transfer
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Progress {
+    /// Bytes transferred so far
+    pub position: usize,
+    /// Total bytes to transfer
+    pub total: usize,
+    /// Recent transfer rate in bytes per second
+    pub rate: f64,
+    /// Average transfer rate in bytes per second since the transfer started
+    pub average_rate: f64,
+    /// Estimated time until the transfer completes, serialized in seconds
+    #[serde(with = "optional_seconds")]
+    pub eta: Option<Duration>,
+}
+
+/// Serializes optional durations as fractional seconds
+mod optional_seconds {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        duration: &Option<Duration>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        duration.map(|d| d.as_secs_f64()).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Duration>, D::Error> {
+        let secs = Option::<f64>::deserialize(deserializer)?;
+        secs.map(|secs| Duration::try_from_secs_f64(secs).map_err(serde::de::Error::custom))
+            .transpose()
+    }
+}
+
+impl fmt::Display for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.1} KiB/s", self.rate / 1024.0)?;
+        if let Some(eta) = self.eta {
+            let secs = eta.as_secs();
+            write!(f, ", ETA {}:{:02}", secs / 60, secs % 60)?;
+        }
+        Ok(())
+    }
+}
+
+/// Computes transfer rates and the ETA from successive positions
+pub struct RateMeter {
+    total: usize,
+    start: (Instant, usize),
+    last: (Instant, usize),
+    rate: Option<f64>,
+}
+
+impl RateMeter {
+    /// Starts measuring a transfer of `total` bytes
+    pub fn new(total: usize) -> RateMeter {
+        RateMeter::resumed(total, 0)
+    }
+
+    /// Starts measuring a transfer of `total` bytes resumed at `position`
+    pub fn resumed(total: usize, position: usize) -> RateMeter {
+        let now = Instant::now();
+        RateMeter {
+            total,
+            start: (now, position),
+            last: (now, position),
+            rate: None,
+        }
+    }
+
+    /// Records that `position` bytes have been transferred
+    pub fn update(&mut self, position: usize) -> Progress {
+        let now = Instant::now();
+        let (last_time, last_position) = self.last;
+        let interval = now.duration_since(last_time).as_secs_f64();
+        if interval > 0.0 && position > last_position {
+            let block_rate = (position - last_position) as f64 / interval;
+            self.rate = Some(match self.rate {
+                Some(rate) => rate + RATE_SMOOTHING * (block_rate - rate),
+                None => block_rate,
+            });
+            self.last = (now, position);
+        }
+
+        let (start_time, start_position) = self.start;
+        let elapsed = now.duration_since(start_time).as_secs_f64();
+        let average_rate = if elapsed > 0.0 {
+            position.saturating_sub(start_position) as f64 / elapsed
+        } else {
+            0.0
+        };
+        let rate = self.rate.unwrap_or(average_rate);
+        let remaining = self.total.saturating_sub(position);
+        let eta = if remaining == 0 {
+            Some(Duration::from_secs(0))
+        } else if rate > 0.0 {
+            Some(Duration::from_secs_f64(remaining as f64 / rate))
+        } else {
+            None
+        };
+
+        Progress {
+            position,
+            total: self.total,
+            rate,
+            average_rate,
+            eta,
+        }
+    }
+}
+
+/// Receives progress updates from [`Downloader::run`](crate::Downloader::run)
+/// and [`Programmer::run`](crate::Programmer::run). All methods have empty
+/// default implementations.
+pub trait ProgressObserver {
+    /// Called after each block
+    fn on_progress(&mut self, _progress: &Progress) {}
+
+    /// Called when the operation enters a new phase
+    fn on_phase_change(&mut self, _phase: Phase) {}
+
+    /// Called before a failed request is retried. `attempt` starts from 1.
+    fn on_retry(&mut self, _attempt: usize, _error: &MzrError) {}
+}
+
+/// Observer that ignores all progress
+pub struct NoProgress;
+
+impl ProgressObserver for NoProgress {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_meter() {
+        let mut meter = RateMeter::new(1000);
+        std::thread::sleep(Duration::from_millis(20));
+        let progress = meter.update(500);
+        assert!(progress.rate > 0.0);
+        assert!(progress.average_rate > 0.0);
+        assert!(progress.eta.is_some());
+
+        let progress = meter.update(1000);
+        assert_eq!(progress.eta, Some(Duration::from_secs(0)));
+
+        let json = serde_json::to_value(progress).unwrap();
+        assert_eq!(json["eta"], 0.0);
+        assert_eq!(serde_json::from_value::<Progress>(json).unwrap(), progress);
+    }
+}
@@ -0,0 +1,132 @@
+//! Transport wrapper that reopens the adapter channel after a link failure
+
+use std::thread;
+use std::time::Duration;
+
+use obd::IsoTp;
+
+use crate::timeout::SetTimeout;
+
+/// Number of attempts made to reopen the channel before giving up
+pub const RECONNECT_ATTEMPTS: usize = 3;
+/// Delay between attempts to reopen the channel
+pub const RECONNECT_DELAY: Duration = Duration::from_millis(500);
+
+/// ISO-TP transport that reopens its channel when the adapter connection
+/// drops. The channel is created by a connect function, which must also set
+/// up any filters, so it can be called again to restore the connection.
+///
+/// A failed send is retried once on the new channel. A failed read can't be
+/// replayed, so the error is returned after reconnecting and the caller
+/// retries the request. The ECU keeps its diagnostic session across a short
+/// reconnect; if the session lapsed in the meantime, the next request is
+/// refused and [`Downloader`](crate::Downloader) re-authenticates.
+pub struct Reconnecting<T, F> {
+    transport: Option<T>,
+    connect: F,
+    reconnects: usize,
+    timeout: Option<Duration>,
+}
+
+impl<T, F> Reconnecting<T, F>
+where
+    T: IsoTp + SetTimeout,
+    F: FnMut() -> Result<T, obd::Error>,
+{
+    /// Opens the channel with `connect`
+    pub fn new(mut connect: F) -> Result<Reconnecting<T, F>, obd::Error> {
+        let transport = connect()?;
+        Ok(Reconnecting {
+            transport: Some(transport),
+            connect,
+            reconnects: 0,
+            timeout: None,
+        })
+    }
+
+    /// Returns the number of times the channel has been reopened
+    pub fn reconnects(&self) -> usize {
+        self.reconnects
+    }
+
+    fn reconnect(&mut self) -> Result<&mut T, obd::Error> {
+        // The old channel must be closed before a new one can be opened
+        self.transport = None;
+        let mut attempt = 1;
+        let transport = loop {
+            match (self.connect)() {
+                Ok(transport) => break transport,
+                Err(err) if attempt >= RECONNECT_ATTEMPTS => return Err(err),
+                Err(_) => {
+                    attempt += 1;
+                    thread::sleep(RECONNECT_DELAY);
+                }
+            }
+        };
+        self.reconnects += 1;
+        let transport = self.transport.insert(transport);
+        if let Some(timeout) = self.timeout {
+            transport.set_timeout(timeout);
+        }
+        Ok(transport)
+    }
+
+    fn transport(&mut self) -> Result<&mut T, obd::Error> {
+        match self.transport {
+            Some(ref mut transport) => Ok(transport),
+            None => self.reconnect(),
+        }
+    }
+}
+
+impl<T, F> SetTimeout for Reconnecting<T, F>
+where
+    T: SetTimeout,
+{
+    fn set_timeout(&mut self, timeout: Duration) {
+        // Remembered so it can be restored on a new channel
+        self.timeout = Some(timeout);
+        if let Some(ref mut transport) = self.transport {
+            transport.set_timeout(timeout);
+        }
+    }
+}
+
+impl<T, F> IsoTp for Reconnecting<T, F>
+where
+    T: IsoTp + SetTimeout,
+    F: FnMut() -> Result<T, obd::Error>,
+{
+    fn send_isotp(&mut self, id: u32, data: &[u8]) -> Result<(), obd::Error> {
+        match self.transport()?.send_isotp(id, data) {
+            Err(err) if is_link_error(&err) => self.reconnect()?.send_isotp(id, data),
+            result => result,
+        }
+    }
+
+    fn read_isotp(&mut self, id: u32) -> Result<Vec<u8>, obd::Error> {
+        match self.transport()?.read_isotp(id) {
+            Err(err) if is_link_error(&err) => {
+                self.reconnect()?;
+                Err(err)
+            }
+            result => result,
+        }
+    }
+}
+
+/// Returns true if `err` means the adapter connection was lost. Timeouts
+/// and empty buffers are left to the caller's retry logic, as reopening the
+/// channel doesn't help with a slow or silent ECU.
+fn is_link_error(err: &obd::Error) -> bool {
+    match err {
+        #[cfg(feature = "passthru")]
+        obd::Error::PassThru(err) => matches!(
+            err,
+            j2534::Error::DeviceNotConnected
+                | j2534::Error::InvalidChannelId
+                | j2534::Error::Failed
+        ),
+        _ => false,
+    }
+}
@@ -0,0 +1,280 @@
+//! Decoding of logged PIDs into named values in physical units
+
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::metadata::LogMetadata;
+
+fn one() -> f64 {
+    1.0
+}
+
+/// Value read with ReadDataByIdentifier. The response data is read as a
+/// big-endian integer and scaled to `raw * factor + offset`.
+///
+/// ```toml
+/// [[log_channels]]
+/// name = "rpm"
+/// pid = 0xF40C
+/// factor = 0.25
+/// unit = "rpm"
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LogChannel {
+    pub name: String,
+    pub pid: u16,
+    #[serde(default = "one")]
+    pub factor: f64,
+    #[serde(default)]
+    pub offset: f64,
+    /// Whether the integer is two's complement
+    #[serde(default)]
+    pub signed: bool,
+    #[serde(default)]
+    pub unit: String,
+}
+
+impl LogChannel {
+    /// Channel reporting the unscaled value of `pid`, named after it
+    pub fn raw(pid: u16) -> LogChannel {
+        LogChannel {
+            name: format!("0x{:04X}", pid),
+            pid,
+            factor: 1.0,
+            offset: 0.0,
+            signed: false,
+            unit: String::new(),
+        }
+    }
+
+    /// Returns the scaled value of response data, or `None` if it is empty
+    /// or longer than 8 bytes
+    pub fn decode(&self, data: &[u8]) -> Option<f64> {
+        if data.is_empty() || data.len() > 8 {
+            return None;
+        }
+        let raw = data
+            .iter()
+            .fold(0u64, |raw, &byte| (raw << 8) | byte as u64);
+        let raw = if self.signed {
+            // Sign-extend from the width of the data
+            let shift = 64 - data.len() * 8;
+            ((raw << shift) as i64 >> shift) as f64
+        } else {
+            raw as f64
+        };
+        Some(raw * self.factor + self.offset)
+    }
+}
+
+/// Returns the channel of each of `pids` from `definitions`, or a raw
+/// channel for PIDs without one
+pub fn channels_for(pids: &[u16], definitions: &[LogChannel]) -> Vec<LogChannel> {
+    pids.iter()
+        .map(|&pid| {
+            definitions
+                .iter()
+                .find(|channel| channel.pid == pid)
+                .cloned()
+                .unwrap_or_else(|| LogChannel::raw(pid))
+        })
+        .collect()
+}
+
+/// Values read in one round of logging, keyed by channel name
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    /// Unix time in milliseconds
+    pub time: u64,
+    pub values: BTreeMap<String, f64>,
+    /// Labels of markers set since the previous record, e.g. `lap`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub markers: Vec<String>,
+    /// Events seen since the previous record, e.g. a trouble code setting.
+    /// Unlike markers, they do not split laps.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub events: Vec<String>,
+}
+
+impl Record {
+    /// Creates an empty record stamped with the current time
+    pub fn new() -> Record {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_millis() as u64);
+        Record {
+            time,
+            values: BTreeMap::new(),
+            markers: Vec::new(),
+            events: Vec::new(),
+        }
+    }
+}
+
+impl Default for Record {
+    fn default() -> Record {
+        Record::new()
+    }
+}
+
+/// Receives the records of a logger, e.g. to publish them
+pub trait RecordSink {
+    fn send(&mut self, record: &Record);
+
+    /// Called when a logging session starts, e.g. when the ignition is
+    /// switched on, before its records are sent
+    fn begin_session(&mut self, _metadata: &LogMetadata) {}
+
+    /// Sends records held back for batching, e.g. when logging pauses
+    fn flush(&mut self) {}
+}
+
+impl<F: FnMut(&Record)> RecordSink for F {
+    fn send(&mut self, record: &Record) {
+        self(record)
+    }
+}
+
+impl<S: RecordSink> RecordSink for Option<S> {
+    fn send(&mut self, record: &Record) {
+        if let Some(sink) = self {
+            sink.send(record);
+        }
+    }
+
+    fn begin_session(&mut self, metadata: &LogMetadata) {
+        if let Some(sink) = self {
+            sink.begin_session(metadata);
+        }
+    }
+
+    fn flush(&mut self) {
+        if let Some(sink) = self {
+            sink.flush();
+        }
+    }
+}
+
+/// Sends records to both sinks
+impl<A: RecordSink, B: RecordSink> RecordSink for (A, B) {
+    fn send(&mut self, record: &Record) {
+        self.0.send(record);
+        self.1.send(record);
+    }
+
+    fn begin_session(&mut self, metadata: &LogMetadata) {
+        self.0.begin_session(metadata);
+        self.1.begin_session(metadata);
+    }
+
+    fn flush(&mut self) {
+        self.0.flush();
+        self.1.flush();
+    }
+}
+
+/// Line of a records file
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Line {
+    /// Start of a session
+    Metadata {
+        metadata: LogMetadata,
+    },
+    Record(Record),
+}
+
+/// Writes records as JSON lines, the input of the lap analysis. Each
+/// session starts with a line of its metadata.
+pub struct RecordWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> RecordWriter<W> {
+    pub fn new(writer: W) -> RecordWriter<W> {
+        RecordWriter { writer }
+    }
+}
+
+impl<W: Write> RecordWriter<W> {
+    fn write_line(&mut self, line: &Line) {
+        let line = serde_json::to_string(line).unwrap();
+        if let Err(err) = writeln!(self.writer, "{}", line) {
+            tracing::warn!(%err, "failed to write record");
+        }
+    }
+}
+
+impl<W: Write> RecordSink for RecordWriter<W> {
+    fn send(&mut self, record: &Record) {
+        self.write_line(&Line::Record(record.clone()));
+    }
+
+    fn begin_session(&mut self, metadata: &LogMetadata) {
+        self.write_line(&Line::Metadata {
+            metadata: metadata.clone(),
+        });
+    }
+
+    fn flush(&mut self) {
+        if let Err(err) = self.writer.flush() {
+            tracing::warn!(%err, "failed to write records");
+        }
+    }
+}
+
+/// Reads records written by [`RecordWriter`]
+pub fn read_records<R: BufRead>(reader: R) -> io::Result<Vec<Record>> {
+    read_log(reader).map(|(_, records)| records)
+}
+
+/// Reads the metadata of the first session and the records of all
+/// sessions written by [`RecordWriter`]
+pub fn read_log<R: BufRead>(reader: R) -> io::Result<(Option<LogMetadata>, Vec<Record>)> {
+    let mut metadata = None;
+    let mut records = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?
+        {
+            Line::Metadata { metadata: session } => {
+                metadata.get_or_insert(session);
+            }
+            Line::Record(record) => records.push(record),
+        }
+    }
+    Ok((metadata, records))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_scaled_values() {
+        let rpm: LogChannel =
+            toml::from_str("name = \"rpm\"\npid = 0xF40C\nfactor = 0.25").unwrap();
+        assert_eq!(rpm.decode(&[0x0D, 0x48]), Some(850.0));
+        let timing = LogChannel {
+            signed: true,
+            factor: 0.5,
+            ..LogChannel::raw(0x1234)
+        };
+        assert_eq!(timing.decode(&[0xF6]), Some(-5.0));
+        assert_eq!(timing.name, "0x1234");
+        let coolant = LogChannel {
+            offset: -40.0,
+            ..LogChannel::raw(0xF405)
+        };
+        assert_eq!(coolant.decode(&[130]), Some(90.0));
+        assert_eq!(coolant.decode(&[]), None);
+        assert_eq!(coolant.decode(&[0; 9]), None);
+    }
+}
@@ -0,0 +1,282 @@
+//! Interactive raw UDS requests. Requests are typed as hex bytes, e.g.
+//! `22 F1 90`, and responses are decoded with an explanation of negative
+//! response codes. The diagnostic session and security access persist
+//! between requests.
+
+use std::fmt;
+
+use obd::Uds;
+use thiserror::Error;
+
+use crate::session::Session;
+use crate::{MzrError, SECURITY_LEVEL_DEFAULT};
+
+const UDS_REQ_SESSION: u8 = 0x10;
+const UDS_RES_NEGATIVE: u8 = 0x7F;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum RequestError {
+    #[error("empty request")]
+    Empty,
+    #[error("invalid hex byte '{0}'")]
+    InvalidByte(String),
+    #[error("unknown command '{0}'")]
+    UnknownCommand(String),
+}
+
+#[derive(Error, Debug)]
+pub enum ReplError {
+    #[error(transparent)]
+    Request(#[from] RequestError),
+    #[error(transparent)]
+    Bus(#[from] MzrError),
+}
+
+/// Parses a request typed as hex bytes. Bytes may be separated by
+/// whitespace or written together, e.g. `22 F1 90` or `22F190`.
+pub fn parse_request(line: &str) -> Result<Vec<u8>, RequestError> {
+    let mut request = Vec::new();
+    for word in line.split_whitespace() {
+        let word = word.trim_start_matches("0x");
+        if word.is_empty() || word.len() % 2 != 0 {
+            return Err(RequestError::InvalidByte(word.to_string()));
+        }
+        for i in (0..word.len()).step_by(2) {
+            let byte = word
+                .get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(|| RequestError::InvalidByte(word.to_string()))?;
+            request.push(byte);
+        }
+    }
+    if request.is_empty() {
+        return Err(RequestError::Empty);
+    }
+    Ok(request)
+}
+
+/// Returns the ISO 14229 name of a negative response code
+pub fn nrc_description(nrc: u8) -> &'static str {
+    match nrc {
+        0x10 => "generalReject",
+        0x11 => "serviceNotSupported",
+        0x12 => "subFunctionNotSupported",
+        0x13 => "incorrectMessageLengthOrInvalidFormat",
+        0x14 => "responseTooLong",
+        0x21 => "busyRepeatRequest",
+        0x22 => "conditionsNotCorrect",
+        0x24 => "requestSequenceError",
+        0x25 => "noResponseFromSubnetComponent",
+        0x26 => "failurePreventsExecutionOfRequestedAction",
+        0x31 => "requestOutOfRange",
+        0x33 => "securityAccessDenied",
+        0x35 => "invalidKey",
+        0x36 => "exceededNumberOfAttempts",
+        0x37 => "requiredTimeDelayNotExpired",
+        0x70 => "uploadDownloadNotAccepted",
+        0x71 => "transferDataSuspended",
+        0x72 => "generalProgrammingFailure",
+        0x73 => "wrongBlockSequenceCounter",
+        0x78 => "requestCorrectlyReceivedResponsePending",
+        0x7E => "subFunctionNotSupportedInActiveSession",
+        0x7F => "serviceNotSupportedInActiveSession",
+        _ => "unknown",
+    }
+}
+
+/// Outcome of a command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    /// Positive response, including the response SID
+    Positive(Vec<u8>),
+    /// Negative response to the service `sid`
+    Negative { sid: u8, nrc: Option<u8> },
+    /// The diagnostic session was entered
+    Session(u8),
+    /// Security access was granted
+    Unlocked(u8),
+    /// Requests are now sent to this arbitration ID
+    RequestId(u32),
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl fmt::Display for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Reply::Positive(response) => {
+                write!(f, "{}", hex(response))?;
+                // Show the text of responses that are mostly printable
+                let text: String = response
+                    .iter()
+                    .skip(1)
+                    .map(|&b| if b.is_ascii_graphic() { b as char } else { '.' })
+                    .collect();
+                if text.chars().filter(|&c| c != '.').count() >= 4 {
+                    write!(f, "  |{}|", text)?;
+                }
+                Ok(())
+            }
+            Reply::Negative {
+                sid,
+                nrc: Some(nrc),
+            } => write!(
+                f,
+                "{:02X} {:02X} {:02X}  {}",
+                UDS_RES_NEGATIVE,
+                sid,
+                nrc,
+                nrc_description(*nrc)
+            ),
+            Reply::Negative { sid, nrc: None } => {
+                write!(f, "{:02X} {:02X}  negative response", UDS_RES_NEGATIVE, sid)
+            }
+            Reply::Session(session) => write!(f, "session 0x{:02X} active", session),
+            Reply::Unlocked(level) => write!(f, "security level 0x{:02X} unlocked", level),
+            Reply::RequestId(id) => write!(f, "sending to 0x{:03X}", id),
+        }
+    }
+}
+
+/// Raw request shell over a [`Session`]. Besides hex requests it accepts
+/// `unlock [level]`, which answers the seed with the session's key
+/// algorithm, and `id <request ID>`. Entering a session with `10 xx` is
+/// tracked, so the session is kept alive and left when the shell is dropped.
+pub struct Repl<'a, M: 'a + Uds> {
+    session: Session<'a, M>,
+}
+
+impl<'a, M: 'a + Uds> Repl<'a, M> {
+    pub fn new(session: Session<'a, M>) -> Repl<'a, M> {
+        Repl { session }
+    }
+
+    /// Returns the underlying session
+    pub fn session(&mut self) -> &mut Session<'a, M> {
+        &mut self.session
+    }
+
+    /// Executes a command line
+    pub fn execute(&mut self, line: &str) -> Result<Reply, ReplError> {
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("unlock") => {
+                let level = match words.next() {
+                    Some(level) => parse_request(level)?[0],
+                    None => SECURITY_LEVEL_DEFAULT,
+                };
+                self.session.set_security_level(level);
+                self.session.unlock()?;
+                Ok(Reply::Unlocked(level))
+            }
+            Some("id") => {
+                let id = words
+                    .next()
+                    .and_then(|id| u32::from_str_radix(id.trim_start_matches("0x"), 16).ok())
+                    .ok_or_else(|| RequestError::UnknownCommand(line.trim().to_string()))?;
+                self.session.set_request_id(id);
+                Ok(Reply::RequestId(id))
+            }
+            _ => {
+                let request = parse_request(line)?;
+                self.send(&request)
+            }
+        }
+    }
+
+    /// Sends a raw request
+    pub fn send(&mut self, request: &[u8]) -> Result<Reply, ReplError> {
+        let (&sid, data) = request.split_first().ok_or(RequestError::Empty)?;
+        let result = match data {
+            [session] if sid == UDS_REQ_SESSION => self
+                .session
+                .enter(*session)
+                .map(|_| Reply::Session(*session)),
+            _ => {
+                let request_id = self.session.request_id();
+                self.session
+                    .bus()
+                    .query_uds(request_id, sid, data)
+                    .map(|response| {
+                        let mut reply = vec![sid + 0x40];
+                        reply.extend_from_slice(&response);
+                        Reply::Positive(reply)
+                    })
+                    .map_err(MzrError::from)
+            }
+        };
+        match result {
+            Err(err) => match err.bus_error() {
+                Some(obd::Error::NegativeResponse(nrc)) => Ok(Reply::Negative { sid, nrc: *nrc }),
+                _ => Err(err.into()),
+            },
+            reply => Ok(reply?),
+        }
+    }
+
+    /// Sends TesterPresent if the session has been idle. Call this
+    /// periodically while waiting for input.
+    pub fn keep_alive(&mut self) -> Result<(), MzrError> {
+        self.session.keep_alive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockEcu;
+
+    /// ECU with a VIN, recording the session it was switched to
+    fn ecu() -> MockEcu<u8> {
+        MockEcu::new(0, |session, request| {
+            Some(match request {
+                [0x10, id] => {
+                    *session = *id;
+                    vec![0x50, *id]
+                }
+                [0x22, 0xF1, 0x90] => [&[0x62, 0xF1, 0x90][..], b"JM1BK34M071234567"].concat(),
+                [0x22, ..] => vec![0x7F, 0x22, 0x31],
+                _ => return None,
+            })
+        })
+    }
+
+    #[test]
+    fn executes_requests() {
+        assert_eq!(parse_request("22 f1 90"), Ok(vec![0x22, 0xF1, 0x90]));
+        assert_eq!(parse_request("0x22F190"), Ok(vec![0x22, 0xF1, 0x90]));
+        assert_eq!(
+            parse_request("22 F"),
+            Err(RequestError::InvalidByte("F".to_string()))
+        );
+        assert_eq!(parse_request("  "), Err(RequestError::Empty));
+
+        let mut ecu = ecu();
+        let mut repl = Repl::new(Session::new(&mut ecu));
+        let reply = repl.execute("22 F1 90").unwrap();
+        assert_eq!(
+            reply.to_string(),
+            "62 F1 90 4A 4D 31 42 4B 33 34 4D 30 37 31 32 33 34 35 36 37  |..JM1BK34M071234567|"
+        );
+        let reply = repl.execute("22 F1 91").unwrap();
+        assert_eq!(
+            reply,
+            Reply::Negative {
+                sid: 0x22,
+                nrc: Some(0x31)
+            }
+        );
+        assert_eq!(reply.to_string(), "7F 22 31  requestOutOfRange");
+        assert_eq!(repl.execute("10 87").unwrap(), Reply::Session(0x87));
+        assert!(repl.session().is_active());
+        drop(repl);
+        // Dropping the shell leaves the session
+        assert_eq!(ecu.state, 0x81);
+    }
+}
@@ -0,0 +1,314 @@
+//! Adapter self-test exercising the J2534 calls a flash relies on, so a
+//! faulty adapter or driver shows up before the ECU is erased
+
+use std::fmt;
+use std::time::Duration;
+
+use crate::datalink::can::{Can, Filter, Message};
+use crate::passthru::{self, PassThruCan};
+use crate::voltage::DEFAULT_MIN_VOLTAGE;
+
+/// Frames echoed to measure the round-trip latency
+pub const LOOPBACK_ROUNDS: usize = 20;
+/// Time to wait for the echo of a frame
+pub const LOOPBACK_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Frame echoed to measure latency. A functional TesterPresent with the
+/// response suppressed is harmless to every ECU on the bus.
+const LOOPBACK_ID: u32 = 0x7DF;
+const LOOPBACK_DATA: [u8; 8] = [0x02, 0x3E, 0x80, 0, 0, 0, 0, 0];
+
+/// Result of a single check
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Passed,
+    Failed,
+    /// The check could not run, because an earlier check failed or the
+    /// adapter doesn't support it
+    Skipped,
+}
+
+/// A check made by the self-test
+#[derive(Debug, Clone, PartialEq)]
+pub struct Check {
+    pub name: &'static str,
+    pub outcome: Outcome,
+    /// What the check found, or why it failed or was skipped
+    pub detail: String,
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let outcome = match self.outcome {
+            Outcome::Passed => "PASS",
+            Outcome::Failed => "FAIL",
+            Outcome::Skipped => "SKIP",
+        };
+        write!(f, "[{}] {}: {}", outcome, self.name, self.detail)
+    }
+}
+
+/// Round-trip latency of echoed frames
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Latency {
+    pub min: Duration,
+    pub mean: Duration,
+    pub max: Duration,
+    pub samples: usize,
+}
+
+impl Latency {
+    /// Summarizes `samples`, returning `None` if there are none
+    pub fn from_samples(samples: &[Duration]) -> Option<Latency> {
+        Some(Latency {
+            min: *samples.iter().min()?,
+            mean: samples.iter().sum::<Duration>() / samples.len() as u32,
+            max: *samples.iter().max()?,
+            samples: samples.len(),
+        })
+    }
+}
+
+impl fmt::Display for Latency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "round trip min {:.1} ms, mean {:.1} ms, max {:.1} ms over {} frames",
+            self.min.as_secs_f64() * 1000.0,
+            self.mean.as_secs_f64() * 1000.0,
+            self.max.as_secs_f64() * 1000.0,
+            self.samples
+        )
+    }
+}
+
+/// Results of a self-test
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    pub checks: Vec<Check>,
+    /// Latency measured by the loopback check
+    pub latency: Option<Latency>,
+}
+
+impl Report {
+    /// Returns true if no check failed
+    pub fn passed(&self) -> bool {
+        self.count(Outcome::Passed) > 0 && self.count(Outcome::Failed) == 0
+    }
+
+    /// Returns the number of checks with `outcome`
+    pub fn count(&self, outcome: Outcome) -> usize {
+        self.checks
+            .iter()
+            .filter(|check| check.outcome == outcome)
+            .count()
+    }
+
+    fn record<F: FnMut(&Check)>(
+        &mut self,
+        on_check: &mut F,
+        name: &'static str,
+        outcome: Outcome,
+        detail: String,
+    ) -> bool {
+        let check = Check {
+            name,
+            outcome,
+            detail,
+        };
+        on_check(&check);
+        self.checks.push(check);
+        outcome == Outcome::Passed
+    }
+}
+
+/// Self-test of a J2534 adapter. Opens the adapter, reads its version and
+/// the battery voltage, opens a CAN channel, sets filters, echoes frames
+/// where the adapter supports loopback and reopens the adapter after
+/// closing it.
+///
+/// The loopback frames are sent on the bus, so they are only echoed when
+/// the adapter is connected to a vehicle.
+#[derive(Debug, Clone)]
+pub struct SelfTest {
+    /// Bitrate of the CAN channel
+    pub bitrate: u32,
+    /// Battery voltage below which the voltage check fails
+    pub min_voltage: f32,
+    /// Number of frames echoed
+    pub rounds: usize,
+    /// Time to wait for each echo
+    pub timeout: Duration,
+}
+
+impl Default for SelfTest {
+    fn default() -> SelfTest {
+        SelfTest {
+            bitrate: 500000,
+            min_voltage: DEFAULT_MIN_VOLTAGE,
+            rounds: LOOPBACK_ROUNDS,
+            timeout: LOOPBACK_TIMEOUT,
+        }
+    }
+}
+
+impl SelfTest {
+    /// Runs the checks on the first device of `interface`, calling
+    /// `on_check` after each one
+    pub fn run<F: FnMut(&Check)>(&self, interface: &j2534::Interface, mut on_check: F) -> Report {
+        let mut report = Report::default();
+        let on_check = &mut on_check;
+
+        {
+            let device =
+                match passthru::traced("PassThruOpen", format_args!(""), || interface.open_any()) {
+                    Ok(device) => device,
+                    Err(err) => {
+                        report.record(on_check, "open", Outcome::Failed, err.to_string());
+                        return report;
+                    }
+                };
+            report.record(on_check, "open", Outcome::Passed, "opened".to_string());
+
+            let (outcome, detail) =
+                match passthru::traced("PassThruReadVersion", format_args!(""), || {
+                    device.read_version()
+                }) {
+                    Ok(version) => (
+                        Outcome::Passed,
+                        format!(
+                            "firmware {}, DLL {}, API {}",
+                            version.firmware_version, version.dll_version, version.api_version
+                        ),
+                    ),
+                    Err(err) => (Outcome::Failed, err.to_string()),
+                };
+            report.record(on_check, "version", outcome, detail);
+
+            let (outcome, detail) =
+                match passthru::traced("PassThruIoctl", format_args!("READ_VBATT"), || {
+                    device.read_battery_voltage()
+                }) {
+                    Ok(millivolts) => {
+                        let voltage = millivolts as f32 / 1000.0;
+                        if voltage < self.min_voltage {
+                            (
+                                Outcome::Failed,
+                                format!("{:.1} V, below {:.1} V", voltage, self.min_voltage),
+                            )
+                        } else {
+                            (Outcome::Passed, format!("{:.1} V", voltage))
+                        }
+                    }
+                    Err(err) => (Outcome::Failed, err.to_string()),
+                };
+            report.record(on_check, "voltage", outcome, detail);
+
+            self.check_channel(&device, &mut report, on_check);
+        }
+
+        // Dropping the device closed it
+        let (outcome, detail) =
+            match passthru::traced("PassThruOpen", format_args!(""), || interface.open_any()) {
+                Ok(_) => (Outcome::Passed, "reopened after closing".to_string()),
+                Err(err) => (Outcome::Failed, err.to_string()),
+            };
+        report.record(on_check, "reopen", outcome, detail);
+        report
+    }
+
+    /// Checks a CAN channel of `device`
+    fn check_channel<F: FnMut(&Check)>(
+        &self,
+        device: &j2534::Device,
+        report: &mut Report,
+        on_check: &mut F,
+    ) {
+        let can = match PassThruCan::new(device, self.bitrate) {
+            Ok(can) => can,
+            Err(err) => {
+                report.record(on_check, "channel", Outcome::Failed, err.to_string());
+                for name in ["filters", "loopback"] {
+                    let detail = "no channel".to_string();
+                    report.record(on_check, name, Outcome::Skipped, detail);
+                }
+                return;
+            }
+        };
+        let detail = format!("CAN at {} bit/s", self.bitrate);
+        report.record(on_check, "channel", Outcome::Passed, detail);
+
+        let filters = [Filter::exact(0x7E8, false), Filter::exact(0x18DAF110, true)];
+        let (outcome, detail) = match can.set_filters(&filters).and_then(|_| can.set_filters(&[])) {
+            Ok(()) => (Outcome::Passed, "set and cleared".to_string()),
+            Err(err) => (Outcome::Failed, err.to_string()),
+        };
+        report.record(on_check, "filters", outcome, detail);
+
+        if let Err(err) = can.set_loopback(true) {
+            let detail = format!("not supported: {}", err);
+            report.record(on_check, "loopback", Outcome::Skipped, detail);
+            return;
+        }
+        let msg = Message::new(LOOPBACK_ID, &LOOPBACK_DATA);
+        let mut samples = Vec::with_capacity(self.rounds);
+        for _ in 0..self.rounds {
+            match can.echo(&msg, self.timeout) {
+                Ok(latency) => samples.push(latency),
+                Err(err) => {
+                    let detail = format!(
+                        "no echo after {} frames ({}); is the adapter connected to a vehicle?",
+                        samples.len(),
+                        err
+                    );
+                    report.record(on_check, "loopback", Outcome::Failed, detail);
+                    return;
+                }
+            }
+        }
+        let _ = can.set_loopback(false);
+        report.latency = Latency::from_samples(&samples);
+        let detail = match report.latency {
+            Some(latency) => latency.to_string(),
+            None => "no frames echoed".to_string(),
+        };
+        report.record(on_check, "loopback", Outcome::Passed, detail);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summarizes_checks() {
+        let samples = [1, 3, 2].map(Duration::from_millis);
+        let latency = Latency::from_samples(&samples).unwrap();
+        assert_eq!(latency.min, Duration::from_millis(1));
+        assert_eq!(latency.mean, Duration::from_millis(2));
+        assert_eq!(latency.max, Duration::from_millis(3));
+        assert_eq!(
+            latency.to_string(),
+            "round trip min 1.0 ms, mean 2.0 ms, max 3.0 ms over 3 frames"
+        );
+        assert_eq!(Latency::from_samples(&[]), None);
+
+        let mut report = Report::default();
+        let mut seen = Vec::new();
+        let mut on_check = |check: &Check| seen.push(check.to_string());
+        assert!(report.record(&mut on_check, "open", Outcome::Passed, "opened".into()));
+        report.record(&mut on_check, "loopback", Outcome::Skipped, "n/a".into());
+        assert!(report.passed());
+        report.record(&mut on_check, "voltage", Outcome::Failed, "11.0 V".into());
+        assert!(!report.passed());
+        assert_eq!(report.count(Outcome::Skipped), 1);
+        assert_eq!(
+            seen,
+            [
+                "[PASS] open: opened",
+                "[SKIP] loopback: n/a",
+                "[FAIL] voltage: 11.0 V"
+            ]
+        );
+    }
+}
@@ -0,0 +1,157 @@
+//! Diagnostic session guard
+
+use std::time::{Duration, Instant};
+
+use obd::Uds;
+
+use crate::profile::KeyAlgorithm;
+use crate::{MzrBus, MzrError, DEFAULT_REQUEST_ID, SECURITY_LEVEL_DEFAULT};
+
+/// Default interval between TesterPresent requests sent by
+/// [`Session::keep_alive`]
+pub const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(2);
+
+const UDS_REQ_ECURESET: u8 = 0x11;
+
+/// Action taken when a [`Session`] is closed or dropped
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ExitAction {
+    /// Return to the default diagnostic session
+    DefaultSession,
+    /// Reset the ECU
+    Reset,
+}
+
+/// Guard for a non-default diagnostic session. While a session is active,
+/// dropping the guard returns the ECU to the default session (or resets it),
+/// so no code path can leave the ECU stuck in a programming session.
+pub struct Session<'a, M: 'a + Uds> {
+    bus: &'a mut M,
+    request_id: u32,
+    security_level: u8,
+    key_algorithm: KeyAlgorithm,
+    session_id: Option<u8>,
+    exit_action: ExitAction,
+    keep_alive_interval: Duration,
+    last_request: Instant,
+}
+
+impl<'a, M: 'a + Uds> Session<'a, M> {
+    /// Creates an inactive guard. No requests are sent until
+    /// [`enter`](Session::enter) is called.
+    pub fn new(bus: &'a mut M) -> Session<'a, M> {
+        Session {
+            bus,
+            request_id: DEFAULT_REQUEST_ID,
+            security_level: SECURITY_LEVEL_DEFAULT,
+            key_algorithm: KeyAlgorithm::Mazda,
+            session_id: None,
+            exit_action: ExitAction::DefaultSession,
+            keep_alive_interval: KEEP_ALIVE_INTERVAL,
+            last_request: Instant::now(),
+        }
+    }
+
+    /// Enters the diagnostic session `session_id` and requests security access
+    pub fn open(bus: &'a mut M, session_id: u8) -> Result<Session<'a, M>, MzrError> {
+        let mut session = Session::new(bus);
+        session.enter(session_id)?;
+        session.unlock()?;
+        Ok(session)
+    }
+
+    /// Sets the arbitration ID requests are sent to
+    pub fn set_request_id(&mut self, request_id: u32) {
+        self.request_id = request_id;
+    }
+
+    /// Returns the arbitration ID requests are sent to
+    pub fn request_id(&self) -> u32 {
+        self.request_id
+    }
+
+    /// Sets the security access level requested by [`unlock`](Session::unlock)
+    pub fn set_security_level(&mut self, level: u8) {
+        self.security_level = level;
+    }
+
+    /// Sets the algorithm used to answer security access seeds
+    pub fn set_key_algorithm(&mut self, algorithm: KeyAlgorithm) {
+        self.key_algorithm = algorithm;
+    }
+
+    /// Sets the interval between TesterPresent requests
+    pub fn set_keep_alive_interval(&mut self, interval: Duration) {
+        self.keep_alive_interval = interval;
+    }
+
+    /// Sets the action taken when the session ends
+    pub fn set_exit_action(&mut self, action: ExitAction) {
+        self.exit_action = action;
+    }
+
+    /// Enters the diagnostic session `session_id`
+    pub fn enter(&mut self, session_id: u8) -> Result<(), MzrError> {
+        self.bus
+            .set_diagnostic_session(self.request_id, session_id)?;
+        self.session_id = Some(session_id);
+        self.last_request = Instant::now();
+        Ok(())
+    }
+
+    /// Requests security access in the active session
+    pub fn unlock(&mut self) -> Result<(), MzrError> {
+        let (request_id, level) = (self.request_id, self.security_level);
+        self.last_request = Instant::now();
+        self.bus.unlock_with(request_id, level, &self.key_algorithm)
+    }
+
+    /// Returns the active diagnostic session
+    pub fn session_id(&self) -> Option<u8> {
+        self.session_id
+    }
+
+    /// Returns true if a non-default session is active
+    pub fn is_active(&self) -> bool {
+        self.session_id.is_some()
+    }
+
+    /// Returns the underlying bus. Requests sent through the bus count as
+    /// session activity.
+    pub fn bus(&mut self) -> &mut M {
+        self.last_request = Instant::now();
+        self.bus
+    }
+
+    /// Sends TesterPresent if no request was sent within
+    /// the keep-alive interval. Call this periodically while idle.
+    pub fn keep_alive(&mut self) -> Result<(), MzrError> {
+        if self.is_active() && self.last_request.elapsed() >= self.keep_alive_interval {
+            let request_id = self.request_id;
+            self.bus().tester_present(request_id)?;
+        }
+        Ok(())
+    }
+
+    /// Ends the session with the configured [`ExitAction`]
+    pub fn exit(&mut self) -> Result<(), MzrError> {
+        if self.session_id.take().is_none() {
+            return Ok(());
+        }
+        match self.exit_action {
+            ExitAction::DefaultSession => self.bus.exit_session(self.request_id),
+            ExitAction::Reset => {
+                self.bus
+                    .query_uds(self.request_id, UDS_REQ_ECURESET, &[0x01])?;
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<'a, M: 'a + Uds> Drop for Session<'a, M> {
+    fn drop(&mut self) {
+        // Errors can't be reported from drop. Use `exit` to handle them.
+        let _ = self.exit();
+    }
+}
@@ -0,0 +1,194 @@
+//! Transport handles shared between threads, so a logger thread and a
+//! diagnostics thread can use one adapter
+//!
+//! Each handle is an [`IsoTp`] transport of its own. A handle keeps the
+//! transport from its request until the final response arrives, so other
+//! handles never read its responses. Responses pending (NRC 0x78) keep the
+//! transport claimed until the real response.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::Duration;
+
+use mzr_core::uds::NRC_RESPONSE_PENDING;
+use obd::IsoTp;
+
+use crate::timeout::SetTimeout;
+
+/// Services whose second byte is a sub-function, which may suppress the
+/// positive response
+const SUBFUNCTION_SERVICES: [u8; 8] = [0x10, 0x11, 0x19, 0x27, 0x28, 0x31, 0x3E, 0x85];
+
+/// Returns true if the ECU doesn't answer `request` when it succeeds
+fn suppresses_response(request: &[u8]) -> bool {
+    matches!(request, [sid, sub, ..] if SUBFUNCTION_SERVICES.contains(sid) && sub & 0x80 != 0)
+}
+
+/// Returns true if `response` asks the tester to keep waiting
+fn is_response_pending(response: &[u8]) -> bool {
+    matches!(response, [0x7F, _, NRC_RESPONSE_PENDING, ..])
+}
+
+struct Inner<T> {
+    transport: Mutex<T>,
+    /// Handle holding the transport between a request and its response
+    owner: Mutex<Option<usize>>,
+    released: Condvar,
+    next_handle: AtomicUsize,
+}
+
+/// Handle to a transport shared between threads. Cloning creates another
+/// handle to the same transport. Each handle has its own timeout.
+pub struct SharedTransport<T> {
+    inner: Arc<Inner<T>>,
+    handle: usize,
+    timeout: Option<Duration>,
+}
+
+impl<T> SharedTransport<T> {
+    /// Creates the first handle to `transport`
+    pub fn new(transport: T) -> SharedTransport<T> {
+        SharedTransport {
+            inner: Arc::new(Inner {
+                transport: Mutex::new(transport),
+                owner: Mutex::new(None),
+                released: Condvar::new(),
+                next_handle: AtomicUsize::new(1),
+            }),
+            handle: 0,
+            timeout: None,
+        }
+    }
+
+    /// Waits until no other handle holds the transport and holds it
+    fn claim(&self) {
+        let mut owner = self.inner.owner.lock().unwrap();
+        while owner.is_some_and(|owner| owner != self.handle) {
+            owner = self.inner.released.wait(owner).unwrap();
+        }
+        *owner = Some(self.handle);
+    }
+
+    /// Lets other handles use the transport
+    fn release(&self) {
+        let mut owner = self.inner.owner.lock().unwrap();
+        if *owner == Some(self.handle) {
+            *owner = None;
+            self.inner.released.notify_one();
+        }
+    }
+
+    fn transport(&self) -> MutexGuard<'_, T> {
+        self.inner.transport.lock().unwrap()
+    }
+}
+
+impl<T> Clone for SharedTransport<T> {
+    fn clone(&self) -> SharedTransport<T> {
+        SharedTransport {
+            inner: self.inner.clone(),
+            handle: self.inner.next_handle.fetch_add(1, Ordering::Relaxed),
+            timeout: self.timeout,
+        }
+    }
+}
+
+impl<T> Drop for SharedTransport<T> {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+impl<T: IsoTp + SetTimeout> IsoTp for SharedTransport<T> {
+    fn send_isotp(&mut self, id: u32, data: &[u8]) -> Result<(), obd::Error> {
+        self.claim();
+        let result = {
+            let mut transport = self.transport();
+            if let Some(timeout) = self.timeout {
+                transport.set_timeout(timeout);
+            }
+            transport.send_isotp(id, data)
+        };
+        if result.is_err() || suppresses_response(data) {
+            self.release();
+        }
+        result
+    }
+
+    fn read_isotp(&mut self, id: u32) -> Result<Vec<u8>, obd::Error> {
+        self.claim();
+        let result = {
+            let mut transport = self.transport();
+            if let Some(timeout) = self.timeout {
+                transport.set_timeout(timeout);
+            }
+            transport.read_isotp(id)
+        };
+        match &result {
+            Ok(response) if is_response_pending(response) => {}
+            _ => self.release(),
+        }
+        result
+    }
+}
+
+impl<T> SetTimeout for SharedTransport<T> {
+    fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = Some(timeout);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use obd::Uds;
+    use std::collections::VecDeque;
+    use std::thread;
+
+    /// ECU echoing each request after a response pending, unless the
+    /// response is suppressed
+    #[derive(Default)]
+    struct Ecu {
+        responses: VecDeque<Vec<u8>>,
+    }
+
+    impl IsoTp for Ecu {
+        fn send_isotp(&mut self, _id: u32, data: &[u8]) -> Result<(), obd::Error> {
+            if !suppresses_response(data) {
+                self.responses.push_back(vec![0x7F, data[0], 0x78]);
+                self.responses
+                    .push_back([&[data[0] + 0x40][..], &data[1..]].concat());
+            }
+            Ok(())
+        }
+
+        fn read_isotp(&mut self, _id: u32) -> Result<Vec<u8>, obd::Error> {
+            self.responses.pop_front().ok_or(obd::Error::EmptyResponse)
+        }
+    }
+
+    impl SetTimeout for Ecu {
+        fn set_timeout(&mut self, _timeout: Duration) {}
+    }
+
+    #[test]
+    fn handles_share_transport() {
+        let shared = SharedTransport::new(Ecu::default());
+        let threads: Vec<_> = (0..4u8)
+            .map(|n| {
+                let mut bus = shared.clone();
+                thread::spawn(move || {
+                    for i in 0..50u8 {
+                        let response = bus.query_uds(0x7E0, 0x22, &[n, i]).unwrap();
+                        assert_eq!(response, [n, i]);
+                        bus.send_isotp(0x7E0, &[0x3E, 0x80]).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert!(shared.inner.owner.lock().unwrap().is_none());
+    }
+}
@@ -0,0 +1,156 @@
+//! ed25519 keys for signing `.mzrrom` packages. Keys are stored and
+//! configured as hex strings.
+
+use std::convert::TryInto;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+pub use ed25519_dalek::{SigningKey, VerifyingKey};
+use rand_core::OsRng;
+use thiserror::Error;
+
+use crate::container::RomContainer;
+
+#[derive(Error, Debug)]
+pub enum KeyError {
+    #[error("failed to access key file: {0}")]
+    Io(#[from] io::Error),
+    #[error("invalid key")]
+    Invalid,
+}
+
+#[derive(Error, Debug)]
+pub enum PackageError {
+    #[error("package is signed by untrusted key {0}")]
+    UntrustedKey(String),
+    #[error("file is not signed by a trusted key")]
+    Unsigned,
+    #[error("package is locked to VIN {lock} and the vehicle's VIN is {}", .vin.as_deref().unwrap_or("unknown"))]
+    VinLocked { lock: String, vin: Option<String> },
+}
+
+/// Generates a new signing key
+pub fn generate_key() -> SigningKey {
+    SigningKey::generate(&mut OsRng)
+}
+
+/// Loads a signing key saved with [`save_signing_key`]
+pub fn load_signing_key(path: &Path) -> Result<SigningKey, KeyError> {
+    let bytes = from_hex(fs::read_to_string(path)?.trim()).ok_or(KeyError::Invalid)?;
+    let bytes = bytes.as_slice().try_into().map_err(|_| KeyError::Invalid)?;
+    Ok(SigningKey::from_bytes(bytes))
+}
+
+/// Saves a signing key as hex
+pub fn save_signing_key(path: &Path, key: &SigningKey) -> Result<(), KeyError> {
+    fs::write(path, format!("{}\n", to_hex(key.as_bytes())))?;
+    Ok(())
+}
+
+/// Parses a public key given in hex
+pub fn parse_public_key(hex: &str) -> Result<VerifyingKey, KeyError> {
+    let bytes = from_hex(hex.trim()).ok_or(KeyError::Invalid)?;
+    let bytes = bytes.as_slice().try_into().map_err(|_| KeyError::Invalid)?;
+    VerifyingKey::from_bytes(bytes).map_err(|_| KeyError::Invalid)
+}
+
+/// Formats a public key as hex
+pub fn public_key_hex(key: &VerifyingKey) -> String {
+    to_hex(key.as_bytes())
+}
+
+/// Returns true if `key` is one of the `trusted` keys. Invalid entries are
+/// ignored.
+pub fn is_trusted(key: &VerifyingKey, trusted: &[String]) -> bool {
+    trusted
+        .iter()
+        .filter_map(|hex| parse_public_key(hex).ok())
+        .any(|trusted| trusted == *key)
+}
+
+/// Checks that a file may be flashed to the vehicle with `vin`. Packages
+/// signed by another key are refused, as are unsigned files once any keys
+/// are trusted, unless `allow_unsigned` is set. `container` is `None` for
+/// plain images. Packages locked to another vehicle are always refused.
+pub fn check_package(
+    container: Option<&RomContainer>,
+    trusted: &[String],
+    vin: Option<&str>,
+    allow_unsigned: bool,
+) -> Result<(), PackageError> {
+    match container.and_then(RomContainer::signer) {
+        Some(key) if is_trusted(&key, trusted) => (),
+        Some(key) if !allow_unsigned => {
+            return Err(PackageError::UntrustedKey(public_key_hex(&key)));
+        }
+        None if !trusted.is_empty() && !allow_unsigned => return Err(PackageError::Unsigned),
+        _ => (),
+    }
+    if let Some(header) = container.map(|container| &container.header) {
+        if !header.allows_vin(vin) {
+            return Err(PackageError::VinLocked {
+                lock: header.vin_lock.clone().unwrap_or_default(),
+                vin: vin.map(str::to_owned),
+            });
+        }
+    }
+    Ok(())
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trusted_keys() {
+        let key = generate_key().verifying_key();
+        let other = generate_key().verifying_key();
+        let trusted = vec!["not a key".to_string(), public_key_hex(&key).to_uppercase()];
+        assert!(is_trusted(&key, &trusted));
+        assert!(!is_trusted(&other, &trusted));
+    }
+
+    #[test]
+    fn package_policy() {
+        let key = generate_key();
+        let trusted = vec![public_key_hex(&key.verifying_key())];
+        let mut container = RomContainer::new(vec![0; 16]);
+        assert!(matches!(
+            check_package(Some(&container), &trusted, None, false),
+            Err(PackageError::Unsigned)
+        ));
+        assert!(check_package(Some(&container), &trusted, None, true).is_ok());
+        assert!(check_package(None, &[], None, false).is_ok());
+
+        container.sign(&generate_key());
+        assert!(matches!(
+            check_package(Some(&container), &trusted, None, false),
+            Err(PackageError::UntrustedKey(_))
+        ));
+
+        container.header.vin_lock = Some("JM1BK343X81111111".to_string());
+        container.sign(&key);
+        assert!(
+            check_package(Some(&container), &trusted, Some("jm1bk343x81111111"), false).is_ok()
+        );
+        assert!(matches!(
+            check_package(Some(&container), &trusted, None, true),
+            Err(PackageError::VinLocked { .. })
+        ));
+    }
+}
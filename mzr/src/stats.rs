@@ -0,0 +1,125 @@
+//! Transfer statistics, for comparing adapters and settings
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+
+use crate::progress::Phase;
+use crate::MzrError;
+
+/// Statistics collected by [`Downloader`](crate::Downloader) and
+/// [`Programmer`](crate::Programmer). Phase timings are only recorded by
+/// `run`.
+#[derive(Debug, Clone, Default)]
+pub struct TransferStats {
+    /// Bytes read from or written to the ECU
+    pub bytes: usize,
+    /// Requests that were retried
+    pub retries: usize,
+    /// Number of times the diagnostic session was re-established
+    pub session_recoveries: usize,
+    /// Count of each negative response code received
+    pub nrcs: BTreeMap<u8, usize>,
+    /// Time spent in each completed phase, in order
+    pub phases: Vec<(Phase, Duration)>,
+    current: Option<(Phase, Instant)>,
+}
+
+impl TransferStats {
+    /// Returns the total time spent in all phases
+    pub fn elapsed(&self) -> Duration {
+        let current = self
+            .current
+            .map_or(Duration::from_secs(0), |(_, t)| t.elapsed());
+        self.phases.iter().map(|(_, d)| *d).sum::<Duration>() + current
+    }
+
+    /// Returns the time spent in `phase`
+    pub fn phase_duration(&self, phase: Phase) -> Option<Duration> {
+        self.phases
+            .iter()
+            .find(|(p, _)| *p == phase)
+            .map(|(_, d)| *d)
+    }
+
+    /// Returns the average transfer rate in bytes per second, measured over
+    /// the transferring phase
+    pub fn bytes_per_second(&self) -> f64 {
+        let elapsed = self
+            .phase_duration(Phase::Transferring)
+            .unwrap_or_else(|| self.elapsed())
+            .as_secs_f64();
+        if elapsed > 0.0 {
+            self.bytes as f64 / elapsed
+        } else {
+            0.0
+        }
+    }
+
+    /// Returns the statistics as a JSON event
+    pub fn to_json(&self) -> Value {
+        let phases: serde_json::Map<String, Value> = self
+            .phases
+            .iter()
+            .map(|(phase, d)| (format!("{:?}", phase), json!(d.as_secs_f64())))
+            .collect();
+        let nrcs: serde_json::Map<String, Value> = self
+            .nrcs
+            .iter()
+            .map(|(code, count)| (format!("0x{:02X}", code), json!(count)))
+            .collect();
+        json!({
+            "event": "stats",
+            "bytes": self.bytes,
+            "elapsed": self.elapsed().as_secs_f64(),
+            "bytes_per_second": self.bytes_per_second(),
+            "retries": self.retries,
+            "session_recoveries": self.session_recoveries,
+            "nrcs": nrcs,
+            "phases": phases,
+        })
+    }
+
+    /// Ends the current phase and starts timing `phase`. Timing stops when
+    /// the operation completes.
+    pub(crate) fn enter_phase(&mut self, phase: Phase) {
+        if let Some((previous, started)) = self.current.take() {
+            self.phases.push((previous, started.elapsed()));
+        }
+        if phase != Phase::Completed {
+            self.current = Some((phase, Instant::now()));
+        }
+    }
+
+    /// Records the negative response code carried by `error`, if any
+    pub(crate) fn record_error(&mut self, error: &MzrError) {
+        if let Some(code) = error.negative_response_code() {
+            *self.nrcs.entry(code).or_insert(0) += 1;
+        }
+    }
+}
+
+impl fmt::Display for TransferStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} bytes in {:.1} s ({:.1} KiB/s), {} retries",
+            self.bytes,
+            self.elapsed().as_secs_f64(),
+            self.bytes_per_second() / 1024.0,
+            self.retries
+        )?;
+        if self.session_recoveries > 0 {
+            write!(f, ", {} session recoveries", self.session_recoveries)?;
+        }
+        for (code, count) in self.nrcs.iter() {
+            write!(f, ", NRC 0x{:02X} x{}", code, count)?;
+        }
+        for (phase, duration) in self.phases.iter() {
+            write!(f, "\n  {:?}: {:.1} s", phase, duration.as_secs_f64())?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,216 @@
+//! Live patching of calibration tables. A ROM patched to read a table from a
+//! mirror in RAM instead of flash picks up cells written to the mirror
+//! immediately, so the table can be tuned while the engine runs. Tables are
+//! described in a TOML file, parsed by [`mzr_rom::table`].
+//!
+//! Each modified run of cells is written with a single short request, so
+//! [`LiveTable::flush`] can be called between logging requests without
+//! stalling the log.
+
+use std::fs;
+use std::path::Path;
+
+use obd::Uds;
+
+use crate::{MzrBus, MzrError};
+
+pub use mzr_rom::table::{parse_definitions, TableDefinition, TableError};
+
+/// Largest number of bytes read or written with one request
+const MAX_TRANSFER: usize = 0x400;
+
+/// Loads the table definitions of a TOML file
+pub fn load_definitions<P: AsRef<Path>>(path: P) -> Result<Vec<TableDefinition>, TableError> {
+    parse_definitions(&fs::read_to_string(path)?)
+}
+
+/// Copy of a table's cells that tracks which cells were modified since they
+/// were last written to the RAM mirror
+#[derive(Debug, Clone)]
+pub struct LiveTable {
+    definition: TableDefinition,
+    data: Vec<u8>,
+    modified: Vec<bool>,
+}
+
+impl LiveTable {
+    /// Creates a table holding the cells of the ROM image `rom`. Returns
+    /// `None` if the image doesn't contain the table.
+    pub fn from_rom(definition: TableDefinition, rom: &[u8]) -> Option<LiveTable> {
+        let data = definition.cells(rom)?.to_vec();
+        Some(LiveTable::new(definition, data))
+    }
+
+    /// Reads the cells from the RAM mirror
+    pub fn read<B: Uds>(
+        bus: &mut B,
+        request_id: u32,
+        definition: TableDefinition,
+    ) -> Result<LiveTable, MzrError> {
+        let mut data = Vec::with_capacity(definition.len());
+        while data.len() < definition.len() {
+            let length = (definition.len() - data.len()).min(MAX_TRANSFER);
+            let address = definition.ram_address + data.len() as u32;
+            let chunk = bus.read_memory_address(request_id, address, length as u16)?;
+            if chunk.len() != length {
+                return Err(MzrError::InvalidResponse);
+            }
+            data.extend_from_slice(&chunk);
+        }
+        Ok(LiveTable::new(definition, data))
+    }
+
+    fn new(definition: TableDefinition, data: Vec<u8>) -> LiveTable {
+        let cells = definition.rows * definition.columns;
+        LiveTable {
+            definition,
+            data,
+            modified: vec![false; cells],
+        }
+    }
+
+    pub fn definition(&self) -> &TableDefinition {
+        &self.definition
+    }
+
+    fn cell_range(&self, row: usize, column: usize) -> Option<std::ops::Range<usize>> {
+        if row >= self.definition.rows || column >= self.definition.columns {
+            return None;
+        }
+        let bytes = self.definition.width().bytes();
+        let start = (row * self.definition.columns + column) * bytes;
+        Some(start..start + bytes)
+    }
+
+    /// Returns the physical value of a cell
+    pub fn get(&self, row: usize, column: usize) -> Option<f64> {
+        let range = self.cell_range(row, column)?;
+        Some(self.definition.to_physical(&self.data[range]))
+    }
+
+    /// Sets the physical value of a cell. The cell is written to the mirror
+    /// by the next [`flush`](LiveTable::flush) if its raw value changed.
+    pub fn set(&mut self, row: usize, column: usize, value: f64) -> Result<(), TableError> {
+        let range = self
+            .cell_range(row, column)
+            .ok_or(TableError::NoSuchCell(row, column))?;
+        let raw = self.definition.to_raw(value)?;
+        if self.data[range.clone()] != raw[..] {
+            self.data[range].copy_from_slice(&raw);
+            self.modified[row * self.definition.columns + column] = true;
+        }
+        Ok(())
+    }
+
+    /// Returns true if cells were modified since the last flush
+    pub fn is_modified(&self) -> bool {
+        self.modified.iter().any(|&modified| modified)
+    }
+
+    /// Writes the modified cells to the RAM mirror, one request per run of
+    /// adjacent cells. Returns the number of cells written. Cells stay
+    /// modified if their write fails.
+    pub fn flush<B: MzrBus>(&mut self, bus: &mut B, request_id: u32) -> Result<usize, MzrError> {
+        let bytes = self.definition.width().bytes();
+        let max_cells = MAX_TRANSFER / bytes;
+        let mut written = 0;
+        let mut cell = 0;
+        while cell < self.modified.len() {
+            if !self.modified[cell] {
+                cell += 1;
+                continue;
+            }
+            let run = self.modified[cell..]
+                .iter()
+                .take(max_cells)
+                .take_while(|&&modified| modified)
+                .count();
+            let address = self.definition.ram_address + (cell * bytes) as u32;
+            bus.write_memory(
+                request_id,
+                address,
+                &self.data[cell * bytes..(cell + run) * bytes],
+            )?;
+            for modified in self.modified[cell..cell + run].iter_mut() {
+                *modified = false;
+            }
+            written += run;
+            cell += run;
+        }
+        Ok(written)
+    }
+
+    /// Writes every cell to the RAM mirror, e.g. to seed it with the cells
+    /// of the ROM
+    pub fn upload<B: MzrBus>(&mut self, bus: &mut B, request_id: u32) -> Result<(), MzrError> {
+        for modified in self.modified.iter_mut() {
+            *modified = true;
+        }
+        self.flush(bus, request_id)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_map::MzrMemoryMap;
+    use obd::IsoTp;
+
+    /// Bus recording WriteMemoryByAddress requests
+    #[derive(Default)]
+    struct Recorder {
+        writes: Vec<Vec<u8>>,
+    }
+
+    impl IsoTp for Recorder {
+        fn send_isotp(&mut self, _id: u32, data: &[u8]) -> Result<(), obd::Error> {
+            self.writes.push(data.to_vec());
+            Ok(())
+        }
+
+        fn read_isotp(&mut self, _id: u32) -> Result<Vec<u8>, obd::Error> {
+            let request = self.writes.last().unwrap();
+            Ok([&[request[0] + 0x40][..], &request[1..7]].concat())
+        }
+    }
+
+    #[test]
+    fn flushes_modified_cells() {
+        let definitions = parse_definitions(
+            "[[table]]\nname = \"Spark advance\"\naddress = 0x10\nram_address = 0xFFFF8000\n\
+             rows = 2\ncolumns = 3\nsigned = true\nscale = 0.5\n",
+        )
+        .unwrap();
+        let definition = definitions[0].clone();
+        assert_eq!(definition.len(), 12);
+        assert!(definition.validate(&MzrMemoryMap::default()).is_err());
+
+        let rom: Vec<u8> = (0..0x20).collect();
+        let mut table = LiveTable::from_rom(definition, &rom).unwrap();
+        assert_eq!(table.get(0, 0), Some(0x1011 as f64 * 0.5));
+        assert_eq!(table.get(2, 0), None);
+        assert!(matches!(
+            table.set(0, 0, 20000.0),
+            Err(TableError::OutOfRange(_))
+        ));
+
+        table.set(0, 1, -1.0).unwrap();
+        table.set(0, 2, 2.0).unwrap();
+        // Unchanged cells are not written
+        table.set(1, 2, 0x1A1B as f64 * 0.5).unwrap();
+        assert_eq!(table.get(0, 1), Some(-1.0));
+        assert!(table.is_modified());
+
+        let mut bus = Recorder::default();
+        assert_eq!(table.flush(&mut bus, 0x7e0).unwrap(), 2);
+        assert_eq!(
+            bus.writes,
+            vec![vec![
+                0x3D, 0xFF, 0xFF, 0x80, 0x02, 0x00, 0x04, 0xFF, 0xFE, 0x00, 0x04
+            ]]
+        );
+        assert!(!table.is_modified());
+        assert_eq!(table.flush(&mut bus, 0x7e0).unwrap(), 0);
+    }
+}
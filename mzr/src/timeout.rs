@@ -0,0 +1,67 @@
+//! Per-operation request timeouts. Erasing flash takes tens of seconds while
+//! most requests are answered within milliseconds, so a single timeout is
+//! either too short for erasing or too slow to detect a dead link.
+
+use std::time::Duration;
+
+/// Class of request, used to select a timeout from a [`TimeoutProfile`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Operation {
+    /// Entering a diagnostic session
+    Connect,
+    /// Any request not covered by another operation
+    Request,
+    /// Erasing flash memory
+    Erase,
+    /// Reading or writing a block of memory
+    Transfer,
+    /// Security access
+    Security,
+}
+
+/// Timeouts used for each class of request
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TimeoutProfile {
+    pub connect: Duration,
+    pub request: Duration,
+    pub erase: Duration,
+    pub transfer: Duration,
+    pub security: Duration,
+}
+
+impl Default for TimeoutProfile {
+    fn default() -> TimeoutProfile {
+        TimeoutProfile {
+            connect: Duration::from_secs(1),
+            request: Duration::from_secs(1),
+            erase: Duration::from_secs(30),
+            transfer: Duration::from_secs(5),
+            security: Duration::from_secs(2),
+        }
+    }
+}
+
+impl TimeoutProfile {
+    /// Returns the timeout for `operation`
+    pub fn get(&self, operation: Operation) -> Duration {
+        match operation {
+            Operation::Connect => self.connect,
+            Operation::Request => self.request,
+            Operation::Erase => self.erase,
+            Operation::Transfer => self.transfer,
+            Operation::Security => self.security,
+        }
+    }
+}
+
+/// Transport with an adjustable response timeout
+pub trait SetTimeout {
+    /// Sets the timeout for subsequent requests
+    fn set_timeout(&mut self, timeout: Duration);
+}
+
+impl<T: SetTimeout + ?Sized> SetTimeout for &mut T {
+    fn set_timeout(&mut self, timeout: Duration) {
+        (**self).set_timeout(timeout)
+    }
+}
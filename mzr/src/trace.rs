@@ -0,0 +1,425 @@
+//! Offline analysis of captured sessions. Loads a candump log or PCAP file
+//! written by [`capture`](crate::capture) or other tools, reassembles the
+//! ISO-TP packets of each CAN ID and decodes them as UDS requests and
+//! responses.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use thiserror::Error;
+
+use crate::datalink::can::{Message, FD_FRAME_LENGTHS, MAX_EXTENDED_ID};
+use crate::isotp::{Addressing, Frame, ParseMode};
+use crate::repl::nrc_description;
+
+const UDS_RES_NEGATIVE: u8 = 0x7F;
+
+/// Link type of SocketCAN frames in PCAP files
+const LINKTYPE_CAN_SOCKETCAN: u32 = 227;
+/// Flags in the ID of SocketCAN frames
+const CAN_EFF_FLAG: u32 = 0x8000_0000;
+const CAN_RTR_FLAG: u32 = 0x4000_0000;
+const CAN_ERR_FLAG: u32 = 0x2000_0000;
+/// Flag of CAN-FD frames in SocketCAN frames
+const CANFD_FDF: u8 = 0x04;
+
+#[derive(Error, Debug)]
+pub enum TraceError {
+    #[error("failed to read trace: {0}")]
+    Io(#[from] io::Error),
+    #[error("invalid candump line {0}: '{1}'")]
+    InvalidLine(usize, String),
+    #[error("invalid PCAP file: {0}")]
+    InvalidPcap(&'static str),
+}
+
+/// CAN frame of a trace
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TraceFrame {
+    /// Capture time since the Unix epoch
+    pub time: Duration,
+    pub msg: Message,
+}
+
+/// Loads the frames of a candump log or PCAP file, telling them apart by
+/// their content
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Vec<TraceFrame>, TraceError> {
+    let data = fs::read(path)?;
+    if is_pcap(&data) {
+        parse_pcap(&data)
+    } else {
+        parse_candump(&String::from_utf8_lossy(&data))
+    }
+}
+
+fn is_pcap(data: &[u8]) -> bool {
+    matches!(
+        data.get(..4),
+        Some([0xD4, 0xC3, 0xB2, 0xA1]) | Some([0xA1, 0xB2, 0xC3, 0xD4])
+    )
+}
+
+/// Parses a log of `candump -l`, e.g. `(1600000000.123456) can0 7E0#021085`.
+/// Error and remote frames are skipped.
+pub fn parse_candump(log: &str) -> Result<Vec<TraceFrame>, TraceError> {
+    let mut frames = Vec::new();
+    for (number, line) in log.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let invalid = || TraceError::InvalidLine(number + 1, line.to_string());
+        let mut words = line.split_whitespace();
+        let (time, _interface, frame) = match (words.next(), words.next(), words.next()) {
+            (Some(time), Some(interface), Some(frame)) => (time, interface, frame),
+            _ => return Err(invalid()),
+        };
+        let time = parse_timestamp(time).ok_or_else(invalid)?;
+        let (id, data) = frame.split_once('#').ok_or_else(invalid)?;
+        let extended = id.len() == 8;
+        let id = u32::from_str_radix(id, 16).map_err(|_| invalid())?;
+        if data.starts_with('R') || id & CAN_ERR_FLAG != 0 {
+            continue;
+        }
+        // CAN-FD frames have a second '#' followed by a flags digit
+        let (fd, data) = match data.strip_prefix('#') {
+            Some(data) => (true, data.get(1..).ok_or_else(invalid)?),
+            None => (false, data),
+        };
+        let data = parse_hex(data).ok_or_else(invalid)?;
+        if data.len() > if fd { 64 } else { 8 } || id > MAX_EXTENDED_ID {
+            return Err(invalid());
+        }
+        frames.push(TraceFrame {
+            time,
+            msg: message(id, &data, extended, fd),
+        });
+    }
+    Ok(frames)
+}
+
+fn parse_timestamp(time: &str) -> Option<Duration> {
+    let time = time.strip_prefix('(')?.strip_suffix(')')?;
+    let (secs, micros) = time.split_once('.')?;
+    Some(Duration::new(
+        secs.parse().ok()?,
+        micros.parse::<u32>().ok()?.checked_mul(1000)?,
+    ))
+}
+
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Creates a message of any valid length, padding CAN-FD payloads like
+/// the sender did
+fn message(id: u32, data: &[u8], extended: bool, fd: bool) -> Message {
+    let mut msg = Message::new(id, &data[..data.len().min(8)]);
+    msg.data[..data.len()].copy_from_slice(data);
+    msg.len = data.len() as u8;
+    msg.extended = extended;
+    msg.fd = fd && FD_FRAME_LENGTHS.contains(&data.len());
+    msg
+}
+
+/// Parses a PCAP file of SocketCAN frames. Error and remote frames are
+/// skipped.
+pub fn parse_pcap(data: &[u8]) -> Result<Vec<TraceFrame>, TraceError> {
+    let header = data.get(..24).ok_or(TraceError::InvalidPcap("truncated"))?;
+    let little_endian = match header[..4] {
+        [0xD4, 0xC3, 0xB2, 0xA1] => true,
+        [0xA1, 0xB2, 0xC3, 0xD4] => false,
+        _ => return Err(TraceError::InvalidPcap("not a PCAP file")),
+    };
+    let read_u32 = |bytes: &[u8]| {
+        let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        if little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        }
+    };
+    if read_u32(&header[20..24]) != LINKTYPE_CAN_SOCKETCAN {
+        return Err(TraceError::InvalidPcap("not a SocketCAN capture"));
+    }
+
+    let mut frames = Vec::new();
+    let mut records = &data[24..];
+    while !records.is_empty() {
+        let record = records
+            .get(..16)
+            .ok_or(TraceError::InvalidPcap("truncated"))?;
+        let time = Duration::from_secs(read_u32(&record[..4]) as u64)
+            + Duration::from_micros(read_u32(&record[4..8]) as u64);
+        let len = read_u32(&record[8..12]) as usize;
+        let frame = records
+            .get(16..16 + len)
+            .ok_or(TraceError::InvalidPcap("truncated"))?;
+        records = &records[16 + len..];
+        if frame.len() < 8 {
+            return Err(TraceError::InvalidPcap("truncated frame"));
+        }
+        // The ID and flags of SocketCAN frames are big-endian
+        let id = u32::from_be_bytes([frame[0], frame[1], frame[2], frame[3]]);
+        if id & (CAN_ERR_FLAG | CAN_RTR_FLAG) != 0 {
+            continue;
+        }
+        let data_len = (frame[4] as usize).min(frame.len() - 8).min(64);
+        let fd = frame[5] & CANFD_FDF != 0 || data_len > 8;
+        let extended = id & CAN_EFF_FLAG != 0;
+        frames.push(TraceFrame {
+            time,
+            msg: message(id & MAX_EXTENDED_ID, &frame[8..8 + data_len], extended, fd),
+        });
+    }
+    Ok(frames)
+}
+
+/// ISO-TP packet reassembled from a trace
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    /// Time of the first frame
+    pub time: Duration,
+    pub id: u32,
+    pub data: Vec<u8>,
+}
+
+/// Something that happened in a trace
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TraceEvent {
+    Packet(Packet),
+    /// A frame that doesn't fit the ISO-TP conversation of its ID, e.g. a
+    /// lost consecutive frame
+    Error {
+        time: Duration,
+        id: u32,
+        message: String,
+    },
+}
+
+/// Packet being received on an ID
+struct Partial {
+    time: Duration,
+    size: usize,
+    data: Vec<u8>,
+    index: u8,
+}
+
+/// Reassembles the ISO-TP packets of every CAN ID in `frames`. Flow control
+/// frames are dropped.
+pub fn reassemble(frames: &[TraceFrame]) -> Vec<TraceEvent> {
+    let mut events = Vec::new();
+    let mut partials: Vec<(u32, Partial)> = Vec::new();
+    for frame in frames {
+        let id = frame.msg.id;
+        let error = |message: &str| TraceEvent::Error {
+            time: frame.time,
+            id,
+            message: message.to_string(),
+        };
+        let partial = partials
+            .iter()
+            .position(|(partial_id, _)| *partial_id == id);
+        match Frame::decode(frame.msg.payload(), ParseMode::Lenient, Addressing::Normal) {
+            Ok(Frame::Single { data }) => {
+                if partial.is_some() {
+                    events.push(error("packet interrupted by a single frame"));
+                    partials.retain(|(partial_id, _)| *partial_id != id);
+                }
+                events.push(TraceEvent::Packet(Packet {
+                    time: frame.time,
+                    id,
+                    data,
+                }));
+            }
+            Ok(Frame::First { size, data }) => {
+                if let Some(partial) = partial {
+                    events.push(error("packet interrupted by a first frame"));
+                    partials.remove(partial);
+                }
+                let size = size as usize;
+                partials.push((
+                    id,
+                    Partial {
+                        time: frame.time,
+                        size,
+                        data: data[..data.len().min(size)].to_vec(),
+                        index: 1,
+                    },
+                ));
+            }
+            Ok(Frame::Consecutive { index, data }) => {
+                let position = match partial {
+                    Some(position) => position,
+                    None => {
+                        events.push(error("consecutive frame without a first frame"));
+                        continue;
+                    }
+                };
+                let (_, partial) = &mut partials[position];
+                if index != partial.index {
+                    events.push(error(&format!(
+                        "expected consecutive frame {}, got {}",
+                        partial.index, index
+                    )));
+                    partials.remove(position);
+                    continue;
+                }
+                let len = data.len().min(partial.size - partial.data.len());
+                partial.data.extend_from_slice(&data[..len]);
+                partial.index = (partial.index + 1) % 16;
+                if partial.data.len() == partial.size {
+                    let (_, partial) = partials.remove(position);
+                    events.push(TraceEvent::Packet(Packet {
+                        time: partial.time,
+                        id,
+                        data: partial.data,
+                    }));
+                }
+            }
+            Ok(Frame::Flow { .. }) => {}
+            Err(_) => {}
+        }
+    }
+    for (id, partial) in partials {
+        events.push(TraceEvent::Error {
+            time: partial.time,
+            id,
+            message: format!(
+                "incomplete packet ({} of {} bytes)",
+                partial.data.len(),
+                partial.size
+            ),
+        });
+    }
+    events.sort_by_key(|event| match event {
+        TraceEvent::Packet(packet) => packet.time,
+        TraceEvent::Error { time, .. } => *time,
+    });
+    events
+}
+
+/// Returns the name of a UDS or OBD request service
+pub fn service_name(sid: u8) -> &'static str {
+    match sid {
+        0x01..=0x0A => "OBD",
+        0x10 => "DiagnosticSessionControl",
+        0x11 => "ECUReset",
+        0x14 => "ClearDiagnosticInformation",
+        0x19 => "ReadDTCInformation",
+        0x22 => "ReadDataByIdentifier",
+        0x23 => "ReadMemoryByAddress",
+        0x27 => "SecurityAccess",
+        0x28 => "CommunicationControl",
+        0x2E => "WriteDataByIdentifier",
+        0x2F => "InputOutputControlByIdentifier",
+        0x31 => "RoutineControl",
+        0x34 => "RequestDownload",
+        0x35 => "RequestUpload",
+        0x36 => "TransferData",
+        0x37 => "RequestTransferExit",
+        0x3D => "WriteMemoryByAddress",
+        0x3E => "TesterPresent",
+        0x85 => "ControlDTCSetting",
+        _ => "unknown service",
+    }
+}
+
+/// UDS meaning of a packet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decoded {
+    Request { sid: u8 },
+    Positive { sid: u8 },
+    Negative { sid: u8, nrc: Option<u8> },
+    Empty,
+}
+
+/// Decodes a packet as a UDS request or response. Responses have bit 6 of
+/// the SID set.
+pub fn decode(data: &[u8]) -> Decoded {
+    match data {
+        [] => Decoded::Empty,
+        [UDS_RES_NEGATIVE, sid, rest @ ..] => Decoded::Negative {
+            sid: *sid,
+            nrc: rest.first().copied(),
+        },
+        [UDS_RES_NEGATIVE] => Decoded::Negative { sid: 0, nrc: None },
+        [sid, ..] if sid & 0x40 != 0 => Decoded::Positive { sid: sid & !0x40 },
+        [sid, ..] => Decoded::Request { sid: *sid },
+    }
+}
+
+impl fmt::Display for Decoded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Decoded::Request { sid } => write!(f, "{} request", service_name(*sid)),
+            Decoded::Positive { sid } => write!(f, "{} positive response", service_name(*sid)),
+            Decoded::Negative {
+                sid,
+                nrc: Some(nrc),
+            } => write!(
+                f,
+                "{} negative response 0x{:02X} {}",
+                service_name(*sid),
+                nrc,
+                nrc_description(*nrc)
+            ),
+            Decoded::Negative { sid, nrc: None } => {
+                write!(f, "{} negative response", service_name(*sid))
+            }
+            Decoded::Empty => f.write_str("empty packet"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::{CaptureFormat, CaptureWriter};
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn reassembles_captures() {
+        let log = "(1600000000.000000) can0 7E0#0223FFFF8000000A\n\
+                   (1600000000.001000) can0 7E8#037F2378\n\
+                   (1600000000.002000) can0 7E8#100B63000102030A\n\
+                   (1600000000.002500) can0 7E0#3000000000000000\n\
+                   (1600000000.003000) can0 7E8#2104050607080900\n\
+                   (1600000000.004000) can0 7E8#2200000000000000\n\
+                   (1600000000.005000) can0 7E0#023E00\n";
+        let frames = parse_candump(log).unwrap();
+        assert_eq!(frames.len(), 7);
+        let events = reassemble(&frames);
+        let packets: Vec<&Packet> = events
+            .iter()
+            .filter_map(|event| match event {
+                TraceEvent::Packet(packet) => Some(packet),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(packets.len(), 4);
+        assert_eq!(decode(&packets[0].data), Decoded::Request { sid: 0x23 });
+        assert_eq!(
+            decode(&packets[1].data).to_string(),
+            "ReadMemoryByAddress negative response 0x78 requestCorrectlyReceivedResponsePending"
+        );
+        assert_eq!(packets[2].data, [0x63, 0, 1, 2, 3, 10, 4, 5, 6, 7, 8]);
+        assert_eq!(packets[2].time, Duration::new(1_600_000_000, 2_000_000));
+        // The third consecutive frame was superfluous
+        assert!(matches!(events[3], TraceEvent::Error { .. }));
+
+        // PCAP files written by captures load to the same frames
+        let mut pcap = CaptureWriter::new(Vec::new(), CaptureFormat::Pcap).unwrap();
+        for frame in frames.iter() {
+            pcap.write_frame(&frame.msg, UNIX_EPOCH + frame.time)
+                .unwrap();
+        }
+        assert_eq!(parse_pcap(&pcap.into_inner()).unwrap(), frames);
+    }
+}
@@ -0,0 +1,73 @@
+//! Common interface of [`Downloader`] and [`Programmer`], so progress UIs,
+//! retry wrappers and cancellation logic can be written once for reads and
+//! writes.
+
+use obd::Uds;
+
+use crate::timeout::SetTimeout;
+use crate::{Downloader, MzrError, Programmer};
+
+/// State after a transfer step
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TransferState {
+    /// Bytes transferred so far
+    InProgress(usize),
+    Completed,
+}
+
+/// A block-wise transfer to or from the ECU
+pub trait Transfer {
+    /// Prepares the ECU for the transfer. Must be called before
+    /// [`step`](Transfer::step).
+    fn start(&mut self) -> Result<(), MzrError>;
+
+    /// Transfers the next block
+    fn step(&mut self) -> Result<TransferState, MzrError>;
+
+    /// Returns the number of bytes to transfer
+    fn total_size(&self) -> usize;
+
+    /// Returns the number of bytes transferred so far
+    fn position(&self) -> usize;
+
+    /// Returns true once all data has been transferred
+    fn is_complete(&self) -> bool {
+        self.position() == self.total_size()
+    }
+}
+
+impl<'a, M: 'a + Uds + SetTimeout> Transfer for Downloader<'a, M> {
+    fn start(&mut self) -> Result<(), MzrError> {
+        Downloader::start(self)
+    }
+
+    fn step(&mut self) -> Result<TransferState, MzrError> {
+        Downloader::step(self)
+    }
+
+    fn total_size(&self) -> usize {
+        Downloader::total_size(self)
+    }
+
+    fn position(&self) -> usize {
+        Downloader::position(self)
+    }
+}
+
+impl<'a, M: 'a + Uds + SetTimeout> Transfer for Programmer<'a, M> {
+    fn start(&mut self) -> Result<(), MzrError> {
+        Programmer::start(self)
+    }
+
+    fn step(&mut self) -> Result<TransferState, MzrError> {
+        Programmer::step(self)
+    }
+
+    fn total_size(&self) -> usize {
+        Programmer::total_size(self)
+    }
+
+    fn position(&self) -> usize {
+        Programmer::position(self)
+    }
+}
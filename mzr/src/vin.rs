@@ -0,0 +1,342 @@
+//! Decoding of Mazda VINs, used to pick the profile of a vehicle without
+//! asking the user for its model.
+//!
+//! A VIN is made of the world manufacturer identifier (characters 1–3), the
+//! vehicle descriptor section (4–9) and the vehicle identifier section
+//! (10–17). Mazda encodes the model line in characters 4–5 and the engine in
+//! character 8; character 10 is the model year and 11 the plant.
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::Serialize;
+use thiserror::Error;
+
+/// Length of a VIN
+pub const VIN_LENGTH: usize = 17;
+
+/// World manufacturer identifiers assigned to Mazda
+const MAZDA_WMIS: &[(&str, &str)] = &[
+    ("JM1", "Mazda, Japan (passenger car)"),
+    ("JM2", "Mazda, Japan (truck)"),
+    ("JM3", "Mazda, Japan (SUV)"),
+    ("JM7", "Mazda, Japan (passenger car)"),
+    ("JMZ", "Mazda, Japan (Europe)"),
+    ("1YV", "Mazda, USA (passenger car)"),
+    ("4F2", "Mazda, USA (SUV)"),
+    ("4F4", "Mazda, USA (truck)"),
+];
+
+/// Mazda assembly plants, identified by character 11 of the VIN
+const PLANTS: &[(char, &str)] = &[
+    ('0', "Hiroshima, Japan"),
+    ('1', "Hiroshima, Japan"),
+    ('2', "Hofu, Japan"),
+    ('5', "Flat Rock, Michigan, USA"),
+];
+
+/// Engine, identified by character 8 of the VIN
+struct Engine {
+    code: char,
+    name: &'static str,
+    turbo: bool,
+}
+
+/// Mazda model line, identified by characters 4–5 of the VIN
+struct ModelLine {
+    code: &'static str,
+    name: &'static str,
+    engines: &'static [Engine],
+    /// Vehicle model of the turbocharged variant
+    turbo_model: Option<&'static str>,
+    /// Vehicle model of the naturally aspirated variants
+    na_model: Option<&'static str>,
+}
+
+const MZR_20: Engine = Engine {
+    code: 'F',
+    name: "2.0L MZR (LF)",
+    turbo: false,
+};
+const MZR_23: Engine = Engine {
+    code: '3',
+    name: "2.3L MZR (L3)",
+    turbo: false,
+};
+const MZR_25: Engine = Engine {
+    code: 'C',
+    name: "2.5L MZR (L5)",
+    turbo: false,
+};
+
+const MODEL_LINES: &[ModelLine] = &[
+    ModelLine {
+        code: "BK",
+        name: "Mazda3 (2004–2009)",
+        engines: &[
+            MZR_20,
+            MZR_23,
+            Engine {
+                code: 'M',
+                name: "2.3L MZR DISI Turbo (L3-VDT)",
+                turbo: true,
+            },
+        ],
+        turbo_model: Some("ms3"),
+        na_model: Some("mazda3"),
+    },
+    ModelLine {
+        code: "BL",
+        name: "Mazda3 (2010–2013)",
+        engines: &[
+            MZR_20,
+            MZR_25,
+            Engine {
+                code: 'L',
+                name: "2.3L MZR DISI Turbo (L3-VDT)",
+                turbo: true,
+            },
+        ],
+        turbo_model: Some("ms3-gen2"),
+        na_model: Some("mazda3"),
+    },
+    ModelLine {
+        code: "GG",
+        name: "Mazda6 (2003–2008)",
+        engines: &[
+            MZR_23,
+            Engine {
+                code: 'L',
+                name: "2.3L MZR DISI Turbo (L3-VDT)",
+                turbo: true,
+            },
+        ],
+        turbo_model: Some("ms6"),
+        na_model: Some("mazda6"),
+    },
+    ModelLine {
+        code: "GY",
+        name: "Mazda6 wagon (2003–2008)",
+        engines: &[MZR_23],
+        turbo_model: None,
+        na_model: Some("mazda6"),
+    },
+    ModelLine {
+        code: "HP",
+        name: "Mazda6",
+        engines: &[MZR_23, MZR_25],
+        turbo_model: None,
+        na_model: Some("mazda6"),
+    },
+    ModelLine {
+        code: "ER",
+        name: "CX-7 (2007–2012)",
+        engines: &[
+            MZR_25,
+            Engine {
+                code: 'L',
+                name: "2.3L MZR DISI Turbo (L3-VDT)",
+                turbo: true,
+            },
+        ],
+        turbo_model: Some("cx7"),
+        na_model: None,
+    },
+];
+
+/// Fields decoded from a VIN. Fields that aren't known are `None`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct VinInfo {
+    pub manufacturer: Option<&'static str>,
+    pub model: Option<&'static str>,
+    pub model_year: Option<u16>,
+    pub plant: Option<&'static str>,
+    pub engine: Option<&'static str>,
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum VinError {
+    #[error("VIN must be {} characters long, got {0}", VIN_LENGTH)]
+    Length(usize),
+    #[error("invalid character '{0}' in VIN")]
+    InvalidCharacter(char),
+}
+
+/// Validated vehicle identification number
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Vin(String);
+
+impl Vin {
+    /// Parses a VIN. Surrounding whitespace is ignored and letters are
+    /// uppercased.
+    pub fn parse(vin: &str) -> Result<Vin, VinError> {
+        let vin = vin.trim().to_ascii_uppercase();
+        if let Some(c) = vin
+            .chars()
+            .find(|&c| !c.is_ascii_alphanumeric() || matches!(c, 'I' | 'O' | 'Q'))
+        {
+            return Err(VinError::InvalidCharacter(c));
+        }
+        if vin.len() != VIN_LENGTH {
+            return Err(VinError::Length(vin.len()));
+        }
+        Ok(Vin(vin))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Returns the world manufacturer identifier
+    pub fn wmi(&self) -> &str {
+        &self.0[..3]
+    }
+
+    /// Returns true if the VIN was assigned by Mazda
+    pub fn is_mazda(&self) -> bool {
+        self.manufacturer().is_some()
+    }
+
+    fn manufacturer(&self) -> Option<&'static str> {
+        MAZDA_WMIS
+            .iter()
+            .find(|&&(wmi, _)| wmi == self.wmi())
+            .map(|&(_, name)| name)
+    }
+
+    fn model_line_info(&self) -> Option<&'static ModelLine> {
+        if !self.is_mazda() {
+            return None;
+        }
+        MODEL_LINES
+            .iter()
+            .find(|line| line.code == self.model_line())
+    }
+
+    fn engine(&self) -> Option<&'static Engine> {
+        self.model_line_info()?
+            .engines
+            .iter()
+            .find(|engine| engine.code == self.engine_code())
+    }
+
+    /// Returns Mazda's model line code
+    pub fn model_line(&self) -> &str {
+        &self.0[3..5]
+    }
+
+    /// Returns Mazda's engine code
+    pub fn engine_code(&self) -> char {
+        self.char_at(7)
+    }
+
+    /// Returns the model year. Year codes repeat every 30 years; the cycle
+    /// starting in 2001 is assumed.
+    pub fn model_year(&self) -> Option<u16> {
+        const LETTERS: &str = "ABCDEFGHJKLMNPRSTVWXY";
+        match self.char_at(9) {
+            c @ '1'..='9' => Some(2000 + c.to_digit(10).unwrap() as u16),
+            c => LETTERS.find(c).map(|i| 2010 + i as u16),
+        }
+    }
+
+    /// Returns the code of the assembly plant
+    pub fn plant_code(&self) -> char {
+        self.char_at(10)
+    }
+
+    /// Returns the vehicle model, as accepted by
+    /// [`EcuProfile::for_model`](crate::profile::EcuProfile::for_model), or
+    /// `None` if the vehicle isn't recognized
+    pub fn model(&self) -> Option<&'static str> {
+        let line = self.model_line_info()?;
+        match self.engine() {
+            Some(engine) if engine.turbo => line.turbo_model,
+            _ => line.na_model,
+        }
+    }
+
+    /// Decodes the VIN with the embedded Mazda tables
+    pub fn decode(&self) -> VinInfo {
+        let mazda = self.is_mazda();
+        VinInfo {
+            manufacturer: self.manufacturer(),
+            model: self.model_line_info().map(|line| line.name),
+            model_year: self.model_year(),
+            plant: PLANTS
+                .iter()
+                .find(|&&(code, _)| mazda && code == self.plant_code())
+                .map(|&(_, name)| name),
+            engine: self.engine().map(|engine| engine.name),
+        }
+    }
+
+    fn char_at(&self, index: usize) -> char {
+        self.0.as_bytes()[index] as char
+    }
+}
+
+impl FromStr for Vin {
+    type Err = VinError;
+
+    fn from_str(s: &str) -> Result<Vin, VinError> {
+        Vin::parse(s)
+    }
+}
+
+impl fmt::Display for Vin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_mazda_vins() {
+        let vin = Vin::parse(" jm1bk34m071234567 ").unwrap();
+        assert_eq!(vin.as_str(), "JM1BK34M071234567");
+        assert_eq!(vin.model_year(), Some(2007));
+        assert_eq!(vin.plant_code(), '1');
+        assert_eq!(vin.model(), Some("ms3"));
+        assert_eq!(
+            vin.decode(),
+            VinInfo {
+                manufacturer: Some("Mazda, Japan (passenger car)"),
+                model: Some("Mazda3 (2004–2009)"),
+                model_year: Some(2007),
+                plant: Some("Hiroshima, Japan"),
+                engine: Some("2.3L MZR DISI Turbo (L3-VDT)"),
+            }
+        );
+
+        assert_eq!(
+            Vin::parse("JM1BL1ML3A1234567").unwrap().model(),
+            Some("ms3-gen2")
+        );
+        assert_eq!(
+            Vin::parse("JM1BL1ML3A1234567").unwrap().model_year(),
+            Some(2010)
+        );
+        assert_eq!(
+            Vin::parse("JM1BK343X81111111").unwrap().model(),
+            Some("mazda3")
+        );
+        assert_eq!(
+            Vin::parse("1YVHP80C785123456").unwrap().model(),
+            Some("mazda6")
+        );
+        let other = Vin::parse("1HGCM82633A004352").unwrap();
+        assert_eq!(other.model(), None);
+        assert_eq!(other.decode().model_year, Some(2003));
+        assert_eq!(other.decode().plant, None);
+
+        assert_eq!(Vin::parse("JM1BK34M0712345"), Err(VinError::Length(15)));
+        assert_eq!(
+            Vin::parse("JM1BK34M07123456O"),
+            Err(VinError::InvalidCharacter('O'))
+        );
+    }
+}
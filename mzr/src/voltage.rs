@@ -0,0 +1,123 @@
+//! Battery voltage monitoring. Low supply voltage during programming is the
+//! most common cause of bricked ECUs.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tracing::warn;
+
+use crate::cancel::CancelToken;
+use crate::event::{Event, Events};
+#[cfg(feature = "passthru")]
+use crate::passthru;
+use crate::MzrError;
+
+/// Default minimum battery voltage for programming
+pub const DEFAULT_MIN_VOLTAGE: f32 = 12.0;
+
+/// Voltage above the minimum required before a sagging supply is considered
+/// recovered
+pub const HYSTERESIS: f32 = 0.3;
+
+/// Interval between voltage checks while programming
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Source of battery voltage readings
+pub trait VoltageMonitor {
+    /// Returns the battery voltage in volts
+    fn battery_voltage(&mut self) -> Result<f32, MzrError>;
+}
+
+impl<F: FnMut() -> Result<f32, MzrError>> VoltageMonitor for F {
+    fn battery_voltage(&mut self) -> Result<f32, MzrError> {
+        self()
+    }
+}
+
+/// Reads battery voltage from pin 16 of a J2534 device
+#[cfg(feature = "passthru")]
+pub struct PassThruVoltage<'a> {
+    device: &'a j2534::Device<'a>,
+}
+
+#[cfg(feature = "passthru")]
+impl<'a> PassThruVoltage<'a> {
+    pub fn new(device: &'a j2534::Device<'a>) -> PassThruVoltage<'a> {
+        PassThruVoltage { device }
+    }
+}
+
+#[cfg(feature = "passthru")]
+impl VoltageMonitor for PassThruVoltage<'_> {
+    fn battery_voltage(&mut self) -> Result<f32, MzrError> {
+        let millivolts = passthru::traced("PassThruIoctl", format_args!("READ_VBATT"), || {
+            self.device.read_battery_voltage()
+        })
+        .map_err(obd::Error::from)?;
+        Ok(millivolts as f32 / 1000.0)
+    }
+}
+
+/// Battery voltage check shared by everything that erases or programs
+/// flash: refuses to start below the minimum and pauses while the supply
+/// sags
+pub(crate) struct VoltageGate<'a> {
+    monitor: Box<dyn VoltageMonitor + 'a>,
+    minimum: f32,
+    last_check: Option<Instant>,
+}
+
+impl<'a> VoltageGate<'a> {
+    pub(crate) fn new<V: VoltageMonitor + 'a>(monitor: V, minimum: f32) -> VoltageGate<'a> {
+        VoltageGate {
+            monitor: Box::new(monitor),
+            minimum,
+            last_check: None,
+        }
+    }
+
+    /// Fails with [`MzrError::LowVoltage`] if the voltage is below the
+    /// minimum
+    pub(crate) fn check(&mut self) -> Result<(), MzrError> {
+        let voltage = self.monitor.battery_voltage()?;
+        if voltage < self.minimum {
+            return Err(MzrError::LowVoltage(voltage));
+        }
+        Ok(())
+    }
+
+    /// Checks the voltage at most every [`CHECK_INTERVAL`] and blocks while
+    /// it is below the minimum, calling `keep_alive` to maintain the
+    /// diagnostic session
+    pub(crate) fn wait<F>(
+        &mut self,
+        events: &mut Events,
+        cancel: &CancelToken,
+        mut keep_alive: F,
+    ) -> Result<(), MzrError>
+    where
+        F: FnMut() -> Result<(), MzrError>,
+    {
+        if matches!(self.last_check, Some(t) if t.elapsed() < CHECK_INTERVAL) {
+            return Ok(());
+        }
+        self.last_check = Some(Instant::now());
+
+        let minimum = self.minimum;
+        let mut voltage = self.monitor.battery_voltage()?;
+        if voltage >= minimum {
+            return Ok(());
+        }
+        warn!(voltage, minimum, "battery voltage low, pausing");
+        events.emit(Event::LowVoltage { voltage });
+        while voltage < minimum + HYSTERESIS && !cancel.is_cancelled() {
+            keep_alive()?;
+            thread::sleep(Duration::from_millis(500));
+            voltage = self.monitor.battery_voltage()?;
+        }
+        if !cancel.is_cancelled() {
+            events.emit(Event::VoltageRecovered { voltage });
+        }
+        Ok(())
+    }
+}
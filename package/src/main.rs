@@ -0,0 +1,138 @@
+use std::path::Path;
+use std::process;
+
+use mzr::container::RomContainer;
+use mzr::image::Image;
+use mzr::memory_map::MzrMemoryMap;
+use mzr::signing;
+
+use clap::clap_app;
+use serde_json::json;
+
+pub fn main() {
+    let matches = clap_app!(myapp =>
+        (version: "1.0")
+        (author: "Jacob Manning <jjacob.manning@gmail.com>")
+        (about: "Creates, signs and verifies .mzrrom tune packages")
+        (@setting SubcommandRequiredElseHelp)
+        (@subcommand keygen =>
+            (about: "Generates a signing key and prints its public key")
+            (@arg KEY_FILE: +required "File to save the secret key to")
+        )
+        (@subcommand create =>
+            (about: "Wraps an image in a package")
+            (@arg model: -m --model +takes_value "Vehicle model")
+            (@arg notes: -n --notes +takes_value "Notes stored in the package")
+            (@arg key: -k --key +takes_value "Signs the package with this key file")
+            (@arg vin_lock: --("vin-lock") +takes_value "Only allows the package to be flashed to this VIN")
+            (@arg INPUT: +required "Input image (raw .bin, Intel HEX, S-record or .mzrrom)")
+            (@arg OUTPUT: +required "Package to create")
+        )
+        (@subcommand sign =>
+            (about: "Signs a package in place")
+            (@arg key: -k --key +takes_value +required "Key file")
+            (@arg PACKAGE: +required "Package to sign")
+        )
+        (@subcommand verify =>
+            (about: "Checks a package and prints its signer")
+            (@arg json: --json "Prints machine-readable JSON output")
+            (@arg PACKAGE: +required "Package to verify")
+        )
+    )
+    .get_matches();
+
+    match matches.subcommand() {
+        Some(("keygen", matches)) => {
+            let key = signing::generate_key();
+            let path = Path::new(matches.value_of("KEY_FILE").unwrap());
+            if let Err(err) = signing::save_signing_key(path, &key) {
+                fail(format!("Failed to save key: {}", err));
+            }
+            println!("{}", signing::public_key_hex(&key.verifying_key()));
+        }
+        Some(("create", matches)) => {
+            let input = Path::new(matches.value_of("INPUT").unwrap());
+            let memory_map = MzrMemoryMap::default();
+            let image = Image::load(input, &memory_map)
+                .unwrap_or_else(|err| fail(format!("Failed to load image: {}", err)));
+            let mut container = image.to_container(&memory_map);
+            container.header.model = matches.value_of("model").map(str::to_owned);
+            container.header.notes = matches.value_of("notes").map(str::to_owned);
+            container.header.vin_lock = matches.value_of("vin_lock").map(str::to_owned);
+            if let Some(key) = matches.value_of("key") {
+                container.sign(&load_key(key));
+            }
+            save(
+                &mut container,
+                Path::new(matches.value_of("OUTPUT").unwrap()),
+            );
+        }
+        Some(("sign", matches)) => {
+            let key = load_key(matches.value_of("key").unwrap());
+            let path = Path::new(matches.value_of("PACKAGE").unwrap());
+            let mut container = load(path);
+            container.sign(&key);
+            save(&mut container, path);
+            println!(
+                "Signed with {}",
+                signing::public_key_hex(&key.verifying_key())
+            );
+        }
+        Some(("verify", matches)) => {
+            let path = Path::new(matches.value_of("PACKAGE").unwrap());
+            let result = RomContainer::load(path);
+            if matches.is_present("json") {
+                println!(
+                    "{}",
+                    match result {
+                        Ok(ref container) => json!({
+                            "valid": true,
+                            "signer": container.signer().map(|key| signing::public_key_hex(&key)),
+                            "vin_lock": container.header.vin_lock,
+                        }),
+                        Err(ref err) => json!({ "valid": false, "error": err.to_string() }),
+                    }
+                );
+            } else {
+                match result {
+                    Ok(ref container) => {
+                        match container.signer() {
+                            Some(key) => {
+                                println!("Valid, signed by {}", signing::public_key_hex(&key))
+                            }
+                            None => println!("Valid, unsigned"),
+                        }
+                        if let Some(ref lock) = container.header.vin_lock {
+                            println!("Locked to VIN {}", lock);
+                        }
+                    }
+                    Err(ref err) => println!("Invalid: {}", err),
+                }
+            }
+            if result.is_err() {
+                process::exit(1);
+            }
+        }
+        _ => unreachable!(),
+    }
+}
+
+fn load_key(path: &str) -> signing::SigningKey {
+    signing::load_signing_key(Path::new(path))
+        .unwrap_or_else(|err| fail(format!("Failed to load key: {}", err)))
+}
+
+fn load(path: &Path) -> RomContainer {
+    RomContainer::load(path).unwrap_or_else(|err| fail(format!("Invalid package: {}", err)))
+}
+
+fn save(container: &mut RomContainer, path: &Path) {
+    if let Err(err) = container.save(path) {
+        fail(format!("Failed to write package: {}", err));
+    }
+}
+
+fn fail(message: String) -> ! {
+    eprintln!("{}", message);
+    process::exit(1)
+}
@@ -0,0 +1,976 @@
+//! Probes what an ECU supports, for reverse engineering unsupported ECUs
+
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use mzr::config::Config;
+use mzr::eeprom;
+use mzr::memory_map::{Eeprom, Region};
+use mzr::output::Output;
+use mzr::passthru::{self, PassThruChannel};
+use mzr::peek::{self, Format, Width};
+use mzr::probe;
+use mzr::profile::{EcuProfile, KeyAlgorithm};
+use mzr::repl::Repl;
+use mzr::session::Session;
+use mzr::trace::{self, TraceEvent};
+use mzr::{MzrBus, MzrError, SECURITY_LEVEL_DEFAULT};
+use obd::Uds;
+
+use clap::clap_app;
+use indicatif::{ProgressBar, ProgressStyle};
+use serde_json::json;
+use tracing_subscriber::filter::{LevelFilter, Targets};
+use tracing_subscriber::fmt;
+use tracing_subscriber::prelude::*;
+
+/// Address ranges mapped by default: the internal flash and its mirrors, and
+/// the RAM and peripheral registers at the top of the address space
+const DEFAULT_MEMORY_SPACES: [Region; 2] = [
+    Region::new(0, 0x200000),
+    Region::new(0xFFFF0000, 0xFFFFFFFF),
+];
+
+pub fn main() -> ExitCode {
+    run();
+    Output::exit_code()
+}
+
+fn run() {
+    let matches = clap_app!(myapp =>
+        (version: "1.0")
+        (author: "Jacob Manning <jjacob.manning@gmail.com>")
+        (about: "Probes the capabilities of an ECU for reverse engineering")
+        (@arg device: -d --device alias("passthru") +takes_value "J2534 device to use when connecting to the ECU (name or index)")
+        (@arg library: --library +takes_value "J2534 library (DLL) to load instead of the installed drivers")
+        (@arg module: --module +takes_value "Request ID of the module to probe, in hex (defaults to the configured request ID)")
+        (@arg json: --json "Prints machine-readable JSON output")
+        (@arg debug_adapter: --("debug-adapter") "Logs every J2534 call with its parameters and return code to stderr")
+        (@subcommand devices =>
+            (about: "Lists installed J2534 devices"))
+        (@subcommand dids =>
+            (about: "Reads every data identifier in a range and reports those that exist")
+            (@arg start: --start +takes_value default_value("0x0000") "First identifier, in hex")
+            (@arg end: --end +takes_value default_value("0xFFFF") "Last identifier, in hex")
+            (@arg output: -o --output +takes_value "Writes the report to this JSON file"))
+        (@subcommand capabilities =>
+            (about: "Maps the security access levels granted with the known key algorithm and the routines that exist")
+            (@arg session: --session +takes_value "Diagnostic session to enter first, in hex, e.g. 0x85")
+            (@arg profile: -p --profile +takes_value "ECU profile file providing the key algorithm (defaults to Mazda's)")
+            (@arg start: --start +takes_value default_value("0x0000") "First routine identifier, in hex")
+            (@arg end: --end +takes_value default_value("0xFFFF") "Last routine identifier, in hex")
+            (@arg output: -o --output +takes_value "Writes the capability map to this JSON file"))
+        (@subcommand memory =>
+            (about: "Maps the memory regions ReadMemoryByAddress allows reading")
+            (@arg session: --session +takes_value default_value("0x87") "Diagnostic session to read memory in, in hex")
+            (@arg profile: -p --profile +takes_value "ECU profile file providing the key algorithm (defaults to Mazda's)")
+            (@arg start: --start +takes_value requires("end") "First address, in hex (defaults to the ROM and the top 64 KiB of the address space)")
+            (@arg end: --end +takes_value requires("start") "End of the range (exclusive), in hex")
+            (@arg step: --step +takes_value default_value("0x100") "Distance between sampled addresses, in hex. Smaller regions may be missed")
+            (@arg output: -o --output +takes_value "Writes the memory map to this JSON file"))
+        (@subcommand peek =>
+            (about: "Reads values from memory with ReadMemoryByAddress")
+            (@arg session: --session +takes_value default_value("0x87") "Diagnostic session to read memory in, in hex")
+            (@arg profile: -p --profile +takes_value "ECU profile file providing the key algorithm (defaults to Mazda's)")
+            (@arg size: -s --size +takes_value possible_values(&["1", "2", "4"]) default_value("1") "Size of each value in bytes")
+            (@arg count: -n --count +takes_value default_value("1") "Number of values to read")
+            (@arg format: -f --format +takes_value possible_values(&["hex", "unsigned", "signed", "float"]) default_value("hex") "How values are printed")
+            (@arg address: +required "Address to read, in hex"))
+        (@subcommand poke =>
+            (about: "Writes a value to memory with WriteMemoryByAddress")
+            (@arg session: --session +takes_value default_value("0x87") "Diagnostic session to write memory in, in hex")
+            (@arg profile: -p --profile +takes_value "ECU profile file providing the key algorithm (defaults to Mazda's)")
+            (@arg size: -s --size +takes_value possible_values(&["1", "2", "4"]) default_value("1") "Size of the value in bytes")
+            (@arg yes: -y --yes "Writes without asking for confirmation")
+            (@arg address: +required "Address to write, in hex")
+            (@arg value: +required "Value to write: hex with a 0x prefix, decimal, or a float for 4-byte values"))
+        (@subcommand eeprom =>
+            (about: "Reads the configuration EEPROM into a file, or writes a file to it for cloning and repairs")
+            (@arg session: --session +takes_value default_value("0x87") "Diagnostic session to access the EEPROM in, in hex")
+            (@arg profile: -p --profile +takes_value "ECU profile file describing the EEPROM mapping (defaults to the configured model's)")
+            (@arg write: --write "Writes FILE to the EEPROM after backing up its current contents")
+            (@arg yes: -y --yes "Writes without asking for confirmation")
+            (@arg FILE: +required "EEPROM image to read into or write from"))
+        (@subcommand trace =>
+            (about: "Decodes the UDS conversation of a captured candump log or PCAP file")
+            (@arg FILE: +required "Capture to analyze"))
+        (@subcommand repl =>
+            (about: "Sends raw UDS requests typed as hex bytes, e.g. 22 F1 90")
+            (@arg profile: -p --profile +takes_value "ECU profile file providing the key algorithm (defaults to Mazda's)"))
+    )
+    .get_matches();
+
+    let out = Output::new(matches.is_present("json"));
+    if matches.subcommand_matches("devices").is_some() {
+        print_drivers(out);
+        return;
+    }
+    // --debug-adapter shows the J2534 calls traced by mzr on stderr
+    if matches.is_present("debug_adapter") {
+        tracing_subscriber::registry()
+            .with(fmt::layer().with_writer(io::stderr))
+            .with(Targets::new().with_target("j2534", LevelFilter::TRACE))
+            .init();
+    }
+
+    // Traces are analyzed offline
+    if let Some(matches) = matches.subcommand_matches("trace") {
+        print_trace(out, matches.value_of("FILE").unwrap());
+        return;
+    }
+
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(err) => {
+            out.error(err);
+            return;
+        }
+    };
+    let request_id = match matches.value_of("module") {
+        Some(module) => match parse_hex(module) {
+            Some(id) => id,
+            None => {
+                out.error(format!("Invalid module ID '{}'", module));
+                return;
+            }
+        },
+        None => config.request_id,
+    };
+
+    // Select an interface
+    let selector = matches.value_of("device").or(config.device.as_deref());
+    let library = matches
+        .value_of("library")
+        .map(Path::new)
+        .or(config.library.as_deref());
+    let device = match passthru::select_driver(library, selector) {
+        Ok(device) => device,
+        Err(err) => {
+            out.error(err);
+            return;
+        }
+    };
+
+    out.message(format!("Opening interface '{}'", device.name));
+    let i = match j2534::Interface::new(&device.path) {
+        Ok(i) => i,
+        Err(err) => {
+            out.error(format!("Failed to load {}: {}", device.path, err));
+            return;
+        }
+    };
+    let d = match passthru::traced("PassThruOpen", format_args!(""), || i.open_any()) {
+        Ok(d) => d,
+        Err(err) => {
+            out.error(format!("Failed to open the adapter: {}", err));
+            return;
+        }
+    };
+    let mut channel = match PassThruChannel::new(&d, 500000, Duration::from_millis(500)) {
+        Ok(channel) => channel,
+        Err(err) => {
+            out.error(format!("Failed to open channel: {}", err));
+            return;
+        }
+    };
+    if let Err(err) = channel.set_filter(request_id, request_id + 8) {
+        out.error(format!("Failed to set filter: {}", err));
+        return;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("dids") {
+        let (start, end) = match identifier_range(matches) {
+            Some(range) => range,
+            None => {
+                out.error("Invalid identifier range");
+                return;
+            }
+        };
+        scan_dids(
+            out,
+            &mut channel,
+            request_id,
+            start,
+            end,
+            matches.value_of("output"),
+        );
+    } else if let Some(matches) = matches.subcommand_matches("capabilities") {
+        let (start, end) = match identifier_range(matches) {
+            Some(range) => range,
+            None => {
+                out.error("Invalid identifier range");
+                return;
+            }
+        };
+        let algorithm = match key_algorithm(out, matches) {
+            Some(algorithm) => algorithm,
+            None => return,
+        };
+        let session = match matches.value_of("session") {
+            Some(session) => match parse_hex(session) {
+                Some(session) if session <= 0xFF => Some(session as u8),
+                _ => {
+                    out.error(format!("Invalid session '{}'", session));
+                    return;
+                }
+            },
+            None => None,
+        };
+        if let Some(session) = session {
+            out.message(format!("Entering session 0x{:02X}", session));
+            if let Err(err) = channel.set_diagnostic_session(request_id, session) {
+                out.error(format!("Failed to enter session: {}", err));
+                return;
+            }
+        }
+        map_capabilities(
+            out,
+            &mut channel,
+            request_id,
+            session,
+            &algorithm,
+            start,
+            end,
+            matches.value_of("output"),
+        );
+        if session.is_some() {
+            let _ = channel.exit_session(request_id);
+        }
+    } else if let Some(matches) = matches.subcommand_matches("memory") {
+        let spaces = match (matches.value_of("start"), matches.value_of("end")) {
+            (Some(start), Some(end)) => match (parse_hex(start), parse_hex(end)) {
+                (Some(start), Some(end)) if start < end => vec![Region::new(start, end)],
+                _ => {
+                    out.error("Invalid address range");
+                    return;
+                }
+            },
+            _ => DEFAULT_MEMORY_SPACES.to_vec(),
+        };
+        let step = match matches.value_of("step").and_then(parse_hex) {
+            Some(step) if step > 0 => step,
+            _ => {
+                out.error("Invalid step");
+                return;
+            }
+        };
+        if !unlock(out, &mut channel, request_id, matches) {
+            return;
+        }
+        map_memory(
+            out,
+            &mut channel,
+            request_id,
+            &spaces,
+            step,
+            matches.value_of("output"),
+        );
+        let _ = channel.exit_session(request_id);
+    } else if let Some(matches) = matches.subcommand_matches("peek") {
+        let address = match matches.value_of("address").and_then(parse_hex) {
+            Some(address) => address,
+            None => {
+                out.error("Invalid address");
+                return;
+            }
+        };
+        let width = value_width(matches);
+        let count = match matches.value_of("count").unwrap().parse::<usize>() {
+            Ok(count) if count > 0 && width.bytes() * count <= 0xFFFF => count,
+            _ => {
+                out.error("Invalid count");
+                return;
+            }
+        };
+        let format = match matches.value_of("format") {
+            Some("unsigned") => Format::Unsigned,
+            Some("signed") => Format::Signed,
+            Some("float") => Format::Float,
+            _ => Format::Hex,
+        };
+        if format == Format::Float && width != Width::Long {
+            out.error("Floats are 4 bytes wide");
+            return;
+        }
+        if !unlock(out, &mut channel, request_id, matches) {
+            return;
+        }
+        peek_memory(out, &mut channel, request_id, address, width, count, format);
+        let _ = channel.exit_session(request_id);
+    } else if let Some(matches) = matches.subcommand_matches("poke") {
+        let address = match matches.value_of("address").and_then(parse_hex) {
+            Some(address) => address,
+            None => {
+                out.error("Invalid address");
+                return;
+            }
+        };
+        let width = value_width(matches);
+        let data = match peek::parse_value(matches.value_of("value").unwrap(), width) {
+            Ok(data) => data,
+            Err(err) => {
+                out.error(err);
+                return;
+            }
+        };
+        if !matches.is_present("yes") && !confirm_write(out, address, &data) {
+            return;
+        }
+        if !unlock(out, &mut channel, request_id, matches) {
+            return;
+        }
+        match peek::poke(&mut channel, request_id, address, &data) {
+            Ok(()) => {
+                out.message(format!("Wrote {} to 0x{:08X}", hex(&data), address));
+                out.event(json!({ "event": "poke", "address": address, "data": data }));
+            }
+            Err(err) => out.error(format!("Write failed: {}", err)),
+        }
+        let _ = channel.exit_session(request_id);
+    } else if let Some(matches) = matches.subcommand_matches("eeprom") {
+        let profile = match EcuProfile::select(matches.value_of("profile"), None, &config) {
+            Ok(profile) => profile.unwrap_or_else(|| EcuProfile::detect(None, &config)),
+            Err(err) => {
+                out.error(err);
+                return;
+            }
+        };
+        let eeprom = match profile.memory_map.eeprom {
+            Some(eeprom) => eeprom,
+            None => {
+                out.error(format!(
+                    "The EEPROM mapping of {} is unknown. Describe it in a profile file.",
+                    profile.name
+                ));
+                return;
+            }
+        };
+        let path = matches.value_of("FILE").unwrap();
+        let image = if matches.is_present("write") {
+            let image = match fs::read(path) {
+                Ok(image) => image,
+                Err(err) => {
+                    out.error(format!("Failed to read {}: {}", path, err));
+                    return;
+                }
+            };
+            if image.len() != eeprom.size as usize {
+                out.error(format!(
+                    "{} is {} bytes, but the EEPROM holds {}",
+                    path,
+                    image.len(),
+                    eeprom.size
+                ));
+                return;
+            }
+            if !matches.is_present("yes") && !confirm_eeprom_write(out, path) {
+                return;
+            }
+            Some(image)
+        } else {
+            None
+        };
+        if !unlock(out, &mut channel, request_id, matches) {
+            return;
+        }
+        match image {
+            Some(image) => write_eeprom(
+                out,
+                &mut channel,
+                request_id,
+                &eeprom,
+                &image,
+                config.output_dir.as_deref(),
+            ),
+            None => read_eeprom(out, &mut channel, request_id, &eeprom, path),
+        }
+        let _ = channel.exit_session(request_id);
+    } else if let Some(matches) = matches.subcommand_matches("repl") {
+        let algorithm = match key_algorithm(out, matches) {
+            Some(algorithm) => algorithm,
+            None => return,
+        };
+        let mut session = Session::new(&mut channel);
+        session.set_request_id(request_id);
+        session.set_key_algorithm(algorithm);
+        repl(out, Repl::new(session));
+    }
+}
+
+/// Prints the installed J2534 drivers, numbered for `--device`
+fn print_drivers(out: Output) {
+    let drivers = match passthru::installed_drivers() {
+        Ok(drivers) => drivers,
+        Err(err) => {
+            out.error(err);
+            return;
+        }
+    };
+    for (index, driver) in drivers.iter().enumerate() {
+        out.message(format!(
+            "{}: {} ({})\n   {}",
+            index, driver.name, driver.vendor, driver.path
+        ));
+        out.event(json!({
+            "event": "device",
+            "index": index,
+            "name": driver.name,
+            "vendor": driver.vendor,
+            "path": driver.path,
+        }));
+    }
+}
+
+/// Reads commands from stdin until it is closed, keeping the session alive
+/// while waiting
+fn repl<M: Uds>(out: Output, mut repl: Repl<M>) {
+    out.message("Type requests as hex bytes, `unlock [level]` or `id <request ID>`. Ctrl-D exits.");
+    let (sender, lines) = mpsc::channel();
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            if line.map(|line| sender.send(line)).is_err() {
+                break;
+            }
+        }
+    });
+
+    loop {
+        if !out.is_json() {
+            print!("> ");
+            let _ = io::stdout().flush();
+        }
+        let line = loop {
+            match lines.recv_timeout(Duration::from_millis(500)) {
+                Ok(line) => break Some(line),
+                Err(RecvTimeoutError::Timeout) => {
+                    if let Err(err) = repl.keep_alive() {
+                        out.error(format!("Keep-alive failed: {}", err));
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => break None,
+            }
+        };
+        let line = match line {
+            Some(line) if line.trim().is_empty() => continue,
+            Some(line) => line,
+            None => break,
+        };
+        match repl.execute(&line) {
+            Ok(reply) => {
+                out.message(&reply);
+                out.event(
+                    json!({ "event": "reply", "request": line.trim(), "reply": reply.to_string() }),
+                );
+            }
+            Err(err) => out.error(err),
+        }
+    }
+    out.message("");
+}
+
+/// Enters the session selected by `--session` and unlocks security access
+/// with the key algorithm of `--profile`. Returns whether it succeeded.
+fn unlock(
+    out: Output,
+    channel: &mut PassThruChannel,
+    request_id: u32,
+    matches: &clap::ArgMatches,
+) -> bool {
+    let algorithm = match key_algorithm(out, matches) {
+        Some(algorithm) => algorithm,
+        None => return false,
+    };
+    let session = match matches.value_of("session").and_then(parse_hex) {
+        Some(session) if session <= 0xFF => session as u8,
+        _ => {
+            out.error("Invalid session");
+            return false;
+        }
+    };
+    out.message(format!("Entering session 0x{:02X}", session));
+    let unlocked = channel
+        .set_diagnostic_session(request_id, session)
+        .map_err(MzrError::from)
+        .and_then(|_| channel.unlock_with(request_id, SECURITY_LEVEL_DEFAULT, &algorithm));
+    match unlocked {
+        Ok(()) => true,
+        Err(err) => {
+            out.error(format!("Failed to unlock: {}", err));
+            false
+        }
+    }
+}
+
+/// Asks on stdin before writing `data` to `address`. Writes in JSON mode
+/// must be confirmed with `--yes`.
+fn confirm_write(out: Output, address: u32, data: &[u8]) -> bool {
+    if out.is_json() {
+        out.error("Writing memory requires --yes in JSON mode");
+        return false;
+    }
+    print!(
+        "Write {} to 0x{:08X}? The ECU runs with the new value immediately. [y/N] ",
+        hex(data),
+        address
+    );
+    let _ = io::stdout().flush();
+    let mut answer = String::new();
+    if io::stdin().lock().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim(), "y" | "Y" | "yes")
+}
+
+/// Asks on stdin before writing `path` to the EEPROM. Writes in JSON mode
+/// must be confirmed with `--yes`.
+fn confirm_eeprom_write(out: Output, path: &str) -> bool {
+    if out.is_json() {
+        out.error("Writing the EEPROM requires --yes in JSON mode");
+        return false;
+    }
+    print!(
+        "Write {} to the EEPROM? It holds the immobilizer keys and VIN; a wrong image can leave the vehicle unable to start. [y/N] ",
+        path
+    );
+    let _ = io::stdout().flush();
+    let mut answer = String::new();
+    if io::stdin().lock().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim(), "y" | "Y" | "yes")
+}
+
+/// Reads the whole EEPROM with a progress bar
+fn read_eeprom_contents(
+    out: Output,
+    channel: &mut PassThruChannel,
+    request_id: u32,
+    eeprom: &Eeprom,
+) -> Option<Vec<u8>> {
+    let pb = progress_bar(out, u64::from(eeprom.size));
+    let result = eeprom::read(channel, request_id, eeprom, |read| {
+        pb.set_position(read as u64)
+    });
+    pb.finish_and_clear();
+    match result {
+        Ok(data) => Some(data),
+        Err(err) => {
+            out.error(format!("Failed to read the EEPROM: {}", err));
+            None
+        }
+    }
+}
+
+/// Reads the EEPROM into `path`
+fn read_eeprom(
+    out: Output,
+    channel: &mut PassThruChannel,
+    request_id: u32,
+    eeprom: &Eeprom,
+    path: &str,
+) {
+    out.message(format!(
+        "Reading {} bytes of EEPROM at 0x{:08X}",
+        eeprom.size, eeprom.base
+    ));
+    let data = match read_eeprom_contents(out, channel, request_id, eeprom) {
+        Some(data) => data,
+        None => return,
+    };
+    match fs::write(path, &data) {
+        Ok(()) => {
+            out.message(format!("EEPROM written to {}", path));
+            out.event(json!({ "event": "eeprom_read", "path": path, "size": data.len() }));
+        }
+        Err(err) => out.error(format!("Failed to write {}: {}", path, err)),
+    }
+}
+
+/// Backs up the EEPROM into `backup_dir` and writes the pages of `image`
+/// that differ from it
+fn write_eeprom(
+    out: Output,
+    channel: &mut PassThruChannel,
+    request_id: u32,
+    eeprom: &Eeprom,
+    image: &[u8],
+    backup_dir: Option<&Path>,
+) {
+    out.message("Backing up the EEPROM...");
+    let current = match read_eeprom_contents(out, channel, request_id, eeprom) {
+        Some(current) => current,
+        None => return,
+    };
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let file_name = format!("eeprom-backup-{}.bin", timestamp);
+    let backup_path = match backup_dir {
+        Some(dir) => dir.join(file_name),
+        None => PathBuf::from(file_name),
+    };
+    if let Err(err) = fs::write(&backup_path, &current) {
+        out.error(format!("Failed to write backup: {}", err));
+        return;
+    }
+    out.message(format!("Backed up the EEPROM to {}", backup_path.display()));
+
+    let changed = eeprom::changed_pages(eeprom, &current, image);
+    if changed.is_empty() {
+        out.message("The EEPROM already holds this image");
+        return;
+    }
+    let pb = progress_bar(out, changed.len() as u64);
+    let result = eeprom::write(channel, request_id, eeprom, &current, image, |page| {
+        pb.set_message(&format!("0x{:04X}", page.start));
+        pb.inc(1);
+    });
+    pb.finish_and_clear();
+    match result {
+        Ok(pages) => {
+            out.message(format!("Wrote and verified {} pages", pages.len()));
+            out.event(json!({
+                "event": "eeprom_written",
+                "pages": pages.len(),
+                "backup": backup_path,
+            }));
+        }
+        Err(err) => {
+            out.error(format!("Write failed: {}", err));
+            out.message(format!(
+                "Restore the previous contents with --write {}",
+                backup_path.display()
+            ));
+        }
+    }
+}
+
+/// Returns the value width selected by `--size`
+fn value_width(matches: &clap::ArgMatches) -> Width {
+    matches
+        .value_of("size")
+        .and_then(|size| size.parse().ok())
+        .and_then(Width::from_bytes)
+        .unwrap_or(Width::Byte)
+}
+
+/// Reads and prints `count` values at `address`, one line per 16 bytes
+fn peek_memory(
+    out: Output,
+    channel: &mut PassThruChannel,
+    request_id: u32,
+    address: u32,
+    width: Width,
+    count: usize,
+    format: Format,
+) {
+    let data = match peek::peek(channel, request_id, address, width, count) {
+        Ok(data) => data,
+        Err(err) => {
+            out.error(format!("Read failed: {}", err));
+            return;
+        }
+    };
+    // The width and format were validated by the caller
+    let values = peek::format_values(&data, width, format).unwrap();
+    let per_line = (16 / width.bytes()).max(1);
+    for (i, line) in values.chunks(per_line).enumerate() {
+        let line_address = address as usize + i * per_line * width.bytes();
+        out.message(format!("0x{:08X}:  {}", line_address, line.join(" ")));
+    }
+    out.event(json!({ "event": "peek", "address": address, "data": data, "values": values }));
+}
+
+/// Number of packet bytes printed on a trace line
+const TRACE_BYTES: usize = 16;
+
+/// Prints the packets of a capture with their UDS meaning, one line each.
+/// Responses show the time since the last request.
+fn print_trace(out: Output, path: &str) {
+    let frames = match trace::load(path) {
+        Ok(frames) => frames,
+        Err(err) => {
+            out.error(err);
+            return;
+        }
+    };
+    let start = match frames.first() {
+        Some(frame) => frame.time,
+        None => {
+            out.message("The capture holds no frames");
+            return;
+        }
+    };
+    let mut last_request = None;
+    for event in trace::reassemble(&frames) {
+        let packet = match event {
+            TraceEvent::Packet(packet) => packet,
+            TraceEvent::Error { time, id, message } => {
+                let time = time.saturating_sub(start).as_secs_f64();
+                out.message(format!("{:12.6}  {:03X}  ! {}", time, id, message));
+                out.event(
+                    json!({ "event": "trace_error", "time": time, "id": id, "message": message }),
+                );
+                continue;
+            }
+        };
+        let decoded = trace::decode(&packet.data);
+        let latency = match decoded {
+            trace::Decoded::Request { .. } => {
+                last_request = Some(packet.time);
+                None
+            }
+            _ => last_request.map(|request| packet.time.saturating_sub(request)),
+        };
+        let mut data = hex(&packet.data[..packet.data.len().min(TRACE_BYTES)]);
+        if packet.data.len() > TRACE_BYTES {
+            data.push_str(&format!(" .. ({} bytes)", packet.data.len()));
+        }
+        let annotation = match latency {
+            Some(latency) => format!("{} (+{} ms)", decoded, latency.as_millis()),
+            None => decoded.to_string(),
+        };
+        let time = packet.time.saturating_sub(start).as_secs_f64();
+        out.message(format!(
+            "{:12.6}  {:03X}  {}  {}",
+            time, packet.id, data, annotation
+        ));
+        out.event(json!({
+            "event": "packet",
+            "time": time,
+            "id": packet.id,
+            "data": packet.data,
+            "decoded": decoded.to_string(),
+            "latency_ms": latency.map(|latency| latency.as_millis() as u64),
+        }));
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Returns the key algorithm of the profile selected by `--profile`, or
+/// Mazda's
+fn key_algorithm(out: Output, matches: &clap::ArgMatches) -> Option<KeyAlgorithm> {
+    match matches.value_of("profile") {
+        Some(path) => match EcuProfile::load(path) {
+            Ok(profile) => Some(profile.key_algorithm),
+            Err(err) => {
+                out.error(err);
+                None
+            }
+        },
+        None => Some(KeyAlgorithm::Mazda),
+    }
+}
+
+/// Returns the identifier range selected by `--start` and `--end`
+fn identifier_range(matches: &clap::ArgMatches) -> Option<(u16, u16)> {
+    let start = parse_hex(matches.value_of("start")?)?;
+    let end = parse_hex(matches.value_of("end")?)?;
+    if start <= end && end <= 0xFFFF {
+        Some((start as u16, end as u16))
+    } else {
+        None
+    }
+}
+
+/// Creates a progress bar counting identifiers or addresses, hidden in JSON
+/// mode
+fn progress_bar(out: Output, length: u64) -> ProgressBar {
+    let pb = if out.is_json() {
+        ProgressBar::hidden()
+    } else {
+        ProgressBar::new(length)
+    };
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template(
+                "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({msg})",
+            )
+            .progress_chars("#>-"),
+    );
+    pb
+}
+
+/// Writes a report to `path`
+fn write_report(out: Output, path: &str, report: &serde_json::Value) {
+    match fs::write(path, serde_json::to_string_pretty(report).unwrap()) {
+        Ok(()) => out.message(format!("Report written to {}", path)),
+        Err(err) => out.error(format!("Failed to write {}: {}", path, err)),
+    }
+}
+
+/// Walks the data identifiers from `start` to `end` and reports those that
+/// exist
+fn scan_dids(
+    out: Output,
+    channel: &mut PassThruChannel,
+    request_id: u32,
+    start: u16,
+    end: u16,
+    output: Option<&str>,
+) {
+    out.message(format!(
+        "Reading identifiers 0x{:04X}-0x{:04X} from 0x{:03X}",
+        start, end, request_id
+    ));
+    let pb = progress_bar(out, u64::from(end - start) + 1);
+    let entries = probe::scan_dids(channel, request_id, start..=end, |did| {
+        pb.set_message(&format!("0x{:04X}", did));
+        pb.inc(1);
+    });
+    pb.finish_and_clear();
+    let entries = match entries {
+        Ok(entries) => entries,
+        Err(err) => {
+            out.error(format!("Scan failed: {}", err));
+            return;
+        }
+    };
+
+    for entry in entries.iter() {
+        match (entry.length, entry.nrc) {
+            (Some(length), _) => out.message(format!("0x{:04X}\t{} bytes", entry.did, length)),
+            (None, Some(nrc)) => {
+                out.message(format!("0x{:04X}\tdenied (NRC 0x{:02X})", entry.did, nrc))
+            }
+            (None, None) => out.message(format!("0x{:04X}\tdenied", entry.did)),
+        }
+    }
+    out.message(format!("{} identifiers found", entries.len()));
+    let report = json!({ "module": request_id, "start": start, "end": end, "dids": entries });
+    out.event(json!({ "event": "dids", "report": report }));
+
+    if let Some(path) = output {
+        write_report(out, path, &report);
+    }
+}
+
+/// Probes the security access levels and routines of the ECU
+#[allow(clippy::too_many_arguments)]
+fn map_capabilities(
+    out: Output,
+    channel: &mut PassThruChannel,
+    request_id: u32,
+    session: Option<u8>,
+    algorithm: &KeyAlgorithm,
+    start: u16,
+    end: u16,
+    output: Option<&str>,
+) {
+    out.message("Probing security access levels");
+    let levels = match probe::scan_security_levels(
+        channel,
+        request_id,
+        probe::SECURITY_LEVELS,
+        algorithm,
+        |_| {},
+    ) {
+        Ok(levels) => levels,
+        Err(err) => {
+            out.error(format!("Security access probe failed: {}", err));
+            return;
+        }
+    };
+    for level in levels.iter() {
+        let result = match (level.granted, level.nrc) {
+            (true, _) => "granted".to_string(),
+            (false, Some(nrc)) => format!("denied (NRC 0x{:02X})", nrc),
+            (false, None) => "denied".to_string(),
+        };
+        out.message(format!("Level 0x{:02X}\t{}", level.level, result));
+    }
+
+    out.message(format!("Probing routines 0x{:04X}-0x{:04X}", start, end));
+    let pb = progress_bar(out, u64::from(end - start) + 1);
+    let routines = probe::scan_routines(channel, request_id, start..=end, |id| {
+        pb.set_message(&format!("0x{:04X}", id));
+        pb.inc(1);
+    });
+    pb.finish_and_clear();
+    // ECUs without RoutineControl still have a useful security map
+    let routines = match routines {
+        Ok(routines) => routines,
+        Err(err) => {
+            out.error(format!("Routine probe failed: {}", err));
+            Vec::new()
+        }
+    };
+    for routine in routines.iter() {
+        match routine.nrc {
+            Some(nrc) => out.message(format!("Routine 0x{:04X}\tNRC 0x{:02X}", routine.id, nrc)),
+            None => out.message(format!("Routine 0x{:04X}\tresults available", routine.id)),
+        }
+    }
+
+    let report = json!({
+        "module": request_id,
+        "session": session,
+        "security_levels": levels,
+        "routines": routines,
+    });
+    out.event(json!({ "event": "capabilities", "report": report }));
+    if let Some(path) = output {
+        write_report(out, path, &report);
+    }
+}
+
+/// Maps the readable regions of each address range in `spaces`
+fn map_memory(
+    out: Output,
+    channel: &mut PassThruChannel,
+    request_id: u32,
+    spaces: &[Region],
+    step: u32,
+    output: Option<&str>,
+) {
+    let mut regions = Vec::new();
+    for space in spaces {
+        out.message(format!("Mapping 0x{:08X}-0x{:08X}", space.start, space.end));
+        let pb = progress_bar(out, u64::from(space.end - space.start));
+        let mapped = probe::map_readable(channel, request_id, *space, step, |address| {
+            pb.set_message(&format!("0x{:08X}", address));
+            pb.set_position(u64::from(address - space.start));
+        });
+        pb.finish_and_clear();
+        match mapped {
+            Ok(mapped) => regions.extend(mapped),
+            Err(err) => {
+                out.error(format!("Mapping failed: {}", err));
+                return;
+            }
+        }
+    }
+
+    for region in regions.iter() {
+        out.message(format!(
+            "0x{:08X}-0x{:08X}\t{} bytes readable",
+            region.start,
+            region.end,
+            region.len()
+        ));
+    }
+    let report =
+        json!({ "module": request_id, "spaces": spaces, "step": step, "readable": regions });
+    out.event(json!({ "event": "memory", "report": report }));
+    if let Some(path) = output {
+        write_report(out, path, &report);
+    }
+}
+
+/// Parses a hex number, with or without a 0x prefix
+fn parse_hex(value: &str) -> Option<u32> {
+    u32::from_str_radix(value.trim_start_matches("0x"), 16).ok()
+}
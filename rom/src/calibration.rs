@@ -0,0 +1,131 @@
+//! Known MZR-DISI calibration IDs. The ECU reports its calibration ID over
+//! OBD, and the same ID is stored in the ROM, so both live ECUs and dumps can
+//! be labelled with the vehicle they belong to.
+
+use std::fmt;
+
+use serde::Serialize;
+
+/// Vehicle a calibration was released for
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Calibration {
+    /// Calibration ID without its revision letter, e.g. `L3K9188K1`
+    pub id: &'static str,
+    pub vehicle: &'static str,
+    pub market: &'static str,
+    pub transmission: &'static str,
+    /// Vehicle model, as accepted by
+    /// `EcuProfile::for_model` in the `mzr` crate
+    pub model: &'static str,
+}
+
+impl Calibration {
+    /// Returns true if a calibration for `other` must not be flashed over
+    /// this one: the vehicle model or transmission differ. Calibrations for
+    /// other markets of the same car are compatible.
+    pub fn conflicts_with(&self, other: &Calibration) -> bool {
+        self.model != other.model || self.transmission != other.transmission
+    }
+}
+
+impl fmt::Display for Calibration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} ({}, {})",
+            self.vehicle, self.market, self.transmission
+        )
+    }
+}
+
+const CALIBRATIONS: &[Calibration] = &[
+    Calibration {
+        id: "L3K9188K1",
+        vehicle: "Mazdaspeed3 2007–2009",
+        market: "North America",
+        transmission: "6-speed manual",
+        model: "ms3",
+    },
+    Calibration {
+        id: "L3P3188K1",
+        vehicle: "Mazda3 MPS 2007–2009",
+        market: "Europe",
+        transmission: "6-speed manual",
+        model: "ms3",
+    },
+    Calibration {
+        id: "L3YH188K1",
+        vehicle: "Mazdaspeed3 2010–2013",
+        market: "North America",
+        transmission: "6-speed manual",
+        model: "ms3-gen2",
+    },
+    Calibration {
+        id: "L3K9188M1",
+        vehicle: "Mazdaspeed6 2006–2007",
+        market: "North America",
+        transmission: "6-speed manual",
+        model: "ms6",
+    },
+    Calibration {
+        id: "L3R2188K1",
+        vehicle: "Mazda6 MPS 2006–2007",
+        market: "Europe",
+        transmission: "6-speed manual",
+        model: "ms6",
+    },
+    Calibration {
+        id: "L33L188K1",
+        vehicle: "CX-7 2007–2009",
+        market: "North America",
+        transmission: "6-speed automatic",
+        model: "cx7",
+    },
+];
+
+/// Returns the known calibrations
+pub fn all() -> &'static [Calibration] {
+    CALIBRATIONS
+}
+
+/// Looks up a calibration ID as reported by the ECU. Dashes, whitespace and
+/// the revision letter are ignored.
+pub fn lookup(calibration_id: &str) -> Option<&'static Calibration> {
+    let id: String = calibration_id
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    CALIBRATIONS
+        .iter()
+        .find(|calibration| id.starts_with(calibration.id))
+}
+
+/// Returns the known calibration whose ID is stored in a ROM image
+pub fn identify(rom: &[u8]) -> Option<&'static Calibration> {
+    CALIBRATIONS.iter().find(|calibration| {
+        rom.windows(calibration.id.len())
+            .any(|window| window == calibration.id.as_bytes())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_calibrations() {
+        let ms3 = lookup("l3k9-188k1-d").unwrap();
+        assert_eq!(ms3.model, "ms3");
+        assert_eq!(lookup("L3K9188K1D"), Some(ms3));
+        assert_eq!(lookup("ZZZZ188K1A"), None);
+
+        let mut rom = vec![0xFF; 0x1000];
+        rom[0x800..0x80A].copy_from_slice(b"L3R2188K1B");
+        let mps6 = identify(&rom).unwrap();
+        assert_eq!(mps6.model, "ms6");
+        assert!(ms3.conflicts_with(mps6));
+        assert!(!ms3.conflicts_with(lookup("L3P3188K1A").unwrap()));
+        assert_eq!(identify(&rom[..0x800]), None);
+    }
+}
@@ -0,0 +1,131 @@
+//! ROM checksums. The sum of the big-endian words of each checksum region of
+//! the memory map must equal the region's target; the first word of the
+//! region is a correction value that makes it so.
+
+use std::convert::TryFrom;
+use std::num::Wrapping;
+
+use serde::{Deserialize, Serialize};
+
+use crate::memory_map::{ChecksumRegion, MzrMemoryMap};
+
+/// Checksum of one region of a ROM
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RegionChecksum {
+    pub start: u32,
+    pub end: u32,
+    /// Sum of the region
+    pub checksum: u32,
+    /// Sum the region must have
+    pub target: u32,
+}
+
+impl RegionChecksum {
+    /// Returns true if the region has its target sum
+    pub fn valid(&self) -> bool {
+        self.checksum == self.target
+    }
+}
+
+/// Checksums of every region of a ROM
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChecksumReport {
+    /// Name of the memory map the ROM was checked against
+    pub memory_map: String,
+    pub regions: Vec<RegionChecksum>,
+    /// Whether every region has its target sum
+    pub valid: bool,
+}
+
+pub fn compute_checksum(data: &[u8]) -> u32 {
+    let mut sum = Wrapping(0_u32);
+
+    for chunk in data.chunks(4) {
+        sum += Wrapping(u32::from_be_bytes(
+            <&[u8; 4]>::try_from(chunk).unwrap().to_owned(),
+        ));
+    }
+
+    sum.0
+}
+
+/// Returns true if the checksum was corrected
+pub fn correct_checksum(data: &mut [u8], target: u32) -> bool {
+    // Zero correction region
+    data[0] = 0;
+    data[1] = 0;
+    data[2] = 0;
+    data[3] = 0;
+
+    let sum = compute_checksum(data);
+    let correction: u32 = (Wrapping(target) - Wrapping(sum)).0;
+    data[0..4].copy_from_slice(&correction.to_be_bytes());
+
+    compute_checksum(data) == target
+}
+
+/// Returns the slice of a full ROM covered by `region`, or `None` if `rom`
+/// doesn't cover the whole flash
+fn slice<'r>(rom: &'r [u8], map: &MzrMemoryMap, region: &ChecksumRegion) -> Option<&'r [u8]> {
+    if rom.len() != map.flash_size() {
+        return None;
+    }
+    let start = region.start.checked_sub(map.flash().start)? as usize;
+    rom.get(start..start + region.region().len())
+}
+
+/// Returns the checksum of each region of a full ROM, in the order of
+/// [`MzrMemoryMap::checksums`], or `None` if `rom` doesn't cover the whole
+/// flash
+pub fn rom_checksums(rom: &[u8], map: &MzrMemoryMap) -> Option<Vec<u32>> {
+    map.checksums
+        .iter()
+        .map(|region| slice(rom, map, region).map(compute_checksum))
+        .collect()
+}
+
+/// Checks every region of a full ROM, or returns `None` if `rom` doesn't
+/// cover the whole flash
+pub fn checksum_report(rom: &[u8], map: &MzrMemoryMap) -> Option<ChecksumReport> {
+    let regions: Vec<RegionChecksum> = rom_checksums(rom, map)?
+        .into_iter()
+        .zip(&map.checksums)
+        .map(|(checksum, region)| RegionChecksum {
+            start: region.start,
+            end: region.end,
+            checksum,
+            target: region.target,
+        })
+        .collect();
+    Some(ChecksumReport {
+        memory_map: map.name.clone(),
+        valid: regions.iter().all(RegionChecksum::valid),
+        regions,
+    })
+}
+
+/// Returns true if `rom` is a full ROM whose checksums are all correct
+pub fn checksum_valid(rom: &[u8], map: &MzrMemoryMap) -> bool {
+    match rom_checksums(rom, map) {
+        Some(sums) => sums
+            .iter()
+            .zip(&map.checksums)
+            .all(|(&sum, region)| sum == region.target),
+        None => false,
+    }
+}
+
+/// Corrects every checksum of a full ROM. Returns false if `rom` doesn't
+/// cover the whole flash or a checksum couldn't be corrected.
+pub fn correct_rom_checksum(rom: &mut [u8], map: &MzrMemoryMap) -> bool {
+    if rom.len() != map.flash_size() {
+        return false;
+    }
+    let base = map.flash().start;
+    map.checksums.iter().all(|region| {
+        let start = (region.start - base) as usize;
+        let end = start + region.region().len();
+        rom.get_mut(start..end)
+            .is_some_and(|data| correct_checksum(data, region.target))
+    })
+}
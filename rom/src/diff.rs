@@ -0,0 +1,234 @@
+//! Differences between two ROM images, e.g. a stock ROM and a tune
+
+use std::cmp;
+
+use serde::{Deserialize, Serialize};
+
+use crate::memory_map::{MzrMemoryMap, Region};
+use crate::table::TableDefinition;
+
+/// Run of bytes that differ, from `start` up to but excluding `end`
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Difference {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Difference {
+    /// Returns the number of bytes in the run
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    /// Returns true if the run is empty
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    /// Returns true if the run overlaps the `len` bytes at `start`
+    pub fn overlaps(&self, start: usize, len: usize) -> bool {
+        self.start < start.saturating_add(len) && start < self.end
+    }
+}
+
+/// Returns the runs of bytes that differ between `old` and `new`, merging
+/// runs separated by fewer than `gap` equal bytes. Bytes past the end of the
+/// shorter image differ.
+pub fn diff(old: &[u8], new: &[u8], gap: usize) -> Vec<Difference> {
+    let mut differences: Vec<Difference> = Vec::new();
+    let common = cmp::min(old.len(), new.len());
+    let mut offset = 0;
+    while offset < common {
+        if old[offset] == new[offset] {
+            offset += 1;
+            continue;
+        }
+        let start = offset;
+        while offset < common && old[offset] != new[offset] {
+            offset += 1;
+        }
+        match differences.last_mut() {
+            Some(last) if start - last.end < gap => last.end = offset,
+            _ => differences.push(Difference { start, end: offset }),
+        }
+    }
+
+    let end = cmp::max(old.len(), new.len());
+    if common < end {
+        match differences.last_mut() {
+            Some(last) if common - last.end < gap => last.end = end,
+            _ => differences.push(Difference { start: common, end }),
+        }
+    }
+    differences
+}
+
+/// Returns the tables of `definitions` with cells in `differences`
+pub fn changed_tables<'a>(
+    definitions: &'a [TableDefinition],
+    differences: &[Difference],
+) -> Vec<&'a TableDefinition> {
+    definitions
+        .iter()
+        .filter(|definition| {
+            differences.iter().any(|difference| {
+                difference.overlaps(definition.address as usize, definition.len())
+            })
+        })
+        .collect()
+}
+
+/// Flash sector reprogrammed by a delta flash, with its new contents
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SectorUpdate {
+    pub sector: Region,
+    pub data: Vec<u8>,
+}
+
+/// Compares `image`, programmed at `offset`, with `reference`, a dump of
+/// the whole flash, and returns the programmable sectors whose contents
+/// change. Parts of a sector outside the image keep the contents of the
+/// reference. Returns `None` if `reference` isn't a dump of `map`.
+pub fn changed_sectors(
+    map: &MzrMemoryMap,
+    reference: &[u8],
+    offset: u32,
+    image: &[u8],
+) -> Option<Vec<SectorUpdate>> {
+    if reference.len() != map.flash_size() {
+        return None;
+    }
+    let flash = map.flash();
+    let mut merged = reference.to_vec();
+    let start = offset.checked_sub(flash.start)? as usize;
+    let end = cmp::min(start + image.len(), merged.len());
+    merged
+        .get_mut(start..end)?
+        .copy_from_slice(&image[..end - start]);
+
+    let programmable = map.programmable();
+    Some(
+        map.sector_regions()
+            .into_iter()
+            .filter(|sector| programmable.contains_range(sector.start, sector.len()))
+            .filter_map(|sector| {
+                let range =
+                    (sector.start - flash.start) as usize..(sector.end - flash.start) as usize;
+                if merged[range.clone()] == reference[range.clone()] {
+                    return None;
+                }
+                Some(SectorUpdate {
+                    sector,
+                    data: merged[range].to_vec(),
+                })
+            })
+            .collect(),
+    )
+}
+
+/// Returns the programmable sectors overlapping `region` with their
+/// contents in `image`, programmed at `offset`, e.g. to reprogram a region
+/// the ECU rejected. Returns `None` if the image doesn't cover all of them.
+pub fn region_sectors(
+    map: &MzrMemoryMap,
+    region: Region,
+    offset: u32,
+    image: &[u8],
+) -> Option<Vec<SectorUpdate>> {
+    let programmable = map.programmable();
+    map.sector_regions()
+        .into_iter()
+        .filter(|sector| programmable.contains_range(sector.start, sector.len()))
+        .filter(|sector| sector.overlaps(region.start, region.len()))
+        .map(|sector| {
+            let start = sector.start.checked_sub(offset)? as usize;
+            Some(SectorUpdate {
+                sector,
+                data: image.get(start..start + sector.len())?.to_vec(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::table::parse_definitions;
+
+    #[test]
+    fn finds_changed_runs_and_tables() {
+        let old = [0u8; 16];
+        let mut new = [0u8; 18];
+        new[2] = 1;
+        new[4] = 1;
+        new[10] = 1;
+        assert_eq!(
+            diff(&old, &new, 0),
+            [
+                Difference { start: 2, end: 3 },
+                Difference { start: 4, end: 5 },
+                Difference { start: 10, end: 11 },
+                Difference { start: 16, end: 18 },
+            ]
+        );
+        let merged = diff(&old, &new, 2);
+        assert_eq!(
+            merged,
+            [
+                Difference { start: 2, end: 5 },
+                Difference { start: 10, end: 11 },
+                Difference { start: 16, end: 18 },
+            ]
+        );
+        assert!(diff(&old, &old, 4).is_empty());
+
+        let definitions = parse_definitions(
+            "[[table]]\nname = \"A\"\naddress = 6\nram_address = 0\nrows = 1\ncolumns = 2\n\n\
+             [[table]]\nname = \"B\"\naddress = 8\nram_address = 0\nrows = 1\ncolumns = 2\n",
+        )
+        .unwrap();
+        let changed = changed_tables(&definitions, &merged);
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].name, "B");
+    }
+
+    #[test]
+    fn finds_changed_sectors() {
+        let map = MzrMemoryMap::mzr_disi_1m();
+        let reference: Vec<u8> = (0..map.flash_size()).map(|i| i as u8).collect();
+        let mut image = reference[0x8000..].to_vec();
+        image[0x9000 - 0x8000] ^= 0xFF;
+        image[0x50000 - 0x8000] ^= 0xFF;
+        let updates = changed_sectors(&map, &reference, 0x8000, &image).unwrap();
+        let sectors: Vec<Region> = updates.iter().map(|update| update.sector).collect();
+        assert_eq!(
+            sectors,
+            [Region::new(0x8000, 0xA000), Region::new(0x40000, 0x60000)]
+        );
+        assert_eq!(updates[0].data[0x1000], !reference[0x9000]);
+        assert_eq!(updates[1].data.len(), 0x20000);
+
+        // A short image leaves the rest of its last sector as it was
+        let updates = changed_sectors(&map, &reference, 0x8000, &image[..0x1001]).unwrap();
+        assert_eq!(updates.len(), 1);
+        assert_eq!(&updates[0].data[0x1001..], &reference[0x9001..0xA000]);
+        assert!(
+            changed_sectors(&map, &reference, 0x8000, &reference[0x8000..])
+                .unwrap()
+                .is_empty()
+        );
+        assert!(changed_sectors(&map, &reference[..0x1000], 0x8000, &image).is_none());
+
+        let updates = region_sectors(&map, Region::new(0x48000, 0x100000), 0x8000, &image).unwrap();
+        assert_eq!(updates.len(), 6);
+        assert_eq!(updates[0].sector, Region::new(0x40000, 0x60000));
+        assert_eq!(updates[0].data, &image[0x38000..0x58000]);
+        assert!(region_sectors(
+            &map,
+            Region::new(0x48000, 0x100000),
+            0x8000,
+            &image[..0x1000]
+        )
+        .is_none());
+    }
+}
@@ -0,0 +1,12 @@
+//! Offline ROM tools: memory maps, checksums, calibration identification,
+//! diffs, calibration transplants and table definitions. Everything operates
+//! on byte slices and strings without I/O, so the crate builds for
+//! `wasm32-unknown-unknown` and can back a browser-based ROM inspector. The `mzr` crate re-exports
+//! these modules.
+
+pub mod calibration;
+pub mod checksum;
+pub mod diff;
+pub mod memory_map;
+pub mod table;
+pub mod transplant;
@@ -0,0 +1,308 @@
+//! Memory layout of each supported ECU generation. Downloads, programming
+//! and checksums are driven by a [`MzrMemoryMap`] instead of fixed offsets.
+
+use serde::{Deserialize, Serialize};
+
+/// Half-open address range `[start, end)`
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Region {
+    pub start: u32,
+    pub end: u32,
+}
+
+impl Region {
+    pub const fn new(start: u32, end: u32) -> Region {
+        Region { start, end }
+    }
+
+    /// Returns the size of the region in bytes
+    pub fn len(&self) -> usize {
+        self.end.saturating_sub(self.start) as usize
+    }
+
+    /// Returns true if the region is empty
+    pub fn is_empty(&self) -> bool {
+        self.end <= self.start
+    }
+
+    /// Returns true if `address` is inside the region
+    pub fn contains(&self, address: u32) -> bool {
+        self.start <= address && address < self.end
+    }
+
+    /// Returns true if `length` bytes starting at `address` are inside the
+    /// region
+    pub fn contains_range(&self, address: u32, length: usize) -> bool {
+        address >= self.start && address as u64 + length as u64 <= self.end as u64
+    }
+
+    /// Returns true if any of the `length` bytes starting at `address` are
+    /// inside the region
+    pub fn overlaps(&self, address: u32, length: usize) -> bool {
+        length > 0
+            && (address as u64) < self.end as u64
+            && address as u64 + length as u64 > self.start as u64
+    }
+}
+
+/// Region of flash whose big-endian words must sum to `target`. The first
+/// word of the region is a correction value that makes it so.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChecksumRegion {
+    pub start: u32,
+    pub end: u32,
+    pub target: u32,
+}
+
+impl ChecksumRegion {
+    /// Returns the address range covered by the checksum
+    pub fn region(&self) -> Region {
+        Region::new(self.start, self.end)
+    }
+}
+
+/// Configuration EEPROM holding the immobilizer keys, VIN and adaptations.
+/// It is a memory space of its own, separate from the flash: the ECU maps
+/// EEPROM offset 0 to `base` for ReadMemoryByAddress and
+/// WriteMemoryByAddress.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Eeprom {
+    pub base: u32,
+    /// Size in bytes
+    pub size: u32,
+    /// Bytes written at once. Writes are done a page at a time.
+    pub page_size: u16,
+}
+
+impl Eeprom {
+    /// Returns the address range the EEPROM is mapped to
+    pub fn region(&self) -> Region {
+        Region::new(self.base, self.base.saturating_add(self.size))
+    }
+
+    /// Returns the pages, as offsets into the EEPROM
+    pub fn pages(&self) -> Vec<Region> {
+        let page_size = self.page_size.max(1) as u32;
+        (0..self.size)
+            .step_by(page_size as usize)
+            .map(|start| Region::new(start, (start + page_size).min(self.size)))
+            .collect()
+    }
+}
+
+/// Layout of an ECU's flash and RAM
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MzrMemoryMap {
+    /// Identifies the layout, e.g. in `.mzrrom` containers
+    pub name: String,
+    /// Bootloader at the start of flash. It is never erased by the
+    /// bootloader's own erase routine.
+    pub bootloader: Region,
+    /// Application code
+    pub code: Region,
+    /// Calibration data
+    pub calibration: Region,
+    /// RAM a kernel can be uploaded to
+    pub ram: Region,
+    /// Start address of each flash erase sector, in ascending order
+    pub sectors: Vec<u32>,
+    /// Regions covered by a ROM checksum
+    pub checksums: Vec<ChecksumRegion>,
+    /// Configuration EEPROM, if its mapping is known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub eeprom: Option<Eeprom>,
+}
+
+impl Default for MzrMemoryMap {
+    fn default() -> MzrMemoryMap {
+        MzrMemoryMap::mzr_disi_1m()
+    }
+}
+
+impl MzrMemoryMap {
+    /// 1 MiB MZR-DISI ECU (SH7058)
+    pub fn mzr_disi_1m() -> MzrMemoryMap {
+        // Eight 8 KiB blocks, one 64 KiB block, then seven 128 KiB blocks
+        let mut sectors: Vec<u32> = (0..8).map(|i| i * 0x2000).collect();
+        sectors.push(0x10000);
+        sectors.extend((0..7).map(|i| 0x20000 + i * 0x20000));
+        MzrMemoryMap {
+            name: "mzr-disi-1m".to_string(),
+            bootloader: Region::new(0, 0x8000),
+            code: Region::new(0x8000, 0x48000),
+            calibration: Region::new(0x48000, 0x100000),
+            ram: Region::new(0xFFFF_0000, 0xFFFF_C000),
+            sectors,
+            checksums: vec![ChecksumRegion {
+                start: 0x48000,
+                end: 0x100000,
+                target: 0x5AA5_5AA5,
+            }],
+            eeprom: None,
+        }
+    }
+
+    /// 1.5 MiB ECU of the second-generation Mazdaspeed3 (SH7059). The code
+    /// and calibration are checksummed separately.
+    pub fn mzr_disi_gen2() -> MzrMemoryMap {
+        // Eight 8 KiB blocks, one 64 KiB block, then eleven 128 KiB blocks
+        let mut sectors: Vec<u32> = (0..8).map(|i| i * 0x2000).collect();
+        sectors.push(0x10000);
+        sectors.extend((0..11).map(|i| 0x20000 + i * 0x20000));
+        MzrMemoryMap {
+            name: "mzr-disi-gen2".to_string(),
+            bootloader: Region::new(0, 0x10000),
+            code: Region::new(0x10000, 0xA0000),
+            calibration: Region::new(0xA0000, 0x180000),
+            ram: Region::new(0xFFFF_0000, 0xFFFF_C000),
+            sectors,
+            checksums: vec![
+                ChecksumRegion {
+                    start: 0x10000,
+                    end: 0xA0000,
+                    target: 0xA55A_A55A,
+                },
+                ChecksumRegion {
+                    start: 0xA0000,
+                    end: 0x180000,
+                    target: 0x5AA5_5AA5,
+                },
+            ],
+            eeprom: None,
+        }
+    }
+
+    /// 512 KiB ECU of the naturally aspirated MZR engines (SH7055)
+    pub fn mzr_na_512k() -> MzrMemoryMap {
+        // Eight 8 KiB blocks, one 64 KiB block, then three 128 KiB blocks
+        let mut sectors: Vec<u32> = (0..8).map(|i| i * 0x2000).collect();
+        sectors.push(0x10000);
+        sectors.extend((0..3).map(|i| 0x20000 + i * 0x20000));
+        MzrMemoryMap {
+            name: "mzr-na-512k".to_string(),
+            bootloader: Region::new(0, 0x8000),
+            code: Region::new(0x8000, 0x40000),
+            calibration: Region::new(0x40000, 0x80000),
+            ram: Region::new(0xFFFF_8000, 0xFFFF_C000),
+            sectors,
+            checksums: vec![ChecksumRegion {
+                start: 0x40000,
+                end: 0x80000,
+                target: 0x5AA5_5AA5,
+            }],
+            eeprom: None,
+        }
+    }
+
+    /// Returns the built-in layouts
+    pub fn all() -> Vec<MzrMemoryMap> {
+        vec![
+            MzrMemoryMap::mzr_disi_1m(),
+            MzrMemoryMap::mzr_disi_gen2(),
+            MzrMemoryMap::mzr_na_512k(),
+        ]
+    }
+
+    /// Returns the built-in layout called `name`
+    pub fn by_name(name: &str) -> Option<MzrMemoryMap> {
+        MzrMemoryMap::all().into_iter().find(|map| map.name == name)
+    }
+
+    /// Returns the whole flash
+    pub fn flash(&self) -> Region {
+        Region::new(self.bootloader.start, self.calibration.end)
+    }
+
+    /// Returns the size of the flash in bytes
+    pub fn flash_size(&self) -> usize {
+        self.flash().len()
+    }
+
+    /// Returns the part of the flash that is erased and reprogrammed: all of
+    /// it except the bootloader
+    pub fn programmable(&self) -> Region {
+        Region::new(self.bootloader.end, self.calibration.end)
+    }
+
+    /// Returns the flash erase sectors
+    pub fn sector_regions(&self) -> Vec<Region> {
+        let end = self.flash().end;
+        self.sectors
+            .iter()
+            .enumerate()
+            .map(|(i, &start)| Region::new(start, self.sectors.get(i + 1).copied().unwrap_or(end)))
+            .collect()
+    }
+
+    /// Returns the sector containing `address`
+    pub fn sector(&self, address: u32) -> Option<Region> {
+        self.sector_regions()
+            .into_iter()
+            .find(|sector| sector.contains(address))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mzr_disi_layout() {
+        let map = MzrMemoryMap::default();
+        assert_eq!(map.flash_size(), 0x100000);
+        assert_eq!(map.programmable(), Region::new(0x8000, 0x100000));
+        assert_eq!(map.sector(0x9000), Some(Region::new(0x8000, 0xA000)));
+        assert_eq!(map.sector(0xFFFFF), Some(Region::new(0xE0000, 0x100000)));
+        assert_eq!(map.sector(0x100000), None);
+        // Sectors tile the flash, and the bootloader ends on a boundary
+        let sectors = map.sector_regions();
+        assert_eq!(
+            sectors.iter().map(Region::len).sum::<usize>(),
+            map.flash_size()
+        );
+        assert!(map.sectors.contains(&map.bootloader.end));
+        assert!(map.bootloader.overlaps(0x7FFF, 2));
+        assert!(!map.bootloader.overlaps(0x8000, 0x100));
+        assert_eq!(MzrMemoryMap::by_name("mzr-disi-1m"), Some(map));
+    }
+
+    #[test]
+    fn layouts_are_consistent() {
+        for map in MzrMemoryMap::all() {
+            let sectors = map.sector_regions();
+            assert_eq!(
+                sectors.iter().map(Region::len).sum::<usize>(),
+                map.flash_size(),
+                "{}",
+                map.name
+            );
+            assert!(map.sectors.contains(&map.bootloader.end), "{}", map.name);
+            for checksum in &map.checksums {
+                let region = checksum.region();
+                assert!(
+                    map.programmable()
+                        .contains_range(region.start, region.len()),
+                    "{}",
+                    map.name
+                );
+            }
+        }
+        assert_eq!(MzrMemoryMap::mzr_disi_gen2().flash_size(), 0x180000);
+        assert_eq!(MzrMemoryMap::mzr_na_512k().flash_size(), 0x80000);
+
+        let eeprom = Eeprom {
+            base: 0x0080_0000,
+            size: 0x800,
+            page_size: 0x300,
+        };
+        assert_eq!(eeprom.region(), Region::new(0x0080_0000, 0x0080_0800));
+        assert_eq!(
+            eeprom.pages(),
+            [
+                Region::new(0, 0x300),
+                Region::new(0x300, 0x600),
+                Region::new(0x600, 0x800)
+            ]
+        );
+    }
+}
@@ -0,0 +1,223 @@
+//! Definitions of calibration tables and conversion of their cells. Tables
+//! are described in TOML, with cells stored row by row:
+//!
+//! ```toml
+//! [[table]]
+//! name = "Spark advance"
+//! address = 0x5A000
+//! ram_address = 0xFFFF8000
+//! rows = 16
+//! columns = 16
+//! size = 2
+//! signed = true
+//! scale = 0.1
+//! ```
+//!
+//! Physical values are `raw * scale + offset`.
+
+use std::io;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::memory_map::MzrMemoryMap;
+
+#[derive(Error, Debug)]
+pub enum TableError {
+    #[error("failed to read table definitions: {0}")]
+    Io(#[from] io::Error),
+    #[error("invalid table definitions: {0}")]
+    Parse(#[from] toml::de::Error),
+    #[error("table '{0}' has an invalid cell size")]
+    InvalidSize(String),
+    #[error("table '{0}' is outside the calibration")]
+    OutsideCalibration(String),
+    #[error("the RAM mirror of table '{0}' is outside RAM")]
+    OutsideRam(String),
+    #[error("cell ({0}, {1}) is outside the table")]
+    NoSuchCell(usize, usize),
+    #[error("{0} cannot be stored in the table")]
+    OutOfRange(f64),
+}
+
+/// Size of a value in memory
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Width {
+    Byte,
+    Word,
+    Long,
+}
+
+impl Width {
+    /// Returns the width of values of `bytes` bytes
+    pub fn from_bytes(bytes: usize) -> Option<Width> {
+        match bytes {
+            1 => Some(Width::Byte),
+            2 => Some(Width::Word),
+            4 => Some(Width::Long),
+            _ => None,
+        }
+    }
+
+    /// Returns the size of a value in bytes
+    pub fn bytes(self) -> usize {
+        match self {
+            Width::Byte => 1,
+            Width::Word => 2,
+            Width::Long => 4,
+        }
+    }
+}
+
+fn default_size() -> u8 {
+    2
+}
+
+fn default_scale() -> f64 {
+    1.0
+}
+
+/// Calibration table and the RAM mirror the patched ROM reads it from
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TableDefinition {
+    pub name: String,
+    /// Address of the table in flash
+    pub address: u32,
+    /// Address of the RAM mirror
+    pub ram_address: u32,
+    pub rows: usize,
+    pub columns: usize,
+    /// Size of a cell in bytes: 1, 2 or 4
+    #[serde(default = "default_size")]
+    pub size: u8,
+    /// Whether cells are two's complement
+    #[serde(default)]
+    pub signed: bool,
+    #[serde(default = "default_scale")]
+    pub scale: f64,
+    #[serde(default)]
+    pub offset: f64,
+}
+
+#[derive(Deserialize)]
+struct Definitions {
+    #[serde(default)]
+    table: Vec<TableDefinition>,
+}
+
+/// Parses table definitions from TOML
+pub fn parse_definitions(toml: &str) -> Result<Vec<TableDefinition>, TableError> {
+    let definitions: Definitions = toml::from_str(toml)?;
+    for definition in definitions.table.iter() {
+        if Width::from_bytes(definition.size as usize).is_none() {
+            return Err(TableError::InvalidSize(definition.name.clone()));
+        }
+    }
+    Ok(definitions.table)
+}
+
+impl TableDefinition {
+    /// Returns the size of a cell
+    pub fn width(&self) -> Width {
+        Width::from_bytes(self.size as usize).unwrap_or(Width::Word)
+    }
+
+    /// Returns the size of the table in bytes
+    pub fn len(&self) -> usize {
+        self.rows * self.columns * self.width().bytes()
+    }
+
+    /// Returns true if the table has no cells
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Checks that the table lies in the calibration of `map` and its mirror
+    /// in RAM
+    pub fn validate(&self, map: &MzrMemoryMap) -> Result<(), TableError> {
+        if Width::from_bytes(self.size as usize).is_none() {
+            return Err(TableError::InvalidSize(self.name.clone()));
+        }
+        if !map.calibration.contains_range(self.address, self.len()) {
+            return Err(TableError::OutsideCalibration(self.name.clone()));
+        }
+        if !map.ram.contains_range(self.ram_address, self.len()) {
+            return Err(TableError::OutsideRam(self.name.clone()));
+        }
+        Ok(())
+    }
+
+    /// Returns the cells of the table in the ROM image `rom`, or `None` if
+    /// the image doesn't contain the table
+    pub fn cells<'a>(&self, rom: &'a [u8]) -> Option<&'a [u8]> {
+        let start = self.address as usize;
+        rom.get(start..start.checked_add(self.len())?)
+    }
+
+    /// Returns the physical values of the table's cells in the ROM image
+    /// `rom`, row by row
+    pub fn values(&self, rom: &[u8]) -> Option<Vec<f64>> {
+        let cells = self.cells(rom)?;
+        Some(
+            cells
+                .chunks(self.width().bytes())
+                .map(|cell| self.to_physical(cell))
+                .collect(),
+        )
+    }
+
+    /// Converts a raw cell to its physical value
+    pub fn to_physical(&self, cell: &[u8]) -> f64 {
+        let bytes = cell.len();
+        let raw = cell.iter().fold(0u32, |raw, &b| raw << 8 | b as u32);
+        let raw = if self.signed {
+            // Sign-extend from the cell's width
+            let shift = 32 - bytes as u32 * 8;
+            (((raw << shift) as i32) >> shift) as f64
+        } else {
+            raw as f64
+        };
+        raw * self.scale + self.offset
+    }
+
+    /// Converts a physical value to the nearest raw cell
+    pub fn to_raw(&self, value: f64) -> Result<Vec<u8>, TableError> {
+        let bytes = self.width().bytes();
+        let raw = ((value - self.offset) / self.scale).round();
+        let bits = bytes as i32 * 8;
+        let (min, max) = if self.signed {
+            (-(2f64.powi(bits - 1)), 2f64.powi(bits - 1) - 1.0)
+        } else {
+            (0.0, 2f64.powi(bits) - 1.0)
+        };
+        if !(min..=max).contains(&raw) {
+            return Err(TableError::OutOfRange(value));
+        }
+        Ok(((raw as i64) as u32).to_be_bytes()[4 - bytes..].to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_cells_from_rom() {
+        let definitions = parse_definitions(
+            "[[table]]\nname = \"Boost\"\naddress = 0x4\nram_address = 0xFFFF8000\n\
+             rows = 2\ncolumns = 2\nsize = 1\nscale = 0.5\noffset = -1\n",
+        )
+        .unwrap();
+        let rom: Vec<u8> = (0..0x10).collect();
+        assert_eq!(definitions[0].cells(&rom), Some(&rom[4..8]));
+        assert_eq!(definitions[0].values(&rom), Some(vec![1.0, 1.5, 2.0, 2.5]));
+        assert_eq!(definitions[0].values(&rom[..6]), None);
+        assert_eq!(definitions[0].to_raw(1.5).unwrap(), [5]);
+
+        let invalid = parse_definitions(
+            "[[table]]\nname = \"Odd\"\naddress = 0\nram_address = 0\nrows = 1\ncolumns = 1\n\
+             size = 3\n",
+        );
+        assert!(matches!(invalid, Err(TableError::InvalidSize(_))));
+    }
+}
@@ -0,0 +1,115 @@
+//! Carrying a calibration onto another ROM, e.g. moving a tune onto a newer
+//! stock image of the same ECU. Only the calibration region is copied; the
+//! bootloader and code of the base image are kept and the checksums are
+//! corrected afterwards.
+
+use thiserror::Error;
+
+use crate::calibration;
+use crate::checksum::correct_rom_checksum;
+use crate::memory_map::MzrMemoryMap;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum TransplantError {
+    #[error("base ROM is {actual} bytes, but the {map} layout holds {expected}")]
+    BaseSize {
+        map: String,
+        expected: usize,
+        actual: usize,
+    },
+    #[error("calibration ROM is {actual} bytes, but the {map} layout holds {expected}")]
+    DonorSize {
+        map: String,
+        expected: usize,
+        actual: usize,
+    },
+    #[error("calibration for the {donor} doesn't fit a base ROM for the {base}")]
+    Incompatible { donor: String, base: String },
+    #[error("failed to correct the checksums")]
+    Checksum,
+}
+
+/// Copies the calibration region of `donor` into `base` and corrects the
+/// checksums of `base`. Both must be full ROMs of `map`, and calibrations
+/// identified in both must be for the same vehicle and transmission.
+/// Returns the number of bytes of the calibration that changed.
+pub fn transplant_calibration(
+    base: &mut [u8],
+    donor: &[u8],
+    map: &MzrMemoryMap,
+) -> Result<usize, TransplantError> {
+    let expected = map.flash_size();
+    if base.len() != expected {
+        return Err(TransplantError::BaseSize {
+            map: map.name.clone(),
+            expected,
+            actual: base.len(),
+        });
+    }
+    if donor.len() != expected {
+        return Err(TransplantError::DonorSize {
+            map: map.name.clone(),
+            expected,
+            actual: donor.len(),
+        });
+    }
+    if let (Some(donor), Some(base)) = (calibration::identify(donor), calibration::identify(base)) {
+        if donor.conflicts_with(base) {
+            return Err(TransplantError::Incompatible {
+                donor: donor.to_string(),
+                base: base.to_string(),
+            });
+        }
+    }
+
+    let start = (map.calibration.start - map.flash().start) as usize;
+    let range = start..start + map.calibration.len();
+    let changed = base[range.clone()]
+        .iter()
+        .zip(&donor[range.clone()])
+        .filter(|(a, b)| a != b)
+        .count();
+    base[range.clone()].copy_from_slice(&donor[range]);
+    if !correct_rom_checksum(base, map) {
+        return Err(TransplantError::Checksum);
+    }
+    Ok(changed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checksum::checksum_valid;
+
+    #[test]
+    fn copies_calibration_and_corrects_checksums() {
+        let map = MzrMemoryMap::mzr_disi_gen2();
+        let mut base = vec![0x11; map.flash_size()];
+        let mut donor = vec![0x22; map.flash_size()];
+        base[0xA0100..0xA0109].copy_from_slice(b"L3YH188K1");
+        donor[0xA0200..0xA0209].copy_from_slice(b"L3YH188K1");
+
+        let changed = transplant_calibration(&mut base, &donor, &map).unwrap();
+        assert_eq!(changed, map.calibration.len());
+        assert!(checksum_valid(&base, &map));
+        // Code is kept, calibration is the donor's apart from its checksum
+        // correction word
+        assert!(base[0x10004..0xA0000].iter().all(|&b| b == 0x11));
+        assert_eq!(&base[0xA0004..], &donor[0xA0004..]);
+
+        // A Mazdaspeed6 calibration doesn't fit a Mazdaspeed3
+        donor[0xA0200..0xA0209].copy_from_slice(b"L3K9188M1");
+        assert!(matches!(
+            transplant_calibration(&mut base, &donor, &map),
+            Err(TransplantError::Incompatible { .. })
+        ));
+        assert_eq!(
+            transplant_calibration(&mut base, &donor[..0x1000], &map),
+            Err(TransplantError::DonorSize {
+                map: map.name.clone(),
+                expected: 0x180000,
+                actual: 0x1000
+            })
+        );
+    }
+}
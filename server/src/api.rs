@@ -0,0 +1,187 @@
+//! Routes of the REST API. Requests and responses are JSON.
+//!
+//! | Route | Action |
+//! |---|---|
+//! | `GET /jobs` | Lists jobs |
+//! | `GET /jobs/{id}` | Returns a job's state, progress and result |
+//! | `DELETE /jobs/{id}` | Cancels a job |
+//! | `POST /identify` | Starts reading the VIN and calibration |
+//! | `POST /download` `{"path"}` | Starts downloading the ROM to `path` |
+//! | `POST /flash` `{"path", "allow_unsigned"}` | Starts flashing the image at `path` |
+//! | `POST /log` `{"pids", "output_dir"}` | Starts logging until cancelled |
+//! | `GET /stream` | WebSocket pushing each record of the running log |
+
+use std::path::PathBuf;
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::jobs::{JobContext, Jobs};
+use mzr::record;
+
+use crate::ops::{self, Settings};
+use crate::stream::Broadcast;
+
+/// Status code and JSON body of a response
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub body: Value,
+}
+
+impl Response {
+    fn ok<T: serde::Serialize>(body: T) -> Response {
+        Response {
+            status: 200,
+            body: json!(body),
+        }
+    }
+
+    fn error<D: std::fmt::Display>(status: u16, error: D) -> Response {
+        Response {
+            status,
+            body: json!({ "error": error.to_string() }),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct PathRequest {
+    path: PathBuf,
+}
+
+#[derive(Deserialize)]
+struct FlashRequest {
+    path: PathBuf,
+    /// Flashes files that aren't signed by a trusted key
+    #[serde(default)]
+    allow_unsigned: bool,
+}
+
+#[derive(Deserialize, Default)]
+struct LogRequest {
+    pids: Option<Vec<u16>>,
+    output_dir: Option<PathBuf>,
+}
+
+/// Handles requests by starting and tracking jobs
+#[derive(Clone)]
+pub struct Api {
+    pub jobs: Jobs,
+    pub settings: Settings,
+    /// Records of log jobs, streamed to WebSocket clients
+    pub records: Broadcast,
+}
+
+impl Api {
+    pub fn new(settings: Settings) -> Api {
+        Api {
+            jobs: Jobs::new(),
+            settings,
+            records: Broadcast::new(),
+        }
+    }
+
+    /// Returns the response to a request for `url` with `body`
+    pub fn handle(&self, method: &str, url: &str, body: &str) -> Response {
+        let path = url.split('?').next().unwrap_or("");
+        let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+        match (method, segments.as_slice()) {
+            ("GET", ["jobs"]) => Response::ok(self.jobs.list()),
+            ("GET", ["jobs", id]) => self.job(id, |id| self.jobs.status(id)),
+            ("DELETE", ["jobs", id]) => self.job(id, |id| self.jobs.cancel(id)),
+            ("POST", ["identify"]) => {
+                let settings = self.settings.clone();
+                self.start("identify", move |_| ops::identify(&settings))
+            }
+            ("POST", ["download"]) => match serde_json::from_str::<PathRequest>(body) {
+                Ok(request) => {
+                    let settings = self.settings.clone();
+                    self.start("download", move |context| {
+                        ops::download(&settings, context, &request.path)
+                    })
+                }
+                Err(err) => Response::error(400, err),
+            },
+            ("POST", ["flash"]) => match serde_json::from_str::<FlashRequest>(body) {
+                Ok(request) => {
+                    let settings = self.settings.clone();
+                    self.start("flash", move |context| {
+                        ops::flash(&settings, context, &request.path, request.allow_unsigned)
+                    })
+                }
+                Err(err) => Response::error(400, err),
+            },
+            ("POST", ["log"]) => {
+                let request = if body.trim().is_empty() {
+                    Ok(LogRequest::default())
+                } else {
+                    serde_json::from_str::<LogRequest>(body)
+                };
+                match request {
+                    Ok(request) => {
+                        let settings = self.settings.clone();
+                        let channels = match request.pids {
+                            Some(pids) => record::channels_for(&pids, &settings.definitions),
+                            None => settings.channels.clone(),
+                        };
+                        let output_dir = request
+                            .output_dir
+                            .unwrap_or_else(|| settings.output_dir.clone());
+                        let records = self.records.clone();
+                        self.start("log", move |context| {
+                            ops::log(&settings, context, &channels, &output_dir, |record| {
+                                records.send(record)
+                            })
+                        })
+                    }
+                    Err(err) => Response::error(400, err),
+                }
+            }
+            _ => Response::error(404, format!("no route for {} {}", method, path)),
+        }
+    }
+
+    fn job<F: FnOnce(u64) -> Option<crate::jobs::JobStatus>>(&self, id: &str, f: F) -> Response {
+        match id.parse().ok().and_then(f) {
+            Some(status) => Response::ok(status),
+            None => Response::error(404, format!("no job {}", id)),
+        }
+    }
+
+    /// Starts a job, responding with its status
+    fn start<F>(&self, kind: &str, run: F) -> Response
+    where
+        F: FnOnce(&mut JobContext) -> Result<Value, String> + Send + 'static,
+    {
+        match self.jobs.start(kind, run) {
+            Ok(id) => Response {
+                status: 202,
+                body: json!(self.jobs.status(id)),
+            },
+            Err(err) => Response::error(409, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn routes_requests() {
+        let api = Api::new(Settings::default());
+        assert_eq!(api.handle("GET", "/jobs", ""), Response::ok(json!([])));
+        assert_eq!(api.handle("GET", "/jobs/1", "").status, 404);
+        assert_eq!(api.handle("DELETE", "/jobs/x", "").status, 404);
+        assert_eq!(api.handle("GET", "/download", "").status, 404);
+        let response = api.handle("POST", "/download", "{}");
+        assert_eq!(response.status, 400);
+        assert!(response.body["error"].as_str().unwrap().contains("path"));
+        assert_eq!(
+            api.handle("POST", "/log", "{\"pids\": [\"a\"]}").status,
+            400
+        );
+        assert!(api.jobs.list().is_empty());
+    }
+}
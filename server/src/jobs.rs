@@ -0,0 +1,236 @@
+//! Long-running operations run on background threads and polled by clients
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+
+use serde::Serialize;
+use serde_json::Value;
+use thiserror::Error;
+
+use mzr::cancel::CancelToken;
+use mzr::progress::{Progress, ProgressObserver};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+/// Snapshot of a job as returned to clients
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct JobStatus {
+    pub id: u64,
+    /// Operation the job runs, e.g. `download`
+    pub kind: String,
+    pub state: JobState,
+    /// Progress of transfers
+    pub progress: Option<Progress>,
+    /// Result of a completed job, or the latest values of a running log
+    pub result: Option<Value>,
+    /// Why the job failed
+    pub error: Option<String>,
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum JobError {
+    /// Jobs share the adapter, so only one runs at a time
+    #[error("job {0} is still running")]
+    Busy(u64),
+}
+
+struct Job {
+    status: JobStatus,
+    cancel: CancelToken,
+}
+
+#[derive(Default)]
+struct Registry {
+    next_id: u64,
+    jobs: BTreeMap<u64, Job>,
+}
+
+/// Jobs started since the server started. Clones share the same jobs.
+#[derive(Clone, Default)]
+pub struct Jobs {
+    registry: Arc<Mutex<Registry>>,
+}
+
+/// Handed to a running job to report progress and check for cancellation
+pub struct JobContext {
+    id: u64,
+    jobs: Jobs,
+    pub cancel: CancelToken,
+}
+
+impl JobContext {
+    /// Publishes intermediate results of a job that runs until cancelled
+    pub fn update(&self, result: Value) {
+        self.jobs
+            .modify(self.id, |status| status.result = Some(result));
+    }
+}
+
+impl ProgressObserver for JobContext {
+    fn on_progress(&mut self, progress: &Progress) {
+        let progress = *progress;
+        self.jobs
+            .modify(self.id, |status| status.progress = Some(progress));
+    }
+}
+
+impl Jobs {
+    pub fn new() -> Jobs {
+        Jobs::default()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Registry> {
+        // Jobs only update plain data under the lock, so it can't be left
+        // inconsistent by a panic
+        self.registry
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn modify<F: FnOnce(&mut JobStatus)>(&self, id: u64, f: F) {
+        if let Some(job) = self.lock().jobs.get_mut(&id) {
+            f(&mut job.status);
+        }
+    }
+
+    /// Runs `run` on a new thread, unless another job is running. Returns
+    /// the ID of the job.
+    pub fn start<F>(&self, kind: &str, run: F) -> Result<u64, JobError>
+    where
+        F: FnOnce(&mut JobContext) -> Result<Value, String> + Send + 'static,
+    {
+        let mut registry = self.lock();
+        if let Some(running) = registry
+            .jobs
+            .values()
+            .find(|job| job.status.state == JobState::Running)
+        {
+            return Err(JobError::Busy(running.status.id));
+        }
+        registry.next_id += 1;
+        let id = registry.next_id;
+        let cancel = CancelToken::new();
+        registry.jobs.insert(
+            id,
+            Job {
+                status: JobStatus {
+                    id,
+                    kind: kind.to_string(),
+                    state: JobState::Running,
+                    progress: None,
+                    result: None,
+                    error: None,
+                },
+                cancel: cancel.clone(),
+            },
+        );
+
+        let mut context = JobContext {
+            id,
+            jobs: self.clone(),
+            cancel,
+        };
+        thread::spawn(move || {
+            let result = run(&mut context);
+            let cancelled = context.cancel.is_cancelled();
+            context.jobs.modify(id, |status| match result {
+                Ok(result) => {
+                    status.state = JobState::Completed;
+                    status.result = Some(result);
+                }
+                Err(_) if cancelled => status.state = JobState::Cancelled,
+                Err(error) => {
+                    status.state = JobState::Failed;
+                    status.error = Some(error);
+                }
+            });
+        });
+        Ok(id)
+    }
+
+    pub fn status(&self, id: u64) -> Option<JobStatus> {
+        self.lock().jobs.get(&id).map(|job| job.status.clone())
+    }
+
+    /// Returns every job, oldest first
+    pub fn list(&self) -> Vec<JobStatus> {
+        self.lock()
+            .jobs
+            .values()
+            .map(|job| job.status.clone())
+            .collect()
+    }
+
+    /// Requests that a job stops at the next safe point. Returns its
+    /// status, or `None` if there is no such job.
+    pub fn cancel(&self, id: u64) -> Option<JobStatus> {
+        let registry = self.lock();
+        let job = registry.jobs.get(&id)?;
+        job.cancel.cancel();
+        Some(job.status.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::time::Duration;
+
+    fn wait(jobs: &Jobs, id: u64) -> JobStatus {
+        loop {
+            let status = jobs.status(id).unwrap();
+            if status.state != JobState::Running {
+                return status;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn tracks_progress_and_cancellation() {
+        let jobs = Jobs::new();
+        let id = jobs
+            .start("download", |context| {
+                context.on_progress(&Progress {
+                    position: 512,
+                    total: 1024,
+                    rate: 0.0,
+                    average_rate: 0.0,
+                    eta: None,
+                });
+                Ok(json!({ "size": 1024 }))
+            })
+            .unwrap();
+        let status = wait(&jobs, id);
+        assert_eq!(status.state, JobState::Completed);
+        assert_eq!(status.progress.unwrap().position, 512);
+        assert_eq!(status.result, Some(json!({ "size": 1024 })));
+
+        let log = jobs
+            .start("wait", |context| {
+                while !context.cancel.is_cancelled() {
+                    context.update(json!({ "rows": 1 }));
+                    thread::sleep(Duration::from_millis(1));
+                }
+                Err("cancelled".to_string())
+            })
+            .unwrap();
+        assert_eq!(
+            jobs.start("identify", |_| Ok(Value::Null)),
+            Err(JobError::Busy(log))
+        );
+        jobs.cancel(log).unwrap();
+        assert_eq!(wait(&jobs, log).state, JobState::Cancelled);
+        assert!(jobs.cancel(42).is_none());
+        assert_eq!(jobs.list().len(), 2);
+    }
+}
@@ -0,0 +1,66 @@
+//! Local REST server exposing the ECU operations to web and mobile
+//! frontends. Operations run as jobs on background threads; clients start
+//! them with a POST and poll `/jobs/{id}` for progress and results. See
+//! [`api`] for the routes.
+
+pub mod api;
+pub mod jobs;
+pub mod ops;
+pub mod stream;
+
+use std::io;
+
+use tiny_http::{Header, Server};
+
+use crate::api::{Api, Response};
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).unwrap()
+}
+
+/// Serves `api` on `addr`, e.g. `127.0.0.1:8080`, until the process exits.
+/// Responses allow any origin, so browser frontends served elsewhere can
+/// call the API.
+pub fn serve(addr: &str, api: Api) -> io::Result<()> {
+    let server = Server::http(addr).map_err(io::Error::other)?;
+    for mut request in server.incoming_requests() {
+        let method = request.method().as_str().to_string();
+        let url = request.url().to_string();
+        if method == "GET" && url.split('?').next() == Some("/stream") {
+            stream::accept(request, &api.records);
+            continue;
+        }
+        let response = if method == "OPTIONS" {
+            // CORS preflight
+            Response {
+                status: 204,
+                body: serde_json::Value::Null,
+            }
+        } else {
+            let mut body = String::new();
+            match request.as_reader().read_to_string(&mut body) {
+                Ok(_) => api.handle(&method, &url, &body),
+                Err(err) => Response {
+                    status: 400,
+                    body: serde_json::json!({ "error": err.to_string() }),
+                },
+            }
+        };
+        let body = match response.body {
+            serde_json::Value::Null => String::new(),
+            body => body.to_string(),
+        };
+        let reply = tiny_http::Response::from_string(body)
+            .with_status_code(response.status)
+            .with_header(header("Content-Type", "application/json"))
+            .with_header(header("Access-Control-Allow-Origin", "*"))
+            .with_header(header(
+                "Access-Control-Allow-Methods",
+                "GET, POST, DELETE, OPTIONS",
+            ))
+            .with_header(header("Access-Control-Allow-Headers", "Content-Type"));
+        // The client may have disconnected
+        let _ = request.respond(reply);
+    }
+    Ok(())
+}
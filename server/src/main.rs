@@ -0,0 +1,55 @@
+//! Serves the ECU operations over a local REST API
+
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use clap::clap_app;
+
+use mzr::config::Config;
+use mzr::record::LogChannel;
+use mzr_server::api::Api;
+use mzr_server::ops::Settings;
+
+pub fn main() -> ExitCode {
+    let matches = clap_app!(myapp =>
+        (version: "1.0")
+        (author: "Jacob Manning <jjacob.manning@gmail.com>")
+        (about: "Serves MZR-DISI ECU operations over a local REST API")
+        (@arg device: -d --device alias("passthru") +takes_value "J2534 device to use when connecting to the ECU (name or index)")
+        (@arg library: --library +takes_value "J2534 library (DLL) to load instead of the installed drivers")
+        (@arg model: -m --model +takes_value "Vehicle model (default: detected from the VIN)")
+        (@arg listen: -l --listen +takes_value default_value("127.0.0.1:8080") "Address to listen on")
+    )
+    .get_matches();
+
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+    let settings = Settings {
+        device: matches.value_of("device").map(String::from),
+        library: matches.value_of("library").map(PathBuf::from),
+        model: matches.value_of("model").map(String::from),
+        channels: match config.channels() {
+            channels if channels.is_empty() => {
+                vec![LogChannel::raw(0), LogChannel::raw(1), LogChannel::raw(2)]
+            }
+            channels => channels,
+        },
+        definitions: config.log_channels.clone(),
+        output_dir: config.output_dir.unwrap_or_else(|| PathBuf::from(".")),
+    };
+
+    let addr = matches.value_of("listen").unwrap();
+    println!("Listening on http://{}", addr);
+    match mzr_server::serve(addr, Api::new(settings)) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Failed to listen on {}: {}", addr, err);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,264 @@
+//! Operations run by jobs. Each job opens the adapter, runs and closes it,
+//! so the J2534 handles never leave the job's thread.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+
+use mzr::calibration;
+use mzr::config::Config;
+use mzr::container::RomContainer;
+use mzr::daemon::{csv_header, RotatingLog, DEFAULT_MAX_FILE_AGE, DEFAULT_MAX_FILE_SIZE};
+use mzr::hash;
+use mzr::history::{FlashRecord, FlashResult, History};
+use mzr::image::{Image, ImageFormat};
+use mzr::metadata::RomMetadata;
+use mzr::passthru::{self, PassThruChannel};
+use mzr::profile::EcuProfile;
+use mzr::record::{LogChannel, Record};
+use mzr::signing;
+use mzr::timeout::TimeoutProfile;
+use mzr::vin::Vin;
+use mzr::voltage::PassThruVoltage;
+use mzr::{MzrBus, MzrError};
+use obd::Uds;
+
+use crate::jobs::JobContext;
+
+/// Adapter, vehicle and log defaults used by every job, from the command
+/// line or the config
+#[derive(Debug, Clone, Default)]
+pub struct Settings {
+    /// J2534 device name or index
+    pub device: Option<String>,
+    pub library: Option<PathBuf>,
+    pub model: Option<String>,
+    /// Channels logged if a request names no PIDs
+    pub channels: Vec<LogChannel>,
+    /// Scaling of the PIDs requests name
+    pub definitions: Vec<LogChannel>,
+    /// Directory of log files if a request names none
+    pub output_dir: PathBuf,
+}
+
+/// Connects to the ECU and runs `f` with the device, channel and profile
+fn with_ecu<T, F>(settings: &Settings, f: F) -> Result<T, String>
+where
+    F: FnOnce(&j2534::Device, &mut PassThruChannel, &EcuProfile) -> Result<T, String>,
+{
+    let config = Config::load().map_err(|err| err.to_string())?;
+    let selected = EcuProfile::select(None, settings.model.as_deref(), &config)
+        .map_err(|err| err.to_string())?;
+    let (request_id, response_id) = match selected {
+        Some(ref profile) => (profile.request_id, profile.response_id),
+        None => (config.request_id, config.response_id),
+    };
+
+    let library = settings.library.as_deref().or(config.library.as_deref());
+    let selector = settings.device.as_deref().or(config.device.as_deref());
+    let driver = passthru::select_driver(library, selector).map_err(|err| err.to_string())?;
+    let interface = j2534::Interface::new(&driver.path)
+        .map_err(|err| format!("failed to load {}: {}", driver.path, err))?;
+    let device = passthru::traced("PassThruOpen", format_args!(""), || interface.open_any())
+        .map_err(|err| format!("failed to open the adapter: {}", err))?;
+    let mut channel = PassThruChannel::new(&device, 500000, TimeoutProfile::default().request)
+        .map_err(|err| err.to_string())?;
+    channel
+        .set_filter(request_id, response_id)
+        .map_err(|err| err.to_string())?;
+
+    let profile = match selected {
+        Some(profile) => profile,
+        None => EcuProfile::detect(channel.query_vin(request_id).ok().as_deref(), &config),
+    };
+    f(&device, &mut channel, &profile)
+}
+
+/// Reads the VIN and calibration of the ECU
+pub fn identify(settings: &Settings) -> Result<Value, String> {
+    with_ecu(settings, |_, channel, profile| {
+        let vin = channel.query_vin(profile.request_id).ok();
+        let vehicle = vin
+            .as_deref()
+            .and_then(|vin| Vin::parse(vin).ok())
+            .map(|vin| vin.decode());
+        let calibration_id = channel.calibration_id(profile.request_id).ok();
+        let calibration = calibration_id.as_deref().and_then(calibration::lookup);
+        Ok(json!({
+            "vin": vin,
+            "vehicle": vehicle,
+            "calibration_id": calibration_id,
+            "calibration": calibration,
+            "profile": profile.name,
+        }))
+    })
+}
+
+/// Downloads the ROM to `path`
+pub fn download(
+    settings: &Settings,
+    context: &mut JobContext,
+    path: &Path,
+) -> Result<Value, String> {
+    with_ecu(settings, |_, channel, profile| {
+        let mut downloader = profile.downloader().build(channel);
+        downloader.set_cancel_token(context.cancel.clone());
+        downloader.run(context).map_err(|err| err.to_string())?;
+        let data = downloader.take_data();
+        fs::write(path, &data)
+            .map_err(|err| format!("failed to write {}: {}", path.display(), err))?;
+        Ok(json!({ "path": path, "size": data.len() }))
+    })
+}
+
+/// Flashes the image at `path` and verifies it, after checking it is
+/// allowed on this vehicle and backing up the current ROM. Unsigned files
+/// are refused once keys are trusted unless `allow_unsigned` is set.
+pub fn flash(
+    settings: &Settings,
+    context: &mut JobContext,
+    path: &Path,
+    allow_unsigned: bool,
+) -> Result<Value, String> {
+    with_ecu(settings, |device, channel, profile| {
+        if !profile.flash_supported {
+            return Err(format!(
+                "flashing is not supported for the {} profile",
+                profile.name
+            ));
+        }
+        let config = Config::load().map_err(|err| err.to_string())?;
+        let data =
+            fs::read(path).map_err(|err| format!("failed to read {}: {}", path.display(), err))?;
+        let container = if RomContainer::is_container(&data) {
+            Some(RomContainer::read(&data).map_err(|err| err.to_string())?)
+        } else {
+            None
+        };
+        let vin = channel.query_vin(profile.request_id).ok();
+        let calibration_id = channel.calibration_id(profile.request_id).ok();
+        signing::check_package(
+            container.as_ref(),
+            &config.trusted_keys,
+            vin.as_deref(),
+            allow_unsigned,
+        )
+        .map_err(|err| err.to_string())?;
+        let image = Image::parse(&data, ImageFormat::from_path(path), &profile.memory_map)
+            .map_err(|err| err.to_string())?;
+        let size = image.data.len();
+
+        // Keep a copy of the current ROM so the previous calibration can be
+        // restored
+        let mut downloader = profile.downloader().build(&mut *channel);
+        downloader.set_cancel_token(context.cancel.clone());
+        downloader
+            .run(context)
+            .map_err(|err| format!("backup failed: {}", err))?;
+        let backup = downloader.take_data();
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let backup_path = settings
+            .output_dir
+            .join(format!("backup-{}.bin", timestamp));
+        fs::write(&backup_path, &backup)
+            .map_err(|err| format!("failed to write backup: {}", err))?;
+        let metadata = RomMetadata {
+            calibration_id: calibration_id.clone(),
+            request_id: profile.request_id,
+            response_id: profile.response_id,
+            ..RomMetadata::new(vin.as_deref().unwrap_or_default(), &backup)
+        };
+        metadata
+            .save(&backup_path)
+            .map_err(|err| format!("failed to write backup metadata: {}", err))?;
+
+        let mut programmer =
+            profile
+                .programmer()
+                .verify(true)
+                .build(channel, image.offset, image.data);
+        programmer.set_voltage_monitor(PassThruVoltage::new(device), config.min_voltage);
+        programmer.set_cancel_token(context.cancel.clone());
+        let result = programmer.run(context);
+
+        // Log the attempt, whatever the outcome
+        let record = FlashRecord {
+            vin,
+            calibration_id,
+            error: match result {
+                Ok(()) | Err(MzrError::Cancelled) => None,
+                Err(ref err) => Some(err.to_string()),
+            },
+            ..FlashRecord::new(
+                path,
+                &hash::sha256_hex(&data),
+                match result {
+                    Ok(()) => FlashResult::Completed,
+                    Err(MzrError::Cancelled) => FlashResult::Cancelled,
+                    Err(_) => FlashResult::Failed,
+                },
+            )
+        };
+        let history_error = History::append(&record).err().map(|err| err.to_string());
+        result.map_err(|err| err.to_string())?;
+        Ok(json!({
+            "path": path,
+            "size": size,
+            "backup": backup_path,
+            "history_error": history_error,
+        }))
+    })
+}
+
+/// Logs `channels` to CSV files in `output_dir` until the job is
+/// cancelled, passing each record to `on_record` and publishing the latest
+/// as the job's result
+pub fn log<F: FnMut(&Record)>(
+    settings: &Settings,
+    context: &mut JobContext,
+    channels: &[LogChannel],
+    output_dir: &Path,
+    mut on_record: F,
+) -> Result<Value, String> {
+    with_ecu(settings, |_, channel, profile| {
+        let pids: Vec<u16> = channels.iter().map(|channel| channel.pid).collect();
+        let mut log = RotatingLog::new(
+            output_dir,
+            csv_header(&pids),
+            DEFAULT_MAX_FILE_SIZE,
+            DEFAULT_MAX_FILE_AGE,
+        );
+        let mut file = None;
+        let mut rows = 0u64;
+        while !context.cancel.is_cancelled() {
+            let mut record = Record::new();
+            let mut row = record.time.to_string();
+            for log_channel in channels {
+                let pid = log_channel.pid;
+                let data = channel
+                    .read_identifier(profile.request_id, pid)
+                    .map_err(|err| format!("failed to read PID {:04X}: {}", pid, err))?;
+                row.push(',');
+                for byte in &data {
+                    row.push_str(&format!("{:02X}", byte));
+                }
+                if let Some(value) = log_channel.decode(&data) {
+                    record.values.insert(log_channel.name.clone(), value);
+                }
+            }
+            if let Some(path) = log.write_line(&row).map_err(|err| err.to_string())? {
+                file = Some(path);
+            }
+            rows += 1;
+            on_record(&record);
+            context.update(json!({ "file": file, "rows": rows, "record": record }));
+        }
+        log.close().map_err(|err| err.to_string())?;
+        Ok(json!({ "file": file, "rows": rows }))
+    })
+}
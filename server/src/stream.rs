@@ -0,0 +1,110 @@
+//! Live log records pushed to WebSocket clients of `/stream`, e.g. browser
+//! dashboards showing boost, AFR and knock while driving
+
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use tiny_http::{Request, Response};
+use tungstenite::handshake::derive_accept_key;
+use tungstenite::protocol::Role;
+use tungstenite::{Message, WebSocket};
+
+use mzr::record::Record;
+
+use crate::header;
+
+/// Records buffered for each client. Clients that fall further behind miss
+/// records rather than delaying the logger.
+const CLIENT_BUFFER: usize = 64;
+
+/// Sends records to every subscribed client. Clones share the same clients.
+#[derive(Clone, Default)]
+pub struct Broadcast {
+    clients: Arc<Mutex<Vec<SyncSender<Record>>>>,
+}
+
+impl Broadcast {
+    pub fn new() -> Broadcast {
+        Broadcast::default()
+    }
+
+    /// Returns a receiver of the records sent from now on
+    pub fn subscribe(&self) -> Receiver<Record> {
+        let (sender, receiver) = sync_channel(CLIENT_BUFFER);
+        self.clients.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Sends `record` to every client, forgetting disconnected ones
+    pub fn send(&self, record: &Record) {
+        self.clients
+            .lock()
+            .unwrap()
+            .retain(|client| match client.try_send(record.clone()) {
+                Ok(()) | Err(TrySendError::Full(_)) => true,
+                Err(TrySendError::Disconnected(_)) => false,
+            });
+    }
+}
+
+/// Completes the WebSocket handshake of `request` and streams records from
+/// `broadcast` to it as JSON text messages on a new thread
+pub fn accept(request: Request, broadcast: &Broadcast) {
+    let key = request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv("Sec-WebSocket-Key"))
+        .map(|header| derive_accept_key(header.value.as_bytes()));
+    let accept = match key {
+        Some(accept) => accept,
+        None => {
+            let _ = request.respond(
+                Response::from_string("expected a WebSocket upgrade").with_status_code(400),
+            );
+            return;
+        }
+    };
+    let records = broadcast.subscribe();
+    let response = Response::empty(101)
+        .with_header(header("Upgrade", "websocket"))
+        .with_header(header("Connection", "Upgrade"))
+        .with_header(header("Sec-WebSocket-Accept", &accept));
+    let stream = request.upgrade("websocket", response);
+    thread::spawn(move || {
+        let mut socket = WebSocket::from_raw_socket(stream, Role::Server, None);
+        for record in records {
+            let text = serde_json::to_string(&record).unwrap();
+            // The client disconnected
+            if socket.send(Message::Text(text)).is_err() {
+                break;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn broadcasts_to_connected_clients() {
+        let broadcast = Broadcast::new();
+        let first = broadcast.subscribe();
+        let second = broadcast.subscribe();
+        let mut record = Record::new();
+        record.values.insert("rpm".to_string(), 850.0);
+        broadcast.send(&record);
+        assert_eq!(first.recv().unwrap(), record);
+        assert_eq!(second.recv().unwrap(), record);
+
+        drop(second);
+        broadcast.send(&record);
+        assert_eq!(broadcast.clients.lock().unwrap().len(), 1);
+        // Slow clients miss records instead of blocking
+        for _ in 0..CLIENT_BUFFER * 2 {
+            broadcast.send(&record);
+        }
+        assert_eq!(first.try_iter().count(), CLIENT_BUFFER);
+    }
+}
@@ -0,0 +1,646 @@
+//! Software emulation of an MZR-DISI ECU. The simulator answers UDS requests
+//! the same way the ECU does, so the download and flash flows can be run
+//! without a vehicle.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use mzr::checksum::compute_checksum;
+use mzr::memory_map::ChecksumRegion;
+use mzr::timeout::SetTimeout;
+use obd::IsoTp;
+
+/// Arbitration ID the simulated ECU listens on
+pub const REQUEST_ID: u32 = 0x7e0;
+/// Arbitration ID the simulated ECU responds with
+pub const RESPONSE_ID: u32 = 0x7e8;
+
+const UDS_RES_NEGATIVE: u8 = 0x7F;
+
+/* Negative response codes */
+const NRC_SERVICE_NOT_SUPPORTED: u8 = 0x11;
+const NRC_INCORRECT_LENGTH: u8 = 0x13;
+const NRC_CONDITIONS_NOT_CORRECT: u8 = 0x22;
+const NRC_SEQUENCE_ERROR: u8 = 0x24;
+const NRC_OUT_OF_RANGE: u8 = 0x31;
+const NRC_SECURITY_DENIED: u8 = 0x33;
+const NRC_INVALID_KEY: u8 = 0x35;
+
+const SESSION_DEFAULT: u8 = 0x81;
+const SESSION_PROGRAMMING: u8 = 0x85;
+
+/// Default start of the region erased by the erase routine. Everything
+/// below this offset is the bootloader.
+const ERASE_START: usize = 0x8000;
+/// Default parameters of the erase routine
+const ERASE_ROUTINE: [u8; 3] = [0x00, 0xB2, 0x00];
+
+/// RAM the kernel can be uploaded to
+const RAM_START: usize = 0xFFFF_0000;
+const RAM_SIZE: usize = 0xC000;
+
+/// Version reported by the simulated RAM kernel
+pub const KERNEL_VERSION: u8 = 1;
+
+/// Default VIN reported by the simulator
+pub const DEFAULT_VIN: &str = "JM1BK34M071234567";
+/// Default calibration ID reported by the simulator
+pub const DEFAULT_CALIBRATION_ID: &str = "L3K9188K1D";
+/// ECU name reported by the simulator
+pub const ECU_NAME: &str = "ECM-EngineControl";
+
+/// Simulated ECU state
+pub struct Ecu {
+    memory: Vec<u8>,
+    ram: Vec<u8>,
+    // Start address of the RAM download in progress, and of the image
+    // completely uploaded to RAM
+    ram_upload: Option<usize>,
+    ram_image: Option<usize>,
+    kernel: bool,
+    vin: String,
+    calibration_id: String,
+    session: u8,
+    seed: Option<[u8; 3]>,
+    seed_counter: u32,
+    unlocked: bool,
+    erased: bool,
+    erase_start: usize,
+    erase_routine: Vec<u8>,
+    // RoutineControl identifier of the checksum routine and the regions it
+    // checks
+    checksum_routine: Option<(u16, Vec<ChecksumRegion>)>,
+    // Download window (current address, end address)
+    download: Option<(usize, usize)>,
+    // Number of requests after which the session lapses
+    session_lifetime: Option<usize>,
+    session_requests: usize,
+    rpm: u16,
+    // Number of requests ignored before the ECU powers up
+    key_on_delay: usize,
+    bootloader: bool,
+    // Time the erase routine takes, compared against the tester's timeout
+    erase_duration: Duration,
+    timeout: Duration,
+    // Largest read response the link can deliver
+    max_read_size: Option<usize>,
+    // TransferData requests whose responses are lost, counted from 1
+    dropped_transfers: Vec<usize>,
+    transfers: usize,
+    // Memory reads whose responses are corrupted, counted from 1
+    corrupted_reads: Vec<usize>,
+    reads: usize,
+    responses: VecDeque<Vec<u8>>,
+}
+
+impl Ecu {
+    /// Creates a simulated ECU backed by `rom`
+    pub fn new(rom: Vec<u8>) -> Ecu {
+        Ecu {
+            memory: rom,
+            ram: vec![0; RAM_SIZE],
+            ram_upload: None,
+            ram_image: None,
+            kernel: false,
+            vin: DEFAULT_VIN.to_string(),
+            calibration_id: DEFAULT_CALIBRATION_ID.to_string(),
+            session: SESSION_DEFAULT,
+            seed: None,
+            seed_counter: 0x1234,
+            unlocked: false,
+            erased: false,
+            erase_start: ERASE_START,
+            erase_routine: ERASE_ROUTINE.to_vec(),
+            checksum_routine: None,
+            download: None,
+            session_lifetime: None,
+            session_requests: 0,
+            rpm: 0,
+            key_on_delay: 0,
+            bootloader: false,
+            erase_duration: Duration::from_secs(0),
+            timeout: Duration::from_secs(1),
+            max_read_size: None,
+            dropped_transfers: Vec::new(),
+            transfers: 0,
+            corrupted_reads: Vec::new(),
+            reads: 0,
+            responses: VecDeque::new(),
+        }
+    }
+
+    /// Creates a simulated ECU backed by a ROM file
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Ecu> {
+        Ok(Ecu::new(fs::read(path)?))
+    }
+
+    /// Sets the size of the bootloader, which is never erased or written
+    pub fn set_bootloader_size(&mut self, size: usize) {
+        self.erase_start = size;
+    }
+
+    /// Sets the parameters the erase routine expects
+    pub fn set_erase_routine(&mut self, parameters: &[u8]) {
+        self.erase_routine = parameters.to_vec();
+    }
+
+    /// Enables a checksum routine with identifier `routine`, which checks
+    /// the sum of any of `regions` once programming has ended
+    pub fn set_checksum_routine(&mut self, routine: u16, regions: Vec<ChecksumRegion>) {
+        self.checksum_routine = Some((routine, regions));
+    }
+
+    /// Sets the VIN reported by the ECU
+    pub fn set_vin(&mut self, vin: &str) {
+        self.vin = vin.to_string();
+    }
+
+    /// Sets the calibration ID reported by the ECU
+    pub fn set_calibration_id(&mut self, calibration_id: &str) {
+        self.calibration_id = calibration_id.to_string();
+    }
+
+    /// Makes non-default sessions lapse after `requests` requests, emulating
+    /// the S3 session timeout of the real ECU
+    pub fn set_session_lifetime(&mut self, requests: Option<usize>) {
+        self.session_lifetime = requests;
+    }
+
+    /// Sets the engine speed reported by the ECU
+    pub fn set_rpm(&mut self, rpm: u16) {
+        self.rpm = rpm;
+    }
+
+    /// Ignores the first `requests` requests, emulating an ECU that stays
+    /// powered off until the ignition is turned on
+    pub fn set_key_on_delay(&mut self, requests: usize) {
+        self.key_on_delay = requests;
+    }
+
+    /// Emulates an ECU left in its bootloader by a failed flash. Only the
+    /// services needed to reprogram it are answered.
+    pub fn set_bootloader_mode(&mut self, bootloader: bool) {
+        self.bootloader = bootloader;
+    }
+
+    /// Sets how long the erase routine takes. If the tester's timeout is
+    /// shorter, the flash is erased but the response never arrives.
+    pub fn set_erase_duration(&mut self, duration: Duration) {
+        self.erase_duration = duration;
+    }
+
+    /// Drops read responses longer than `size` bytes, emulating an adapter
+    /// that overruns on large transfers
+    pub fn set_max_read_size(&mut self, size: Option<usize>) {
+        self.max_read_size = size;
+    }
+
+    /// Handles the TransferData requests with these indices, counted from 1
+    /// from now on, but drops their responses, emulating an adapter that
+    /// loses frames
+    pub fn set_dropped_transfers(&mut self, transfers: &[usize]) {
+        self.dropped_transfers = transfers.to_vec();
+        self.transfers = 0;
+    }
+
+    /// Flips a bit in the last byte of the responses to the memory reads
+    /// with these indices, counted from 1 from now on, emulating an adapter
+    /// that silently corrupts data
+    pub fn set_corrupted_reads(&mut self, reads: &[usize]) {
+        self.corrupted_reads = reads.to_vec();
+        self.reads = 0;
+    }
+
+    /// Returns the contents of the virtual flash
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    /// Returns true while an uploaded RAM kernel is running
+    pub fn kernel_running(&self) -> bool {
+        self.kernel
+    }
+
+    /// Returns the active diagnostic session
+    pub fn session(&self) -> u8 {
+        self.session
+    }
+
+    /// Returns true if security access has been granted
+    pub fn unlocked(&self) -> bool {
+        self.unlocked
+    }
+
+    /// Returns true if a download was requested and all of its data has
+    /// been transferred
+    pub fn programming_complete(&self) -> bool {
+        matches!(self.download, Some((position, end)) if position == end)
+    }
+
+    /// Handles a UDS request and returns the response
+    pub fn handle(&mut self, request: &[u8]) -> Vec<u8> {
+        let (&sid, data) = match request.split_first() {
+            Some(r) => r,
+            None => return vec![UDS_RES_NEGATIVE, 0, NRC_INCORRECT_LENGTH],
+        };
+
+        if self.session != SESSION_DEFAULT {
+            self.session_requests += 1;
+            if matches!(self.session_lifetime, Some(lifetime) if self.session_requests > lifetime) {
+                let _ = self.handle_session(&[SESSION_DEFAULT]);
+            }
+        }
+
+        if self.kernel {
+            return respond(sid, self.handle_kernel(sid, data));
+        }
+
+        let result = match sid {
+            0x01 | 0x03 | 0x09 | 0x23 if self.bootloader => Err(NRC_SERVICE_NOT_SUPPORTED),
+            0x01 => self.handle_current_data(data),
+            0x03 => Ok(vec![0]),
+            0x09 => self.handle_vehicle_info(data),
+            0x10 => self.handle_session(data),
+            0x11 => self.handle_reset(data),
+            0x23 => self.handle_read_memory(data),
+            0x27 => self.handle_security(data),
+            0x31 => self.handle_routine_control(data),
+            0x34 => self.handle_request_download(data),
+            0x36 => self.handle_transfer_data(data),
+            0x37 => self.handle_transfer_exit(),
+            0x38 => self.handle_start_routine(data),
+            0x3D => self.handle_write_memory(data),
+            0x3E => Ok(vec![0]),
+            0xB1 => self.handle_erase(data),
+            _ => Err(NRC_SERVICE_NOT_SUPPORTED),
+        };
+        respond(sid, result)
+    }
+
+    /// Handles the block protocol of the RAM kernel
+    fn handle_kernel(&mut self, sid: u8, data: &[u8]) -> Result<Vec<u8>, u8> {
+        match sid {
+            0x11 => {
+                self.kernel = false;
+                self.handle_reset(data)
+            }
+            0x3E => Ok(vec![0]),
+            0x80 => Ok(vec![KERNEL_VERSION]),
+            0x81 => {
+                if data.len() != 6 {
+                    return Err(NRC_INCORRECT_LENGTH);
+                }
+                let address = read_u32(&data[0..4]) as usize;
+                let length = ((data[4] as usize) << 8) | data[5] as usize;
+                if length == 0 || address + length > self.memory.len() {
+                    return Err(NRC_OUT_OF_RANGE);
+                }
+                Ok(self.memory[address..address + length].to_vec())
+            }
+            0x82 => {
+                if data.len() != 8 {
+                    return Err(NRC_INCORRECT_LENGTH);
+                }
+                let address = read_u32(&data[0..4]) as usize;
+                let length = read_u32(&data[4..8]) as usize;
+                if address < self.erase_start || address + length > self.memory.len() {
+                    return Err(NRC_OUT_OF_RANGE);
+                }
+                for b in self.memory[address..address + length].iter_mut() {
+                    *b = 0xFF;
+                }
+                Ok(Vec::new())
+            }
+            0x83 => {
+                if data.len() < 5 {
+                    return Err(NRC_INCORRECT_LENGTH);
+                }
+                let address = read_u32(&data[0..4]) as usize;
+                let data = &data[4..];
+                if address < self.erase_start || address + data.len() > self.memory.len() {
+                    return Err(NRC_OUT_OF_RANGE);
+                }
+                let target = &mut self.memory[address..address + data.len()];
+                // Flash can only be written once erased
+                if target.iter().any(|&b| b != 0xFF) {
+                    return Err(NRC_CONDITIONS_NOT_CORRECT);
+                }
+                target.copy_from_slice(data);
+                Ok(Vec::new())
+            }
+            _ => Err(NRC_SERVICE_NOT_SUPPORTED),
+        }
+    }
+
+    fn handle_current_data(&mut self, data: &[u8]) -> Result<Vec<u8>, u8> {
+        match data {
+            [0x0C] => {
+                let value = self.rpm.saturating_mul(4);
+                Ok(vec![0x0C, (value >> 8) as u8, value as u8])
+            }
+            _ => Err(NRC_OUT_OF_RANGE),
+        }
+    }
+
+    fn handle_vehicle_info(&mut self, data: &[u8]) -> Result<Vec<u8>, u8> {
+        match data {
+            [0x02] => {
+                let mut response = vec![0x02, 0x01];
+                response.extend_from_slice(self.vin.as_bytes());
+                Ok(response)
+            }
+            [0x04] => Ok(padded_info(0x04, &self.calibration_id, 16)),
+            [0x0A] => Ok(padded_info(0x0A, ECU_NAME, 20)),
+            _ => Err(NRC_OUT_OF_RANGE),
+        }
+    }
+
+    fn handle_session(&mut self, data: &[u8]) -> Result<Vec<u8>, u8> {
+        let &id = data.first().ok_or(NRC_INCORRECT_LENGTH)?;
+        // Changing sessions always revokes security access
+        self.session = id;
+        self.session_requests = 0;
+        self.unlocked = false;
+        self.seed = None;
+        self.erased = false;
+        self.download = None;
+        Ok(vec![id])
+    }
+
+    fn handle_reset(&mut self, data: &[u8]) -> Result<Vec<u8>, u8> {
+        let &reset_type = data.first().ok_or(NRC_INCORRECT_LENGTH)?;
+        self.handle_session(&[SESSION_DEFAULT])?;
+        Ok(vec![reset_type])
+    }
+
+    fn handle_security(&mut self, data: &[u8]) -> Result<Vec<u8>, u8> {
+        match data.split_first() {
+            Some((1, _)) => {
+                if self.session == SESSION_DEFAULT {
+                    return Err(NRC_CONDITIONS_NOT_CORRECT);
+                }
+                // The real ECU derives the seed from a free-running timer
+                self.seed_counter = self
+                    .seed_counter
+                    .wrapping_mul(1_103_515_245)
+                    .wrapping_add(12345);
+                let seed = [
+                    (self.seed_counter >> 16) as u8,
+                    (self.seed_counter >> 8) as u8,
+                    self.seed_counter as u8,
+                ];
+                self.seed = Some(seed);
+                let mut response = vec![1];
+                response.extend_from_slice(&seed);
+                Ok(response)
+            }
+            Some((2, key)) => {
+                let seed = self.seed.take().ok_or(NRC_SEQUENCE_ERROR)?;
+                if key != mzr::security_key(&seed) {
+                    return Err(NRC_INVALID_KEY);
+                }
+                self.unlocked = true;
+                Ok(vec![2])
+            }
+            _ => Err(NRC_OUT_OF_RANGE),
+        }
+    }
+
+    fn handle_read_memory(&mut self, data: &[u8]) -> Result<Vec<u8>, u8> {
+        if data.len() != 6 {
+            return Err(NRC_INCORRECT_LENGTH);
+        }
+        if !self.unlocked {
+            return Err(NRC_SECURITY_DENIED);
+        }
+        let address = read_u32(&data[0..4]) as usize;
+        let length = ((data[4] as usize) << 8) | data[5] as usize;
+        if address >= RAM_START {
+            let start = address - RAM_START;
+            if length == 0 || start + length > RAM_SIZE {
+                return Err(NRC_OUT_OF_RANGE);
+            }
+            return Ok(self.ram[start..start + length].to_vec());
+        }
+        if length == 0 || address + length > self.memory.len() {
+            return Err(NRC_OUT_OF_RANGE);
+        }
+        Ok(self.memory[address..address + length].to_vec())
+    }
+
+    /// Writes RAM. The address and length are echoed.
+    fn handle_write_memory(&mut self, data: &[u8]) -> Result<Vec<u8>, u8> {
+        if data.len() < 6 {
+            return Err(NRC_INCORRECT_LENGTH);
+        }
+        let address = read_u32(&data[0..4]) as usize;
+        let length = ((data[4] as usize) << 8) | data[5] as usize;
+        if data.len() != 6 + length {
+            return Err(NRC_INCORRECT_LENGTH);
+        }
+        if !self.unlocked {
+            return Err(NRC_SECURITY_DENIED);
+        }
+        if length == 0 || address < RAM_START || address - RAM_START + length > RAM_SIZE {
+            return Err(NRC_OUT_OF_RANGE);
+        }
+        let start = address - RAM_START;
+        self.ram[start..start + length].copy_from_slice(&data[6..]);
+        Ok(data[..6].to_vec())
+    }
+
+    fn handle_erase(&mut self, data: &[u8]) -> Result<Vec<u8>, u8> {
+        if data != self.erase_routine.as_slice() {
+            return Err(NRC_OUT_OF_RANGE);
+        }
+        if self.session != SESSION_PROGRAMMING || !self.unlocked {
+            return Err(NRC_SECURITY_DENIED);
+        }
+        let start = self.erase_start.min(self.memory.len());
+        for b in self.memory[start..].iter_mut() {
+            *b = 0xFF;
+        }
+        self.erased = true;
+        Ok(data.to_vec())
+    }
+
+    fn handle_routine_control(&mut self, data: &[u8]) -> Result<Vec<u8>, u8> {
+        // RoutineControl is only answered with a checksum routine
+        let (routine, regions) = self
+            .checksum_routine
+            .as_ref()
+            .ok_or(NRC_SERVICE_NOT_SUPPORTED)?;
+        if data.len() != 11 {
+            return Err(NRC_INCORRECT_LENGTH);
+        }
+        if data[0] != 0x01 || u16::from_be_bytes([data[1], data[2]]) != *routine {
+            return Err(NRC_OUT_OF_RANGE);
+        }
+        if self.session != SESSION_PROGRAMMING || !self.unlocked {
+            return Err(NRC_SECURITY_DENIED);
+        }
+        // The flash is only checked once the transfer has ended
+        if self.download.is_some() {
+            return Err(NRC_SEQUENCE_ERROR);
+        }
+        let start = read_u32(&data[3..7]);
+        let end = read_u32(&data[7..11]);
+        let region = regions
+            .iter()
+            .find(|region| region.start == start && region.end == end)
+            .ok_or(NRC_OUT_OF_RANGE)?;
+        let sum = compute_checksum(
+            self.memory
+                .get(start as usize..end as usize)
+                .ok_or(NRC_OUT_OF_RANGE)?,
+        );
+        let status = if sum == region.target { 0x00 } else { 0x01 };
+        Ok([&data[..3], &[status]].concat())
+    }
+
+    fn handle_request_download(&mut self, data: &[u8]) -> Result<Vec<u8>, u8> {
+        if data.len() != 8 {
+            return Err(NRC_INCORRECT_LENGTH);
+        }
+        if !self.unlocked {
+            return Err(NRC_SECURITY_DENIED);
+        }
+        let offset = read_u32(&data[0..4]) as usize;
+        let length = read_u32(&data[4..8]) as usize;
+        // RAM can be written without erasing
+        if offset >= RAM_START {
+            if self.session != SESSION_PROGRAMMING {
+                return Err(NRC_CONDITIONS_NOT_CORRECT);
+            }
+            if length == 0 || offset + length > RAM_START + RAM_SIZE {
+                return Err(NRC_OUT_OF_RANGE);
+            }
+            self.ram_upload = Some(offset);
+            self.ram_image = None;
+            self.download = Some((offset, offset + length));
+            return Ok(Vec::new());
+        }
+        if !self.erased {
+            return Err(NRC_CONDITIONS_NOT_CORRECT);
+        }
+        if offset < self.erase_start || offset + length > self.memory.len() {
+            return Err(NRC_OUT_OF_RANGE);
+        }
+        self.download = Some((offset, offset + length));
+        Ok(Vec::new())
+    }
+
+    fn handle_transfer_data(&mut self, data: &[u8]) -> Result<Vec<u8>, u8> {
+        let (position, end) = self.download.ok_or(NRC_SEQUENCE_ERROR)?;
+        if position + data.len() > end {
+            return Err(NRC_OUT_OF_RANGE);
+        }
+        if position >= RAM_START {
+            let start = position - RAM_START;
+            self.ram[start..start + data.len()].copy_from_slice(data);
+        } else {
+            self.memory[position..position + data.len()].copy_from_slice(data);
+        }
+        self.download = Some((position + data.len(), end));
+        Ok(Vec::new())
+    }
+
+    fn handle_transfer_exit(&mut self) -> Result<Vec<u8>, u8> {
+        let (position, end) = self.download.take().ok_or(NRC_SEQUENCE_ERROR)?;
+        let upload = self.ram_upload.take();
+        if position == end {
+            self.ram_image = upload;
+        }
+        Ok(Vec::new())
+    }
+
+    fn handle_start_routine(&mut self, data: &[u8]) -> Result<Vec<u8>, u8> {
+        if data.len() != 4 {
+            return Err(NRC_INCORRECT_LENGTH);
+        }
+        if self.session != SESSION_PROGRAMMING || !self.unlocked {
+            return Err(NRC_SECURITY_DENIED);
+        }
+        // Only a completely uploaded image can be started, at its first byte
+        let address = read_u32(data) as usize;
+        if self.ram_image != Some(address) {
+            return Err(NRC_OUT_OF_RANGE);
+        }
+        self.kernel = true;
+        Ok(Vec::new())
+    }
+}
+
+impl SetTimeout for Ecu {
+    fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+}
+
+impl IsoTp for Ecu {
+    fn send_isotp(&mut self, id: u32, data: &[u8]) -> Result<(), obd::Error> {
+        // Frames addressed to other modules are ignored
+        if id == REQUEST_ID {
+            if self.key_on_delay > 0 {
+                self.key_on_delay -= 1;
+                return Ok(());
+            }
+            let mut response = self.handle(data);
+            if response.first() == Some(&0x63) {
+                self.reads += 1;
+                if self.corrupted_reads.contains(&self.reads) {
+                    if let Some(byte) = response.last_mut() {
+                        *byte ^= 0x01;
+                    }
+                }
+            }
+            if data.first() == Some(&0xB1) && self.erase_duration > self.timeout {
+                return Ok(());
+            }
+            if matches!(self.max_read_size, Some(size) if response.len() > size + 1) {
+                return Ok(());
+            }
+            if data.first() == Some(&0x36) {
+                self.transfers += 1;
+                if self.dropped_transfers.contains(&self.transfers) {
+                    return Ok(());
+                }
+            }
+            self.responses.push_back(response);
+        }
+        Ok(())
+    }
+
+    fn read_isotp(&mut self, id: u32) -> Result<Vec<u8>, obd::Error> {
+        if id != RESPONSE_ID {
+            return Err(obd::Error::EmptyResponse);
+        }
+        self.responses.pop_front().ok_or(obd::Error::EmptyResponse)
+    }
+}
+
+/// Builds the positive or negative response to service `sid`
+fn respond(sid: u8, result: Result<Vec<u8>, u8>) -> Vec<u8> {
+    match result {
+        Ok(mut response) => {
+            response.insert(0, sid + 0x40);
+            response
+        }
+        Err(code) => vec![UDS_RES_NEGATIVE, sid, code],
+    }
+}
+
+fn read_u32(data: &[u8]) -> u32 {
+    ((data[0] as u32) << 24) | ((data[1] as u32) << 16) | ((data[2] as u32) << 8) | data[3] as u32
+}
+
+/// Builds a vehicle information response with `text` padded to `length`
+fn padded_info(pid: u8, text: &str, length: usize) -> Vec<u8> {
+    let mut response = vec![pid, 0x01];
+    response.extend_from_slice(text.as_bytes());
+    response.resize(2 + length.max(text.len()), 0);
+    response
+}
@@ -0,0 +1,99 @@
+//! Serves a simulated MZR-DISI ECU on a SocketCAN interface
+
+use clap::clap_app;
+
+#[cfg(target_os = "linux")]
+pub fn main() {
+    use std::fs;
+    use std::time::Duration;
+
+    use mzr::datalink::socketcan::SocketCan;
+    use mzr::isotp::{Isotp, IsotpCan, IsotpError};
+    use mzr_sim::{Ecu, REQUEST_ID, RESPONSE_ID};
+
+    let matches = clap_app!(myapp =>
+        (version: "1.0")
+        (author: "Jacob Manning <jjacob.manning@gmail.com>")
+        (about: "Emulates an MZR-DISI ECU on a SocketCAN interface")
+        (@arg interface: -i --interface +takes_value "CAN interface to serve on (defaults to vcan0)")
+        (@arg output: -o --output +takes_value "Saves the virtual flash to this file after programming")
+        (@arg vin: --vin +takes_value "VIN reported by the ECU")
+        (@arg verbose: -v --verbose "Prints every request and response")
+        (@arg ROM: +required "ROM file backing the ECU memory")
+    )
+    .get_matches();
+
+    let rom_path = matches.value_of("ROM").unwrap();
+    let mut ecu = match Ecu::from_file(rom_path) {
+        Ok(ecu) => ecu,
+        Err(err) => {
+            println!("Failed to read {}: {}", rom_path, err);
+            return;
+        }
+    };
+    if let Some(vin) = matches.value_of("vin") {
+        ecu.set_vin(vin);
+    }
+
+    let interface = matches.value_of("interface").unwrap_or("vcan0");
+    let can = match SocketCan::open(interface) {
+        Ok(can) => can,
+        Err(err) => {
+            println!("Failed to open {}: {}", interface, err);
+            return;
+        }
+    };
+    // The ECU sends on the response ID and listens on the request ID
+    let isotp = IsotpCan::new(can, RESPONSE_ID, REQUEST_ID, Duration::from_secs(1));
+    let verbose = matches.is_present("verbose");
+
+    println!("Serving simulated ECU on {}", interface);
+    loop {
+        let request = match isotp.read_isotp() {
+            Ok(request) => request,
+            Err(IsotpError::TimedOut) => continue,
+            Err(err) => {
+                println!("Receive error: {}", err);
+                continue;
+            }
+        };
+
+        let response = ecu.handle(&request);
+        if verbose {
+            println!("> {}", hex(&request));
+            println!("< {}", hex(&response));
+        }
+        if let Err(err) = isotp.write_isotp(&response) {
+            println!("Transmit error: {}", err);
+            continue;
+        }
+
+        if request.first() == Some(&0x36) && ecu.programming_complete() {
+            if let Some(output) = matches.value_of("output") {
+                match fs::write(output, ecu.memory()) {
+                    Ok(()) => println!("Saved flash to {}", output),
+                    Err(err) => println!("Failed to save flash to {}: {}", output, err),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn hex(data: &[u8]) -> String {
+    data.iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn main() {
+    let _ = clap_app!(myapp =>
+        (version: "1.0")
+        (author: "Jacob Manning <jjacob.manning@gmail.com>")
+        (about: "Emulates an MZR-DISI ECU on a SocketCAN interface")
+    )
+    .get_matches();
+    println!("The ECU simulator requires Linux SocketCAN");
+}
@@ -0,0 +1,950 @@
+use mzr::cancel::CancelToken;
+use mzr::checksum;
+use mzr::datalink::can::CanBus;
+use mzr::diff;
+use mzr::event::Event;
+use mzr::kernel::{KernelTransfer, RamKernel, DEFAULT_KERNEL_ADDRESS};
+use mzr::memory_map::{MzrMemoryMap, Region};
+use mzr::metadata::RomMetadata;
+use mzr::module;
+use mzr::partial::PartialFile;
+use mzr::pause::PauseToken;
+use mzr::peek::{self, Format, Width};
+use mzr::probe;
+use mzr::profile::{EcuProfile, KeyAlgorithm};
+use mzr::progress::{NoProgress, Phase, Progress, ProgressObserver};
+use mzr::reconnect::Reconnecting;
+use mzr::session::{ExitAction, Session};
+use mzr::table::{LiveTable, TableDefinition};
+use mzr::timeout::{SetTimeout, TimeoutProfile};
+use mzr::{
+    DownloadState, Downloader, DownloaderBuilder, MzrBus, MzrError, Programmer, ProgrammerBuilder,
+    ProgrammerState, RetryPolicy, Transfer, TransferState,
+};
+use mzr_sim::Ecu;
+use obd::{IsoTp, Uds};
+use std::cell::{Cell, RefCell};
+use std::time::Duration;
+
+fn test_rom() -> Vec<u8> {
+    (0..1024 * 1024).map(|i| (i * 7 + i / 251) as u8).collect()
+}
+
+#[test]
+fn download_reads_rom() {
+    let rom = test_rom();
+    let mut ecu = Ecu::new(rom.clone());
+
+    let mut downloader = Downloader::new(&mut ecu);
+    downloader.start().unwrap();
+    while let DownloadState::InProgress(_) = downloader.step().unwrap() {}
+
+    assert_eq!(downloader.take_data(), rom);
+}
+
+#[test]
+fn flash_writes_image() {
+    let mut ecu = Ecu::new(vec![0; 1024 * 1024]);
+    let image = test_rom();
+
+    let mut programmer = Programmer::new(&mut ecu, 0x8000, image[0x8000..].to_owned());
+    programmer.start().unwrap();
+    while let ProgrammerState::InProgress(_) = programmer.step().unwrap() {}
+    drop(programmer);
+
+    assert!(ecu.memory()[..0x8000].iter().all(|&b| b == 0));
+    assert_eq!(&ecu.memory()[0x8000..], &image[0x8000..]);
+}
+
+#[test]
+fn step_before_erase_fails() {
+    let mut ecu = Ecu::new(test_rom());
+    let mut programmer = Programmer::new(&mut ecu, 0x8000, vec![0; 16]);
+    assert!(matches!(programmer.step(), Err(MzrError::NotErased)));
+}
+
+#[test]
+fn read_requires_security_access() {
+    let mut ecu = Ecu::new(test_rom());
+    assert!(ecu.read_memory_address(0x7e0, 0, 16).is_err());
+
+    ecu.authenticate(0x7e0, 0x87).unwrap();
+    assert!(ecu.unlocked());
+    assert_eq!(
+        ecu.read_memory_address(0x7e0, 0, 16).unwrap(),
+        &test_rom()[..16]
+    );
+}
+
+#[test]
+fn query_vin() {
+    let mut ecu = Ecu::new(test_rom());
+    ecu.set_vin("JM1BK343X81111111");
+    assert_eq!(ecu.query_vin(0x7e0).unwrap(), "JM1BK343X81111111");
+}
+
+#[test]
+fn download_events() {
+    let mut ecu = Ecu::new(test_rom());
+    let (tx, rx) = std::sync::mpsc::channel();
+
+    let mut downloader = Downloader::new(&mut ecu);
+    downloader.set_event_sink(tx);
+    downloader.start().unwrap();
+    while let DownloadState::InProgress(_) = downloader.step().unwrap() {}
+    drop(downloader);
+
+    let events: Vec<Event> = rx.try_iter().collect();
+    assert_eq!(events[0], Event::Connected { session: 0x87 });
+    assert_eq!(events[1], Event::Authenticated);
+    assert_eq!(
+        events[events.len() - 2],
+        Event::BlockTransferred {
+            block: 257,
+            blocks: 257,
+            position: 1024 * 1024,
+            total: 1024 * 1024,
+        }
+    );
+    assert_eq!(events.last(), Some(&Event::Completed));
+}
+
+#[derive(Default)]
+struct Recorder {
+    phases: Vec<Phase>,
+    last_position: usize,
+}
+
+impl ProgressObserver for Recorder {
+    fn on_progress(&mut self, progress: &Progress) {
+        self.last_position = progress.position;
+    }
+
+    fn on_phase_change(&mut self, phase: Phase) {
+        self.phases.push(phase);
+    }
+}
+
+#[test]
+fn programmer_run() {
+    let mut ecu = Ecu::new(vec![0; 1024 * 1024]);
+    let image = test_rom();
+    let mut recorder = Recorder::default();
+
+    let mut programmer = Programmer::new(&mut ecu, 0x8000, image[0x8000..].to_owned());
+    programmer.run(&mut recorder).unwrap();
+    drop(programmer);
+
+    assert_eq!(
+        recorder.phases,
+        vec![
+            Phase::Authenticating,
+            Phase::Erasing,
+            Phase::Transferring,
+            Phase::Completed
+        ]
+    );
+    assert_eq!(recorder.last_position, 1024 * 1024 - 0x8000);
+    assert_eq!(&ecu.memory()[0x8000..], &image[0x8000..]);
+}
+
+#[test]
+fn cancel_programming() {
+    let mut ecu = Ecu::new(vec![0; 1024 * 1024]);
+    let token = CancelToken::new();
+
+    let mut programmer = Programmer::new(&mut ecu, 0x8000, test_rom()[0x8000..].to_owned());
+    programmer.set_cancel_token(token.clone());
+    programmer.start().unwrap();
+    programmer.step().unwrap();
+    token.cancel();
+    assert!(matches!(programmer.step(), Err(MzrError::Cancelled)));
+    drop(programmer);
+
+    // The ECU is back in the default session with the transfer closed
+    assert_eq!(ecu.session(), 0x81);
+    assert!(!ecu.unlocked());
+    assert!(!ecu.programming_complete());
+}
+
+#[test]
+fn pause_programming() {
+    let mut ecu = Ecu::new(vec![0; 1024 * 1024]);
+    let pause = PauseToken::new();
+    let (tx, rx) = std::sync::mpsc::channel();
+
+    let mut programmer = Programmer::new(&mut ecu, 0x8000, test_rom()[0x8000..].to_owned());
+    programmer.set_pause_token(pause.clone());
+    programmer.set_event_sink(tx);
+    programmer.start().unwrap();
+
+    pause.pause();
+    let resumer = {
+        let pause = pause.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            pause.resume();
+        })
+    };
+    programmer.step().unwrap();
+    resumer.join().unwrap();
+    drop(programmer);
+
+    let events: Vec<Event> = rx.try_iter().collect();
+    let paused = events.iter().position(|e| *e == Event::Paused).unwrap();
+    assert_eq!(events[paused + 1], Event::Resumed);
+    assert!(matches!(
+        events[paused + 2],
+        Event::BlockTransferred { block: 1, .. }
+    ));
+}
+
+#[test]
+fn cancel_while_paused() {
+    let mut ecu = Ecu::new(vec![0; 1024 * 1024]);
+    let pause = PauseToken::new();
+    let token = CancelToken::new();
+    let (tx, rx) = std::sync::mpsc::channel();
+
+    let mut programmer = Programmer::new(&mut ecu, 0x8000, test_rom()[0x8000..].to_owned());
+    programmer.set_pause_token(pause.clone());
+    programmer.set_cancel_token(token.clone());
+    programmer.set_event_sink(tx);
+    programmer.start().unwrap();
+
+    pause.pause();
+    let canceller = std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        token.cancel();
+    });
+    assert!(matches!(programmer.step(), Err(MzrError::Cancelled)));
+    canceller.join().unwrap();
+    drop(programmer);
+
+    let events: Vec<Event> = rx.try_iter().collect();
+    assert!(events.contains(&Event::Paused));
+    assert!(!events.contains(&Event::Resumed));
+}
+
+#[test]
+fn session_guard_restores_default_session() {
+    let mut ecu = Ecu::new(test_rom());
+    {
+        let session = Session::open(&mut ecu, 0x85).unwrap();
+        assert_eq!(session.session_id(), Some(0x85));
+    }
+    assert_eq!(ecu.session(), 0x81);
+
+    {
+        let mut session = Session::open(&mut ecu, 0x85).unwrap();
+        session.set_exit_action(ExitAction::Reset);
+    }
+    assert_eq!(ecu.session(), 0x81);
+    assert!(!ecu.unlocked());
+}
+
+#[test]
+fn download_recovers_lapsed_session() {
+    let rom = test_rom();
+    let mut ecu = Ecu::new(rom.clone());
+    ecu.set_session_lifetime(Some(100));
+    let (tx, rx) = std::sync::mpsc::channel();
+
+    let mut downloader = Downloader::new(&mut ecu);
+    downloader.set_event_sink(tx);
+    downloader.start().unwrap();
+    while let DownloadState::InProgress(_) = downloader.step().unwrap() {}
+
+    assert_eq!(downloader.take_data(), rom);
+    assert!(rx
+        .try_iter()
+        .any(|e| matches!(e, Event::SessionRecovered { .. })));
+}
+
+#[test]
+fn low_voltage_refuses_to_flash() {
+    let mut ecu = Ecu::new(test_rom());
+    let mut programmer = Programmer::new(&mut ecu, 0x8000, vec![0; 16]);
+    programmer.set_voltage_monitor(|| Ok(11.2), 12.0);
+    assert!(matches!(programmer.start(), Err(MzrError::LowVoltage(_))));
+    drop(programmer);
+    assert_eq!(ecu.memory(), &test_rom()[..]);
+}
+
+#[test]
+fn engine_running_refuses_to_flash() {
+    let mut ecu = Ecu::new(test_rom());
+    ecu.set_rpm(750);
+    let mut programmer = Programmer::new(&mut ecu, 0x8000, vec![0; 16]);
+    assert!(matches!(programmer.start(), Err(MzrError::EngineRunning(rpm)) if rpm == 750.0));
+
+    programmer.allow_engine_running(true);
+    programmer.start().unwrap();
+}
+
+#[test]
+fn recover_bricked_ecu() {
+    let mut ecu = Ecu::new(vec![0xFF; 1024 * 1024]);
+    ecu.set_bootloader_mode(true);
+    ecu.set_key_on_delay(20);
+    let image = test_rom();
+
+    let mut programmer = Programmer::new(&mut ecu, 0x8000, image[0x8000..].to_owned());
+    programmer.set_recovery_mode(true);
+    programmer.start().unwrap();
+    while let ProgrammerState::InProgress(_) = programmer.step().unwrap() {}
+    drop(programmer);
+
+    assert_eq!(&ecu.memory()[0x8000..], &image[0x8000..]);
+}
+
+/// Link to the simulator whose adapter fails a read every 100 reads
+struct FlakyLink<'a> {
+    ecu: &'a RefCell<Ecu>,
+    reads: &'a Cell<usize>,
+    error: fn() -> j2534::Error,
+}
+
+impl SetTimeout for FlakyLink<'_> {
+    fn set_timeout(&mut self, _timeout: Duration) {}
+}
+
+impl IsoTp for FlakyLink<'_> {
+    fn send_isotp(&mut self, id: u32, data: &[u8]) -> Result<(), obd::Error> {
+        self.ecu.borrow_mut().send_isotp(id, data)
+    }
+
+    fn read_isotp(&mut self, id: u32) -> Result<Vec<u8>, obd::Error> {
+        let response = self.ecu.borrow_mut().read_isotp(id);
+        self.reads.set(self.reads.get() + 1);
+        if self.reads.get().is_multiple_of(100) {
+            return Err((self.error)().into());
+        }
+        response
+    }
+}
+
+#[test]
+fn download_reconnects_dropped_adapter() {
+    let ecu = RefCell::new(Ecu::new(test_rom()));
+    let reads = Cell::new(0);
+    let mut link = Reconnecting::new(|| {
+        Ok(FlakyLink {
+            ecu: &ecu,
+            reads: &reads,
+            error: || j2534::Error::DeviceNotConnected,
+        })
+    })
+    .unwrap();
+
+    let mut downloader = Downloader::new(&mut link);
+    downloader.run(&mut NoProgress).unwrap();
+    assert_eq!(downloader.take_data(), test_rom());
+    assert_eq!(link.reconnects(), 2);
+
+    // A timeout is retried without reopening the channel
+    let ecu = RefCell::new(Ecu::new(test_rom()));
+    let reads = Cell::new(0);
+    let mut link = Reconnecting::new(|| {
+        Ok(FlakyLink {
+            ecu: &ecu,
+            reads: &reads,
+            error: || j2534::Error::Timeout,
+        })
+    })
+    .unwrap();
+
+    let mut downloader = Downloader::new(&mut link);
+    downloader.run(&mut NoProgress).unwrap();
+    assert_eq!(downloader.take_data(), test_rom());
+    assert_eq!(link.reconnects(), 0);
+}
+
+#[test]
+fn erase_uses_erase_timeout() {
+    let mut ecu = Ecu::new(test_rom());
+    ecu.set_erase_duration(Duration::from_secs(12));
+
+    let mut programmer = Programmer::new(&mut ecu, 0x8000, vec![0; 16]);
+    programmer.set_timeouts(TimeoutProfile {
+        erase: Duration::from_secs(10),
+        ..TimeoutProfile::default()
+    });
+    assert!(programmer.start().is_err());
+
+    programmer.set_timeouts(TimeoutProfile::default());
+    programmer.start().unwrap();
+}
+
+#[test]
+fn download_adapts_chunk_size() {
+    let mut ecu = Ecu::new(test_rom());
+    ecu.set_max_read_size(Some(0x400));
+
+    let mut downloader = Downloader::new(&mut ecu);
+    downloader.run(&mut NoProgress).unwrap();
+    assert!(downloader.chunk_size() <= 0x400);
+    assert_eq!(downloader.take_data(), test_rom());
+}
+
+#[test]
+fn download_stats() {
+    let mut ecu = Ecu::new(test_rom());
+    ecu.set_session_lifetime(Some(100));
+
+    let mut downloader = Downloader::new(&mut ecu);
+    downloader.run(&mut NoProgress).unwrap();
+    let stats = downloader.stats();
+    assert_eq!(stats.bytes, 1024 * 1024);
+    assert_eq!(stats.session_recoveries, 2);
+    assert_eq!(stats.nrcs.get(&0x33), Some(&2));
+    assert!(stats.phase_duration(Phase::Transferring).is_some());
+}
+
+#[test]
+fn iterate_download_and_programming() {
+    let rom = test_rom();
+    let mut ecu = Ecu::new(rom.clone());
+
+    let mut downloader = Downloader::new(&mut ecu);
+    downloader.start().unwrap();
+    let progress: Vec<Progress> = downloader.iter().collect::<Result<_, _>>().unwrap();
+    assert_eq!(progress.len(), 257);
+    assert_eq!(progress.last().unwrap().position, 1024 * 1024);
+    assert_eq!(downloader.take_data(), rom);
+
+    let mut programmer = Programmer::new(&mut ecu, 0x8000, vec![0; 0x2000]);
+    assert!(matches!(
+        programmer.iter().next(),
+        Some(Err(MzrError::NotErased))
+    ));
+    programmer.start().unwrap();
+    let last = programmer.iter().last().unwrap().unwrap();
+    assert_eq!(last.position, 0x2000);
+}
+
+#[test]
+fn builder_configures_programmer() {
+    let mut ecu = Ecu::new(vec![0; 1024 * 1024]);
+    let image = test_rom();
+    let (tx, rx) = std::sync::mpsc::channel();
+
+    let mut programmer = ProgrammerBuilder::new()
+        .block_size(0x800)
+        .verify(true)
+        .build(&mut ecu, 0x8000, image[0x8000..].to_owned());
+    programmer.set_event_sink(tx);
+    programmer.run(&mut NoProgress).unwrap();
+    drop(programmer);
+
+    let events: Vec<Event> = rx.try_iter().collect();
+    assert!(events.contains(&Event::BlockTransferred {
+        block: 1,
+        blocks: (1024 * 1024 - 0x8000) / 0x800,
+        position: 0x800,
+        total: 1024 * 1024 - 0x8000,
+    }));
+    assert_eq!(events.last(), Some(&Event::Verified));
+}
+
+#[test]
+fn programmer_runs_checksum_routine() {
+    let map = MzrMemoryMap::default();
+    let mut ecu = Ecu::new(vec![0; map.flash_size()]);
+    ecu.set_checksum_routine(0xFF01, map.checksums.clone());
+    let mut image = test_rom();
+    assert!(checksum::correct_rom_checksum(&mut image, &map));
+    let (tx, rx) = std::sync::mpsc::channel();
+
+    // Verifying first ends the transfer only once
+    let mut programmer = ProgrammerBuilder::new()
+        .verify(true)
+        .checksum_routine(Some(0xFF01))
+        .build(&mut ecu, 0x8000, image[0x8000..].to_owned());
+    programmer.set_event_sink(tx);
+    programmer.run(&mut NoProgress).unwrap();
+    drop(programmer);
+    assert_eq!(rx.try_iter().last(), Some(Event::ChecksumsAccepted));
+
+    // A corrupted calibration is caught before the flash is trusted
+    let mut corrupted = image.clone();
+    corrupted[0x50000] ^= 0xFF;
+    let mut programmer = ProgrammerBuilder::new()
+        .checksum_routine(Some(0xFF01))
+        .build(&mut ecu, 0x8000, corrupted[0x8000..].to_owned());
+    assert!(matches!(
+        programmer.run(&mut NoProgress),
+        Err(MzrError::ChecksumRejected {
+            start: 0x48000,
+            end: 0x100000
+        })
+    ));
+    drop(programmer);
+
+    // Reprogramming the failed region through a kernel repairs it
+    let kernel = RamKernel::new(DEFAULT_KERNEL_ADDRESS, vec![0x09; 0x1800]);
+    let updates = diff::region_sectors(
+        &map,
+        Region::new(0x48000, 0x100000),
+        0x8000,
+        &image[0x8000..],
+    )
+    .unwrap();
+    let mut writer = KernelTransfer::write_sectors(&mut ecu, kernel, updates);
+    writer.start().unwrap();
+    while let TransferState::InProgress(_) = writer.step().unwrap() {}
+    drop(writer);
+    assert_eq!(&ecu.memory()[0x8000..], &image[0x8000..]);
+}
+
+#[test]
+fn programmer_retries_failed_blocks() {
+    let mut ecu = Ecu::new(vec![0; 1024 * 1024]);
+    let image = test_rom();
+    // The first lost block was written, the ECU's position has moved on
+    ecu.set_dropped_transfers(&[3, 10, 11]);
+
+    let mut programmer =
+        ProgrammerBuilder::new()
+            .verify(true)
+            .build(&mut ecu, 0x8000, image[0x8000..].to_owned());
+    programmer.run(&mut NoProgress).unwrap();
+    assert_eq!(programmer.stats().retries, 3);
+    drop(programmer);
+    assert!(ecu.memory()[0x8000..] == image[0x8000..]);
+
+    // Retries are bounded
+    ecu.set_dropped_transfers(&(1..=300).collect::<Vec<_>>());
+    let mut programmer = ProgrammerBuilder::new()
+        .retry_policy(RetryPolicy {
+            attempts: 2,
+            ..RetryPolicy::default()
+        })
+        .build(&mut ecu, 0x8000, image[0x8000..].to_owned());
+    assert!(matches!(
+        programmer.run(&mut NoProgress),
+        Err(MzrError::Context {
+            phase: Phase::Transferring,
+            block: 1,
+            ..
+        })
+    ));
+    assert_eq!(programmer.stats().retries, 2);
+}
+
+#[test]
+fn downloader_spot_checks_blocks() {
+    let rom = test_rom();
+    let mut ecu = Ecu::new(rom.clone());
+    let (tx, rx) = std::sync::mpsc::channel();
+
+    let mut downloader = DownloaderBuilder::new()
+        .chunk_size(0x800)
+        .spot_check(10)
+        .build(&mut ecu);
+    downloader.set_event_sink(tx);
+    downloader.run(&mut NoProgress).unwrap();
+    assert_eq!(rx.try_iter().last(), Some(Event::Verified));
+    assert_eq!(downloader.spot_check().unwrap(), 52);
+    assert_eq!(downloader.take_data(), rom);
+
+    // A block corrupted during the main pass reads back differently
+    ecu.set_corrupted_reads(&[7]);
+    let mut downloader = DownloaderBuilder::new()
+        .chunk_size(0x800)
+        .spot_check(100)
+        .build(&mut ecu);
+    assert!(matches!(
+        downloader.run(&mut NoProgress),
+        Err(MzrError::VerifyFailed(0x37FF))
+    ));
+}
+
+#[test]
+fn builder_configures_request_id() {
+    let mut ecu = Ecu::new(test_rom());
+    let mut downloader = DownloaderBuilder::new()
+        .request_id(0x7e1)
+        .retry_policy(RetryPolicy {
+            attempts: 0,
+            ..RetryPolicy::default()
+        })
+        .build(&mut ecu);
+    // The simulator only answers on 0x7e0
+    assert!(downloader.run(&mut NoProgress).is_err());
+}
+
+/// Runs any transfer to completion, counting the steps
+fn run_transfer<T: Transfer>(transfer: &mut T) -> usize {
+    transfer.start().unwrap();
+    let mut steps = 0;
+    while let TransferState::InProgress(_) = transfer.step().unwrap() {
+        steps += 1;
+    }
+    assert!(transfer.is_complete());
+    steps + 1
+}
+
+#[test]
+fn transfer_trait() {
+    let mut ecu = Ecu::new(test_rom());
+
+    let mut downloader = Downloader::new(&mut ecu);
+    assert_eq!(run_transfer(&mut downloader), 257);
+    drop(downloader);
+
+    let mut programmer = Programmer::new(&mut ecu, 0x8000, vec![0; 0x2000]);
+    assert_eq!(run_transfer(&mut programmer), 3);
+}
+
+#[test]
+fn errors_carry_context() {
+    // The adapter can't deliver a full chunk
+    let mut ecu = Ecu::new(test_rom());
+    ecu.set_max_read_size(Some(0x400));
+    let mut downloader = DownloaderBuilder::new().chunk_size(0x800).build(&mut ecu);
+    downloader.start().unwrap();
+    match downloader.step().unwrap_err() {
+        MzrError::Context {
+            phase,
+            offset,
+            block,
+            source,
+        } => {
+            assert_eq!(phase, Phase::Transferring);
+            assert_eq!(offset, 0);
+            assert_eq!(block, 1);
+            assert!(matches!(
+                source.bus_error(),
+                Some(obd::Error::EmptyResponse)
+            ));
+        }
+        err => panic!("unexpected error: {}", err),
+    }
+}
+
+#[test]
+fn resume_partial_download() {
+    let rom = test_rom();
+    let mut ecu = Ecu::new(rom.clone());
+    let output = std::env::temp_dir().join(format!("mzr-resume-{}.bin", std::process::id()));
+
+    // Interrupt the download after a few blocks
+    let mut downloader = Downloader::new(&mut ecu);
+    downloader.set_partial_file(PartialFile::create(&output, &[], Some("VIN")).unwrap());
+    downloader.start().unwrap();
+    for _ in 0..3 {
+        downloader.step().unwrap();
+    }
+    drop(downloader);
+
+    let partial = PartialFile::load(&output).unwrap().unwrap();
+    assert_eq!(partial.vin.as_deref(), Some("VIN"));
+    assert_eq!(partial.data, &rom[..3 * 0xFFE]);
+
+    let mut downloader = Downloader::new(&mut ecu);
+    downloader.set_partial_file(PartialFile::create(&output, &partial.data, Some("VIN")).unwrap());
+    downloader.resume(partial.data);
+    downloader.run(&mut NoProgress).unwrap();
+    assert_eq!(downloader.stats().bytes, rom.len() - 3 * 0xFFE);
+    assert_eq!(downloader.take_data(), rom);
+
+    PartialFile::remove(&output).unwrap();
+    assert!(PartialFile::load(&output).unwrap().is_none());
+}
+
+#[test]
+fn rom_metadata_sidecar() {
+    let rom = test_rom();
+    let mut ecu = Ecu::new(rom.clone());
+    ecu.set_calibration_id("L3K9188K1E");
+    assert_eq!(ecu.calibration_id(0x7e0).unwrap(), "L3K9188K1E");
+    assert_eq!(ecu.ecu_name(0x7e0).unwrap(), mzr_sim::ECU_NAME);
+
+    let path = std::env::temp_dir().join(format!("mzr-metadata-{}.bin", std::process::id()));
+    let metadata = RomMetadata {
+        calibration_id: Some("L3K9188K1E".to_string()),
+        adapter: Some("Simulator".to_string()),
+        ..RomMetadata::new(mzr_sim::DEFAULT_VIN, &rom)
+    };
+    metadata.save(&path).unwrap();
+    assert_eq!(RomMetadata::load(&path).unwrap(), metadata);
+    assert_eq!(metadata.size, rom.len());
+    assert_eq!(metadata.sha256.len(), 64);
+    std::fs::remove_file(RomMetadata::sidecar_path(&path)).unwrap();
+}
+
+#[test]
+fn kernel_reads_and_writes_flash() {
+    let rom = test_rom();
+    let mut ecu = Ecu::new(rom.clone());
+    let kernel = RamKernel::new(DEFAULT_KERNEL_ADDRESS, vec![0x09; 0x1800]);
+
+    let mut reader = KernelTransfer::read(&mut ecu, kernel.clone(), 0, rom.len());
+    let (tx, rx) = std::sync::mpsc::channel();
+    reader.set_event_sink(tx);
+    reader.start().unwrap();
+    while let TransferState::InProgress(_) = reader.step().unwrap() {}
+    assert_eq!(reader.take_data(), rom);
+    assert!(rx.try_iter().any(|event| event
+        == Event::KernelStarted {
+            address: DEFAULT_KERNEL_ADDRESS
+        }));
+    // The ECU is reset into the application once the transfer completes
+    assert!(!ecu.kernel_running());
+    assert_eq!(ecu.session(), 0x81);
+
+    let image: Vec<u8> = rom[0x8000..].iter().map(|b| !b).collect();
+    let mut writer = KernelTransfer::write(&mut ecu, kernel, 0x8000, image.clone());
+    let transfer: &mut dyn Transfer = &mut writer;
+    transfer.start().unwrap();
+    while let TransferState::InProgress(_) = transfer.step().unwrap() {}
+    drop(writer);
+    assert_eq!(&ecu.memory()[..0x8000], &rom[..0x8000]);
+    assert_eq!(&ecu.memory()[0x8000..], &image[..]);
+}
+
+#[test]
+fn kernel_writes_changed_sectors_only() {
+    let rom = test_rom();
+    let mut ecu = Ecu::new(rom.clone());
+    let kernel = RamKernel::new(DEFAULT_KERNEL_ADDRESS, vec![0x09; 0x1800]);
+    let map = MzrMemoryMap::default();
+
+    // Changes in an 8 KiB sector and two adjacent 128 KiB sectors
+    let mut image = rom[0x8000..].to_vec();
+    image[0x1000] ^= 0xFF;
+    image[0x60000 - 0x8000] ^= 0xFF;
+    image[0x9FFFF - 0x8000] ^= 0xFF;
+    let updates = diff::changed_sectors(&map, &rom, 0x8000, &image).unwrap();
+    assert_eq!(
+        updates
+            .iter()
+            .map(|update| update.sector)
+            .collect::<Vec<_>>(),
+        [
+            Region::new(0x8000, 0xA000),
+            Region::new(0x60000, 0x80000),
+            Region::new(0x80000, 0xA0000)
+        ]
+    );
+
+    let mut writer = KernelTransfer::write_sectors(&mut ecu, kernel, updates);
+    assert_eq!(writer.total_size(), 0x42000);
+    writer.start().unwrap();
+    while let TransferState::InProgress(_) = writer.step().unwrap() {}
+    drop(writer);
+    assert_eq!(&ecu.memory()[..0x8000], &rom[..0x8000]);
+    assert_eq!(&ecu.memory()[0x8000..], &image[..]);
+    assert!(!ecu.kernel_running());
+}
+
+#[test]
+fn delta_flash_checks_engine_and_battery() {
+    let rom = test_rom();
+    let kernel = RamKernel::new(DEFAULT_KERNEL_ADDRESS, vec![0x09; 0x1800]);
+    let mut image = rom[0x8000..].to_vec();
+    image[0x1000] ^= 0xFF;
+    let map = MzrMemoryMap::default();
+    let updates = diff::changed_sectors(&map, &rom, 0x8000, &image).unwrap();
+
+    let mut ecu = Ecu::new(rom.clone());
+    ecu.set_rpm(750);
+    let mut writer = KernelTransfer::write_sectors(&mut ecu, kernel.clone(), updates.clone());
+    assert!(matches!(writer.start(), Err(MzrError::EngineRunning(rpm)) if rpm == 750.0));
+    drop(writer);
+    assert!(!ecu.kernel_running());
+    assert!(ecu.memory() == &rom[..]);
+
+    let mut ecu = Ecu::new(rom.clone());
+    let mut writer = KernelTransfer::write_sectors(&mut ecu, kernel, updates);
+    writer.set_voltage_monitor(|| Ok(11.2), 12.0);
+    assert!(matches!(writer.start(), Err(MzrError::LowVoltage(_))));
+    drop(writer);
+    assert!(!ecu.kernel_running());
+    assert!(ecu.memory() == &rom[..]);
+}
+
+#[test]
+fn kernel_must_be_uploaded_before_jumping() {
+    let mut ecu = Ecu::new(test_rom());
+    ecu.authenticate(0x7e0, 0x85).unwrap();
+    assert!(ecu
+        .query_uds(0x7e0, 0x38, &DEFAULT_KERNEL_ADDRESS.to_be_bytes())
+        .is_err());
+    assert!(!ecu.kernel_running());
+}
+
+#[test]
+fn programmer_refuses_bootloader_region() {
+    let rom = test_rom();
+    let mut ecu = Ecu::new(rom.clone());
+
+    let mut programmer = Programmer::new(&mut ecu, 0x7000, vec![0; 0x2000]);
+    assert!(matches!(
+        programmer.start(),
+        Err(MzrError::BootloaderWrite {
+            offset: 0x7000,
+            length: 0x2000
+        })
+    ));
+    drop(programmer);
+    // Nothing was erased
+    assert_eq!(ecu.memory(), &rom[..]);
+}
+
+#[test]
+fn profile_describes_other_ecus() {
+    let rom: Vec<u8> = test_rom()[..0x80000].to_vec();
+    let mut ecu = Ecu::new(rom.clone());
+
+    let mut profile = EcuProfile::default();
+    profile.memory_map.name = "small".to_string();
+    profile.memory_map.calibration = Region::new(0x40000, 0x80000);
+    profile.memory_map.sectors.retain(|&start| start < 0x80000);
+    let mut downloader = profile.downloader().build(&mut ecu);
+    downloader.run(&mut NoProgress).unwrap();
+    assert_eq!(downloader.take_data(), rom);
+}
+
+#[test]
+fn second_generation_profile_flashes() {
+    let profile = EcuProfile::for_model("ms3-gen2").unwrap();
+    let map = profile.memory_map.clone();
+    let rom: Vec<u8> = (0..map.flash_size())
+        .map(|i| (i * 7 + i / 251) as u8)
+        .collect();
+    let mut ecu = Ecu::new(rom.clone());
+    ecu.set_bootloader_size(map.bootloader.end as usize);
+    ecu.set_erase_routine(&profile.erase_routine);
+
+    let mut image = rom.clone();
+    assert!(!checksum::checksum_valid(&image, &map));
+    assert!(checksum::correct_rom_checksum(&mut image, &map));
+    let programmable = map.programmable();
+    let data = image[programmable.start as usize..].to_vec();
+    let mut programmer = profile
+        .programmer()
+        .build(&mut ecu, programmable.start, data);
+    programmer.run(&mut NoProgress).unwrap();
+    drop(programmer);
+    assert_eq!(ecu.memory(), &image[..]);
+
+    // The MZR-DISI erase routine is refused
+    let mut programmer =
+        EcuProfile::default()
+            .programmer()
+            .build(&mut ecu, programmable.start, vec![0; 16]);
+    assert!(programmer.start().is_err());
+}
+
+#[test]
+fn non_turbo_profile_dumps_rom() {
+    let profile = EcuProfile::for_model("mazda3").unwrap();
+    let map = profile.memory_map.clone();
+    let mut rom: Vec<u8> = test_rom()[..map.flash_size()].to_vec();
+    assert!(checksum::correct_rom_checksum(&mut rom, &map));
+    let mut ecu = Ecu::new(rom.clone());
+
+    let mut downloader = profile.downloader().build(&mut ecu);
+    downloader.run(&mut NoProgress).unwrap();
+    let data = downloader.take_data();
+    assert_eq!(data, rom);
+    assert!(checksum::checksum_valid(&data, &map));
+}
+
+#[test]
+fn scan_finds_engine_ecu() {
+    let mut ecu = Ecu::new(test_rom());
+    let found: Vec<_> = module::scan_ids(CanBus::HighSpeed)
+        .into_iter()
+        .filter_map(|id| module::probe(&mut ecu, id))
+        .collect();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].request_id, 0x7E0);
+    assert_eq!(found[0].name, Some("Powertrain control module"));
+    // The simulator has no part number, so the OBD ECU name is used
+    assert_eq!(found[0].identification.as_deref(), Some(mzr_sim::ECU_NAME));
+}
+
+#[test]
+fn probe_maps_security_levels() {
+    let mut ecu = Ecu::new(test_rom());
+    ecu.set_diagnostic_session(0x7e0, mzr::SESSION_PROGRAMMING)
+        .unwrap();
+    let levels = probe::scan_security_levels(
+        &mut ecu,
+        0x7e0,
+        probe::SECURITY_LEVELS,
+        &KeyAlgorithm::Mazda,
+        |_| {},
+    )
+    .unwrap();
+    assert_eq!(levels.len(), 1);
+    assert_eq!(levels[0].level, 1);
+    assert!(levels[0].granted);
+    // The simulator doesn't implement RoutineControl
+    assert!(probe::scan_routines(&mut ecu, 0x7e0, 0..=0xFF, |_| {}).is_err());
+}
+
+#[test]
+fn probe_maps_readable_memory() {
+    let mut ecu = Ecu::new(test_rom());
+    ecu.authenticate(0x7e0, mzr::SESSION_DOWNLOAD).unwrap();
+    let regions =
+        probe::map_readable(&mut ecu, 0x7e0, Region::new(0, 0x200000), 0x10000, |_| {}).unwrap();
+    assert_eq!(regions, vec![Region::new(0, 0x100000)]);
+}
+
+#[test]
+fn poke_writes_ram() {
+    let mut ecu = Ecu::new(test_rom());
+    let value = peek::parse_value("-2", Width::Word).unwrap();
+    assert!(peek::poke(&mut ecu, 0x7e0, 0xFFFF_8000, &value).is_err());
+
+    ecu.authenticate(0x7e0, mzr::SESSION_DOWNLOAD).unwrap();
+    peek::poke(&mut ecu, 0x7e0, 0xFFFF_8000, &value).unwrap();
+    let data = peek::peek(&mut ecu, 0x7e0, 0xFFFF_8000, Width::Word, 2).unwrap();
+    assert_eq!(
+        peek::format_values(&data, Width::Word, Format::Signed).unwrap(),
+        vec!["-2", "0"]
+    );
+    // ROM is read-only
+    assert!(peek::poke(&mut ecu, 0x7e0, 0x1000, &value).is_err());
+}
+
+#[test]
+fn live_table_patches_ram_mirror_while_logging() {
+    let rom = test_rom();
+    let mut ecu = Ecu::new(rom.clone());
+    ecu.set_rpm(3000);
+    ecu.authenticate(0x7e0, mzr::SESSION_DOWNLOAD).unwrap();
+
+    let definition = TableDefinition {
+        name: "Spark advance".to_string(),
+        address: 0x50000,
+        ram_address: 0xFFFF_8000,
+        rows: 8,
+        columns: 8,
+        size: 2,
+        signed: true,
+        scale: 0.25,
+        offset: 0.0,
+    };
+    definition
+        .validate(&mzr::memory_map::MzrMemoryMap::default())
+        .unwrap();
+    let mut table = LiveTable::from_rom(definition.clone(), &rom).unwrap();
+    table.upload(&mut ecu, 0x7e0).unwrap();
+
+    for step in 0..8 {
+        assert_eq!(ecu.engine_rpm(0x7e0).unwrap(), 3000.0);
+        table.set(step, step, step as f64 - 2.0).unwrap();
+        assert_eq!(table.flush(&mut ecu, 0x7e0).unwrap(), 1);
+    }
+
+    let mirror = LiveTable::read(&mut ecu, 0x7e0, definition).unwrap();
+    assert_eq!(mirror.get(0, 0), Some(-2.0));
+    assert_eq!(mirror.get(7, 7), Some(5.0));
+    assert_eq!(mirror.get(0, 1), table.get(0, 1));
+}